@@ -0,0 +1,99 @@
+use amms::{
+    amm::{erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AmmState},
+    math::fixed_point::DecimalScalingCache,
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ethers::types::{H160, U256};
+
+//A cache built for other decimals is recomputed on every call, which is what every call cost before the scaling was cached
+fn stale_cache() -> DecimalScalingCache {
+    DecimalScalingCache::new(0, 0)
+}
+
+fn calculate_price(c: &mut Criterion) {
+    let token_a = H160::from_low_u64_be(1);
+    let token_b = H160::from_low_u64_be(2);
+
+    let v2_pool = UniswapV2Pool {
+        token_a,
+        token_a_decimals: 6,
+        token_b,
+        token_b_decimals: 18,
+        reserve_0: 30_000_000 * 10_u128.pow(6),
+        reserve_1: 18_000 * 10_u128.pow(18),
+        fee: 300,
+        ..Default::default()
+    };
+
+    let v3_pool = UniswapV3Pool {
+        token_a,
+        token_a_decimals: 6,
+        token_b,
+        token_b_decimals: 18,
+        liquidity: 10_u128.pow(24),
+        sqrt_price: U256::one() << 96,
+        fee: 3000,
+        tick_spacing: 60,
+        ..Default::default()
+    };
+
+    let vault = ERC4626Vault {
+        vault_token: token_a,
+        vault_token_decimals: 18,
+        asset_token: token_b,
+        asset_token_decimals: 6,
+        vault_reserve: U256::exp10(24),
+        asset_reserve: U256::exp10(12) * 3 / 2,
+        ..Default::default()
+    };
+
+    let mut v2_cached = v2_pool.clone();
+    v2_cached.reset_decimal_scaling();
+    let mut v3_cached = v3_pool.clone();
+    v3_cached.reset_decimal_scaling();
+    let mut vault_cached = vault.clone();
+    vault_cached.reset_decimal_scaling();
+
+    let v2_recomputed = UniswapV2Pool {
+        decimal_scaling: stale_cache(),
+        ..v2_pool
+    };
+    let v3_recomputed = UniswapV3Pool {
+        decimal_scaling: stale_cache(),
+        ..v3_pool
+    };
+    let vault_recomputed = ERC4626Vault {
+        decimal_scaling: stale_cache(),
+        ..vault
+    };
+
+    let mut group = c.benchmark_group("uniswap_v2_calculate_price");
+    group.bench_function("cached", |b| {
+        b.iter(|| v2_cached.calculate_price(black_box(token_a)))
+    });
+    group.bench_function("recomputed", |b| {
+        b.iter(|| v2_recomputed.calculate_price(black_box(token_a)))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("uniswap_v3_calculate_price");
+    group.bench_function("cached", |b| {
+        b.iter(|| v3_cached.calculate_price(black_box(token_a)))
+    });
+    group.bench_function("recomputed", |b| {
+        b.iter(|| v3_recomputed.calculate_price(black_box(token_a)))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("erc_4626_calculate_price");
+    group.bench_function("cached", |b| {
+        b.iter(|| vault_cached.calculate_price(black_box(token_a)))
+    });
+    group.bench_function("recomputed", |b| {
+        b.iter(|| vault_recomputed.calculate_price(black_box(token_a)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, calculate_price);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+use amms::{
+    amm::{
+        factory::Factory,
+        uniswap_v2::{factory::UniswapV2Factory, UniswapV2Pool},
+        uniswap_v3::UniswapV3Pool,
+        AMM,
+    },
+    sync::checkpoint::{Checkpoint, FactoryCheckpoint, Format},
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ethers::types::H160;
+
+const UNISWAP_V3_FIXTURE: &str = include_str!("fixtures/uniswap_v3_pool.json");
+
+//Checkpoint with 50,000 V2 pools and 100 copies of the V3 fixture, roughly the size of a mainnet sync
+fn checkpoint() -> Checkpoint {
+    let v3_pool: UniswapV3Pool =
+        serde_json::from_str(UNISWAP_V3_FIXTURE).expect("could not parse fixture");
+
+    let mut amms = (0..50_000_u64)
+        .map(|i| {
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(i + 1_000_000),
+                token_a: H160::from_low_u64_be(i),
+                token_a_decimals: 18,
+                token_b: H160::from_low_u64_be(i + 1),
+                token_b_decimals: 6,
+                reserve_0: 10_u128.pow(24) + i as u128,
+                reserve_1: 2 * 10_u128.pow(12) + i as u128,
+                fee: 300,
+                ..Default::default()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    amms.extend((0..100_u64).map(|i| {
+        let mut pool = v3_pool.clone();
+        pool.address = H160::from_low_u64_be(i + 2_000_000);
+        AMM::UniswapV3Pool(pool)
+    }));
+
+    Checkpoint::new(
+        1690000000,
+        17700000,
+        vec![FactoryCheckpoint::new(
+            Factory::UniswapV2Factory(UniswapV2Factory::new(
+                H160::from_low_u64_be(1),
+                10000835,
+                300,
+            )),
+            17700000,
+            50_000,
+        )],
+        amms,
+    )
+    .with_chain_id(1)
+}
+
+fn load_checkpoint(c: &mut Criterion) {
+    let checkpoint = checkpoint();
+    let mut group = c.benchmark_group("load_checkpoint");
+    group.sample_size(10);
+
+    for format in [Format::Json, Format::Bincode, Format::MessagePack] {
+        let data = checkpoint
+            .encode(format)
+            .expect("could not encode checkpoint");
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{format:?}")),
+            &data,
+            |b, data| b.iter(|| Checkpoint::decode(black_box(data)).expect("could not decode")),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, load_checkpoint);
+criterion_main!(benches);
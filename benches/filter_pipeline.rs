@@ -0,0 +1,98 @@
+use std::{sync::Arc, time::Duration};
+
+use amms::{
+    amm::{uniswap_v2::UniswapV2Pool, AMM},
+    errors::AMMError,
+    filters::{address::BlacklistFilter, AmmFilter, FilterCost, FilterOutcome, FilterPipeline},
+};
+use async_trait::async_trait;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ethers::{
+    providers::{Http, Middleware, Provider},
+    types::H160,
+};
+
+const POOLS: u64 = 100_000;
+const BATCH_SIZE: usize = 1000;
+
+//Stands in for a filter making one RPC round trip per batch
+#[derive(Debug)]
+struct SimulatedRpcFilter {
+    latency: Duration,
+}
+
+#[async_trait]
+impl<M: 'static + Middleware> AmmFilter<M> for SimulatedRpcFilter {
+    fn name(&self) -> &str {
+        "simulated_rpc"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Rpc
+    }
+
+    async fn filter(
+        &self,
+        amms: Vec<AMM>,
+        _middleware: Arc<M>,
+    ) -> Result<FilterOutcome, AMMError<M>> {
+        tokio::time::sleep(self.latency).await;
+
+        Ok(FilterOutcome {
+            kept: amms,
+            dropped: vec![],
+        })
+    }
+}
+
+fn synthetic_pools() -> Vec<AMM> {
+    (0..POOLS)
+        .map(|idx| {
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(idx),
+                token_a: H160::from_low_u64_be(idx % 1000),
+                token_b: H160::from_low_u64_be(idx % 1000 + 1),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn filter_pipeline(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+    let amms = synthetic_pools();
+
+    let mut group = c.benchmark_group("filter_pipeline_100k");
+    group.sample_size(10);
+
+    //With 100 batches of 10ms each, the run time should drop close to linearly up to the concurrency limit
+    for concurrency in [1, 4, 16, 64] {
+        let pipeline = FilterPipeline::new()
+            .with_filter(BlacklistFilter::new(vec![H160::from_low_u64_be(7)], vec![]))
+            .with_filter(SimulatedRpcFilter {
+                latency: Duration::from_millis(10),
+            })
+            .with_batch_size(BATCH_SIZE)
+            .with_concurrency(concurrency);
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &concurrency,
+            |b, _| {
+                b.to_async(&runtime).iter(|| async {
+                    let (kept, _) = pipeline
+                        .run(amms.clone(), middleware.clone())
+                        .await
+                        .unwrap();
+                    black_box(kept);
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, filter_pipeline);
+criterion_main!(benches);
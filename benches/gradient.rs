@@ -0,0 +1,80 @@
+use amms::amm::{
+    erc_4626::ERC4626Vault,
+    uniswap_v2::{UniswapV2Pool, FEE_DENOMINATOR},
+    uniswap_v3::UniswapV3Pool,
+    AmmState,
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ethers::types::{H160, U256};
+use num_bigfloat::BigFloat;
+
+//Closed form V2 gradient evaluated with BigFloat, as the optimizer used to compute it
+fn bigfloat_v2_gradient(pool: &UniswapV2Pool, amount_in: U256) -> f64 {
+    let fee = BigFloat::from(FEE_DENOMINATOR - pool.fee);
+    let denominator = BigFloat::from(FEE_DENOMINATOR);
+    let reserve_in = BigFloat::from(pool.reserve_0);
+    let reserve_out = BigFloat::from(pool.reserve_1);
+
+    let scaled_reserve_in = reserve_in.mul(&denominator);
+    let total = scaled_reserve_in.add(&BigFloat::from(amount_in.as_u128()).mul(&fee));
+
+    fee.mul(&reserve_out)
+        .mul(&scaled_reserve_in)
+        .div(&total.mul(&total))
+        .to_f64()
+}
+
+fn gradient(c: &mut Criterion) {
+    let token_a = H160::from_low_u64_be(1);
+    let token_b = H160::from_low_u64_be(2);
+    let amount_in = U256::exp10(21);
+
+    let v2_pool = UniswapV2Pool {
+        token_a,
+        token_b,
+        reserve_0: 10_u128.pow(24),
+        reserve_1: 2 * 10_u128.pow(21),
+        fee: 300,
+        ..Default::default()
+    };
+
+    let v3_pool = UniswapV3Pool {
+        token_a,
+        token_b,
+        liquidity: 10_u128.pow(24),
+        sqrt_price: U256::one() << 96,
+        fee: 3000,
+        tick_spacing: 60,
+        ..Default::default()
+    };
+
+    let vault = ERC4626Vault {
+        vault_token: token_a,
+        asset_token: token_b,
+        vault_reserve: U256::exp10(24),
+        asset_reserve: U256::exp10(24) * 3 / 2,
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("uniswap_v2_gradient");
+    group.bench_function("q128", |b| {
+        b.iter(|| v2_pool.gradient(black_box(token_a), black_box(amount_in)))
+    });
+    group.bench_function("q128_to_f64", |b| {
+        b.iter(|| v2_pool.gradient_f64(black_box(token_a), black_box(amount_in)))
+    });
+    group.bench_function("bigfloat", |b| {
+        b.iter(|| bigfloat_v2_gradient(black_box(&v2_pool), black_box(amount_in)))
+    });
+    group.finish();
+
+    c.bench_function("uniswap_v3_gradient", |b| {
+        b.iter(|| v3_pool.gradient(black_box(token_a), black_box(amount_in)))
+    });
+    c.bench_function("erc_4626_gradient", |b| {
+        b.iter(|| vault.gradient(black_box(token_a), black_box(amount_in)))
+    });
+}
+
+criterion_group!(benches, gradient);
+criterion_main!(benches);
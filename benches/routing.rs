@@ -0,0 +1,68 @@
+use amms::{
+    amm::{uniswap_v2::UniswapV2Pool, AMM},
+    routing::{find_best_route, RouteConstraints, TokenGraph},
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ethers::types::{H160, U256};
+
+const POOLS_PER_PAIR: u64 = 2000;
+//Every pair of the first four tokens, so the search has routes of one to three hops
+const PAIRS: [(u64, u64); 6] = [(1, 2), (1, 3), (1, 4), (2, 3), (2, 4), (3, 4)];
+
+fn synthetic_pools() -> Vec<AMM> {
+    let mut amms = vec![];
+    for (pair, (token_a, token_b)) in PAIRS.iter().enumerate() {
+        for idx in 0..POOLS_PER_PAIR {
+            let depth = (idx * 7919 % POOLS_PER_PAIR + 1) as u128;
+            amms.push(AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(pair as u64 * POOLS_PER_PAIR + idx),
+                token_a: H160::from_low_u64_be(*token_a),
+                token_b: H160::from_low_u64_be(*token_b),
+                reserve_0: depth * 10_u128.pow(20),
+                reserve_1: depth * (pair as u128 + 1) * 10_u128.pow(20),
+                fee: 300,
+                ..Default::default()
+            }));
+        }
+    }
+
+    amms
+}
+
+fn best_route(c: &mut Criterion) {
+    let amms = synthetic_pools();
+    let graph = TokenGraph::new(&amms);
+    let (token_in, token_out) = (H160::from_low_u64_be(1), H160::from_low_u64_be(4));
+    let amount_in = U256::exp10(21);
+
+    let mut group = c.benchmark_group("find_best_route");
+    for max_hops in [1, 2, 3] {
+        group.bench_with_input(
+            BenchmarkId::new("max_hops", max_hops),
+            &max_hops,
+            |b, max_hops| {
+                b.iter(|| {
+                    find_best_route(
+                        &graph,
+                        &amms,
+                        token_in,
+                        token_out,
+                        black_box(amount_in),
+                        *max_hops,
+                        5,
+                        None,
+                        &RouteConstraints::default(),
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+
+    c.bench_function("token_graph_new", |b| {
+        b.iter(|| TokenGraph::new(black_box(&amms)))
+    });
+}
+
+criterion_group!(benches, best_route);
+criterion_main!(benches);
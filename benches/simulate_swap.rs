@@ -0,0 +1,210 @@
+use amms::amm::{
+    best_quote, erc_4626::ERC4626Vault, simulate_all, uniswap_v2::UniswapV2Pool,
+    uniswap_v3::UniswapV3Pool, AmmState, AMM,
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ethers::types::{H160, U256};
+
+const UNISWAP_V3_FIXTURE: &str = include_str!("fixtures/uniswap_v3_pool.json");
+const TICKS_CROSSED: [usize; 4] = [0, 1, 5, 20];
+
+fn uniswap_v2_pool() -> UniswapV2Pool {
+    UniswapV2Pool {
+        address: H160::from_low_u64_be(1),
+        token_a: H160::from_low_u64_be(2),
+        token_a_decimals: 6,
+        token_b: H160::from_low_u64_be(3),
+        token_b_decimals: 18,
+        reserve_0: 30_000_000 * 10_u128.pow(6),
+        reserve_1: 18_000 * 10_u128.pow(18),
+        fee: 300,
+        ..Default::default()
+    }
+}
+
+fn erc_4626_vault() -> ERC4626Vault {
+    ERC4626Vault {
+        vault_token: H160::from_low_u64_be(4),
+        vault_token_decimals: 18,
+        asset_token: H160::from_low_u64_be(3),
+        asset_token_decimals: 18,
+        vault_reserve: U256::exp10(24),
+        asset_reserve: U256::exp10(24) * 21 / 20,
+        deposit_fee: 10,
+        withdraw_fee: 10,
+        ..Default::default()
+    }
+}
+
+//Initialized ticks crossed when swapping token a for token b moves the pool from `start` down to `end`
+fn ticks_crossed(pool: &UniswapV3Pool, start: i32, end: i32) -> usize {
+    pool.ticks
+        .keys()
+        .filter(|tick| **tick > end && **tick <= start)
+        .count()
+}
+
+//Smallest amount of token a that crosses at least `ticks` initialized ticks, found by bisection
+fn amount_crossing(pool: &UniswapV3Pool, ticks: usize) -> U256 {
+    let crosses = |amount_in: U256| {
+        let mut swapped = pool.clone();
+        match swapped.simulate_swap_mut(pool.token_a, amount_in) {
+            Ok(_) => ticks_crossed(pool, pool.tick, swapped.tick) >= ticks,
+            //Running out of liquidity crosses every tick left
+            Err(_) => true,
+        }
+    };
+
+    let (mut low, mut high) = (U256::zero(), U256::one());
+    while !crosses(high) {
+        high <<= 1;
+    }
+
+    while high - low > U256::one() {
+        let mid = (low + high) / 2;
+        if crosses(mid) {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+
+    high
+}
+
+fn fixture_pool() -> UniswapV3Pool {
+    serde_json::from_str(UNISWAP_V3_FIXTURE).expect("could not deserialize the Uniswap V3 fixture")
+}
+
+fn uniswap_v2(c: &mut Criterion) {
+    let pool = uniswap_v2_pool();
+    let amount_in = U256::exp10(6) * 10_000;
+
+    let mut group = c.benchmark_group("uniswap_v2");
+    group.bench_function("simulate_swap", |b| {
+        b.iter(|| pool.simulate_swap(black_box(pool.token_a), black_box(amount_in)))
+    });
+    group.bench_function("calculate_price", |b| {
+        b.iter(|| pool.calculate_price(black_box(pool.token_a)))
+    });
+    group.finish();
+}
+
+fn uniswap_v3(c: &mut Criterion) {
+    let pool = fixture_pool();
+
+    let mut group = c.benchmark_group("uniswap_v3_simulate_swap");
+    for ticks in TICKS_CROSSED {
+        //Midway between the amounts crossing `ticks` and `ticks + 1`, so rounding can not change the tick count
+        let amount_in = (amount_crossing(&pool, ticks) + amount_crossing(&pool, ticks + 1)) / 2;
+
+        group.bench_with_input(
+            BenchmarkId::new("ticks_crossed", ticks),
+            &amount_in,
+            |b, amount_in| b.iter(|| pool.simulate_swap(black_box(pool.token_a), *amount_in)),
+        );
+    }
+    group.finish();
+}
+
+fn erc_4626(c: &mut Criterion) {
+    let vault = erc_4626_vault();
+    let amount_in = U256::exp10(21);
+
+    let mut group = c.benchmark_group("erc_4626");
+    group.bench_function("deposit", |b| {
+        b.iter(|| vault.simulate_swap(black_box(vault.asset_token), black_box(amount_in)))
+    });
+    group.bench_function("redeem", |b| {
+        b.iter(|| vault.simulate_swap(black_box(vault.vault_token), black_box(amount_in)))
+    });
+    group.finish();
+}
+
+//Cost of going through the AMM enum compared to calling the pool directly
+fn amm_dispatch(c: &mut Criterion) {
+    let pool = uniswap_v2_pool();
+    let amm = AMM::UniswapV2Pool(pool.clone());
+    let amount_in = U256::exp10(6) * 10_000;
+
+    let mut group = c.benchmark_group("amm_dispatch");
+    group.bench_function("direct", |b| {
+        b.iter(|| pool.simulate_swap(black_box(pool.token_a), black_box(amount_in)))
+    });
+    group.bench_function("enum", |b| {
+        b.iter(|| amm.simulate_swap(black_box(pool.token_a), black_box(amount_in)))
+    });
+    group.finish();
+}
+
+//Quoting one trade against 5,000 candidate pools, one in ten being the V3 fixture
+fn quote_many(c: &mut Criterion) {
+    let v2_pool = uniswap_v2_pool();
+    let v3_pool = fixture_pool();
+    let amms = (0..5000_u64)
+        .map(|i| {
+            if i % 10 == 0 {
+                AMM::UniswapV3Pool(v3_pool.clone())
+            } else {
+                AMM::UniswapV2Pool(UniswapV2Pool {
+                    address: H160::from_low_u64_be(1000 + i),
+                    token_a: v3_pool.token_a,
+                    token_b: v3_pool.token_b,
+                    reserve_1: v2_pool.reserve_1 + i as u128 * 10_u128.pow(18),
+                    ..v2_pool.clone()
+                })
+            }
+        })
+        .collect::<Vec<_>>();
+    let (token_in, token_out) = (v3_pool.token_a, v3_pool.token_b);
+    let amount_in = U256::exp10(6) * 10_000;
+
+    let mut group = c.benchmark_group("quote_5000_pools");
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            amms.iter()
+                .map(|amm| amm.simulate_swap(black_box(token_in), black_box(amount_in)))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("simulate_all", |b| {
+        b.iter(|| simulate_all(&amms, black_box(token_in), black_box(amount_in)))
+    });
+    group.bench_function("best_quote", |b| {
+        b.iter(|| best_quote(&amms, black_box(token_in), token_out, black_box(amount_in)))
+    });
+    group.finish();
+}
+
+//Overhead of the tracing instrumentation without a subscriber and with a subscriber filtering out info events
+fn tracing_overhead(c: &mut Criterion) {
+    let pool = fixture_pool();
+    let amount_in = amount_crossing(&pool, 5);
+
+    let mut group = c.benchmark_group("tracing_overhead");
+    group.bench_function("no_subscriber", |b| {
+        b.iter(|| pool.simulate_swap(black_box(pool.token_a), black_box(amount_in)))
+    });
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_writer(std::io::sink)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        group.bench_function("warn_subscriber", |b| {
+            b.iter(|| pool.simulate_swap(black_box(pool.token_a), black_box(amount_in)))
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    uniswap_v2,
+    uniswap_v3,
+    erc_4626,
+    amm_dispatch,
+    quote_many,
+    tracing_overhead
+);
+criterion_main!(benches);
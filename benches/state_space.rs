@@ -0,0 +1,173 @@
+use std::{collections::HashSet, sync::Arc};
+
+use amms::{
+    amm::{
+        uniswap_v2::{self, UniswapV2Pool},
+        uniswap_v3::{self, UniswapV3Pool},
+        AmmState, AMM,
+    },
+    state_space::state::{
+        handle_state_changes_from_logs, initialize_state_space, order_logs, StateChangeCache,
+        StateSpace,
+    },
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ethers::{
+    abi::Token,
+    providers::{Http, Provider},
+    types::{Log, H160, H256, I256, U256, U64},
+};
+use tokio::sync::RwLock;
+
+const UNISWAP_V3_FIXTURE: &str = include_str!("fixtures/uniswap_v3_pool.json");
+const V2_POOLS: u64 = 200;
+const LOGS: u64 = 900;
+
+fn log(address: H160, topics: Vec<H256>, data: &[Token], log_index: u64) -> Log {
+    Log {
+        address,
+        topics,
+        data: ethers::abi::encode(data).into(),
+        block_number: Some(U64::from(1)),
+        transaction_hash: Some(H256::from_low_u64_be(log_index / 4 + 1)),
+        transaction_index: Some(U64::from(log_index / 4)),
+        log_index: Some(U256::from(log_index)),
+        ..Default::default()
+    }
+}
+
+//Generated block shaped after a busy one rather than recorded from mainnet, so it measures the overhead of
+//routing and applying logs rather than real load. A third of the logs are transfers the state space does not
+//track, one in nine is a swap on the V3 fixture and the rest are syncs spread over the V2 pools
+fn synthetic_block() -> (Vec<AMM>, Vec<Log>) {
+    let v3_pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)
+        .expect("could not deserialize the Uniswap V3 fixture");
+
+    let mut amms = (0..V2_POOLS)
+        .map(|idx| {
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(idx + 1000),
+                token_a: v3_pool.token_a,
+                token_b: v3_pool.token_b,
+                reserve_0: 30_000_000 * 10_u128.pow(6),
+                reserve_1: 18_000 * 10_u128.pow(18),
+                fee: 300,
+                ..Default::default()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let transfer = H256::from_low_u64_be(1);
+    let owner = H256::from(H160::from_low_u64_be(4));
+    let mut logs = vec![];
+
+    for idx in 0..LOGS {
+        logs.push(match idx % 9 {
+            0 | 1 | 2 => log(
+                v3_pool.token_a,
+                vec![transfer, owner, owner],
+                &[Token::Uint(U256::exp10(9))],
+                idx,
+            ),
+            3 => log(
+                v3_pool.address,
+                vec![uniswap_v3::SWAP_EVENT_SIGNATURE, owner, owner],
+                &[
+                    Token::Int(I256::from(10_i64.pow(9)).into_raw()),
+                    Token::Int(I256::from(-6 * 10_i64.pow(17)).into_raw()),
+                    Token::Uint(v3_pool.sqrt_price),
+                    Token::Uint(U256::from(v3_pool.liquidity)),
+                    Token::Int(I256::from(v3_pool.tick).into_raw()),
+                ],
+                idx,
+            ),
+            _ => log(
+                H160::from_low_u64_be(idx % V2_POOLS + 1000),
+                vec![uniswap_v2::SYNC_EVENT_SIGNATURE],
+                &[
+                    Token::Uint(U256::from(30_000_000 * 10_u128.pow(6) + idx as u128)),
+                    Token::Uint(U256::from(18_000 * 10_u128.pow(18) - idx as u128)),
+                ],
+                idx,
+            ),
+        });
+    }
+
+    amms.push(AMM::UniswapV3Pool(v3_pool));
+
+    (amms, logs)
+}
+
+//The previous hot loop, locking the state, looking up and cloning the AMM for every log
+async fn apply_per_log(state: Arc<RwLock<StateSpace>>, logs: Vec<Log>) -> Vec<AMM> {
+    let mut state_changes = vec![];
+
+    for log in order_logs(logs) {
+        if let Some(amm) = state.write().await.get_mut(&log.address) {
+            state_changes.push(amm.clone());
+            amm.sync_from_log(&log).expect("could not sync from log");
+        }
+    }
+
+    state_changes
+}
+
+fn apply_block(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+
+    let (amms, logs) = synthetic_block();
+    let event_signatures = amms
+        .iter()
+        .flat_map(|amm| amm.sync_on_event_signatures())
+        .collect::<HashSet<H256>>();
+
+    let relevant_logs = logs
+        .iter()
+        .filter(|log| event_signatures.contains(&log.topics[0]))
+        .count();
+    assert!(relevant_logs >= 500, "only {relevant_logs} relevant logs");
+
+    let setup = || {
+        (
+            Arc::new(RwLock::new(initialize_state_space(amms.clone()))),
+            logs.clone(),
+        )
+    };
+
+    let event_signatures = &event_signatures;
+    let middleware = &middleware;
+
+    let mut group = c.benchmark_group("apply_synthetic_block");
+
+    group.bench_function("routed", |b| {
+        b.to_async(&runtime).iter_batched(
+            setup,
+            |(state, logs)| async move {
+                handle_state_changes_from_logs(
+                    state,
+                    Arc::new(RwLock::new(StateChangeCache::new())),
+                    logs,
+                    event_signatures,
+                    middleware.clone(),
+                )
+                .await
+                .expect("could not apply block")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("per_log", |b| {
+        b.to_async(&runtime).iter_batched(
+            setup,
+            |(state, logs)| apply_per_log(state, logs),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, apply_block);
+criterion_main!(benches);
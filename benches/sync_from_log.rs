@@ -0,0 +1,195 @@
+use amms::amm::{
+    erc_4626::{self, ERC4626Vault},
+    uniswap_v2::{self, UniswapV2Pool},
+    uniswap_v3::{
+        self,
+        factory::{decode_pool_created_log, PoolCreatedFilter, POOL_CREATED_EVENT_SIGNATURE},
+        UniswapV3Pool,
+    },
+    AmmState,
+};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ethers::{
+    abi::{RawLog, Token},
+    prelude::EthEvent,
+    types::{Log, H160, H256, I256, U256},
+};
+
+const UNISWAP_V3_FIXTURE: &str = include_str!("fixtures/uniswap_v3_pool.json");
+
+fn log(address: H160, topics: Vec<H256>, data: &[Token]) -> Log {
+    Log {
+        address,
+        topics,
+        data: ethers::abi::encode(data).into(),
+        ..Default::default()
+    }
+}
+
+//Clones the pool outside of the timed section, so only decoding and applying the log is measured
+fn bench_log<T: AmmState + Clone>(c: &mut Criterion, name: &str, amm: &T, log: &Log) {
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || amm.clone(),
+            |mut amm| {
+                amm.sync_from_log(log).expect("could not sync from log");
+                amm
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn uniswap_v2(c: &mut Criterion) {
+    let pool = UniswapV2Pool {
+        address: H160::from_low_u64_be(1),
+        token_a: H160::from_low_u64_be(2),
+        token_b: H160::from_low_u64_be(3),
+        reserve_0: 30_000_000 * 10_u128.pow(6),
+        reserve_1: 18_000 * 10_u128.pow(18),
+        fee: 300,
+        ..Default::default()
+    };
+
+    let sync = log(
+        pool.address,
+        vec![uniswap_v2::SYNC_EVENT_SIGNATURE],
+        &[
+            Token::Uint(U256::from(pool.reserve_0 + 10_u128.pow(9))),
+            Token::Uint(U256::from(pool.reserve_1 - 6 * 10_u128.pow(17))),
+        ],
+    );
+
+    bench_log(c, "uniswap_v2_sync", &pool, &sync);
+}
+
+fn uniswap_v3(c: &mut Criterion) {
+    let pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)
+        .expect("could not deserialize the Uniswap V3 fixture");
+
+    //A position around the current tick, both of its ticks are initialized in the fixture
+    let tick_lower = H256::from_low_u64_be(200_940);
+    let tick_upper = H256::from_low_u64_be(201_060);
+    let owner = H256::from(H160::from_low_u64_be(4));
+
+    let swap = log(
+        pool.address,
+        vec![uniswap_v3::SWAP_EVENT_SIGNATURE, owner, owner],
+        &[
+            Token::Int(I256::from(10_i64.pow(9)).into_raw()),
+            Token::Int(I256::from(-6 * 10_i64.pow(17)).into_raw()),
+            Token::Uint(pool.sqrt_price),
+            Token::Uint(U256::from(pool.liquidity)),
+            Token::Int(I256::from(pool.tick).into_raw()),
+        ],
+    );
+
+    let mint = log(
+        pool.address,
+        vec![
+            uniswap_v3::MINT_EVENT_SIGNATURE,
+            owner,
+            tick_lower,
+            tick_upper,
+        ],
+        &[
+            Token::Address(H160::from_low_u64_be(4)),
+            Token::Uint(U256::exp10(15)),
+            Token::Uint(U256::exp10(9)),
+            Token::Uint(U256::exp10(17)),
+        ],
+    );
+
+    let burn = log(
+        pool.address,
+        vec![
+            uniswap_v3::BURN_EVENT_SIGNATURE,
+            owner,
+            tick_lower,
+            tick_upper,
+        ],
+        &[
+            Token::Uint(U256::exp10(12)),
+            Token::Uint(U256::exp10(6)),
+            Token::Uint(U256::exp10(14)),
+        ],
+    );
+
+    bench_log(c, "uniswap_v3_swap", &pool, &swap);
+    bench_log(c, "uniswap_v3_mint", &pool, &mint);
+    bench_log(c, "uniswap_v3_burn", &pool, &burn);
+}
+
+fn erc_4626(c: &mut Criterion) {
+    let vault = ERC4626Vault {
+        vault_token: H160::from_low_u64_be(4),
+        asset_token: H160::from_low_u64_be(3),
+        vault_reserve: U256::exp10(24),
+        asset_reserve: U256::exp10(24) * 21 / 20,
+        ..Default::default()
+    };
+    let account = H256::from(H160::from_low_u64_be(5));
+
+    let deposit = log(
+        vault.vault_token,
+        vec![erc_4626::DEPOSIT_EVENT_SIGNATURE, account, account],
+        &[
+            Token::Uint(U256::exp10(21) * 21 / 20),
+            Token::Uint(U256::exp10(21)),
+        ],
+    );
+
+    let withdraw = log(
+        vault.vault_token,
+        vec![
+            erc_4626::WITHDRAW_EVENT_SIGNATURE,
+            account,
+            account,
+            account,
+        ],
+        &[
+            Token::Uint(U256::exp10(21) * 21 / 20),
+            Token::Uint(U256::exp10(21)),
+        ],
+    );
+
+    bench_log(c, "erc_4626_deposit", &vault, &deposit);
+    bench_log(c, "erc_4626_withdraw", &vault, &withdraw);
+}
+
+//Factory discovery decodes every creation log in the history of the factory
+fn pool_created(c: &mut Criterion) {
+    let pool_created = log(
+        H160::from_low_u64_be(100),
+        vec![
+            POOL_CREATED_EVENT_SIGNATURE,
+            H256::from(H160::from_low_u64_be(2)),
+            H256::from(H160::from_low_u64_be(3)),
+            H256::from_low_u64_be(3000),
+        ],
+        &[
+            Token::Int(I256::from(60).into_raw()),
+            Token::Address(H160::from_low_u64_be(1)),
+        ],
+    );
+
+    let mut group = c.benchmark_group("uniswap_v3_pool_created");
+
+    //Decoding through ethabi takes the log by value, so it is cloned outside of the timed section
+    group.bench_function("abi", |b| {
+        b.iter_batched(
+            || RawLog::from(pool_created.clone()),
+            |raw_log| PoolCreatedFilter::decode_log(&raw_log).expect("could not decode log"),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("sliced", |b| {
+        b.iter(|| decode_pool_created_log(black_box(&pool_created)).expect("could not decode log"))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, uniswap_v2, uniswap_v3, erc_4626, pool_created);
+criterion_main!(benches);
@@ -0,0 +1,267 @@
+; Runtime code of the transfer tax probe, placed at addresses through eth_call state overrides.
+; It is small enough to be assembled by hand, PUSH2 @label pushes the offset of the label's JUMPDEST.
+;
+; probeTransfer(address token, address to, uint256 amount, address next) 0x2350213a
+;   Transfers `amount` of `token` from this address to `to` and measures the balance of `to` before and after.
+;   A zero `amount` transfers the whole balance of this address.
+;   If `next` is not zero and the transfer succeeded, `to` (which must also run this code) is called to transfer
+;   the received amount on to `next`. Returns (ok, before, after, next_ok, next_before, next_after).
+;
+; probeTransfers() 0x00e85734
+;   Calldata is the selector followed by tasks of five words (holder, token, to, amount, next). Each task calls
+;   probeTransfer on the holder and appends its six return words to the output, or six zero words if it reverted.
+;
+; Any other calldata is delegated to the code at the bitwise complement of this address, so holders keep behaving
+; like the original contract if its code is moved there.
+
+    PUSH1 0x00
+    CALLDATALOAD
+    PUSH1 0xe0
+    SHR
+    DUP1
+    PUSH4 0x2350213a
+    EQ
+    PUSH2 @single
+    JUMPI
+    PUSH4 0x00e85734
+    EQ
+    PUSH2 @batch
+    JUMPI
+
+; Delegate to the original code
+    CALLDATASIZE
+    PUSH1 0x00
+    PUSH1 0x00
+    CALLDATACOPY
+    PUSH1 0x00
+    PUSH1 0x00
+    CALLDATASIZE
+    PUSH1 0x00
+    PUSH20 0xffffffffffffffffffffffffffffffffffffffff
+    ADDRESS
+    XOR
+    GAS
+    DELEGATECALL
+    RETURNDATASIZE
+    PUSH1 0x00
+    PUSH1 0x00
+    RETURNDATACOPY
+    PUSH2 @delegated
+    JUMPI
+    RETURNDATASIZE
+    PUSH1 0x00
+    REVERT
+delegated:
+    JUMPDEST
+    RETURNDATASIZE
+    PUSH1 0x00
+    RETURN
+
+single:
+    JUMPDEST
+; mem[0x100..0x144] = transfer(to, amount), the whole balance of this address is sent if amount is zero
+    PUSH4 0xa9059cbb
+    PUSH1 0xe0
+    SHL
+    PUSH2 0x0100
+    MSTORE
+    PUSH1 0x24
+    CALLDATALOAD
+    PUSH2 0x0104
+    MSTORE
+    PUSH1 0x44
+    CALLDATALOAD
+    PUSH2 0x0124
+    MSTORE
+    PUSH1 0x44
+    CALLDATALOAD
+    PUSH2 @measure
+    JUMPI
+    PUSH4 0x70a08231
+    PUSH1 0xe0
+    SHL
+    PUSH1 0x00
+    MSTORE
+    ADDRESS
+    PUSH1 0x04
+    MSTORE
+    PUSH1 0x20
+    PUSH2 0x0124
+    PUSH1 0x24
+    PUSH1 0x00
+    PUSH1 0x04
+    CALLDATALOAD
+    GAS
+    STATICCALL
+    POP
+measure:
+    JUMPDEST
+; mem[0x00..0x24] = balanceOf(to), the balance before the transfer is written to 0x220
+    PUSH4 0x70a08231
+    PUSH1 0xe0
+    SHL
+    PUSH1 0x00
+    MSTORE
+    PUSH1 0x24
+    CALLDATALOAD
+    PUSH1 0x04
+    MSTORE
+    PUSH1 0x20
+    PUSH2 0x0220
+    PUSH1 0x24
+    PUSH1 0x00
+    PUSH1 0x04
+    CALLDATALOAD
+    GAS
+    STATICCALL
+    POP
+    PUSH1 0x20
+    PUSH2 0x0300
+    PUSH1 0x44
+    PUSH2 0x0100
+    PUSH1 0x00
+    PUSH1 0x04
+    CALLDATALOAD
+    GAS
+    CALL
+; The transfer succeeded if the call did not revert and returned nothing or true
+    RETURNDATASIZE
+    ISZERO
+    PUSH2 0x0300
+    MLOAD
+    ISZERO
+    ISZERO
+    OR
+    AND
+    PUSH2 0x0200
+    MSTORE
+; Balance after the transfer is written to 0x240
+    PUSH1 0x20
+    PUSH2 0x0240
+    PUSH1 0x24
+    PUSH1 0x00
+    PUSH1 0x04
+    CALLDATALOAD
+    GAS
+    STATICCALL
+    POP
+; Forward the received amount to `next`
+    PUSH1 0x64
+    CALLDATALOAD
+    ISZERO
+    PUSH2 0x0200
+    MLOAD
+    ISZERO
+    OR
+    PUSH2 @done
+    JUMPI
+    PUSH4 0x2350213a
+    PUSH1 0xe0
+    SHL
+    PUSH2 0x0400
+    MSTORE
+    PUSH1 0x04
+    CALLDATALOAD
+    PUSH2 0x0404
+    MSTORE
+    PUSH1 0x64
+    CALLDATALOAD
+    PUSH2 0x0424
+    MSTORE
+    PUSH2 0x0220
+    MLOAD
+    PUSH2 0x0240
+    MLOAD
+    SUB
+    PUSH2 0x0444
+    MSTORE
+    PUSH1 0x00
+    PUSH1 0x00
+    PUSH1 0x84
+    PUSH2 0x0400
+    PUSH1 0x00
+    PUSH1 0x24
+    CALLDATALOAD
+    GAS
+    CALL
+    ISZERO
+    PUSH2 @done
+    JUMPI
+    PUSH1 0x60
+    RETURNDATASIZE
+    LT
+    PUSH2 @done
+    JUMPI
+    PUSH1 0x60
+    PUSH1 0x00
+    PUSH2 0x0260
+    RETURNDATACOPY
+done:
+    JUMPDEST
+    PUSH1 0xc0
+    PUSH2 0x0200
+    RETURN
+
+batch:
+    JUMPDEST
+; Stack holds the output pointer and the calldata pointer of the current task
+    PUSH2 0x0500
+    PUSH1 0x04
+loop:
+    JUMPDEST
+    CALLDATASIZE
+    DUP2
+    LT
+    ISZERO
+    PUSH2 @end
+    JUMPI
+    PUSH4 0x2350213a
+    PUSH1 0xe0
+    SHL
+    PUSH2 0x0400
+    MSTORE
+    PUSH1 0x80
+    DUP2
+    PUSH1 0x20
+    ADD
+    PUSH2 0x0404
+    CALLDATACOPY
+    PUSH1 0x00
+    PUSH1 0x00
+    PUSH1 0x84
+    PUSH2 0x0400
+    PUSH1 0x00
+    DUP6
+    CALLDATALOAD
+    GAS
+    CALL
+    ISZERO
+    PUSH2 @next
+    JUMPI
+    PUSH1 0xc0
+    RETURNDATASIZE
+    LT
+    PUSH2 @next
+    JUMPI
+    PUSH1 0xc0
+    PUSH1 0x00
+    DUP4
+    RETURNDATACOPY
+next:
+    JUMPDEST
+    PUSH1 0xa0
+    ADD
+    SWAP1
+    PUSH1 0xc0
+    ADD
+    SWAP1
+    PUSH2 @loop
+    JUMP
+end:
+    JUMPDEST
+    POP
+    PUSH2 0x0500
+    DUP2
+    SUB
+    PUSH2 0x0500
+    RETURN
@@ -0,0 +1,47 @@
+use alloy::{primitives::address, providers::ProviderBuilder};
+use amms::{
+    amm::{
+        factory::Factory, uniswap_v2::factory::UniswapV2Factory,
+        uniswap_v3::factory::UniswapV3Factory, AmmState,
+    },
+    interop::alloy::{middleware, ToAlloy, ToEthers},
+    sync,
+};
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    //Add rpc endpoint here:
+    let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+    let provider = ProviderBuilder::new().on_http(rpc_endpoint.parse()?);
+
+    //Requests of the crate go through the alloy provider
+    let middleware = Arc::new(middleware(provider));
+
+    let factories = vec![
+        //Add UniswapV2
+        Factory::UniswapV2Factory(UniswapV2Factory::new(
+            address!("5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f").to_ethers(),
+            2638438,
+            300,
+        )),
+        //Add UniswapV3
+        Factory::UniswapV3Factory(UniswapV3Factory::new(
+            address!("1F98431c8aD98523631AE4a59f267346ea31F984").to_ethers(),
+            185,
+        )),
+    ];
+
+    //Sync pairs
+    let (amms, last_synced_block) = sync::sync_amms(factories, middleware, None, 500).await?;
+
+    for amm in amms.iter().take(10) {
+        let tokens = amm.tokens().to_alloy();
+        println!("{} trades {:?}", amm.address().to_alloy(), tokens);
+    }
+    println!("Synced {} AMMs up to block {last_synced_block}", amms.len());
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use amms::{
+    service::{QuoteRequest, Quoter},
+    state_space::state::{initialize_state_space, StateSpaceManager},
+    sync::store::{CheckpointStore, FileCheckpointStore},
+};
+use ethers::providers::{Http, Provider, Ws};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+//Answers quote requests read from stdin, one JSON `QuoteRequest` per line, with one JSON line per request on stdout:
+//
+//{"token_in":"0xc02a...","token_out":"0xa0b8...","amount_in":"0xde0b6b3a7640000","max_hops":2}
+//
+//The state space is loaded from the checkpoint at CHECKPOINT_PATH. When ETHEREUM_RPC_ENDPOINT and
+//ETHEREUM_WS_ENDPOINT are set it follows the chain head, otherwise every quote is answered at the checkpoint block.
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let store = Arc::new(FileCheckpointStore::new(
+        std::env::var("CHECKPOINT_PATH").unwrap_or("checkpoint.json".to_string()),
+    ));
+
+    let quoter = match (
+        std::env::var("ETHEREUM_RPC_ENDPOINT"),
+        std::env::var("ETHEREUM_WS_ENDPOINT"),
+    ) {
+        (Ok(rpc_endpoint), Ok(ws_endpoint)) => {
+            let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+            let stream_middleware = Arc::new(Provider::<Ws>::connect(ws_endpoint).await?);
+
+            let state_space_manager =
+                StateSpaceManager::load(store, middleware, stream_middleware).await?;
+            let last_synced_block = state_space_manager.last_synced_block();
+
+            let quoter = Arc::new(Quoter::new(
+                &*state_space_manager.state.read().await,
+                last_synced_block,
+            ));
+
+            //Take a snapshot after each block, the listener tasks run until the process exits
+            let (amms_updated, _join_handles) = state_space_manager
+                .listen_for_state_changes(last_synced_block, 100)
+                .await?;
+            quoter.clone().listen(
+                state_space_manager.state.clone(),
+                state_space_manager.sync_progress.clone(),
+                amms_updated,
+            );
+
+            quoter
+        }
+        _ => {
+            let checkpoint = store.read().await?;
+            Arc::new(Quoter::new(
+                &initialize_state_space(checkpoint.amms),
+                checkpoint.block_number,
+            ))
+        }
+    };
+
+    let (handle, _serve_handle) = quoter.serve(100);
+
+    //Each line is quoted on its own task, so responses are written in the order they complete
+    let (responses_tx, mut responses_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(response) = responses_rx.recv().await {
+            stdout.write_all(response.as_bytes()).await?;
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await?;
+        }
+
+        Ok::<(), std::io::Error>(())
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let handle = handle.clone();
+        let responses_tx = responses_tx.clone();
+        tokio::spawn(async move {
+            let response = match serde_json::from_str::<QuoteRequest>(&line) {
+                Ok(request) => match handle.quote(request).await {
+                    Ok(response) => serde_json::to_string(&response)
+                        .unwrap_or_else(|error| error_line(&line, error)),
+                    Err(error) => error_line(&line, error),
+                },
+                Err(error) => error_line(&line, error),
+            };
+
+            let _ = responses_tx.send(response);
+        });
+    }
+
+    //Stdin is closed, wait for the requests still being quoted
+    drop(responses_tx);
+    writer.await??;
+
+    Ok(())
+}
+
+fn error_line(request: &str, error: impl std::fmt::Display) -> String {
+    serde_json::json!({ "request": request, "error": error.to_string() }).to_string()
+}
@@ -1,4 +1,4 @@
-use amms::amm::{uniswap_v2::UniswapV2Pool, AutomatedMarketMaker};
+use amms::amm::{uniswap_v2::UniswapV2Pool, AmmState};
 use ethers::{
     providers::{Http, Provider},
     types::{H160, U256},
@@ -0,0 +1,82 @@
+use std::{str::FromStr, sync::Arc};
+
+use amms::{
+    amm::{
+        factory::{self, Factory},
+        uniswap_v2::{factory::UniswapV2Factory, UniswapV2Pool},
+        AmmState, AMM,
+    },
+    state_space::state::StateSpaceManager,
+};
+use ethers::{
+    providers::{Http, Middleware, Provider, StreamExt, Ws},
+    types::H160,
+};
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+    let ws_endpoint = std::env::var("ETHEREUM_WS_ENDPOINT")?;
+
+    // Initialize middleware
+    let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+    let stream_middleware = Arc::new(Provider::<Ws>::connect(ws_endpoint).await?);
+
+    // Initialize factories
+    let factories = vec![
+        //Add UniswapV2
+        Factory::UniswapV2Factory(UniswapV2Factory::new(
+            H160::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")?,
+            2638438,
+            300,
+        )),
+        //Add Sushiswap
+        Factory::UniswapV2Factory(UniswapV2Factory::new(
+            H160::from_str("0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac")?,
+            10794229,
+            300,
+        )),
+    ];
+
+    // Seed the state space with a single pool, new pools are added as they are created.
+    // Listeners track the event signatures of the AMM variants present when they start, so seed with each variant you subscribe to.
+    let weth_usdc = AMM::UniswapV2Pool(
+        UniswapV2Pool::new_from_address(
+            H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+            300,
+            middleware.clone(),
+        )
+        .await?,
+    );
+
+    let state_space_manager = StateSpaceManager::new(
+        vec![weth_usdc],
+        middleware.clone(),
+        stream_middleware.clone(),
+    );
+
+    let last_synced_block = middleware.get_block_number().await?.as_u64();
+    let (mut state_changes, _state_space_handles) = state_space_manager
+        .listen_for_state_changes(last_synced_block, 100)
+        .await?;
+
+    tokio::spawn(async move {
+        while let Some(updated_amms) = state_changes.recv().await {
+            println!("State changes: {:?}", updated_amms);
+        }
+    });
+
+    // Track every new pool created by the factories
+    let (new_pools, _subscription_handle) =
+        factory::subscribe_new_pools(factories, stream_middleware.clone());
+    let mut new_pools = Box::pin(new_pools);
+
+    while let Some(amm) = new_pools.next().await {
+        println!("New pool: {:?}", amm.address());
+        state_space_manager.add_amms(vec![amm]).await;
+    }
+
+    Ok(())
+}
@@ -3,13 +3,13 @@ use amms::{
         factory::Factory, uniswap_v2::factory::UniswapV2Factory,
         uniswap_v3::factory::UniswapV3Factory,
     },
-    sync,
+    export, sync,
 };
 use ethers::{
     providers::{Http, Provider},
     types::H160,
 };
-use std::{str::FromStr, sync::Arc};
+use std::{fs::File, io::BufWriter, str::FromStr, sync::Arc};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -40,7 +40,23 @@ async fn main() -> eyre::Result<()> {
     ];
 
     //Sync pairs
-    sync::sync_amms(factories, provider, None, 500).await?;
+    let (amms, _) = sync::sync_amms(factories, provider, None, 500).await?;
+
+    //Pass `--export pools.jsonl` or `--export pools.csv` to write the synced pools to a flat file
+    let args = std::env::args().collect::<Vec<String>>();
+    if let Some(path) = args
+        .iter()
+        .position(|arg| arg == "--export")
+        .and_then(|i| args.get(i + 1))
+    {
+        let writer = BufWriter::new(File::create(path)?);
+        let rows = if path.ends_with(".csv") {
+            export::write_csv(&amms, writer)?
+        } else {
+            export::write_jsonl(&amms, writer)?
+        };
+        println!("Exported {rows} rows to {path}");
+    }
 
     Ok(())
 }
@@ -0,0 +1,34 @@
+use amms::{
+    amm::known_factories::{chain_preset, ARBITRUM},
+    sync::{self, store::FileCheckpointStore, SyncConfig},
+};
+use ethers::providers::{Http, Provider};
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    //Add an Arbitrum rpc endpoint here:
+    let rpc_endpoint = std::env::var("ARBITRUM_RPC_ENDPOINT")?;
+    let provider = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+    //The preset holds the deployments of the chain and log and batch steps its providers accept
+    let preset = chain_preset(ARBITRUM).expect("Arbitrum preset");
+    let factory = preset
+        .factory("Uniswap V3")
+        .expect("Uniswap V3 on Arbitrum");
+
+    //The sync fails if the provider is not on Arbitrum, and the checkpoint records the chain it was synced on
+    let config = SyncConfig::for_chain(ARBITRUM)
+        .expect("Arbitrum preset")
+        .with_checkpoint_store(Arc::new(FileCheckpointStore::new(
+            "arbitrum-uniswap-v3.json",
+        )));
+
+    let (amms, block_number, _) =
+        sync::sync_amms_with_config(vec![factory], vec![], provider, config).await?;
+    println!("Synced {} pools at block {block_number}", amms.len());
+
+    Ok(())
+}
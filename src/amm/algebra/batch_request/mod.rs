@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use ethers::{
+    providers::Middleware,
+    types::{Bytes, H160, I256, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{AlgebraPool, FEE_DENOMINATOR, TICK_SPACING};
+
+//token0()
+const TOKEN_0_SELECTOR: [u8; 4] = [13, 254, 22, 129];
+//token1()
+const TOKEN_1_SELECTOR: [u8; 4] = [210, 18, 32, 167];
+//globalState()
+const GLOBAL_STATE_SELECTOR: [u8; 4] = [231, 108, 1, 228];
+//liquidity()
+const LIQUIDITY_SELECTOR: [u8; 4] = [26, 104, 101, 2];
+//tickSpacing()
+const TICK_SPACING_SELECTOR: [u8; 4] = [208, 201, 58, 124];
+
+const POOL_SELECTORS: [[u8; 4]; 5] = [
+    TOKEN_0_SELECTOR,
+    TOKEN_1_SELECTOR,
+    GLOBAL_STATE_SELECTOR,
+    LIQUIDITY_SELECTOR,
+    TICK_SPACING_SELECTOR,
+];
+
+//Words returned by `globalState()` of Algebra 1.9 pools, which hold a fee for each direction
+const DIRECTIONAL_GLOBAL_STATE_WORDS: usize = 8;
+
+/// Reads the price, liquidity and fees of `pool` at `block_number`, erroring with `AMMError::BatchRequestError` if
+/// it is not an Algebra pool
+pub async fn get_algebra_pool_data_batch_request<M: Middleware>(
+    pool: &mut AlgebraPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address()], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address()))?;
+
+    Ok(())
+}
+
+/// Reads every Algebra pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be read
+/// as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::AlgebraPool(algebra_pool) = amm {
+            if let Some(pool) = populate_pool_data(algebra_pool.to_owned(), pool_data) {
+                *algebra_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    tokens: Option<(H160, H160)>,
+    //price, tick and the fees of each direction of `globalState`
+    global_state: Option<(U256, i32, u32, u32)>,
+    liquidity: Option<u128>,
+    //Algebra V1 pools have no `tickSpacing()`, their tick spacing is `TICK_SPACING`
+    tick_spacing: Option<i32>,
+    decimals: (Option<u8>, Option<u8>),
+}
+
+fn populate_pool_data(mut pool: AlgebraPool, pool_data: PoolData) -> Option<AlgebraPool> {
+    let (sqrt_price, tick, fee_zero_for_one, fee_one_for_zero) = pool_data.global_state?;
+
+    (pool.pool.token_a, pool.pool.token_b) = pool_data.tokens?;
+    (pool.pool.token_a_decimals, pool.pool.token_b_decimals) =
+        (pool_data.decimals.0?, pool_data.decimals.1?);
+    pool.pool.set_slot0(sqrt_price, tick).ok()?;
+    pool.pool.set_liquidity(pool_data.liquidity?).ok()?;
+    pool.pool.tick_spacing = pool_data.tick_spacing.unwrap_or(TICK_SPACING);
+    pool.pool.fee = fee_zero_for_one;
+    pool.fee_one_for_zero = fee_one_for_zero;
+    pool.pool.reset_decimal_scaling();
+
+    Some(pool)
+}
+
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| POOL_SELECTORS.map(|selector| multicall::call(*pool, selector, &[])))
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(POOL_SELECTORS.len())
+        .map(|pool_return_data| PoolData {
+            tokens: address(&pool_return_data[0]).zip(address(&pool_return_data[1])),
+            global_state: global_state(&pool_return_data[2]),
+            liquidity: word(&pool_return_data[3], 0)
+                .filter(|liquidity| *liquidity <= U256::from(u128::MAX))
+                .map(|liquidity| liquidity.as_u128()),
+            tick_spacing: int_word(&pool_return_data[4], 0).filter(|spacing| *spacing > 0),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pool_data
+        .iter()
+        .filter_map(|pool_data| pool_data.tokens)
+        .flat_map(|(token_a, token_b)| [token_a, token_b])
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+    for pool_data in pool_data.iter_mut() {
+        if let Some((token_a, token_b)) = pool_data.tokens {
+            pool_data.decimals = (
+                decimals.get(&token_a).copied(),
+                decimals.get(&token_b).copied(),
+            );
+        }
+    }
+
+    Ok(pool_data)
+}
+
+//uint160 price, int24 tick, uint16 fee, ... for Algebra V1 and uint160 price, int24 tick, uint16 feeZto,
+//uint16 feeOtz, ... for Algebra 1.9, told apart by their length
+fn global_state(return_data: &Option<Bytes>) -> Option<(U256, i32, u32, u32)> {
+    let fee = |index: usize| {
+        word(return_data, index)
+            .filter(|fee| *fee < U256::from(FEE_DENOMINATOR))
+            .map(|fee| fee.as_u32())
+    };
+
+    let fee_zero_for_one = fee(2)?;
+    let fee_one_for_zero = if return_data.as_ref()?.len() == DIRECTIONAL_GLOBAL_STATE_WORDS * 32 {
+        fee(3)?
+    } else {
+        fee_zero_for_one
+    };
+
+    Some((
+        word(return_data, 0)?,
+        int_word(return_data, 1)?,
+        fee_zero_for_one,
+        fee_one_for_zero,
+    ))
+}
+
+fn word(return_data: &Option<Bytes>, index: usize) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, index)
+}
+
+//Sign extended `int24` held in word `index`
+fn int_word(return_data: &Option<Bytes>, index: usize) -> Option<i32> {
+    let value = I256::from_raw(word(return_data, index)?);
+
+    (I256::from(-(1 << 23)) <= value && value < I256::from(1 << 23)).then(|| value.low_i32())
+}
+
+fn address(return_data: &Option<Bytes>) -> Option<H160> {
+    multicall::address_word(return_data.as_ref()?, 0).filter(|address| !address.is_zero())
+}
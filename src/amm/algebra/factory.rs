@@ -0,0 +1,277 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use ethers::{
+    prelude::abigen,
+    providers::Middleware,
+    types::{BlockNumber, Filter, Log, H160, H256, U64},
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    amm::{
+        decode,
+        factory::{AutomatedMarketMakerFactory, TASK_LIMIT},
+        uniswap_v3::{BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE},
+        AutomatedMarketMaker, AMM,
+    },
+    errors::{AMMError, EventLogError},
+};
+
+use super::{batch_request, AlgebraPool, TICK_SPACING};
+
+abigen!(
+    IAlgebraFactory,
+    r#"[
+        function poolByPair(address tokenA, address tokenB) external view returns (address pool)
+    ]"#;
+);
+
+//Pool(address indexed token0, address indexed token1, address pool)
+pub const POOL_EVENT_SIGNATURE: H256 = H256([
+    145, 204, 170, 122, 39, 129, 48, 182, 81, 104, 195, 160, 200, 211, 188, 174, 132, 207, 94, 67,
+    112, 67, 66, 189, 62, 192, 181, 158, 89, 192, 54, 219,
+]);
+
+/// Algebra pool factory, discovering its pools from their `Pool` logs. A factory deploys a single pool for each
+/// pair, the fee of which is set by the pool.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AlgebraFactory {
+    pub address: H160,
+    pub creation_block: u64,
+}
+
+impl AlgebraFactory {
+    pub fn new(address: H160, creation_block: u64) -> AlgebraFactory {
+        AlgebraFactory {
+            address,
+            creation_block,
+        }
+    }
+
+    /// Gets every pool created up to `to_block` with its ticks synced from its `Mint` and `Burn` logs, scanning
+    /// `step` blocks at a time
+    pub async fn get_all_pools_from_logs<M: 'static + Middleware>(
+        self,
+        to_block: u64,
+        step: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let mut from_block = self.creation_block;
+        let mut aggregated_amms: HashMap<H160, AMM> = HashMap::new();
+
+        tracing::info!(from_block, to_block, step, "getting all pools from logs");
+
+        let mut handles = vec![];
+
+        while from_block < to_block {
+            let middleware = middleware.clone();
+            let target_block = (from_block + step - 1).min(to_block);
+
+            let pool_filter = Filter::new()
+                .topic0(POOL_EVENT_SIGNATURE)
+                .address(self.address)
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            //Positions can only be filtered by topic, the pools emitting them are not known before the range is synced
+            let position_filter = Filter::new()
+                .topic0(vec![BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE])
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            handles.push(tokio::spawn(async move {
+                let (mut logs, position_logs) = tokio::try_join!(
+                    middleware.get_logs(&pool_filter),
+                    middleware.get_logs(&position_filter)
+                )
+                .map_err(AMMError::MiddlewareError)?;
+
+                logs.extend(position_logs);
+                logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+                Ok::<Vec<Log>, AMMError<M>>(logs)
+            }));
+
+            from_block += step;
+
+            //Here we are limiting the number of green threads that can be spun up to not have the node time out
+            if handles.len() == TASK_LIMIT {
+                self.sync_logs_from_handles(handles, &mut aggregated_amms)
+                    .await?;
+                handles = vec![];
+            }
+        }
+
+        self.sync_logs_from_handles(handles, &mut aggregated_amms)
+            .await?;
+
+        Ok(aggregated_amms.into_values().collect::<Vec<AMM>>())
+    }
+
+    //Handles resolve in the order their block ranges were spawned, so logs are applied in chronological order
+    async fn sync_logs_from_handles<M: Middleware>(
+        &self,
+        handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
+        aggregated_amms: &mut HashMap<H160, AMM>,
+    ) -> Result<(), AMMError<M>> {
+        for handle in handles {
+            for log in handle.await?? {
+                if log.block_number.is_none() {
+                    return Err(EventLogError::LogBlockNumberNotFound((&log).into()))?;
+                }
+
+                let event_signature = decode::event_signature(&log)?;
+
+                if event_signature == POOL_EVENT_SIGNATURE {
+                    if log.address == self.address {
+                        let new_pool = self.new_empty_amm_from_log(&log)?;
+                        aggregated_amms.insert(new_pool.address(), new_pool);
+                    }
+                } else if event_signature == BURN_EVENT_SIGNATURE {
+                    if let Some(AMM::AlgebraPool(pool)) = aggregated_amms.get_mut(&log.address) {
+                        pool.pool.sync_from_burn_log(&log)?;
+                    }
+                } else if event_signature == MINT_EVENT_SIGNATURE {
+                    if let Some(AMM::AlgebraPool(pool)) = aggregated_amms.get_mut(&log.address) {
+                        pool.pool.sync_from_mint_log(&log)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the pool created by a `Pool` log, with a fee of zero until it is populated and the tick spacing of
+/// Algebra V1
+pub fn decode_pool_log(log: &Log) -> Result<AlgebraPool, EventLogError> {
+    if decode::event_signature(log)? != POOL_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    Ok(AlgebraPool::new(
+        decode::data_address(log, 0)?,
+        decode::topic_address(log, 1)?,
+        0,
+        decode::topic_address(log, 2)?,
+        0,
+        0,
+        TICK_SPACING,
+    ))
+}
+
+#[async_trait]
+impl AutomatedMarketMakerFactory for AlgebraFactory {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn creation_block(&self) -> u64 {
+        self.creation_block
+    }
+
+    fn amm_created_event_signature(&self) -> H256 {
+        POOL_EVENT_SIGNATURE
+    }
+
+    async fn new_amm_from_log<M: 'static + Middleware>(
+        &self,
+        log: Log,
+        middleware: Arc<M>,
+    ) -> Result<AMM, AMMError<M>> {
+        let mut amm = self.new_empty_amm_from_log(&log)?;
+        let block_number = log.block_number.map(|block_number| block_number.as_u64());
+        amm.populate_data(block_number, middleware).await?;
+
+        Ok(amm)
+    }
+
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        decode::check_address(log, self.address)?;
+        let mut pool = decode_pool_log(log)?;
+        pool.pool.creation_block = log.block_number.map(|block_number| block_number.as_u64());
+
+        Ok(AMM::AlgebraPool(pool))
+    }
+
+    async fn get_all_amms<M: 'static + Middleware>(
+        &self,
+        to_block: Option<u64>,
+        middleware: Arc<M>,
+        step: u64,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        match to_block {
+            Some(block) => self.get_all_pools_from_logs(block, step, middleware).await,
+            None => Err(AMMError::BlockNumberNotFound),
+        }
+    }
+
+    async fn populate_amm_data<M: Middleware>(
+        &self,
+        amms: &mut [AMM],
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+        _step: u64,
+    ) -> Result<(), AMMError<M>> {
+        //Multicall3 chunks its calls itself
+        batch_request::get_amm_data_batch_request(amms, block_number, middleware).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, U64},
+    };
+
+    use crate::{
+        amm::{factory::AutomatedMarketMakerFactory, AmmState, AMM},
+        errors::EventLogError,
+    };
+
+    use super::{AlgebraFactory, POOL_EVENT_SIGNATURE};
+
+    #[test]
+    fn test_new_empty_amm_from_log() -> eyre::Result<()> {
+        let factory = AlgebraFactory::new(H160::from_low_u64_be(100), 0);
+        let log = Log {
+            address: factory.address,
+            topics: vec![
+                POOL_EVENT_SIGNATURE,
+                H160::from_low_u64_be(1).into(),
+                H160::from_low_u64_be(2).into(),
+            ],
+            data: ethers::abi::encode(&[Token::Address(H160::from_low_u64_be(10))]).into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        };
+
+        match factory.new_empty_amm_from_log(&log)? {
+            AMM::AlgebraPool(pool) => {
+                assert_eq!(pool.address(), H160::from_low_u64_be(10));
+                assert_eq!(
+                    pool.tokens(),
+                    vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)]
+                );
+                assert_eq!(pool.pool.tick_spacing, 60);
+                assert_eq!(pool.pool.creation_block, Some(10));
+                assert!(!pool.data_is_populated());
+            }
+            _ => panic!("Expected a new Algebra pool"),
+        }
+
+        //Pools of other factories are rejected
+        let mut foreign_log = log;
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            factory.new_empty_amm_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+}
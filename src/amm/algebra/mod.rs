@@ -0,0 +1,432 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+#[cfg(feature = "rpc")]
+pub mod factory;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::types::{Log, H160, H256, U256};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{
+        self,
+        compat::SerdeVersion,
+        decode,
+        uniswap_v3::{
+            UniswapV3Pool, BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE, SWAP_EVENT_SIGNATURE,
+        },
+        AmmState,
+    },
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    tokens::TokenStore,
+};
+
+//Fee(uint16 fee)
+pub const FEE_EVENT_SIGNATURE: H256 = H256([
+    89, 139, 159, 4, 60, 129, 58, 166, 190, 52, 38, 202, 96, 209, 198, 93, 23, 37, 99, 18, 137, 11,
+    229, 17, 141, 171, 85, 176, 119, 94, 190, 42,
+]);
+//Fee(uint16 feeZto, uint16 feeOtz), logged by the pools of Algebra 1.9 such as Camelot V3
+pub const DIRECTIONAL_FEE_EVENT_SIGNATURE: H256 = H256([
+    138, 137, 222, 112, 133, 107, 204, 236, 9, 102, 97, 56, 143, 48, 91, 154, 117, 245, 246, 92,
+    176, 216, 160, 225, 232, 3, 195, 157, 171, 237, 181, 127,
+]);
+
+/// Tick spacing of Algebra V1 pools, which have no `tickSpacing()`
+pub const TICK_SPACING: i32 = 60;
+/// Denominator of the fees, i.e. a fee of 3000 is 0.3%
+pub const FEE_DENOMINATOR: u32 = 1_000_000;
+
+/// Algebra pool, as deployed by QuickSwap V3 and Camelot V3.
+///
+/// The concentrated liquidity of the pool follows Uniswap V3 and is held in `pool`, and its `Swap`, `Mint` and
+/// `Burn` logs are those of V3. The fee is dynamic, it is read from `globalState()` rather than set by the factory
+/// and follows the `Fee` logs of the pool. Algebra 1.9 pools charge a fee for each direction.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AlgebraPool {
+    /// Liquidity, price and ticks of the pool with the fee of swaps of token a for token b as `fee`
+    pub pool: UniswapV3Pool,
+    /// Fee over 1e6 of swaps of token b for token a, the fee of `pool` on pools with a single fee
+    pub fee_one_for_zero: u32,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+impl AmmState for AlgebraPool {
+    fn address(&self) -> H160 {
+        self.pool.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        self.pool.tokens()
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        self.pool.token_symbol(token)
+    }
+
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        self.pool.calculate_price(base_token)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![
+            SWAP_EVENT_SIGNATURE,
+            MINT_EVENT_SIGNATURE,
+            BURN_EVENT_SIGNATURE,
+            FEE_EVENT_SIGNATURE,
+            DIRECTIONAL_FEE_EVENT_SIGNATURE,
+        ]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.pool.address)?;
+        let event_signature = decode::event_signature(log)?;
+
+        if event_signature == FEE_EVENT_SIGNATURE {
+            let fee = decode::data_uint(log, 0, 16)?.low_u32();
+            self.set_fees(fee, fee);
+            Ok(())
+        } else if event_signature == DIRECTIONAL_FEE_EVENT_SIGNATURE {
+            self.set_fees(
+                decode::data_uint(log, 0, 16)?.low_u32(),
+                decode::data_uint(log, 1, 16)?.low_u32(),
+            );
+            Ok(())
+        } else {
+            self.pool.sync_from_log(log)
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::Algebra.name(),
+            &[
+                U256::from_big_endian(self.pool.state_fingerprint().as_bytes()),
+                U256::from(self.pool.fee),
+                U256::from(self.fee_one_for_zero),
+            ],
+        )
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        let swap_fee = self.check_swap(token_in)?;
+        if amount_in.is_zero() {
+            return Ok(U256::zero());
+        }
+
+        let current_state = self
+            .pool
+            .simulate_swap_state(token_in, amount_in, swap_fee)?;
+
+        Ok((-current_state.amount_calculated).into_raw())
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let swap_fee = self.check_swap(token_in)?;
+        if amount_in.is_zero() {
+            return Ok(U256::zero());
+        }
+
+        let current_state = self
+            .pool
+            .simulate_swap_state(token_in, amount_in, swap_fee)?;
+
+        self.pool.liquidity = current_state.liquidity;
+        self.pool.sqrt_price = current_state.sqrt_price_x_96;
+        self.pool.tick = current_state.tick;
+
+        Ok((-current_state.amount_calculated).into_raw())
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        self.pool.get_token_out(token_in)
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        self.pool.opp_token(token)
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for AlgebraPool {
+    //Reads the fee again along with the price and liquidity
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_algebra_pool_data_batch_request(self, None, middleware).await
+    }
+
+    //Ticks are not read, see `populate_tick_data`
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.pool.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_algebra_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl AlgebraPool {
+    /// Pool charging `fee` over 1e6 in both directions, before its price and liquidity are set
+    pub fn new(
+        address: H160,
+        token_a: H160,
+        token_a_decimals: u8,
+        token_b: H160,
+        token_b_decimals: u8,
+        fee: u32,
+        tick_spacing: i32,
+    ) -> AlgebraPool {
+        let mut pool = UniswapV3Pool {
+            address,
+            token_a,
+            token_a_decimals,
+            token_b,
+            token_b_decimals,
+            fee,
+            tick_spacing,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        };
+        pool.reset_decimal_scaling();
+
+        AlgebraPool {
+            pool,
+            fee_one_for_zero: fee,
+            serde_version: SerdeVersion::CURRENT,
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.pool.token_a.is_zero()
+            || self.pool.token_b.is_zero()
+            || self.pool.sqrt_price.is_zero())
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.pool.token_a {
+            Some(self.pool.token_a_decimals)
+        } else if token == self.pool.token_b {
+            Some(self.pool.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        self.pool.set_token_metadata(tokens);
+    }
+
+    /// Fee over 1e6 charged on swaps in the direction of `zero_for_one`
+    pub fn swap_fee(&self, zero_for_one: bool) -> u32 {
+        if zero_for_one {
+            self.pool.fee
+        } else {
+            self.fee_one_for_zero
+        }
+    }
+
+    /// Syncs the ticks of the pool from its `Mint` and `Burn` logs, from `from_block` to the current block, returning
+    /// the current block
+    #[cfg(feature = "rpc")]
+    pub async fn populate_tick_data<M: 'static + Middleware>(
+        &mut self,
+        from_block: u64,
+        middleware: Arc<M>,
+    ) -> Result<u64, AMMError<M>> {
+        self.pool.populate_tick_data(from_block, middleware).await
+    }
+
+    //Fees of a log are uint16, so they are always below the denominator
+    fn set_fees(&mut self, fee_zero_for_one: u32, fee_one_for_zero: u32) {
+        self.pool.fee = fee_zero_for_one;
+        self.fee_one_for_zero = fee_one_for_zero;
+    }
+
+    //The fee of the direction of `token_in`, if the pool holds it
+    fn check_swap(&self, token_in: H160) -> Result<u32, SwapSimulationError> {
+        if self.opp_token(token_in).is_none() {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        }
+
+        Ok(self.swap_fee(token_in == self.pool.token_a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, H256, I256, U256},
+    };
+
+    use crate::{
+        amm::{uniswap_v3::UniswapV3Pool, AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{
+        AlgebraPool, DIRECTIONAL_FEE_EVENT_SIGNATURE, FEE_EVENT_SIGNATURE, SWAP_EVENT_SIGNATURE,
+        TICK_SPACING,
+    };
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    //Price of 1 with the liquidity spread over the whole bitmap window, as `single_range_pool` of V3
+    fn single_range_pool(fee: u32) -> AlgebraPool {
+        let mut pool = AlgebraPool::new(token(100), token(1), 18, token(2), 18, fee, TICK_SPACING);
+        pool.pool
+            .set_liquidity(10_u128.pow(24))
+            .expect("valid liquidity");
+        pool.pool
+            .set_slot0(
+                uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(0).expect("valid tick"),
+                0,
+            )
+            .expect("valid slot0");
+
+        pool
+    }
+
+    fn v3_pool(fee: u32) -> UniswapV3Pool {
+        UniswapV3Pool::builder()
+            .with_token_a(token(1), 18)
+            .with_token_b(token(2), 18)
+            .with_liquidity(10_u128.pow(24))
+            .with_tick(0)
+            .with_fee(fee)
+            .with_tick_spacing(TICK_SPACING)
+            .build()
+            .expect("valid pool")
+    }
+
+    fn fee_log(pool: &AlgebraPool, fees: &[u32]) -> Log {
+        let (event_signature, fees) = match fees {
+            [fee] => (FEE_EVENT_SIGNATURE, vec![Token::Uint(U256::from(*fee))]),
+            _ => (
+                DIRECTIONAL_FEE_EVENT_SIGNATURE,
+                fees.iter()
+                    .map(|fee| Token::Uint(U256::from(*fee)))
+                    .collect(),
+            ),
+        };
+
+        Log {
+            address: pool.address(),
+            topics: vec![event_signature],
+            data: ethers::abi::encode(&fees).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let amount_in = U256::exp10(21);
+
+        //With a single fee, pools swap as V3 pools of the same fee
+        let mut pool = single_range_pool(500);
+        for token_in in [token(1), token(2)] {
+            assert_eq!(
+                pool.simulate_swap(token_in, amount_in)?,
+                v3_pool(500).simulate_swap(token_in, amount_in)?
+            );
+        }
+
+        //Each direction is charged its own fee
+        pool.fee_one_for_zero = 2500;
+        assert_eq!(
+            pool.simulate_swap(token(1), amount_in)?,
+            v3_pool(500).simulate_swap(token(1), amount_in)?
+        );
+        assert_eq!(
+            pool.simulate_swap(token(2), amount_in)?,
+            v3_pool(2500).simulate_swap(token(2), amount_in)?
+        );
+
+        let mut v3_pool = v3_pool(2500);
+        assert_eq!(
+            pool.simulate_swap_mut(token(2), amount_in)?,
+            v3_pool.simulate_swap_mut(token(2), amount_in)?
+        );
+        assert_eq!(
+            (pool.pool.sqrt_price, pool.pool.tick, pool.pool.liquidity),
+            (v3_pool.sqrt_price, v3_pool.tick, v3_pool.liquidity)
+        );
+
+        assert_eq!(pool.simulate_swap(token(1), U256::zero())?, U256::zero());
+        assert!(matches!(
+            pool.simulate_swap(token(3), amount_in),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = single_range_pool(500);
+        let mut amm = AMM::AlgebraPool(pool.clone());
+
+        //Swap logs are those of V3
+        let sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(-600)?;
+        let swap_log = Log {
+            address: pool.address(),
+            topics: vec![
+                SWAP_EVENT_SIGNATURE,
+                H256::from(token(7)),
+                H256::from(token(7)),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(1000).into_raw()),
+                Token::Int(I256::from(-1000).into_raw()),
+                Token::Uint(sqrt_price),
+                Token::Uint(U256::from(10_u128.pow(23))),
+                Token::Int(I256::from(-600).into_raw()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&swap_log)?;
+        amm.sync_from_log(&swap_log)?;
+        assert_eq!(
+            (pool.pool.sqrt_price, pool.pool.tick, pool.pool.liquidity),
+            (sqrt_price, -600, 10_u128.pow(23))
+        );
+        assert!(AMM::AlgebraPool(pool.clone()).state_eq(&amm));
+
+        //The dynamic fee follows the fee logs
+        pool.sync_from_log(&fee_log(&pool, &[1200]))?;
+        assert_eq!((pool.swap_fee(true), pool.swap_fee(false)), (1200, 1200));
+        assert!(!AMM::AlgebraPool(pool.clone()).state_eq(&amm));
+
+        pool.sync_from_log(&fee_log(&pool, &[100, 3000]))?;
+        assert_eq!((pool.swap_fee(true), pool.swap_fee(false)), (100, 3000));
+
+        let mut foreign_log = fee_log(&pool, &[1200]);
+        foreign_log.address = token(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+}
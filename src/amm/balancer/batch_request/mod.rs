@@ -0,0 +1,226 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::{
+    abi::{ParamType, Token},
+    providers::Middleware,
+    types::{Bytes, H160, H256, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::BalancerWeightedPool;
+
+//getPoolId()
+const GET_POOL_ID_SELECTOR: [u8; 4] = [56, 255, 242, 208];
+//getVault()
+const GET_VAULT_SELECTOR: [u8; 4] = [141, 146, 138, 248];
+//getNormalizedWeights()
+const GET_NORMALIZED_WEIGHTS_SELECTOR: [u8; 4] = [248, 159, 39, 237];
+//getSwapFeePercentage()
+const GET_SWAP_FEE_PERCENTAGE_SELECTOR: [u8; 4] = [85, 198, 118, 40];
+//getPoolTokens(bytes32), called on the Vault
+const GET_POOL_TOKENS_SELECTOR: [u8; 4] = [249, 77, 70, 104];
+
+const POOL_SELECTORS: [[u8; 4]; 4] = [
+    GET_POOL_ID_SELECTOR,
+    GET_VAULT_SELECTOR,
+    GET_NORMALIZED_WEIGHTS_SELECTOR,
+    GET_SWAP_FEE_PERCENTAGE_SELECTOR,
+];
+
+/// Reads `pool` at `block_number`, erroring with `AMMError::BatchRequestError` if it is not a weighted pool
+pub async fn get_balancer_pool_data_batch_request<M: Middleware>(
+    pool: &mut BalancerWeightedPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Balancer pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be
+/// read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::BalancerWeightedPool(balancer_pool) = amm {
+            if let Some(pool) = populate_pool_data(balancer_pool.to_owned(), pool_data) {
+                *balancer_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    pool_id: Option<H256>,
+    vault: Option<H160>,
+    weights: Option<Vec<U256>>,
+    swap_fee: Option<U256>,
+    tokens: Option<(Vec<H160>, Vec<U256>)>,
+    decimals: Vec<Option<u8>>,
+}
+
+fn populate_pool_data(
+    mut pool: BalancerWeightedPool,
+    pool_data: PoolData,
+) -> Option<BalancerWeightedPool> {
+    let (tokens, balances) = pool_data.tokens?;
+    let weights = pool_data.weights?;
+    if tokens.len() < 2 || weights.len() != tokens.len() {
+        return None;
+    }
+
+    pool.pool_id = pool_data.pool_id?;
+    pool.vault = pool_data.vault?;
+    pool.swap_fee = pool_data.swap_fee?;
+    pool.decimals = pool_data.decimals.into_iter().collect::<Option<_>>()?;
+    pool.tokens = tokens;
+    pool.balances = balances;
+    pool.weights = weights;
+    pool.token_symbols.resize(pool.tokens.len(), None);
+    pool.token_names.resize(pool.tokens.len(), None);
+
+    Some(pool)
+}
+
+//Reads the pool id, Vault, weights and fee of each pool, then the tokens and balances of every pool from its Vault
+//and the decimals of the tokens, in one multicall each
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| POOL_SELECTORS.map(|selector| multicall::call(*pool, selector, &[])))
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(POOL_SELECTORS.len())
+        .map(|pool_return_data| PoolData {
+            pool_id: pool_return_data[0]
+                .as_ref()
+                .and_then(|return_data| return_data.get(..32))
+                .map(H256::from_slice),
+            vault: pool_return_data[1]
+                .as_ref()
+                .and_then(|return_data| multicall::address_word(return_data, 0)),
+            weights: pool_return_data[2].as_ref().and_then(decode_weights),
+            swap_fee: word(&pool_return_data[3]),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    //Only pools that returned a pool id and a Vault are looked up in their Vault
+    let vault_reads = pool_data
+        .iter()
+        .enumerate()
+        .filter_map(|(i, data)| Some((i, data.vault?, data.pool_id?)))
+        .collect::<Vec<_>>();
+    let calls = vault_reads
+        .iter()
+        .map(|(_, vault, pool_id)| {
+            multicall::call(*vault, GET_POOL_TOKENS_SELECTOR, pool_id.as_bytes())
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    for ((i, _, _), return_data) in vault_reads.iter().zip(return_data) {
+        pool_data[*i].tokens = return_data.as_ref().and_then(decode_pool_tokens);
+    }
+
+    let tokens = pool_data
+        .iter()
+        .filter_map(|data| data.tokens.as_ref())
+        .flat_map(|(tokens, _)| tokens.iter().copied())
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+
+    for data in pool_data.iter_mut() {
+        data.decimals = token_decimals(data, &decimals);
+    }
+
+    Ok(pool_data)
+}
+
+fn token_decimals(pool_data: &PoolData, decimals: &HashMap<H160, u8>) -> Vec<Option<u8>> {
+    match &pool_data.tokens {
+        Some((tokens, _)) => tokens
+            .iter()
+            .map(|token| decimals.get(token).copied())
+            .collect(),
+        None => vec![],
+    }
+}
+
+//uint256[] weights
+fn decode_weights(return_data: &Bytes) -> Option<Vec<U256>> {
+    let tokens = ethers::abi::decode(
+        &[ParamType::Array(Box::new(ParamType::Uint(256)))],
+        return_data,
+    )
+    .ok()?;
+
+    match tokens.as_slice() {
+        [Token::Array(weights)] => weights
+            .iter()
+            .map(|weight| weight.clone().into_uint())
+            .collect(),
+        _ => None,
+    }
+}
+
+//address[] tokens, uint256[] balances, uint256 lastChangeBlock
+fn decode_pool_tokens(return_data: &Bytes) -> Option<(Vec<H160>, Vec<U256>)> {
+    let tokens = ethers::abi::decode(
+        &[
+            ParamType::Array(Box::new(ParamType::Address)),
+            ParamType::Array(Box::new(ParamType::Uint(256))),
+            ParamType::Uint(256),
+        ],
+        return_data,
+    )
+    .ok()?;
+
+    match tokens.as_slice() {
+        [Token::Array(tokens), Token::Array(balances), _] if tokens.len() == balances.len() => {
+            Some((
+                tokens
+                    .iter()
+                    .map(|token| token.clone().into_address())
+                    .collect::<Option<_>>()?,
+                balances
+                    .iter()
+                    .map(|balance| balance.clone().into_uint())
+                    .collect::<Option<_>>()?,
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn word(return_data: &Option<Bytes>) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, 0)
+}
@@ -0,0 +1,764 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::{
+    abi::{ParamType, Token},
+    types::{Log, H160, H256, I256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{fixed_point::u256_to_f64, mul_div, mul_div_rounding_up},
+    tokens::TokenStore,
+};
+
+//Swap(bytes32 indexed poolId, address indexed tokenIn, address indexed tokenOut, uint256 amountIn, uint256 amountOut)
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    33, 112, 199, 65, 196, 21, 49, 174, 194, 14, 124, 16, 124, 36, 238, 207, 221, 21, 230, 156,
+    155, 176, 168, 221, 55, 177, 132, 11, 158, 11, 32, 123,
+]);
+//PoolBalanceChanged(bytes32 indexed poolId, address indexed liquidityProvider, address[] tokens, int256[] deltas, uint256[] protocolFeeAmounts)
+pub const POOL_BALANCE_CHANGED_EVENT_SIGNATURE: H256 = H256([
+    229, 206, 36, 144, 135, 206, 4, 240, 90, 149, 113, 146, 67, 84, 0, 253, 151, 134, 141, 186, 14,
+    106, 75, 76, 4, 154, 191, 138, 248, 13, 174, 120,
+]);
+/// Events of the Vault naming the pool they apply to by its id in their first topic
+pub const VAULT_EVENT_SIGNATURES: [H256; 2] =
+    [SWAP_EVENT_SIGNATURE, POOL_BALANCE_CHANGED_EVENT_SIGNATURE];
+
+/// Balancer V2 Vault, deployed at the same address on every chain
+pub const VAULT_ADDRESS: H160 = H160([
+    186, 18, 34, 34, 34, 34, 141, 139, 164, 69, 149, 138, 117, 160, 112, 77, 86, 107, 242, 200,
+]);
+
+/// Scale of weights and fees, i.e. a swap fee of 3e15 is 0.3%
+pub const ONE: u128 = 1_000_000_000_000_000_000;
+//Pools revert swaps of more than 30% of the balance in
+const MAX_IN_RATIO: u128 = 300_000_000_000_000_000;
+//Error margin added by the pools to powers computed through logarithms, 1e-14
+const MAX_POW_RELATIVE_ERROR: u128 = 10_000;
+//Balances are scaled to 18 decimals before entering the weighted math
+const PRECISION_DECIMALS: u8 = 18;
+
+/// Balancer V2 weighted pool, such as the 80/20 BAL/WETH pool.
+///
+/// Balances are held and swaps are settled by the Vault, which emits the logs of every pool and names each pool
+/// by its id, so the pool syncs from the logs of `vault` carrying its `pool_id`. Swaps follow the weighted math of
+/// the pools, exactly for weight ratios of one, two and four and otherwise to within the error margin the pools
+/// add to their powers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BalancerWeightedPool {
+    pub address: H160,
+    /// Id of the pool in the Vault, the address of the pool followed by its specialization and a nonce
+    pub pool_id: H256,
+    pub vault: H160,
+    pub tokens: Vec<H160>,
+    pub decimals: Vec<u8>,
+    pub balances: Vec<U256>,
+    /// Normalized weight of each token over `ONE`, summing to `ONE`
+    pub weights: Vec<U256>,
+    /// Swap fee over `ONE`, taken from the amount in
+    pub swap_fee: U256,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_symbols: Vec<Option<String>>,
+    #[serde(default)]
+    pub token_names: Vec<Option<String>>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+impl AmmState for BalancerWeightedPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn log_address(&self) -> H160 {
+        self.vault
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        self.tokens.clone()
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        self.token_symbols.get(self.token_index(token)?)?.as_deref()
+    }
+
+    /// Spot price of a whole `base_token` in whole units of the first other token, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let quote_token = self
+            .other_tokens(base_token)
+            .first()
+            .copied()
+            .unwrap_or_default();
+
+        self.calculate_price_to(base_token, quote_token)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        VAULT_EVENT_SIGNATURES.to_vec()
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.vault)?;
+        let event_signature = decode::event_signature(log)?;
+        decode::check_pool_id(log, self.pool_id)?;
+
+        if event_signature == SWAP_EVENT_SIGNATURE {
+            self.sync_from_swap_log(log)
+        } else if event_signature == POOL_BALANCE_CHANGED_EVENT_SIGNATURE {
+            self.sync_from_pool_balance_changed_log(log)
+        } else {
+            Err(EventLogError::UnexpectedEvent(log.into()))
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        let words = [self.swap_fee]
+            .into_iter()
+            .chain(self.weights.iter().copied())
+            .chain(self.balances.iter().copied())
+            .collect::<Vec<U256>>();
+
+        amm::state_fingerprint(Protocol::BalancerWeighted.name(), &words)
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        let token_out = self.swap_token_out(token_in)?;
+        self.simulate_swap_to(token_in, token_out, amount_in)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let token_out = self.swap_token_out(token_in)?;
+        self.simulate_swap_to_mut(token_in, token_out, amount_in)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        self.other_tokens(token_in)
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if self.tokens.len() != 2 {
+            return None;
+        }
+
+        match self.token_index(token)? {
+            0 => Some(self.tokens[1]),
+            _ => Some(self.tokens[0]),
+        }
+    }
+
+    fn simulate_swap_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let (i, j) = self.swap_indices(token_in, token_out)?;
+        self.out_given_in(i, j, amount_in)
+    }
+
+    fn simulate_swap_to_mut(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let (i, j) = self.swap_indices(token_in, token_out)?;
+        let amount_out = self.out_given_in(i, j, amount_in)?;
+
+        //The fee stays in the pool, so its balance grows by the whole amount in
+        self.balances[i] += amount_in;
+        self.balances[j] = self.balances[j].saturating_sub(amount_out);
+
+        Ok(amount_out)
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for BalancerWeightedPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_balancer_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_balancer_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl BalancerWeightedPool {
+    pub fn new(
+        address: H160,
+        pool_id: H256,
+        tokens: Vec<H160>,
+        decimals: Vec<u8>,
+        balances: Vec<U256>,
+        weights: Vec<U256>,
+        swap_fee: U256,
+    ) -> BalancerWeightedPool {
+        BalancerWeightedPool {
+            address,
+            pool_id,
+            vault: VAULT_ADDRESS,
+            token_symbols: vec![None; tokens.len()],
+            token_names: vec![None; tokens.len()],
+            tokens,
+            decimals,
+            balances,
+            weights,
+            swap_fee,
+            serde_version: SerdeVersion::CURRENT,
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        self.tokens.len() >= 2
+            && self.holds_balances()
+            && pool_address(self.pool_id) == self.address
+            && !self.vault.is_zero()
+            && !self.tokens.iter().any(H160::is_zero)
+            && !self.balances.iter().any(U256::is_zero)
+            && !self.weights.iter().any(U256::is_zero)
+    }
+
+    /// Index of `token` in the tokens of the pool
+    pub fn token_index(&self, token: H160) -> Option<usize> {
+        self.tokens
+            .iter()
+            .position(|pool_token| *pool_token == token)
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        self.decimals.get(self.token_index(token)?).copied()
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        self.token_symbols.resize(self.tokens.len(), None);
+        self.token_names.resize(self.tokens.len(), None);
+
+        for (i, token) in self.tokens.iter().enumerate() {
+            if let Some(info) = tokens.get(*token) {
+                self.token_symbols[i] = info.symbol.or(self.token_symbols[i].take());
+                self.token_names[i] = info.name.or(self.token_names[i].take());
+            }
+        }
+    }
+
+    /// Spot price of a whole `base_token` in whole units of `quote_token`, without the fee. Each token is worth
+    /// its weight over its balance, so the price is `(balance_quote / weight_quote) / (balance_base / weight_base)`.
+    pub fn calculate_price_to(
+        &self,
+        base_token: H160,
+        quote_token: H160,
+    ) -> Result<f64, ArithmeticError> {
+        let (i, j) = match (self.token_index(base_token), self.token_index(quote_token)) {
+            (Some(i), Some(j)) if self.holds_balances() => (i, j),
+            _ => return Err(ArithmeticError::DivisionByZero),
+        };
+
+        let weighted_balance = |k: usize| {
+            u256_to_f64(self.balances[k], self.decimals[k]) / u256_to_f64(self.weights[k], 18)
+        };
+
+        let price = weighted_balance(j) / weighted_balance(i);
+        if !price.is_finite() || price == 0.0 {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    /// Amount of token `j` paid out for `amount_in` of token `i`, rounded as in `onSwap` of the pool
+    pub fn out_given_in(
+        &self,
+        i: usize,
+        j: usize,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        if amount_in.is_zero() {
+            return Ok(U256::zero());
+        } else if !self.holds_balances() {
+            return Err(ArithmeticError::DivisionByZero.into());
+        }
+
+        let scale_in = self.scaling_factor(i)?;
+        let scale_out = self.scaling_factor(j)?;
+        let fee = mul_up(amount_in, self.swap_fee)?;
+        let amount_in = (amount_in - fee.min(amount_in))
+            .checked_mul(scale_in)
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        let balance_in = self.balances[i]
+            .checked_mul(scale_in)
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        let balance_out = self.balances[j]
+            .checked_mul(scale_out)
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+
+        if amount_in > mul_down(balance_in, U256::from(MAX_IN_RATIO))? {
+            return Err(SwapSimulationError::MaxInRatioExceeded(self.tokens[i]));
+        }
+
+        let base = div_up(
+            balance_in,
+            balance_in
+                .checked_add(amount_in)
+                .ok_or(ArithmeticError::MulDivOverflow)?,
+        )?;
+        let exponent = div_down(self.weights[i], self.weights[j])?;
+        let power = pow_up(base, exponent)?;
+        let amount_out = mul_down(balance_out, complement(power))?;
+
+        Ok(amount_out / scale_out)
+    }
+
+    //Skeleton pools, such as the ones rebuilt from an export, list their tokens before their balances and weights
+    fn holds_balances(&self) -> bool {
+        self.decimals.len() == self.tokens.len()
+            && self.balances.len() == self.tokens.len()
+            && self.weights.len() == self.tokens.len()
+    }
+
+    //Token swapped for `token_in` when the caller does not name one, only defined for two token pools
+    fn swap_token_out(&self, token_in: H160) -> Result<H160, SwapSimulationError> {
+        if self.token_index(token_in).is_none() {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        }
+
+        self.opp_token(token_in)
+            .ok_or(SwapSimulationError::TokenOutRequired(token_in))
+    }
+
+    fn swap_indices(
+        &self,
+        token_in: H160,
+        token_out: H160,
+    ) -> Result<(usize, usize), SwapSimulationError> {
+        let i = self
+            .token_index(token_in)
+            .ok_or(SwapSimulationError::TokenNotInPool(token_in))?;
+        let j = self
+            .token_index(token_out)
+            .filter(|j| *j != i)
+            .ok_or(SwapSimulationError::TokenNotInPool(token_out))?;
+
+        Ok((i, j))
+    }
+
+    //Multiplier scaling the amounts of token `k` to 18 decimals
+    fn scaling_factor(&self, k: usize) -> Result<U256, ArithmeticError> {
+        let decimals = self.decimals[k];
+
+        PRECISION_DECIMALS
+            .checked_sub(decimals)
+            .map(|shift| U256::exp10(shift as usize))
+            .ok_or(ArithmeticError::UnsupportedDecimals(decimals))
+    }
+
+    fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+
+        let token_in = self
+            .token_index(decode::topic_address(log, 2)?)
+            .ok_or_else(malformed_log)?;
+        let token_out = self
+            .token_index(decode::topic_address(log, 3)?)
+            .ok_or_else(malformed_log)?;
+        let amount_in = decode::data_uint(log, 0, 256)?;
+        let amount_out = decode::data_uint(log, 1, 256)?;
+        if token_in == token_out {
+            return Err(malformed_log());
+        }
+
+        //Both balances are checked before either is updated, so a bad log leaves the pool untouched
+        let balance_in = self.balances[token_in]
+            .checked_add(amount_in)
+            .ok_or_else(malformed_log)?;
+        let balance_out = self.balances[token_out]
+            .checked_sub(amount_out)
+            .ok_or_else(malformed_log)?;
+
+        self.balances[token_in] = balance_in;
+        self.balances[token_out] = balance_out;
+
+        Ok(())
+    }
+
+    //Joins and exits move `delta` of each token, less the protocol fees the Vault collects on the way
+    fn sync_from_pool_balance_changed_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+
+        let tokens = ethers::abi::decode(
+            &[
+                ParamType::Array(Box::new(ParamType::Address)),
+                ParamType::Array(Box::new(ParamType::Int(256))),
+                ParamType::Array(Box::new(ParamType::Uint(256))),
+            ],
+            &log.data,
+        )?;
+        let (tokens, deltas, protocol_fees) = match tokens.as_slice() {
+            [Token::Array(tokens), Token::Array(deltas), Token::Array(protocol_fees)]
+                if tokens.len() == deltas.len() && tokens.len() == protocol_fees.len() =>
+            {
+                (tokens, deltas, protocol_fees)
+            }
+            _ => return Err(malformed_log()),
+        };
+
+        let mut balances = self.balances.clone();
+        for ((token, delta), protocol_fee) in tokens.iter().zip(deltas).zip(protocol_fees) {
+            let (token, delta, protocol_fee) = match (token, delta, protocol_fee) {
+                (Token::Address(token), Token::Int(delta), Token::Uint(protocol_fee)) => {
+                    (*token, I256::from_raw(*delta), *protocol_fee)
+                }
+                _ => return Err(malformed_log()),
+            };
+            let k = self.token_index(token).ok_or_else(malformed_log)?;
+
+            let balance = if delta.is_negative() {
+                balances[k].checked_sub(delta.unsigned_abs())
+            } else {
+                balances[k].checked_add(delta.into_raw())
+            };
+            balances[k] = balance
+                .and_then(|balance| balance.checked_sub(protocol_fee))
+                .ok_or_else(malformed_log)?;
+        }
+
+        self.balances = balances;
+
+        Ok(())
+    }
+}
+
+/// Address of the pool of `pool_id`, held in its first 20 bytes
+pub fn pool_address(pool_id: H256) -> H160 {
+    H160::from_slice(&pool_id[..20])
+}
+
+//Fixed point arithmetic of the pools over `ONE`, rounding as the pools do
+fn mul_down(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    mul_div(a, b, U256::from(ONE))
+}
+
+fn mul_up(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    mul_div_rounding_up(a, b, U256::from(ONE))
+}
+
+fn div_down(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    if b.is_zero() {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+
+    mul_div(a, U256::from(ONE), b)
+}
+
+fn div_up(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    if b.is_zero() {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+
+    mul_div_rounding_up(a, U256::from(ONE), b)
+}
+
+fn complement(x: U256) -> U256 {
+    U256::from(ONE).saturating_sub(x)
+}
+
+//`base` to the power of `exponent`, rounded up. Squares are exact like in the pools, other exponents go through
+//logarithms in floating point, which the error margin of the pools covers.
+fn pow_up(base: U256, exponent: U256) -> Result<U256, ArithmeticError> {
+    let one = U256::from(ONE);
+
+    if exponent == one {
+        return Ok(base);
+    } else if exponent == one * 2 {
+        return mul_up(base, base);
+    } else if exponent == one * 4 {
+        let square = mul_up(base, base)?;
+        return mul_up(square, square);
+    }
+
+    let power = (u256_to_f64(exponent, 18) * u256_to_f64(base, 18).ln()).exp();
+    if !power.is_finite() || power >= u128::MAX as f64 / ONE as f64 {
+        return Err(ArithmeticError::InvalidPrice(power));
+    }
+
+    let power = U256::from((power * ONE as f64) as u128);
+    let max_error = mul_up(power, U256::from(MAX_POW_RELATIVE_ERROR))? + 1;
+
+    Ok(power + max_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, H256, I256, U256},
+    };
+
+    use crate::{
+        amm::{self, AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{
+        pool_address, BalancerWeightedPool, POOL_BALANCE_CHANGED_EVENT_SIGNATURE,
+        SWAP_EVENT_SIGNATURE, VAULT_ADDRESS,
+    };
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    //Pool id of a two token pool, its address followed by the two token specialization and a nonce
+    fn pool_id(address: H160) -> H256 {
+        let mut pool_id = [0; 32];
+        pool_id[..20].copy_from_slice(address.as_bytes());
+        pool_id[21] = 2;
+        pool_id[31] = 7;
+        H256(pool_id)
+    }
+
+    //80/20 BAL/WETH with 1M BAL, 1000 WETH and a 1% fee
+    fn bal_weth() -> BalancerWeightedPool {
+        let address = H160::from_low_u64_be(10);
+
+        BalancerWeightedPool::new(
+            address,
+            pool_id(address),
+            vec![token(1), token(2)],
+            vec![18, 18],
+            vec![U256::exp10(24), U256::exp10(21)],
+            vec![U256::exp10(17) * 8, U256::exp10(17) * 2],
+            U256::exp10(16),
+        )
+    }
+
+    //50/50 WETH/USDC with 1000 WETH, 2M USDC and a 0.3% fee
+    fn weth_usdc() -> BalancerWeightedPool {
+        let address = H160::from_low_u64_be(20);
+
+        BalancerWeightedPool::new(
+            address,
+            pool_id(address),
+            vec![token(2), token(3)],
+            vec![18, 6],
+            vec![U256::exp10(21), U256::from(2) * U256::exp10(12)],
+            vec![U256::exp10(17) * 5, U256::exp10(17) * 5],
+            U256::exp10(15) * 3,
+        )
+    }
+
+    fn swap_log(
+        pool: &BalancerWeightedPool,
+        token_in: (H160, U256),
+        token_out: (H160, U256),
+    ) -> Log {
+        Log {
+            address: VAULT_ADDRESS,
+            topics: vec![
+                SWAP_EVENT_SIGNATURE,
+                pool.pool_id,
+                token_in.0.into(),
+                token_out.0.into(),
+            ],
+            data: ethers::abi::encode(&[Token::Uint(token_in.1), Token::Uint(token_out.1)]).into(),
+            ..Default::default()
+        }
+    }
+
+    fn pool_balance_changed_log(pool: &BalancerWeightedPool, changes: &[(H160, i128, u64)]) -> Log {
+        let array = |tokens: Vec<Token>| Token::Array(tokens);
+
+        Log {
+            address: VAULT_ADDRESS,
+            topics: vec![
+                POOL_BALANCE_CHANGED_EVENT_SIGNATURE,
+                pool.pool_id,
+                H160::zero().into(),
+            ],
+            data: ethers::abi::encode(&[
+                array(changes.iter().map(|(t, _, _)| Token::Address(*t)).collect()),
+                array(
+                    changes
+                        .iter()
+                        .map(|(_, delta, _)| Token::Int(I256::from(*delta).into_raw()))
+                        .collect(),
+                ),
+                array(
+                    changes
+                        .iter()
+                        .map(|(_, _, fee)| Token::Uint(U256::from(*fee)))
+                        .collect(),
+                ),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        //A weight ratio of four is computed exactly, as in the pool
+        let pool = bal_weth();
+        assert_eq!(
+            pool.simulate_swap(token(1), U256::exp10(21))?,
+            U256::from(3950218372412315000_u128)
+        );
+
+        //A weight ratio of a quarter goes through logarithms, within the error margin of the pool
+        let bal_out = pool.simulate_swap(token(2), U256::exp10(18))?;
+        let expected = U256::from(247346972980593000000_u128);
+        assert!(bal_out.max(expected) - bal_out.min(expected) < expected / U256::exp10(13));
+
+        //Equal weights with mixed decimals
+        let pool = weth_usdc();
+        assert_eq!(
+            pool.simulate_swap(token(2), U256::exp10(18))?,
+            U256::from(1992013962)
+        );
+        assert_eq!(
+            pool.simulate_swap(token(3), U256::from(2_000_000_000))?,
+            U256::from(996006981039903000_u128)
+        );
+        assert_eq!(pool.simulate_swap(token(2), U256::zero())?, U256::zero());
+
+        //Pools reject swaps of more than 30% of the balance in
+        assert!(matches!(
+            pool.simulate_swap(token(2), U256::exp10(21)),
+            Err(SwapSimulationError::MaxInRatioExceeded(_))
+        ));
+        assert!(matches!(
+            pool.simulate_swap(token(4), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_mut() -> eyre::Result<()> {
+        let mut pool = bal_weth();
+        let quote = pool.simulate_swap(token(2), U256::exp10(18))?;
+
+        let amount_out = pool.simulate_swap_mut(token(2), U256::exp10(18))?;
+        assert_eq!(amount_out, quote);
+        assert_eq!(pool.balances[1], U256::exp10(21) + U256::exp10(18));
+        assert_eq!(pool.balances[0], U256::exp10(24) - amount_out);
+
+        //The second swap moves along the curve
+        assert!(pool.simulate_swap(token(2), U256::exp10(18))? < quote);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let pool = bal_weth();
+
+        //A BAL is worth 0.8 / 1M of the pool against 0.2 / 1000 for a WETH
+        assert!((pool.calculate_price(token(1))? - 0.004).abs() < 1e-15);
+        assert!((pool.calculate_price_to(token(2), token(1))? - 250.0).abs() < 1e-9);
+
+        //Mixed decimals are priced in whole tokens
+        assert!((weth_usdc().calculate_price(token(2))? - 2000.0).abs() < 1e-9);
+
+        //The spot price is the rate of small trades before the fee
+        let amount_out = pool.simulate_swap(token(1), U256::exp10(15))?;
+        let rate = amount_out.as_u128() as f64 / 1e15 / 0.99;
+        assert!((rate / 0.004 - 1.0).abs() < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = bal_weth();
+        let mut swapped = pool.clone();
+        let amount_out = swapped.simulate_swap_mut(token(1), U256::exp10(21))?;
+
+        //Vault logs are routed to the pool named by their pool id
+        let log = swap_log(&pool, (token(1), U256::exp10(21)), (token(2), amount_out));
+        assert_eq!(amm::log_amm_address(&log), pool.address);
+        assert_eq!(pool_address(pool.pool_id), pool.address);
+
+        let mut amm = AMM::BalancerWeightedPool(pool.clone());
+        amm.sync_from_log(&log)?;
+        pool.sync_from_log(&log)?;
+        assert_eq!(pool.balances, swapped.balances);
+        assert!(AMM::BalancerWeightedPool(pool.clone()).state_eq(&amm));
+
+        //Joins add the deltas and exits remove them, less the protocol fees
+        let balances = pool.balances.clone();
+        pool.sync_from_log(&pool_balance_changed_log(
+            &pool,
+            &[(token(1), 1000, 10), (token(2), -100, 1)],
+        ))?;
+        assert_eq!(pool.balances[0], balances[0] + 990);
+        assert_eq!(pool.balances[1], balances[1] - 101);
+
+        //Logs of other pools, of another contract or of unknown tokens leave the balances untouched
+        let balances = pool.balances.clone();
+        let other_pool = weth_usdc();
+        assert!(matches!(
+            pool.sync_from_log(&swap_log(
+                &other_pool,
+                (token(2), U256::one()),
+                (token(1), U256::one())
+            )),
+            Err(EventLogError::PoolIdMismatch { .. })
+        ));
+
+        let mut foreign_log = swap_log(&pool, (token(1), U256::one()), (token(2), U256::one()));
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        assert!(pool
+            .sync_from_log(&swap_log(
+                &pool,
+                (token(1), U256::one()),
+                (token(3), U256::one())
+            ))
+            .is_err());
+        assert!(pool
+            .sync_from_log(&pool_balance_changed_log(
+                &pool,
+                &[(token(1), 1000, 0), (token(2), -(10_i128.pow(30)), 0)],
+            ))
+            .is_err());
+        assert_eq!(pool.balances, balances);
+
+        Ok(())
+    }
+}
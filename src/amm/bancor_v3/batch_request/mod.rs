@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AMM},
+    errors::AMMError,
+};
+
+use super::{pool_address, BancorV3Pool, NATIVE_TOKEN, PPM_RESOLUTION};
+
+//tradingLiquidity(address)
+const TRADING_LIQUIDITY_SELECTOR: [u8; 4] = [142, 216, 34, 90];
+//tradingFeePPM(address)
+const TRADING_FEE_PPM_SELECTOR: [u8; 4] = [48, 205, 179, 8];
+//tradingEnabled(address)
+const TRADING_ENABLED_SELECTOR: [u8; 4] = [190, 223, 149, 37];
+//stakedBalance(address)
+const STAKED_BALANCE_SELECTOR: [u8; 4] = [96, 33, 114, 103];
+
+const POOL_SELECTORS: [[u8; 4]; 4] = [
+    TRADING_LIQUIDITY_SELECTOR,
+    TRADING_FEE_PPM_SELECTOR,
+    TRADING_ENABLED_SELECTOR,
+    STAKED_BALANCE_SELECTOR,
+];
+//The native currency has no `decimals()`
+const NATIVE_DECIMALS: u8 = 18;
+
+/// Reads `pool` at `block_number` from its BancorNetworkInfo, erroring with `AMMError::BatchRequestError` if its
+/// token has no pool
+pub async fn get_bancor_v3_pool_data_batch_request<M: Middleware>(
+    pool: &mut BancorV3Pool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data =
+        get_pool_data(&[(pool.network_info, pool.token)], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Bancor V3 pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be
+/// read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms
+        .iter()
+        .map(|amm| match amm {
+            AMM::BancorV3Pool(pool) => (pool.network_info, pool.token),
+            _ => (H160::zero(), H160::zero()),
+        })
+        .collect::<Vec<(H160, H160)>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::BancorV3Pool(bancor_pool) = amm {
+            if let Some(pool) = populate_pool_data(bancor_pool.to_owned(), pool_data) {
+                *bancor_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    //bntTradingLiquidity and baseTokenTradingLiquidity
+    trading_liquidity: Option<(u128, u128)>,
+    trading_fee_ppm: Option<u32>,
+    trading_enabled: Option<bool>,
+    staked_balance: Option<U256>,
+    decimals: Option<u8>,
+}
+
+fn populate_pool_data(mut pool: BancorV3Pool, pool_data: PoolData) -> Option<BancorV3Pool> {
+    (
+        pool.bnt_trading_liquidity,
+        pool.base_token_trading_liquidity,
+    ) = pool_data.trading_liquidity?;
+    pool.trading_fee_ppm = pool_data.trading_fee_ppm?;
+    pool.trading_enabled = pool_data.trading_enabled?;
+    pool.staked_balance = pool_data.staked_balance?;
+    pool.token_decimals = pool_data.decimals?;
+    pool.address = pool_address(pool.network, pool.token, pool.bnt);
+
+    Some(pool)
+}
+
+async fn get_pool_data<M: Middleware>(
+    pools: &[(H160, H160)],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|(network_info, token)| {
+            let args = ethers::abi::encode(&[Token::Address(*token)]);
+            POOL_SELECTORS.map(|selector| multicall::call(*network_info, selector, &args))
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(POOL_SELECTORS.len())
+        .map(|pool_return_data| PoolData {
+            trading_liquidity: word(&pool_return_data[0], 0)
+                .zip(word(&pool_return_data[0], 1))
+                .and_then(|(bnt, base_token)| {
+                    Some((u128::try_from(bnt).ok()?, u128::try_from(base_token).ok()?))
+                }),
+            trading_fee_ppm: word(&pool_return_data[1], 0)
+                .filter(|fee| *fee <= U256::from(PPM_RESOLUTION))
+                .map(|fee| fee.as_u32()),
+            trading_enabled: word(&pool_return_data[2], 0)
+                .filter(|enabled| *enabled <= U256::one())
+                .map(|enabled| !enabled.is_zero()),
+            staked_balance: word(&pool_return_data[3], 0),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pools
+        .iter()
+        .map(|(_, token)| *token)
+        .filter(|token| *token != NATIVE_TOKEN)
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+    for (pool_data, (_, token)) in pool_data.iter_mut().zip(pools) {
+        pool_data.decimals = if *token == NATIVE_TOKEN {
+            Some(NATIVE_DECIMALS)
+        } else {
+            decimals.get(token).copied()
+        };
+    }
+
+    Ok(pool_data)
+}
+
+fn word(return_data: &Option<Bytes>, index: usize) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, index)
+}
@@ -0,0 +1,687 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::{
+    abi::Token,
+    types::{Log, H160, H256, U256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::mul_div,
+    tokens::TokenStore,
+};
+
+//TokensTraded(bytes32 indexed contextId, address indexed sourceToken, address indexed targetToken, uint256 sourceAmount, uint256 targetAmount, uint256 bntAmount, uint256 targetFeeAmount, uint256 bntFeeAmount, address trader)
+pub const TOKENS_TRADED_EVENT_SIGNATURE: H256 = H256([
+    92, 2, 194, 187, 45, 29, 8, 35, 23, 235, 35, 145, 108, 162, 123, 62, 124, 41, 67, 152, 182, 0,
+    97, 162, 173, 84, 241, 195, 192, 24, 195, 24,
+]);
+
+/// Denominator of the trading fees, i.e. a fee of 2000 is 0.2%
+pub const PPM_RESOLUTION: u32 = 1_000_000;
+/// Decimals of BNT
+pub const BNT_DECIMALS: u8 = 18;
+/// Address the pools name the native currency by
+pub const NATIVE_TOKEN: H160 = H160([0xee; 20]);
+
+/// Bancor V3 pool of a token, trading it against BNT in the omnipool of the BancorNetwork.
+///
+/// Pools are not contracts of their own, every trade goes through the network and logs a `TokensTraded` for each
+/// pool it crosses, so a trade of a token for another is two hops through BNT. As the pools share the network,
+/// `address()` is derived from the network and the tokens of the pool, see `pool_address`, and `log_address()` is
+/// the network. The state is read from the BancorNetworkInfo contract.
+///
+/// Trades are synced from their amounts, the network fee taken out of the trading fee is not, so pools are read
+/// again periodically along with the trading fee and whether trading is enabled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BancorV3Pool {
+    pub address: H160,
+    /// BancorNetwork, emitting the trades of every pool
+    pub network: H160,
+    /// BancorNetworkInfo, the pools are read through
+    pub network_info: H160,
+    pub bnt: H160,
+    /// Token of the pool, the pools name them by it
+    pub token: H160,
+    pub token_decimals: u8,
+    pub bnt_trading_liquidity: u128,
+    pub base_token_trading_liquidity: u128,
+    /// Balance of the token staked in the pool, its trading liquidity being a share of it
+    pub staked_balance: U256,
+    /// Fee of trades over `PPM_RESOLUTION` of the amount out
+    pub trading_fee_ppm: u32,
+    pub trading_enabled: bool,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_symbol: Option<String>,
+    #[serde(default)]
+    pub token_name: Option<String>,
+    #[serde(default)]
+    pub bnt_symbol: Option<String>,
+    #[serde(default)]
+    pub bnt_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+//Outcome of a trade, applied by `simulate_swap_mut`
+struct TradeState {
+    amount_out: U256,
+    bnt_trading_liquidity: u128,
+    base_token_trading_liquidity: u128,
+    staked_balance: U256,
+}
+
+impl AmmState for BancorV3Pool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn log_address(&self) -> H160 {
+        self.network
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        vec![self.token, self.bnt]
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token {
+            self.token_symbol.as_deref()
+        } else if token == self.bnt {
+            self.bnt_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Price of a whole `base_token` in whole units of the other token at the trading liquidity, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let token =
+            self.base_token_trading_liquidity as f64 / 10_f64.powi(self.token_decimals as i32);
+        let bnt = self.bnt_trading_liquidity as f64 / 10_f64.powi(BNT_DECIMALS as i32);
+        let price = if base_token == self.token {
+            bnt / token
+        } else {
+            token / bnt
+        };
+
+        if !price.is_finite() || price == 0.0 {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![TOKENS_TRADED_EVENT_SIGNATURE]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.network)?;
+        if decode::event_signature(log)? != TOKENS_TRADED_EVENT_SIGNATURE {
+            return Err(EventLogError::UnexpectedEvent(log.into()));
+        }
+
+        let source_token = decode::topic_address(log, 2)?;
+        let target_token = decode::topic_address(log, 3)?;
+        let got = pool_address(self.network, source_token, target_token);
+        if got != self.address {
+            return Err(EventLogError::PoolIdMismatch {
+                expected: H256::from(self.address),
+                got: H256::from(got),
+                context: log.into(),
+            });
+        }
+
+        let source_amount = decode::data_uint(log, 0, 256)?;
+        let target_amount = decode::data_uint(log, 1, 256)?;
+        let target_fee_amount = decode::data_uint(log, 3, 256)?;
+
+        let trade = self
+            .apply_trade(
+                source_token == self.token,
+                source_amount,
+                target_amount,
+                target_fee_amount,
+            )
+            .map_err(|_| EventLogError::MalformedLog(log.into()))?;
+        self.set_trade_state(trade);
+
+        Ok(())
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::BancorV3.name(),
+            &[
+                U256::from(self.bnt_trading_liquidity),
+                U256::from(self.base_token_trading_liquidity),
+                self.staked_balance,
+                U256::from(self.trading_fee_ppm),
+                U256::from(self.trading_enabled as u8),
+            ],
+        )
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        Ok(self.trade(token_in, amount_in)?.amount_out)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let trade = self.trade(token_in, amount_in)?;
+        let amount_out = trade.amount_out;
+        self.set_trade_state(trade);
+
+        Ok(amount_out)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token == token_in {
+            self.bnt
+        } else {
+            self.token
+        }
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if token == self.token {
+            Some(self.bnt)
+        } else if token == self.bnt {
+            Some(self.token)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for BancorV3Pool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_bancor_v3_pool_data_batch_request(self, None, middleware).await
+    }
+
+    //Pools are read by their token, so the network, its info contract, BNT and the token must already be set
+    #[tracing::instrument(level = "debug", skip_all, fields(token = ?self.token), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_bancor_v3_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl BancorV3Pool {
+    /// Pool of `token` in `network` holding the given trading liquidity, with trading enabled
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        network: H160,
+        network_info: H160,
+        bnt: H160,
+        token: H160,
+        token_decimals: u8,
+        bnt_trading_liquidity: u128,
+        base_token_trading_liquidity: u128,
+        staked_balance: U256,
+        trading_fee_ppm: u32,
+    ) -> BancorV3Pool {
+        BancorV3Pool {
+            address: pool_address(network, token, bnt),
+            network,
+            network_info,
+            bnt,
+            token,
+            token_decimals,
+            bnt_trading_liquidity,
+            base_token_trading_liquidity,
+            staked_balance,
+            trading_fee_ppm,
+            trading_enabled: true,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.network.is_zero()
+            || self.network_info.is_zero()
+            || self.bnt.is_zero()
+            || self.token.is_zero()
+            || self.bnt_trading_liquidity == 0
+            || self.base_token_trading_liquidity == 0)
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.token {
+            Some(self.token_decimals)
+        } else if token == self.bnt {
+            Some(BNT_DECIMALS)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of both tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token) {
+            self.token_symbol = info.symbol.or(self.token_symbol.take());
+            self.token_name = info.name.or(self.token_name.take());
+        }
+        if let Some(info) = tokens.get(self.bnt) {
+            self.bnt_symbol = info.symbol.or(self.bnt_symbol.take());
+            self.bnt_name = info.name.or(self.bnt_name.take());
+        }
+    }
+
+    //`tradeOutputBySourceAmount` of a single hop, the constant product of the trading liquidity less the trading fee
+    //of the amount out, and the state the trade leaves
+    fn trade(&self, token_in: H160, amount_in: U256) -> Result<TradeState, SwapSimulationError> {
+        let from_token = if token_in == self.token {
+            true
+        } else if token_in == self.bnt {
+            false
+        } else {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        };
+        if !self.trading_enabled {
+            return Err(SwapSimulationError::TradingDisabled(self.token));
+        }
+
+        let (source_balance, target_balance) = if from_token {
+            (
+                self.base_token_trading_liquidity,
+                self.bnt_trading_liquidity,
+            )
+        } else {
+            (
+                self.bnt_trading_liquidity,
+                self.base_token_trading_liquidity,
+            )
+        };
+        if source_balance == 0 || target_balance == 0 {
+            return Err(ArithmeticError::DivisionByZero.into());
+        }
+
+        let target_amount = mul_div(
+            U256::from(target_balance),
+            amount_in,
+            U256::from(source_balance)
+                .checked_add(amount_in)
+                .ok_or(ArithmeticError::MulDivOverflow)?,
+        )?;
+        let trading_fee_amount = mul_div(
+            target_amount,
+            U256::from(self.trading_fee_ppm),
+            U256::from(PPM_RESOLUTION),
+        )?;
+        let amount_out = target_amount
+            .checked_sub(trading_fee_amount)
+            .ok_or(ArithmeticError::SubtractionUnderflow)?;
+
+        Ok(self.apply_trade(from_token, amount_in, amount_out, trading_fee_amount)?)
+    }
+
+    //Trading liquidity and staked balance after a trade paying `target_amount` for `source_amount`. The trading fee
+    //stays in the pool, on the token side it is staked with the providers of the pool while fees in BNT go to the
+    //BNT pool.
+    fn apply_trade(
+        &self,
+        from_token: bool,
+        source_amount: U256,
+        target_amount: U256,
+        target_fee_amount: U256,
+    ) -> Result<TradeState, ArithmeticError> {
+        let add = |balance: u128, amount: U256| {
+            u128::try_from(amount)
+                .ok()
+                .and_then(|amount| balance.checked_add(amount))
+                .ok_or(ArithmeticError::U128ConversionError)
+        };
+        let sub = |balance: u128, amount: U256| {
+            u128::try_from(amount)
+                .ok()
+                .and_then(|amount| balance.checked_sub(amount))
+                .ok_or(ArithmeticError::SubtractionUnderflow)
+        };
+
+        Ok(if from_token {
+            TradeState {
+                amount_out: target_amount,
+                bnt_trading_liquidity: sub(self.bnt_trading_liquidity, target_amount)?,
+                base_token_trading_liquidity: add(
+                    self.base_token_trading_liquidity,
+                    source_amount,
+                )?,
+                staked_balance: self.staked_balance,
+            }
+        } else {
+            TradeState {
+                amount_out: target_amount,
+                bnt_trading_liquidity: add(self.bnt_trading_liquidity, source_amount)?,
+                base_token_trading_liquidity: sub(
+                    self.base_token_trading_liquidity,
+                    target_amount,
+                )?,
+                staked_balance: self
+                    .staked_balance
+                    .checked_add(target_fee_amount)
+                    .ok_or(ArithmeticError::MulDivOverflow)?,
+            }
+        })
+    }
+
+    fn set_trade_state(&mut self, trade: TradeState) {
+        self.bnt_trading_liquidity = trade.bnt_trading_liquidity;
+        self.base_token_trading_liquidity = trade.base_token_trading_liquidity;
+        self.staked_balance = trade.staked_balance;
+    }
+}
+
+/// Address keying the pool trading `token_a` and `token_b` in `network`, the first 20 bytes of the hash of the
+/// network and both tokens in ascending order. One of the tokens is BNT, so the address of a pool is also found from
+/// the tokens of the trades it logs.
+pub fn pool_address(network: H160, token_a: H160, token_b: H160) -> H160 {
+    let (token_a, token_b) = if token_a < token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    };
+
+    H160::from_slice(
+        &keccak256(ethers::abi::encode(&[
+            Token::Address(network),
+            Token::Address(token_a),
+            Token::Address(token_b),
+        ]))[..20],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::{
+        abi::Token,
+        prelude::abigen,
+        types::{Log, H160, H256, U256},
+    };
+
+    use crate::{
+        amm::{self, AmmState, AutomatedMarketMaker, AMM},
+        errors::{EventLogError, SwapSimulationError},
+        routing::Route,
+        test_utils::ForkHarness,
+    };
+
+    use super::{pool_address, BancorV3Pool, NATIVE_TOKEN, TOKENS_TRADED_EVENT_SIGNATURE};
+
+    abigen!(
+        IBancorNetworkInfo,
+        r#"[
+            function tradeOutputBySourceAmount(address sourceToken, address targetToken, uint256 sourceAmount) external view returns (uint256)
+        ]"#;
+    );
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    //1M of an 18 decimals token worth 2 BNT, with a fee of 0.2%
+    fn pool() -> BancorV3Pool {
+        BancorV3Pool::new(
+            address(1),
+            address(2),
+            address(3),
+            address(10),
+            18,
+            2 * 10_u128.pow(24),
+            10_u128.pow(24),
+            U256::exp10(24) * 3,
+            2000,
+        )
+    }
+
+    fn tokens_traded(
+        pool: &BancorV3Pool,
+        (source_token, source_amount): (H160, U256),
+        (target_token, target_amount): (H160, U256),
+        target_fee_amount: U256,
+    ) -> Log {
+        Log {
+            address: pool.network,
+            topics: vec![
+                TOKENS_TRADED_EVENT_SIGNATURE,
+                H256::repeat_byte(1),
+                source_token.into(),
+                target_token.into(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(source_amount),
+                Token::Uint(target_amount),
+                Token::Uint(U256::zero()),
+                Token::Uint(target_fee_amount),
+                Token::Uint(U256::zero()),
+                Token::Address(address(99)),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pool_address() {
+        let pool = pool();
+
+        assert_eq!(
+            pool.address,
+            pool_address(pool.network, pool.bnt, pool.token)
+        );
+        assert_ne!(pool.address, pool_address(address(4), pool.token, pool.bnt));
+
+        //Trades in either direction are routed to the pool
+        let log = tokens_traded(
+            &pool,
+            (pool.bnt, U256::one()),
+            (pool.token, U256::one()),
+            U256::zero(),
+        );
+        assert_eq!(amm::log_amm_address(&log), pool.address);
+        assert_eq!(pool.log_address(), pool.network);
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let mut pool = pool();
+
+        //2e24 * 1e21 / (1e24 + 1e21) less 0.2%
+        let amount_out = pool.simulate_swap(pool.token, U256::exp10(21))?;
+        assert_eq!(amount_out, U256::from_dec_str("1994005994005994005995")?);
+        let amount_out = pool.simulate_swap(pool.bnt, U256::exp10(21))?;
+        assert_eq!(amount_out, U256::from_dec_str("498750624687656171914")?);
+        assert_eq!(pool.simulate_swap(pool.token, U256::zero())?, U256::zero());
+
+        assert!(matches!(
+            pool.simulate_swap(address(11), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        //The fee of trades for the token is staked with the pool
+        let staked_balance = pool.staked_balance;
+        let amount_out = pool.simulate_swap_mut(pool.bnt, U256::exp10(21))?;
+        assert_eq!(
+            pool.bnt_trading_liquidity,
+            2 * 10_u128.pow(24) + 10_u128.pow(21)
+        );
+        assert_eq!(
+            U256::from(pool.base_token_trading_liquidity),
+            U256::exp10(24) - amount_out
+        );
+        assert_eq!(
+            pool.staked_balance - staked_balance,
+            U256::from_dec_str("999500249875062468")?
+        );
+
+        //Pools with trading disabled are populated but not quoted
+        pool.trading_enabled = false;
+        assert!(matches!(
+            pool.simulate_swap(pool.token, U256::one()),
+            Err(SwapSimulationError::TradingDisabled(token)) if token == pool.token
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let mut pool = pool();
+        pool.token_decimals = 6;
+        pool.base_token_trading_liquidity = 10_u128.pow(12);
+
+        assert!((pool.calculate_price(pool.token)? - 2.0).abs() < 1e-12);
+        assert!((pool.calculate_price(pool.bnt)? - 0.5).abs() < 1e-12);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = pool();
+        let mut swapped = pool.clone();
+        let amount_out = swapped.simulate_swap_mut(pool.bnt, U256::exp10(21))?;
+        let fee = swapped.staked_balance - pool.staked_balance;
+
+        let log = tokens_traded(
+            &pool,
+            (pool.bnt, U256::exp10(21)),
+            (pool.token, amount_out),
+            fee,
+        );
+        let mut amm = AMM::BancorV3Pool(pool.clone());
+        amm.sync_from_log(&log)?;
+        pool.sync_from_log(&log)?;
+
+        assert_eq!(pool.bnt_trading_liquidity, swapped.bnt_trading_liquidity);
+        assert_eq!(
+            pool.base_token_trading_liquidity,
+            swapped.base_token_trading_liquidity
+        );
+        assert_eq!(pool.staked_balance, swapped.staked_balance);
+        assert!(AMM::BancorV3Pool(pool.clone()).state_eq(&amm));
+
+        //Trades of other pools and trades taking more than the pool holds are rejected
+        let state = pool.state_fingerprint();
+        assert!(matches!(
+            pool.sync_from_log(&tokens_traded(
+                &pool,
+                (address(11), U256::one()),
+                (pool.bnt, U256::one()),
+                U256::zero(),
+            )),
+            Err(EventLogError::PoolIdMismatch { .. })
+        ));
+        assert!(pool
+            .sync_from_log(&tokens_traded(
+                &pool,
+                (pool.token, U256::one()),
+                (pool.bnt, U256::MAX),
+                U256::zero(),
+            ))
+            .is_err());
+        assert_eq!(pool.state_fingerprint(), state);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_through_bnt() -> eyre::Result<()> {
+        let pool_a = pool();
+        let mut pool_b = pool();
+        pool_b.token = address(20);
+        pool_b.address = pool_address(pool_b.network, pool_b.token, pool_b.bnt);
+        pool_b.bnt_trading_liquidity = 10_u128.pow(24);
+        let (token_a, token_b, bnt) = (pool_a.token, pool_b.token, pool_a.bnt);
+
+        let amms = vec![AMM::BancorV3Pool(pool_a), AMM::BancorV3Pool(pool_b)];
+        let route = Route::new(vec![(0, token_a), (1, bnt)], token_b, &amms)?;
+
+        let amount_in = U256::exp10(21);
+        let bnt_amount = amms[0].simulate_swap(token_a, amount_in)?;
+        assert_eq!(
+            route.simulate(amount_in, &amms)?,
+            amms[1].simulate_swap(bnt, bnt_amount)?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_simulate_swap_against_network_info() -> eyre::Result<()> {
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
+
+        let network = H160::from_str("0xeEF417e1D5CC832e619ae18D2F140De2999dD4fB")?;
+        let network_info = H160::from_str("0x8E303D296851B320e6a697bAcB979d13c9D6E760")?;
+        let bnt = H160::from_str("0x1F573D6Fb3F13d689FF844B4cE37794d79a7FF1C")?;
+        let link = H160::from_str("0x514910771AF9Ca656af840dff83E8264EcF986CA")?;
+
+        let mut amms = vec![];
+        for token in [link, NATIVE_TOKEN] {
+            let mut pool =
+                BancorV3Pool::new(network, network_info, bnt, token, 0, 0, 0, U256::zero(), 0);
+            pool.populate_data(Some(fork.block_number), middleware.clone())
+                .await?;
+            assert!(pool.data_is_populated());
+            amms.push(AMM::BancorV3Pool(pool));
+        }
+
+        let network_info = IBancorNetworkInfo::new(network_info, middleware);
+        let trade_output = |source_token: H160, target_token: H160, amount_in: U256| {
+            network_info
+                .trade_output_by_source_amount(source_token, target_token, amount_in)
+                .block(fork.block_number)
+        };
+
+        let amount_in = U256::exp10(20);
+        assert_eq!(
+            amms[0].simulate_swap(link, amount_in)?,
+            trade_output(link, bnt, amount_in).call().await?
+        );
+        assert_eq!(
+            amms[0].simulate_swap(bnt, amount_in)?,
+            trade_output(bnt, link, amount_in).call().await?
+        );
+
+        //LINK to ETH through BNT
+        let route = Route::new(vec![(0, link), (1, bnt)], NATIVE_TOKEN, &amms)?;
+        assert_eq!(
+            route.simulate(amount_in, &amms)?,
+            trade_output(link, NATIVE_TOKEN, amount_in).call().await?
+        );
+
+        Ok(())
+    }
+}
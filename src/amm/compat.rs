@@ -0,0 +1,241 @@
+//! Serde compatibility of the pool structs, which are stored in checkpoints and storage backends.
+//!
+//! Every pool serialized by a release of the crate keeps deserializing in later releases:
+//!
+//! - Fields serialized since the first releases pin their name with `#[serde(rename)]`, so the Rust field can be
+//!   renamed without changing the stored one.
+//! - Fields added later are `#[serde(default)]`, defaulting to a value meaning unknown, i.e. `None`, or to the
+//!   behavior before the field existed.
+//! - Changes a default can not express, such as a field changing units or encoding, bump `SerdeVersion::CURRENT`
+//!   and migrate the pools read at an older version, which each pool records in its `serde_version`.
+//!
+//! Pools are always written at the current version. The fixtures in `benches/fixtures/compat` are pools as written
+//! by each version and are never edited, the tests below assert they still load and are upgraded when written
+//! again. Binary checkpoints have no field names to default from, so any change to the layout of a pool also bumps
+//! `CHECKPOINT_VERSION`.
+
+use std::fmt;
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Layout version a pool was serialized with, `SerdeVersion::LEGACY` for pools written before it was recorded.
+/// Pools are always serialized at `SerdeVersion::CURRENT`, pools written by a newer version of the crate are rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SerdeVersion(pub u16);
+
+impl SerdeVersion {
+    pub const LEGACY: SerdeVersion = SerdeVersion(0);
+    /// Version 1 added the version tag itself
+    pub const CURRENT: SerdeVersion = SerdeVersion(1);
+
+    /// Version of pools missing the tag, for `#[serde(default = "SerdeVersion::legacy")]`
+    pub fn legacy() -> Self {
+        SerdeVersion::LEGACY
+    }
+}
+
+/// Pools built in memory are at the current version
+impl Default for SerdeVersion {
+    fn default() -> Self {
+        SerdeVersion::CURRENT
+    }
+}
+
+impl fmt::Display for SerdeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for SerdeVersion {
+    //Pools read at an older version are written upgraded
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(SerdeVersion::CURRENT.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SerdeVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = SerdeVersion(u16::deserialize(deserializer)?);
+        if version > SerdeVersion::CURRENT {
+            return Err(D::Error::custom(format!(
+                "pool serde version {version} is newer than the supported version {}",
+                SerdeVersion::CURRENT
+            )));
+        }
+
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use ethers::types::{H160, U256};
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_json::Value;
+
+    use crate::amm::{
+        erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AmmState, AMM,
+    };
+
+    use super::SerdeVersion;
+
+    //Pools as written before the version tag, i.e. before and after the token metadata was added
+    const UNISWAP_V2_V0: &str =
+        include_str!("../../benches/fixtures/compat/v0/uniswap_v2_pool.json");
+    const UNISWAP_V2_V0_MINIMAL: &str =
+        include_str!("../../benches/fixtures/compat/v0/uniswap_v2_pool_minimal.json");
+    const UNISWAP_V3_V0: &str =
+        include_str!("../../benches/fixtures/compat/v0/uniswap_v3_pool.json");
+    const ERC4626_V0: &str = include_str!("../../benches/fixtures/compat/v0/erc4626_vault.json");
+    const CHECKPOINT_AMMS_V0: &str =
+        include_str!("../../benches/fixtures/compat/v0/checkpoint_amms.json");
+
+    const UNISWAP_V2_V1: &str =
+        include_str!("../../benches/fixtures/compat/v1/uniswap_v2_pool.json");
+    const UNISWAP_V3_V1: &str =
+        include_str!("../../benches/fixtures/compat/v1/uniswap_v3_pool.json");
+    const ERC4626_V1: &str = include_str!("../../benches/fixtures/compat/v1/erc4626_vault.json");
+
+    fn fields(value: &Value) -> BTreeSet<&str> {
+        value
+            .as_object()
+            .map(|object| object.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    //Asserts a pool stored as `stored` is written again at the current version, keeping every stored field
+    fn assert_upgraded(stored: &Value, written: &Value) {
+        assert!(
+            fields(stored).is_subset(&fields(written)),
+            "fields {:?} are no longer written",
+            fields(stored)
+                .difference(&fields(written))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(written["serde_version"], SerdeVersion::CURRENT.0);
+    }
+
+    //Reads a fixture, writes it again and asserts the written pool reads back the same
+    fn upgrade<T: Serialize + DeserializeOwned>(fixture: &str) -> eyre::Result<T> {
+        let stored: Value = serde_json::from_str(fixture)?;
+        let pool: T = serde_json::from_str(fixture)?;
+
+        let written = serde_json::to_value(&pool)?;
+        assert_upgraded(&stored, &written);
+
+        let read_again: T = serde_json::from_value(written.clone())?;
+        assert_eq!(serde_json::to_value(&read_again)?, written);
+
+        Ok(pool)
+    }
+
+    #[test]
+    fn test_uniswap_v2_fixtures() -> eyre::Result<()> {
+        for fixture in [UNISWAP_V2_V0, UNISWAP_V2_V0_MINIMAL, UNISWAP_V2_V1] {
+            let pool: UniswapV2Pool = upgrade(fixture)?;
+
+            assert_eq!(
+                pool.address,
+                "0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc".parse::<H160>()?
+            );
+            assert_eq!((pool.token_a_decimals, pool.token_b_decimals), (6, 18));
+            assert_eq!(pool.reserve_0, 38_593_018_243_115);
+            assert_eq!(pool.reserve_1, 9_694_514_287_432_447_276);
+            assert_eq!(pool.fee, 300);
+        }
+
+        //Fields added after the first release default to unknown
+        let minimal: UniswapV2Pool = serde_json::from_str(UNISWAP_V2_V0_MINIMAL)?;
+        assert_eq!(minimal.serde_version, SerdeVersion::LEGACY);
+        assert_eq!(minimal.fee_change_event, None);
+        assert!(minimal.token_a_transfer_tax.is_zero());
+        assert_eq!(minimal.creation_block, None);
+        assert_eq!(minimal.token_a_symbol, None);
+
+        let tagged: UniswapV2Pool = serde_json::from_str(UNISWAP_V2_V1)?;
+        assert_eq!(tagged.serde_version, SerdeVersion::CURRENT);
+        assert_eq!(tagged.creation_block, Some(10_008_355));
+        assert_eq!(tagged.token_a_symbol.as_deref(), Some("USDC"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uniswap_v3_fixtures() -> eyre::Result<()> {
+        let mut pools = vec![];
+        for fixture in [UNISWAP_V3_V0, UNISWAP_V3_V1] {
+            let pool: UniswapV3Pool = upgrade(fixture)?;
+
+            assert_eq!(
+                pool.address,
+                "0x88e6a0c2ddd26feeb64f039a2c41296fcb3f5640".parse::<H160>()?
+            );
+            assert_eq!(pool.fee, 500);
+            assert_eq!(pool.tick_spacing, 10);
+            assert_eq!(pool.ticks.len(), 3);
+            assert_eq!(pool.tick_bitmap.len(), 2);
+            pools.push(AMM::UniswapV3Pool(pool));
+        }
+
+        //The map form of the ticks written before version 1 loads to the same state as the compact form
+        assert!(pools[0].state_eq(&pools[1]));
+        assert_eq!(pools[0].state_fingerprint(), pools[1].state_fingerprint());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_erc_4626_fixtures() -> eyre::Result<()> {
+        for fixture in [ERC4626_V0, ERC4626_V1] {
+            let vault: ERC4626Vault = upgrade(fixture)?;
+
+            assert_eq!(
+                vault.vault_reserve,
+                U256::from(1_000_000_000_000_000_000_000_u128)
+            );
+            assert_eq!(vault.asset_reserve, U256::from(1_050_000_000_u64));
+            assert_eq!((vault.deposit_fee, vault.withdraw_fee), (0, 10));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_amms_fixture() -> eyre::Result<()> {
+        let stored: Vec<Value> = serde_json::from_str(CHECKPOINT_AMMS_V0)?;
+        let amms: Vec<AMM> = serde_json::from_str(CHECKPOINT_AMMS_V0)?;
+
+        //AMMs are stored under the name of their variant
+        for (stored, amm) in stored.iter().zip(amms.iter()) {
+            let written = serde_json::to_value(amm)?;
+            let variant = fields(stored)
+                .into_iter()
+                .next()
+                .expect("variant is stored");
+            assert_upgraded(&stored[variant], &written[variant]);
+        }
+
+        assert_eq!(amms.len(), 3);
+        assert!(matches!(amms[0], AMM::UniswapV2Pool(_)));
+        assert!(matches!(amms[1], AMM::UniswapV3Pool(_)));
+        assert!(matches!(amms[2], AMM::ERC4626Vault(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_version_rejected() -> eyre::Result<()> {
+        let mut pool = serde_json::to_value(UniswapV2Pool::default())?;
+        pool["serde_version"] = (SerdeVersion::CURRENT.0 + 1).into();
+
+        let error = serde_json::from_value::<UniswapV2Pool>(pool).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("is newer than the supported version"));
+
+        Ok(())
+    }
+}
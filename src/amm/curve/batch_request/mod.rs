@@ -0,0 +1,190 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{CurveStableSwapPool, MAX_COINS};
+
+//A()
+const A_SELECTOR: [u8; 4] = [244, 70, 193, 208];
+//fee()
+const FEE_SELECTOR: [u8; 4] = [221, 202, 63, 67];
+//admin_fee()
+const ADMIN_FEE_SELECTOR: [u8; 4] = [254, 227, 247, 249];
+//coins(uint256)
+const COINS_SELECTOR: [u8; 4] = [198, 97, 6, 87];
+//balances(uint256)
+const BALANCES_SELECTOR: [u8; 4] = [73, 3, 176, 209];
+//coins(int128) and balances(int128), of the first pools which index their coins by int128
+const COINS_INT128_SELECTOR: [u8; 4] = [35, 116, 110, 184];
+const BALANCES_INT128_SELECTOR: [u8; 4] = [6, 90, 128, 216];
+
+const COIN_SELECTORS: [[u8; 4]; 4] = [
+    COINS_SELECTOR,
+    COINS_INT128_SELECTOR,
+    BALANCES_SELECTOR,
+    BALANCES_INT128_SELECTOR,
+];
+//A, fee and admin fee followed by the coin selectors for every coin index
+const CALLS_PER_POOL: usize = 3 + COIN_SELECTORS.len() * MAX_COINS;
+
+//Pools holding ether list it under this address, which has no `decimals()`
+const ETH_ADDRESS: H160 = H160([0xee; 20]);
+
+/// Reads `pool` at `block_number`, erroring with `AMMError::BatchRequestError` if it is not a StableSwap pool
+pub async fn get_curve_pool_data_batch_request<M: Middleware>(
+    pool: &mut CurveStableSwapPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|return_data| populate_pool_data(pool.to_owned(), &return_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Curve pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be read
+/// as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, return_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::CurveStableSwapPool(curve_pool) = amm {
+            if let Some(pool) = populate_pool_data(curve_pool.to_owned(), &return_data) {
+                *curve_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+struct PoolData {
+    a: Option<U256>,
+    fee: Option<U256>,
+    admin_fee: Option<U256>,
+    coins: Vec<(H160, U256)>,
+    decimals: Vec<Option<u8>>,
+}
+
+fn populate_pool_data(
+    mut pool: CurveStableSwapPool,
+    pool_data: &PoolData,
+) -> Option<CurveStableSwapPool> {
+    if pool_data.coins.len() < 2 {
+        return None;
+    }
+
+    pool.a = pool_data.a?;
+    pool.fee = u64::try_from(pool_data.fee?).ok()?;
+    pool.admin_fee = u64::try_from(pool_data.admin_fee?).ok()?;
+    pool.decimals = pool_data.decimals.iter().copied().collect::<Option<_>>()?;
+    pool.coins = pool_data.coins.iter().map(|(coin, _)| *coin).collect();
+    pool.balances = pool_data
+        .coins
+        .iter()
+        .map(|(_, balance)| *balance)
+        .collect();
+    pool.coin_symbols.resize(pool.coins.len(), None);
+    pool.coin_names.resize(pool.coins.len(), None);
+
+    Some(pool)
+}
+
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| {
+            let coin_calls = (0..MAX_COINS).flat_map(move |i| {
+                let index = ethers::abi::encode(&[Token::Uint(U256::from(i))]);
+                COIN_SELECTORS.map(|selector| multicall::call(*pool, selector, &index))
+            });
+
+            [A_SELECTOR, FEE_SELECTOR, ADMIN_FEE_SELECTOR]
+                .map(|selector| multicall::call(*pool, selector, &[]))
+                .into_iter()
+                .chain(coin_calls)
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let pool_coins = return_data
+        .chunks(CALLS_PER_POOL)
+        .map(|pool_return_data| coins_from_multicall(&pool_return_data[3..]))
+        .collect::<Vec<_>>();
+    let tokens = pool_coins
+        .iter()
+        .flatten()
+        .map(|(coin, _)| *coin)
+        .filter(|coin| *coin != ETH_ADDRESS)
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+
+    Ok(return_data
+        .chunks(CALLS_PER_POOL)
+        .zip(pool_coins)
+        .map(|(pool_return_data, coins)| PoolData {
+            a: word(&pool_return_data[0]),
+            fee: word(&pool_return_data[1]),
+            admin_fee: word(&pool_return_data[2]),
+            decimals: coins
+                .iter()
+                .map(|(coin, _)| coin_decimals(*coin, &decimals))
+                .collect(),
+            coins,
+        })
+        .collect())
+}
+
+//Coins and balances of a pool, up to the first index where neither selector answers
+fn coins_from_multicall(return_data: &[Option<Bytes>]) -> Vec<(H160, U256)> {
+    return_data
+        .chunks(COIN_SELECTORS.len())
+        .map_while(|coin_return_data| {
+            let coin = coin_return_data[0]
+                .as_ref()
+                .or(coin_return_data[1].as_ref())
+                .and_then(|return_data| multicall::address_word(return_data, 0))
+                .filter(|coin| !coin.is_zero())?;
+            let balance = word(&coin_return_data[2]).or(word(&coin_return_data[3]))?;
+
+            Some((coin, balance))
+        })
+        .collect()
+}
+
+fn coin_decimals(coin: H160, decimals: &HashMap<H160, u8>) -> Option<u8> {
+    if coin == ETH_ADDRESS {
+        return Some(18);
+    }
+
+    decimals.get(&coin).copied()
+}
+
+fn word(return_data: &Option<Bytes>) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, 0)
+}
@@ -0,0 +1,697 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::types::{Log, H160, H256, U256};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{fixed_point::u256_to_f64_lossy, mul_div},
+    tokens::TokenStore,
+};
+
+//TokenExchange(address indexed buyer, int128 sold_id, uint256 tokens_sold, int128 bought_id, uint256 tokens_bought)
+pub const TOKEN_EXCHANGE_EVENT_SIGNATURE: H256 = H256([
+    139, 62, 150, 242, 184, 137, 250, 119, 28, 83, 201, 129, 180, 13, 175, 0, 95, 99, 246, 55, 241,
+    134, 159, 112, 112, 82, 209, 90, 61, 217, 113, 64,
+]);
+
+/// Denominator of `fee` and `admin_fee`, i.e. a fee of 4000000 is 0.04%
+pub const FEE_DENOMINATOR: u64 = 10_000_000_000;
+/// Largest number of coins held by a StableSwap pool
+pub const MAX_COINS: usize = 4;
+//Iterations of the Newton method of the pools before they give up
+const MAX_ITERATIONS: usize = 255;
+//Balances are normalized to 18 decimals before entering the invariant
+const PRECISION_DECIMALS: u8 = 18;
+
+/// Curve StableSwap pool of two to four coins, following the plain pools written in Vyper such as 3pool.
+///
+/// Balances are the raw balances of each coin, which are normalized to 18 decimals before entering the invariant,
+/// so coins of mixed decimals like DAI and USDT are compared one to one. Only plain pools are modeled, lending
+/// and meta pools scale their balances by rates read from other contracts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CurveStableSwapPool {
+    pub address: H160,
+    pub coins: Vec<H160>,
+    pub decimals: Vec<u8>,
+    pub balances: Vec<U256>,
+    /// Amplification coefficient, as returned by `A()`
+    pub a: U256,
+    /// Swap fee over `FEE_DENOMINATOR`
+    pub fee: u64,
+    /// Share of the swap fee kept by the admin over `FEE_DENOMINATOR`, which leaves the balances of the pool
+    pub admin_fee: u64,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub coin_symbols: Vec<Option<String>>,
+    #[serde(default)]
+    pub coin_names: Vec<Option<String>>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+impl AmmState for CurveStableSwapPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        self.coins.clone()
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        self.coin_symbols.get(self.coin_index(token)?)?.as_deref()
+    }
+
+    /// Marginal rate of a whole `base_token` in whole units of the first other coin, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let quote_token = self
+            .other_tokens(base_token)
+            .first()
+            .copied()
+            .unwrap_or_default();
+
+        self.calculate_price_to(base_token, quote_token)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![TOKEN_EXCHANGE_EVENT_SIGNATURE]
+    }
+
+    //Liquidity events carry the amounts of every coin, in a layout depending on the number of coins, so the
+    //balances are only synced from swaps and refreshed from the pool, see `RefreshPolicy::default_for`
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+        if decode::event_signature(log)? != TOKEN_EXCHANGE_EVENT_SIGNATURE {
+            return Err(EventLogError::UnexpectedEvent(log.into()));
+        }
+
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+        let coin = |id: U256| {
+            (id < U256::from(self.coins.len()))
+                .then(|| id.as_usize())
+                .ok_or_else(malformed_log)
+        };
+
+        let sold = coin(decode::data_int(log, 0, 128)?.into_raw())?;
+        let tokens_sold = decode::data_uint(log, 1, 256)?;
+        let bought = coin(decode::data_int(log, 2, 128)?.into_raw())?;
+        let tokens_bought = decode::data_uint(log, 3, 256)?;
+        if sold == bought {
+            return Err(malformed_log());
+        }
+
+        //The admin share of the fee leaves the pool along with the tokens bought, the fee is taken from the
+        //amount bought before it is paid out so it is recovered from the amount paid out
+        let admin_fee = mul_div(
+            tokens_bought,
+            U256::from(self.fee),
+            U256::from(FEE_DENOMINATOR),
+        )
+        .and_then(|fee| {
+            mul_div(
+                fee,
+                U256::from(self.admin_fee),
+                U256::from(FEE_DENOMINATOR - self.fee.min(FEE_DENOMINATOR - 1)),
+            )
+        })
+        .map_err(|_| malformed_log())?;
+
+        //Both balances are checked before either is updated, so a bad log leaves the pool untouched
+        let sold_balance = self.balances[sold]
+            .checked_add(tokens_sold)
+            .ok_or_else(malformed_log)?;
+        let bought_balance = self.balances[bought]
+            .checked_sub(tokens_bought)
+            .ok_or_else(malformed_log)?
+            .saturating_sub(admin_fee);
+
+        self.balances[sold] = sold_balance;
+        self.balances[bought] = bought_balance;
+
+        Ok(())
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        let words = [self.a, U256::from(self.fee)]
+            .into_iter()
+            .chain(self.balances.iter().copied())
+            .collect::<Vec<U256>>();
+
+        amm::state_fingerprint(Protocol::CurveStableSwap.name(), &words)
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        let token_out = self.swap_token_out(token_in)?;
+        self.simulate_swap_to(token_in, token_out, amount_in)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let token_out = self.swap_token_out(token_in)?;
+        self.simulate_swap_to_mut(token_in, token_out, amount_in)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        self.other_tokens(token_in)
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if self.coins.len() != 2 {
+            return None;
+        }
+
+        match self.coin_index(token)? {
+            0 => Some(self.coins[1]),
+            _ => Some(self.coins[0]),
+        }
+    }
+
+    fn simulate_swap_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let (i, j) = self.swap_indices(token_in, token_out)?;
+        Ok(self.exchange(i, j, amount_in)?.0)
+    }
+
+    fn simulate_swap_to_mut(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let (i, j) = self.swap_indices(token_in, token_out)?;
+        let (amount_out, admin_fee) = self.exchange(i, j, amount_in)?;
+
+        self.balances[i] += amount_in;
+        self.balances[j] = self.balances[j]
+            .saturating_sub(amount_out)
+            .saturating_sub(admin_fee);
+
+        Ok(amount_out)
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for CurveStableSwapPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_curve_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_curve_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl CurveStableSwapPool {
+    pub fn new(
+        address: H160,
+        coins: Vec<H160>,
+        decimals: Vec<u8>,
+        balances: Vec<U256>,
+        a: U256,
+        fee: u64,
+        admin_fee: u64,
+    ) -> CurveStableSwapPool {
+        CurveStableSwapPool {
+            address,
+            coin_symbols: vec![None; coins.len()],
+            coin_names: vec![None; coins.len()],
+            coins,
+            decimals,
+            balances,
+            a,
+            fee,
+            admin_fee,
+            serde_version: SerdeVersion::CURRENT,
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        (2..=MAX_COINS).contains(&self.coins.len())
+            && self.decimals.len() == self.coins.len()
+            && self.balances.len() == self.coins.len()
+            && !self.a.is_zero()
+            && !self.coins.iter().any(H160::is_zero)
+            && !self.balances.iter().any(U256::is_zero)
+    }
+
+    /// Index of `token` in the coins of the pool
+    pub fn coin_index(&self, token: H160) -> Option<usize> {
+        self.coins.iter().position(|coin| *coin == token)
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn coin_decimals(&self, token: H160) -> Option<u8> {
+        self.decimals.get(self.coin_index(token)?).copied()
+    }
+
+    /// Copies the symbols and names of the coins found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        self.coin_symbols.resize(self.coins.len(), None);
+        self.coin_names.resize(self.coins.len(), None);
+
+        for (i, coin) in self.coins.iter().enumerate() {
+            if let Some(info) = tokens.get(*coin) {
+                self.coin_symbols[i] = info.symbol.or(self.coin_symbols[i].take());
+                self.coin_names[i] = info.name.or(self.coin_names[i].take());
+            }
+        }
+    }
+
+    /// Marginal rate of a whole `base_token` in whole units of `quote_token`, without the fee. The slope of the
+    /// invariant is `Ann + D_P / x` for each coin, with `D_P = D^(n+1) / (n^n * prod(x))`.
+    pub fn calculate_price_to(
+        &self,
+        base_token: H160,
+        quote_token: H160,
+    ) -> Result<f64, ArithmeticError> {
+        let (i, j) = match (self.coin_index(base_token), self.coin_index(quote_token)) {
+            (Some(i), Some(j)) => (i, j),
+            _ => return Err(ArithmeticError::DivisionByZero),
+        };
+
+        let xp = self.xp()?;
+        if xp.iter().any(U256::is_zero) {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+
+        let d = get_d(&xp, self.a)?;
+        let d_p = u256_to_f64_lossy(d_p(&xp, d)?);
+        let ann = u256_to_f64_lossy(self.a) * xp.len() as f64;
+
+        let price = (ann + d_p / u256_to_f64_lossy(xp[i])) / (ann + d_p / u256_to_f64_lossy(xp[j]));
+        if !price.is_finite() {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    //Coin swapped for `token_in` when the caller does not name one, only defined for two coin pools
+    fn swap_token_out(&self, token_in: H160) -> Result<H160, SwapSimulationError> {
+        if self.coin_index(token_in).is_none() {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        }
+
+        self.opp_token(token_in)
+            .ok_or(SwapSimulationError::TokenOutRequired(token_in))
+    }
+
+    fn swap_indices(
+        &self,
+        token_in: H160,
+        token_out: H160,
+    ) -> Result<(usize, usize), SwapSimulationError> {
+        let i = self
+            .coin_index(token_in)
+            .ok_or(SwapSimulationError::TokenNotInPool(token_in))?;
+        let j = self
+            .coin_index(token_out)
+            .filter(|j| *j != i)
+            .ok_or(SwapSimulationError::TokenNotInPool(token_out))?;
+
+        Ok((i, j))
+    }
+
+    //Multiplier of each coin normalizing its balance to 18 decimals
+    fn multipliers(&self) -> Result<Vec<U256>, ArithmeticError> {
+        self.decimals
+            .iter()
+            .map(|decimals| {
+                PRECISION_DECIMALS
+                    .checked_sub(*decimals)
+                    .map(|shift| U256::exp10(shift as usize))
+                    .ok_or(ArithmeticError::UnsupportedDecimals(*decimals))
+            })
+            .collect()
+    }
+
+    /// Balances normalized to 18 decimals, as they enter the invariant
+    pub fn xp(&self) -> Result<Vec<U256>, ArithmeticError> {
+        self.balances
+            .iter()
+            .zip(self.multipliers()?)
+            .map(|(balance, multiplier)| {
+                balance
+                    .checked_mul(multiplier)
+                    .ok_or(ArithmeticError::MulDivOverflow)
+            })
+            .collect()
+    }
+
+    /// Amount of coin `j` paid out for `dx` of coin `i` and the admin fee leaving the pool with it, rounded as in
+    /// `exchange` of the pool
+    pub fn exchange(&self, i: usize, j: usize, dx: U256) -> Result<(U256, U256), ArithmeticError> {
+        if dx.is_zero() {
+            return Ok((U256::zero(), U256::zero()));
+        }
+
+        let multipliers = self.multipliers()?;
+        let xp = self.xp()?;
+
+        let x = dx
+            .checked_mul(multipliers[i])
+            .and_then(|dx| dx.checked_add(xp[i]))
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        let y = get_y(i, j, x, &xp, self.a)?;
+
+        //One wei is kept by the pool against rounding errors
+        let dy = xp[j].saturating_sub(y).saturating_sub(U256::one());
+        let dy_fee = mul_div(dy, U256::from(self.fee), U256::from(FEE_DENOMINATOR))?;
+        let dy_admin_fee = mul_div(
+            dy_fee,
+            U256::from(self.admin_fee),
+            U256::from(FEE_DENOMINATOR),
+        )?;
+
+        Ok((
+            (dy - dy_fee) / multipliers[j],
+            dy_admin_fee / multipliers[j],
+        ))
+    }
+}
+
+/// StableSwap invariant of the normalized balances `xp` for the amplification coefficient `amp`, found with the
+/// Newton method of the pools
+pub fn get_d(xp: &[U256], amp: U256) -> Result<U256, ArithmeticError> {
+    let n = U256::from(xp.len());
+    let sum = xp
+        .iter()
+        .try_fold(U256::zero(), |sum, x| sum.checked_add(*x))
+        .ok_or(ArithmeticError::MulDivOverflow)?;
+    if sum.is_zero() {
+        return Ok(U256::zero());
+    }
+
+    let ann = amp.checked_mul(n).ok_or(ArithmeticError::MulDivOverflow)?;
+    let mut d = sum;
+    for _ in 0..MAX_ITERATIONS {
+        let d_p = d_p(xp, d)?;
+        let d_prev = d;
+
+        //D = (Ann * S + D_P * n) * D / ((Ann - 1) * D + (n + 1) * D_P)
+        let numerator = ann
+            .checked_mul(sum)
+            .and_then(|ann_sum| ann_sum.checked_add(d_p.checked_mul(n)?))
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        let denominator = ann
+            .checked_sub(U256::one())
+            .and_then(|ann| ann.checked_mul(d))
+            .and_then(|ann_d| ann_d.checked_add(d_p.checked_mul(n + 1)?))
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        d = mul_div(numerator, d, denominator)?;
+
+        if converged(d, d_prev) {
+            return Ok(d);
+        }
+    }
+
+    Err(ArithmeticError::InvariantNotConverged)
+}
+
+/// Normalized balance of coin `j` keeping the invariant of `xp` once the balance of coin `i` is set to `x`
+pub fn get_y(i: usize, j: usize, x: U256, xp: &[U256], amp: U256) -> Result<U256, ArithmeticError> {
+    let n = U256::from(xp.len());
+    let d = get_d(xp, amp)?;
+    let ann = amp.checked_mul(n).ok_or(ArithmeticError::MulDivOverflow)?;
+
+    let mut c = d;
+    let mut sum = U256::zero();
+    for (k, balance) in xp.iter().enumerate() {
+        let x_k = if k == i {
+            x
+        } else if k != j {
+            *balance
+        } else {
+            continue;
+        };
+
+        sum = sum
+            .checked_add(x_k)
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        c = mul_div(
+            c,
+            d,
+            x_k.checked_mul(n).ok_or(ArithmeticError::MulDivOverflow)?,
+        )?;
+    }
+    c = mul_div(
+        c,
+        d,
+        ann.checked_mul(n).ok_or(ArithmeticError::MulDivOverflow)?,
+    )?;
+    let b = sum + d / ann;
+
+    //y = (y^2 + c) / (2y + b - D)
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        let numerator = y
+            .checked_mul(y)
+            .and_then(|y_squared| y_squared.checked_add(c))
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        let denominator = (y * 2 + b)
+            .checked_sub(d)
+            .filter(|denominator| !denominator.is_zero())
+            .ok_or(ArithmeticError::DivisionByZero)?;
+        y = numerator / denominator;
+
+        if converged(y, y_prev) {
+            return Ok(y);
+        }
+    }
+
+    Err(ArithmeticError::InvariantNotConverged)
+}
+
+//Newton iterations stop once they move by at most one wei
+fn converged(value: U256, previous: U256) -> bool {
+    value.max(previous) - value.min(previous) <= U256::one()
+}
+
+//D^(n+1) / (n^n * prod(xp)), accumulated one coin at a time like the pools
+fn d_p(xp: &[U256], d: U256) -> Result<U256, ArithmeticError> {
+    let n = U256::from(xp.len());
+
+    xp.iter().try_fold(d, |d_p, x| {
+        mul_div(
+            d_p,
+            d,
+            x.checked_mul(n).ok_or(ArithmeticError::MulDivOverflow)?,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, I256, U256},
+    };
+
+    use crate::{
+        amm::{AmmState, AMM},
+        errors::SwapSimulationError,
+    };
+
+    use super::{get_d, CurveStableSwapPool, TOKEN_EXCHANGE_EVENT_SIGNATURE};
+
+    //DAI, USDC and USDT with 100M of each, A of 2000 and a 0.01% fee with half of it going to the admin
+    fn three_pool() -> CurveStableSwapPool {
+        CurveStableSwapPool::new(
+            H160::from_low_u64_be(10),
+            (1..=3).map(H160::from_low_u64_be).collect(),
+            vec![18, 6, 6],
+            vec![U256::exp10(26), U256::exp10(14), U256::exp10(14)],
+            U256::from(2000),
+            1_000_000,
+            5_000_000_000,
+        )
+    }
+
+    fn coin(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    fn token_exchange(pool: &CurveStableSwapPool, sold: (i64, U256), bought: (i64, U256)) -> Log {
+        Log {
+            address: pool.address,
+            topics: vec![TOKEN_EXCHANGE_EVENT_SIGNATURE, H160::zero().into()],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(sold.0).into_raw()),
+                Token::Uint(sold.1),
+                Token::Int(I256::from(bought.0).into_raw()),
+                Token::Uint(bought.1),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_swap_mixed_decimals() -> eyre::Result<()> {
+        let pool = three_pool();
+
+        //A balanced pool trades close to one to one net of the fee, whatever the decimals of the coins
+        let usdc_out = pool.simulate_swap_to(coin(1), coin(2), U256::exp10(24))?;
+        assert!(usdc_out < U256::from(999_900_000_000_u64));
+        assert!(usdc_out > U256::from(999_800_000_000_u64));
+
+        let dai_out = pool.simulate_swap_to(coin(3), coin(1), U256::exp10(12))?;
+        assert!(dai_out < U256::exp10(24) * 9999 / 10000);
+        assert!(dai_out > U256::exp10(24) * 9998 / 10000);
+
+        //Coins of the same decimals are symmetric
+        assert_eq!(
+            pool.simulate_swap_to(coin(2), coin(3), U256::exp10(12))?,
+            pool.simulate_swap_to(coin(3), coin(2), U256::exp10(12))?
+        );
+
+        //Larger trades get a worse rate
+        let small_out = pool.simulate_swap_to(coin(2), coin(3), U256::exp10(12))?;
+        let large_out = pool.simulate_swap_to(coin(2), coin(3), U256::exp10(12) * 50)?;
+        assert!(large_out < small_out * 50);
+
+        assert_eq!(
+            pool.simulate_swap_to(coin(1), coin(2), U256::zero())?,
+            U256::zero()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_coin_counts() -> eyre::Result<()> {
+        for coins in 2..=4_u64 {
+            let pool = CurveStableSwapPool::new(
+                H160::from_low_u64_be(10),
+                (1..=coins).map(H160::from_low_u64_be).collect(),
+                vec![18; coins as usize],
+                vec![U256::exp10(24); coins as usize],
+                U256::from(100),
+                4_000_000,
+                0,
+            );
+            assert!(pool.data_is_populated());
+
+            let amount_out = pool.simulate_swap_to(coin(1), coin(coins), U256::exp10(18))?;
+            assert!(amount_out < U256::exp10(18) * 9996 / 10000);
+            assert!(amount_out > U256::exp10(18) * 9990 / 10000);
+            assert!((pool.calculate_price(coin(1))? - 1.0).abs() < 1e-12);
+
+            //Only two coin pools name the coin swapped for
+            if coins == 2 {
+                assert_eq!(pool.simulate_swap(coin(1), U256::exp10(18))?, amount_out);
+            } else {
+                assert!(matches!(
+                    pool.simulate_swap(coin(1), U256::exp10(18)),
+                    Err(SwapSimulationError::TokenOutRequired(_))
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_mut() -> eyre::Result<()> {
+        let mut pool = three_pool();
+        let d = get_d(&pool.xp()?, pool.a)?;
+
+        let quote = pool.simulate_swap_to(coin(2), coin(1), U256::exp10(12))?;
+        let amount_out = pool.simulate_swap_to_mut(coin(2), coin(1), U256::exp10(12))?;
+        assert_eq!(amount_out, quote);
+        assert_eq!(pool.balances[1], U256::exp10(14) + U256::exp10(12));
+        assert!(pool.balances[0] < U256::exp10(26) - amount_out);
+
+        //The fee kept by the pool grows the invariant
+        assert!(get_d(&pool.xp()?, pool.a)? > d);
+
+        assert!(matches!(
+            pool.simulate_swap_to(coin(1), coin(1), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let mut pool = three_pool();
+        assert!((pool.calculate_price_to(coin(1), coin(2))? - 1.0).abs() < 1e-12);
+
+        //Selling USDC makes it cheaper against DAI, and DAI dearer against it
+        pool.simulate_swap_to_mut(coin(2), coin(1), U256::exp10(13) * 3)?;
+        let usdc_price = pool.calculate_price_to(coin(2), coin(1))?;
+        assert!(usdc_price < 1.0);
+        assert!((pool.calculate_price_to(coin(1), coin(2))? * usdc_price - 1.0).abs() < 1e-12);
+
+        //The marginal rate is the rate of small trades
+        let amount_out = pool.simulate_swap_to(coin(2), coin(1), U256::exp10(6))?;
+        let rate = amount_out.as_u128() as f64 / 1e18 / (1.0 - 0.0001);
+        assert!((rate - usdc_price).abs() < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = three_pool();
+        let mut swapped = pool.clone();
+        let amount_out = swapped.simulate_swap_to_mut(coin(1), coin(3), U256::exp10(24))?;
+
+        let log = token_exchange(&pool, (0, U256::exp10(24)), (2, amount_out));
+        let mut amm = AMM::CurveStableSwapPool(pool.clone());
+        amm.sync_from_log(&log)?;
+        pool.sync_from_log(&log)?;
+
+        //The admin fee is recovered from the amount bought, to within rounding
+        assert_eq!(pool.balances[0], swapped.balances[0]);
+        let (synced, simulated) = (pool.balances[2], swapped.balances[2]);
+        assert!(synced.max(simulated) - synced.min(simulated) <= U256::from(2));
+        assert!(AMM::CurveStableSwapPool(pool.clone()).state_eq(&amm));
+
+        //Coins the pool does not hold are rejected without touching the balances
+        let balances = pool.balances.clone();
+        assert!(pool
+            .sync_from_log(&token_exchange(&pool, (0, U256::one()), (3, U256::one())))
+            .is_err());
+        assert!(pool
+            .sync_from_log(&token_exchange(&pool, (-1, U256::one()), (1, U256::one())))
+            .is_err());
+        assert!(pool
+            .sync_from_log(&token_exchange(&pool, (0, U256::one()), (1, U256::MAX)))
+            .is_err());
+        assert_eq!(pool.balances, balances);
+
+        Ok(())
+    }
+}
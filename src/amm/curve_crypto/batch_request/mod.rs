@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{CurveCryptoPool, N_COINS};
+
+//A()
+const A_SELECTOR: [u8; 4] = [244, 70, 193, 208];
+//gamma()
+const GAMMA_SELECTOR: [u8; 4] = [177, 55, 57, 41];
+//D()
+const D_SELECTOR: [u8; 4] = [15, 82, 155, 162];
+//fee_gamma()
+const FEE_GAMMA_SELECTOR: [u8; 4] = [114, 212, 240, 226];
+//mid_fee()
+const MID_FEE_SELECTOR: [u8; 4] = [146, 82, 108, 12];
+//out_fee()
+const OUT_FEE_SELECTOR: [u8; 4] = [238, 141, 230, 117];
+//price_scale(uint256)
+const PRICE_SCALE_SELECTOR: [u8; 4] = [163, 247, 205, 213];
+//coins(uint256)
+const COINS_SELECTOR: [u8; 4] = [198, 97, 6, 87];
+//balances(uint256)
+const BALANCES_SELECTOR: [u8; 4] = [73, 3, 176, 209];
+
+const POOL_SELECTORS: [[u8; 4]; 6] = [
+    A_SELECTOR,
+    GAMMA_SELECTOR,
+    D_SELECTOR,
+    FEE_GAMMA_SELECTOR,
+    MID_FEE_SELECTOR,
+    OUT_FEE_SELECTOR,
+];
+//Pool selectors followed by the price scale of every coin after the first and the coin and balance of every coin
+const CALLS_PER_POOL: usize = POOL_SELECTORS.len() + (N_COINS - 1) + 2 * N_COINS;
+
+/// Reads `pool` at `block_number`, erroring with `AMMError::BatchRequestError` if it is not a crypto pool
+pub async fn get_curve_crypto_pool_data_batch_request<M: Middleware>(
+    pool: &mut CurveCryptoPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every crypto pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be read
+/// as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::CurveCryptoPool(crypto_pool) = amm {
+            if let Some(pool) = populate_pool_data(crypto_pool.to_owned(), pool_data) {
+                *crypto_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+struct PoolData {
+    //A, gamma, D, fee gamma, mid fee and out fee
+    parameters: Vec<Option<U256>>,
+    price_scale: Vec<Option<U256>>,
+    coins: Vec<Option<(H160, U256)>>,
+    decimals: Vec<Option<u8>>,
+}
+
+fn populate_pool_data(mut pool: CurveCryptoPool, pool_data: PoolData) -> Option<CurveCryptoPool> {
+    let parameters = pool_data
+        .parameters
+        .into_iter()
+        .collect::<Option<Vec<U256>>>()?;
+    let coins = pool_data.coins.into_iter().collect::<Option<Vec<_>>>()?;
+
+    pool.a = parameters[0];
+    pool.gamma = parameters[1];
+    pool.d = parameters[2];
+    pool.fee_gamma = parameters[3];
+    pool.mid_fee = u64::try_from(parameters[4]).ok()?;
+    pool.out_fee = u64::try_from(parameters[5]).ok()?;
+    pool.price_scale = pool_data.price_scale.into_iter().collect::<Option<_>>()?;
+    pool.decimals = pool_data.decimals.into_iter().collect::<Option<_>>()?;
+    pool.coins = coins.iter().map(|(coin, _)| *coin).collect();
+    pool.balances = coins.iter().map(|(_, balance)| *balance).collect();
+    pool.coin_symbols.resize(pool.coins.len(), None);
+    pool.coin_names.resize(pool.coins.len(), None);
+
+    Some(pool)
+}
+
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let index = |i: usize| ethers::abi::encode(&[Token::Uint(U256::from(i))]);
+    let calls = pools
+        .iter()
+        .flat_map(|pool| {
+            let price_scale_calls = (0..N_COINS - 1)
+                .map(move |i| multicall::call(*pool, PRICE_SCALE_SELECTOR, &index(i)));
+            let coin_calls = (0..N_COINS).flat_map(move |i| {
+                [COINS_SELECTOR, BALANCES_SELECTOR]
+                    .map(|selector| multicall::call(*pool, selector, &index(i)))
+            });
+
+            POOL_SELECTORS
+                .map(|selector| multicall::call(*pool, selector, &[]))
+                .into_iter()
+                .chain(price_scale_calls)
+                .chain(coin_calls)
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(CALLS_PER_POOL)
+        .map(|pool_return_data| {
+            let (parameters, rest) = pool_return_data.split_at(POOL_SELECTORS.len());
+            let (price_scale, coins) = rest.split_at(N_COINS - 1);
+
+            PoolData {
+                parameters: parameters.iter().map(word).collect(),
+                price_scale: price_scale.iter().map(word).collect(),
+                coins: coins
+                    .chunks(2)
+                    .map(|coin_return_data| {
+                        address(&coin_return_data[0]).zip(word(&coin_return_data[1]))
+                    })
+                    .collect(),
+                decimals: vec![],
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pool_data
+        .iter()
+        .flat_map(|pool_data| pool_data.coins.iter().flatten().map(|(coin, _)| *coin))
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+    for pool_data in pool_data.iter_mut() {
+        pool_data.decimals = pool_data
+            .coins
+            .iter()
+            .map(|coin| decimals.get(&coin.as_ref()?.0).copied())
+            .collect();
+    }
+
+    Ok(pool_data)
+}
+
+fn word(return_data: &Option<Bytes>) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, 0)
+}
+
+fn address(return_data: &Option<Bytes>) -> Option<H160> {
+    multicall::address_word(return_data.as_ref()?, 0).filter(|address| !address.is_zero())
+}
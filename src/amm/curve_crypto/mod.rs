@@ -0,0 +1,947 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::types::{Log, H160, H256, U256};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::fixed_point::u256_to_f64_lossy,
+    tokens::TokenStore,
+};
+
+//TokenExchange(address indexed buyer, uint256 sold_id, uint256 tokens_sold, uint256 bought_id, uint256 tokens_bought)
+pub const TOKEN_EXCHANGE_EVENT_SIGNATURE: H256 = H256([
+    178, 231, 106, 233, 151, 97, 220, 19, 110, 89, 141, 74, 98, 155, 179, 71, 236, 203, 149, 50,
+    165, 248, 187, 215, 46, 24, 70, 124, 60, 52, 204, 152,
+]);
+
+/// Number of coins of a crypto pool, the invariant is only implemented for the three coins of tricrypto
+pub const N_COINS: usize = 3;
+/// Denominator of `mid_fee` and `out_fee`, i.e. a fee of 3000000 is 0.03%
+pub const FEE_DENOMINATOR: u64 = 10_000_000_000;
+/// Scale of `A()` over `A * N^N`
+pub const A_MULTIPLIER: u64 = 10_000;
+//1e18, the precision of gamma, the fee gamma, the price scale and the normalized balances
+const PRECISION: U256 = U256([1_000_000_000_000_000_000, 0, 0, 0]);
+//Iterations of the Newton method of the pools before they give up
+const MAX_ITERATIONS: usize = 255;
+//Balances are normalized to 18 decimals before entering the invariant
+const PRECISION_DECIMALS: u8 = 18;
+
+/// Curve crypto pool of three coins, following tricrypto2.
+///
+/// The invariant concentrates the liquidity around `price_scale`, the price of each coin after the first in the
+/// first coin. The pool moves it towards its internal price oracle after trades, which is not modeled, so the
+/// price scale is only picked up when the pool is refreshed, see `RefreshPolicy::default_for`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CurveCryptoPool {
+    pub address: H160,
+    pub coins: Vec<H160>,
+    pub decimals: Vec<u8>,
+    pub balances: Vec<U256>,
+    /// Amplification coefficient, as returned by `A()`, i.e. `A * N^N * A_MULTIPLIER`
+    pub a: U256,
+    /// Curvature of the invariant over 1e18
+    pub gamma: U256,
+    /// Price of each coin after the first in the first coin over 1e18
+    pub price_scale: Vec<U256>,
+    /// Invariant of the balances at the price scale, as returned by `D()`
+    pub d: U256,
+    /// How fast the fee moves from `mid_fee` to `out_fee` as the pool leaves balance, over 1e18
+    pub fee_gamma: U256,
+    /// Fee of a balanced pool over `FEE_DENOMINATOR`
+    pub mid_fee: u64,
+    /// Fee of an imbalanced pool over `FEE_DENOMINATOR`
+    pub out_fee: u64,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub coin_symbols: Vec<Option<String>>,
+    #[serde(default)]
+    pub coin_names: Vec<Option<String>>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+impl AmmState for CurveCryptoPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        self.coins.clone()
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        self.coin_symbols.get(self.coin_index(token)?)?.as_deref()
+    }
+
+    /// Marginal rate of a whole `base_token` in whole units of the first other coin, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let quote_token = self
+            .other_tokens(base_token)
+            .first()
+            .copied()
+            .unwrap_or_default();
+
+        self.calculate_price_to(base_token, quote_token)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![TOKEN_EXCHANGE_EVENT_SIGNATURE]
+    }
+
+    //Liquidity events and the moves of the price scale are not synced, the pool is refreshed instead, see
+    //`RefreshPolicy::default_for`
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+        if decode::event_signature(log)? != TOKEN_EXCHANGE_EVENT_SIGNATURE {
+            return Err(EventLogError::UnexpectedEvent(log.into()));
+        }
+
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+        let coin = |id: U256| {
+            (id < U256::from(self.coins.len()))
+                .then(|| id.as_usize())
+                .ok_or_else(malformed_log)
+        };
+
+        let sold = coin(decode::data_uint(log, 0, 256)?)?;
+        let tokens_sold = decode::data_uint(log, 1, 256)?;
+        let bought = coin(decode::data_uint(log, 2, 256)?)?;
+        let tokens_bought = decode::data_uint(log, 3, 256)?;
+        if sold == bought {
+            return Err(malformed_log());
+        }
+
+        //The whole fee stays in the pool, admin fees are claimed later by minting LP tokens
+        let mut balances = self.balances.clone();
+        balances[sold] = balances[sold]
+            .checked_add(tokens_sold)
+            .ok_or_else(malformed_log)?;
+        balances[bought] = balances[bought]
+            .checked_sub(tokens_bought)
+            .ok_or_else(malformed_log)?;
+        let d = newton_d(
+            self.a,
+            self.gamma,
+            &self.scaled(&balances).map_err(|_| malformed_log())?,
+        )
+        .map_err(|_| malformed_log())?;
+
+        self.balances = balances;
+        self.d = d;
+
+        Ok(())
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        let words = [
+            self.a,
+            self.gamma,
+            self.d,
+            self.fee_gamma,
+            U256::from(self.mid_fee),
+            U256::from(self.out_fee),
+        ]
+        .into_iter()
+        .chain(self.price_scale.iter().copied())
+        .chain(self.balances.iter().copied())
+        .collect::<Vec<U256>>();
+
+        amm::state_fingerprint(Protocol::CurveCrypto.name(), &words)
+    }
+
+    //Every coin can be swapped for two others, so the coin swapped for has to be named
+    fn simulate_swap(&self, token_in: H160, _amount_in: U256) -> Result<U256, SwapSimulationError> {
+        Err(self.token_out_required(token_in))
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        _amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        Err(self.token_out_required(token_in))
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        self.other_tokens(token_in)
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn opp_token(&self, _token: H160) -> Option<H160> {
+        None
+    }
+
+    fn simulate_swap_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let (i, j) = self.swap_indices(token_in, token_out)?;
+        Ok(self.get_dy(i, j, amount_in)?)
+    }
+
+    fn simulate_swap_to_mut(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let (i, j) = self.swap_indices(token_in, token_out)?;
+        let amount_out = self.get_dy(i, j, amount_in)?;
+
+        let mut balances = self.balances.clone();
+        balances[i] = add(balances[i], amount_in)?;
+        balances[j] = sub(balances[j], amount_out)?;
+        self.d = newton_d(self.a, self.gamma, &self.scaled(&balances)?)?;
+        self.balances = balances;
+
+        Ok(amount_out)
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for CurveCryptoPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_curve_crypto_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_curve_crypto_pool_data_batch_request(self, block_number, middleware)
+            .await
+    }
+}
+
+impl CurveCryptoPool {
+    /// Pool holding `balances`, with the invariant computed from them as the pool stores it after every trade
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: H160,
+        coins: Vec<H160>,
+        decimals: Vec<u8>,
+        balances: Vec<U256>,
+        a: U256,
+        gamma: U256,
+        price_scale: Vec<U256>,
+        fee_gamma: U256,
+        mid_fee: u64,
+        out_fee: u64,
+    ) -> CurveCryptoPool {
+        let mut pool = CurveCryptoPool {
+            address,
+            coin_symbols: vec![None; coins.len()],
+            coin_names: vec![None; coins.len()],
+            coins,
+            decimals,
+            balances,
+            a,
+            gamma,
+            price_scale,
+            d: U256::zero(),
+            fee_gamma,
+            mid_fee,
+            out_fee,
+            serde_version: SerdeVersion::CURRENT,
+        };
+
+        //Pools whose invariant cannot be computed are left unpopulated
+        pool.d = pool
+            .xp()
+            .and_then(|xp| newton_d(pool.a, pool.gamma, &xp))
+            .unwrap_or_default();
+
+        pool
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        self.coins.len() == N_COINS
+            && self.decimals.len() == N_COINS
+            && self.balances.len() == N_COINS
+            && self.price_scale.len() == N_COINS - 1
+            && !self.a.is_zero()
+            && !self.gamma.is_zero()
+            && !self.d.is_zero()
+            && !self.coins.iter().any(H160::is_zero)
+            && !self.balances.iter().any(U256::is_zero)
+            && !self.price_scale.iter().any(U256::is_zero)
+    }
+
+    /// Index of `token` in the coins of the pool
+    pub fn coin_index(&self, token: H160) -> Option<usize> {
+        self.coins.iter().position(|coin| *coin == token)
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn coin_decimals(&self, token: H160) -> Option<u8> {
+        self.decimals.get(self.coin_index(token)?).copied()
+    }
+
+    /// Copies the symbols and names of the coins found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        self.coin_symbols.resize(self.coins.len(), None);
+        self.coin_names.resize(self.coins.len(), None);
+
+        for (i, coin) in self.coins.iter().enumerate() {
+            if let Some(info) = tokens.get(*coin) {
+                self.coin_symbols[i] = info.symbol.or(self.coin_symbols[i].take());
+                self.coin_names[i] = info.name.or(self.coin_names[i].take());
+            }
+        }
+    }
+
+    /// Marginal rate of a whole `base_token` in whole units of `quote_token`, without the fee. The invariant is
+    /// `K * D^(n-1) * S + P = K * D^n + (D/n)^n` with `K = A * K0 * gamma^2 / (gamma + 1 - K0)^2` and
+    /// `K0 = n^n * P / D^n`, its slope is taken on the balances normalized by `D`.
+    pub fn calculate_price_to(
+        &self,
+        base_token: H160,
+        quote_token: H160,
+    ) -> Result<f64, ArithmeticError> {
+        let (i, j) = match (self.coin_index(base_token), self.coin_index(quote_token)) {
+            (Some(i), Some(j)) => (i, j),
+            _ => return Err(ArithmeticError::DivisionByZero),
+        };
+
+        let xp = self.xp()?;
+        if xp.iter().any(U256::is_zero) || self.d.is_zero() {
+            return Err(ArithmeticError::DivisionByZero);
+        }
+
+        let d = u256_to_f64_lossy(self.d);
+        let x = xp
+            .iter()
+            .map(|x| u256_to_f64_lossy(*x) / d)
+            .collect::<Vec<f64>>();
+        let n = x.len() as f64;
+        let n_n = n.powi(x.len() as i32);
+        let amp = u256_to_f64_lossy(self.a) / A_MULTIPLIER as f64 / n_n;
+        let gamma = u256_to_f64_lossy(self.gamma) / 1e18;
+
+        let product = x.iter().product::<f64>();
+        let sum = x.iter().sum::<f64>();
+        let k0 = n_n * product;
+        let g1k0 = gamma + 1.0 - k0;
+        let k = amp * k0 * gamma.powi(2) / g1k0.powi(2);
+        let dk_dk0 = amp * gamma.powi(2) * (gamma + 1.0 + k0) / g1k0.powi(3);
+
+        //Slope of the invariant for a whole coin, which is worth its price scale in normalized balance
+        let slope = |k_index: usize| {
+            let price_scale = match k_index {
+                0 => 1.0,
+                _ => u256_to_f64_lossy(self.price_scale[k_index - 1]) / 1e18,
+            };
+
+            (dk_dk0 * k0 / x[k_index] * (sum - 1.0) + k + product / x[k_index]) * price_scale
+        };
+
+        let price = slope(i) / slope(j);
+        if !price.is_finite() {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    fn token_out_required(&self, token_in: H160) -> SwapSimulationError {
+        match self.coin_index(token_in) {
+            Some(_) => SwapSimulationError::TokenOutRequired(token_in),
+            None => SwapSimulationError::TokenNotInPool(token_in),
+        }
+    }
+
+    fn swap_indices(
+        &self,
+        token_in: H160,
+        token_out: H160,
+    ) -> Result<(usize, usize), SwapSimulationError> {
+        let i = self
+            .coin_index(token_in)
+            .ok_or(SwapSimulationError::TokenNotInPool(token_in))?;
+        let j = self
+            .coin_index(token_out)
+            .filter(|j| *j != i)
+            .ok_or(SwapSimulationError::TokenNotInPool(token_out))?;
+
+        Ok((i, j))
+    }
+
+    //Multiplier of each coin normalizing its balance to 18 decimals
+    fn precisions(&self) -> Result<Vec<U256>, ArithmeticError> {
+        self.decimals
+            .iter()
+            .map(|decimals| {
+                PRECISION_DECIMALS
+                    .checked_sub(*decimals)
+                    .map(|shift| U256::exp10(shift as usize))
+                    .ok_or(ArithmeticError::UnsupportedDecimals(*decimals))
+            })
+            .collect()
+    }
+
+    /// Balances normalized to 18 decimals and valued in the first coin at the price scale, as they enter the
+    /// invariant
+    pub fn xp(&self) -> Result<Vec<U256>, ArithmeticError> {
+        self.scaled(&self.balances)
+    }
+
+    fn scaled(&self, balances: &[U256]) -> Result<Vec<U256>, ArithmeticError> {
+        balances
+            .iter()
+            .zip(self.precisions()?)
+            .enumerate()
+            .map(|(k, (balance, precision))| {
+                let x = mul(*balance, precision)?;
+                match k {
+                    0 => Ok(x),
+                    _ => {
+                        let price_scale = self
+                            .price_scale
+                            .get(k - 1)
+                            .ok_or(ArithmeticError::UnsafeInvariantInput)?;
+                        div(mul(x, *price_scale)?, PRECISION)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Amount of coin `j` paid out for `dx` of coin `i`, rounded as in `get_dy` of the pool
+    pub fn get_dy(&self, i: usize, j: usize, dx: U256) -> Result<U256, ArithmeticError> {
+        if dx.is_zero() {
+            return Ok(U256::zero());
+        }
+
+        let mut balances = self.balances.clone();
+        balances[i] = add(balances[i], dx)?;
+        let mut xp = self.scaled(&balances)?;
+
+        let y = newton_y(self.a, self.gamma, &xp, self.d, j)?;
+        //One wei is kept by the pool against rounding errors
+        let mut dy = sub(sub(xp[j], y)?, U256::one())?;
+        xp[j] = y;
+
+        if j > 0 {
+            dy = div(mul(dy, PRECISION)?, self.price_scale[j - 1])?;
+        }
+        dy = div(dy, self.precisions()?[j])?;
+
+        sub(
+            dy,
+            div(mul(self.fee(&xp)?, dy)?, U256::from(FEE_DENOMINATOR))?,
+        )
+    }
+
+    /// Fee over `FEE_DENOMINATOR` charged at the normalized balances `xp`, `mid_fee` for a balanced pool moving
+    /// towards `out_fee` as it leaves balance
+    pub fn fee(&self, xp: &[U256]) -> Result<U256, ArithmeticError> {
+        let f = reduction_coefficient(xp, self.fee_gamma)?;
+
+        div(
+            add(
+                mul(U256::from(self.mid_fee), f)?,
+                mul(U256::from(self.out_fee), sub(PRECISION, f)?)?,
+            )?,
+            PRECISION,
+        )
+    }
+}
+
+/// Invariant of the normalized balances `xp` for the amplification coefficient `ann` and `gamma`, found with the
+/// Newton method of the pools
+pub fn newton_d(ann: U256, gamma: U256, xp: &[U256]) -> Result<U256, ArithmeticError> {
+    check_parameters(ann, gamma)?;
+    let n = U256::from(xp.len());
+
+    let mut x = xp.to_vec();
+    x.sort_unstable_by(|a, b| b.cmp(a));
+    if x.is_empty() || x[0] < U256::exp10(9) || x[0] > U256::exp10(33) {
+        return Err(ArithmeticError::UnsafeInvariantInput);
+    }
+    for x_i in &x[1..] {
+        if div(mul(*x_i, PRECISION)?, x[0])? < U256::exp10(11) {
+            return Err(ArithmeticError::UnsafeInvariantInput);
+        }
+    }
+
+    let sum = x.iter().try_fold(U256::zero(), |sum, x_i| add(sum, *x_i))?;
+    let mut d = mul(n, geometric_mean(&x)?)?;
+    for _ in 0..MAX_ITERATIONS {
+        let d_prev = d;
+
+        let k0 = x
+            .iter()
+            .try_fold(PRECISION, |k0, x_i| div(mul(mul(k0, *x_i)?, n)?, d))?;
+        let g1k0 = g1k0(gamma, k0);
+        let mul1 = mul1(d, gamma, g1k0, ann)?;
+        let mul2 = div(mul(mul(PRECISION * 2, n)?, k0)?, g1k0)?;
+
+        let neg_fprime = sub(
+            add(
+                add(sum, div(mul(sum, mul2)?, PRECISION)?)?,
+                div(mul(mul1, n)?, k0)?,
+            )?,
+            div(mul(mul2, d)?, PRECISION)?,
+        )?;
+
+        //D -= f / fprime, split in the terms added and removed to stay unsigned
+        let d_plus = div(mul(d, add(neg_fprime, sum)?)?, neg_fprime)?;
+        let d_minus = div(mul(d, d)?, neg_fprime)?;
+        let correction = |k0_delta: U256| {
+            div(
+                mul(div(mul(d, div(mul1, neg_fprime)?)?, PRECISION)?, k0_delta)?,
+                k0,
+            )
+        };
+        let d_minus = if PRECISION > k0 {
+            add(d_minus, correction(PRECISION - k0)?)?
+        } else {
+            sub(d_minus, correction(k0 - PRECISION)?)?
+        };
+
+        d = if d_plus > d_minus {
+            d_plus - d_minus
+        } else {
+            (d_minus - d_plus) / 2
+        };
+
+        let diff = d.max(d_prev) - d.min(d_prev);
+        if mul(diff, U256::exp10(14))? < d.max(U256::exp10(16)) {
+            for x_i in &x {
+                check_fraction(*x_i, d)?;
+            }
+
+            return Ok(d);
+        }
+    }
+
+    Err(ArithmeticError::InvariantNotConverged)
+}
+
+/// Normalized balance of coin `i` keeping the invariant `d` with the other balances of `xp`, found with the
+/// Newton method of the pools
+pub fn newton_y(
+    ann: U256,
+    gamma: U256,
+    xp: &[U256],
+    d: U256,
+    i: usize,
+) -> Result<U256, ArithmeticError> {
+    check_parameters(ann, gamma)?;
+    if d < U256::exp10(17) || d > U256::exp10(33) {
+        return Err(ArithmeticError::UnsafeInvariantInput);
+    }
+    for (k, x_k) in xp.iter().enumerate() {
+        if k != i {
+            check_fraction(*x_k, d)?;
+        }
+    }
+
+    let n = U256::from(xp.len());
+    let mut x_sorted = xp.to_vec();
+    x_sorted[i] = U256::zero();
+    x_sorted.sort_unstable_by(|a, b| b.cmp(a));
+    let others = &x_sorted[..xp.len() - 1];
+
+    let convergence_limit = (x_sorted[0] / U256::exp10(14))
+        .max(d / U256::exp10(14))
+        .max(U256::from(100));
+
+    //Small balances first for y and large ones first for K0, in the order of the pools
+    let mut y = d / n;
+    let mut sum_i = U256::zero();
+    for x_k in others.iter().rev() {
+        y = div(mul(y, d)?, mul(*x_k, n)?)?;
+        sum_i = add(sum_i, *x_k)?;
+    }
+    let k0_i = others
+        .iter()
+        .try_fold(PRECISION, |k0_i, x_k| div(mul(mul(k0_i, *x_k)?, n)?, d))?;
+
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+
+        let k0 = div(mul(mul(k0_i, y)?, n)?, d)?;
+        let sum = add(sum_i, y)?;
+        let g1k0 = g1k0(gamma, k0);
+        let mul1 = mul1(d, gamma, g1k0, ann)?;
+        let mul2 = add(PRECISION, div(mul(PRECISION * 2, k0)?, g1k0)?)?;
+
+        //Overshooting below zero halves y instead
+        let yfprime = add(add(mul(PRECISION, y)?, mul(sum, mul2)?)?, mul1)?;
+        let dyfprime = mul(d, mul2)?;
+        if yfprime < dyfprime {
+            y = y_prev / 2;
+            continue;
+        }
+        let yfprime = yfprime - dyfprime;
+        let fprime = div(yfprime, y)?;
+
+        //y -= f / fprime, split in the terms added and removed to stay unsigned
+        let y_minus = div(mul1, fprime)?;
+        let y_plus = add(
+            div(add(yfprime, mul(PRECISION, d)?)?, fprime)?,
+            div(mul(y_minus, PRECISION)?, k0)?,
+        )?;
+        let y_minus = add(y_minus, div(mul(PRECISION, sum)?, fprime)?)?;
+
+        y = if y_plus < y_minus {
+            y_prev / 2
+        } else {
+            y_plus - y_minus
+        };
+
+        let diff = y.max(y_prev) - y.min(y_prev);
+        if diff < convergence_limit.max(y / U256::exp10(14)) {
+            check_fraction(y, d)?;
+            return Ok(y);
+        }
+    }
+
+    Err(ArithmeticError::InvariantNotConverged)
+}
+
+//Geometric mean of `x` sorted from high to low, the invariant of a balanced pool
+fn geometric_mean(x: &[U256]) -> Result<U256, ArithmeticError> {
+    let n = U256::from(x.len());
+
+    let mut d = x[0];
+    for _ in 0..MAX_ITERATIONS {
+        let d_prev = d;
+        let tmp = x
+            .iter()
+            .try_fold(PRECISION, |tmp, x_i| div(mul(tmp, *x_i)?, d))?;
+        d = div(
+            mul(d, add(mul(n - 1, PRECISION)?, tmp)?)?,
+            mul(n, PRECISION)?,
+        )?;
+
+        let diff = d.max(d_prev) - d.min(d_prev);
+        if diff <= U256::one() || mul(diff, PRECISION)? < d {
+            return Ok(d);
+        }
+    }
+
+    Err(ArithmeticError::InvariantNotConverged)
+}
+
+//fee_gamma / (fee_gamma + 1 - K) with K = prod(x) / (sum(x) / n)^n, all over 1e18
+fn reduction_coefficient(x: &[U256], fee_gamma: U256) -> Result<U256, ArithmeticError> {
+    let n = U256::from(x.len());
+    let sum = x.iter().try_fold(U256::zero(), |sum, x_i| add(sum, *x_i))?;
+
+    let k = x
+        .iter()
+        .try_fold(PRECISION, |k, x_i| div(mul(mul(k, n)?, *x_i)?, sum))?;
+    if fee_gamma.is_zero() {
+        return Ok(k);
+    }
+
+    div(
+        mul(fee_gamma, PRECISION)?,
+        sub(add(fee_gamma, PRECISION)?, k)?,
+    )
+}
+
+//|gamma + 1 - K0| + 1
+fn g1k0(gamma: U256, k0: U256) -> U256 {
+    let g1 = gamma + PRECISION;
+    g1.max(k0) - g1.min(k0) + U256::one()
+}
+
+//D / (A * N^N) * g1k0^2 / gamma^2, over 1e18
+fn mul1(d: U256, gamma: U256, g1k0: U256, ann: U256) -> Result<U256, ArithmeticError> {
+    let mul1 = div(mul(PRECISION, d)?, gamma)?;
+    let mul1 = div(mul(mul1, g1k0)?, gamma)?;
+
+    div(mul(mul(mul1, g1k0)?, U256::from(A_MULTIPLIER))?, ann)
+}
+
+//The pools refuse parameters and balances outside of the range their math is tested for
+fn check_parameters(ann: U256, gamma: U256) -> Result<(), ArithmeticError> {
+    let n_n = U256::from(N_COINS.pow(N_COINS as u32));
+    let min_a = n_n * A_MULTIPLIER / 100;
+    let max_a = n_n * A_MULTIPLIER * 1000;
+    let (min_gamma, max_gamma) = (U256::exp10(10), U256::exp10(16) * 5);
+
+    if ann < min_a || ann > max_a || gamma < min_gamma || gamma > max_gamma {
+        return Err(ArithmeticError::UnsafeInvariantInput);
+    }
+
+    Ok(())
+}
+
+fn check_fraction(x: U256, d: U256) -> Result<(), ArithmeticError> {
+    let fraction = div(mul(x, PRECISION)?, d)?;
+    if fraction < U256::exp10(16) || fraction > U256::exp10(20) {
+        return Err(ArithmeticError::UnsafeInvariantInput);
+    }
+
+    Ok(())
+}
+
+//Checked operations, the pools revert on overflow and division by zero
+fn mul(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_mul(b).ok_or(ArithmeticError::MulDivOverflow)
+}
+
+fn div(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_div(b).ok_or(ArithmeticError::DivisionByZero)
+}
+
+fn add(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_add(b).ok_or(ArithmeticError::MulDivOverflow)
+}
+
+fn sub(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_sub(b)
+        .ok_or(ArithmeticError::SubtractionUnderflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::{
+        abi::Token,
+        prelude::abigen,
+        types::{Log, H160, U256},
+    };
+
+    use crate::{
+        amm::{AmmState, AutomatedMarketMaker, AMM},
+        errors::SwapSimulationError,
+        test_utils::ForkHarness,
+    };
+
+    use super::{newton_d, CurveCryptoPool, TOKEN_EXCHANGE_EVENT_SIGNATURE};
+
+    abigen!(
+        ICurveCryptoPool,
+        r#"[
+            function get_dy(uint256 i, uint256 j, uint256 dx) external view returns (uint256)
+        ]"#;
+    );
+
+    //USDT, WBTC and WETH worth 30M each, at 30000 and 2000 USDT, with the parameters of tricrypto2
+    fn tricrypto() -> CurveCryptoPool {
+        CurveCryptoPool::new(
+            H160::from_low_u64_be(10),
+            (1..=3).map(H160::from_low_u64_be).collect(),
+            vec![6, 8, 18],
+            vec![
+                U256::exp10(13) * 3,
+                U256::exp10(11),
+                U256::exp10(22) + U256::exp10(21) * 5,
+            ],
+            U256::from(1_707_629),
+            U256::from(11_809_167_828_997_u64),
+            vec![U256::exp10(18) * 30000, U256::exp10(18) * 2000],
+            U256::from(500_000_000_000_000_u64),
+            3_000_000,
+            30_000_000,
+        )
+    }
+
+    fn coin(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    fn token_exchange(pool: &CurveCryptoPool, sold: (u64, U256), bought: (u64, U256)) -> Log {
+        Log {
+            address: pool.address,
+            topics: vec![TOKEN_EXCHANGE_EVENT_SIGNATURE, H160::zero().into()],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(sold.0)),
+                Token::Uint(sold.1),
+                Token::Uint(U256::from(bought.0)),
+                Token::Uint(bought.1),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let pool = tricrypto();
+        assert!(pool.data_is_populated());
+        assert_eq!(pool.d, U256::exp10(25) * 9);
+        assert_eq!(pool.fee(&pool.xp()?)?, U256::from(pool.mid_fee));
+
+        //A balanced pool trades at the price scale less the mid fee
+        assert_eq!(
+            pool.simulate_swap_to(coin(1), coin(2), U256::from(30_000_000_000_u64))?,
+            U256::from(99_967_443)
+        );
+        assert_eq!(
+            pool.simulate_swap_to(coin(2), coin(1), U256::exp10(8))?,
+            U256::from(29_990_232_871_u64)
+        );
+        assert_eq!(
+            pool.simulate_swap_to(coin(2), coin(3), U256::exp10(8))?,
+            U256::from_dec_str("14995116435628962527")?
+        );
+        assert_eq!(
+            pool.simulate_swap_to(coin(3), coin(1), U256::exp10(18))?,
+            U256::from(1_999_397_649)
+        );
+        assert_eq!(
+            pool.simulate_swap_to(coin(1), coin(3), U256::zero())?,
+            U256::zero()
+        );
+
+        //Every coin can be swapped for two others
+        assert!(matches!(
+            pool.simulate_swap(coin(1), U256::exp10(6)),
+            Err(SwapSimulationError::TokenOutRequired(_))
+        ));
+        assert!(matches!(
+            pool.simulate_swap_to(coin(1), coin(4), U256::exp10(6)),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_mut() -> eyre::Result<()> {
+        let mut pool = tricrypto();
+
+        let quote = pool.simulate_swap_to(coin(1), coin(2), U256::exp10(12) * 3)?;
+        let amount_out = pool.simulate_swap_to_mut(coin(1), coin(2), U256::exp10(12) * 3)?;
+        assert_eq!(amount_out, quote);
+        assert_eq!(amount_out, U256::from(9_101_429_560_u64));
+        assert_eq!(pool.balances[0], U256::exp10(12) * 33);
+        assert_eq!(pool.balances[1], U256::exp10(11) - amount_out);
+
+        //The invariant follows the balances, growing with the fee kept by the pool
+        assert_eq!(pool.d, newton_d(pool.a, pool.gamma, &pool.xp()?)?);
+        assert_eq!(pool.d, U256::from_dec_str("90008601616058127471185799")?);
+
+        //An imbalanced pool charges more than the mid fee and trades back at a worse rate
+        assert!(pool.fee(&pool.xp()?)? > U256::from(pool.mid_fee));
+        assert_eq!(
+            pool.simulate_swap_to(coin(2), coin(1), amount_out)?,
+            U256::from(2_990_500_507_749_u64)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let mut pool = tricrypto();
+        assert!((pool.calculate_price_to(coin(2), coin(1))? / 30000.0 - 1.0).abs() < 1e-12);
+        assert!((pool.calculate_price_to(coin(3), coin(2))? * 15.0 - 1.0).abs() < 1e-12);
+        assert!((pool.calculate_price(coin(2))? / 30000.0 - 1.0).abs() < 1e-12);
+
+        //Selling USDT makes WBTC dearer against it
+        pool.simulate_swap_to_mut(coin(1), coin(2), U256::exp10(12) * 3)?;
+        let wbtc_price = pool.calculate_price_to(coin(2), coin(1))?;
+        assert!(wbtc_price > 30000.0);
+        assert!((pool.calculate_price_to(coin(1), coin(2))? * wbtc_price - 1.0).abs() < 1e-12);
+
+        //The marginal rate is the rate of small trades before the fee
+        let fee = pool.fee(&pool.xp()?)?.as_u64() as f64 / 1e10;
+        let amount_out = pool.simulate_swap_to(coin(2), coin(1), U256::exp10(5))?;
+        let rate = amount_out.as_u128() as f64 / 1e6 / 1e-3 / (1.0 - fee);
+        assert!((rate / wbtc_price - 1.0).abs() < 1e-5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = tricrypto();
+        let mut swapped = pool.clone();
+        let amount_out = swapped.simulate_swap_to_mut(coin(3), coin(2), U256::exp10(20))?;
+
+        let log = token_exchange(&pool, (2, U256::exp10(20)), (1, amount_out));
+        let mut amm = AMM::CurveCryptoPool(pool.clone());
+        amm.sync_from_log(&log)?;
+        pool.sync_from_log(&log)?;
+
+        assert_eq!(pool.balances, swapped.balances);
+        assert_eq!(pool.d, swapped.d);
+        assert!(AMM::CurveCryptoPool(pool.clone()).state_eq(&amm));
+
+        //Coins the pool does not hold are rejected without touching the balances
+        let (balances, d) = (pool.balances.clone(), pool.d);
+        assert!(pool
+            .sync_from_log(&token_exchange(&pool, (0, U256::one()), (3, U256::one())))
+            .is_err());
+        assert!(pool
+            .sync_from_log(&token_exchange(&pool, (1, U256::one()), (1, U256::one())))
+            .is_err());
+        assert!(pool
+            .sync_from_log(&token_exchange(&pool, (0, U256::one()), (1, U256::MAX)))
+            .is_err());
+        assert_eq!(pool.balances, balances);
+        assert_eq!(pool.d, d);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_simulate_swap_tricrypto2() -> eyre::Result<()> {
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
+
+        //USDT, WBTC and WETH
+        let address = H160::from_str("0xD51a44d3FaE010294C616388b506AcdA1bfAAE46")?;
+        let mut pool = CurveCryptoPool {
+            address,
+            ..Default::default()
+        };
+        pool.populate_data(Some(fork.block_number), middleware.clone())
+            .await?;
+        assert!(pool.data_is_populated());
+
+        let curve_pool = ICurveCryptoPool::new(address, middleware);
+        let swaps = [
+            (0, 1, U256::exp10(9)),
+            (0, 2, U256::exp10(12)),
+            (1, 0, U256::exp10(6)),
+            (1, 2, U256::exp10(9)),
+            (2, 0, U256::exp10(17)),
+            (2, 1, U256::exp10(21)),
+        ];
+        for (i, j, amount_in) in swaps {
+            let amount_out = pool.simulate_swap_to(pool.coins[i], pool.coins[j], amount_in)?;
+            let expected_amount_out = curve_pool
+                .get_dy(U256::from(i), U256::from(j), amount_in)
+                .block(fork.block_number)
+                .call()
+                .await?;
+
+            assert_eq!(amount_out, expected_amount_out);
+        }
+
+        Ok(())
+    }
+}
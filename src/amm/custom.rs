@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::RwLock,
+};
+
+use serde::{
+    de::{DeserializeOwned, Error as DeserializeError},
+    ser::{Error as SerializeError, SerializeStruct},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::AmmState;
+
+/// Serializable AMM interface for protocols implemented outside of this crate.
+///
+/// Custom AMMs are carried in `AMM::Custom` and are populated through the `AmmFactory` that discovered them,
+/// since the middleware bound methods of `AutomatedMarketMaker` can not be called on a trait object.
+pub trait CustomAutomatedMarketMaker: AmmState {
+    /// Unique identifier of the protocol, used to look up the deserializer registered with `register_custom_amm`
+    fn protocol(&self) -> &'static str;
+    fn data_is_populated(&self) -> bool {
+        true
+    }
+
+    fn clone_box(&self) -> Box<dyn CustomAutomatedMarketMaker>;
+    /// Serializes the AMM state to a JSON string, which is stored alongside the protocol in checkpoints
+    fn serialize_state(&self) -> Result<String, serde_json::Error>;
+}
+
+pub type CustomAMMDeserializer =
+    fn(&str) -> Result<Box<dyn CustomAutomatedMarketMaker>, serde_json::Error>;
+
+lazy_static::lazy_static! {
+    static ref CUSTOM_AMM_DESERIALIZERS: RwLock<HashMap<String, CustomAMMDeserializer>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Registers the deserializer for a custom protocol so that checkpoints containing it can be loaded.
+pub fn register_custom_amm(protocol: &str, deserializer: CustomAMMDeserializer) {
+    CUSTOM_AMM_DESERIALIZERS
+        .write()
+        .expect("Custom AMM registry poisoned")
+        .insert(protocol.to_owned(), deserializer);
+}
+
+/// Default deserializer for custom AMMs that implement `Deserialize`, i.e. `register_custom_amm("foo", deserialize_custom_amm::<FooPool>)`
+pub fn deserialize_custom_amm<T>(
+    state: &str,
+) -> Result<Box<dyn CustomAutomatedMarketMaker>, serde_json::Error>
+where
+    T: 'static + CustomAutomatedMarketMaker + DeserializeOwned,
+{
+    Ok(Box::new(serde_json::from_str::<T>(state)?))
+}
+
+#[derive(Debug)]
+pub struct CustomAMM(pub Box<dyn CustomAutomatedMarketMaker>);
+
+impl CustomAMM {
+    pub fn new<T: 'static + CustomAutomatedMarketMaker>(amm: T) -> Self {
+        CustomAMM(Box::new(amm))
+    }
+}
+
+impl Clone for CustomAMM {
+    fn clone(&self) -> Self {
+        CustomAMM(self.0.clone_box())
+    }
+}
+
+impl Deref for CustomAMM {
+    type Target = dyn CustomAutomatedMarketMaker;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl DerefMut for CustomAMM {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut()
+    }
+}
+
+impl Serialize for CustomAMM {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let state = self.0.serialize_state().map_err(S::Error::custom)?;
+
+        let mut custom_amm = serializer.serialize_struct("CustomAMM", 2)?;
+        custom_amm.serialize_field("protocol", self.0.protocol())?;
+        custom_amm.serialize_field("state", &state)?;
+        custom_amm.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CustomAMM {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct SerializedCustomAMM {
+            protocol: String,
+            state: String,
+        }
+
+        let serialized = SerializedCustomAMM::deserialize(deserializer)?;
+
+        let custom_amm_deserializer = CUSTOM_AMM_DESERIALIZERS
+            .read()
+            .map_err(|_| D::Error::custom("Custom AMM registry poisoned"))?
+            .get(&serialized.protocol)
+            .copied()
+            .ok_or_else(|| {
+                D::Error::custom(format!(
+                    "No deserializer registered for custom AMM protocol {}",
+                    serialized.protocol
+                ))
+            })?;
+
+        custom_amm_deserializer(&serialized.state)
+            .map(CustomAMM)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Log, H160, H256, U256};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AmmState, AMM},
+        errors::{ArithmeticError, EventLogError, SwapSimulationError},
+        math::Q128x128,
+    };
+
+    use super::{
+        deserialize_custom_amm, register_custom_amm, CustomAMM, CustomAutomatedMarketMaker,
+    };
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct ConstantSumPool {
+        address: H160,
+        token_a: H160,
+        token_b: H160,
+    }
+
+    impl AmmState for ConstantSumPool {
+        fn address(&self) -> H160 {
+            self.address
+        }
+
+        fn sync_on_event_signatures(&self) -> Vec<H256> {
+            vec![]
+        }
+
+        fn tokens(&self) -> Vec<H160> {
+            vec![self.token_a, self.token_b]
+        }
+
+        fn calculate_price(&self, _base_token: H160) -> Result<f64, ArithmeticError> {
+            Ok(1.0)
+        }
+
+        fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+            Err(EventLogError::UnexpectedEvent(log.into()))
+        }
+
+        fn simulate_swap(
+            &self,
+            _token_in: H160,
+            amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            Ok(amount_in)
+        }
+
+        fn simulate_swap_mut(
+            &mut self,
+            token_in: H160,
+            amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            self.simulate_swap(token_in, amount_in)
+        }
+
+        fn get_token_out(&self, token_in: H160) -> H160 {
+            if token_in == self.token_a {
+                self.token_b
+            } else {
+                self.token_a
+            }
+        }
+
+        fn opp_token(&self, token: H160) -> Option<H160> {
+            if token == self.token_a {
+                Some(self.token_b)
+            } else if token == self.token_b {
+                Some(self.token_a)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl CustomAutomatedMarketMaker for ConstantSumPool {
+        fn protocol(&self) -> &'static str {
+            "constant_sum"
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomAutomatedMarketMaker> {
+            Box::new(self.clone())
+        }
+
+        fn serialize_state(&self) -> Result<String, serde_json::Error> {
+            serde_json::to_string(self)
+        }
+    }
+
+    #[test]
+    fn test_custom_amm_serde_round_trip() -> eyre::Result<()> {
+        register_custom_amm("constant_sum", deserialize_custom_amm::<ConstantSumPool>);
+
+        let amm = AMM::Custom(CustomAMM::new(ConstantSumPool {
+            address: H160::from_low_u64_be(1),
+            token_a: H160::from_low_u64_be(2),
+            token_b: H160::from_low_u64_be(3),
+        }));
+
+        let serialized = serde_json::to_string(&amm)?;
+        let deserialized: AMM = serde_json::from_str(&serialized)?;
+
+        assert_eq!(deserialized.address(), amm.address());
+        assert_eq!(deserialized.tokens(), amm.tokens());
+        assert_eq!(
+            deserialized.simulate_swap(H160::from_low_u64_be(2), U256::from(10))?,
+            U256::from(10)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_gradient() -> eyre::Result<()> {
+        let amm = AMM::Custom(CustomAMM::new(ConstantSumPool {
+            address: H160::from_low_u64_be(1),
+            token_a: H160::from_low_u64_be(2),
+            token_b: H160::from_low_u64_be(3),
+        }));
+
+        for amount_in in [U256::zero(), U256::from(10), U256::exp10(24)] {
+            assert_eq!(
+                amm.gradient(H160::from_low_u64_be(2), amount_in)?,
+                Q128x128::one()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_boxed_amm_state() -> eyre::Result<()> {
+        let token_a = H160::from_low_u64_be(2);
+        let token_b = H160::from_low_u64_be(3);
+
+        let mut amms: Vec<Box<dyn AmmState>> = vec![
+            Box::new(UniswapV2Pool {
+                address: H160::from_low_u64_be(1),
+                token_a,
+                token_b,
+                reserve_0: 10_u128.pow(24),
+                reserve_1: 10_u128.pow(24),
+                fee: 300,
+                ..Default::default()
+            }),
+            Box::new(ConstantSumPool {
+                address: H160::from_low_u64_be(4),
+                token_a,
+                token_b,
+            }),
+        ];
+
+        for amm in amms.iter_mut() {
+            assert_eq!(amm.get_token_out(token_a), token_b);
+            assert!(amm.simulate_swap(token_a, U256::exp10(18))? > U256::zero());
+            amm.simulate_swap_mut(token_a, U256::exp10(18))?;
+        }
+
+        assert!(amms[1].sync_from_log(&Log::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unregistered_custom_amm_fails_to_deserialize() {
+        let serialized = r#"{"Custom":{"protocol":"unregistered","state":"{}"}}"#;
+        assert!(serde_json::from_str::<AMM>(serialized).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use ethers::types::{Log, H160, H256, I256, U256};
+
+use crate::errors::EventLogError;
+
+//Reads values straight from the log topics and data at their ABI offsets, without allocating tokens
+
+/// First topic of the log, the signature of non anonymous events
+pub fn event_signature(log: &Log) -> Result<H256, EventLogError> {
+    topic(log, 0).copied()
+}
+
+/// Indexed `uint<bits>` stored in topic `index`
+pub fn topic_uint(log: &Log, index: usize, bits: usize) -> Result<U256, EventLogError> {
+    uint(log, topic(log, index)?.as_bytes(), bits)
+}
+
+/// Indexed `int<bits>` stored in topic `index`
+pub fn topic_int(log: &Log, index: usize, bits: usize) -> Result<I256, EventLogError> {
+    int(log, topic(log, index)?.as_bytes(), bits)
+}
+
+/// Indexed `address` stored in topic `index`
+pub fn topic_address(log: &Log, index: usize) -> Result<H160, EventLogError> {
+    address(log, topic(log, index)?.as_bytes())
+}
+
+/// `uint<bits>` stored in the data word at `index`
+pub fn data_uint(log: &Log, index: usize, bits: usize) -> Result<U256, EventLogError> {
+    uint(log, data_word(log, index)?, bits)
+}
+
+/// `int<bits>` stored in the data word at `index`
+pub fn data_int(log: &Log, index: usize, bits: usize) -> Result<I256, EventLogError> {
+    int(log, data_word(log, index)?, bits)
+}
+
+/// `address` stored in the data word at `index`
+pub fn data_address(log: &Log, index: usize) -> Result<H160, EventLogError> {
+    address(log, data_word(log, index)?)
+}
+
+/// Errors if the log was not emitted by `expected`
+pub fn check_address(log: &Log, expected: H160) -> Result<(), EventLogError> {
+    if log.address != expected {
+        return Err(EventLogError::LogAddressMismatch {
+            expected,
+            got: log.address,
+            context: log.into(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Errors if topic 1 of the log is not `expected`, the pool id of AMMs living in a singleton contract
+pub fn check_pool_id(log: &Log, expected: H256) -> Result<(), EventLogError> {
+    let got = *topic(log, 1)?;
+    if got != expected {
+        return Err(EventLogError::PoolIdMismatch {
+            expected,
+            got,
+            context: log.into(),
+        });
+    }
+
+    Ok(())
+}
+
+fn topic(log: &Log, index: usize) -> Result<&H256, EventLogError> {
+    log.topics
+        .get(index)
+        .ok_or_else(|| EventLogError::MissingTopic {
+            index,
+            context: log.into(),
+        })
+}
+
+fn data_word(log: &Log, index: usize) -> Result<&[u8], EventLogError> {
+    let end = index.saturating_add(1).saturating_mul(32);
+
+    log.data
+        .get(end - 32..end)
+        .ok_or_else(|| EventLogError::TruncatedData {
+            expected: end,
+            actual: log.data.len(),
+            context: log.into(),
+        })
+}
+
+//Words with bits set above the width of the type are not valid ABI encoding
+fn uint(log: &Log, word: &[u8], bits: usize) -> Result<U256, EventLogError> {
+    let value = U256::from_big_endian(word);
+
+    if value.bits() > bits {
+        return Err(EventLogError::MalformedLog(log.into()));
+    }
+
+    Ok(value)
+}
+
+//Addresses are left padded to the full word
+fn address(log: &Log, word: &[u8]) -> Result<H160, EventLogError> {
+    uint(log, word, 160)?;
+
+    Ok(H160::from_slice(&word[12..]))
+}
+
+//Negative values are sign extended to the full word
+fn int(log: &Log, word: &[u8], bits: usize) -> Result<I256, EventLogError> {
+    let value = I256::from_raw(U256::from_big_endian(word));
+
+    if bits < 256 {
+        let bound = I256::from_raw(U256::one() << (bits - 1));
+        if value < -bound || value >= bound {
+            return Err(EventLogError::MalformedLog(log.into()));
+        }
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        core::rand::{thread_rng, Rng},
+        types::{Log, H160, H256, I256, U256},
+    };
+
+    use crate::{
+        amm::{
+            erc_4626::{self, ERC4626Vault},
+            uniswap_v2::{self, UniswapV2Pool},
+            uniswap_v3::{self, UniswapV3Pool},
+            AmmState,
+        },
+        errors::EventLogError,
+    };
+
+    use super::{
+        data_address, data_int, data_uint, event_signature, topic_address, topic_int, topic_uint,
+    };
+
+    #[test]
+    fn test_decode() {
+        let log = Log {
+            topics: vec![
+                H256::from_low_u64_be(1),
+                H256::from_slice(&ethers::abi::encode(&[Token::Int(
+                    I256::from(-887272).into_raw(),
+                )])),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(u128::MAX)),
+                Token::Int(I256::from(-1).into_raw()),
+                Token::Int(I256::from(1 << 23).into_raw()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+
+        assert_eq!(event_signature(&log).unwrap(), H256::from_low_u64_be(1));
+        assert_eq!(topic_uint(&log, 0, 8).unwrap(), U256::one());
+        assert_eq!(topic_int(&log, 1, 24).unwrap(), I256::from(-887272));
+        assert!(topic_int(&log, 1, 16).is_err());
+        assert!(topic_int(&log, 2, 24).is_err());
+
+        assert_eq!(data_uint(&log, 0, 128).unwrap(), U256::from(u128::MAX));
+        assert!(data_uint(&log, 0, 112).is_err());
+        assert_eq!(data_int(&log, 1, 24).unwrap(), I256::from(-1));
+        //Only the low 24 bits are set, so the value is out of range as a signed int24
+        assert!(data_int(&log, 2, 24).is_err());
+        assert!(data_int(&log, 2, 25).is_ok());
+        assert!(data_uint(&log, 3, 256).is_err());
+        assert!(data_uint(&log, usize::MAX, 256).is_err());
+        assert!(data_uint(&log, usize::MAX / 32, 256).is_err());
+
+        assert!(event_signature(&Log::default()).is_err());
+    }
+
+    #[test]
+    fn test_decode_address() {
+        let address = H160::from_low_u64_be(u64::MAX);
+        let log = Log {
+            topics: vec![H256::from_low_u64_be(1), H256::from(address)],
+            data: ethers::abi::encode(&[Token::Address(address), Token::Uint(U256::MAX)]).into(),
+            ..Default::default()
+        };
+
+        assert_eq!(topic_address(&log, 1).unwrap(), address);
+        assert_eq!(data_address(&log, 0).unwrap(), address);
+        //Bits set in the padding are not a valid address
+        assert!(data_address(&log, 1).is_err());
+        assert!(topic_address(&log, 2).is_err());
+    }
+
+    #[test]
+    fn test_typed_log_errors() {
+        let address = H160::from_low_u64_be(1);
+        let mut amms: Vec<(Box<dyn AmmState>, H256)> = vec![
+            (
+                Box::new(UniswapV2Pool {
+                    address,
+                    ..Default::default()
+                }),
+                uniswap_v2::SYNC_EVENT_SIGNATURE,
+            ),
+            (
+                Box::new(UniswapV3Pool {
+                    address,
+                    ..Default::default()
+                }),
+                uniswap_v3::SWAP_EVENT_SIGNATURE,
+            ),
+            (
+                Box::new(ERC4626Vault {
+                    vault_token: address,
+                    ..Default::default()
+                }),
+                erc_4626::DEPOSIT_EVENT_SIGNATURE,
+            ),
+        ];
+
+        for (amm, signature) in amms.iter_mut() {
+            let log = Log {
+                address,
+                topics: vec![*signature],
+                data: vec![0; 31].into(),
+                block_number: Some(17000000.into()),
+                log_index: Some(7.into()),
+                ..Default::default()
+            };
+
+            //Truncated data, reported with the position of the log
+            let error = amm.sync_from_log(&log).unwrap_err();
+            assert!(matches!(
+                error,
+                EventLogError::TruncatedData { actual: 31, .. }
+            ));
+            let context = error.context().unwrap();
+            assert_eq!(context.address, address);
+            assert_eq!(context.topic0, Some(*signature));
+            assert_eq!(context.block_number, Some(17000000));
+            assert_eq!(context.log_index, Some(7.into()));
+
+            //Anonymous events have no signature to dispatch on
+            let anonymous = Log {
+                topics: vec![],
+                ..log.clone()
+            };
+            assert!(matches!(
+                amm.sync_from_log(&anonymous),
+                Err(EventLogError::MissingTopic { index: 0, .. })
+            ));
+
+            let unexpected_event = Log {
+                topics: vec![H256::from_low_u64_be(1)],
+                ..log.clone()
+            };
+            assert!(matches!(
+                amm.sync_from_log(&unexpected_event),
+                Err(EventLogError::UnexpectedEvent(_))
+            ));
+
+            let unexpected_address = Log {
+                address: H160::from_low_u64_be(2),
+                ..log.clone()
+            };
+            assert!(matches!(
+                amm.sync_from_log(&unexpected_address),
+                Err(EventLogError::LogAddressMismatch { expected, got, .. })
+                    if expected == address && got == unexpected_address.address
+            ));
+
+            //Extra topics are ignored rather than read out of place
+            let extra_topics = Log {
+                topics: vec![*signature; 6],
+                data: vec![0; 32 * 8].into(),
+                ..log.clone()
+            };
+            let _ = amm.sync_from_log(&extra_topics);
+        }
+    }
+
+    //Random topics and data for the events each AMM syncs on, none of which may panic
+    #[test]
+    fn test_malformed_logs_do_not_panic() {
+        let mut rng = thread_rng();
+
+        let mut v3_pool = UniswapV3Pool {
+            tick_spacing: 60,
+            ..Default::default()
+        };
+        v3_pool.modify_position(-600, 600, 10_i128.pow(18));
+
+        let amms: Vec<Box<dyn Fn(&Log)>> = vec![
+            Box::new(|log| {
+                let _ = UniswapV2Pool::default().sync_from_log(log);
+            }),
+            Box::new(|log| {
+                let _ = v3_pool.clone().sync_from_log(log);
+            }),
+            Box::new(|log| {
+                let _ = UniswapV3Pool::default().sync_from_log(log);
+            }),
+            Box::new(|log| {
+                let _ = ERC4626Vault::default().sync_from_log(log);
+            }),
+        ];
+
+        let signatures = [
+            uniswap_v2::SYNC_EVENT_SIGNATURE,
+            uniswap_v3::SWAP_EVENT_SIGNATURE,
+            uniswap_v3::MINT_EVENT_SIGNATURE,
+            uniswap_v3::BURN_EVENT_SIGNATURE,
+            erc_4626::DEPOSIT_EVENT_SIGNATURE,
+            erc_4626::WITHDRAW_EVENT_SIGNATURE,
+        ];
+
+        for _ in 0..5000 {
+            let mut topics = vec![];
+            if rng.gen_bool(0.95) {
+                topics.push(signatures[rng.gen_range(0..signatures.len())]);
+            }
+            for _ in 0..rng.gen_range(0..4) {
+                topics.push(random_word(&mut rng));
+            }
+
+            //Whole words followed by a few trailing bytes
+            let mut data = vec![];
+            for _ in 0..rng.gen_range(0..8) {
+                data.extend(random_word(&mut rng).to_fixed_bytes());
+            }
+            for _ in 0..rng.gen_range(0..4) {
+                data.push(rng.gen::<u8>());
+            }
+
+            for amm in &amms {
+                amm(&Log {
+                    address: H160::zero(),
+                    topics: topics.clone(),
+                    data: data.clone().into(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    //Mostly small or sign extended values, so that decoding succeeds often enough to reach the state updates
+    fn random_word(rng: &mut impl Rng) -> H256 {
+        let value = match rng.gen_range(0..4) {
+            0 => U256(rng.gen::<[u64; 4]>()),
+            1 => U256::from(rng.gen::<u128>()) >> rng.gen_range(0..128_usize),
+            2 => I256::from(rng.gen_range(-900_000..900_000) / 60 * 60).into_raw(),
+            _ => U256::from(rng.gen_range(0..=u8::MAX)),
+        };
+
+        H256::from_slice(&ethers::abi::encode(&[Token::Uint(value)]))
+    }
+}
@@ -0,0 +1,309 @@
+use std::{fmt, sync::Arc};
+
+use ethers::{
+    abi::Token,
+    contract::Multicall,
+    prelude::abigen,
+    providers::Middleware,
+    types::{Bytes, H160, I256, U256},
+};
+
+use crate::errors::AMMError;
+
+use super::{
+    erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool,
+    AutomatedMarketMaker, AMM,
+};
+
+abigen!(
+    IDetectableAMM,
+    r#"[
+        function getReserves() external view returns (uint112, uint112, uint32)
+        function slot0() external view returns (uint160, int24, uint16, uint16, uint16, uint8, bool)
+        function asset() external view returns (address)
+        function convertToShares(uint256) external view returns (uint256)
+        function fee() external view returns (uint24)
+        function tickSpacing() external view returns (int24)
+    ]"#;
+);
+
+//Fee given to detected V2 pools, since forks do not expose their fee in a common way
+const DEFAULT_UNISWAP_V2_FEE: u32 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedVariant {
+    UniswapV2Pool,
+    UniswapV3Pool,
+    ERC4626Vault,
+}
+
+impl fmt::Display for DetectedVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DetectedVariant::UniswapV2Pool => "Uniswap V2 pool",
+            DetectedVariant::UniswapV3Pool => "Uniswap V3 pool",
+            DetectedVariant::ERC4626Vault => "ERC4626 vault",
+        })
+    }
+}
+
+/// Result of probing a contract with the selectors that discriminate between the AMM variants
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DetectionBasis {
+    pub get_reserves: bool,
+    pub slot_0: bool,
+    pub asset: Option<H160>,
+    pub convert_to_shares: bool,
+    pub fee: Option<u32>,
+    pub tick_spacing: Option<i32>,
+}
+
+impl DetectionBasis {
+    /// Probes `address` in a single multicall, erroring if there is no contract at the address
+    pub async fn probe<M: 'static + Middleware>(
+        address: H160,
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        let code = middleware
+            .get_code(address, None)
+            .await
+            .map_err(AMMError::MiddlewareError)?;
+
+        if code.is_empty() {
+            return Err(AMMError::NoContractCode(address));
+        }
+
+        let contract = IDetectableAMM::new(address, middleware.clone());
+        let mut multicall = Multicall::new(middleware.clone(), None).await?;
+        multicall
+            .add_call(contract.get_reserves(), true)
+            .add_call(contract.slot_0(), true)
+            .add_call(contract.asset(), true)
+            .add_call(contract.convert_to_shares(U256::exp10(18)), true)
+            .add_call(contract.fee(), true)
+            .add_call(contract.tick_spacing(), true);
+
+        let detection_basis = DetectionBasis::from_probe(&multicall.call_raw().await?);
+        tracing::debug!(?address, ?detection_basis, "probed contract");
+
+        Ok(detection_basis)
+    }
+
+    fn from_probe(probe: &[Result<Token, Bytes>]) -> Self {
+        let token = |idx: usize| probe.get(idx).and_then(|result| result.clone().ok());
+
+        DetectionBasis {
+            get_reserves: matches!(token(0), Some(Token::Tuple(_))),
+            slot_0: matches!(token(1), Some(Token::Tuple(_))),
+            asset: token(2)
+                .and_then(|asset| asset.into_address())
+                .filter(|asset| !asset.is_zero()),
+            convert_to_shares: matches!(token(3), Some(Token::Uint(_))),
+            fee: token(4)
+                .and_then(|fee| fee.into_uint())
+                .map(|fee| fee.low_u32()),
+            tick_spacing: token(5)
+                .and_then(|tick_spacing| tick_spacing.into_int())
+                .map(|tick_spacing| I256::from_raw(tick_spacing).low_i32()),
+        }
+    }
+
+    /// The most specific variant matched by the probe, contracts implementing several interfaces
+    /// (i.e. proxies) resolve to the variant with the most matching selectors.
+    pub fn variant(&self) -> Option<DetectedVariant> {
+        if self.slot_0 && self.fee.is_some() && self.tick_spacing.is_some() {
+            Some(DetectedVariant::UniswapV3Pool)
+        } else if self.asset.is_some() && self.convert_to_shares {
+            Some(DetectedVariant::ERC4626Vault)
+        } else if self.get_reserves {
+            Some(DetectedVariant::UniswapV2Pool)
+        } else {
+            None
+        }
+    }
+}
+
+impl AMM {
+    /// Detects whether `address` is a V2 pool, V3 pool or ERC4626 vault and returns the populated AMM.
+    /// V3 tick data is not populated and V2 pools are given a 0.3% fee.
+    pub async fn try_detect<M: 'static + Middleware>(
+        address: H160,
+        middleware: Arc<M>,
+    ) -> Result<AMM, AMMError<M>> {
+        Ok(AMM::try_detect_with_basis(address, middleware).await?.0)
+    }
+
+    /// Same as `try_detect`, also returning the probe results the variant was detected from
+    pub async fn try_detect_with_basis<M: 'static + Middleware>(
+        address: H160,
+        middleware: Arc<M>,
+    ) -> Result<(AMM, DetectionBasis), AMMError<M>> {
+        let detection_basis = DetectionBasis::probe(address, middleware.clone()).await?;
+
+        let mut amm = match detection_basis.variant() {
+            Some(DetectedVariant::UniswapV3Pool) => AMM::UniswapV3Pool(UniswapV3Pool {
+                address,
+                fee: detection_basis.fee.unwrap_or_default(),
+                tick_spacing: detection_basis.tick_spacing.unwrap_or_default(),
+                ..Default::default()
+            }),
+            Some(DetectedVariant::ERC4626Vault) => AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: address,
+                ..Default::default()
+            }),
+            Some(DetectedVariant::UniswapV2Pool) => AMM::UniswapV2Pool(UniswapV2Pool {
+                address,
+                fee: DEFAULT_UNISWAP_V2_FEE,
+                ..Default::default()
+            }),
+            None => return Err(AMMError::UndetectedAMM(address)),
+        };
+
+        amm.populate_data(None, middleware).await?;
+
+        let data_is_populated = match &amm {
+            AMM::UniswapV2Pool(pool) => pool.data_is_populated(),
+            AMM::UniswapV3Pool(pool) => pool.data_is_populated(),
+            AMM::ERC4626Vault(vault) => vault.data_is_populated(),
+            AMM::CurveStableSwapPool(pool) => pool.data_is_populated(),
+            AMM::BalancerWeightedPool(pool) => pool.data_is_populated(),
+            AMM::VelodromePool(pool) => pool.data_is_populated(),
+            AMM::UniswapV4Pool(pool) => pool.data_is_populated(),
+            AMM::LiquidityBookPool(pool) => pool.data_is_populated(),
+            AMM::MaverickPool(pool) => pool.data_is_populated(),
+            AMM::DodoPool(pool) => pool.data_is_populated(),
+            AMM::KyberElasticPool(pool) => pool.data_is_populated(),
+            AMM::AlgebraPool(pool) => pool.data_is_populated(),
+            AMM::CurveCryptoPool(pool) => pool.data_is_populated(),
+            AMM::BancorV3Pool(pool) => pool.data_is_populated(),
+            AMM::Custom(amm) => amm.data_is_populated(),
+        };
+
+        if !data_is_populated {
+            return Err(AMMError::PoolDataError);
+        }
+
+        Ok((amm, detection_basis))
+    }
+}
+
+/// Errors with `AMMError::AMMVariantMismatch` unless the contract at `address` is detected as `expected`,
+/// used by the constructors populating a pool from its address
+pub(crate) async fn expect_variant<M: 'static + Middleware>(
+    address: H160,
+    expected: DetectedVariant,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let detected = DetectionBasis::probe(address, middleware).await?.variant();
+
+    if detected != Some(expected) {
+        return Err(AMMError::AMMVariantMismatch {
+            address,
+            expected,
+            detected,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use ethers::{
+        abi::Token,
+        providers::{Http, Provider},
+        types::{Bytes, H160, I256, U256},
+    };
+
+    use crate::amm::{AmmState, AMM};
+
+    use super::{DetectedVariant, DetectionBasis};
+
+    #[test]
+    fn test_detection_basis_variant() {
+        let reverted: Result<Token, Bytes> = Err(Bytes::new());
+        let tuple = Ok(Token::Tuple(vec![Token::Uint(U256::one())]));
+        let asset = Ok(Token::Address(H160::from_low_u64_be(1)));
+        let uint = Ok(Token::Uint(U256::from(500)));
+        let tick_spacing = Ok(Token::Int(I256::from(-10).into_raw()));
+
+        let v2 = DetectionBasis::from_probe(&[
+            tuple.clone(),
+            reverted.clone(),
+            reverted.clone(),
+            reverted.clone(),
+            reverted.clone(),
+            reverted.clone(),
+        ]);
+        assert_eq!(v2.variant(), Some(DetectedVariant::UniswapV2Pool));
+
+        let v3 = DetectionBasis::from_probe(&[
+            reverted.clone(),
+            tuple.clone(),
+            reverted.clone(),
+            reverted.clone(),
+            uint.clone(),
+            tick_spacing.clone(),
+        ]);
+        assert_eq!(v3.variant(), Some(DetectedVariant::UniswapV3Pool));
+        assert_eq!(v3.fee, Some(500));
+        assert_eq!(v3.tick_spacing, Some(-10));
+
+        let vault = DetectionBasis::from_probe(&[
+            reverted.clone(),
+            reverted.clone(),
+            asset.clone(),
+            uint.clone(),
+            reverted.clone(),
+            reverted.clone(),
+        ]);
+        assert_eq!(vault.variant(), Some(DetectedVariant::ERC4626Vault));
+
+        //A proxy implementing every interface resolves to the most specific match
+        let proxy = DetectionBasis::from_probe(&[
+            tuple.clone(),
+            tuple,
+            asset,
+            uint.clone(),
+            uint,
+            tick_spacing,
+        ]);
+        assert_eq!(proxy.variant(), Some(DetectedVariant::UniswapV3Pool));
+
+        assert_eq!(
+            DetectionBasis::from_probe(&vec![reverted; 6]).variant(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_try_detect() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let v2_pool = H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?;
+        assert!(matches!(
+            AMM::try_detect(v2_pool, middleware.clone()).await?,
+            AMM::UniswapV2Pool(_)
+        ));
+
+        let v3_pool = H160::from_str("0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640")?;
+        match AMM::try_detect(v3_pool, middleware.clone()).await? {
+            AMM::UniswapV3Pool(pool) => {
+                assert_eq!(pool.fee, 500);
+                assert_eq!(pool.tick_spacing, 10);
+                assert_eq!(pool.address(), v3_pool);
+            }
+            _ => panic!("Expected a UniswapV3 pool"),
+        }
+
+        //Addresses without code are rejected before probing
+        assert!(AMM::try_detect(H160::from_low_u64_be(1234), middleware)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
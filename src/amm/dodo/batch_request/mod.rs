@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{DodoPool, PMMState, RState, ONE};
+
+//_BASE_TOKEN_()
+const BASE_TOKEN_SELECTOR: [u8; 4] = [74, 36, 141, 42];
+//_QUOTE_TOKEN_()
+const QUOTE_TOKEN_SELECTOR: [u8; 4] = [212, 185, 112, 70];
+//getPMMState()
+const GET_PMM_STATE_SELECTOR: [u8; 4] = [163, 130, 209, 185];
+//getUserFeeRate(address)
+const GET_USER_FEE_RATE_SELECTOR: [u8; 4] = [68, 9, 102, 9];
+
+/// Reads `pool` at `block_number`, erroring with `AMMError::BatchRequestError` if it is not a DODO V2 pool
+pub async fn get_dodo_pool_data_batch_request<M: Middleware>(
+    pool: &mut DodoPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every DODO pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be read
+/// as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::DodoPool(dodo_pool) = amm {
+            if let Some(pool) = populate_pool_data(dodo_pool.to_owned(), pool_data) {
+                *dodo_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    tokens: Option<(H160, H160)>,
+    state: Option<PMMState>,
+    //lpFeeRate and mtFeeRate of the zero address
+    fee_rates: Option<(U256, U256)>,
+    decimals: (Option<u8>, Option<u8>),
+}
+
+fn populate_pool_data(mut pool: DodoPool, pool_data: PoolData) -> Option<DodoPool> {
+    (pool.token_a, pool.token_b) = pool_data.tokens?;
+    (pool.token_a_decimals, pool.token_b_decimals) = (pool_data.decimals.0?, pool_data.decimals.1?);
+    pool.state = pool_data.state?;
+    (pool.lp_fee_rate, pool.mt_fee_rate) = pool_data.fee_rates?;
+
+    Some(pool)
+}
+
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let user_fee_rate_args = ethers::abi::encode(&[Token::Address(H160::zero())]);
+    let calls = pools
+        .iter()
+        .flat_map(|pool| {
+            [
+                multicall::call(*pool, BASE_TOKEN_SELECTOR, &[]),
+                multicall::call(*pool, QUOTE_TOKEN_SELECTOR, &[]),
+                multicall::call(*pool, GET_PMM_STATE_SELECTOR, &[]),
+                multicall::call(*pool, GET_USER_FEE_RATE_SELECTOR, &user_fee_rate_args),
+            ]
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(4)
+        .map(|pool_return_data| PoolData {
+            tokens: address(&pool_return_data[0]).zip(address(&pool_return_data[1])),
+            state: pmm_state(&pool_return_data[2]),
+            fee_rates: fee_rates(&pool_return_data[3]),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pool_data
+        .iter()
+        .filter_map(|pool_data| pool_data.tokens)
+        .flat_map(|(token_a, token_b)| [token_a, token_b])
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+    for pool_data in pool_data.iter_mut() {
+        if let Some((token_a, token_b)) = pool_data.tokens {
+            pool_data.decimals = (
+                decimals.get(&token_a).copied(),
+                decimals.get(&token_b).copied(),
+            );
+        }
+    }
+
+    Ok(pool_data)
+}
+
+//uint256 i, uint256 K, uint256 B, uint256 Q, uint256 B0, uint256 Q0, uint8 R, with the targets already adjusted to
+//the reserves. Vending machines have no quote target and report R as above one.
+fn pmm_state(return_data: &Option<Bytes>) -> Option<PMMState> {
+    let r = word(return_data, 6)
+        .filter(|r| *r <= U256::from(u8::MAX))
+        .and_then(|r| RState::from_u8(r.as_u32() as u8))?;
+
+    Some(PMMState {
+        i: word(return_data, 0)?,
+        k: word(return_data, 1).filter(|k| *k <= ONE)?,
+        base_reserve: word(return_data, 2)?,
+        quote_reserve: word(return_data, 3)?,
+        base_target: word(return_data, 4)?,
+        quote_target: word(return_data, 5)?,
+        r,
+    })
+}
+
+fn fee_rates(return_data: &Option<Bytes>) -> Option<(U256, U256)> {
+    let lp_fee_rate = word(return_data, 0).filter(|rate| *rate <= ONE)?;
+    let mt_fee_rate = word(return_data, 1).filter(|rate| *rate <= ONE)?;
+
+    Some((lp_fee_rate, mt_fee_rate))
+}
+
+fn word(return_data: &Option<Bytes>, index: usize) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, index)
+}
+
+fn address(return_data: &Option<Bytes>) -> Option<H160> {
+    multicall::address_word(return_data.as_ref()?, 0).filter(|address| !address.is_zero())
+}
@@ -0,0 +1,1080 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::{
+    prelude::abigen,
+    types::{Log, H160, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::fixed_point::u256_to_f64_lossy,
+    tokens::TokenStore,
+};
+
+abigen!(
+    IDODOV2,
+    r#"[
+        function querySellBase(address trader, uint256 payBaseAmount) external view returns (uint256 receiveQuoteAmount, uint256 mtFee)
+        function querySellQuote(address trader, uint256 payQuoteAmount) external view returns (uint256 receiveBaseAmount, uint256 mtFee)
+    ]"#;
+);
+
+//DODOSwap(address fromToken, address toToken, uint256 fromAmount, uint256 toAmount, address trader, address receiver)
+pub const DODO_SWAP_EVENT_SIGNATURE: H256 = H256([
+    194, 192, 36, 94, 5, 109, 95, 176, 149, 240, 76, 214, 55, 59, 199, 112, 128, 46, 189, 30, 108,
+    145, 142, 183, 143, 222, 248, 67, 205, 179, 123, 15,
+]);
+//LpFeeRateChange(uint256 newLpFeeRate)
+pub const LP_FEE_RATE_CHANGE_EVENT_SIGNATURE: H256 = H256([
+    153, 80, 213, 162, 242, 199, 38, 72, 99, 212, 1, 0, 191, 153, 63, 12, 219, 196, 113, 24, 6,
+    202, 186, 98, 132, 208, 126, 128, 253, 80, 8, 121,
+]);
+
+/// Denominator of `i`, `k` and the fee rates, i.e. a `k` of 1e17 is 0.1
+pub const ONE: U256 = U256([1_000_000_000_000_000_000, 0, 0, 0]);
+//ONE squared
+const ONE2: U256 = U256([12_919_594_847_110_692_864, 54_210_108_624_275_221, 0, 0]);
+
+/// Side the reserves of a pool are off their targets, as `PMMPricing.RState`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RState {
+    /// Both reserves are at their targets
+    #[default]
+    One,
+    /// The pool holds less base and more quote than its targets
+    AboveOne,
+    /// The pool holds more base and less quote than its targets
+    BelowOne,
+}
+
+impl RState {
+    /// The state of the `uint8` value of the enum in the pools
+    pub fn from_u8(r: u8) -> Option<RState> {
+        match r {
+            0 => Some(RState::One),
+            1 => Some(RState::AboveOne),
+            2 => Some(RState::BelowOne),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            RState::One => 0,
+            RState::AboveOne => 1,
+            RState::BelowOne => 2,
+        }
+    }
+}
+
+/// State of a pool as returned by `getPMMState`, with the target off its reserve adjusted to the reserves
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PMMState {
+    /// Guide price of the base token in the quote token over `ONE`, in raw units
+    pub i: U256,
+    /// Slippage factor over `ONE`, from a constant price at 0 to a constant product at `ONE`
+    pub k: U256,
+    /// B
+    pub base_reserve: U256,
+    /// Q
+    pub quote_reserve: U256,
+    /// B0
+    pub base_target: U256,
+    /// Q0
+    pub quote_target: U256,
+    /// R
+    pub r: RState,
+}
+
+impl PMMState {
+    /// Amount of quote paid for `pay_base_amount`, before fees, and the R state after the trade, as
+    /// `PMMPricing.sellBaseToken`
+    pub fn sell_base_token(
+        &self,
+        pay_base_amount: U256,
+    ) -> Result<(U256, RState), ArithmeticError> {
+        match self.r {
+            RState::One => Ok((
+                self.r_one_sell_base_token(pay_base_amount)?,
+                RState::BelowOne,
+            )),
+            RState::AboveOne => {
+                let back_to_one_pay_base = sub(self.base_target, self.base_reserve)?;
+                let back_to_one_receive_quote = sub(self.quote_reserve, self.quote_target)?;
+
+                if pay_base_amount < back_to_one_pay_base {
+                    //Rounding can make the integral overshoot the quote above the target
+                    let receive_quote_amount = general_integrate(
+                        self.base_target,
+                        add(self.base_reserve, pay_base_amount)?,
+                        self.base_reserve,
+                        self.i,
+                        self.k,
+                    )?;
+
+                    Ok((
+                        receive_quote_amount.min(back_to_one_receive_quote),
+                        RState::AboveOne,
+                    ))
+                } else if pay_base_amount == back_to_one_pay_base {
+                    Ok((back_to_one_receive_quote, RState::One))
+                } else {
+                    Ok((
+                        add(
+                            back_to_one_receive_quote,
+                            self.r_one_sell_base_token(sub(
+                                pay_base_amount,
+                                back_to_one_pay_base,
+                            )?)?,
+                        )?,
+                        RState::BelowOne,
+                    ))
+                }
+            }
+            RState::BelowOne => Ok((
+                solve_quadratic_function_for_trade(
+                    self.quote_target,
+                    self.quote_reserve,
+                    pay_base_amount,
+                    self.i,
+                    self.k,
+                )?,
+                RState::BelowOne,
+            )),
+        }
+    }
+
+    /// Amount of base paid for `pay_quote_amount`, before fees, and the R state after the trade, as
+    /// `PMMPricing.sellQuoteToken`
+    pub fn sell_quote_token(
+        &self,
+        pay_quote_amount: U256,
+    ) -> Result<(U256, RState), ArithmeticError> {
+        match self.r {
+            RState::One => Ok((
+                self.r_one_sell_quote_token(pay_quote_amount)?,
+                RState::AboveOne,
+            )),
+            RState::AboveOne => Ok((
+                solve_quadratic_function_for_trade(
+                    self.base_target,
+                    self.base_reserve,
+                    pay_quote_amount,
+                    reciprocal_floor(self.i)?,
+                    self.k,
+                )?,
+                RState::AboveOne,
+            )),
+            RState::BelowOne => {
+                let back_to_one_pay_quote = sub(self.quote_target, self.quote_reserve)?;
+                let back_to_one_receive_base = sub(self.base_reserve, self.base_target)?;
+
+                if pay_quote_amount < back_to_one_pay_quote {
+                    let receive_base_amount = general_integrate(
+                        self.quote_target,
+                        add(self.quote_reserve, pay_quote_amount)?,
+                        self.quote_reserve,
+                        reciprocal_floor(self.i)?,
+                        self.k,
+                    )?;
+
+                    Ok((
+                        receive_base_amount.min(back_to_one_receive_base),
+                        RState::BelowOne,
+                    ))
+                } else if pay_quote_amount == back_to_one_pay_quote {
+                    Ok((back_to_one_receive_base, RState::One))
+                } else {
+                    Ok((
+                        add(
+                            back_to_one_receive_base,
+                            self.r_one_sell_quote_token(sub(
+                                pay_quote_amount,
+                                back_to_one_pay_quote,
+                            )?)?,
+                        )?,
+                        RState::AboveOne,
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Recomputes the target the reserves are off of from the other target, as `PMMPricing.adjustedTarget`
+    pub fn adjust_target(&mut self) -> Result<(), ArithmeticError> {
+        match self.r {
+            RState::BelowOne => {
+                self.quote_target = solve_quadratic_function_for_target(
+                    self.quote_reserve,
+                    sub(self.base_reserve, self.base_target)?,
+                    self.i,
+                    self.k,
+                )?;
+            }
+            RState::AboveOne => {
+                self.base_target = solve_quadratic_function_for_target(
+                    self.base_reserve,
+                    sub(self.quote_reserve, self.quote_target)?,
+                    reciprocal_floor(self.i)?,
+                    self.k,
+                )?;
+            }
+            RState::One => {}
+        }
+
+        Ok(())
+    }
+
+    /// Marginal price of the base token in the quote token over `ONE`, in raw units, as `PMMPricing.getMidPrice`
+    pub fn mid_price(&self) -> Result<U256, ArithmeticError> {
+        let one_minus_k = sub(ONE, self.k)?;
+
+        if self.r == RState::BelowOne {
+            let r = div_floor(
+                div(
+                    mul(self.quote_target, self.quote_target)?,
+                    self.quote_reserve,
+                )?,
+                self.quote_reserve,
+            )?;
+            div_floor(self.i, add(one_minus_k, mul_floor(self.k, r)?)?)
+        } else {
+            let r = div_floor(
+                div(mul(self.base_target, self.base_target)?, self.base_reserve)?,
+                self.base_reserve,
+            )?;
+            mul_floor(self.i, add(one_minus_k, mul_floor(self.k, r)?)?)
+        }
+    }
+
+    fn r_one_sell_base_token(&self, pay_base_amount: U256) -> Result<U256, ArithmeticError> {
+        solve_quadratic_function_for_trade(
+            self.quote_target,
+            self.quote_target,
+            pay_base_amount,
+            self.i,
+            self.k,
+        )
+    }
+
+    fn r_one_sell_quote_token(&self, pay_quote_amount: U256) -> Result<U256, ArithmeticError> {
+        solve_quadratic_function_for_trade(
+            self.base_target,
+            self.base_target,
+            pay_quote_amount,
+            reciprocal_floor(self.i)?,
+            self.k,
+        )
+    }
+}
+
+/// DODO V2 proactive market maker pool, a vending machine, stable or private pool.
+///
+/// The pool quotes around its guide price `i`, with slippage growing with `k` as the reserves move away from their
+/// targets. Once the reserves are off their targets the pool prices against the side holding less than its target,
+/// so quotes only follow a constant product when `k` is `ONE` and the pool is balanced.
+///
+/// Swaps are synced by simulating them again, the maintainer fee of the trader being whatever the amount out of the
+/// log leaves. Liquidity changes are not logged with their amounts and private pools reset their guide price, `k`
+/// and reserves with only their new LP fee rate logged, so pools are read again periodically.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DodoPool {
+    pub address: H160,
+    /// Base token of the pool
+    pub token_a: H160,
+    pub token_a_decimals: u8,
+    /// Quote token of the pool
+    pub token_b: H160,
+    pub token_b_decimals: u8,
+    pub state: PMMState,
+    /// Fee kept by the pool, over `ONE` of the amount out
+    pub lp_fee_rate: U256,
+    /// Fee paid to the maintainer by the zero address, over `ONE` of the amount out. The fee rate model of the pool
+    /// can charge other traders another rate.
+    pub mt_fee_rate: U256,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+//Outcome of a swap, applied by `simulate_swap_mut`
+struct SwapState {
+    //Amount paid to the zero address, net of both fees
+    amount_out: U256,
+    //Amount leaving the pool, net of the LP fee only
+    amount_withdrawn: U256,
+    state: PMMState,
+}
+
+impl AmmState for DodoPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        vec![self.token_a, self.token_b]
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Mid price of a whole `base_token` in whole units of the other token, without the fees
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let price = u256_to_f64_lossy(self.state.mid_price()?) / 1e18
+            * 10_f64.powi(self.token_a_decimals as i32 - self.token_b_decimals as i32);
+        let price = if base_token == self.token_a {
+            price
+        } else {
+            1.0 / price
+        };
+
+        if !price.is_finite() || price == 0.0 {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![
+            DODO_SWAP_EVENT_SIGNATURE,
+            LP_FEE_RATE_CHANGE_EVENT_SIGNATURE,
+        ]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+
+        match decode::event_signature(log)? {
+            DODO_SWAP_EVENT_SIGNATURE => self.sync_from_swap_log(log),
+            LP_FEE_RATE_CHANGE_EVENT_SIGNATURE => {
+                let lp_fee_rate = decode::data_uint(log, 0, 256)?;
+                if lp_fee_rate > ONE {
+                    return Err(EventLogError::MalformedLog(log.into()));
+                }
+                self.lp_fee_rate = lp_fee_rate;
+
+                Ok(())
+            }
+            _ => Err(EventLogError::UnexpectedEvent(log.into())),
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::Dodo.name(),
+            &[
+                self.state.i,
+                self.state.k,
+                self.state.base_reserve,
+                self.state.quote_reserve,
+                self.state.base_target,
+                self.state.quote_target,
+                U256::from(self.state.r.as_u8()),
+                self.lp_fee_rate,
+                self.mt_fee_rate,
+            ],
+        )
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        Ok(self.swap(token_in, amount_in)?.amount_out)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let swap = self.swap(token_in, amount_in)?;
+        self.state = swap.state;
+
+        Ok(swap.amount_out)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token_a == token_in {
+            self.token_b
+        } else {
+            self.token_a
+        }
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if token == self.token_a {
+            Some(self.token_b)
+        } else if token == self.token_b {
+            Some(self.token_a)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for DodoPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_dodo_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_dodo_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl DodoPool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: H160,
+        token_a: H160,
+        token_a_decimals: u8,
+        token_b: H160,
+        token_b_decimals: u8,
+        state: PMMState,
+        lp_fee_rate: U256,
+        mt_fee_rate: U256,
+    ) -> DodoPool {
+        DodoPool {
+            address,
+            token_a,
+            token_a_decimals,
+            token_b,
+            token_b_decimals,
+            state,
+            lp_fee_rate,
+            mt_fee_rate,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.token_a.is_zero() || self.token_b.is_zero() || self.state.i.is_zero())
+            && !(self.state.base_reserve.is_zero() && self.state.quote_reserve.is_zero())
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.token_a {
+            Some(self.token_a_decimals)
+        } else if token == self.token_b {
+            Some(self.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of both tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Fee of a swap over `ONE` of the amount out, for the zero address
+    pub fn total_fee(&self) -> U256 {
+        self.lp_fee_rate.saturating_add(self.mt_fee_rate)
+    }
+
+    //`querySellBase` and `querySellQuote` of the pools for the zero address, and the state the trade leaves
+    fn swap(&self, token_in: H160, amount_in: U256) -> Result<SwapState, SwapSimulationError> {
+        let sell_base = if token_in == self.token_a {
+            true
+        } else if token_in == self.token_b {
+            false
+        } else {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        };
+
+        let (receive_amount, r) = if sell_base {
+            self.state.sell_base_token(amount_in)?
+        } else {
+            self.state.sell_quote_token(amount_in)?
+        };
+        let amount_withdrawn = sub(receive_amount, mul_floor(receive_amount, self.lp_fee_rate)?)?;
+        let amount_out = sub(
+            amount_withdrawn,
+            mul_floor(receive_amount, self.mt_fee_rate)?,
+        )?;
+
+        //The target the reserves moved off of is kept when R changes, the other one follows the reserves
+        let mut state = self.state;
+        if sell_base {
+            state.base_reserve = add(state.base_reserve, amount_in)?;
+            state.quote_reserve = sub(state.quote_reserve, amount_withdrawn)?;
+        } else {
+            state.quote_reserve = add(state.quote_reserve, amount_in)?;
+            state.base_reserve = sub(state.base_reserve, amount_withdrawn)?;
+        }
+        state.r = r;
+        state.adjust_target()?;
+
+        Ok(SwapState {
+            amount_out,
+            amount_withdrawn,
+            state,
+        })
+    }
+
+    //Swaps log the amount paid to the receiver, which is short of the amount leaving the pool by the maintainer fee
+    //of the trader
+    fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let from_token = decode::data_address(log, 0)?;
+        let to_token = decode::data_address(log, 1)?;
+        let from_amount = decode::data_uint(log, 2, 256)?;
+        let to_amount = decode::data_uint(log, 3, 256)?;
+        if self.get_token_out(from_token) != to_token {
+            return Err(EventLogError::MalformedLog(log.into()));
+        }
+
+        match self.swap(from_token, from_amount) {
+            Ok(swap) if to_amount <= swap.amount_withdrawn => {
+                self.state = swap.state;
+
+                Ok(())
+            }
+            _ => Err(EventLogError::MalformedLog(log.into())),
+        }
+    }
+}
+
+/// Integral of the price curve from `v2` to `v1` of the side off its target `v0`, as `DODOMath._GeneralIntegrate`
+pub fn general_integrate(
+    v0: U256,
+    v1: U256,
+    v2: U256,
+    i: U256,
+    k: U256,
+) -> Result<U256, ArithmeticError> {
+    if v0.is_zero() {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+
+    let fair_amount = mul(i, sub(v1, v2)?)?;
+    if k.is_zero() {
+        return div(fair_amount, ONE);
+    }
+
+    let v0_v0_v1_v2 = div_floor(div(mul(v0, v0)?, v1)?, v2)?;
+    let penalty = mul_floor(k, v0_v0_v1_v2)?;
+
+    div(mul(add(sub(ONE, k)?, penalty)?, fair_amount)?, ONE2)
+}
+
+/// Target of a side holding `v1` after `delta` of the other side was paid out of its target, as
+/// `DODOMath._SolveQuadraticFunctionForTarget`
+pub fn solve_quadratic_function_for_target(
+    v1: U256,
+    delta: U256,
+    i: U256,
+    k: U256,
+) -> Result<U256, ArithmeticError> {
+    if k.is_zero() {
+        return add(v1, mul_floor(i, delta)?);
+    }
+    if v1.is_zero() {
+        return Ok(U256::zero());
+    }
+
+    //The pools fall back to a less precise order of operations where `ki * delta` overflows
+    let ki = mul(mul(U256::from(4), k)?, i)?;
+    let sqrt = if ki.is_zero() {
+        ONE
+    } else {
+        match ki.checked_mul(delta) {
+            Some(ki_delta) => babylonian_sqrt(add(div(ki_delta, v1)?, ONE2)?),
+            None => babylonian_sqrt(add(mul(div(ki, v1)?, delta)?, ONE2)?),
+        }
+    };
+    let premium = add(div_floor(sub(sqrt, ONE)?, mul(k, U256::from(2))?)?, ONE)?;
+
+    mul_floor(v1, premium)
+}
+
+/// Amount paid out of a side holding `v1` of its target `v0` for `delta` of the other side, as
+/// `DODOMath._SolveQuadraticFunctionForTrade`
+pub fn solve_quadratic_function_for_trade(
+    v0: U256,
+    v1: U256,
+    delta: U256,
+    i: U256,
+    k: U256,
+) -> Result<U256, ArithmeticError> {
+    if v0.is_zero() {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+    if delta.is_zero() {
+        return Ok(U256::zero());
+    }
+
+    if k.is_zero() {
+        return Ok(mul_floor(i, delta)?.min(v1));
+    }
+
+    if k == ONE {
+        let i_delta = mul(i, delta)?;
+        let temp = if i_delta.is_zero() {
+            U256::zero()
+        } else {
+            match i_delta.checked_mul(v1) {
+                Some(i_delta_v1) => div(i_delta_v1, mul(v0, v0)?)?,
+                None => div(mul(div(mul(delta, v1)?, v0)?, i)?, v0)?,
+            }
+        };
+
+        return div(mul(v1, temp)?, add(temp, ONE)?);
+    }
+
+    //-b of the quadratic, kQ0^2/Q1 + i*delta - (1-k)Q1, as an absolute value and its sign
+    let part2 = add(mul(div(mul(k, v0)?, v1)?, v0)?, mul(i, delta)?)?;
+    let part1 = mul(sub(ONE, k)?, v1)?;
+    let (b_abs, b_sig) = if part1 >= part2 {
+        (part1 - part2, false)
+    } else {
+        (part2 - part1, true)
+    };
+    let b_abs = div(b_abs, ONE)?;
+
+    //sqrt(b^2 + 4(1-k)kQ0^2)
+    let square_root = mul_floor(
+        mul(sub(ONE, k)?, U256::from(4))?,
+        mul(mul_floor(k, v0)?, v0)?,
+    )?;
+    let square_root = babylonian_sqrt(add(mul(b_abs, b_abs)?, square_root)?);
+
+    let denominator = mul(sub(ONE, k)?, U256::from(2))?;
+    let numerator = if b_sig {
+        sub(square_root, b_abs)?
+    } else {
+        add(b_abs, square_root)?
+    };
+
+    let v2 = div_ceil(numerator, denominator)?;
+    Ok(v1.saturating_sub(v2))
+}
+
+//`Math.sqrt` of the pools, which rounds 2 up to 2
+fn babylonian_sqrt(x: U256) -> U256 {
+    let mut z = x / 2 + 1;
+    let mut y = x;
+    while z < y {
+        y = z;
+        z = (x / z + z) / 2;
+    }
+
+    y
+}
+
+fn mul_floor(target: U256, d: U256) -> Result<U256, ArithmeticError> {
+    div(mul(target, d)?, ONE)
+}
+
+fn div_floor(target: U256, d: U256) -> Result<U256, ArithmeticError> {
+    div(mul(target, ONE)?, d)
+}
+
+fn div_ceil(target: U256, d: U256) -> Result<U256, ArithmeticError> {
+    let numerator = mul(target, ONE)?;
+    let quotient = div(numerator, d)?;
+
+    Ok(if (numerator % d).is_zero() {
+        quotient
+    } else {
+        quotient + 1
+    })
+}
+
+fn reciprocal_floor(target: U256) -> Result<U256, ArithmeticError> {
+    div(ONE2, target)
+}
+
+//SafeMath of the pools, which revert on overflow, underflow and division by zero
+fn add(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_add(b).ok_or(ArithmeticError::MulDivOverflow)
+}
+
+fn sub(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_sub(b)
+        .ok_or(ArithmeticError::SubtractionUnderflow)
+}
+
+fn mul(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_mul(b).ok_or(ArithmeticError::MulDivOverflow)
+}
+
+fn div(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_div(b).ok_or(ArithmeticError::DivisionByZero)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        abi::Token,
+        prelude::abigen,
+        providers::{Http, Provider},
+        types::{Log, H160, U256},
+    };
+
+    use crate::{
+        amm::{AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{
+        DodoPool, PMMState, RState, DODO_SWAP_EVENT_SIGNATURE, IDODOV2,
+        LP_FEE_RATE_CHANGE_EVENT_SIGNATURE,
+    };
+
+    abigen!(
+        IDVMFactory,
+        r#"[
+            function getDODOPool(address baseToken, address quoteToken) external view returns (address[] memory machines)
+        ]"#;
+    );
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    fn ether(amount: u64) -> U256 {
+        U256::exp10(18) * amount
+    }
+
+    fn usdc(amount: u64) -> U256 {
+        U256::exp10(6) * amount
+    }
+
+    //WETH/USDC guided at 2000 USDC per WETH with a k of 0.1, a 0.2% LP fee and a 0.1% maintainer fee, holding
+    //`base_reserve` and `quote_reserve` off the given targets
+    fn weth_usdc(
+        base_reserve: U256,
+        quote_reserve: U256,
+        base_target: U256,
+        quote_target: U256,
+        r: RState,
+    ) -> eyre::Result<DodoPool> {
+        let mut state = PMMState {
+            i: U256::from(2_000_000_000_u64),
+            k: U256::exp10(17),
+            base_reserve,
+            quote_reserve,
+            base_target,
+            quote_target,
+            r,
+        };
+        state.adjust_target()?;
+
+        Ok(DodoPool::new(
+            H160::from_low_u64_be(10),
+            token(1),
+            18,
+            token(2),
+            6,
+            state,
+            U256::exp10(15) * 2,
+            U256::exp10(15),
+        ))
+    }
+
+    fn balanced() -> eyre::Result<DodoPool> {
+        weth_usdc(
+            ether(100),
+            usdc(200_000),
+            ether(100),
+            usdc(200_000),
+            RState::One,
+        )
+    }
+
+    //Sold 20 WETH down to 80 WETH, holding more USDC than its target
+    fn above_one() -> eyre::Result<DodoPool> {
+        weth_usdc(
+            ether(80),
+            usdc(240_000),
+            U256::zero(),
+            usdc(200_000),
+            RState::AboveOne,
+        )
+    }
+
+    //Bought 20 WETH up to 120 WETH, holding less USDC than its target
+    fn below_one() -> eyre::Result<DodoPool> {
+        weth_usdc(
+            ether(120),
+            usdc(160_000),
+            ether(100),
+            U256::zero(),
+            RState::BelowOne,
+        )
+    }
+
+    fn swap_log(pool: &DodoPool, from_token: H160, from_amount: U256, to_amount: U256) -> Log {
+        Log {
+            address: pool.address,
+            topics: vec![DODO_SWAP_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[
+                Token::Address(from_token),
+                Token::Address(pool.get_token_out(from_token)),
+                Token::Uint(from_amount),
+                Token::Uint(to_amount),
+                Token::Address(H160::from_low_u64_be(7)),
+                Token::Address(H160::from_low_u64_be(8)),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_adjust_target() -> eyre::Result<()> {
+        //The target the reserves are off of is recomputed from the other target
+        assert_eq!(
+            above_one()?.state.base_target,
+            U256::from(99_523_539_268_060_618_400_u128)
+        );
+        assert_eq!(
+            below_one()?.state.quote_target,
+            U256::from(199_047_078_536_u64)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        //Balanced pools are priced at the guide price, pools off their targets at the side short of its target
+        let pool = balanced()?;
+        assert!((pool.calculate_price(token(1))? - 2000.0).abs() < 1e-9);
+        assert!((pool.calculate_price(token(2))? * 2000.0 - 1.0).abs() < 1e-9);
+
+        assert_eq!(
+            above_one()?.state.mid_price()?,
+            U256::from(2_109_529_214_u64)
+        );
+        assert_eq!(
+            below_one()?.state.mid_price()?,
+            U256::from(1_896_157_669_u64)
+        );
+        assert!(above_one()?.calculate_price(token(1))? > 2000.0);
+        assert!(below_one()?.calculate_price(token(1))? < 2000.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        //Amounts out net of both fees, as `querySellBase` and `querySellQuote` return them
+        for (pool, expected) in [
+            (
+                balanced()?,
+                [
+                    1_991_989_939_u128,
+                    995_994_968_869_095_359,
+                    57_490_900_814,
+                    28_745_450_406_228_142_050,
+                ],
+            ),
+            (
+                above_one()?,
+                [
+                    2_099_390_744,
+                    943_577_096_009_184_373,
+                    60_531_468_761,
+                    26_434_318_485_452_279_694,
+                ],
+            ),
+            (
+                below_one()?,
+                [
+                    1_887_154_195,
+                    1_049_695_371_354_911_875,
+                    52_868_636_973,
+                    30_265_734_380_199_788_934,
+                ],
+            ),
+        ] {
+            let amounts_in = [
+                (token(1), ether(1)),
+                (token(2), usdc(2000)),
+                (token(1), ether(30)),
+                (token(2), usdc(60_000)),
+            ];
+            for ((token_in, amount_in), expected) in amounts_in.into_iter().zip(expected) {
+                assert_eq!(
+                    pool.simulate_swap(token_in, amount_in)?,
+                    U256::from(expected)
+                );
+            }
+        }
+
+        assert!(matches!(
+            balanced()?.simulate_swap(token(3), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_across_targets() -> eyre::Result<()> {
+        //Selling exactly the base short of the target pays out the quote above it and balances the pool
+        let pool = above_one()?;
+        let back_to_one = pool.state.base_target - pool.state.base_reserve;
+        let mut balanced = pool.clone();
+        assert_eq!(
+            balanced.simulate_swap_mut(token(1), back_to_one)?,
+            U256::from(39_880_000_000_u64)
+        );
+        assert_eq!(balanced.state.r, RState::One);
+        assert_eq!(balanced.state.base_reserve, balanced.state.base_target);
+        assert_eq!(balanced.state.mid_price()?, U256::from(2_000_000_000_u64));
+
+        //Selling past it leaves the pool below one, priced against the quote it is now short of
+        let mut swapped = pool.clone();
+        assert_eq!(
+            swapped.simulate_swap_mut(token(1), ether(30))?,
+            U256::from(60_531_468_761_u64)
+        );
+        assert_eq!(swapped.state.r, RState::BelowOne);
+        assert_eq!(swapped.state.base_reserve, ether(110));
+        assert_eq!(swapped.state.quote_reserve, U256::from(179_407_817_630_u64));
+        assert_eq!(swapped.state.base_target, pool.state.base_target);
+        assert_eq!(swapped.state.quote_target, U256::from(200_121_585_534_u64));
+        assert_eq!(
+            swapped.simulate_swap(token(2), usdc(60_000))?,
+            U256::from(29_572_452_219_358_024_328_u128)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = above_one()?;
+        let mut amm = AMM::DodoPool(pool.clone());
+        let mut expected = pool.clone();
+        expected.simulate_swap_mut(token(1), ether(30))?;
+
+        //A trader without maintainer fee is paid the whole amount leaving the pool, which syncs the same
+        let log = swap_log(&pool, token(1), ether(30), U256::from(60_592_182_370_u64));
+        pool.sync_from_log(&log)?;
+        amm.sync_from_log(&log)?;
+        assert_eq!(pool.state, expected.state);
+        assert!(AMM::DodoPool(pool.clone()).state_eq(&amm));
+
+        //Swaps paying out more than the pool can are rejected without touching it
+        let before = pool.state;
+        assert!(matches!(
+            pool.sync_from_log(&swap_log(&pool, token(2), usdc(100), ether(1))),
+            Err(EventLogError::MalformedLog(_))
+        ));
+        assert_eq!(pool.state, before);
+
+        let fee_log = Log {
+            address: pool.address,
+            topics: vec![LP_FEE_RATE_CHANGE_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[Token::Uint(U256::exp10(14))]).into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&fee_log)?;
+        assert_eq!(pool.lp_fee_rate, U256::exp10(14));
+
+        let mut foreign_log = fee_log;
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_simulate_swap_against_pool() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //WETH/USDC vending machines of the DODO V2 DVM factory
+        let factory = IDVMFactory::new(
+            "0x72d220cE168C4f361dD4deE5D826a01AD8598f6C".parse::<H160>()?,
+            middleware.clone(),
+        );
+        let weth = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2".parse::<H160>()?;
+        let usdc = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".parse::<H160>()?;
+        let pools = factory.get_dodo_pool(weth, usdc).call().await?;
+        let address = *pools
+            .first()
+            .ok_or_else(|| eyre::eyre!("no WETH/USDC vending machine found"))?;
+
+        let mut pool = DodoPool {
+            address,
+            ..Default::default()
+        };
+        let block_number = ethers::providers::Middleware::get_block_number(&*middleware)
+            .await?
+            .as_u64();
+        super::batch_request::get_dodo_pool_data_batch_request(
+            &mut pool,
+            Some(block_number),
+            middleware.clone(),
+        )
+        .await?;
+
+        let contract = IDODOV2::new(pool.address, middleware.clone());
+        for (token_in, amount_in) in [(pool.token_a, ether(1) / 10), (pool.token_b, usdc(100))] {
+            let (expected, _) = if token_in == pool.token_a {
+                contract.query_sell_base(H160::zero(), amount_in)
+            } else {
+                contract.query_sell_quote(H160::zero(), amount_in)
+            }
+            .block(block_number)
+            .call()
+            .await?;
+
+            assert_eq!(pool.simulate_swap(token_in, amount_in)?, expected);
+        }
+
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
 use ethers::{
     abi::{ParamType, Token},
     providers::Middleware,
-    types::{Bytes, U256},
+    types::{Bytes, H160, U256},
 };
 use std::sync::Arc;
 
-use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{AmmState, AMM},
+    errors::AMMError,
+};
 
 use ethers::prelude::abigen;
 
@@ -26,6 +29,7 @@ fn populate_vault_data_from_tokens(
     vault.asset_token_decimals = tokens[3].to_owned().into_uint()?.as_u32() as u8;
     vault.vault_reserve = tokens[4].to_owned().into_uint()?;
     vault.asset_reserve = tokens[5].to_owned().into_uint()?;
+    vault.reset_decimal_scaling();
 
     let deposit_fee_delta_1 = tokens[6].to_owned().into_uint()?;
     let deposit_fee_delta_2 = tokens[7].to_owned().into_uint()?;
@@ -67,12 +71,54 @@ pub async fn get_4626_vault_data_batch_request<M: Middleware>(
     vault: &mut ERC4626Vault,
     middleware: Arc<M>,
 ) -> Result<(), AMMError<M>> {
-    let constructor_args =
-        Token::Tuple(vec![Token::Array(vec![Token::Address(vault.vault_token)])]);
+    for vault_data in get_vault_data(&[vault.vault_token], None, middleware).await? {
+        *vault = populate_vault_data_from_tokens(vault.to_owned(), vault_data)
+            .ok_or(AMMError::BatchRequestError(vault.address()))?;
+    }
+
+    Ok(())
+}
+
+/// Reads every vault of `amms` at `block_number` in a single call, leaving the vaults that could not be read as
+/// they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let vaults = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let vault_data = get_vault_data(&vaults, block_number, middleware).await?;
+
+    for (amm, vault_data) in amms.iter_mut().zip(vault_data) {
+        if let AMM::ERC4626Vault(erc_4626_vault) = amm {
+            if let Some(vault) =
+                populate_vault_data_from_tokens(erc_4626_vault.to_owned(), vault_data)
+            {
+                *erc_4626_vault = vault;
+            }
+        }
+    }
 
-    let deployer = IGetERC4626VaultDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
+    Ok(())
+}
+
+//Data of `vaults` as returned by the batch contract, one tuple per vault
+async fn get_vault_data<M: Middleware>(
+    vaults: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    let target_addresses = vaults.iter().map(|vault| Token::Address(*vault)).collect();
+    let constructor_args = Token::Tuple(vec![Token::Array(target_addresses)]);
+
+    let deployer = IGetERC4626VaultDataBatchRequest::deploy(middleware, constructor_args)?;
 
-    let return_data: Bytes = deployer.call_raw().await?;
+    let return_data: Bytes = if let Some(block_number) = block_number {
+        deployer.block(block_number).call_raw().await?
+    } else {
+        deployer.call_raw().await?
+    };
     let return_data_tokens = ethers::abi::decode(
         &[ParamType::Array(Box::new(ParamType::Tuple(vec![
             ParamType::Address,   // vault token
@@ -91,18 +137,10 @@ pub async fn get_4626_vault_data_batch_request<M: Middleware>(
         &return_data,
     )?;
 
-    for tokens in return_data_tokens {
-        if let Some(tokens_arr) = tokens.into_array() {
-            for tup in tokens_arr {
-                let vault_data = tup
-                    .into_tuple()
-                    .ok_or(AMMError::BatchRequestError(vault.address()))?;
-
-                *vault = populate_vault_data_from_tokens(vault.to_owned(), vault_data)
-                    .ok_or(AMMError::BatchRequestError(vault.address()))?;
-            }
-        }
-    }
-
-    Ok(())
+    Ok(return_data_tokens
+        .into_iter()
+        .filter_map(Token::into_array)
+        .flatten()
+        .filter_map(Token::into_tuple)
+        .collect())
 }
@@ -1,24 +1,39 @@
+#[cfg(feature = "rpc")]
 pub mod batch_request;
 
-use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
+use std::collections::BTreeMap;
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
 
+#[cfg(feature = "rpc")]
 use async_trait::async_trait;
-use ethers::{
-    abi::RawLog,
-    prelude::EthEvent,
-    providers::Middleware,
-    types::{Log, H160, H256, U256},
-};
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::types::{Log, H160, H256, U256};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    amm::AutomatedMarketMaker,
-    errors::{AMMError, ArithmeticError, EventLogError, StorageError, SwapSimulationError},
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{
+        fixed_point::{q64_to_f64, DecimalScaling, DecimalScalingCache},
+        mul_div, Q128x128,
+    },
+    tokens::TokenStore,
+};
+#[cfg(feature = "rpc")]
+use crate::{
+    amm::{
+        detect::{self, DetectedVariant},
+        AutomatedMarketMaker,
+    },
+    errors::AMMError,
 };
 
 use ethers::prelude::abigen;
 
-use super::uniswap_v2::{div_uu, q64_to_f64, U128_0X10000000000000000};
+use super::uniswap_v2::{div_uu, U128_0X10000000000000000};
 
 abigen!(
     IERC4626Vault,
@@ -44,18 +59,39 @@ pub const WITHDRAW_EVENT_SIGNATURE: H256 = H256([
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ERC4626Vault {
+    //Stored names of the fields of the first release are pinned, see `amm::compat`
+    #[serde(rename = "vault_token")]
     pub vault_token: H160, // token received from depositing, i.e. shares token
+    #[serde(rename = "vault_token_decimals")]
     pub vault_token_decimals: u8,
+    #[serde(rename = "asset_token")]
     pub asset_token: H160, // token received from withdrawing, i.e. underlying token
+    #[serde(rename = "asset_token_decimals")]
     pub asset_token_decimals: u8,
+    #[serde(rename = "vault_reserve")]
     pub vault_reserve: U256, // total supply of vault tokens
+    #[serde(rename = "asset_reserve")]
     pub asset_reserve: U256, // total balance of asset tokens held by vault
-    pub deposit_fee: u32,    // deposit fee in basis points
-    pub withdraw_fee: u32,   // withdrawal fee in basis points
+    #[serde(rename = "deposit_fee")]
+    pub deposit_fee: u32, // deposit fee in basis points
+    #[serde(rename = "withdraw_fee")]
+    pub withdraw_fee: u32, // withdrawal fee in basis points
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub vault_token_symbol: Option<String>,
+    #[serde(default)]
+    pub vault_token_name: Option<String>,
+    #[serde(default)]
+    pub asset_token_symbol: Option<String>,
+    #[serde(default)]
+    pub asset_token_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+    #[serde(skip)]
+    pub decimal_scaling: DecimalScalingCache,
 }
 
-#[async_trait]
-impl AutomatedMarketMaker for ERC4626Vault {
+impl AmmState for ERC4626Vault {
     fn address(&self) -> H160 {
         self.vault_token
     }
@@ -64,16 +100,22 @@ impl AutomatedMarketMaker for ERC4626Vault {
         vec![self.vault_token, self.asset_token]
     }
 
-    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
-        Ok(q64_to_f64(self.calculate_price_64_x_64(base_token)?))
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.vault_token {
+            self.vault_token_symbol.as_deref()
+        } else if token == self.asset_token {
+            self.asset_token_symbol.as_deref()
+        } else {
+            None
+        }
     }
 
-    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
-        (self.vault_reserve, self.asset_reserve) = self.get_reserves(middleware).await?;
-
-        Ok(())
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        Ok(q64_to_f64(self.calculate_price_64_x_64(base_token)?))
     }
 
+    //Vaults keep their totals in a layout of their own, or compute them from other contracts, so they are synced
+    //from logs and `sync_from_storage` is not supported
     fn sync_on_storage_slots(&self) -> Vec<H256> {
         vec![]
     }
@@ -81,43 +123,44 @@ impl AutomatedMarketMaker for ERC4626Vault {
         vec![DEPOSIT_EVENT_SIGNATURE, WITHDRAW_EVENT_SIGNATURE]
     }
 
-    fn sync_from_log(&mut self, log: Log) -> Result<(), EventLogError> {
-        let event_signature = log.topics[0];
-        if event_signature == DEPOSIT_EVENT_SIGNATURE {
-            let deposit_event = DepositFilter::decode_log(&RawLog::from(log))?;
-
-            self.asset_reserve += deposit_event.assets;
-            self.vault_reserve += deposit_event.shares;
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.vault_token)?;
+        let event_signature = decode::event_signature(log)?;
+        let assets = || decode::data_uint(log, 0, 256);
+        let shares = || decode::data_uint(log, 1, 256);
+
+        //Both reserves are checked before either is updated, so a bad log leaves the vault untouched
+        let (asset_reserve, vault_reserve) = if event_signature == DEPOSIT_EVENT_SIGNATURE {
+            (
+                self.asset_reserve.checked_add(assets()?),
+                self.vault_reserve.checked_add(shares()?),
+            )
         } else if event_signature == WITHDRAW_EVENT_SIGNATURE {
-            let withdraw_filter = WithdrawFilter::decode_log(&RawLog::from(log))?;
-            self.asset_reserve -= withdraw_filter.assets;
-            self.vault_reserve -= withdraw_filter.shares;
+            (
+                self.asset_reserve.checked_sub(assets()?),
+                self.vault_reserve.checked_sub(shares()?),
+            )
         } else {
-            return Err(EventLogError::InvalidEventSignature);
-        }
+            return Err(EventLogError::UnexpectedEvent(log.into()));
+        };
 
-        Ok(())
-    }
-
-    fn sync_from_storage(
-        &mut self,
-        _storage: &'_ BTreeMap<H256, H256>,
-    ) -> Result<(), StorageError> {
-        todo!();
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+        self.set_totals(
+            asset_reserve.ok_or_else(malformed_log)?,
+            vault_reserve.ok_or_else(malformed_log)?,
+        )
+        .map_err(|_| malformed_log())
     }
 
     fn reserves(&self) -> BTreeMap<H256, H256> {
         todo!()
     }
 
-    async fn populate_data<M: Middleware>(
-        &mut self,
-        _block_number: Option<u64>,
-        middleware: Arc<M>,
-    ) -> Result<(), AMMError<M>> {
-        batch_request::get_4626_vault_data_batch_request(self, middleware.clone()).await?;
-
-        Ok(())
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::ERC4626.name(),
+            &[self.vault_reserve, self.asset_reserve],
+        )
     }
 
     fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
@@ -150,6 +193,28 @@ impl AutomatedMarketMaker for ERC4626Vault {
         }
     }
 
+    //Conversions are linear, so the gradient is the exchange rate net of the fee at any amount
+    fn gradient(&self, token_in: H160, _amount_in: U256) -> Result<Q128x128, SwapSimulationError> {
+        if self.vault_reserve.is_zero() {
+            return Ok(Q128x128::one());
+        }
+
+        let (reserve_in, reserve_out, fee) = if self.vault_token == token_in {
+            (self.vault_reserve, self.asset_reserve, self.withdraw_fee)
+        } else {
+            (self.asset_reserve, self.vault_reserve, self.deposit_fee)
+        };
+
+        Q128x128::from_ratio(reserve_out, reserve_in)
+            .and_then(|gradient| {
+                gradient.checked_mul(Q128x128::from_ratio(
+                    U256::from(10000 - fee),
+                    U256::from(10000),
+                )?)
+            })
+            .ok_or(SwapSimulationError::GradientOverflow)
+    }
+
     fn get_token_out(&self, token_in: H160) -> H160 {
         if self.vault_token == token_in {
             self.asset_token
@@ -169,7 +234,64 @@ impl AutomatedMarketMaker for ERC4626Vault {
     }
 }
 
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for ERC4626Vault {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        let (vault_reserve, asset_reserve) = self.get_reserves(middleware).await?;
+        self.set_totals(asset_reserve, vault_reserve)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.vault_token), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        _block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_4626_vault_data_batch_request(self, middleware.clone()).await?;
+
+        Ok(())
+    }
+}
+
 impl ERC4626Vault {
+    /// Scaling between the decimals of the vault token and the asset token, cached on the vault
+    pub fn decimal_scaling(&self) -> DecimalScaling {
+        self.decimal_scaling
+            .get(self.vault_token_decimals, self.asset_token_decimals)
+    }
+
+    /// Recomputes the cached decimal scaling, called once the decimals of the vault are populated
+    pub fn reset_decimal_scaling(&mut self) {
+        self.decimal_scaling =
+            DecimalScalingCache::new(self.vault_token_decimals, self.asset_token_decimals);
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.vault_token) {
+            self.vault_token_symbol = info.symbol.or(self.vault_token_symbol.take());
+            self.vault_token_name = info.name.or(self.vault_token_name.take());
+        }
+        if let Some(info) = tokens.get(self.asset_token) {
+            self.asset_token_symbol = info.symbol.or(self.asset_token_symbol.take());
+            self.asset_token_name = info.name.or(self.asset_token_name.take());
+        }
+    }
+
+    /// Sets the total assets held by the vault and the total supply of shares, rejecting totals that overflow once
+    /// scaled by the decimals of the tokens, which the price of the vault could not be computed from
+    pub fn set_totals(&mut self, assets: U256, shares: U256) -> Result<(), ArithmeticError> {
+        self.decimal_scaling().normalize(shares, assets)?;
+
+        self.asset_reserve = assets;
+        self.vault_reserve = shares;
+
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         vault_token: H160,
@@ -190,10 +312,35 @@ impl ERC4626Vault {
             asset_reserve,
             deposit_fee,
             withdraw_fee,
+            vault_token_symbol: None,
+            vault_token_name: None,
+            asset_token_symbol: None,
+            asset_token_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::new(vault_token_decimals, asset_token_decimals),
         }
     }
 
-    pub async fn new_from_address<M: Middleware>(
+    /// Creates the vault at `vault_token` populated at the latest block, ready to simulate deposits and withdrawals.
+    /// Errors with `AMMError::AMMVariantMismatch` if the contract is not an ERC4626 vault.
+    #[cfg(feature = "rpc")]
+    pub async fn new_from_address<M: 'static + Middleware>(
+        vault_token: H160,
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        detect::expect_variant(
+            vault_token,
+            DetectedVariant::ERC4626Vault,
+            middleware.clone(),
+        )
+        .await?;
+
+        ERC4626Vault::new_populated(vault_token, middleware).await
+    }
+
+    //Populates the vault without probing the contract, for addresses already identified as vaults
+    #[cfg(feature = "rpc")]
+    pub(crate) async fn new_populated<M: Middleware>(
         vault_token: H160,
         middleware: Arc<M>,
     ) -> Result<Self, AMMError<M>> {
@@ -206,6 +353,12 @@ impl ERC4626Vault {
             asset_reserve: U256::zero(),
             deposit_fee: 0,
             withdraw_fee: 0,
+            vault_token_symbol: None,
+            vault_token_name: None,
+            asset_token_symbol: None,
+            asset_token_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::default(),
         };
 
         vault.populate_data(None, middleware.clone()).await?;
@@ -224,6 +377,7 @@ impl ERC4626Vault {
             || self.asset_reserve.is_zero())
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_reserves<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -245,19 +399,10 @@ impl ERC4626Vault {
     }
 
     pub fn calculate_price_64_x_64(&self, base_token: H160) -> Result<u128, ArithmeticError> {
-        let decimal_shift = self.vault_token_decimals as i8 - self.asset_token_decimals as i8;
-
         // Normalize reserves by decimal shift
-        let (r_v, r_a) = match decimal_shift.cmp(&0) {
-            Ordering::Less => (
-                self.vault_reserve * U256::from(10u128.pow(decimal_shift.unsigned_abs() as u32)),
-                self.asset_reserve,
-            ),
-            _ => (
-                self.vault_reserve,
-                self.asset_reserve * U256::from(10u128.pow(decimal_shift as u32)),
-            ),
-        };
+        let (r_v, r_a) = self
+            .decimal_scaling()
+            .normalize(self.vault_reserve, self.asset_reserve)?;
 
         // Withdraw
         if base_token == self.vault_token {
@@ -291,23 +436,110 @@ impl ERC4626Vault {
             self.deposit_fee
         };
 
-        amount_in * reserve_out / reserve_in * (10000 - fee) / 10000
+        //Rounded down at both steps like the vault, an empty input reserve or an overflowing output yields nothing
+        mul_div(amount_in, reserve_out, reserve_in)
+            .and_then(|amount_out| mul_div(amount_out, U256::from(10000 - fee), U256::from(10000)))
+            .unwrap_or_default()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr, sync::Arc};
+    use std::{collections::BTreeMap, str::FromStr, sync::Arc};
 
     use ethers::{
         providers::{Http, Provider},
-        types::{H160, U256},
+        types::{H160, H256, U256},
     };
 
-    use crate::amm::AutomatedMarketMaker;
+    use crate::{
+        amm::{AmmState, AutomatedMarketMaker},
+        errors::StateSyncError,
+        math::Q128x128,
+    };
 
     use super::ERC4626Vault;
 
+    #[test]
+    fn test_gradient() -> eyre::Result<()> {
+        let vault = ERC4626Vault {
+            vault_token: H160::from_low_u64_be(1),
+            asset_token: H160::from_low_u64_be(2),
+            vault_reserve: U256::exp10(24),
+            asset_reserve: U256::exp10(24) * 3 / 2,
+            deposit_fee: 10,
+            withdraw_fee: 0,
+            ..Default::default()
+        };
+
+        for amount_in in [U256::exp10(18), U256::exp10(21)] {
+            let gradient = vault.gradient_f64(vault.vault_token, amount_in)?;
+            let amount_out = vault.simulate_swap(vault.vault_token, amount_in)?;
+            assert!((gradient - 1.5).abs() < 1e-15);
+            assert!(
+                (gradient - amount_out.as_u128() as f64 / amount_in.as_u128() as f64).abs() < 1e-15
+            );
+
+            let gradient = vault.gradient_f64(vault.asset_token, amount_in)?;
+            assert!((gradient - 0.999 / 1.5).abs() < 1e-15);
+        }
+
+        //Empty vaults mint shares one to one
+        assert_eq!(
+            ERC4626Vault::default().gradient(H160::zero(), U256::exp10(18))?,
+            Q128x128::one()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_totals() -> eyre::Result<()> {
+        let mut vault = ERC4626Vault::new(
+            H160::from_low_u64_be(1),
+            18,
+            H160::from_low_u64_be(2),
+            6,
+            U256::zero(),
+            U256::zero(),
+            0,
+            0,
+        );
+
+        vault.set_totals(U256::exp10(6), U256::exp10(18))?;
+        assert_eq!(vault.calculate_price(vault.vault_token)?, 1.0);
+
+        //The asset total no longer fits once scaled to the 18 decimals of the shares
+        assert!(vault.set_totals(U256::MAX, U256::exp10(18)).is_err());
+        assert_eq!(vault.asset_reserve, U256::exp10(6));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_storage() -> eyre::Result<()> {
+        let mut vault = ERC4626Vault::new(
+            H160::from_low_u64_be(1),
+            18,
+            H160::from_low_u64_be(2),
+            18,
+            U256::exp10(18),
+            U256::exp10(18),
+            0,
+            0,
+        );
+
+        //Totals live in a layout of each vault, any diff is rejected rather than guessed at
+        let diff = BTreeMap::from([(H256::from_low_u64_be(2), H256::from_low_u64_be(1))]);
+        assert!(matches!(
+            vault.sync_from_storage(&diff),
+            Err(StateSyncError::Unsupported)
+        ));
+        assert_eq!(vault.vault_reserve, U256::exp10(18));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_vault_data() -> eyre::Result<()> {
         let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
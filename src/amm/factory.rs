@@ -1,22 +1,57 @@
-use std::sync::Arc;
+use std::{collections::HashSet, fmt::Debug, sync::Arc};
 
 use async_trait::async_trait;
 use ethers::{
-    providers::Middleware,
+    contract::Multicall,
+    providers::{Middleware, PubsubClient, StreamExt},
     types::{BlockNumber, Filter, Log, ValueOrArray, H160, H256, U64},
 };
+use futures::Stream;
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
+use tracing::Instrument;
 
-use crate::errors::{AMMError, EventLogError};
+use crate::{
+    errors::{AMMError, EventLogError, LogContext},
+    sync,
+};
 
 use super::{
-    uniswap_v2::factory::{UniswapV2Factory, PAIR_CREATED_EVENT_SIGNATURE},
-    uniswap_v3::factory::{UniswapV3Factory, POOL_CREATED_EVENT_SIGNATURE},
-    AMM,
+    algebra::{
+        factory::{AlgebraFactory, IAlgebraFactory},
+        AlgebraPool, TICK_SPACING,
+    },
+    kyber_elastic::factory::KyberElasticFactory,
+    maverick::factory::MaverickFactory,
+    uniswap_v2::{
+        factory::{IUniswapV2Factory, UniswapV2Factory, PAIR_CREATED_EVENT_SIGNATURE},
+        UniswapV2Pool,
+    },
+    uniswap_v3::{
+        factory::{IUniswapV3Factory, UniswapV3Factory, POOL_CREATED_EVENT_SIGNATURE},
+        UniswapV3Pool,
+    },
+    uniswap_v4::factory::UniswapV4Factory,
+    velodrome::{
+        factory::{IVelodromeFactory, VelodromeFactory},
+        VelodromePool,
+    },
+    AmmState, AMM,
 };
 
+#[cfg(feature = "known-factories")]
+pub use super::known_factories::known_factories;
+
 pub const TASK_LIMIT: usize = 10;
+pub const UNISWAP_V3_FEE_TIERS: [u32; 4] = [100, 500, 3000, 10000];
+
+/// A page of populated AMMs yielded by paginated pool discovery.
+/// `cursor` is the pair index (V2) or block number (V3) to resume from after this page.
+#[derive(Debug, Clone)]
+pub struct AmmPage {
+    pub cursor: u64,
+    pub amms: Vec<AMM>,
+}
 
 #[async_trait]
 pub trait AutomatedMarketMakerFactory {
@@ -47,13 +82,89 @@ pub trait AutomatedMarketMakerFactory {
         middleware: Arc<M>,
     ) -> Result<AMM, AMMError<M>>;
 
-    fn new_empty_amm_from_log(&self, log: Log) -> Result<AMM, ethers::abi::Error>;
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError>;
+}
+
+/// Object safe factory interface, allowing protocols that are not part of the `Factory` enum to be discovered and synced.
+/// Pools are usually returned as `AMM::Custom`, but a factory may also return any of the built in variants.
+#[async_trait]
+pub trait AmmFactory<M: 'static + Middleware>: Debug + Send + Sync {
+    fn address(&self) -> H160;
+
+    fn amm_created_event_signature(&self) -> H256;
+
+    fn creation_block(&self) -> u64;
+
+    /// Decodes a creation log into an AMM skeleton, which is filled in by `populate_amm_data`
+    fn new_empty_amm_from_log(&self, log: Log) -> Result<AMM, EventLogError>;
+
+    /// Batched population hook, called with every AMM discovered by the factory
+    async fn populate_amm_data(
+        &self,
+        amms: &mut [AMM],
+        block_number: u64,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>>;
+
+    async fn get_all_amms(
+        &self,
+        to_block: u64,
+        middleware: Arc<M>,
+        step: u64,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        //A step of 0 would never advance past the first block
+        let step = step.max(1);
+        let mut from_block = self.creation_block();
+        let mut amms = vec![];
+
+        while from_block <= to_block {
+            let target_block = (from_block + step - 1).min(to_block);
+
+            let logs = middleware
+                .get_logs(
+                    &Filter::new()
+                        .topic0(ValueOrArray::Value(self.amm_created_event_signature()))
+                        .address(self.address())
+                        .from_block(BlockNumber::Number(U64([from_block])))
+                        .to_block(BlockNumber::Number(U64([target_block]))),
+                )
+                .instrument(tracing::debug_span!(
+                    "scan_range",
+                    factory = ?self.address(),
+                    from_block,
+                    to_block = target_block
+                ))
+                .await
+                .map_err(|err| {
+                    tracing::error!(
+                        ?err,
+                        from_block,
+                        to_block = target_block,
+                        "could not get creation logs"
+                    );
+                    AMMError::MiddlewareError(err)
+                })?;
+
+            for log in logs {
+                amms.push(self.new_empty_amm_from_log(log)?);
+            }
+
+            from_block += step;
+        }
+
+        Ok(amms)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Factory {
     UniswapV2Factory(UniswapV2Factory),
     UniswapV3Factory(UniswapV3Factory),
+    VelodromeFactory(VelodromeFactory),
+    UniswapV4Factory(UniswapV4Factory),
+    MaverickFactory(MaverickFactory),
+    KyberElasticFactory(KyberElasticFactory),
+    AlgebraFactory(AlgebraFactory),
 }
 
 #[async_trait]
@@ -62,6 +173,11 @@ impl AutomatedMarketMakerFactory for Factory {
         match self {
             Factory::UniswapV2Factory(factory) => factory.address(),
             Factory::UniswapV3Factory(factory) => factory.address(),
+            Factory::VelodromeFactory(factory) => factory.address(),
+            Factory::UniswapV4Factory(factory) => factory.address(),
+            Factory::MaverickFactory(factory) => factory.address(),
+            Factory::KyberElasticFactory(factory) => factory.address(),
+            Factory::AlgebraFactory(factory) => factory.address(),
         }
     }
 
@@ -69,6 +185,11 @@ impl AutomatedMarketMakerFactory for Factory {
         match self {
             Factory::UniswapV2Factory(factory) => factory.amm_created_event_signature(),
             Factory::UniswapV3Factory(factory) => factory.amm_created_event_signature(),
+            Factory::VelodromeFactory(factory) => factory.amm_created_event_signature(),
+            Factory::UniswapV4Factory(factory) => factory.amm_created_event_signature(),
+            Factory::MaverickFactory(factory) => factory.amm_created_event_signature(),
+            Factory::KyberElasticFactory(factory) => factory.amm_created_event_signature(),
+            Factory::AlgebraFactory(factory) => factory.amm_created_event_signature(),
         }
     }
 
@@ -80,13 +201,25 @@ impl AutomatedMarketMakerFactory for Factory {
         match self {
             Factory::UniswapV2Factory(factory) => factory.new_amm_from_log(log, middleware).await,
             Factory::UniswapV3Factory(factory) => factory.new_amm_from_log(log, middleware).await,
+            Factory::VelodromeFactory(factory) => factory.new_amm_from_log(log, middleware).await,
+            Factory::UniswapV4Factory(factory) => factory.new_amm_from_log(log, middleware).await,
+            Factory::MaverickFactory(factory) => factory.new_amm_from_log(log, middleware).await,
+            Factory::KyberElasticFactory(factory) => {
+                factory.new_amm_from_log(log, middleware).await
+            }
+            Factory::AlgebraFactory(factory) => factory.new_amm_from_log(log, middleware).await,
         }
     }
 
-    fn new_empty_amm_from_log(&self, log: Log) -> Result<AMM, ethers::abi::Error> {
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
         match self {
             Factory::UniswapV2Factory(factory) => factory.new_empty_amm_from_log(log),
             Factory::UniswapV3Factory(factory) => factory.new_empty_amm_from_log(log),
+            Factory::VelodromeFactory(factory) => factory.new_empty_amm_from_log(log),
+            Factory::UniswapV4Factory(factory) => factory.new_empty_amm_from_log(log),
+            Factory::MaverickFactory(factory) => factory.new_empty_amm_from_log(log),
+            Factory::KyberElasticFactory(factory) => factory.new_empty_amm_from_log(log),
+            Factory::AlgebraFactory(factory) => factory.new_empty_amm_from_log(log),
         }
     }
 
@@ -103,6 +236,21 @@ impl AutomatedMarketMakerFactory for Factory {
             Factory::UniswapV3Factory(factory) => {
                 factory.get_all_amms(to_block, middleware, step).await
             }
+            Factory::VelodromeFactory(factory) => {
+                factory.get_all_amms(to_block, middleware, step).await
+            }
+            Factory::UniswapV4Factory(factory) => {
+                factory.get_all_amms(to_block, middleware, step).await
+            }
+            Factory::MaverickFactory(factory) => {
+                factory.get_all_amms(to_block, middleware, step).await
+            }
+            Factory::KyberElasticFactory(factory) => {
+                factory.get_all_amms(to_block, middleware, step).await
+            }
+            Factory::AlgebraFactory(factory) => {
+                factory.get_all_amms(to_block, middleware, step).await
+            }
         }
     }
 
@@ -124,6 +272,31 @@ impl AutomatedMarketMakerFactory for Factory {
                     .populate_amm_data(amms, block_number, middleware, step)
                     .await
             }
+            Factory::VelodromeFactory(factory) => {
+                factory
+                    .populate_amm_data(amms, block_number, middleware, step)
+                    .await
+            }
+            Factory::UniswapV4Factory(factory) => {
+                factory
+                    .populate_amm_data(amms, block_number, middleware, step)
+                    .await
+            }
+            Factory::MaverickFactory(factory) => {
+                factory
+                    .populate_amm_data(amms, block_number, middleware, step)
+                    .await
+            }
+            Factory::KyberElasticFactory(factory) => {
+                factory
+                    .populate_amm_data(amms, block_number, middleware, step)
+                    .await
+            }
+            Factory::AlgebraFactory(factory) => {
+                factory
+                    .populate_amm_data(amms, block_number, middleware, step)
+                    .await
+            }
         }
     }
 
@@ -131,6 +304,13 @@ impl AutomatedMarketMakerFactory for Factory {
         match self {
             Factory::UniswapV2Factory(uniswap_v2_factory) => uniswap_v2_factory.creation_block,
             Factory::UniswapV3Factory(uniswap_v3_factory) => uniswap_v3_factory.creation_block,
+            Factory::VelodromeFactory(velodrome_factory) => velodrome_factory.creation_block,
+            Factory::UniswapV4Factory(uniswap_v4_factory) => uniswap_v4_factory.creation_block,
+            Factory::MaverickFactory(maverick_factory) => maverick_factory.creation_block,
+            Factory::KyberElasticFactory(kyber_elastic_factory) => {
+                kyber_elastic_factory.creation_block
+            }
+            Factory::AlgebraFactory(algebra_factory) => algebra_factory.creation_block,
         }
     }
 }
@@ -145,37 +325,48 @@ impl Factory {
     ) -> Result<Vec<AMM>, AMMError<M>> {
         let factory_address = self.address();
         let amm_created_event_signature = self.amm_created_event_signature();
-        let mut log_group = vec![];
         let mut handles = vec![];
         let mut tasks = 0;
         let mut aggregated_amms: Vec<AMM> = vec![];
 
-        while from_block < to_block {
+        while from_block <= to_block {
             let middleware = middleware.clone();
             let mut target_block = from_block + step - 1;
             if target_block > to_block {
                 target_block = to_block;
             }
 
-            handles.push(tokio::spawn(async move {
-                let logs = middleware
-                    .get_logs(
-                        &Filter::new()
-                            .topic0(ValueOrArray::Value(amm_created_event_signature))
-                            .address(factory_address)
-                            .from_block(BlockNumber::Number(U64([from_block])))
-                            .to_block(BlockNumber::Number(U64([target_block]))),
-                    )
-                    .await
-                    .map_err(AMMError::MiddlewareError)?;
+            let span = tracing::debug_span!(
+                "scan_range",
+                factory = ?factory_address,
+                from_block,
+                to_block = target_block
+            );
+            handles.push(tokio::spawn(
+                async move {
+                    let logs = middleware
+                        .get_logs(
+                            &Filter::new()
+                                .topic0(ValueOrArray::Value(amm_created_event_signature))
+                                .address(factory_address)
+                                .from_block(BlockNumber::Number(U64([from_block])))
+                                .to_block(BlockNumber::Number(U64([target_block]))),
+                        )
+                        .await
+                        .map_err(|err| {
+                            tracing::error!(?err, "could not get creation logs");
+                            AMMError::MiddlewareError(err)
+                        })?;
 
-                Ok::<Vec<Log>, AMMError<M>>(logs)
-            }));
+                    Ok::<Vec<Log>, AMMError<M>>(logs)
+                }
+                .instrument(span),
+            ));
 
             from_block += step;
             tasks += 1;
             if tasks == TASK_LIMIT {
-                self.process_logs_from_handles(handles, &mut log_group)
+                self.decode_logs_from_handles(handles, &mut aggregated_amms)
                     .await?;
 
                 handles = vec![];
@@ -183,25 +374,79 @@ impl Factory {
             }
         }
 
-        self.process_logs_from_handles(handles, &mut log_group)
+        self.decode_logs_from_handles(handles, &mut aggregated_amms)
             .await?;
 
-        for log in log_group {
-            aggregated_amms.push(self.new_empty_amm_from_log(log)?);
+        Ok(aggregated_amms)
+    }
+
+    /// Decodes this factory's creation logs into unpopulated AMMs, preserving the order of `logs`.
+    /// Logs from other contracts or events are ignored and logs that fail to decode are skipped,
+    /// an error is only returned if none of the matching logs could be decoded.
+    pub fn amms_from_logs(&self, logs: &[Log]) -> Result<Vec<AMM>, EventLogError> {
+        let (amms, mut errors) = self.amms_from_logs_with_errors(logs);
+
+        if amms.is_empty() && !errors.is_empty() {
+            return Err(errors.swap_remove(0).1);
         }
 
-        Ok(aggregated_amms)
+        for (idx, err) in errors {
+            tracing::warn!(idx, ?err, factory = ?self.address(), "could not decode creation log");
+        }
+
+        Ok(amms)
+    }
+
+    /// Same as `amms_from_logs`, also returning the index into `logs` and the error of each matching log that failed to decode
+    pub fn amms_from_logs_with_errors(
+        &self,
+        logs: &[Log],
+    ) -> (Vec<AMM>, Vec<(usize, EventLogError)>) {
+        let factory_address = self.address();
+        let amm_created_event_signature = self.amm_created_event_signature();
+
+        let mut amms = vec![];
+        let mut errors = vec![];
+
+        for (idx, log) in logs.iter().enumerate() {
+            if log.removed == Some(true)
+                || log.address != factory_address
+                || log.topics.first() != Some(&amm_created_event_signature)
+            {
+                continue;
+            }
+
+            match self.new_empty_amm_with_factory_fee(log) {
+                Ok(amm) => amms.push(amm),
+                Err(err) => errors.push((idx, err)),
+            }
+        }
+
+        (amms, errors)
+    }
+
+    //Decodes a creation log into an empty AMM, V2 pools take the fee of the factory
+    fn new_empty_amm_with_factory_fee(&self, log: &Log) -> Result<AMM, EventLogError> {
+        let mut amm = self.new_empty_amm_from_log(log)?;
+
+        if let (Factory::UniswapV2Factory(uniswap_v2_factory), AMM::UniswapV2Pool(pool)) =
+            (self, &mut amm)
+        {
+            pool.fee = uniswap_v2_factory.fee;
+        }
+
+        Ok(amm)
     }
 
-    async fn process_logs_from_handles<M: Middleware>(
+    //Decodes each block range as it resolves, so the logs of the whole history are never held at once
+    async fn decode_logs_from_handles<M: Middleware>(
         &self,
         handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
-        log_group: &mut Vec<Log>,
+        amms: &mut Vec<AMM>,
     ) -> Result<(), AMMError<M>> {
         for handle in handles {
-            let logs = handle.await??;
-            for log in logs {
-                log_group.push(log);
+            for log in handle.await?? {
+                amms.push(self.new_empty_amm_from_log(&log)?);
             }
         }
         Ok(())
@@ -212,12 +457,588 @@ impl TryFrom<H256> for Factory {
     type Error = EventLogError;
 
     fn try_from(value: H256) -> Result<Self, Self::Error> {
+        //KyberSwap Elastic factories log the same `PoolCreated` event as V3 factories, so its signature is read as a
+        //V3 factory
         if value == PAIR_CREATED_EVENT_SIGNATURE {
             Ok(Factory::UniswapV2Factory(UniswapV2Factory::default()))
         } else if value == POOL_CREATED_EVENT_SIGNATURE {
             Ok(Factory::UniswapV3Factory(UniswapV3Factory::default()))
+        } else if value == super::velodrome::factory::POOL_CREATED_EVENT_SIGNATURE {
+            Ok(Factory::VelodromeFactory(VelodromeFactory::default()))
+        } else if value == super::uniswap_v4::factory::INITIALIZE_EVENT_SIGNATURE {
+            Ok(Factory::UniswapV4Factory(UniswapV4Factory::default()))
+        } else if value == super::maverick::factory::POOL_CREATED_EVENT_SIGNATURE {
+            Ok(Factory::MaverickFactory(MaverickFactory::default()))
+        } else if value == super::algebra::factory::POOL_EVENT_SIGNATURE {
+            Ok(Factory::AlgebraFactory(AlgebraFactory::default()))
         } else {
-            return Err(EventLogError::InvalidEventSignature);
+            return Err(EventLogError::UnexpectedEvent(LogContext {
+                topic0: Some(value),
+                ..Default::default()
+            }));
+        }
+    }
+}
+
+/// Sorts a token pair into the canonical (token0, token1) order used by V2 and V3 factories
+pub fn sort_tokens(token_a: H160, token_b: H160) -> (H160, H160) {
+    if token_a < token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    }
+}
+
+/// Gets every pool trading `token_a`/`token_b` on the supplied factories, querying `getPair` on V2 factories,
+/// `getPool` for each fee tier of the fork on V3 factories, `getPool` for the stable and volatile pool on Velodrome
+/// factories and `poolByPair` on Algebra factories in a single multicall. Uniswap V4 pools are keyed by their hooks as well, Maverick factories have no
+/// lookup by pair without the fee and tick spacing of the pool and KyberSwap Elastic fees are set per pool, so V4,
+/// Maverick and KyberSwap Elastic factories are skipped.
+pub async fn get_pools_for_pair<M: 'static + Middleware>(
+    token_a: H160,
+    token_b: H160,
+    factories: &[Factory],
+    middleware: Arc<M>,
+) -> Result<Vec<AMM>, AMMError<M>> {
+    get_pools_for_pair_with_fee_tiers(token_a, token_b, factories, &[], middleware).await
+}
+
+/// Same as `get_pools_for_pair`, additionally checking `extra_fee_tiers` on V3 factories, i.e. for fees enabled by
+/// the factory after its fork was configured.
+/// V3 pools are populated with their current price and liquidity, tick data can be populated with `UniswapV3Pool::populate_tick_data`.
+/// V3 pools of a fee tier of the factory whose tick spacing is not that of the tier are dropped, as they do not belong
+/// to the fork the factory is configured for.
+pub async fn get_pools_for_pair_with_fee_tiers<M: 'static + Middleware>(
+    token_a: H160,
+    token_b: H160,
+    factories: &[Factory],
+    extra_fee_tiers: &[u32],
+    middleware: Arc<M>,
+) -> Result<Vec<AMM>, AMMError<M>> {
+    let mut multicall = Multicall::new(middleware.clone(), None).await?;
+
+    //Keep track of which factory and fee each call in the multicall belongs to
+    let mut calls = vec![];
+    for factory in factories {
+        match factory {
+            Factory::UniswapV2Factory(uniswap_v2_factory) => {
+                let contract =
+                    IUniswapV2Factory::new(uniswap_v2_factory.address, middleware.clone());
+                multicall.add_call(contract.get_pair(token_a, token_b), true);
+                calls.push((factory, uniswap_v2_factory.fee));
+            }
+            Factory::UniswapV3Factory(uniswap_v3_factory) => {
+                let contract =
+                    IUniswapV3Factory::new(uniswap_v3_factory.address, middleware.clone());
+                let mut fee_tiers = uniswap_v3_factory.fee_tiers();
+                for fee in extra_fee_tiers {
+                    if !fee_tiers.contains(fee) {
+                        fee_tiers.push(*fee);
+                    }
+                }
+
+                for fee in fee_tiers {
+                    multicall.add_call(contract.get_pool(token_a, token_b, fee), true);
+                    calls.push((factory, fee));
+                }
+            }
+            Factory::VelodromeFactory(velodrome_factory) => {
+                let contract =
+                    IVelodromeFactory::new(velodrome_factory.address, middleware.clone());
+                //The fee of Velodrome pools is read when they are populated
+                for stable in [false, true] {
+                    multicall.add_call(contract.get_pool(token_a, token_b, stable), true);
+                    calls.push((factory, 0));
+                }
+            }
+            Factory::AlgebraFactory(algebra_factory) => {
+                let contract = IAlgebraFactory::new(algebra_factory.address, middleware.clone());
+                //The fee of Algebra pools is read from their global state when they are populated
+                multicall.add_call(contract.pool_by_pair(token_a, token_b), true);
+                calls.push((factory, 0));
+            }
+            Factory::UniswapV4Factory(_)
+            | Factory::MaverickFactory(_)
+            | Factory::KyberElasticFactory(_) => {}
         }
     }
+
+    if calls.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let block_number = middleware
+        .get_block_number()
+        .await
+        .map_err(AMMError::MiddlewareError)?
+        .as_u64();
+
+    let return_data = multicall.block(block_number).call_raw().await?;
+
+    let mut uniswap_v2_pools: Vec<AMM> = vec![];
+    let mut uniswap_v3_pools: Vec<AMM> = vec![];
+    let mut velodrome_pools: Vec<AMM> = vec![];
+    let mut algebra_pools: Vec<AMM> = vec![];
+    //Tick spacing of the fee tier each V3 pool was found for, none for extra fee tiers
+    let mut uniswap_v3_tick_spacings = vec![];
+
+    for ((factory, fee), result) in calls.into_iter().zip(return_data) {
+        //Reverted calls and zero addresses mean that there is no pool for this factory/fee
+        let address = match result.ok().and_then(|token| token.into_address()) {
+            Some(address) if !address.is_zero() => address,
+            _ => continue,
+        };
+
+        match factory {
+            Factory::UniswapV2Factory(_) => {
+                if !uniswap_v2_pools.iter().any(|amm| amm.address() == address) {
+                    uniswap_v2_pools.push(AMM::UniswapV2Pool(UniswapV2Pool {
+                        address,
+                        fee,
+                        ..Default::default()
+                    }));
+                }
+            }
+            Factory::UniswapV3Factory(uniswap_v3_factory) => {
+                if !uniswap_v3_pools.iter().any(|amm| amm.address() == address) {
+                    uniswap_v3_pools.push(AMM::UniswapV3Pool(UniswapV3Pool {
+                        address,
+                        fee,
+                        ..Default::default()
+                    }));
+                    if let Some(tick_spacing) = uniswap_v3_factory.tick_spacing(fee) {
+                        uniswap_v3_tick_spacings.push((address, tick_spacing));
+                    }
+                }
+            }
+            Factory::VelodromeFactory(_) => {
+                if !velodrome_pools.iter().any(|amm| amm.address() == address) {
+                    velodrome_pools.push(AMM::VelodromePool(VelodromePool {
+                        address,
+                        ..Default::default()
+                    }));
+                }
+            }
+            Factory::AlgebraFactory(_) => {
+                if !algebra_pools.iter().any(|amm| amm.address() == address) {
+                    algebra_pools.push(AMM::AlgebraPool(AlgebraPool::new(
+                        address,
+                        H160::zero(),
+                        0,
+                        H160::zero(),
+                        0,
+                        fee,
+                        TICK_SPACING,
+                    )));
+                }
+            }
+            Factory::UniswapV4Factory(_)
+            | Factory::MaverickFactory(_)
+            | Factory::KyberElasticFactory(_) => {}
+        }
+    }
+
+    let mut amms = vec![];
+    for mut pools in [
+        uniswap_v2_pools,
+        uniswap_v3_pools,
+        velodrome_pools,
+        algebra_pools,
+    ] {
+        if !pools.is_empty() {
+            let step = pools.len() as u64;
+            sync::populate_amms(&mut pools, block_number, middleware.clone(), step).await?;
+            amms.extend(sync::remove_empty_amms(pools));
+        }
+    }
+
+    amms.retain(|amm| match amm {
+        AMM::UniswapV3Pool(pool) => has_tier_tick_spacing(pool, &uniswap_v3_tick_spacings),
+        _ => true,
+    });
+
+    Ok(amms)
+}
+
+//Whether the tick spacing read from `pool` is that of the fee tier it was found for
+fn has_tier_tick_spacing(pool: &UniswapV3Pool, tick_spacings: &[(H160, i32)]) -> bool {
+    match tick_spacings
+        .iter()
+        .find(|(address, _)| *address == pool.address)
+    {
+        Some((_, tick_spacing)) if *tick_spacing != pool.tick_spacing => {
+            tracing::warn!(
+                pool = ?pool.address,
+                fee = pool.fee,
+                tick_spacing = pool.tick_spacing,
+                expected = tick_spacing,
+                "dropping V3 pool with the tick spacing of another fork"
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Subscribes to the creation events of `factories`, yielding each new pool populated at its creation block.
+/// Creation logs that are delivered more than once, i.e. after a reorg, are deduplicated by pool address once the pool
+/// was populated, pools failing to populate are retried if their log is delivered again.
+/// Removed logs of creations unwound by a reorg are skipped, pools already yielded from them are not retracted.
+pub fn subscribe_new_pools<M>(
+    factories: Vec<Factory>,
+    middleware: Arc<M>,
+) -> (impl Stream<Item = AMM>, JoinHandle<Result<(), AMMError<M>>>)
+where
+    M: 'static + Middleware,
+    M::Provider: PubsubClient,
+{
+    let (amm_tx, amm_rx) = tokio::sync::mpsc::channel(100);
+
+    let subscription_handle = tokio::spawn(async move {
+        let filter = Filter::new()
+            .topic0(
+                factories
+                    .iter()
+                    .map(|factory| factory.amm_created_event_signature())
+                    .collect::<Vec<H256>>(),
+            )
+            .address(
+                factories
+                    .iter()
+                    .map(|factory| factory.address())
+                    .collect::<Vec<H160>>(),
+            );
+
+        let mut log_stream = middleware
+            .subscribe_logs(&filter)
+            .await
+            .map_err(AMMError::MiddlewareError)?;
+
+        let mut seen_pools = HashSet::new();
+
+        while let Some(log) = log_stream.next().await {
+            let block_number = log.block_number.map(|block_number| block_number.as_u64());
+
+            let (factory, mut amm) = match new_pool_from_log(&factories, &seen_pools, log) {
+                Ok(Some(new_pool)) => new_pool,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!(?err, "could not decode pool creation log");
+                    continue;
+                }
+            };
+
+            tracing::info!(address = ?amm.address(), block_number, "new pool created");
+
+            //A single pool failing to populate should not end the subscription
+            if let Err(err) = factory
+                .populate_amm_data(
+                    std::slice::from_mut(&mut amm),
+                    block_number,
+                    middleware.clone(),
+                    1,
+                )
+                .await
+            {
+                tracing::warn!(?err, address = ?amm.address(), "could not populate new pool");
+                continue;
+            }
+
+            seen_pools.insert(amm.address());
+
+            for amm in sync::remove_empty_amms(vec![amm]) {
+                if amm_tx.send(amm).await.is_err() {
+                    //The receiving stream was dropped
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok::<(), AMMError<M>>(())
+    });
+
+    let amm_stream = futures::stream::unfold(amm_rx, |mut amm_rx| async move {
+        amm_rx.recv().await.map(|amm| (amm, amm_rx))
+    });
+
+    (amm_stream, subscription_handle)
+}
+
+fn factory_for_log<'a>(factories: &'a [Factory], log: &Log) -> Option<&'a Factory> {
+    factories.iter().find(|factory| {
+        factory.address() == log.address
+            && log.topics.first() == Some(&factory.amm_created_event_signature())
+    })
+}
+
+//Decodes a creation log into an empty AMM and the factory that created it, skipping removed logs and pools that have
+//already been seen
+fn new_pool_from_log<'a>(
+    factories: &'a [Factory],
+    seen_pools: &HashSet<H160>,
+    log: Log,
+) -> Result<Option<(&'a Factory, AMM)>, EventLogError> {
+    if log.removed == Some(true) {
+        return Ok(None);
+    }
+
+    let factory = match factory_for_log(factories, &log) {
+        Some(factory) => factory,
+        None => return Ok(None),
+    };
+
+    let amm = factory.new_empty_amm_with_factory_fee(&log)?;
+
+    if seen_pools.contains(&amm.address()) {
+        return Ok(None);
+    }
+
+    Ok(Some((factory, amm)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use std::collections::HashSet;
+
+    use ethers::{
+        abi::{RawLog, Token},
+        prelude::EthEvent,
+        providers::{Http, Provider},
+        types::{Log, H160, H256, I256, U256, U64},
+    };
+
+    use crate::amm::{
+        factory::AutomatedMarketMakerFactory,
+        uniswap_v2::factory::{UniswapV2Factory, PAIR_CREATED_EVENT_SIGNATURE},
+        uniswap_v3::factory::{
+            decode_pool_created_log, PoolCreatedFilter, UniswapV3Factory,
+            POOL_CREATED_EVENT_SIGNATURE,
+        },
+        uniswap_v3::UniswapV3Pool,
+        AmmState, AMM,
+    };
+
+    use super::{get_pools_for_pair, has_tier_tick_spacing, new_pool_from_log, Factory};
+
+    #[tokio::test]
+    async fn test_get_pools_for_pair() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let factories = vec![
+            Factory::UniswapV2Factory(UniswapV2Factory::new(
+                H160::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")?,
+                10000835,
+                300,
+            )),
+            Factory::UniswapV3Factory(UniswapV3Factory::new(
+                H160::from_str("0x1F98431c8aD98523631AE4a59f267346ea31F984")?,
+                12369621,
+            )),
+        ];
+
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")?;
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")?;
+
+        let amms = get_pools_for_pair(weth, usdc, &factories, middleware).await?;
+        let addresses = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+
+        assert!(addresses.contains(&H160::from_str(
+            "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"
+        )?));
+        assert!(addresses.contains(&H160::from_str(
+            "0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640"
+        )?));
+
+        for amm in amms {
+            assert!(amm.tokens().contains(&weth));
+            assert!(amm.tokens().contains(&usdc));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_tier_tick_spacing() {
+        let pool = UniswapV3Pool {
+            address: H160::from_low_u64_be(1),
+            fee: 2500,
+            tick_spacing: 50,
+            ..Default::default()
+        };
+
+        assert!(has_tier_tick_spacing(&pool, &[(pool.address, 50)]));
+        assert!(!has_tier_tick_spacing(&pool, &[(pool.address, 60)]));
+        //Pools of extra fee tiers have no tick spacing to check
+        assert!(has_tier_tick_spacing(&pool, &[]));
+    }
+
+    #[test]
+    fn test_new_pool_from_log() -> eyre::Result<()> {
+        let factory_address = H160::from_low_u64_be(100);
+        let factories = vec![Factory::UniswapV2Factory(UniswapV2Factory::new(
+            factory_address,
+            0,
+            300,
+        ))];
+        let pair = H160::from_low_u64_be(1);
+
+        let creation_log = Log {
+            address: factory_address,
+            topics: vec![
+                PAIR_CREATED_EVENT_SIGNATURE,
+                H256::from(H160::from_low_u64_be(2)),
+                H256::from(H160::from_low_u64_be(3)),
+            ],
+            data: ethers::abi::encode(&[Token::Address(pair), Token::Uint(U256::one())]).into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        };
+
+        let mut seen_pools = HashSet::new();
+
+        match new_pool_from_log(&factories, &seen_pools, creation_log.clone())? {
+            Some((factory, AMM::UniswapV2Pool(pool))) => {
+                assert_eq!(factory.address(), factory_address);
+                assert_eq!(pool.address, pair);
+                assert_eq!(pool.fee, 300);
+                assert_eq!(pool.creation_block, Some(10));
+            }
+            _ => panic!("Expected a new UniswapV2 pool"),
+        }
+
+        //Until the pool is populated the log is not deduplicated, so that a failed population can be retried
+        assert!(new_pool_from_log(&factories, &seen_pools, creation_log.clone())?.is_some());
+
+        //The same creation log delivered again after a reorg is deduplicated by address
+        seen_pools.insert(pair);
+        assert!(new_pool_from_log(&factories, &seen_pools, creation_log.clone())?.is_none());
+
+        //Removed logs are skipped
+        let removed_log = Log {
+            removed: Some(true),
+            ..creation_log.clone()
+        };
+        assert!(new_pool_from_log(&factories, &HashSet::new(), removed_log)?.is_none());
+
+        //Logs from unknown factories are ignored
+        let unknown_factory_log = Log {
+            address: H160::from_low_u64_be(101),
+            ..creation_log
+        };
+        assert!(new_pool_from_log(&factories, &HashSet::new(), unknown_factory_log)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_amms_from_logs() {
+        let factory_address = H160::from_low_u64_be(100);
+        let factory = Factory::UniswapV2Factory(UniswapV2Factory::new(factory_address, 0, 300));
+
+        let creation_log = |pair: H160| Log {
+            address: factory_address,
+            topics: vec![
+                PAIR_CREATED_EVENT_SIGNATURE,
+                H256::from(H160::from_low_u64_be(2)),
+                H256::from(H160::from_low_u64_be(3)),
+            ],
+            data: ethers::abi::encode(&[Token::Address(pair), Token::Uint(U256::one())]).into(),
+            ..Default::default()
+        };
+
+        let logs = vec![
+            creation_log(H160::from_low_u64_be(1)),
+            //Logs from other contracts are ignored
+            Log {
+                address: H160::from_low_u64_be(101),
+                ..creation_log(H160::from_low_u64_be(4))
+            },
+            //Malformed logs are reported without failing the batch
+            Log {
+                data: vec![0; 4].into(),
+                ..creation_log(H160::zero())
+            },
+            creation_log(H160::from_low_u64_be(5)),
+        ];
+
+        let (amms, errors) = factory.amms_from_logs_with_errors(&logs);
+        assert_eq!(
+            amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>(),
+            vec![H160::from_low_u64_be(1), H160::from_low_u64_be(5)]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 2);
+
+        for amm in factory
+            .amms_from_logs(&logs)
+            .expect("Could not decode logs")
+        {
+            if let AMM::UniswapV2Pool(pool) = amm {
+                assert_eq!(pool.fee, 300);
+            }
+        }
+
+        assert!(factory.amms_from_logs(&logs[2..3]).is_err());
+        assert!(factory
+            .amms_from_logs(&logs[1..2])
+            .expect("Could not decode logs")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_decode_pool_created_log() -> eyre::Result<()> {
+        let factory = UniswapV3Factory::new(H160::from_low_u64_be(100), 0);
+        let pool = H160::from_low_u64_be(1);
+
+        let creation_log = Log {
+            address: factory.address,
+            topics: vec![
+                POOL_CREATED_EVENT_SIGNATURE,
+                H256::from(H160::from_low_u64_be(2)),
+                H256::from(H160::from_low_u64_be(3)),
+                H256::from_low_u64_be(500),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(60).into_raw()),
+                Token::Address(pool),
+            ])
+            .into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        };
+
+        //Slicing the log decodes the same event as the ABI decoder
+        assert_eq!(
+            decode_pool_created_log(&creation_log)?,
+            PoolCreatedFilter::decode_log(&RawLog::from(creation_log.clone()))?
+        );
+
+        match factory.new_empty_amm_from_log(&creation_log)? {
+            AMM::UniswapV3Pool(v3_pool) => {
+                assert_eq!(v3_pool.address, pool);
+                assert_eq!(v3_pool.fee, 500);
+                assert_eq!(v3_pool.tick_spacing, 60);
+                assert_eq!(v3_pool.creation_block, Some(10));
+            }
+            _ => panic!("Expected a new UniswapV3 pool"),
+        }
+
+        //Other events and truncated logs are rejected
+        let pair_created_log = Log {
+            topics: vec![PAIR_CREATED_EVENT_SIGNATURE],
+            ..creation_log.clone()
+        };
+        assert!(decode_pool_created_log(&pair_created_log).is_err());
+
+        let truncated_log = Log {
+            data: creation_log.data[..32].to_vec().into(),
+            ..creation_log
+        };
+        assert!(decode_pool_created_log(&truncated_log).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,676 @@
+use std::str::FromStr;
+
+use ethers::types::H160;
+
+use super::{
+    factory::Factory,
+    uniswap_v2::factory::UniswapV2Factory,
+    uniswap_v3::factory::{UniswapV3Factory, UniswapV3Fork},
+    uniswap_v4::factory::UniswapV4Factory,
+    velodrome::factory::VelodromeFactory,
+};
+
+pub const ETHEREUM: u64 = 1;
+pub const OPTIMISM: u64 = 10;
+pub const BSC: u64 = 56;
+pub const POLYGON: u64 = 137;
+pub const BASE: u64 = 8453;
+pub const ARBITRUM: u64 = 42161;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownFactoryKind {
+    UniswapV2 { fee: u32 },
+    UniswapV3,
+    PancakeSwapV3,
+    Velodrome,
+    UniswapV4,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KnownFactory {
+    pub name: &'static str,
+    pub address: &'static str,
+    pub creation_block: u64,
+    pub kind: KnownFactoryKind,
+}
+
+impl KnownFactory {
+    const fn uniswap_v2(
+        name: &'static str,
+        address: &'static str,
+        creation_block: u64,
+        fee: u32,
+    ) -> Self {
+        KnownFactory {
+            name,
+            address,
+            creation_block,
+            kind: KnownFactoryKind::UniswapV2 { fee },
+        }
+    }
+
+    const fn uniswap_v3(name: &'static str, address: &'static str, creation_block: u64) -> Self {
+        KnownFactory {
+            name,
+            address,
+            creation_block,
+            kind: KnownFactoryKind::UniswapV3,
+        }
+    }
+
+    const fn pancakeswap_v3(
+        name: &'static str,
+        address: &'static str,
+        creation_block: u64,
+    ) -> Self {
+        KnownFactory {
+            name,
+            address,
+            creation_block,
+            kind: KnownFactoryKind::PancakeSwapV3,
+        }
+    }
+
+    const fn velodrome(name: &'static str, address: &'static str, creation_block: u64) -> Self {
+        KnownFactory {
+            name,
+            address,
+            creation_block,
+            kind: KnownFactoryKind::Velodrome,
+        }
+    }
+
+    const fn uniswap_v4(name: &'static str, address: &'static str, creation_block: u64) -> Self {
+        KnownFactory {
+            name,
+            address,
+            creation_block,
+            kind: KnownFactoryKind::UniswapV4,
+        }
+    }
+
+    pub fn address(&self) -> H160 {
+        H160::from_str(self.address).expect("Known factory address is valid")
+    }
+
+    pub fn to_factory(&self) -> Factory {
+        match self.kind {
+            KnownFactoryKind::UniswapV2 { fee } => Factory::UniswapV2Factory(
+                UniswapV2Factory::new(self.address(), self.creation_block, fee),
+            ),
+            KnownFactoryKind::UniswapV3 => Factory::UniswapV3Factory(UniswapV3Factory::new(
+                self.address(),
+                self.creation_block,
+            )),
+            KnownFactoryKind::PancakeSwapV3 => {
+                Factory::UniswapV3Factory(UniswapV3Factory::new_with_fork(
+                    self.address(),
+                    self.creation_block,
+                    UniswapV3Fork::PancakeSwapV3,
+                ))
+            }
+            KnownFactoryKind::Velodrome => Factory::VelodromeFactory(VelodromeFactory::new(
+                self.address(),
+                self.creation_block,
+            )),
+            KnownFactoryKind::UniswapV4 => Factory::UniswapV4Factory(UniswapV4Factory::new(
+                self.address(),
+                self.creation_block,
+            )),
+        }
+    }
+}
+
+// Aerodrome Slipstream pools are not listed since they are not compatible with the UniswapV3 variant, their factory
+// emits a different event
+const ETHEREUM_FACTORIES: &[KnownFactory] = &[
+    KnownFactory::uniswap_v2(
+        "Uniswap V2",
+        "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f",
+        10000835,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "Sushiswap",
+        "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac",
+        10794229,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "PancakeSwap V2",
+        "0x1097053Fd2ea711dad45caCcc45EfF7548fCB362",
+        15614590,
+        250,
+    ),
+    KnownFactory::uniswap_v3(
+        "Uniswap V3",
+        "0x1F98431c8aD98523631AE4a59f267346ea31F984",
+        12369621,
+    ),
+    KnownFactory::uniswap_v3(
+        "Sushiswap V3",
+        "0xbACEB8eC6b9355Dfc0269C18bac9d6E2Bdc29C4F",
+        16955547,
+    ),
+    KnownFactory::pancakeswap_v3(
+        "PancakeSwap V3",
+        "0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865",
+        16950686,
+    ),
+    KnownFactory::uniswap_v4(
+        "Uniswap V4",
+        "0x000000000004444c5dc75cB358380D2e3dE08A90",
+        21688329,
+    ),
+];
+
+const OPTIMISM_FACTORIES: &[KnownFactory] = &[
+    KnownFactory::uniswap_v2(
+        "Uniswap V2",
+        "0x0c3c1c532F1e39EdF36BE9Fe0bE1410313E074Bf",
+        112197986,
+        300,
+    ),
+    KnownFactory::uniswap_v3(
+        "Uniswap V3",
+        "0x1F98431c8aD98523631AE4a59f267346ea31F984",
+        0,
+    ),
+];
+
+const BSC_FACTORIES: &[KnownFactory] = &[
+    KnownFactory::uniswap_v2(
+        "PancakeSwap V2",
+        "0xcA143Ce32Fe78f1f7019d7d551a6402fC5350c73",
+        6809737,
+        250,
+    ),
+    KnownFactory::uniswap_v2(
+        "Sushiswap",
+        "0xc35DADB65012eC5796536bD9864eD8773aBc74C4",
+        5205069,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "Uniswap V2",
+        "0x8909Dc15e40173Ff4699343b6eB8132c65e18eC6",
+        33496018,
+        300,
+    ),
+    KnownFactory::pancakeswap_v3(
+        "PancakeSwap V3",
+        "0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865",
+        26956207,
+    ),
+    KnownFactory::uniswap_v3(
+        "Uniswap V3",
+        "0xdB1d10011AD0Ff90774D0C6Bb92e5C5c8b4461F7",
+        26324014,
+    ),
+];
+
+const POLYGON_FACTORIES: &[KnownFactory] = &[
+    KnownFactory::uniswap_v2(
+        "QuickSwap",
+        "0x5757371414417b8C6CAad45bAeF941aBc7d3Ab32",
+        4931780,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "Sushiswap",
+        "0xc35DADB65012eC5796536bD9864eD8773aBc74C4",
+        11333218,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "Uniswap V2",
+        "0x9e5A52f57b3038F1B8EeE45F28b3C1967e22799C",
+        49948178,
+        300,
+    ),
+    KnownFactory::uniswap_v3(
+        "Uniswap V3",
+        "0x1F98431c8aD98523631AE4a59f267346ea31F984",
+        22757547,
+    ),
+];
+
+const BASE_FACTORIES: &[KnownFactory] = &[
+    KnownFactory::uniswap_v2(
+        "Uniswap V2",
+        "0x8909Dc15e40173Ff4699343b6eB8132c65e18eC6",
+        6601915,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "Sushiswap",
+        "0x71524B4f93c58fcbF659783284E38825f0622859",
+        2631214,
+        300,
+    ),
+    KnownFactory::uniswap_v3(
+        "Uniswap V3",
+        "0x33128a8fC17869897dcE68Ed026d694621f6FDfD",
+        1371680,
+    ),
+    KnownFactory::velodrome(
+        "Aerodrome",
+        "0x420DD381b31aEf6683db6B902084cB0FFECe40Da",
+        3200559,
+    ),
+    KnownFactory::pancakeswap_v3(
+        "PancakeSwap V3",
+        "0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865",
+        2912007,
+    ),
+];
+
+const ARBITRUM_FACTORIES: &[KnownFactory] = &[
+    KnownFactory::uniswap_v2(
+        "Sushiswap",
+        "0xc35DADB65012eC5796536bD9864eD8773aBc74C4",
+        70,
+        300,
+    ),
+    KnownFactory::uniswap_v2(
+        "Uniswap V2",
+        "0xf1D7CC64Fb4452F05c498126312eBE29f30Fbcf9",
+        150442611,
+        300,
+    ),
+    KnownFactory::uniswap_v3(
+        "Uniswap V3",
+        "0x1F98431c8aD98523631AE4a59f267346ea31F984",
+        165,
+    ),
+    KnownFactory::pancakeswap_v3(
+        "PancakeSwap V3",
+        "0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865",
+        101028949,
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownRouterKind {
+    UniswapV2Router,
+    UniswapV3SwapRouter,
+    UniversalRouter,
+}
+
+/// Canonical Uniswap router, swapping through the pools of the Uniswap factory of its kind on the same chain
+#[derive(Debug, Clone, Copy)]
+pub struct KnownRouter {
+    pub name: &'static str,
+    pub address: &'static str,
+    pub kind: KnownRouterKind,
+}
+
+impl KnownRouter {
+    const fn new(name: &'static str, address: &'static str, kind: KnownRouterKind) -> Self {
+        KnownRouter {
+            name,
+            address,
+            kind,
+        }
+    }
+
+    pub fn address(&self) -> H160 {
+        H160::from_str(self.address).expect("Known router address is valid")
+    }
+}
+
+const ETHEREUM_ROUTERS: &[KnownRouter] = &[
+    KnownRouter::new(
+        "Uniswap V2 Router02",
+        "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D",
+        KnownRouterKind::UniswapV2Router,
+    ),
+    KnownRouter::new(
+        "Uniswap V3 SwapRouter",
+        "0xE592427A0AEce92De3Edee1F18E0157C05861564",
+        KnownRouterKind::UniswapV3SwapRouter,
+    ),
+    KnownRouter::new(
+        "Uniswap UniversalRouter",
+        "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD",
+        KnownRouterKind::UniversalRouter,
+    ),
+];
+
+const OPTIMISM_ROUTERS: &[KnownRouter] = &[
+    KnownRouter::new(
+        "Uniswap V2 Router02",
+        "0x4A7b5Da61326A6379179b40d00F57E5bbDC962c2",
+        KnownRouterKind::UniswapV2Router,
+    ),
+    KnownRouter::new(
+        "Uniswap V3 SwapRouter",
+        "0xE592427A0AEce92De3Edee1F18E0157C05861564",
+        KnownRouterKind::UniswapV3SwapRouter,
+    ),
+    KnownRouter::new(
+        "Uniswap UniversalRouter",
+        "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD",
+        KnownRouterKind::UniversalRouter,
+    ),
+];
+
+// The original V3 SwapRouter, taking a deadline, was not deployed on BSC and Base
+const BSC_ROUTERS: &[KnownRouter] = &[
+    KnownRouter::new(
+        "Uniswap V2 Router02",
+        "0x4752ba5DBc23f44D87826276BF6Fd6b1C372aD24",
+        KnownRouterKind::UniswapV2Router,
+    ),
+    KnownRouter::new(
+        "Uniswap UniversalRouter",
+        "0x4Dae2f939ACf50408e13d58534Ff8c2776d45265",
+        KnownRouterKind::UniversalRouter,
+    ),
+];
+
+const POLYGON_ROUTERS: &[KnownRouter] = &[
+    KnownRouter::new(
+        "Uniswap V2 Router02",
+        "0xedf6066a2b290C185783862C7F4776A2C8077AD1",
+        KnownRouterKind::UniswapV2Router,
+    ),
+    KnownRouter::new(
+        "Uniswap V3 SwapRouter",
+        "0xE592427A0AEce92De3Edee1F18E0157C05861564",
+        KnownRouterKind::UniswapV3SwapRouter,
+    ),
+    KnownRouter::new(
+        "Uniswap UniversalRouter",
+        "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD",
+        KnownRouterKind::UniversalRouter,
+    ),
+];
+
+const BASE_ROUTERS: &[KnownRouter] = &[
+    KnownRouter::new(
+        "Uniswap V2 Router02",
+        "0x4752ba5DBc23f44D87826276BF6Fd6b1C372aD24",
+        KnownRouterKind::UniswapV2Router,
+    ),
+    KnownRouter::new(
+        "Uniswap UniversalRouter",
+        "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD",
+        KnownRouterKind::UniversalRouter,
+    ),
+];
+
+const ARBITRUM_ROUTERS: &[KnownRouter] = &[
+    KnownRouter::new(
+        "Uniswap V2 Router02",
+        "0x4752ba5DBc23f44D87826276BF6Fd6b1C372aD24",
+        KnownRouterKind::UniswapV2Router,
+    ),
+    KnownRouter::new(
+        "Uniswap V3 SwapRouter",
+        "0xE592427A0AEce92De3Edee1F18E0157C05861564",
+        KnownRouterKind::UniswapV3SwapRouter,
+    ),
+    KnownRouter::new(
+        "Uniswap UniversalRouter",
+        "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD",
+        KnownRouterKind::UniversalRouter,
+    ),
+];
+
+/// Deployments and sync defaults of a chain.
+///
+/// Providers of L2s cap `eth_getLogs` ranges far more aggressively than L1 providers, and the gas limit of `eth_call`
+/// bounds how many pools a batch request can populate, so syncs on L2s need smaller steps than on Ethereum.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainPreset {
+    pub chain_id: u64,
+    pub name: &'static str,
+    /// Blocks per `eth_getLogs` request
+    pub log_step: u64,
+    /// Pools per batch request
+    pub batch_step: u64,
+    pub factories: &'static [KnownFactory],
+    pub routers: &'static [KnownRouter],
+}
+
+impl ChainPreset {
+    /// Returns the factory named `name`, i.e. `"Uniswap V3"`
+    pub fn factory(&self, name: &str) -> Option<Factory> {
+        self.factories
+            .iter()
+            .find(|known_factory| known_factory.name == name)
+            .map(KnownFactory::to_factory)
+    }
+}
+
+const CHAIN_PRESETS: &[ChainPreset] = &[
+    ChainPreset {
+        chain_id: ETHEREUM,
+        name: "Ethereum",
+        log_step: 10000,
+        batch_step: 100,
+        factories: ETHEREUM_FACTORIES,
+        routers: ETHEREUM_ROUTERS,
+    },
+    ChainPreset {
+        chain_id: OPTIMISM,
+        name: "Optimism",
+        log_step: 5000,
+        batch_step: 50,
+        factories: OPTIMISM_FACTORIES,
+        routers: OPTIMISM_ROUTERS,
+    },
+    ChainPreset {
+        chain_id: BSC,
+        name: "BSC",
+        log_step: 5000,
+        batch_step: 100,
+        factories: BSC_FACTORIES,
+        routers: BSC_ROUTERS,
+    },
+    ChainPreset {
+        chain_id: POLYGON,
+        name: "Polygon",
+        log_step: 3500,
+        batch_step: 100,
+        factories: POLYGON_FACTORIES,
+        routers: POLYGON_ROUTERS,
+    },
+    ChainPreset {
+        chain_id: BASE,
+        name: "Base",
+        log_step: 2000,
+        batch_step: 50,
+        factories: BASE_FACTORIES,
+        routers: BASE_ROUTERS,
+    },
+    //Arbitrum produces a block every 250ms, and the L1 component of its gas accounting lowers the gas left to batch calls
+    ChainPreset {
+        chain_id: ARBITRUM,
+        name: "Arbitrum",
+        log_step: 2000,
+        batch_step: 30,
+        factories: ARBITRUM_FACTORIES,
+        routers: ARBITRUM_ROUTERS,
+    },
+];
+
+/// Returns the preset of `chain_id`, or None if the chain is not supported
+pub fn chain_preset(chain_id: u64) -> Option<&'static ChainPreset> {
+    CHAIN_PRESETS
+        .iter()
+        .find(|preset| preset.chain_id == chain_id)
+}
+
+/// Returns the registry entries for `chain_id`, or an empty slice if the chain is not supported
+pub fn known_factory_table(chain_id: u64) -> &'static [KnownFactory] {
+    chain_preset(chain_id)
+        .map(|preset| preset.factories)
+        .unwrap_or(&[])
+}
+
+/// Returns the canonical factories deployed on `chain_id` with their creation blocks and default fees
+pub fn known_factories(chain_id: u64) -> Vec<Factory> {
+    known_factory_table(chain_id)
+        .iter()
+        .map(KnownFactory::to_factory)
+        .collect()
+}
+
+/// Returns the routers deployed on `chain_id`, or an empty slice if the chain is not supported
+pub fn known_router_table(chain_id: u64) -> &'static [KnownRouter] {
+    chain_preset(chain_id)
+        .map(|preset| preset.routers)
+        .unwrap_or(&[])
+}
+
+/// Returns the address of the router of `kind` deployed on `chain_id`, if any
+pub fn known_router(chain_id: u64, kind: KnownRouterKind) -> Option<H160> {
+    known_router_table(chain_id)
+        .iter()
+        .find(|router| router.kind == kind)
+        .map(KnownRouter::address)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        providers::{Http, Middleware, Provider},
+        types::{BlockNumber, Filter, ValueOrArray},
+        utils::to_checksum,
+    };
+
+    use crate::{amm::factory::AutomatedMarketMakerFactory, sync};
+
+    use super::{
+        chain_preset, known_factories, known_factory_table, known_router, known_router_table,
+        KnownRouterKind, ARBITRUM, BASE, BSC, ETHEREUM, OPTIMISM, POLYGON,
+    };
+
+    #[test]
+    fn test_known_factory_addresses_are_checksummed() {
+        for chain_id in [ETHEREUM, OPTIMISM, BSC, POLYGON, BASE, ARBITRUM] {
+            assert!(!known_factory_table(chain_id).is_empty());
+
+            for known_factory in known_factory_table(chain_id) {
+                assert_eq!(
+                    to_checksum(&known_factory.address(), None),
+                    known_factory.address,
+                    "{} on chain {}",
+                    known_factory.name,
+                    chain_id
+                );
+            }
+        }
+
+        assert!(known_factories(0).is_empty());
+    }
+
+    #[test]
+    fn test_known_router_addresses_are_checksummed() {
+        for chain_id in [ETHEREUM, OPTIMISM, BSC, POLYGON, BASE, ARBITRUM] {
+            assert!(known_router(chain_id, KnownRouterKind::UniversalRouter).is_some());
+
+            for known_router in known_router_table(chain_id) {
+                assert_eq!(
+                    to_checksum(&known_router.address(), None),
+                    known_router.address,
+                    "{} on chain {}",
+                    known_router.name,
+                    chain_id
+                );
+            }
+        }
+
+        assert_eq!(
+            known_router(BASE, KnownRouterKind::UniswapV3SwapRouter),
+            None
+        );
+        assert!(known_router_table(0).is_empty());
+    }
+
+    #[test]
+    fn test_chain_presets() {
+        let ethereum = chain_preset(ETHEREUM).expect("Ethereum preset");
+        for chain_id in [ETHEREUM, OPTIMISM, BSC, POLYGON, BASE, ARBITRUM] {
+            let preset = chain_preset(chain_id).expect("Chain preset");
+            assert_eq!(preset.chain_id, chain_id);
+
+            //L2 providers cap log ranges and batch calls below L1 providers
+            assert!(preset.log_step <= ethereum.log_step, "{}", preset.name);
+            assert!(preset.batch_step <= ethereum.batch_step, "{}", preset.name);
+        }
+
+        let arbitrum = chain_preset(ARBITRUM).expect("Arbitrum preset");
+        assert_eq!(
+            arbitrum
+                .factory("Uniswap V3")
+                .map(|factory| factory.address()),
+            Some(known_factory_table(ARBITRUM)[2].address())
+        );
+        assert!(arbitrum.factory("Aerodrome").is_none());
+        assert!(chain_preset(0).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sync_uniswap_v3_on_arbitrum() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ARBITRUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        assert_eq!(middleware.get_chainid().await?.as_u64(), ARBITRUM);
+
+        let preset = chain_preset(ARBITRUM).expect("Arbitrum preset");
+        let factory = preset
+            .factory("Uniswap V3")
+            .expect("Uniswap V3 on Arbitrum");
+
+        //Syncs the pools created in the first blocks after the factory was deployed with the steps of the preset
+        let to_block = factory.creation_block() + 1000 * preset.log_step;
+        let mut amms = factory
+            .get_all_pools_from_logs(
+                factory.creation_block(),
+                to_block,
+                preset.log_step,
+                middleware.clone(),
+            )
+            .await?;
+        factory
+            .populate_amm_data(
+                &mut amms,
+                Some(to_block),
+                middleware.clone(),
+                preset.batch_step,
+            )
+            .await?;
+
+        let amms = sync::remove_empty_amms(amms);
+        assert!(!amms.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_known_factory_creation_blocks() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //No pool can have been created by a factory before its creation block
+        for factory in known_factories(ETHEREUM) {
+            let logs = middleware
+                .get_logs(
+                    &Filter::new()
+                        .topic0(ValueOrArray::Value(factory.amm_created_event_signature()))
+                        .address(factory.address())
+                        .from_block(BlockNumber::Earliest)
+                        .to_block(factory.creation_block() - 1),
+                )
+                .await?;
+
+            assert!(logs.is_empty(), "factory {:?}", factory.address());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, I256, U256},
+};
+
+use crate::{
+    amm::{
+        multicall,
+        uniswap_v3::{MAX_SQRT_RATIO, MIN_SQRT_RATIO},
+        AmmState, AMM,
+    },
+    errors::AMMError,
+};
+
+use super::{KyberElasticPool, KyberTick, FEE_UNITS, MAX_TICK, MIN_TICK};
+
+/// Initialized ticks read on each side of the current tick when populating a pool
+pub const TICK_RADIUS: usize = 20;
+
+//token0()
+const TOKEN_0_SELECTOR: [u8; 4] = [13, 254, 22, 129];
+//token1()
+const TOKEN_1_SELECTOR: [u8; 4] = [210, 18, 32, 167];
+//swapFeeUnits()
+const SWAP_FEE_UNITS_SELECTOR: [u8; 4] = [199, 154, 89, 14];
+//tickDistance()
+const TICK_DISTANCE_SELECTOR: [u8; 4] = [72, 98, 106, 140];
+//getPoolState()
+const GET_POOL_STATE_SELECTOR: [u8; 4] = [33, 122, 194, 55];
+//getLiquidityState()
+const GET_LIQUIDITY_STATE_SELECTOR: [u8; 4] = [171, 97, 47, 43];
+//ticks(int24)
+const TICKS_SELECTOR: [u8; 4] = [243, 13, 186, 147];
+//initializedTicks(int24)
+const INITIALIZED_TICKS_SELECTOR: [u8; 4] = [192, 172, 117, 207];
+
+const POOL_SELECTORS: [[u8; 4]; 6] = [
+    TOKEN_0_SELECTOR,
+    TOKEN_1_SELECTOR,
+    SWAP_FEE_UNITS_SELECTOR,
+    TICK_DISTANCE_SELECTOR,
+    GET_POOL_STATE_SELECTOR,
+    GET_LIQUIDITY_STATE_SELECTOR,
+];
+
+/// Reads `pool` and the initialized ticks within `TICK_RADIUS` of its current tick at `block_number`, erroring with
+/// `AMMError::BatchRequestError` if it is not a KyberSwap Elastic pool
+pub async fn get_kyber_elastic_pool_data_batch_request<M: Middleware>(
+    pool: &mut KyberElasticPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every KyberSwap Elastic pool of `amms` at `block_number` through Multicall3, leaving the pools that could
+/// not be read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::KyberElasticPool(kyber_pool) = amm {
+            if let Some(pool) = populate_pool_data(kyber_pool.to_owned(), pool_data) {
+                *kyber_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    tokens: Option<(H160, H160)>,
+    fee: Option<u32>,
+    tick_distance: Option<i32>,
+    //sqrtP, currentTick and nearestCurrentTick of `getPoolState`
+    pool_state: Option<(U256, i32, i32)>,
+    //baseL and reinvestL of `getLiquidityState`
+    liquidity_state: Option<(u128, u128)>,
+    decimals: (Option<u8>, Option<u8>),
+    ticks: Option<(BTreeMap<i32, KyberTick>, (i32, i32))>,
+}
+
+fn populate_pool_data(mut pool: KyberElasticPool, pool_data: PoolData) -> Option<KyberElasticPool> {
+    (pool.token_a, pool.token_b) = pool_data.tokens?;
+    (pool.token_a_decimals, pool.token_b_decimals) = (pool_data.decimals.0?, pool_data.decimals.1?);
+    pool.fee = pool_data.fee?;
+    pool.tick_distance = pool_data.tick_distance?;
+    (pool.sqrt_price, pool.tick, _) = pool_data.pool_state?;
+    (pool.base_liquidity, pool.reinvest_liquidity) = pool_data.liquidity_state?;
+    (pool.ticks, pool.tick_range) = pool_data.ticks?;
+
+    Some(pool)
+}
+
+//Initialized ticks of a pool read so far, and the next ones to read below and above them
+struct TickWalk {
+    ticks: BTreeMap<i32, KyberTick>,
+    lower: Option<i32>,
+    upper: Option<i32>,
+}
+
+impl TickWalk {
+    fn new(nearest_tick: i32) -> TickWalk {
+        TickWalk {
+            ticks: BTreeMap::new(),
+            lower: Some(nearest_tick),
+            upper: Some(nearest_tick),
+        }
+    }
+
+    fn next_ticks(&self) -> Vec<i32> {
+        let mut ticks = self.lower.into_iter().chain(self.upper).collect::<Vec<_>>();
+        ticks.dedup();
+
+        ticks
+    }
+
+    //The ticks at MIN_TICK and MAX_TICK end the list by pointing to themselves
+    fn read(&mut self, tick: i32, info: KyberTick, previous: i32, next: i32) {
+        self.ticks.insert(tick, info);
+        if self.lower == Some(tick) {
+            self.lower = (previous < tick).then_some(previous);
+        }
+        if self.upper == Some(tick) {
+            self.upper = (next > tick).then_some(next);
+        }
+    }
+
+    fn into_ticks(self) -> Option<(BTreeMap<i32, KyberTick>, (i32, i32))> {
+        let tick_range = (*self.ticks.keys().next()?, *self.ticks.keys().next_back()?);
+
+        Some((self.ticks, tick_range))
+    }
+}
+
+//Reads the pools, then walks the linked list of initialized ticks of each pool from the nearest tick at or below
+//its current tick, one round of calls per tick on each side
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| POOL_SELECTORS.map(|selector| multicall::call(*pool, selector, &[])))
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(POOL_SELECTORS.len())
+        .map(|pool_return_data| PoolData {
+            tokens: address(&pool_return_data[0]).zip(address(&pool_return_data[1])),
+            fee: word(&pool_return_data[2], 0)
+                .filter(|fee| *fee < U256::from(FEE_UNITS))
+                .map(|fee| fee.as_u32()),
+            tick_distance: int_word(&pool_return_data[3], 0)
+                .filter(|tick_distance| *tick_distance > 0),
+            pool_state: pool_state(&pool_return_data[4]),
+            liquidity_state: liquidity_state(&pool_return_data[5]),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pool_data
+        .iter()
+        .filter_map(|pool_data| pool_data.tokens)
+        .flat_map(|(token_0, token_1)| [token_0, token_1])
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware.clone()).await?;
+    for pool_data in pool_data.iter_mut() {
+        if let Some((token_0, token_1)) = pool_data.tokens {
+            pool_data.decimals = (
+                decimals.get(&token_0).copied(),
+                decimals.get(&token_1).copied(),
+            );
+        }
+    }
+
+    let mut walks = pool_data
+        .iter()
+        .map(|pool_data| {
+            pool_data
+                .pool_state
+                .map(|(_, _, nearest_tick)| TickWalk::new(nearest_tick))
+        })
+        .collect::<Vec<_>>();
+    //The nearest tick, then `TICK_RADIUS` ticks on each side of it
+    for _ in 0..=TICK_RADIUS {
+        let reads = walks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, walk)| Some((i, walk.as_ref()?.next_ticks())))
+            .flat_map(|(i, ticks)| ticks.into_iter().map(move |tick| (i, tick)))
+            .collect::<Vec<_>>();
+        if reads.is_empty() {
+            break;
+        }
+
+        let calls = reads
+            .iter()
+            .flat_map(|(i, tick)| {
+                let args = ethers::abi::encode(&[Token::Int(I256::from(*tick).into_raw())]);
+                [
+                    multicall::call(pools[*i], TICKS_SELECTOR, &args),
+                    multicall::call(pools[*i], INITIALIZED_TICKS_SELECTOR, &args),
+                ]
+            })
+            .collect();
+        let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+        //A pool whose ticks could not be read is left unpopulated
+        for ((i, tick), tick_return_data) in reads.into_iter().zip(return_data.chunks(2)) {
+            let info = tick_info(&tick_return_data[0]).zip(linked_ticks(&tick_return_data[1]));
+            walks[i] = walks[i].take().and_then(|mut walk| {
+                let (info, (previous, next)) = info?;
+                walk.read(tick, info, previous, next);
+                Some(walk)
+            });
+        }
+    }
+
+    for (pool_data, walk) in pool_data.iter_mut().zip(walks) {
+        pool_data.ticks = walk.and_then(TickWalk::into_ticks);
+    }
+
+    Ok(pool_data)
+}
+
+//uint160 sqrtP, int24 currentTick, int24 nearestCurrentTick, bool locked
+fn pool_state(return_data: &Option<Bytes>) -> Option<(U256, i32, i32)> {
+    let sqrt_price = word(return_data, 0)
+        .filter(|sqrt_price| *sqrt_price >= MIN_SQRT_RATIO && *sqrt_price < MAX_SQRT_RATIO)?;
+
+    Some((
+        sqrt_price,
+        tick_word(return_data, 1)?,
+        tick_word(return_data, 2)?,
+    ))
+}
+
+//uint128 baseL, uint128 reinvestL, uint128 reinvestLLast
+fn liquidity_state(return_data: &Option<Bytes>) -> Option<(u128, u128)> {
+    let base_liquidity =
+        word(return_data, 0).filter(|liquidity| *liquidity <= U256::from(u128::MAX))?;
+    let reinvest_liquidity =
+        word(return_data, 1).filter(|liquidity| *liquidity <= U256::from(u128::MAX))?;
+
+    Some((base_liquidity.as_u128(), reinvest_liquidity.as_u128()))
+}
+
+//uint128 liquidityGross, int128 liquidityNet, uint256 feeGrowthOutside, uint128 secondsPerLiquidityOutside
+fn tick_info(return_data: &Option<Bytes>) -> Option<KyberTick> {
+    let liquidity_gross =
+        word(return_data, 0).filter(|liquidity| *liquidity <= U256::from(u128::MAX))?;
+    let liquidity_net = I256::from_raw(word(return_data, 1)?);
+    if liquidity_net < I256::from(i128::MIN) || liquidity_net > I256::from(i128::MAX) {
+        return None;
+    }
+
+    Some(KyberTick {
+        liquidity_gross: liquidity_gross.as_u128(),
+        liquidity_net: liquidity_net.as_i128(),
+    })
+}
+
+//int24 previous, int24 next
+fn linked_ticks(return_data: &Option<Bytes>) -> Option<(i32, i32)> {
+    Some((tick_word(return_data, 0)?, tick_word(return_data, 1)?))
+}
+
+fn word(return_data: &Option<Bytes>, index: usize) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, index)
+}
+
+//Sign extended `int24` held in word `index`
+fn int_word(return_data: &Option<Bytes>, index: usize) -> Option<i32> {
+    let value = I256::from_raw(word(return_data, index)?);
+
+    (I256::from(-(1 << 23)) <= value && value < I256::from(1 << 23)).then(|| value.low_i32())
+}
+
+fn tick_word(return_data: &Option<Bytes>, index: usize) -> Option<i32> {
+    int_word(return_data, index).filter(|tick| (MIN_TICK..=MAX_TICK).contains(tick))
+}
+
+fn address(return_data: &Option<Bytes>) -> Option<H160> {
+    multicall::address_word(return_data.as_ref()?, 0).filter(|address| !address.is_zero())
+}
@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, Filter, Log, H160, H256, U256, U64},
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    amm::{
+        decode,
+        factory::{AutomatedMarketMakerFactory, TASK_LIMIT},
+        AutomatedMarketMaker, AMM,
+    },
+    errors::{AMMError, EventLogError},
+};
+
+use super::{batch_request, KyberElasticPool, FEE_UNITS};
+
+//PoolCreated(address indexed token0, address indexed token1, uint24 indexed swapFeeUnits, int24 tickDistance, address pool)
+pub const POOL_CREATED_EVENT_SIGNATURE: H256 = H256([
+    120, 60, 202, 28, 4, 18, 221, 13, 105, 94, 120, 69, 104, 201, 109, 162, 233, 194, 47, 249, 137,
+    53, 122, 46, 139, 29, 155, 43, 78, 107, 113, 24,
+]);
+
+/// KyberSwap Elastic pool factory, discovering its pools from their `PoolCreated` logs
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct KyberElasticFactory {
+    pub address: H160,
+    pub creation_block: u64,
+}
+
+impl KyberElasticFactory {
+    pub fn new(address: H160, creation_block: u64) -> KyberElasticFactory {
+        KyberElasticFactory {
+            address,
+            creation_block,
+        }
+    }
+
+    /// Gets every pool created up to `to_block`, unpopulated, scanning `step` blocks at a time
+    pub async fn get_all_pools_from_logs<M: 'static + Middleware>(
+        self,
+        to_block: u64,
+        step: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let mut from_block = self.creation_block;
+        let mut aggregated_amms: Vec<AMM> = vec![];
+
+        tracing::info!(from_block, to_block, step, "getting all pools from logs");
+
+        let mut handles = vec![];
+
+        while from_block <= to_block {
+            let middleware = middleware.clone();
+            let target_block = (from_block + step - 1).min(to_block);
+
+            let filter = Filter::new()
+                .topic0(POOL_CREATED_EVENT_SIGNATURE)
+                .address(self.address)
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            handles.push(tokio::spawn(async move {
+                let logs = middleware
+                    .get_logs(&filter)
+                    .await
+                    .map_err(AMMError::MiddlewareError)?;
+
+                Ok::<Vec<Log>, AMMError<M>>(logs)
+            }));
+
+            from_block += step;
+
+            //Here we are limiting the number of green threads that can be spun up to not have the node time out
+            if handles.len() == TASK_LIMIT {
+                self.decode_logs_from_handles(handles, &mut aggregated_amms)
+                    .await?;
+                handles = vec![];
+            }
+        }
+
+        self.decode_logs_from_handles(handles, &mut aggregated_amms)
+            .await?;
+
+        Ok(aggregated_amms)
+    }
+
+    async fn decode_logs_from_handles<M: Middleware>(
+        &self,
+        handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
+        amms: &mut Vec<AMM>,
+    ) -> Result<(), AMMError<M>> {
+        for handle in handles {
+            for log in handle.await?? {
+                amms.push(self.new_empty_amm_from_log(&log)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the pool created by a `PoolCreated` log, with the fee in units of 0.1 bps and the tick distance it was
+/// created with
+pub fn decode_pool_created_log(log: &Log) -> Result<KyberElasticPool, EventLogError> {
+    if decode::event_signature(log)? != POOL_CREATED_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    let fee = decode::topic_uint(log, 3, 24)?;
+    let tick_distance = decode::data_int(log, 0, 24)?.low_i32();
+    if fee >= U256::from(FEE_UNITS) || tick_distance <= 0 {
+        return Err(EventLogError::MalformedLog(log.into()));
+    }
+
+    Ok(KyberElasticPool::new(
+        decode::data_address(log, 1)?,
+        decode::topic_address(log, 1)?,
+        0,
+        decode::topic_address(log, 2)?,
+        0,
+        fee.as_u32(),
+        tick_distance,
+        U256::zero(),
+        0,
+    ))
+}
+
+#[async_trait]
+impl AutomatedMarketMakerFactory for KyberElasticFactory {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn creation_block(&self) -> u64 {
+        self.creation_block
+    }
+
+    fn amm_created_event_signature(&self) -> H256 {
+        POOL_CREATED_EVENT_SIGNATURE
+    }
+
+    async fn new_amm_from_log<M: 'static + Middleware>(
+        &self,
+        log: Log,
+        middleware: Arc<M>,
+    ) -> Result<AMM, AMMError<M>> {
+        let mut amm = self.new_empty_amm_from_log(&log)?;
+        let block_number = log.block_number.map(|block_number| block_number.as_u64());
+        amm.populate_data(block_number, middleware).await?;
+
+        Ok(amm)
+    }
+
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        decode::check_address(log, self.address)?;
+        let mut pool = decode_pool_created_log(log)?;
+        pool.creation_block = log.block_number.map(|block_number| block_number.as_u64());
+
+        Ok(AMM::KyberElasticPool(pool))
+    }
+
+    async fn get_all_amms<M: 'static + Middleware>(
+        &self,
+        to_block: Option<u64>,
+        middleware: Arc<M>,
+        step: u64,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        match to_block {
+            Some(block) => self.get_all_pools_from_logs(block, step, middleware).await,
+            None => Err(AMMError::BlockNumberNotFound),
+        }
+    }
+
+    async fn populate_amm_data<M: Middleware>(
+        &self,
+        amms: &mut [AMM],
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+        _step: u64,
+    ) -> Result<(), AMMError<M>> {
+        //Multicall3 chunks its calls itself
+        batch_request::get_amm_data_batch_request(amms, block_number, middleware).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, H256, I256, U64},
+    };
+
+    use crate::{
+        amm::{factory::AutomatedMarketMakerFactory, AmmState, AMM},
+        errors::EventLogError,
+    };
+
+    use super::{KyberElasticFactory, POOL_CREATED_EVENT_SIGNATURE};
+
+    fn pool_created_log(factory: &KyberElasticFactory, fee: u32) -> Log {
+        Log {
+            address: factory.address,
+            topics: vec![
+                POOL_CREATED_EVENT_SIGNATURE,
+                H160::from_low_u64_be(1).into(),
+                H160::from_low_u64_be(2).into(),
+                H256::from_low_u64_be(fee as u64),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(8).into_raw()),
+                Token::Address(H160::from_low_u64_be(10)),
+            ])
+            .into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_new_empty_amm_from_log() -> eyre::Result<()> {
+        let factory = KyberElasticFactory::new(H160::from_low_u64_be(100), 0);
+
+        match factory.new_empty_amm_from_log(&pool_created_log(&factory, 40))? {
+            AMM::KyberElasticPool(pool) => {
+                assert_eq!(pool.address, H160::from_low_u64_be(10));
+                assert_eq!(
+                    pool.tokens(),
+                    vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)]
+                );
+                assert_eq!((pool.fee, pool.tick_distance), (40, 8));
+                assert_eq!(pool.creation_block, Some(10));
+                assert!(!pool.data_is_populated());
+            }
+            _ => panic!("Expected a new KyberSwap Elastic pool"),
+        }
+
+        //Fees of 100% or more are not fees
+        assert!(matches!(
+            factory.new_empty_amm_from_log(&pool_created_log(&factory, 100_000)),
+            Err(EventLogError::MalformedLog(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,988 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+#[cfg(feature = "rpc")]
+pub mod factory;
+
+use std::collections::BTreeMap;
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::types::{Log, H160, H256, I256, U256};
+use serde::{Deserialize, Serialize};
+use uniswap_v3_math::tick_math::{get_sqrt_ratio_at_tick, get_tick_at_sqrt_ratio};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{
+        self,
+        compat::SerdeVersion,
+        decode,
+        uniswap_v3::{self, MAX_SQRT_RATIO, MIN_SQRT_RATIO},
+        AmmState,
+    },
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{
+        fixed_point::{price_x128_to_f64, sqrt_price_x96_to_price_x128},
+        mul_div, mul_div_rounding_up,
+    },
+    tokens::TokenStore,
+};
+
+//Swap(address indexed sender, address indexed recipient, int256 deltaQty0, int256 deltaQty1, uint160 sqrtP, uint128 liquidity, int24 currentTick)
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    196, 32, 121, 249, 74, 99, 80, 215, 230, 35, 95, 41, 23, 73, 36, 249, 40, 204, 42, 200, 24,
+    235, 100, 254, 216, 0, 78, 17, 95, 188, 202, 103,
+]);
+//Mint(address sender, address indexed owner, int24 indexed tickLower, int24 indexed tickUpper, uint128 qty, uint256 qty0, uint256 qty1)
+pub const MINT_EVENT_SIGNATURE: H256 = H256([
+    122, 83, 8, 11, 164, 20, 21, 139, 231, 236, 105, 185, 135, 181, 251, 125, 7, 222, 225, 1, 254,
+    133, 72, 143, 8, 83, 174, 22, 35, 157, 11, 222,
+]);
+//Burn(address indexed owner, int24 indexed tickLower, int24 indexed tickUpper, uint128 qty, uint256 qty0, uint256 qty1),
+//which has the parameter types of the Burn event of Uniswap V3
+pub const BURN_EVENT_SIGNATURE: H256 = uniswap_v3::BURN_EVENT_SIGNATURE;
+
+/// Denominator of the swap fee, i.e. a fee of 10 units is 1 bps
+pub const FEE_UNITS: u32 = 100_000;
+/// Ticks a single step of a swap moves at most, so the fee of each step stays close to its exact value
+pub const MAX_TICK_DISTANCE: i32 = 480;
+pub const MIN_TICK: i32 = -887272;
+pub const MAX_TICK: i32 = 887272;
+
+const TWO_FEE_UNITS: U256 = U256([200_000, 0, 0, 0]);
+const Q96: U256 = U256([0, 1 << 32, 0, 0]);
+
+/// Initialized tick of a KyberSwap Elastic pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KyberTick {
+    pub liquidity_gross: u128,
+    /// Liquidity added to the base liquidity when the price crosses the tick upwards
+    pub liquidity_net: i128,
+}
+
+/// KyberSwap Elastic pool.
+///
+/// Positions provide concentrated liquidity between ticks as in Uniswap V3 pools, and the fees of the swaps are
+/// compounded into a reinvestment liquidity that is in range at every price. Swaps trade against the sum of both,
+/// growing the reinvestment liquidity by the fee of each step rather than paying the fee aside.
+///
+/// Only the initialized ticks within `tick_range` are known, swaps needing any tick beyond them fail with
+/// `SwapSimulationError::TicksExhausted`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KyberElasticPool {
+    pub address: H160,
+    pub token_a: H160,
+    pub token_a_decimals: u8,
+    pub token_b: H160,
+    pub token_b_decimals: u8,
+    /// Swap fee over `FEE_UNITS`
+    pub fee: u32,
+    pub tick_distance: i32,
+    pub sqrt_price: U256,
+    pub tick: i32,
+    /// Liquidity of the positions in range of the current tick
+    pub base_liquidity: u128,
+    /// Liquidity compounded from the fees
+    pub reinvest_liquidity: u128,
+    /// Initialized ticks between the ticks of `tick_range`
+    pub ticks: BTreeMap<i32, KyberTick>,
+    /// Lowest and highest initialized ticks that were read, `MIN_TICK` and `MAX_TICK` once every tick on that side
+    /// of the price was read
+    pub tick_range: (i32, i32),
+    pub creation_block: Option<u64>,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+//Outcome of a swap, applied by `simulate_swap_mut`
+struct SwapState {
+    amount_out: U256,
+    sqrt_price: U256,
+    tick: i32,
+    base_liquidity: u128,
+    reinvest_liquidity: u128,
+}
+
+impl AmmState for KyberElasticPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        vec![self.token_a, self.token_b]
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Price of a whole `base_token` in whole units of the other token at the current sqrt price, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let price = price_x128_to_f64(sqrt_price_x96_to_price_x128(
+            self.sqrt_price,
+            self.token_a_decimals,
+            self.token_b_decimals,
+        )?);
+        let price = if base_token == self.token_a {
+            price
+        } else {
+            1.0 / price
+        };
+
+        if !price.is_finite() || price == 0.0 {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![
+            SWAP_EVENT_SIGNATURE,
+            MINT_EVENT_SIGNATURE,
+            BURN_EVENT_SIGNATURE,
+        ]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+
+        match decode::event_signature(log)? {
+            SWAP_EVENT_SIGNATURE => self.sync_from_swap_log(log),
+            MINT_EVENT_SIGNATURE => self.sync_from_position_log(log, true),
+            BURN_EVENT_SIGNATURE => self.sync_from_position_log(log, false),
+            _ => Err(EventLogError::UnexpectedEvent(log.into())),
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        let mut words = vec![
+            U256::from(self.fee),
+            I256::from(self.tick_distance).into_raw(),
+            self.sqrt_price,
+            I256::from(self.tick).into_raw(),
+            U256::from(self.base_liquidity),
+            U256::from(self.reinvest_liquidity),
+            I256::from(self.tick_range.0).into_raw(),
+            I256::from(self.tick_range.1).into_raw(),
+        ];
+        words.extend(self.ticks.iter().flat_map(|(tick, info)| {
+            [
+                I256::from(*tick).into_raw(),
+                U256::from(info.liquidity_gross),
+                I256::from(info.liquidity_net).into_raw(),
+            ]
+        }));
+
+        amm::state_fingerprint(Protocol::KyberElastic.name(), &words)
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        Ok(self.swap(token_in, amount_in)?.amount_out)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let swap = self.swap(token_in, amount_in)?;
+
+        self.sqrt_price = swap.sqrt_price;
+        self.tick = swap.tick;
+        self.base_liquidity = swap.base_liquidity;
+        self.reinvest_liquidity = swap.reinvest_liquidity;
+
+        Ok(swap.amount_out)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token_a == token_in {
+            self.token_b
+        } else {
+            self.token_a
+        }
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if token == self.token_a {
+            Some(self.token_b)
+        } else if token == self.token_b {
+            Some(self.token_a)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for KyberElasticPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_kyber_elastic_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_kyber_elastic_pool_data_batch_request(self, block_number, middleware)
+            .await
+    }
+}
+
+impl KyberElasticPool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: H160,
+        token_a: H160,
+        token_a_decimals: u8,
+        token_b: H160,
+        token_b_decimals: u8,
+        fee: u32,
+        tick_distance: i32,
+        sqrt_price: U256,
+        tick: i32,
+    ) -> KyberElasticPool {
+        KyberElasticPool {
+            address,
+            token_a,
+            token_a_decimals,
+            token_b,
+            token_b_decimals,
+            fee,
+            tick_distance,
+            sqrt_price,
+            tick,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.token_a.is_zero()
+            || self.token_b.is_zero()
+            || self.tick_distance == 0
+            || self.sqrt_price.is_zero())
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.token_a {
+            Some(self.token_a_decimals)
+        } else if token == self.token_b {
+            Some(self.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of both tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Liquidity the swaps trade against at the current price, the base and reinvestment liquidity together
+    pub fn liquidity(&self) -> U256 {
+        U256::from(self.base_liquidity) + U256::from(self.reinvest_liquidity)
+    }
+
+    //Walks the initialized ticks from the current tick as `swap` of the pools for an exact amount in, towards lower
+    //ticks when token a is sold. Swaps reaching the price limit of the pools are partially filled.
+    fn swap(&self, token_in: H160, amount_in: U256) -> Result<SwapState, SwapSimulationError> {
+        let token_a_in = if token_in == self.token_a {
+            true
+        } else if token_in == self.token_b {
+            false
+        } else {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        };
+        let up = !token_a_in;
+        let sqrt_price_limit = if up {
+            MAX_SQRT_RATIO - 1
+        } else {
+            MIN_SQRT_RATIO + 1
+        };
+
+        let mut swap = SwapState {
+            amount_out: U256::zero(),
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            base_liquidity: self.base_liquidity,
+            reinvest_liquidity: self.reinvest_liquidity,
+        };
+        let mut amount_in_left = amount_in;
+        let mut returned_amount = I256::zero();
+        let mut next_tick = self.next_initialized_tick(self.tick, up);
+
+        while !amount_in_left.is_zero() && swap.sqrt_price != sqrt_price_limit {
+            let next_tick_initialized = next_tick.ok_or(SwapSimulationError::TicksExhausted)?;
+            let temp_next_tick = if up {
+                next_tick_initialized.min(swap.tick.saturating_add(MAX_TICK_DISTANCE))
+            } else {
+                next_tick_initialized.max(swap.tick.saturating_sub(MAX_TICK_DISTANCE))
+            };
+
+            let start_sqrt_price = swap.sqrt_price;
+            let next_sqrt_price = get_sqrt_ratio_at_tick(temp_next_tick)?;
+            let target_sqrt_price = if up == (next_sqrt_price > sqrt_price_limit) {
+                sqrt_price_limit
+            } else {
+                next_sqrt_price
+            };
+
+            let liquidity = U256::from(swap.base_liquidity) + U256::from(swap.reinvest_liquidity);
+            let (used_amount, step_returned_amount, delta_liquidity, sqrt_price) =
+                compute_swap_step(
+                    liquidity,
+                    swap.sqrt_price,
+                    target_sqrt_price,
+                    self.fee,
+                    amount_in_left,
+                    token_a_in,
+                )?;
+            swap.sqrt_price = sqrt_price;
+            amount_in_left -= used_amount;
+            returned_amount += step_returned_amount;
+            let reinvest_liquidity = U256::from(swap.reinvest_liquidity) + delta_liquidity;
+            if reinvest_liquidity > U256::from(u128::MAX) {
+                return Err(ArithmeticError::U128ConversionError.into());
+            }
+            swap.reinvest_liquidity = reinvest_liquidity.as_u128();
+
+            //The swap ends within the step
+            if swap.sqrt_price != next_sqrt_price {
+                if swap.sqrt_price != start_sqrt_price {
+                    swap.tick = get_tick_at_sqrt_ratio(swap.sqrt_price)?;
+                }
+                break;
+            }
+
+            swap.tick = if up {
+                temp_next_tick
+            } else {
+                temp_next_tick - 1
+            };
+            if temp_next_tick != next_tick_initialized {
+                continue;
+            }
+
+            //Crossing the tick adds its net liquidity going up and removes it going down
+            let liquidity_net = self
+                .ticks
+                .get(&next_tick_initialized)
+                .map(|tick| tick.liquidity_net)
+                .unwrap_or_default();
+            let liquidity_net = if up { liquidity_net } else { -liquidity_net };
+            swap.base_liquidity = if liquidity_net >= 0 {
+                swap.base_liquidity
+                    .checked_add(liquidity_net.unsigned_abs())
+                    .ok_or(ArithmeticError::U128ConversionError)?
+            } else {
+                swap.base_liquidity
+                    .checked_sub(liquidity_net.unsigned_abs())
+                    .ok_or(SwapSimulationError::LiquidityUnderflow)?
+            };
+            next_tick = self.next_initialized_tick(
+                if up {
+                    next_tick_initialized
+                } else {
+                    next_tick_initialized - 1
+                },
+                up,
+            );
+        }
+
+        //Amounts paid out by the pool are negative
+        swap.amount_out = if returned_amount.is_negative() {
+            returned_amount.unsigned_abs()
+        } else {
+            U256::zero()
+        };
+
+        Ok(swap)
+    }
+
+    //Next initialized tick a swap from `tick` crosses, above it going up and at or below it going down. The ticks
+    //at MIN_TICK and MAX_TICK are always initialized, so None means the ticks that were read have run out.
+    fn next_initialized_tick(&self, tick: i32, up: bool) -> Option<i32> {
+        if up {
+            self.ticks.range(tick.saturating_add(1)..).next()
+        } else {
+            self.ticks.range(..=tick).next_back()
+        }
+        .map(|(tick, _)| *tick)
+    }
+
+    //Swaps are simulated again with the logged amount in to compound their fees into the reinvestment liquidity,
+    //then leave the price, tick and base liquidity where the pool says. Exact output swaps compound slightly
+    //different fees, which the refresh policy of the pools corrects.
+    fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let amount_a = decode::data_int(log, 0, 256)?;
+        let amount_b = decode::data_int(log, 1, 256)?;
+        let sqrt_price = decode::data_uint(log, 2, 160)?;
+        let base_liquidity = decode::data_uint(log, 3, 128)?.as_u128();
+        let tick = decode::data_int(log, 4, 24)?.low_i32();
+        if sqrt_price < MIN_SQRT_RATIO || sqrt_price >= MAX_SQRT_RATIO {
+            return Err(EventLogError::MalformedLog(log.into()));
+        }
+
+        let amount_in = if amount_a.is_positive() {
+            Some((self.token_a, amount_a.into_raw()))
+        } else if amount_b.is_positive() {
+            Some((self.token_b, amount_b.into_raw()))
+        } else {
+            None
+        };
+        //Swaps needing ticks that were not read keep the reinvestment liquidity as it was
+        if let Some(swap) =
+            amount_in.and_then(|(token_in, amount_in)| self.swap(token_in, amount_in).ok())
+        {
+            self.reinvest_liquidity = swap.reinvest_liquidity;
+        }
+
+        self.sqrt_price = sqrt_price;
+        self.tick = tick;
+        self.base_liquidity = base_liquidity;
+
+        Ok(())
+    }
+
+    //Positions update the ticks at both of their ends that fall within the ticks that were read, and the base
+    //liquidity when they are in range
+    fn sync_from_position_log(&mut self, log: &Log, mint: bool) -> Result<(), EventLogError> {
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+
+        let tick_lower = decode::topic_int(log, 2, 24)?.low_i32();
+        let tick_upper = decode::topic_int(log, 3, 24)?.low_i32();
+        let quantity = decode::data_uint(log, if mint { 1 } else { 0 }, 128)?.as_u128();
+        let quantity_net = i128::try_from(quantity).map_err(|_| malformed_log())?;
+        if tick_lower >= tick_upper || tick_lower < MIN_TICK || tick_upper > MAX_TICK {
+            return Err(malformed_log());
+        }
+
+        //Both ticks and the base liquidity are checked before any is updated, so a bad log leaves the pool untouched
+        let mut ticks = vec![];
+        for (tick, liquidity_net) in [(tick_lower, quantity_net), (tick_upper, -quantity_net)] {
+            if tick < self.tick_range.0 || tick > self.tick_range.1 {
+                continue;
+            }
+
+            let info = self.ticks.get(&tick).copied().unwrap_or_default();
+            let updated = if mint {
+                info.liquidity_gross
+                    .checked_add(quantity)
+                    .zip(info.liquidity_net.checked_add(liquidity_net))
+            } else {
+                info.liquidity_gross
+                    .checked_sub(quantity)
+                    .zip(info.liquidity_net.checked_sub(liquidity_net))
+            };
+            match updated {
+                Some((liquidity_gross, liquidity_net)) => ticks.push((
+                    tick,
+                    KyberTick {
+                        liquidity_gross,
+                        liquidity_net,
+                    },
+                )),
+                None => return Err(malformed_log()),
+            }
+        }
+
+        let base_liquidity = if self.tick >= tick_lower && self.tick < tick_upper {
+            if mint {
+                self.base_liquidity.checked_add(quantity)
+            } else {
+                self.base_liquidity.checked_sub(quantity)
+            }
+            .ok_or_else(malformed_log)?
+        } else {
+            self.base_liquidity
+        };
+
+        for (tick, info) in ticks {
+            //The ticks at the ends of the price range stay initialized
+            if info.liquidity_gross == 0 && tick != MIN_TICK && tick != MAX_TICK {
+                self.ticks.remove(&tick);
+            } else {
+                self.ticks.insert(tick, info);
+            }
+        }
+        self.base_liquidity = base_liquidity;
+
+        Ok(())
+    }
+}
+
+//Single step of a swap of an exact amount in between the current sqrt price and `target_sqrt_price`, returning the
+//amount in used, the signed amount out, the liquidity the fee compounds into and the sqrt price reached
+fn compute_swap_step(
+    liquidity: U256,
+    sqrt_price: U256,
+    target_sqrt_price: U256,
+    fee: u32,
+    amount_in: U256,
+    token_a_in: bool,
+) -> Result<(U256, I256, U256, U256), ArithmeticError> {
+    if sqrt_price == target_sqrt_price {
+        return Ok((U256::zero(), I256::zero(), U256::zero(), sqrt_price));
+    }
+
+    let fee = U256::from(fee);
+    let reach_amount =
+        calc_reach_amount(liquidity, sqrt_price, target_sqrt_price, fee, token_a_in)?;
+    let (used_amount, delta_liquidity, next_sqrt_price) = if reach_amount > amount_in {
+        let delta_liquidity =
+            estimate_incremental_liquidity(amount_in, sqrt_price, fee, token_a_in)?;
+        let next_sqrt_price = calc_final_price(
+            amount_in,
+            liquidity,
+            delta_liquidity,
+            sqrt_price,
+            token_a_in,
+        )?;
+        (amount_in, delta_liquidity, next_sqrt_price)
+    } else {
+        let delta_liquidity = calc_incremental_liquidity(
+            reach_amount,
+            liquidity,
+            sqrt_price,
+            target_sqrt_price,
+            token_a_in,
+        )?;
+        (reach_amount, delta_liquidity, target_sqrt_price)
+    };
+
+    let mut returned_amount = calc_returned_amount(
+        liquidity,
+        sqrt_price,
+        next_sqrt_price,
+        delta_liquidity,
+        token_a_in,
+    )?;
+    //Rounding can leave the pool asking for a single unit it does not need
+    if returned_amount == I256::one() {
+        returned_amount = I256::zero();
+    }
+
+    Ok((
+        used_amount,
+        returned_amount,
+        delta_liquidity,
+        next_sqrt_price,
+    ))
+}
+
+//Amount in, fee included, that moves the sqrt price to `target_sqrt_price`, rounded down
+fn calc_reach_amount(
+    liquidity: U256,
+    sqrt_price: U256,
+    target_sqrt_price: U256,
+    fee: U256,
+    token_a_in: bool,
+) -> Result<U256, ArithmeticError> {
+    let price_diff = if sqrt_price >= target_sqrt_price {
+        sqrt_price - target_sqrt_price
+    } else {
+        target_sqrt_price - sqrt_price
+    };
+
+    if token_a_in {
+        let denominator = checked_sub(TWO_FEE_UNITS * target_sqrt_price, fee * sqrt_price)?;
+        let numerator = mul_div(liquidity, TWO_FEE_UNITS * price_diff, denominator)?;
+        mul_div(numerator, Q96, sqrt_price)
+    } else {
+        let denominator = checked_sub(TWO_FEE_UNITS * sqrt_price, fee * target_sqrt_price)?;
+        let numerator = mul_div(liquidity, TWO_FEE_UNITS * price_diff, denominator)?;
+        mul_div(numerator, sqrt_price, Q96)
+    }
+}
+
+//Liquidity the fee of `amount_in` compounds into when the step ends before the target price
+fn estimate_incremental_liquidity(
+    amount_in: U256,
+    sqrt_price: U256,
+    fee: U256,
+    token_a_in: bool,
+) -> Result<U256, ArithmeticError> {
+    let fee_amount = amount_in
+        .checked_mul(fee)
+        .ok_or(ArithmeticError::MulDivOverflow)?;
+
+    if token_a_in {
+        mul_div(sqrt_price, fee_amount, TWO_FEE_UNITS << 96)
+    } else {
+        mul_div(Q96, fee_amount, TWO_FEE_UNITS * sqrt_price)
+    }
+}
+
+//Liquidity the fee of `amount_in` compounds into when the step reaches `next_sqrt_price`
+fn calc_incremental_liquidity(
+    amount_in: U256,
+    liquidity: U256,
+    sqrt_price: U256,
+    next_sqrt_price: U256,
+    token_a_in: bool,
+) -> Result<U256, ArithmeticError> {
+    let liquidity_after = if token_a_in {
+        let amount = mul_div(liquidity, Q96, sqrt_price)? + amount_in;
+        mul_div(next_sqrt_price, amount, Q96)?
+    } else {
+        let amount = mul_div(liquidity, sqrt_price, Q96)? + amount_in;
+        mul_div(amount, Q96, next_sqrt_price)?
+    };
+
+    Ok(liquidity_after.saturating_sub(liquidity))
+}
+
+//Sqrt price reached by a step using `amount_in` and compounding `delta_liquidity`
+fn calc_final_price(
+    amount_in: U256,
+    liquidity: U256,
+    delta_liquidity: U256,
+    sqrt_price: U256,
+    token_a_in: bool,
+) -> Result<U256, ArithmeticError> {
+    if token_a_in {
+        let amount = mul_div(amount_in, sqrt_price, Q96)?;
+        mul_div_rounding_up(liquidity + delta_liquidity, sqrt_price, liquidity + amount)
+    } else {
+        let amount = mul_div(amount_in, Q96, sqrt_price)?;
+        mul_div(liquidity + amount, sqrt_price, liquidity + delta_liquidity)
+    }
+}
+
+//Signed amount of the token out of a step, negative when the pool pays out, rounded toward the pool
+fn calc_returned_amount(
+    liquidity: U256,
+    sqrt_price: U256,
+    next_sqrt_price: U256,
+    delta_liquidity: U256,
+    token_a_in: bool,
+) -> Result<I256, ArithmeticError> {
+    let (paid_in, paid_out) = if token_a_in {
+        (
+            mul_div_rounding_up(delta_liquidity, next_sqrt_price, Q96)?,
+            mul_div(liquidity, checked_sub(sqrt_price, next_sqrt_price)?, Q96)?,
+        )
+    } else {
+        (
+            mul_div_rounding_up(liquidity + delta_liquidity, Q96, next_sqrt_price)?,
+            mul_div(liquidity, Q96, sqrt_price)?,
+        )
+    };
+
+    Ok(I256::from_raw(paid_in) - I256::from_raw(paid_out))
+}
+
+fn checked_sub(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_sub(b)
+        .ok_or(ArithmeticError::SubtractionUnderflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        abi::Token,
+        providers::{Http, Middleware, Provider},
+        types::{Filter, Log, H160, H256, I256, U256},
+        utils::keccak256,
+    };
+
+    use crate::{
+        amm::{AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{KyberElasticPool, KyberTick, MAX_TICK, MIN_TICK, Q96, SWAP_EVENT_SIGNATURE};
+
+    const ETHER: u128 = 1_000_000_000_000_000_000;
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    //Pool at tick 0 with a fee of 4 bps, a million units of liquidity between ticks -80 and 80 and ten million
+    //between ticks -800 and 800
+    fn pool(tick_range: (i32, i32)) -> KyberElasticPool {
+        let (narrow, wide) = (1_000_000 * ETHER, 10_000_000 * ETHER);
+        let mut pool = KyberElasticPool::new(
+            H160::from_low_u64_be(10),
+            token(1),
+            18,
+            token(2),
+            18,
+            40,
+            8,
+            Q96,
+            0,
+        );
+        pool.base_liquidity = narrow + wide;
+        pool.reinvest_liquidity = 5 * ETHER;
+        pool.tick_range = tick_range;
+
+        for (tick, liquidity_net) in [
+            (MIN_TICK, 0),
+            (-800, wide as i128),
+            (-80, narrow as i128),
+            (80, -(narrow as i128)),
+            (800, -(wide as i128)),
+            (MAX_TICK, 0),
+        ] {
+            if tick >= tick_range.0 && tick <= tick_range.1 {
+                pool.ticks.insert(
+                    tick,
+                    KyberTick {
+                        liquidity_gross: liquidity_net.unsigned_abs(),
+                        liquidity_net,
+                    },
+                );
+            }
+        }
+
+        pool
+    }
+
+    fn position_log(pool: &KyberElasticPool, mint: bool, ticks: (i32, i32), quantity: u128) -> Log {
+        let quantity = Token::Uint(U256::from(quantity));
+        let amounts = [Token::Uint(U256::one()), Token::Uint(U256::one())];
+
+        Log {
+            address: pool.address,
+            topics: vec![
+                //Hashed from the signatures of the pool rather than taken from the constants under test
+                if mint {
+                    H256(keccak256(
+                        "Mint(address,address,int24,int24,uint128,uint256,uint256)",
+                    ))
+                } else {
+                    H256(keccak256(
+                        "Burn(address,int24,int24,uint128,uint256,uint256)",
+                    ))
+                },
+                H160::from_low_u64_be(7).into(),
+                I256::from(ticks.0).into_raw().into(),
+                I256::from(ticks.1).into_raw().into(),
+            ],
+            data: if mint {
+                ethers::abi::encode(
+                    &[
+                        vec![Token::Address(H160::from_low_u64_be(8)), quantity],
+                        amounts.to_vec(),
+                    ]
+                    .concat(),
+                )
+            } else {
+                ethers::abi::encode(&[vec![quantity], amounts.to_vec()].concat())
+            }
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let mut pool = pool((MIN_TICK, MAX_TICK));
+        assert_eq!(pool.calculate_price(token(1))?, 1.0);
+
+        pool.sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(6932)?;
+        pool.token_b_decimals = 6;
+        let price = pool.calculate_price(token(1))?;
+        assert!((price / 2e12 - 1.0).abs() < 1e-4);
+        assert!((pool.calculate_price(token(2))? * price - 1.0).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let pool = pool((MIN_TICK, MAX_TICK));
+
+        //Small swaps stay within the current tick and pay the fee of 4 bps
+        assert_eq!(
+            pool.simulate_swap(token(1), U256::from(ETHER))?,
+            U256::from(999_599_909_127_318_656_u128)
+        );
+        assert_eq!(
+            pool.simulate_swap(token(2), U256::from(ETHER))?,
+            U256::from(999_599_909_127_318_657_u128)
+        );
+
+        //The fee compounds into the reinvestment liquidity
+        let mut swapped = pool.clone();
+        let amount_out = swapped.simulate_swap_mut(token(2), U256::from(10_000 * ETHER))?;
+        assert_eq!(amount_out, U256::from(9_986_920_984_686_201_323_250_u128));
+        assert_eq!(swapped.tick, 18);
+        assert_eq!(swapped.base_liquidity, pool.base_liquidity);
+        assert_eq!(swapped.reinvest_liquidity, 7 * ETHER);
+
+        //Larger swaps cross every tick, steps of 480 ticks at a time, down to the reinvestment liquidity alone
+        let mut swapped = pool.clone();
+        let amount_out = swapped.simulate_swap_mut(token(1), U256::from(1_000_000 * ETHER))?;
+        assert_eq!(amount_out, U256::from(396_083_192_628_429_129_015_434_u128));
+        assert_eq!((swapped.tick, swapped.base_liquidity), (-176_444, 0));
+        assert_eq!(swapped.reinvest_liquidity, 86_710_818_493_979_064_736);
+
+        //Swaps needing ticks that were not read fail rather than make up liquidity
+        let partial = self::pool((-800, 800));
+        assert_eq!(
+            partial.simulate_swap(token(1), U256::from(10_000 * ETHER))?,
+            U256::from(9_986_920_984_686_201_323_250_u128)
+        );
+        assert!(matches!(
+            partial.simulate_swap(token(1), U256::from(1_000_000 * ETHER)),
+            Err(SwapSimulationError::TicksExhausted)
+        ));
+        assert!(matches!(
+            pool.simulate_swap(token(3), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = pool((-800, 800));
+        let mut amm = AMM::KyberElasticPool(pool.clone());
+        let base_liquidity = pool.base_liquidity;
+
+        //Positions in range add to the base liquidity, ticks beyond the ticks that were read are left out
+        let log = position_log(&pool, true, (-40, 1600), 1000);
+        pool.sync_from_log(&log)?;
+        amm.sync_from_log(&log)?;
+        assert_eq!(pool.base_liquidity, base_liquidity + 1000);
+        assert_eq!(
+            pool.ticks[&-40],
+            KyberTick {
+                liquidity_gross: 1000,
+                liquidity_net: 1000
+            }
+        );
+        assert!(!pool.ticks.contains_key(&1600));
+        assert!(AMM::KyberElasticPool(pool.clone()).state_eq(&amm));
+
+        //Burning a whole position uninitializes its ticks, burning more than a tick holds is rejected
+        pool.sync_from_log(&position_log(&pool, false, (-40, 1600), 1000))?;
+        assert_eq!(pool.base_liquidity, base_liquidity);
+        assert!(!pool.ticks.contains_key(&-40));
+        let ticks = pool.ticks.clone();
+        assert!(matches!(
+            pool.sync_from_log(&position_log(&pool, false, (-80, 80), 2_000_000 * ETHER)),
+            Err(EventLogError::MalformedLog(_))
+        ));
+        assert_eq!(pool.ticks, ticks);
+
+        //Swaps are simulated again for the fee, and leave the price, tick and base liquidity where the log says
+        let mut expected = pool.clone();
+        expected.simulate_swap_mut(token(2), U256::from(10_000 * ETHER))?;
+        let swap_log = Log {
+            address: pool.address,
+            topics: vec![
+                SWAP_EVENT_SIGNATURE,
+                H160::from_low_u64_be(7).into(),
+                H160::from_low_u64_be(8).into(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(-9_986_920_984_686_201_323_250_i128).into_raw()),
+                Token::Int(U256::from(10_000 * ETHER)),
+                Token::Uint(expected.sqrt_price),
+                Token::Uint(U256::from(expected.base_liquidity)),
+                Token::Int(I256::from(expected.tick).into_raw()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&swap_log)?;
+        assert_eq!(
+            (pool.sqrt_price, pool.tick, pool.reinvest_liquidity),
+            (
+                expected.sqrt_price,
+                expected.tick,
+                expected.reinvest_liquidity
+            )
+        );
+        assert_eq!(pool.ticks, expected.ticks);
+
+        let mut foreign_log = swap_log;
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_populate_data() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //The first pool created by the KyberSwap Elastic factory on mainnet
+        let logs = middleware
+            .get_logs(
+                &Filter::new()
+                    .address("0x5F1dddbf348aC2fbe22a163e30F99F9ECE3DD50a".parse::<H160>()?)
+                    .topic0(super::factory::POOL_CREATED_EVENT_SIGNATURE)
+                    .from_block(16200000)
+                    .to_block(16300000),
+            )
+            .await?;
+        let mut pool = super::factory::decode_pool_created_log(&logs[0])?;
+        super::batch_request::get_kyber_elastic_pool_data_batch_request(
+            &mut pool,
+            Some(16300000),
+            middleware,
+        )
+        .await?;
+
+        assert!(pool.data_is_populated());
+        assert!(pool.ticks.range(..=pool.tick).next_back().is_some());
+        assert!(pool.ticks.range(pool.tick + 1..).next().is_some());
+
+        //Small swaps are paid out close to the price less the fee
+        let amount_in = U256::exp10(pool.token_a_decimals as usize) / 1000;
+        let amount_out = pool.simulate_swap(pool.token_a, amount_in)?;
+        let expected = pool.calculate_price(pool.token_a)?
+            * (1.0 - pool.fee as f64 / super::FEE_UNITS as f64)
+            * 10_f64.powi(pool.token_b_decimals as i32)
+            / 1000.0;
+        assert!((amount_out.as_u128() as f64 / expected - 1.0).abs() < 1e-3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{LiquidityBookPool, StaticFeeParameters, VariableFeeParameters, MAX_BIN_ID};
+
+/// Bins read on each side of the active bin when populating a pool
+pub const BIN_RADIUS: u32 = 100;
+
+//getTokenX()
+const GET_TOKEN_X_SELECTOR: [u8; 4] = [5, 232, 116, 109];
+//getTokenY()
+const GET_TOKEN_Y_SELECTOR: [u8; 4] = [218, 16, 97, 12];
+//getBinStep()
+const GET_BIN_STEP_SELECTOR: [u8; 4] = [23, 241, 30, 204];
+//getActiveId()
+const GET_ACTIVE_ID_SELECTOR: [u8; 4] = [219, 230, 94, 220];
+//getStaticFeeParameters()
+const GET_STATIC_FEE_PARAMETERS_SELECTOR: [u8; 4] = [124, 160, 222, 48];
+//getVariableFeeParameters()
+const GET_VARIABLE_FEE_PARAMETERS_SELECTOR: [u8; 4] = [141, 112, 36, 229];
+//getBin(uint24)
+const GET_BIN_SELECTOR: [u8; 4] = [10, 190, 150, 136];
+//getNextNonEmptyBin(bool,uint24)
+const GET_NEXT_NON_EMPTY_BIN_SELECTOR: [u8; 4] = [164, 26, 1, 251];
+
+const PAIR_SELECTORS: [[u8; 4]; 6] = [
+    GET_TOKEN_X_SELECTOR,
+    GET_TOKEN_Y_SELECTOR,
+    GET_BIN_STEP_SELECTOR,
+    GET_ACTIVE_ID_SELECTOR,
+    GET_STATIC_FEE_PARAMETERS_SELECTOR,
+    GET_VARIABLE_FEE_PARAMETERS_SELECTOR,
+];
+
+/// Reads `pool` and the bins within `BIN_RADIUS` of its active bin at `block_number`, erroring with
+/// `AMMError::BatchRequestError` if it is not a Liquidity Book pair
+pub async fn get_liquidity_book_pool_data_batch_request<M: Middleware>(
+    pool: &mut LiquidityBookPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Liquidity Book pool of `amms` at `block_number` through Multicall3, leaving the pools that could not
+/// be read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::LiquidityBookPool(lb_pool) = amm {
+            if let Some(pool) = populate_pool_data(lb_pool.to_owned(), pool_data) {
+                *lb_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    tokens: Option<(H160, H160)>,
+    bin_step: Option<u16>,
+    active_id: Option<u32>,
+    static_fee_parameters: Option<StaticFeeParameters>,
+    variable_fee_parameters: Option<VariableFeeParameters>,
+    decimals: (Option<u8>, Option<u8>),
+    bins: Option<(BTreeMap<u32, (u128, u128)>, (u32, u32))>,
+}
+
+fn populate_pool_data(
+    mut pool: LiquidityBookPool,
+    pool_data: PoolData,
+) -> Option<LiquidityBookPool> {
+    (pool.token_a, pool.token_b) = pool_data.tokens?;
+    (pool.token_a_decimals, pool.token_b_decimals) = (pool_data.decimals.0?, pool_data.decimals.1?);
+    pool.bin_step = pool_data.bin_step?;
+    pool.active_id = pool_data.active_id?;
+    pool.static_fee_parameters = pool_data.static_fee_parameters?;
+    pool.variable_fee_parameters = pool_data.variable_fee_parameters?;
+    (pool.bins, pool.bin_range) = pool_data.bins?;
+
+    Some(pool)
+}
+
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| PAIR_SELECTORS.map(|selector| multicall::call(*pool, selector, &[])))
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(PAIR_SELECTORS.len())
+        .map(|pool_return_data| PoolData {
+            tokens: address(&pool_return_data[0]).zip(address(&pool_return_data[1])),
+            bin_step: word(&pool_return_data[2], 0)
+                .filter(|bin_step| !bin_step.is_zero() && *bin_step <= U256::from(u16::MAX))
+                .map(|bin_step| bin_step.as_u32() as u16),
+            active_id: word(&pool_return_data[3], 0)
+                .filter(|active_id| *active_id <= U256::from(MAX_BIN_ID))
+                .map(|active_id| active_id.as_u32()),
+            static_fee_parameters: static_fee_parameters(&pool_return_data[4]),
+            variable_fee_parameters: variable_fee_parameters(&pool_return_data[5]),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pool_data
+        .iter()
+        .filter_map(|pool_data| pool_data.tokens)
+        .flat_map(|(token_x, token_y)| [token_x, token_y])
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware.clone()).await?;
+    for pool_data in pool_data.iter_mut() {
+        if let Some((token_x, token_y)) = pool_data.tokens {
+            pool_data.decimals = (
+                decimals.get(&token_x).copied(),
+                decimals.get(&token_y).copied(),
+            );
+        }
+    }
+
+    //The bins around the active bin of each pair, then whether any bin holds reserves past them
+    let bin_ranges = pool_data
+        .iter()
+        .map(|pool_data| {
+            pool_data.active_id.map(|active_id| {
+                (
+                    active_id.saturating_sub(BIN_RADIUS),
+                    (active_id + BIN_RADIUS).min(MAX_BIN_ID),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    let calls = pools
+        .iter()
+        .zip(&bin_ranges)
+        .filter_map(|(pool, bin_range)| Some((*pool, (*bin_range)?)))
+        .flat_map(|(pool, (low, high))| {
+            (low..=high)
+                .map(move |id| {
+                    multicall::call(
+                        pool,
+                        GET_BIN_SELECTOR,
+                        &ethers::abi::encode(&[Token::Uint(U256::from(id))]),
+                    )
+                })
+                .chain([(true, low), (false, high)].map(|(swap_for_y, id)| {
+                    multicall::call(
+                        pool,
+                        GET_NEXT_NON_EMPTY_BIN_SELECTOR,
+                        &ethers::abi::encode(&[
+                            Token::Bool(swap_for_y),
+                            Token::Uint(U256::from(id)),
+                        ]),
+                    )
+                }))
+        })
+        .collect();
+    let mut return_data = multicall::aggregate(calls, block_number, middleware)
+        .await?
+        .into_iter();
+
+    for (pool_data, bin_range) in pool_data.iter_mut().zip(bin_ranges) {
+        let (low, high) = match bin_range {
+            Some(bin_range) => bin_range,
+            None => continue,
+        };
+        let bin_return_data = return_data
+            .by_ref()
+            .take((high - low + 3) as usize)
+            .collect::<Vec<_>>();
+        pool_data.bins = bins_from_multicall(low, high, &bin_return_data);
+    }
+
+    Ok(pool_data)
+}
+
+//Non empty bins of `low..=high` and the range of ids they cover, which reaches the end of the ids on the sides where
+//no bin holds reserves past them
+#[allow(clippy::type_complexity)]
+fn bins_from_multicall(
+    low: u32,
+    high: u32,
+    return_data: &[Option<Bytes>],
+) -> Option<(BTreeMap<u32, (u128, u128)>, (u32, u32))> {
+    if return_data.len() != (high - low + 3) as usize {
+        return None;
+    }
+    let (bin_return_data, next_return_data) = return_data.split_at(return_data.len() - 2);
+
+    let mut bins = BTreeMap::new();
+    for (id, return_data) in (low..=high).zip(bin_return_data) {
+        let reserve_x = word(return_data, 0).filter(|reserve| *reserve <= U256::from(u128::MAX))?;
+        let reserve_y = word(return_data, 1).filter(|reserve| *reserve <= U256::from(u128::MAX))?;
+        if !(reserve_x.is_zero() && reserve_y.is_zero()) {
+            bins.insert(id, (reserve_x.as_u128(), reserve_y.as_u128()));
+        }
+    }
+
+    //The pairs answer 0 or the largest id when there is no next non empty bin
+    let is_last = |return_data: &Option<Bytes>| {
+        word(return_data, 0).map(|next_id| next_id.is_zero() || next_id >= U256::from(MAX_BIN_ID))
+    };
+    let low = if is_last(&next_return_data[0])? {
+        0
+    } else {
+        low
+    };
+    let high = if is_last(&next_return_data[1])? {
+        MAX_BIN_ID
+    } else {
+        high
+    };
+
+    Some((bins, (low, high)))
+}
+
+//uint16 baseFactor, uint16 filterPeriod, uint16 decayPeriod, uint16 reductionFactor, uint24 variableFeeControl,
+//uint16 protocolShare, uint24 maxVolatilityAccumulator
+fn static_fee_parameters(return_data: &Option<Bytes>) -> Option<StaticFeeParameters> {
+    let words = (0..7)
+        .map(|i| word(return_data, i).filter(|word| *word <= U256::from(u32::MAX)))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(StaticFeeParameters {
+        base_factor: words[0].as_u32() as u16,
+        filter_period: words[1].as_u32() as u16,
+        decay_period: words[2].as_u32() as u16,
+        reduction_factor: words[3].as_u32() as u16,
+        variable_fee_control: words[4].as_u32(),
+        protocol_share: words[5].as_u32() as u16,
+        max_volatility_accumulator: words[6].as_u32(),
+    })
+}
+
+//uint24 volatilityAccumulator, uint24 volatilityReference, uint24 idReference, uint40 timeOfLastUpdate
+fn variable_fee_parameters(return_data: &Option<Bytes>) -> Option<VariableFeeParameters> {
+    let words = (0..4)
+        .map(|i| word(return_data, i).filter(|word| *word <= U256::from(u64::MAX)))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(VariableFeeParameters {
+        volatility_accumulator: words[0].low_u32(),
+        volatility_reference: words[1].low_u32(),
+        id_reference: words[2].low_u32(),
+        time_of_last_update: words[3].as_u64(),
+    })
+}
+
+fn word(return_data: &Option<Bytes>, index: usize) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, index)
+}
+
+fn address(return_data: &Option<Bytes>) -> Option<H160> {
+    multicall::address_word(return_data.as_ref()?, 0).filter(|address| !address.is_zero())
+}
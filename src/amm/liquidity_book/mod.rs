@@ -0,0 +1,1043 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+
+use std::collections::BTreeMap;
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::{
+    abi::{ParamType, Token},
+    prelude::abigen,
+    types::{Log, H160, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{fixed_point::price_x128_to_f64, mul_div, mul_div_rounding_up, mul_shift_right},
+    tokens::TokenStore,
+};
+
+abigen!(
+    ILBPair,
+    r#"[
+        function getSwapOut(uint128 amountIn, bool swapForY) external view returns (uint128 amountInLeft, uint128 amountOut, uint128 fee)
+    ]"#;
+);
+
+//Swap(address indexed sender, address indexed to, uint24 id, bytes32 amountsIn, bytes32 amountsOut, uint24 volatilityAccumulator, bytes32 totalFees, bytes32 protocolFees)
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    173, 125, 111, 151, 171, 245, 28, 225, 142, 23, 163, 143, 77, 112, 233, 117, 190, 156, 7, 8,
+    71, 73, 135, 187, 62, 38, 173, 33, 189, 147, 202, 112,
+]);
+//DepositedToBins(address indexed sender, address indexed to, uint256[] ids, bytes32[] amounts)
+pub const DEPOSITED_TO_BINS_EVENT_SIGNATURE: H256 = H256([
+    135, 241, 249, 220, 245, 232, 8, 154, 62, 0, 129, 27, 106, 0, 141, 143, 48, 41, 58, 61, 168,
+    120, 203, 31, 232, 201, 12, 163, 118, 64, 47, 138,
+]);
+//WithdrawnFromBins(address indexed sender, address indexed to, uint256[] ids, bytes32[] amounts)
+pub const WITHDRAWN_FROM_BINS_EVENT_SIGNATURE: H256 = H256([
+    163, 46, 20, 104, 68, 214, 20, 74, 34, 233, 76, 88, 103, 21, 161, 49, 125, 88, 168, 170, 53,
+    129, 236, 51, 208, 64, 17, 61, 220, 178, 67, 80,
+]);
+
+/// Id of the bin priced at one, the middle of the uint24 ids
+pub const REAL_ID_SHIFT: u32 = 1 << 23;
+/// Largest bin id
+pub const MAX_BIN_ID: u32 = (1 << 24) - 1;
+/// Denominator of the bin step and of the shares of the fee parameters, i.e. a bin step of 25 is 0.25%
+pub const BASIS_POINT_MAX: u32 = 10_000;
+/// Denominator of fees, i.e. a fee of 1e15 is 0.1%
+pub const PRECISION: u128 = 1_000_000_000_000_000_000;
+
+/// Fee parameters of a pair set by its factory, as returned by `getStaticFeeParameters`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticFeeParameters {
+    pub base_factor: u16,
+    pub filter_period: u16,
+    pub decay_period: u16,
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub protocol_share: u16,
+    pub max_volatility_accumulator: u32,
+}
+
+/// Volatility tracked by a pair for its variable fee, as returned by `getVariableFeeParameters`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VariableFeeParameters {
+    pub volatility_accumulator: u32,
+    pub volatility_reference: u32,
+    pub id_reference: u32,
+    pub time_of_last_update: u64,
+}
+
+impl VariableFeeParameters {
+    //`updateReferences` of the pairs, run once at the start of every swap
+    fn update_references(
+        &mut self,
+        active_id: u32,
+        static_fee_parameters: &StaticFeeParameters,
+        timestamp: u64,
+    ) {
+        let dt = timestamp.saturating_sub(self.time_of_last_update);
+
+        if dt >= static_fee_parameters.filter_period as u64 {
+            self.id_reference = active_id;
+            self.volatility_reference = if dt < static_fee_parameters.decay_period as u64 {
+                (self.volatility_accumulator as u64 * static_fee_parameters.reduction_factor as u64
+                    / BASIS_POINT_MAX as u64) as u32
+            } else {
+                0
+            };
+        }
+
+        self.time_of_last_update = timestamp;
+    }
+
+    //`updateVolatilityAccumulator` of the pairs, run for every bin a swap trades in
+    fn update_volatility_accumulator(
+        &mut self,
+        id: u32,
+        static_fee_parameters: &StaticFeeParameters,
+    ) {
+        let volatility_accumulator = self.volatility_reference as u64
+            + id.abs_diff(self.id_reference) as u64 * BASIS_POINT_MAX as u64;
+
+        self.volatility_accumulator = volatility_accumulator
+            .min(static_fee_parameters.max_volatility_accumulator as u64)
+            as u32;
+    }
+}
+
+/// Trader Joe v2.1 Liquidity Book pair.
+///
+/// Liquidity sits in bins of constant price `(1 + bin_step / 10_000)^(id - 2^23)`, in token b per token a, and swaps
+/// empty the bins one after the other from the active bin, paying a base fee plus a variable fee that grows with the
+/// number of bins crossed since the volatility references were last reset. Only the bins within `bin_range` are
+/// read, so swaps walking out of it fail rather than skip liquidity the pool does not know.
+///
+/// The volatility references and the time of the last update are not in the logs of the pairs, so pools are read
+/// again periodically to keep the variable fee accurate.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LiquidityBookPool {
+    pub address: H160,
+    /// Token X of the pair
+    pub token_a: H160,
+    pub token_a_decimals: u8,
+    /// Token Y of the pair
+    pub token_b: H160,
+    pub token_b_decimals: u8,
+    /// Price step between consecutive bins over `BASIS_POINT_MAX`
+    pub bin_step: u16,
+    pub active_id: u32,
+    /// Reserves of token a and token b of the non empty bins within `bin_range`, by id
+    pub bins: BTreeMap<u32, (u128, u128)>,
+    /// Lowest and highest ids of the bins read, the reserves of the bins outside of it are unknown
+    pub bin_range: (u32, u32),
+    pub static_fee_parameters: StaticFeeParameters,
+    pub variable_fee_parameters: VariableFeeParameters,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+//Outcome of a swap, applied by `simulate_swap_mut`
+struct SwapState {
+    amount_out: u128,
+    active_id: u32,
+    variable_fee_parameters: VariableFeeParameters,
+    //New reserves of every bin traded in
+    bins: Vec<(u32, (u128, u128))>,
+}
+
+impl AmmState for LiquidityBookPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        vec![self.token_a, self.token_b]
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Price of a whole `base_token` in whole units of the other token in the active bin, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let price = price_x128_to_f64(get_price_from_id(self.active_id, self.bin_step)?)
+            * 10_f64.powi(self.token_a_decimals as i32 - self.token_b_decimals as i32);
+        let price = if base_token == self.token_a {
+            price
+        } else {
+            1.0 / price
+        };
+
+        if !price.is_finite() || price == 0.0 {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![
+            SWAP_EVENT_SIGNATURE,
+            DEPOSITED_TO_BINS_EVENT_SIGNATURE,
+            WITHDRAWN_FROM_BINS_EVENT_SIGNATURE,
+        ]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+
+        match decode::event_signature(log)? {
+            SWAP_EVENT_SIGNATURE => self.sync_from_swap_log(log),
+            DEPOSITED_TO_BINS_EVENT_SIGNATURE => self.sync_from_bins_log(log, true),
+            WITHDRAWN_FROM_BINS_EVENT_SIGNATURE => self.sync_from_bins_log(log, false),
+            _ => Err(EventLogError::UnexpectedEvent(log.into())),
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        let mut words = vec![
+            U256::from(self.bin_step),
+            U256::from(self.active_id),
+            U256::from(self.bin_range.0),
+            U256::from(self.bin_range.1),
+            U256::from(self.variable_fee_parameters.volatility_accumulator),
+            U256::from(self.variable_fee_parameters.volatility_reference),
+            U256::from(self.variable_fee_parameters.id_reference),
+            U256::from(self.variable_fee_parameters.time_of_last_update),
+        ];
+        words.extend(self.bins.iter().flat_map(|(id, (reserve_x, reserve_y))| {
+            [
+                U256::from(*id),
+                U256::from(*reserve_x),
+                U256::from(*reserve_y),
+            ]
+        }));
+
+        amm::state_fingerprint(Protocol::LiquidityBook.name(), &words)
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        self.simulate_swap_at(
+            token_in,
+            amount_in,
+            self.variable_fee_parameters.time_of_last_update,
+        )
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let swap = self.swap(
+            token_in,
+            amount_in,
+            self.variable_fee_parameters.time_of_last_update,
+        )?;
+
+        for (id, reserves) in swap.bins {
+            self.set_bin(id, reserves);
+        }
+        self.active_id = swap.active_id;
+        self.variable_fee_parameters = swap.variable_fee_parameters;
+
+        Ok(U256::from(swap.amount_out))
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token_a == token_in {
+            self.token_b
+        } else {
+            self.token_a
+        }
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if token == self.token_a {
+            Some(self.token_b)
+        } else if token == self.token_b {
+            Some(self.token_a)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for LiquidityBookPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_liquidity_book_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_liquidity_book_pool_data_batch_request(self, block_number, middleware)
+            .await
+    }
+}
+
+impl LiquidityBookPool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: H160,
+        token_a: H160,
+        token_a_decimals: u8,
+        token_b: H160,
+        token_b_decimals: u8,
+        bin_step: u16,
+        active_id: u32,
+        static_fee_parameters: StaticFeeParameters,
+    ) -> LiquidityBookPool {
+        LiquidityBookPool {
+            address,
+            token_a,
+            token_a_decimals,
+            token_b,
+            token_b_decimals,
+            bin_step,
+            active_id,
+            bin_range: (active_id, active_id),
+            static_fee_parameters,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.token_a.is_zero() || self.token_b.is_zero() || self.bin_step == 0)
+            && self
+                .bins
+                .values()
+                .any(|(reserve_x, reserve_y)| *reserve_x != 0 || *reserve_y != 0)
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.token_a {
+            Some(self.token_a_decimals)
+        } else if token == self.token_b {
+            Some(self.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of both tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Reserves of token a and token b summed over the bins read
+    pub fn total_reserves(&self) -> (U256, U256) {
+        self.bins.values().fold(
+            (U256::zero(), U256::zero()),
+            |(total_x, total_y), (reserve_x, reserve_y)| {
+                (total_x + *reserve_x, total_y + *reserve_y)
+            },
+        )
+    }
+
+    /// Base fee of the pair over `PRECISION`
+    pub fn base_fee(&self) -> u128 {
+        self.static_fee_parameters.base_factor as u128 * self.bin_step as u128 * 10_000_000_000
+    }
+
+    /// Variable fee of the pair over `PRECISION` at `volatility_accumulator`, rounded up as in the pairs
+    pub fn variable_fee(&self, volatility_accumulator: u32) -> u128 {
+        let variable_fee_control = self.static_fee_parameters.variable_fee_control as u128;
+        if variable_fee_control == 0 {
+            return 0;
+        }
+
+        let product = volatility_accumulator as u128 * self.bin_step as u128;
+        (product * product * variable_fee_control + 99) / 100
+    }
+
+    /// Fee of the next swap in the active bin over `PRECISION`
+    pub fn total_fee(&self) -> u128 {
+        self.base_fee() + self.variable_fee(self.variable_fee_parameters.volatility_accumulator)
+    }
+
+    /// Amount of the other token paid out for `amount_in` of `token_in` in a swap at `timestamp`, which decays the
+    /// volatility references of the variable fee as the pair would in a block of that timestamp
+    pub fn simulate_swap_at(
+        &self,
+        token_in: H160,
+        amount_in: U256,
+        timestamp: u64,
+    ) -> Result<U256, SwapSimulationError> {
+        Ok(U256::from(
+            self.swap(token_in, amount_in, timestamp)?.amount_out,
+        ))
+    }
+
+    //Walks the bins from the active bin as `swap` of the pairs, towards lower ids when token a is sold
+    fn swap(
+        &self,
+        token_in: H160,
+        amount_in: U256,
+        timestamp: u64,
+    ) -> Result<SwapState, SwapSimulationError> {
+        self.check_token_in(token_in)?;
+        if amount_in > U256::from(u128::MAX) {
+            return Err(ArithmeticError::U128ConversionError.into());
+        }
+
+        let swap_for_y = token_in == self.token_a;
+        let mut amount_in_left = amount_in.as_u128();
+        let mut swap = SwapState {
+            amount_out: 0,
+            active_id: self.active_id,
+            variable_fee_parameters: self.variable_fee_parameters,
+            bins: vec![],
+        };
+        swap.variable_fee_parameters.update_references(
+            self.active_id,
+            &self.static_fee_parameters,
+            timestamp,
+        );
+
+        let mut id = self.active_id;
+        while amount_in_left != 0 {
+            if !self.bin_is_known(id) {
+                return Err(SwapSimulationError::BinsExhausted);
+            }
+
+            let (reserve_x, reserve_y) = self.bins.get(&id).copied().unwrap_or_default();
+            let reserve_out = if swap_for_y { reserve_y } else { reserve_x };
+
+            if reserve_out != 0 {
+                swap.variable_fee_parameters
+                    .update_volatility_accumulator(id, &self.static_fee_parameters);
+                let fee = self.variable_fee(swap.variable_fee_parameters.volatility_accumulator)
+                    + self.base_fee();
+
+                let (amount_in_with_fees, amount_out, fee_amount) = get_amounts(
+                    reserve_out,
+                    swap_for_y,
+                    id,
+                    self.bin_step,
+                    fee,
+                    amount_in_left,
+                )?;
+
+                if amount_in_with_fees != 0 {
+                    amount_in_left -= amount_in_with_fees;
+                    swap.amount_out = swap
+                        .amount_out
+                        .checked_add(amount_out)
+                        .ok_or(ArithmeticError::U128ConversionError)?;
+
+                    //The protocol share of the fee is set aside by the pair, the rest stays in the bin
+                    let protocol_fee = U256::from(fee_amount)
+                        * self.static_fee_parameters.protocol_share
+                        / BASIS_POINT_MAX;
+                    let amount_in_to_bin = amount_in_with_fees - protocol_fee.as_u128();
+                    let reserves = if swap_for_y {
+                        (
+                            reserve_x.checked_add(amount_in_to_bin),
+                            Some(reserve_y - amount_out),
+                        )
+                    } else {
+                        (
+                            Some(reserve_x - amount_out),
+                            reserve_y.checked_add(amount_in_to_bin),
+                        )
+                    };
+                    match reserves {
+                        (Some(reserve_x), Some(reserve_y)) => {
+                            swap.bins.push((id, (reserve_x, reserve_y)))
+                        }
+                        _ => return Err(ArithmeticError::U128ConversionError.into()),
+                    }
+                }
+            }
+
+            swap.active_id = id;
+            if amount_in_left != 0 {
+                id = self
+                    .next_non_empty_bin(swap_for_y, id)
+                    .ok_or(SwapSimulationError::BinsExhausted)?;
+            }
+        }
+
+        Ok(swap)
+    }
+
+    //Next bin holding reserves past `id`, below it when `swap_for_y`, as long as every bin in between was read
+    fn next_non_empty_bin(&self, swap_for_y: bool, id: u32) -> Option<u32> {
+        let next = if swap_for_y {
+            self.bins.range(..id).next_back()
+        } else {
+            self.bins.range(id + 1..).next()
+        };
+
+        next.map(|(next, _)| *next)
+            .filter(|next| self.bin_is_known(id) && self.bin_is_known(*next))
+    }
+
+    fn bin_is_known(&self, id: u32) -> bool {
+        self.bin_range.0 <= id && id <= self.bin_range.1
+    }
+
+    fn set_bin(&mut self, id: u32, reserves: (u128, u128)) {
+        if reserves == (0, 0) {
+            self.bins.remove(&id);
+        } else {
+            self.bins.insert(id, reserves);
+        }
+    }
+
+    fn check_token_in(&self, token_in: H160) -> Result<(), SwapSimulationError> {
+        if token_in == self.token_a || token_in == self.token_b {
+            Ok(())
+        } else {
+            Err(SwapSimulationError::TokenNotInPool(token_in))
+        }
+    }
+
+    //Swaps leave the active id on the last bin traded in, and the bin with the amounts in less the protocol fees
+    //and without the amounts out
+    fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let id = decode::data_uint(log, 0, 24)?.as_u32();
+        let (amount_in_x, amount_in_y) = decode_amounts(decode::data_uint(log, 1, 256)?);
+        let (amount_out_x, amount_out_y) = decode_amounts(decode::data_uint(log, 2, 256)?);
+        let volatility_accumulator = decode::data_uint(log, 3, 24)?.as_u32();
+        let (protocol_fee_x, protocol_fee_y) = decode_amounts(decode::data_uint(log, 5, 256)?);
+
+        if self.bin_is_known(id) {
+            let (reserve_x, reserve_y) = self.bins.get(&id).copied().unwrap_or_default();
+            let reserves = (
+                reserve_x
+                    .checked_add(amount_in_x)
+                    .and_then(|reserve_x| reserve_x.checked_sub(protocol_fee_x))
+                    .and_then(|reserve_x| reserve_x.checked_sub(amount_out_x)),
+                reserve_y
+                    .checked_add(amount_in_y)
+                    .and_then(|reserve_y| reserve_y.checked_sub(protocol_fee_y))
+                    .and_then(|reserve_y| reserve_y.checked_sub(amount_out_y)),
+            );
+            match reserves {
+                (Some(reserve_x), Some(reserve_y)) => self.set_bin(id, (reserve_x, reserve_y)),
+                _ => return Err(EventLogError::MalformedLog(log.into())),
+            }
+        }
+
+        self.active_id = id;
+        self.variable_fee_parameters.volatility_accumulator = volatility_accumulator;
+
+        Ok(())
+    }
+
+    //Mints and burns list the amounts added to or removed from each bin, only the bins within `bin_range` are kept
+    fn sync_from_bins_log(&mut self, log: &Log, deposit: bool) -> Result<(), EventLogError> {
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+
+        let tokens = ethers::abi::decode(
+            &[
+                ParamType::Array(Box::new(ParamType::Uint(256))),
+                ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+            ],
+            &log.data,
+        )?;
+        let (ids, amounts) = match tokens.as_slice() {
+            [Token::Array(ids), Token::Array(amounts)] if ids.len() == amounts.len() => {
+                (ids, amounts)
+            }
+            _ => return Err(malformed_log()),
+        };
+
+        //Every bin is checked before any is updated, so a bad log leaves the pool untouched
+        let mut bins = vec![];
+        for (id, amounts) in ids.iter().zip(amounts) {
+            let (id, amounts) = match (id, amounts) {
+                (Token::Uint(id), Token::FixedBytes(amounts)) if *id <= U256::from(MAX_BIN_ID) => {
+                    (id.as_u32(), decode_amounts(U256::from_big_endian(amounts)))
+                }
+                _ => return Err(malformed_log()),
+            };
+            if !self.bin_is_known(id) {
+                continue;
+            }
+
+            let (reserve_x, reserve_y) = bins
+                .iter()
+                .rev()
+                .find(|(bin_id, _)| *bin_id == id)
+                .map(|(_, reserves)| *reserves)
+                .or_else(|| self.bins.get(&id).copied())
+                .unwrap_or_default();
+            let reserves = if deposit {
+                (
+                    reserve_x.checked_add(amounts.0),
+                    reserve_y.checked_add(amounts.1),
+                )
+            } else {
+                (
+                    reserve_x.checked_sub(amounts.0),
+                    reserve_y.checked_sub(amounts.1),
+                )
+            };
+            match reserves {
+                (Some(reserve_x), Some(reserve_y)) => bins.push((id, (reserve_x, reserve_y))),
+                _ => return Err(malformed_log()),
+            }
+        }
+
+        for (id, reserves) in bins {
+            self.set_bin(id, reserves);
+        }
+
+        Ok(())
+    }
+}
+
+/// Price of bin `id` in token Y per token X as a Q128.128, `(1 + bin_step / 10_000)^(id - 2^23)` rounded as
+/// `getPriceFromId` of the pairs
+pub fn get_price_from_id(id: u32, bin_step: u16) -> Result<U256, ArithmeticError> {
+    let base = (U256::one() << 128) + (U256::from(bin_step) << 128) / U256::from(BASIS_POINT_MAX);
+
+    pow(base, id as i64 - REAL_ID_SHIFT as i64)
+}
+
+//`Uint128x128Math.pow`, raising a Q128.128 by squaring over the 20 bits of the exponent
+fn pow(x: U256, y: i64) -> Result<U256, ArithmeticError> {
+    if y == 0 {
+        return Ok(U256::one() << 128);
+    }
+
+    let abs_y = y.unsigned_abs();
+    if abs_y >= 0x100000 {
+        return Err(ArithmeticError::MulDivOverflow);
+    }
+
+    let mut invert = y < 0;
+    let mut result = U256::one() << 128;
+    //Bases above one are inverted first so the squares stay below one, then the result is inverted back
+    let mut squared = x;
+    if x > U256::from(u128::MAX) {
+        squared = U256::MAX / squared;
+        invert = !invert;
+    }
+
+    for bit in 0..20 {
+        if abs_y & (1 << bit) != 0 {
+            result = mul_shift_right(result, squared, 128)?;
+        }
+        squared = mul_shift_right(squared, squared, 128)?;
+    }
+
+    if result.is_zero() {
+        return Err(ArithmeticError::MulDivOverflow);
+    }
+
+    Ok(if invert { U256::MAX / result } else { result })
+}
+
+/// Amount in including the fee, amount out and fee of a swap of up to `amount_in` in bin `id` holding `reserve_out`
+/// of the token out, rounded as `getAmounts` of the pairs. `fee` is over `PRECISION`.
+pub fn get_amounts(
+    reserve_out: u128,
+    swap_for_y: bool,
+    id: u32,
+    bin_step: u16,
+    fee: u128,
+    amount_in: u128,
+) -> Result<(u128, u128, u128), ArithmeticError> {
+    let price = get_price_from_id(id, bin_step)?;
+    let scale = U256::one() << 128;
+
+    let max_amount_in = if swap_for_y {
+        mul_div_rounding_up(U256::from(reserve_out), scale, price)?
+    } else {
+        mul_div_rounding_up(U256::from(reserve_out), price, scale)?
+    };
+    if max_amount_in > U256::from(u128::MAX) {
+        return Err(ArithmeticError::U128ConversionError);
+    }
+    let max_fee = get_fee_amount(max_amount_in.as_u128(), fee);
+    let max_amount_in = max_amount_in
+        .as_u128()
+        .checked_add(max_fee)
+        .ok_or(ArithmeticError::U128ConversionError)?;
+
+    if amount_in >= max_amount_in {
+        return Ok((max_amount_in, reserve_out, max_fee));
+    }
+
+    let fee_amount = get_fee_amount_from(amount_in, fee);
+    let amount_in_without_fee = U256::from(amount_in - fee_amount);
+    let amount_out = if swap_for_y {
+        mul_shift_right(amount_in_without_fee, price, 128)?
+    } else {
+        mul_div(amount_in_without_fee, scale, price)?
+    };
+
+    Ok((
+        amount_in,
+        amount_out.min(U256::from(reserve_out)).as_u128(),
+        fee_amount,
+    ))
+}
+
+//Fee to add to `amount` so that it is `amount` once the fee is taken, rounded up
+fn get_fee_amount(amount: u128, fee: u128) -> u128 {
+    let denominator = U256::from(PRECISION - fee);
+    ((U256::from(amount) * fee + denominator - U256::one()) / denominator).as_u128()
+}
+
+//Fee taken from `amount_with_fees`, rounded up
+fn get_fee_amount_from(amount_with_fees: u128, fee: u128) -> u128 {
+    ((U256::from(amount_with_fees) * fee + PRECISION - 1) / PRECISION).as_u128()
+}
+
+//Amounts of token X and token Y packed in a bytes32, token X in the low 128 bits
+fn decode_amounts(amounts: U256) -> (u128, u128) {
+    (amounts.low_u128(), (amounts >> 128).low_u128())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        abi::Token,
+        providers::{Http, Middleware, Provider},
+        types::{Log, H160, U256},
+    };
+
+    use crate::{
+        amm::{AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+        math::fixed_point::price_x128_to_f64,
+    };
+
+    use super::{
+        get_price_from_id, ILBPair, LiquidityBookPool, StaticFeeParameters,
+        DEPOSITED_TO_BINS_EVENT_SIGNATURE, REAL_ID_SHIFT, SWAP_EVENT_SIGNATURE,
+        WITHDRAWN_FROM_BINS_EVENT_SIGNATURE,
+    };
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    fn amounts(x: u128, y: u128) -> [u8; 32] {
+        let mut amounts = [0; 32];
+        ((U256::from(y) << 128) | U256::from(x)).to_big_endian(&mut amounts);
+        amounts
+    }
+
+    //WAVAX/USDC with a bin step of 20 around 20 USDC per AVAX, 20,000 USDC in each of the 10 bins below the active
+    //bin, 1000 AVAX in each of the 10 above it and both in the active bin
+    fn avax_usdc() -> LiquidityBookPool {
+        //1.002^1500 is close to 20 and the decimals bring the raw price down by 1e12
+        let active_id = REAL_ID_SHIFT + 1500 - 13_829;
+        let mut pool = LiquidityBookPool::new(
+            H160::from_low_u64_be(10),
+            token(1),
+            18,
+            token(2),
+            6,
+            20,
+            active_id,
+            StaticFeeParameters {
+                base_factor: 5000,
+                filter_period: 30,
+                decay_period: 600,
+                reduction_factor: 5000,
+                variable_fee_control: 40_000,
+                protocol_share: 1000,
+                max_volatility_accumulator: 350_000,
+            },
+        );
+        pool.variable_fee_parameters.id_reference = active_id;
+        pool.bin_range = (active_id - 10, active_id + 10);
+        for i in 1..=10 {
+            pool.bins
+                .insert(active_id - i, (0, 20_000 * 10_u128.pow(6)));
+            pool.bins.insert(active_id + i, (1000 * 10_u128.pow(18), 0));
+        }
+        pool.bins
+            .insert(active_id, (500 * 10_u128.pow(18), 10_000 * 10_u128.pow(6)));
+
+        pool
+    }
+
+    fn bins_log(pool: &LiquidityBookPool, deposit: bool, bins: &[(u32, u128, u128)]) -> Log {
+        Log {
+            address: pool.address,
+            topics: vec![
+                if deposit {
+                    DEPOSITED_TO_BINS_EVENT_SIGNATURE
+                } else {
+                    WITHDRAWN_FROM_BINS_EVENT_SIGNATURE
+                },
+                H160::from_low_u64_be(7).into(),
+                H160::from_low_u64_be(8).into(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Array(
+                    bins.iter()
+                        .map(|(id, _, _)| Token::Uint(U256::from(*id)))
+                        .collect(),
+                ),
+                Token::Array(
+                    bins.iter()
+                        .map(|(_, x, y)| Token::FixedBytes(amounts(*x, *y).to_vec()))
+                        .collect(),
+                ),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_get_price_from_id() -> eyre::Result<()> {
+        let one = U256::one() << 128;
+        assert_eq!(get_price_from_id(REAL_ID_SHIFT, 25)?, one);
+        assert_eq!(
+            get_price_from_id(REAL_ID_SHIFT + 1, 25)?,
+            one + (U256::from(25) << 128) / 10_000
+        );
+
+        //The price follows (1 + bin_step / 10_000)^(id - 2^23) on both sides of the middle id
+        for (offset, bin_step) in [(1000_i64, 1_u16), (-1000, 1), (5000, 20), (-13_829, 20)] {
+            let id = (REAL_ID_SHIFT as i64 + offset) as u32;
+            let price = price_x128_to_f64(get_price_from_id(id, bin_step)?);
+            let expected = (1.0 + bin_step as f64 / 10_000.0).powi(offset as i32);
+            assert!((price / expected - 1.0).abs() < 1e-12);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let pool = avax_usdc();
+        let expected = 1.002_f64.powi(1500 - 13_829) * 1e12;
+
+        assert!((pool.calculate_price(token(1))? / expected - 1.0).abs() < 1e-9);
+        assert!((pool.calculate_price(token(2))? * expected - 1.0).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let pool = avax_usdc();
+
+        //A small swap stays in the active bin and only pays the base fee of 0.1%
+        let amount_out = pool.simulate_swap(token(1), U256::exp10(18))?;
+        let expected = 0.999 * pool.calculate_price(token(1))? * 1e6;
+        assert!((amount_out.as_u128() as f64 / expected - 1.0).abs() < 1e-6);
+        assert_eq!(pool.total_fee(), 1_000_000_000_000_000);
+
+        //Larger swaps cross bins at lower prices and pay a growing variable fee
+        let amount_in = U256::exp10(21) * 2;
+        let amount_out = pool.simulate_swap(token(1), amount_in)?;
+        let rate = amount_out.as_u128() as f64 / 1e6 / 2000.0;
+        assert!(rate < 0.999 * pool.calculate_price(token(1))?);
+        assert!(amount_out.as_u128() > 30_000 * 10_u128.pow(6));
+
+        let mut swapped = pool.clone();
+        assert_eq!(swapped.simulate_swap_mut(token(1), amount_in)?, amount_out);
+        assert!(swapped.active_id < pool.active_id);
+        assert!(swapped.variable_fee_parameters.volatility_accumulator > 0);
+        assert!(swapped.total_fee() > pool.total_fee());
+        //Every bin crossed has been emptied of token b, and the bin the swap stopped in still holds some
+        for id in swapped.active_id + 1..=pool.active_id {
+            assert_eq!(swapped.bins[&id].1, 0);
+        }
+        assert!(swapped.bins[&swapped.active_id].1 > 0);
+
+        //Swaps back pay more of token b than the first swap paid out for the same amount of token a
+        assert!(swapped.simulate_swap(token(2), amount_out)? < amount_in);
+
+        //Swaps walking out of the bins read fail rather than skip liquidity
+        assert!(matches!(
+            pool.simulate_swap(token(2), U256::exp10(12)),
+            Err(SwapSimulationError::BinsExhausted)
+        ));
+        assert!(matches!(
+            pool.simulate_swap(token(3), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_fee_decay() -> eyre::Result<()> {
+        let mut pool = avax_usdc();
+        pool.simulate_swap_mut(token(1), U256::exp10(21) * 2)?;
+        let time_of_last_update = pool.variable_fee_parameters.time_of_last_update;
+
+        //Within the filter period the bins already crossed still count, past the decay period they are forgotten
+        let amount_in = U256::exp10(20);
+        let quote = pool.simulate_swap_at(token(1), amount_in, time_of_last_update + 10)?;
+        let decayed = pool.simulate_swap_at(token(1), amount_in, time_of_last_update + 1000)?;
+        assert!(decayed > quote);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = avax_usdc();
+        let mut amm = AMM::LiquidityBookPool(pool.clone());
+        let id = pool.active_id;
+
+        //Deposits and withdrawals only touch the bins within the range read
+        let log = bins_log(&pool, true, &[(id, 5, 7), (id + 100, 1, 1)]);
+        pool.sync_from_log(&log)?;
+        amm.sync_from_log(&log)?;
+        assert_eq!(
+            pool.bins[&id],
+            (500 * 10_u128.pow(18) + 5, 10_000 * 10_u128.pow(6) + 7)
+        );
+        assert!(!pool.bins.contains_key(&(id + 100)));
+        assert!(AMM::LiquidityBookPool(pool.clone()).state_eq(&amm));
+
+        pool.sync_from_log(&bins_log(
+            &pool,
+            false,
+            &[(id + 1, 1000 * 10_u128.pow(18), 0)],
+        ))?;
+        assert!(!pool.bins.contains_key(&(id + 1)));
+
+        //Withdrawing more than a bin holds is rejected without touching any bin
+        let before = pool.bins.clone();
+        assert!(matches!(
+            pool.sync_from_log(&bins_log(
+                &pool,
+                false,
+                &[(id, 1, 1), (id + 2, 1, u128::MAX)]
+            )),
+            Err(EventLogError::MalformedLog(_))
+        ));
+        assert_eq!(pool.bins, before);
+
+        //Swaps move the active id to the last bin traded in
+        let swap_log = Log {
+            address: pool.address,
+            topics: vec![
+                SWAP_EVENT_SIGNATURE,
+                H160::from_low_u64_be(7).into(),
+                H160::from_low_u64_be(8).into(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(id - 1)),
+                Token::FixedBytes(amounts(100, 0).to_vec()),
+                Token::FixedBytes(amounts(0, 0).to_vec()),
+                Token::Uint(U256::from(20_000)),
+                Token::FixedBytes(amounts(1, 0).to_vec()),
+                Token::FixedBytes(amounts(1, 0).to_vec()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&swap_log)?;
+        assert_eq!(pool.active_id, id - 1);
+        assert_eq!(pool.bins[&(id - 1)], (99, 20_000 * 10_u128.pow(6)));
+        assert_eq!(pool.variable_fee_parameters.volatility_accumulator, 20_000);
+
+        let mut foreign_log = swap_log;
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_simulate_swap_against_pair() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("AVALANCHE_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //WAVAX/USDC pair with a bin step of 20
+        let mut pool = LiquidityBookPool {
+            address: "0xD446eb1660F766d533BeCeEf890Df7A69d26f7d1".parse()?,
+            ..Default::default()
+        };
+        let block = middleware
+            .get_block(middleware.get_block_number().await?)
+            .await?
+            .ok_or_else(|| eyre::eyre!("latest block not found"))?;
+        let block_number = block.number.unwrap_or_default().as_u64();
+        super::batch_request::get_liquidity_book_pool_data_batch_request(
+            &mut pool,
+            Some(block_number),
+            middleware.clone(),
+        )
+        .await?;
+
+        let pair = ILBPair::new(pool.address, middleware.clone());
+        for (token_in, amount_in) in [
+            (
+                pool.token_a,
+                U256::exp10(pool.token_a_decimals as usize) * 100,
+            ),
+            (
+                pool.token_b,
+                U256::exp10(pool.token_b_decimals as usize) * 1000,
+            ),
+        ] {
+            let (amount_in_left, expected, _) = pair
+                .get_swap_out(amount_in.as_u128(), token_in == pool.token_a)
+                .block(block_number)
+                .call()
+                .await?;
+            assert_eq!(amount_in_left, 0);
+
+            assert_eq!(
+                pool.simulate_swap_at(token_in, amount_in, block.timestamp.as_u64())?,
+                U256::from(expected)
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, I256, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{BinKind, MaverickBin, MaverickPool, PROTOCOL_FEE_RATIO_DENOMINATOR, WAD};
+
+//tokenA()
+const TOKEN_A_SELECTOR: [u8; 4] = [15, 198, 61, 16];
+//tokenB()
+const TOKEN_B_SELECTOR: [u8; 4] = [95, 100, 181, 91];
+//fee()
+const FEE_SELECTOR: [u8; 4] = [221, 202, 63, 67];
+//tickSpacing()
+const TICK_SPACING_SELECTOR: [u8; 4] = [208, 201, 58, 124];
+//getState()
+const GET_STATE_SELECTOR: [u8; 4] = [24, 101, 197, 125];
+//getBin(uint128)
+const GET_BIN_SELECTOR: [u8; 4] = [68, 161, 133, 187];
+
+const POOL_SELECTORS: [[u8; 4]; 5] = [
+    TOKEN_A_SELECTOR,
+    TOKEN_B_SELECTOR,
+    FEE_SELECTOR,
+    TICK_SPACING_SELECTOR,
+    GET_STATE_SELECTOR,
+];
+
+/// Reads `pool` and all of its bins at `block_number`, erroring with `AMMError::BatchRequestError` if it is not a
+/// Maverick pool
+pub async fn get_maverick_pool_data_batch_request<M: Middleware>(
+    pool: &mut MaverickPool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Maverick pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be
+/// read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::MaverickPool(maverick_pool) = amm {
+            if let Some(pool) = populate_pool_data(maverick_pool.to_owned(), pool_data) {
+                *maverick_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    tokens: Option<(H160, H160)>,
+    fee: Option<u64>,
+    tick_spacing: Option<u32>,
+    //activeTick, binCounter and protocolFeeRatio of `getState`
+    state: Option<(i32, u128, u64)>,
+    decimals: (Option<u8>, Option<u8>),
+    bins: Option<BTreeMap<u128, MaverickBin>>,
+}
+
+fn populate_pool_data(mut pool: MaverickPool, pool_data: PoolData) -> Option<MaverickPool> {
+    (pool.token_a, pool.token_b) = pool_data.tokens?;
+    (pool.token_a_decimals, pool.token_b_decimals) = (pool_data.decimals.0?, pool_data.decimals.1?);
+    pool.fee = pool_data.fee?;
+    pool.tick_spacing = pool_data.tick_spacing?;
+    (pool.active_tick, _, pool.protocol_fee_ratio) = pool_data.state?;
+    pool.bins = pool_data.bins?;
+    pool.bins_pending_move = false;
+
+    Some(pool)
+}
+
+//Reads the pools, then every bin each pool has created, as the `PoolInformation` lens of Maverick does
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| POOL_SELECTORS.map(|selector| multicall::call(*pool, selector, &[])))
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(POOL_SELECTORS.len())
+        .map(|pool_return_data| PoolData {
+            tokens: address(&pool_return_data[0]).zip(address(&pool_return_data[1])),
+            fee: word(&pool_return_data[2], 0)
+                .filter(|fee| *fee < U256::from(WAD))
+                .map(|fee| fee.as_u64()),
+            tick_spacing: word(&pool_return_data[3], 0)
+                .filter(|tick_spacing| {
+                    !tick_spacing.is_zero() && *tick_spacing <= U256::from(u32::MAX)
+                })
+                .map(|tick_spacing| tick_spacing.as_u32()),
+            state: state(&pool_return_data[4]),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    let tokens = pool_data
+        .iter()
+        .filter_map(|pool_data| pool_data.tokens)
+        .flat_map(|(token_a, token_b)| [token_a, token_b])
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware.clone()).await?;
+    for pool_data in pool_data.iter_mut() {
+        if let Some((token_a, token_b)) = pool_data.tokens {
+            pool_data.decimals = (
+                decimals.get(&token_a).copied(),
+                decimals.get(&token_b).copied(),
+            );
+        }
+    }
+
+    //Bin ids start at one and count up to the bin counter of the pool
+    let bin_counters = pool_data
+        .iter()
+        .map(|pool_data| pool_data.state.map(|(_, bin_counter, _)| bin_counter))
+        .collect::<Vec<_>>();
+    let calls = pools
+        .iter()
+        .zip(&bin_counters)
+        .filter_map(|(pool, bin_counter)| Some((*pool, (*bin_counter)?)))
+        .flat_map(|(pool, bin_counter)| {
+            (1..=bin_counter).map(move |id| {
+                multicall::call(
+                    pool,
+                    GET_BIN_SELECTOR,
+                    &ethers::abi::encode(&[Token::Uint(U256::from(id))]),
+                )
+            })
+        })
+        .collect();
+    let mut return_data = multicall::aggregate(calls, block_number, middleware)
+        .await?
+        .into_iter();
+
+    for (pool_data, bin_counter) in pool_data.iter_mut().zip(bin_counters) {
+        let bin_counter = match bin_counter {
+            Some(bin_counter) => bin_counter,
+            None => continue,
+        };
+        let bin_return_data = return_data
+            .by_ref()
+            .take(bin_counter as usize)
+            .collect::<Vec<_>>();
+        pool_data.bins = bins_from_multicall(&bin_return_data);
+    }
+
+    Ok(pool_data)
+}
+
+//uint128 reserveA, uint128 reserveB, uint128 mergeBinBalance, uint128 mergeId, uint128 totalSupply, uint8 kind,
+//int32 lowerTick, leaving out the bins that were merged into another bin
+fn bins_from_multicall(return_data: &[Option<Bytes>]) -> Option<BTreeMap<u128, MaverickBin>> {
+    let mut bins = BTreeMap::new();
+    for (id, return_data) in (1..).zip(return_data) {
+        let reserve_a = word(return_data, 0).filter(|reserve| *reserve <= U256::from(u128::MAX))?;
+        let reserve_b = word(return_data, 1).filter(|reserve| *reserve <= U256::from(u128::MAX))?;
+        let merge_id = word(return_data, 3)?;
+        let kind = word(return_data, 5)
+            .filter(|kind| *kind <= U256::from(u8::MAX))
+            .and_then(|kind| BinKind::from_u8(kind.as_u32() as u8))?;
+        let lower_tick = int_word(return_data, 6)?;
+
+        if merge_id.is_zero() {
+            bins.insert(
+                id,
+                MaverickBin {
+                    reserve_a: reserve_a.as_u128(),
+                    reserve_b: reserve_b.as_u128(),
+                    kind,
+                    lower_tick,
+                },
+            );
+        }
+    }
+
+    Some(bins)
+}
+
+//int32 activeTick, uint8 status, uint128 binCounter, uint64 protocolFeeRatio
+fn state(return_data: &Option<Bytes>) -> Option<(i32, u128, u64)> {
+    let active_tick = int_word(return_data, 0)?;
+    let bin_counter =
+        word(return_data, 2).filter(|bin_counter| *bin_counter <= U256::from(u128::MAX))?;
+    let protocol_fee_ratio = word(return_data, 3)
+        .filter(|ratio| *ratio <= U256::from(PROTOCOL_FEE_RATIO_DENOMINATOR))?;
+
+    Some((
+        active_tick,
+        bin_counter.as_u128(),
+        protocol_fee_ratio.as_u64(),
+    ))
+}
+
+fn word(return_data: &Option<Bytes>, index: usize) -> Option<U256> {
+    multicall::word(return_data.as_ref()?, index)
+}
+
+//Sign extended `int32` held in word `index`
+fn int_word(return_data: &Option<Bytes>, index: usize) -> Option<i32> {
+    let value = I256::from_raw(word(return_data, index)?);
+
+    (I256::from(i32::MIN) <= value && value <= I256::from(i32::MAX)).then(|| value.low_i32())
+}
+
+fn address(return_data: &Option<Bytes>) -> Option<H160> {
+    multicall::address_word(return_data.as_ref()?, 0).filter(|address| !address.is_zero())
+}
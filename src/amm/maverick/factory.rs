@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, Filter, Log, H160, H256, U256, U64},
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    amm::{
+        decode,
+        factory::{AutomatedMarketMakerFactory, TASK_LIMIT},
+        AutomatedMarketMaker, AMM,
+    },
+    errors::{AMMError, EventLogError},
+};
+
+use super::{batch_request, MaverickPool, PROTOCOL_FEE_RATIO_DENOMINATOR, WAD};
+
+//PoolCreated(address poolAddress, uint256 fee, uint256 tickSpacing, int32 activeTick, int256 lookback, uint64 protocolFeeRatio, address tokenA, address tokenB)
+pub const POOL_CREATED_EVENT_SIGNATURE: H256 = H256([
+    155, 63, 179, 161, 123, 78, 148, 235, 77, 18, 23, 37, 115, 114, 220, 199, 18, 33, 143, 205, 75,
+    193, 194, 132, 130, 189, 138, 104, 4, 167, 199, 117,
+]);
+
+/// Maverick V1 pool factory, discovering its pools from their `PoolCreated` logs
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MaverickFactory {
+    pub address: H160,
+    pub creation_block: u64,
+}
+
+impl MaverickFactory {
+    pub fn new(address: H160, creation_block: u64) -> MaverickFactory {
+        MaverickFactory {
+            address,
+            creation_block,
+        }
+    }
+
+    /// Gets every pool created up to `to_block`, unpopulated, scanning `step` blocks at a time
+    pub async fn get_all_pools_from_logs<M: 'static + Middleware>(
+        self,
+        to_block: u64,
+        step: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let mut from_block = self.creation_block;
+        let mut aggregated_amms: Vec<AMM> = vec![];
+
+        tracing::info!(from_block, to_block, step, "getting all pools from logs");
+
+        let mut handles = vec![];
+
+        while from_block <= to_block {
+            let middleware = middleware.clone();
+            let target_block = (from_block + step - 1).min(to_block);
+
+            let filter = Filter::new()
+                .topic0(POOL_CREATED_EVENT_SIGNATURE)
+                .address(self.address)
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            handles.push(tokio::spawn(async move {
+                let logs = middleware
+                    .get_logs(&filter)
+                    .await
+                    .map_err(AMMError::MiddlewareError)?;
+
+                Ok::<Vec<Log>, AMMError<M>>(logs)
+            }));
+
+            from_block += step;
+
+            //Here we are limiting the number of green threads that can be spun up to not have the node time out
+            if handles.len() == TASK_LIMIT {
+                self.decode_logs_from_handles(handles, &mut aggregated_amms)
+                    .await?;
+                handles = vec![];
+            }
+        }
+
+        self.decode_logs_from_handles(handles, &mut aggregated_amms)
+            .await?;
+
+        Ok(aggregated_amms)
+    }
+
+    async fn decode_logs_from_handles<M: Middleware>(
+        &self,
+        handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
+        amms: &mut Vec<AMM>,
+    ) -> Result<(), AMMError<M>> {
+        for handle in handles {
+            for log in handle.await?? {
+                amms.push(self.new_empty_amm_from_log(&log)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the pool created by a `PoolCreated` log, with the fee, tick spacing, active tick and protocol fee ratio it
+/// was created with
+pub fn decode_pool_created_log(log: &Log) -> Result<MaverickPool, EventLogError> {
+    if decode::event_signature(log)? != POOL_CREATED_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    let fee = decode::data_uint(log, 1, 256)?;
+    let tick_spacing = decode::data_uint(log, 2, 256)?;
+    let protocol_fee_ratio = decode::data_uint(log, 5, 64)?;
+    if fee >= U256::from(WAD)
+        || tick_spacing.is_zero()
+        || tick_spacing > U256::from(u32::MAX)
+        || protocol_fee_ratio > U256::from(PROTOCOL_FEE_RATIO_DENOMINATOR)
+    {
+        return Err(EventLogError::MalformedLog(log.into()));
+    }
+
+    Ok(MaverickPool::new(
+        decode::data_address(log, 0)?,
+        decode::data_address(log, 6)?,
+        0,
+        decode::data_address(log, 7)?,
+        0,
+        fee.as_u64(),
+        tick_spacing.as_u32(),
+        decode::data_int(log, 3, 32)?.low_i32(),
+        protocol_fee_ratio.as_u64(),
+    ))
+}
+
+#[async_trait]
+impl AutomatedMarketMakerFactory for MaverickFactory {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn creation_block(&self) -> u64 {
+        self.creation_block
+    }
+
+    fn amm_created_event_signature(&self) -> H256 {
+        POOL_CREATED_EVENT_SIGNATURE
+    }
+
+    async fn new_amm_from_log<M: 'static + Middleware>(
+        &self,
+        log: Log,
+        middleware: Arc<M>,
+    ) -> Result<AMM, AMMError<M>> {
+        let mut amm = self.new_empty_amm_from_log(&log)?;
+        let block_number = log.block_number.map(|block_number| block_number.as_u64());
+        amm.populate_data(block_number, middleware).await?;
+
+        Ok(amm)
+    }
+
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        decode::check_address(log, self.address)?;
+        let mut pool = decode_pool_created_log(log)?;
+        pool.creation_block = log.block_number.map(|block_number| block_number.as_u64());
+
+        Ok(AMM::MaverickPool(pool))
+    }
+
+    async fn get_all_amms<M: 'static + Middleware>(
+        &self,
+        to_block: Option<u64>,
+        middleware: Arc<M>,
+        step: u64,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        match to_block {
+            Some(block) => self.get_all_pools_from_logs(block, step, middleware).await,
+            None => Err(AMMError::BlockNumberNotFound),
+        }
+    }
+
+    async fn populate_amm_data<M: Middleware>(
+        &self,
+        amms: &mut [AMM],
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+        _step: u64,
+    ) -> Result<(), AMMError<M>> {
+        //Multicall3 chunks its calls itself
+        batch_request::get_amm_data_batch_request(amms, block_number, middleware).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, I256, U256, U64},
+    };
+
+    use crate::{
+        amm::{factory::AutomatedMarketMakerFactory, AmmState, AMM},
+        errors::EventLogError,
+    };
+
+    use super::{MaverickFactory, POOL_CREATED_EVENT_SIGNATURE};
+
+    fn pool_created_log(factory: &MaverickFactory, fee: U256) -> Log {
+        Log {
+            address: factory.address,
+            topics: vec![POOL_CREATED_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[
+                Token::Address(H160::from_low_u64_be(10)),
+                Token::Uint(fee),
+                Token::Uint(U256::from(10)),
+                Token::Int(I256::from(-3).into_raw()),
+                Token::Int(I256::from(3600).into_raw()),
+                Token::Uint(U256::from(250)),
+                Token::Address(H160::from_low_u64_be(1)),
+                Token::Address(H160::from_low_u64_be(2)),
+            ])
+            .into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_new_empty_amm_from_log() -> eyre::Result<()> {
+        let factory = MaverickFactory::new(H160::from_low_u64_be(100), 0);
+
+        match factory.new_empty_amm_from_log(&pool_created_log(&factory, U256::exp10(14)))? {
+            AMM::MaverickPool(pool) => {
+                assert_eq!(pool.address, H160::from_low_u64_be(10));
+                assert_eq!(
+                    pool.tokens(),
+                    vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)]
+                );
+                assert_eq!(
+                    (pool.fee, pool.tick_spacing, pool.active_tick),
+                    (100_000_000_000_000, 10, -3)
+                );
+                assert_eq!(pool.protocol_fee_ratio, 250);
+                assert_eq!(pool.creation_block, Some(10));
+            }
+            _ => panic!("Expected a new Maverick pool"),
+        }
+
+        //Fees of 100% or more are not fees
+        assert!(matches!(
+            factory.new_empty_amm_from_log(&pool_created_log(&factory, U256::exp10(18))),
+            Err(EventLogError::MalformedLog(_))
+        ));
+
+        Ok(())
+    }
+}
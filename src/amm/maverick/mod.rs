@@ -0,0 +1,1168 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+#[cfg(feature = "rpc")]
+pub mod factory;
+
+use std::collections::BTreeMap;
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::{
+    abi::{ParamType, Token},
+    types::{Log, H160, H256, I256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{fixed_point::u256_to_f64, mul_div, mul_div_rounding_up, mul_shift_right},
+    tokens::TokenStore,
+};
+
+//Swap(address sender, address recipient, bool tokenAIn, bool exactOutput, uint256 amountIn, uint256 amountOut, int32 activeTick)
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    59, 132, 29, 201, 171, 81, 227, 16, 75, 218, 79, 97, 180, 30, 66, 113, 25, 45, 34, 205, 25,
+    218, 94, 230, 226, 146, 220, 142, 39, 68, 247, 19,
+]);
+//AddLiquidity(address indexed sender, uint256 indexed tokenId, BinDelta[] binDeltas)
+pub const ADD_LIQUIDITY_EVENT_SIGNATURE: H256 = H256([
+    19, 58, 2, 115, 39, 88, 43, 226, 8, 159, 108, 164, 113, 55, 227, 211, 55, 190, 76, 162, 205,
+    146, 30, 95, 11, 23, 140, 156, 45, 91, 131, 100,
+]);
+//RemoveLiquidity(address indexed sender, address indexed recipient, uint256 indexed tokenId, BinDelta[] binDeltas)
+pub const REMOVE_LIQUIDITY_EVENT_SIGNATURE: H256 = H256([
+    101, 218, 40, 12, 30, 151, 58, 28, 88, 132, 195, 141, 99, 226, 194, 179, 194, 163, 21, 138, 7,
+    97, 231, 101, 69, 182, 64, 53, 226, 72, 157, 254,
+]);
+//BinMerged(uint128 indexed binId, uint128 reserveA, uint128 reserveB, uint128 mergeId)
+pub const BIN_MERGED_EVENT_SIGNATURE: H256 = H256([
+    142, 207, 31, 157, 167, 24, 220, 76, 23, 68, 130, 205, 180, 227, 52, 17, 56, 86, 180, 106, 133,
+    229, 105, 77, 238, 236, 6, 213, 18, 232, 247, 114,
+]);
+//BinMoved(uint128 indexed binId, int128 previousTick, int128 newTick)
+pub const BIN_MOVED_EVENT_SIGNATURE: H256 = H256([
+    66, 229, 22, 32, 231, 80, 150, 52, 74, 200, 137, 204, 29, 137, 154, 182, 25, 174, 219, 232,
+    154, 79, 107, 35, 14, 227, 206, 203, 132, 156, 126, 47,
+]);
+
+/// Denominator of the fee and of the reserves of the bins, which hold every token scaled to 18 decimals
+pub const WAD: u128 = 1_000_000_000_000_000_000;
+/// Denominator of the protocol fee ratio, i.e. a ratio of 250 sets 25% of the fee aside
+pub const PROTOCOL_FEE_RATIO_DENOMINATOR: u64 = 1000;
+
+/// How a bin follows the time weighted average price of the pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BinKind {
+    /// Never moves
+    #[default]
+    Static,
+    /// Moves up with the price
+    Right,
+    /// Moves down with the price
+    Left,
+    /// Moves both ways with the price
+    Both,
+}
+
+impl BinKind {
+    /// Kind of the `uint8` the pools use, None if it is not a kind
+    pub fn from_u8(kind: u8) -> Option<BinKind> {
+        match kind {
+            0 => Some(BinKind::Static),
+            1 => Some(BinKind::Right),
+            2 => Some(BinKind::Left),
+            3 => Some(BinKind::Both),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            BinKind::Static => 0,
+            BinKind::Right => 1,
+            BinKind::Left => 2,
+            BinKind::Both => 3,
+        }
+    }
+}
+
+/// Bin of a Maverick pool, with its reserves scaled to 18 decimals as held by the pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaverickBin {
+    pub reserve_a: u128,
+    pub reserve_b: u128,
+    pub kind: BinKind,
+    /// Tick the bin sits in
+    pub lower_tick: i32,
+}
+
+/// Maverick V1 pool.
+///
+/// Liquidity sits in bins, each in a tick of price `1.0001^(tick * tick_spacing)` in token b per token a, and the
+/// bins of the active tick trade along a constant product curve bounded by the prices of the tick and of the next
+/// one. Swaps empty the ticks one after the other from the active tick, paying `fee` on the amount in of which the
+/// pool keeps `protocol_fee_ratio` aside.
+///
+/// Bins of every kind but `BinKind::Static` move towards the time weighted average price of the pool at the start
+/// of the swaps of a new block, the price of a swap only depends on the bins in each tick. Moves and merges are
+/// synced from the `BinMoved` and `BinMerged` logs of the pools rather than simulated, so `simulate_swap_mut` flags
+/// the pool with `bins_pending_move` when the bins it leaves behind would move at the next block.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MaverickPool {
+    pub address: H160,
+    pub token_a: H160,
+    pub token_a_decimals: u8,
+    pub token_b: H160,
+    pub token_b_decimals: u8,
+    /// Fee over `WAD`
+    pub fee: u64,
+    pub tick_spacing: u32,
+    pub active_tick: i32,
+    /// Share of the fee set aside for the protocol over `PROTOCOL_FEE_RATIO_DENOMINATOR`
+    pub protocol_fee_ratio: u64,
+    /// Bins that were not merged into another bin, by id
+    pub bins: BTreeMap<u128, MaverickBin>,
+    /// Set by `simulate_swap_mut` when it moves the active tick of a pool with bins that move. Those bins move towards
+    /// the new price at the start of the next block, which is not simulated, so quotes past the block of the swap are
+    /// against stale bins until the pool is read again or synced from the `BinMoved` and `BinMerged` logs of the block
+    #[serde(skip)]
+    pub bins_pending_move: bool,
+    pub creation_block: Option<u64>,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+//Outcome of a swap, applied by `simulate_swap_mut`
+struct SwapState {
+    amount_out: U256,
+    active_tick: i32,
+    //Reserves of every tick traded in, before and after the swap
+    ticks: Vec<(i32, (U256, U256), (U256, U256))>,
+}
+
+impl AmmState for MaverickPool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        vec![self.token_a, self.token_b]
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Price of a whole `base_token` in whole units of the other token at the current price of the active tick,
+    /// without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let sqrt_price = u256_to_f64(self.sqrt_price()?, 18);
+        //The bins hold both tokens scaled to 18 decimals, so the price is already in whole units
+        let price = if base_token == self.token_a {
+            sqrt_price * sqrt_price
+        } else {
+            1.0 / (sqrt_price * sqrt_price)
+        };
+
+        if !price.is_finite() || price == 0.0 {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![
+            SWAP_EVENT_SIGNATURE,
+            ADD_LIQUIDITY_EVENT_SIGNATURE,
+            REMOVE_LIQUIDITY_EVENT_SIGNATURE,
+            BIN_MERGED_EVENT_SIGNATURE,
+            BIN_MOVED_EVENT_SIGNATURE,
+        ]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+
+        match decode::event_signature(log)? {
+            SWAP_EVENT_SIGNATURE => self.sync_from_swap_log(log),
+            ADD_LIQUIDITY_EVENT_SIGNATURE => self.sync_from_liquidity_log(log, true),
+            REMOVE_LIQUIDITY_EVENT_SIGNATURE => self.sync_from_liquidity_log(log, false),
+            BIN_MERGED_EVENT_SIGNATURE => {
+                self.bins_pending_move = false;
+                self.sync_from_bin_merged_log(log)
+            }
+            BIN_MOVED_EVENT_SIGNATURE => {
+                self.bins_pending_move = false;
+                self.sync_from_bin_moved_log(log)
+            }
+            _ => Err(EventLogError::UnexpectedEvent(log.into())),
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        let mut words = vec![
+            U256::from(self.fee),
+            U256::from(self.tick_spacing),
+            I256::from(self.active_tick).into_raw(),
+            U256::from(self.protocol_fee_ratio),
+        ];
+        words.extend(self.bins.iter().flat_map(|(id, bin)| {
+            [
+                U256::from(*id),
+                U256::from(bin.reserve_a),
+                U256::from(bin.reserve_b),
+                U256::from(bin.kind.as_u8()),
+                I256::from(bin.lower_tick).into_raw(),
+            ]
+        }));
+
+        amm::state_fingerprint(Protocol::Maverick.name(), &words)
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        Ok(self.swap(token_in, amount_in)?.amount_out)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let swap = self.swap(token_in, amount_in)?;
+
+        for (tick, reserves, new_reserves) in swap.ticks {
+            self.set_tick_reserves(tick, reserves, new_reserves, token_in == self.token_a)?;
+        }
+        if swap.active_tick != self.active_tick && self.has_movable_bins() {
+            self.bins_pending_move = true;
+        }
+        self.active_tick = swap.active_tick;
+
+        Ok(swap.amount_out)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token_a == token_in {
+            self.token_b
+        } else {
+            self.token_a
+        }
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if token == self.token_a {
+            Some(self.token_b)
+        } else if token == self.token_b {
+            Some(self.token_a)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for MaverickPool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_maverick_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_maverick_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl MaverickPool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: H160,
+        token_a: H160,
+        token_a_decimals: u8,
+        token_b: H160,
+        token_b_decimals: u8,
+        fee: u64,
+        tick_spacing: u32,
+        active_tick: i32,
+        protocol_fee_ratio: u64,
+    ) -> MaverickPool {
+        MaverickPool {
+            address,
+            token_a,
+            token_a_decimals,
+            token_b,
+            token_b_decimals,
+            fee,
+            tick_spacing,
+            active_tick,
+            protocol_fee_ratio,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        }
+    }
+
+    /// Whether any bin holding liquidity is of a kind that moves with the price of the pool
+    pub fn has_movable_bins(&self) -> bool {
+        self.bins
+            .values()
+            .any(|bin| bin.kind != BinKind::Static && (bin.reserve_a != 0 || bin.reserve_b != 0))
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.token_a.is_zero() || self.token_b.is_zero() || self.tick_spacing == 0)
+            && self
+                .bins
+                .values()
+                .any(|bin| bin.reserve_a != 0 || bin.reserve_b != 0)
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.token_a {
+            Some(self.token_a_decimals)
+        } else if token == self.token_b {
+            Some(self.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of both tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Balances of token a and token b held by the bins, in the decimals of each token
+    pub fn total_reserves(&self) -> (U256, U256) {
+        let (reserve_a, reserve_b) = self
+            .bins
+            .values()
+            .fold((U256::zero(), U256::zero()), |(total_a, total_b), bin| {
+                (total_a + bin.reserve_a, total_b + bin.reserve_b)
+            });
+
+        (
+            from_wad(reserve_a, self.token_a_decimals),
+            from_wad(reserve_b, self.token_b_decimals),
+        )
+    }
+
+    /// Reserves of token a and token b scaled to 18 decimals summed over the bins of each tick
+    pub fn tick_reserves(&self) -> BTreeMap<i32, (U256, U256)> {
+        let mut ticks = BTreeMap::new();
+        for bin in self.bins.values() {
+            let (reserve_a, reserve_b) = ticks
+                .entry(bin.lower_tick)
+                .or_insert((U256::zero(), U256::zero()));
+            *reserve_a += U256::from(bin.reserve_a);
+            *reserve_b += U256::from(bin.reserve_b);
+        }
+
+        ticks
+    }
+
+    /// Square root of the current price of the active tick over `WAD`, the lower price of the tick if it is empty
+    pub fn sqrt_price(&self) -> Result<U256, ArithmeticError> {
+        let (reserve_a, reserve_b) = self
+            .tick_reserves()
+            .get(&self.active_tick)
+            .copied()
+            .unwrap_or_default();
+
+        Ok(self.tick_state(self.active_tick, reserve_a, reserve_b)?.2)
+    }
+
+    //Walks the ticks from the active tick as `swap` of the pools, towards lower ticks when token a is sold
+    fn swap(&self, token_in: H160, amount_in: U256) -> Result<SwapState, SwapSimulationError> {
+        let token_a_in = if token_in == self.token_a {
+            true
+        } else if token_in == self.token_b {
+            false
+        } else {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        };
+        let (decimals_in, decimals_out) = if token_a_in {
+            (self.token_a_decimals, self.token_b_decimals)
+        } else {
+            (self.token_b_decimals, self.token_a_decimals)
+        };
+
+        let ticks = self.tick_reserves();
+        let fee = U256::from(self.fee);
+        let wad = U256::from(WAD);
+        let mut amount_in_left = to_wad(amount_in, decimals_in)?;
+        let mut swap = SwapState {
+            amount_out: U256::zero(),
+            active_tick: self.active_tick,
+            ticks: vec![],
+        };
+
+        let mut tick = self.active_tick;
+        while !amount_in_left.is_zero() {
+            let (reserve_a, reserve_b) = ticks.get(&tick).copied().unwrap_or_default();
+            let reserve_out = if token_a_in { reserve_b } else { reserve_a };
+
+            if !reserve_out.is_zero() {
+                let (sqrt_lower, sqrt_upper, sqrt_price, liquidity) =
+                    self.tick_state(tick, reserve_a, reserve_b)?;
+
+                //Amount in that takes the price to the edge of the tick, emptying it of the token out
+                let max_amount_in = if token_a_in {
+                    mul_div_rounding_up(
+                        mul_div_rounding_up(liquidity, sqrt_price - sqrt_lower, sqrt_lower)?,
+                        wad,
+                        sqrt_price,
+                    )?
+                } else {
+                    mul_div_rounding_up(liquidity, sqrt_upper - sqrt_price, wad)?
+                };
+                let max_fee = mul_div_rounding_up(max_amount_in, fee, wad)?;
+
+                let (bin_amount_in, fee_amount, amount_out) =
+                    if amount_in_left >= max_amount_in + max_fee {
+                        (max_amount_in, max_fee, reserve_out)
+                    } else {
+                        let bin_amount_in = mul_div(amount_in_left, wad, wad + fee)?;
+                        let amount_out =
+                            amount_out_in_tick(token_a_in, bin_amount_in, liquidity, sqrt_price)?;
+                        (
+                            bin_amount_in,
+                            amount_in_left - bin_amount_in,
+                            amount_out.min(reserve_out),
+                        )
+                    };
+
+                amount_in_left -= bin_amount_in + fee_amount;
+                swap.amount_out += amount_out;
+
+                //The protocol share of the fee is set aside by the pool, the rest stays in the tick
+                let protocol_fee = fee_amount * self.protocol_fee_ratio
+                    / U256::from(PROTOCOL_FEE_RATIO_DENOMINATOR);
+                let amount_in_to_tick = bin_amount_in + fee_amount - protocol_fee;
+                let new_reserves = if token_a_in {
+                    (reserve_a + amount_in_to_tick, reserve_b - amount_out)
+                } else {
+                    (reserve_a - amount_out, reserve_b + amount_in_to_tick)
+                };
+                swap.ticks
+                    .push((tick, (reserve_a, reserve_b), new_reserves));
+            }
+
+            swap.active_tick = tick;
+            if !amount_in_left.is_zero() {
+                tick = next_tick(&ticks, token_a_in, tick)
+                    .ok_or(SwapSimulationError::BinsExhausted)?;
+            }
+        }
+
+        swap.amount_out = from_wad(swap.amount_out, decimals_out);
+
+        Ok(swap)
+    }
+
+    //Lower and upper sqrt prices of `tick`, the sqrt price of its reserves and the liquidity they provide
+    fn tick_state(
+        &self,
+        tick: i32,
+        reserve_a: U256,
+        reserve_b: U256,
+    ) -> Result<(U256, U256, U256, U256), ArithmeticError> {
+        let sqrt_lower = tick_sqrt_price(tick, self.tick_spacing)?;
+        let sqrt_upper = tick_sqrt_price(tick + 1, self.tick_spacing)?;
+        let liquidity = get_tick_liquidity(reserve_a, reserve_b, sqrt_lower, sqrt_upper)?;
+
+        //The virtual reserve of token b is the liquidity times the sqrt price
+        let sqrt_price = if liquidity.is_zero() {
+            sqrt_lower
+        } else {
+            (mul_div(reserve_b, U256::from(WAD), liquidity)? + sqrt_lower).min(sqrt_upper)
+        };
+
+        Ok((sqrt_lower, sqrt_upper, sqrt_price, liquidity))
+    }
+
+    //Spreads the change of the reserves of a tick over its bins in proportion to their share of the token out, which
+    //all bins of a tick hold in the same ratio
+    fn set_tick_reserves(
+        &mut self,
+        tick: i32,
+        reserves: (U256, U256),
+        new_reserves: (U256, U256),
+        token_a_in: bool,
+    ) -> Result<(), ArithmeticError> {
+        let (total_out, amount_in, amount_out) = if token_a_in {
+            (
+                reserves.1,
+                new_reserves.0 - reserves.0,
+                reserves.1 - new_reserves.1,
+            )
+        } else {
+            (
+                reserves.0,
+                new_reserves.1 - reserves.1,
+                reserves.0 - new_reserves.0,
+            )
+        };
+        let ids = self
+            .bins
+            .iter()
+            .filter(|(_, bin)| bin.lower_tick == tick)
+            .map(|(id, bin)| {
+                (
+                    *id,
+                    if token_a_in {
+                        bin.reserve_b
+                    } else {
+                        bin.reserve_a
+                    },
+                )
+            })
+            .filter(|(_, reserve_out)| *reserve_out != 0)
+            .collect::<Vec<_>>();
+
+        let (mut amount_in_left, mut amount_out_left) = (amount_in, amount_out);
+        for (i, (id, reserve_out)) in ids.iter().enumerate() {
+            //The last bin takes what is left so that the bins add up to the tick
+            let (bin_amount_in, bin_amount_out) = if i == ids.len() - 1 {
+                (amount_in_left, amount_out_left)
+            } else {
+                (
+                    mul_div(amount_in, U256::from(*reserve_out), total_out)?,
+                    mul_div(amount_out, U256::from(*reserve_out), total_out)?,
+                )
+            };
+            amount_in_left -= bin_amount_in;
+            amount_out_left -= bin_amount_out;
+
+            if let Some(bin) = self.bins.get_mut(id) {
+                let (reserve_in, reserve_out) = if token_a_in {
+                    (&mut bin.reserve_a, &mut bin.reserve_b)
+                } else {
+                    (&mut bin.reserve_b, &mut bin.reserve_a)
+                };
+                match (
+                    Some(U256::from(*reserve_in) + bin_amount_in)
+                        .filter(|reserve| *reserve <= U256::from(u128::MAX)),
+                    U256::from(*reserve_out).checked_sub(bin_amount_out),
+                ) {
+                    (Some(new_reserve_in), Some(new_reserve_out)) => {
+                        *reserve_in = new_reserve_in.as_u128();
+                        *reserve_out = new_reserve_out.as_u128();
+                    }
+                    _ => return Err(ArithmeticError::U128ConversionError),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //Swaps are simulated again with the logged amount in, then leave the active tick where the pool says
+    fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let token_a_in = !decode::data_uint(log, 2, 8)?.is_zero();
+        let amount_in = decode::data_uint(log, 4, 256)?;
+        let active_tick = decode::data_int(log, 6, 32)?.low_i32();
+
+        let token_in = if token_a_in {
+            self.token_a
+        } else {
+            self.token_b
+        };
+        self.simulate_swap_mut(token_in, amount_in)
+            .map_err(|_| EventLogError::MalformedLog(log.into()))?;
+        self.active_tick = active_tick;
+
+        Ok(())
+    }
+
+    //Liquidity changes list the amounts added to or removed from each bin, creating the bins that are new
+    fn sync_from_liquidity_log(&mut self, log: &Log, add: bool) -> Result<(), EventLogError> {
+        let malformed_log = || EventLogError::MalformedLog(log.into());
+
+        let bin_delta = ParamType::Tuple(vec![
+            ParamType::Uint(128),
+            ParamType::Uint(128),
+            ParamType::Uint(256),
+            ParamType::Uint(128),
+            ParamType::Uint(8),
+            ParamType::Int(32),
+            ParamType::Bool,
+        ]);
+        let tokens = ethers::abi::decode(&[ParamType::Array(Box::new(bin_delta))], &log.data)?;
+        let bin_deltas = match tokens.as_slice() {
+            [Token::Array(bin_deltas)] => bin_deltas,
+            _ => return Err(malformed_log()),
+        };
+
+        //Every bin is checked before any is updated, so a bad log leaves the pool untouched
+        let mut bins: Vec<(u128, MaverickBin)> = vec![];
+        for bin_delta in bin_deltas {
+            let (delta_a, delta_b, id, kind, lower_tick) = match bin_delta {
+                Token::Tuple(fields) => match fields.as_slice() {
+                    [Token::Uint(delta_a), Token::Uint(delta_b), Token::Uint(_), Token::Uint(id), Token::Uint(kind), Token::Int(lower_tick), Token::Bool(_)]
+                        if [delta_a, delta_b, id]
+                            .iter()
+                            .all(|value| **value <= U256::from(u128::MAX)) =>
+                    {
+                        (
+                            delta_a.as_u128(),
+                            delta_b.as_u128(),
+                            id.as_u128(),
+                            BinKind::from_u8(kind.low_u32() as u8).ok_or_else(malformed_log)?,
+                            I256::from_raw(*lower_tick).low_i32(),
+                        )
+                    }
+                    _ => return Err(malformed_log()),
+                },
+                _ => return Err(malformed_log()),
+            };
+
+            let bin = bins
+                .iter()
+                .rev()
+                .find(|(bin_id, _)| *bin_id == id)
+                .map(|(_, bin)| *bin)
+                .or_else(|| self.bins.get(&id).copied())
+                .unwrap_or(MaverickBin {
+                    kind,
+                    lower_tick,
+                    ..Default::default()
+                });
+            let reserves = if add {
+                (
+                    bin.reserve_a.checked_add(delta_a),
+                    bin.reserve_b.checked_add(delta_b),
+                )
+            } else {
+                (
+                    bin.reserve_a.checked_sub(delta_a),
+                    bin.reserve_b.checked_sub(delta_b),
+                )
+            };
+            match reserves {
+                (Some(reserve_a), Some(reserve_b)) => bins.push((
+                    id,
+                    MaverickBin {
+                        reserve_a,
+                        reserve_b,
+                        ..bin
+                    },
+                )),
+                _ => return Err(malformed_log()),
+            }
+        }
+
+        for (id, bin) in bins {
+            self.bins.insert(id, bin);
+        }
+
+        Ok(())
+    }
+
+    //Merged bins hand their reserves over to the bin they are merged into
+    fn sync_from_bin_merged_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let id = decode::topic_uint(log, 1, 128)?.as_u128();
+        let reserve_a = decode::data_uint(log, 0, 128)?.as_u128();
+        let reserve_b = decode::data_uint(log, 1, 128)?.as_u128();
+        let merge_id = decode::data_uint(log, 2, 128)?.as_u128();
+
+        let merged = match self.bins.get(&merge_id) {
+            Some(bin) => match (
+                bin.reserve_a.checked_add(reserve_a),
+                bin.reserve_b.checked_add(reserve_b),
+            ) {
+                (Some(reserve_a), Some(reserve_b)) => MaverickBin {
+                    reserve_a,
+                    reserve_b,
+                    ..*bin
+                },
+                _ => return Err(EventLogError::MalformedLog(log.into())),
+            },
+            None => return Err(EventLogError::MalformedLog(log.into())),
+        };
+
+        self.bins.remove(&id);
+        self.bins.insert(merge_id, merged);
+
+        Ok(())
+    }
+
+    fn sync_from_bin_moved_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let id = decode::topic_uint(log, 1, 128)?.as_u128();
+        let new_tick = decode::data_int(log, 1, 128)?;
+        if new_tick > I256::from(i32::MAX) || new_tick < I256::from(i32::MIN) {
+            return Err(EventLogError::MalformedLog(log.into()));
+        }
+
+        match self.bins.get_mut(&id) {
+            Some(bin) => bin.lower_tick = new_tick.low_i32(),
+            None => return Err(EventLogError::MalformedLog(log.into())),
+        }
+
+        Ok(())
+    }
+}
+
+/// Square root of the price of `tick` over `WAD`, `1.0001^(tick * tick_spacing / 2)`
+pub fn tick_sqrt_price(tick: i32, tick_spacing: u32) -> Result<U256, ArithmeticError> {
+    let tick = (tick as i64)
+        .checked_mul(tick_spacing as i64)
+        .and_then(|tick| i32::try_from(tick).ok())
+        .ok_or(ArithmeticError::SqrtPriceOverflow)?;
+    let sqrt_price_x96 = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(tick)
+        .map_err(|_| ArithmeticError::SqrtPriceOverflow)?;
+
+    mul_shift_right(sqrt_price_x96, U256::from(WAD), 96)
+}
+
+/// Liquidity of reserves between the sqrt prices `sqrt_lower` and `sqrt_upper`, all over `WAD`, which solves
+/// `(reserve_a + L / sqrt_upper) * (reserve_b + L * sqrt_lower) = L^2`
+pub fn get_tick_liquidity(
+    reserve_a: U256,
+    reserve_b: U256,
+    sqrt_lower: U256,
+    sqrt_upper: U256,
+) -> Result<U256, ArithmeticError> {
+    let wad = U256::from(WAD);
+    if sqrt_lower >= sqrt_upper {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+
+    //L^2 * a - L * b - reserve_a * reserve_b = 0
+    let a = wad - mul_div(sqrt_lower, wad, sqrt_upper)?;
+    let b = mul_div(reserve_a, sqrt_lower, wad)? + mul_div(reserve_b, wad, sqrt_upper)?;
+    let c = mul_div(reserve_a, reserve_b, wad)?;
+    let discriminant = mul_div(b, b, wad)? + mul_div(a, c, wad)? * 4;
+    let sqrt_discriminant = discriminant
+        .checked_mul(wad)
+        .ok_or(ArithmeticError::MulDivOverflow)?
+        .integer_sqrt();
+
+    mul_div(b + sqrt_discriminant, wad, a * 2)
+}
+
+//Amount out of a swap of `amount_in` that stays within the tick, from the constant product of the virtual reserves
+fn amount_out_in_tick(
+    token_a_in: bool,
+    amount_in: U256,
+    liquidity: U256,
+    sqrt_price: U256,
+) -> Result<U256, ArithmeticError> {
+    let wad = U256::from(WAD);
+    if liquidity.is_zero() {
+        return Ok(U256::zero());
+    }
+
+    if token_a_in {
+        //The price falls to L * sqrt_price / (L + amount_in * sqrt_price)
+        let amount_in_sqrt_price = mul_div(amount_in, sqrt_price, wad)?;
+        mul_div(
+            mul_div(liquidity, sqrt_price, wad)?,
+            amount_in_sqrt_price,
+            liquidity + amount_in_sqrt_price,
+        )
+    } else {
+        //The price rises to sqrt_price + amount_in / L
+        let new_sqrt_price = sqrt_price + mul_div(amount_in, wad, liquidity)?;
+        mul_div(mul_div(amount_in, wad, sqrt_price)?, wad, new_sqrt_price)
+    }
+}
+
+//Next tick holding the token out past `tick`, below it when token a is sold
+fn next_tick(ticks: &BTreeMap<i32, (U256, U256)>, token_a_in: bool, tick: i32) -> Option<i32> {
+    if token_a_in {
+        ticks
+            .range(..tick)
+            .rev()
+            .find(|(_, (_, reserve_b))| !reserve_b.is_zero())
+            .map(|(tick, _)| *tick)
+    } else {
+        ticks
+            .range(tick + 1..)
+            .find(|(_, (reserve_a, _))| !reserve_a.is_zero())
+            .map(|(tick, _)| *tick)
+    }
+}
+
+//Amount of a token with `decimals` scaled to the 18 decimals of the bins
+fn to_wad(amount: U256, decimals: u8) -> Result<U256, ArithmeticError> {
+    if decimals <= 18 {
+        amount
+            .checked_mul(U256::exp10(18 - decimals as usize))
+            .ok_or(ArithmeticError::MulDivOverflow)
+    } else {
+        Ok(amount / U256::exp10(decimals as usize - 18))
+    }
+}
+
+//Amount scaled to 18 decimals back in the decimals of the token, rounded down as the pools pay out
+fn from_wad(amount: U256, decimals: u8) -> U256 {
+    if decimals <= 18 {
+        amount / U256::exp10(18 - decimals as usize)
+    } else {
+        amount.saturating_mul(U256::exp10(decimals as usize - 18))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        abi::Token,
+        providers::{Http, Middleware, Provider},
+        types::{Filter, Log, H160, I256, U256},
+    };
+
+    use crate::{
+        amm::{AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{
+        get_tick_liquidity, tick_sqrt_price, BinKind, MaverickBin, MaverickPool,
+        ADD_LIQUIDITY_EVENT_SIGNATURE, BIN_MERGED_EVENT_SIGNATURE, BIN_MOVED_EVENT_SIGNATURE,
+        REMOVE_LIQUIDITY_EVENT_SIGNATURE, SWAP_EVENT_SIGNATURE, WAD,
+    };
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    fn wad(amount: u128) -> u128 {
+        amount * WAD
+    }
+
+    //wstETH/WETH with a tick spacing of 10 and a fee of 0.01% around a price of 1.1, 100 WETH in each of the 5 ticks
+    //below the active tick, 100 wstETH in each of the 5 above and both in two bins of the active tick
+    fn wsteth_weth() -> MaverickPool {
+        //1.0001^(95 * 10) is close to 1.1
+        let active_tick = 95;
+        let mut pool = MaverickPool::new(
+            H160::from_low_u64_be(10),
+            token(1),
+            18,
+            token(2),
+            18,
+            100_000_000_000_000,
+            10,
+            active_tick,
+            250,
+        );
+
+        let mut id = 0;
+        let mut bin = |pool: &mut MaverickPool, reserve_a, reserve_b, kind, lower_tick| {
+            id += 1;
+            pool.bins.insert(
+                id,
+                MaverickBin {
+                    reserve_a,
+                    reserve_b,
+                    kind,
+                    lower_tick,
+                },
+            );
+        };
+        for i in 1..=5 {
+            bin(&mut pool, 0, wad(100), BinKind::Static, active_tick - i);
+            bin(&mut pool, wad(100), 0, BinKind::Static, active_tick + i);
+        }
+        bin(&mut pool, wad(30), wad(33), BinKind::Static, active_tick);
+        bin(&mut pool, wad(60), wad(66), BinKind::Right, active_tick);
+
+        pool
+    }
+
+    fn liquidity_log(pool: &MaverickPool, add: bool, bins: &[(u128, u128, u128, i32)]) -> Log {
+        let bin_deltas = Token::Array(
+            bins.iter()
+                .map(|(id, delta_a, delta_b, lower_tick)| {
+                    Token::Tuple(vec![
+                        Token::Uint(U256::from(*delta_a)),
+                        Token::Uint(U256::from(*delta_b)),
+                        Token::Uint(U256::one()),
+                        Token::Uint(U256::from(*id)),
+                        Token::Uint(U256::from(1)),
+                        Token::Int(I256::from(*lower_tick).into_raw()),
+                        Token::Bool(false),
+                    ])
+                })
+                .collect(),
+        );
+
+        Log {
+            address: pool.address,
+            topics: if add {
+                vec![
+                    ADD_LIQUIDITY_EVENT_SIGNATURE,
+                    H160::from_low_u64_be(7).into(),
+                    U256::one().into(),
+                ]
+            } else {
+                vec![
+                    REMOVE_LIQUIDITY_EVENT_SIGNATURE,
+                    H160::from_low_u64_be(7).into(),
+                    H160::from_low_u64_be(8).into(),
+                    U256::one().into(),
+                ]
+            },
+            data: ethers::abi::encode(&[bin_deltas]).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_get_tick_liquidity() -> eyre::Result<()> {
+        let sqrt_lower = tick_sqrt_price(0, 10)?;
+        let sqrt_upper = tick_sqrt_price(1, 10)?;
+        assert_eq!(sqrt_lower, U256::from(WAD));
+        assert!((sqrt_upper.as_u128() as f64 / 1e18 - 1.0001_f64.powi(5)).abs() < 1e-12);
+
+        //Reserves of token a alone sit at the upper edge of the curve, those of token b alone at the lower edge
+        let liquidity =
+            get_tick_liquidity(U256::from(wad(1)), U256::zero(), sqrt_lower, sqrt_upper)?;
+        let expected = 1.0001_f64.powi(5) / (1.0001_f64.powi(5) - 1.0);
+        assert!((liquidity.as_u128() as f64 / 1e18 / expected - 1.0).abs() < 1e-9);
+
+        let liquidity =
+            get_tick_liquidity(U256::zero(), U256::from(wad(1)), sqrt_lower, sqrt_upper)?;
+        let expected = 1.0 / (1.0001_f64.powi(5) - 1.0);
+        assert!((liquidity.as_u128() as f64 / 1e18 / expected - 1.0).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        let pool = wsteth_weth();
+        let lower = 1.0001_f64.powi(950);
+        let upper = 1.0001_f64.powi(960);
+
+        let price = pool.calculate_price(token(1))?;
+        assert!(price > lower && price < upper);
+        assert!((pool.calculate_price(token(2))? * price - 1.0).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let pool = wsteth_weth();
+        let price = pool.calculate_price(token(1))?;
+
+        //A small swap stays in the active tick and pays the fee of 0.01%
+        let amount_out = pool.simulate_swap(token(1), U256::exp10(15))?;
+        let expected = 0.9999 * price * 1e15;
+        assert!((amount_out.as_u128() as f64 / expected - 1.0).abs() < 1e-6);
+
+        //Larger swaps cross ticks at lower prices
+        let amount_in = U256::from(wad(300));
+        let amount_out = pool.simulate_swap(token(1), amount_in)?;
+        assert!(amount_out.as_u128() > wad(300));
+        assert!((amount_out.as_u128() as f64) < 300.0 * 0.9999 * price * 1e18);
+
+        let mut swapped = pool.clone();
+        assert_eq!(swapped.simulate_swap_mut(token(1), amount_in)?, amount_out);
+        assert!(swapped.active_tick < pool.active_tick);
+        //Every tick crossed has been emptied of token b, and the bins of the active tick keep their ratio
+        let ticks = swapped.tick_reserves();
+        for tick in swapped.active_tick + 1..=pool.active_tick {
+            assert!(ticks[&tick].1.is_zero());
+        }
+        assert!((swapped.bins[&11].reserve_a * 2).abs_diff(swapped.bins[&12].reserve_a) <= 2);
+        //The right bin left behind in the previous active tick would move at the next block
+        assert!(swapped.bins_pending_move);
+        //The pool kept a quarter of the fee aside, the reserves gained the rest of the amount in
+        let (reserve_a, _) = swapped.total_reserves();
+        let (reserve_a_before, _) = pool.total_reserves();
+        assert!(reserve_a - reserve_a_before < amount_in);
+
+        //Swaps back pay less than the first swap took in
+        assert!(swapped.simulate_swap(token(2), amount_out)? < amount_in);
+
+        //Swaps within the active tick and pools of static bins alone have no bins to move
+        let mut small_swap = pool.clone();
+        small_swap.simulate_swap_mut(token(1), U256::exp10(15))?;
+        assert!(!small_swap.bins_pending_move);
+        let mut static_pool = pool.clone();
+        static_pool
+            .bins
+            .values_mut()
+            .for_each(|bin| bin.kind = BinKind::Static);
+        assert!(!static_pool.has_movable_bins());
+        static_pool.simulate_swap_mut(token(1), amount_in)?;
+        assert!(!static_pool.bins_pending_move);
+
+        //Swaps emptying every tick fail rather than make up liquidity
+        assert!(matches!(
+            pool.simulate_swap(token(2), U256::from(wad(10_000))),
+            Err(SwapSimulationError::BinsExhausted)
+        ));
+        assert!(matches!(
+            pool.simulate_swap(token(3), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = wsteth_weth();
+        let mut amm = AMM::MaverickPool(pool.clone());
+        let tick = pool.active_tick;
+
+        //Liquidity changes create the bins that are new
+        let log = liquidity_log(&pool, true, &[(11, 5, 7, tick), (13, wad(1), 0, tick + 8)]);
+        pool.sync_from_log(&log)?;
+        amm.sync_from_log(&log)?;
+        assert_eq!(
+            (pool.bins[&11].reserve_a, pool.bins[&11].reserve_b),
+            (wad(30) + 5, wad(33) + 7)
+        );
+        assert_eq!(pool.bins[&13].kind, BinKind::Right);
+        assert!(AMM::MaverickPool(pool.clone()).state_eq(&amm));
+
+        //Removing more than a bin holds is rejected without touching any bin
+        let before = pool.bins.clone();
+        assert!(matches!(
+            pool.sync_from_log(&liquidity_log(
+                &pool,
+                false,
+                &[(11, 5, 7, tick), (1, 1, 0, tick - 1)]
+            )),
+            Err(EventLogError::MalformedLog(_))
+        ));
+        assert_eq!(pool.bins, before);
+
+        //Moved bins change tick, merged bins hand their reserves over
+        let moved_log = Log {
+            address: pool.address,
+            topics: vec![BIN_MOVED_EVENT_SIGNATURE, U256::from(13).into()],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(tick + 8).into_raw()),
+                Token::Int(I256::from(tick + 5).into_raw()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&moved_log)?;
+        assert_eq!(pool.bins[&13].lower_tick, tick + 5);
+
+        let merged_log = Log {
+            address: pool.address,
+            topics: vec![BIN_MERGED_EVENT_SIGNATURE, U256::from(13).into()],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(wad(1))),
+                Token::Uint(U256::zero()),
+                Token::Uint(U256::from(10)),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&merged_log)?;
+        assert!(!pool.bins.contains_key(&13));
+        assert_eq!(pool.bins[&10].reserve_a, wad(101));
+
+        //Swaps are simulated again and move the active tick where the log says
+        let swap_log = Log {
+            address: pool.address,
+            topics: vec![SWAP_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[
+                Token::Address(H160::from_low_u64_be(7)),
+                Token::Address(H160::from_low_u64_be(8)),
+                Token::Bool(true),
+                Token::Bool(false),
+                Token::Uint(U256::from(wad(150))),
+                Token::Uint(U256::from(wad(160))),
+                Token::Int(I256::from(tick - 2).into_raw()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        let mut expected = pool.clone();
+        expected.simulate_swap_mut(token(1), U256::from(wad(150)))?;
+        pool.sync_from_log(&swap_log)?;
+        assert_eq!(pool.active_tick, tick - 2);
+        assert_eq!(pool.bins, expected.bins);
+
+        let mut foreign_log = swap_log;
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_populate_data() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //The first pool created by the Maverick V1 factory on mainnet
+        let logs = middleware
+            .get_logs(
+                &Filter::new()
+                    .address("0xEb6625D65a0553c9dBc64449e56abFe519bd9c9B".parse::<H160>()?)
+                    .topic0(super::factory::POOL_CREATED_EVENT_SIGNATURE)
+                    .from_block(17210000)
+                    .to_block(17310000),
+            )
+            .await?;
+        let mut pool = super::factory::decode_pool_created_log(&logs[0])?;
+        super::batch_request::get_maverick_pool_data_batch_request(&mut pool, None, middleware)
+            .await?;
+
+        assert!(pool.data_is_populated());
+        let (reserve_a, reserve_b) = pool.total_reserves();
+        assert!(!reserve_a.is_zero() || !reserve_b.is_zero());
+
+        //Small swaps are paid out close to the price less the fee, rounding is not bit exact with the pools
+        let amount_in = U256::exp10(pool.token_a_decimals as usize) / 1000;
+        let amount_out = pool.simulate_swap(pool.token_a, amount_in)?;
+        let expected = pool.calculate_price(pool.token_a)?
+            * (1.0 - pool.fee as f64 / 1e18)
+            * 10_f64.powi(pool.token_b_decimals as i32)
+            / 1000.0;
+        assert!((amount_out.as_u128() as f64 / expected - 1.0).abs() < 1e-3);
+
+        Ok(())
+    }
+}
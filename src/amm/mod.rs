@@ -1,120 +1,410 @@
+pub mod algebra;
+pub mod balancer;
+pub mod bancor_v3;
+pub mod compat;
+pub mod curve;
+pub mod curve_crypto;
+pub mod custom;
+pub mod decode;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod detect;
+pub mod dodo;
 pub mod erc_4626;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
 pub mod factory;
+/// Requires the `known-factories` feature
+#[cfg(feature = "known-factories")]
+pub mod known_factories;
+pub mod kyber_elastic;
+pub mod liquidity_book;
+pub mod maverick;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod multicall;
+pub mod simulate;
+pub mod summary;
 pub mod uniswap_v2;
 pub mod uniswap_v3;
+pub mod uniswap_v4;
+pub mod velodrome;
 
-use std::{collections::BTreeMap, sync::Arc};
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
 
+#[cfg(feature = "rpc")]
 use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
 use ethers::{
-    providers::Middleware,
+    abi::{self, Token},
     types::{Log, H160, H256, U256},
+    utils::keccak256,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::errors::{AMMError, ArithmeticError, EventLogError, StorageError, SwapSimulationError};
+#[cfg(feature = "rpc")]
+use crate::errors::AMMError;
+use crate::{
+    errors::{ArithmeticError, EventLogError, StateSyncError, SwapSimulationError},
+    math::Q128x128,
+    tokens::TokenStore,
+};
 
-use self::{erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool};
+pub use self::simulate::{best_quote, simulate_all};
 
-#[async_trait]
-pub trait AutomatedMarketMaker {
+use self::{
+    algebra::AlgebraPool, balancer::BalancerWeightedPool, bancor_v3::BancorV3Pool,
+    curve::CurveStableSwapPool, curve_crypto::CurveCryptoPool, custom::CustomAMM, dodo::DodoPool,
+    erc_4626::ERC4626Vault, kyber_elastic::KyberElasticPool, liquidity_book::LiquidityBookPool,
+    maverick::MaverickPool, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool,
+    uniswap_v4::UniswapV4Pool, velodrome::VelodromePool,
+};
+
+/// Object safe state of an AMM, everything that can be done without a middleware.
+///
+/// AMMs implemented outside of this crate can be held as `Box<dyn AmmState>`, synced from logs and simulated
+/// alongside the built in ones. The middleware bound methods live in `AutomatedMarketMaker`.
+pub trait AmmState: Debug + Send + Sync {
     fn address(&self) -> H160;
-    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>>;
+    /// Contract emitting the logs the AMM syncs from, the AMM itself unless it lives in a singleton contract
+    fn log_address(&self) -> H160 {
+        self.address()
+    }
     fn sync_on_event_signatures(&self) -> Vec<H256>;
-    fn sync_on_storage_slots(&self) -> Vec<H256>;
+    fn sync_on_storage_slots(&self) -> Vec<H256> {
+        vec![]
+    }
     fn tokens(&self) -> Vec<H160>;
+    /// Symbol of `token` if it was fetched when populating the AMM, see `PopulateOptions`
+    fn token_symbol(&self, _token: H160) -> Option<&str> {
+        None
+    }
     fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError>;
-    fn sync_from_log(&mut self, log: Log) -> Result<(), EventLogError>;
-    async fn populate_data<M: Middleware>(
+    /// Applies a log emitted by the AMM. Logs emitted by another contract are rejected with
+    /// `EventLogError::LogAddressMismatch`, AMMs living in a singleton contract check their pool id topic instead.
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError>;
+    /// Applies a storage diff of the AMM, returning whether its state changed. Decoded values are validated before
+    /// any is applied, a diff failing validation leaves the AMM untouched.
+    fn sync_from_storage(
         &mut self,
-        block_number: Option<u64>,
-        middleware: Arc<M>,
-    ) -> Result<(), AMMError<M>>;
-    fn sync_from_storage(&mut self, diff: &'_ BTreeMap<H256, H256>) -> Result<(), StorageError>;
+        _diff: &'_ BTreeMap<H256, H256>,
+    ) -> Result<bool, StateSyncError> {
+        Err(StateSyncError::Unsupported)
+    }
 
-    fn reserves(&self) -> BTreeMap<H256, H256>;
+    fn reserves(&self) -> BTreeMap<H256, H256> {
+        BTreeMap::new()
+    }
+    /// Stable hash of the protocol and of the state swaps are simulated against, which changes exactly when that
+    /// state changes. Hashes the `reserves` storage slots by default, AMMs keeping more state override it.
+    fn state_fingerprint(&self) -> H256 {
+        let words = self
+            .reserves()
+            .into_iter()
+            .flat_map(|(slot, value)| {
+                [
+                    U256::from_big_endian(slot.as_bytes()),
+                    U256::from_big_endian(value.as_bytes()),
+                ]
+            })
+            .collect::<Vec<_>>();
 
+        state_fingerprint("custom", &words)
+    }
+
+    /// Whether the swap math of the AMM is not modeled locally, so `AMM::simulate_swap` quotes it with the
+    /// fallback quoter of the `revm` feature instead
+    fn requires_rpc_quote(&self) -> bool {
+        false
+    }
     fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError>;
+    /// `simulate_swap`, failing with `SwapSimulationError::InsufficientOutput` when the output is below `min_out`,
+    /// as a router reverts when `amountOutMin` is not met
+    fn simulate_swap_checked(
+        &self,
+        token_in: H160,
+        amount_in: U256,
+        min_out: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        SwapSimulationError::check_output(self.simulate_swap(token_in, amount_in)?, min_out)
+    }
     fn simulate_swap_mut(
         &mut self,
         token_in: H160,
         amount_in: U256,
     ) -> Result<U256, SwapSimulationError>;
+    /// Derivative of the `simulate_swap` output with respect to `amount_in`, in units of token out per token in.
+    /// Approximated by default with a forward difference of `simulate_swap`.
+    fn gradient(&self, token_in: H160, amount_in: U256) -> Result<Q128x128, SwapSimulationError> {
+        let step = (amount_in / U256::from(1_000_000)).max(U256::one());
+        let amount_out = self.simulate_swap(token_in, amount_in)?;
+        let amount_out_after_step = self.simulate_swap(token_in, amount_in.saturating_add(step))?;
+
+        Q128x128::from_ratio(amount_out_after_step.saturating_sub(amount_out), step)
+            .ok_or(SwapSimulationError::GradientOverflow)
+    }
+    fn gradient_f64(&self, token_in: H160, amount_in: U256) -> Result<f64, SwapSimulationError> {
+        Ok(self.gradient(token_in, amount_in)?.to_f64())
+    }
+    /// Token bought for `token_in`, only meaningful for two token AMMs
     fn get_token_out(&self, token_in: H160) -> H160;
+    /// Other token of a two token AMM, None for AMMs holding more than two tokens or not holding `token`
     fn opp_token(&self, token: H160) -> Option<H160>;
+    /// Tokens `token_in` can be swapped for, empty when the AMM does not hold `token_in`
+    fn other_tokens(&self, token_in: H160) -> Vec<H160> {
+        let tokens = self.tokens();
+        if !tokens.contains(&token_in) {
+            return vec![];
+        }
+
+        tokens
+            .into_iter()
+            .filter(|token| *token != token_in)
+            .collect()
+    }
+    /// Amount of `token_out` bought for `amount_in` of `token_in`. AMMs holding more than two tokens implement it,
+    /// two token AMMs swap through `simulate_swap` by default.
+    fn simulate_swap_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        if self.opp_token(token_in) != Some(token_out) {
+            return Err(SwapSimulationError::TokenNotInPool(token_out));
+        }
+
+        self.simulate_swap(token_in, amount_in)
+    }
+    fn simulate_swap_to_mut(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        if self.opp_token(token_in) != Some(token_out) {
+            return Err(SwapSimulationError::TokenNotInPool(token_out));
+        }
+
+        self.simulate_swap_mut(token_in, amount_in)
+    }
+    /// Derivative of the `simulate_swap_to` output with respect to `amount_in`, `gradient` for two token AMMs and
+    /// a forward difference of `simulate_swap_to` otherwise.
+    fn gradient_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<Q128x128, SwapSimulationError> {
+        if self.opp_token(token_in) == Some(token_out) {
+            return self.gradient(token_in, amount_in);
+        }
+
+        let step = (amount_in / U256::from(1_000_000)).max(U256::one());
+        let amount_out = self.simulate_swap_to(token_in, token_out, amount_in)?;
+        let amount_out_after_step =
+            self.simulate_swap_to(token_in, token_out, amount_in.saturating_add(step))?;
+
+        Q128x128::from_ratio(amount_out_after_step.saturating_sub(amount_out), step)
+            .ok_or(SwapSimulationError::GradientOverflow)
+    }
+}
+
+/// Keccak256 of the ABI encoded protocol tag and state words, the encoding behind `AmmState::state_fingerprint`
+pub fn state_fingerprint(protocol: &str, words: &[U256]) -> H256 {
+    H256(keccak256(abi::encode(&[
+        Token::String(protocol.to_string()),
+        Token::Array(words.iter().copied().map(Token::Uint).collect()),
+    ])))
 }
 
+/// Address of the AMM `log` is meant for, the contract emitting it unless it is a Vault event of a Balancer pool or
+/// a PoolManager event of a Uniswap V4 pool, which name the pool by its id, or a trade of a Bancor V3 pool, which
+/// names the pool by its tokens
+pub fn log_amm_address(log: &Log) -> H160 {
+    match (log.topics.first(), log.topics.get(1)) {
+        (Some(event_signature), Some(pool_id))
+            if balancer::VAULT_EVENT_SIGNATURES.contains(event_signature) =>
+        {
+            balancer::pool_address(*pool_id)
+        }
+        (Some(event_signature), Some(pool_id))
+            if uniswap_v4::POOL_MANAGER_EVENT_SIGNATURES.contains(event_signature) =>
+        {
+            uniswap_v4::pool_address(*pool_id)
+        }
+        (Some(event_signature), Some(_))
+            if *event_signature == bancor_v3::TOKENS_TRADED_EVENT_SIGNATURE
+                && log.topics.len() == 4 =>
+        {
+            bancor_v3::pool_address(
+                log.address,
+                H160::from(log.topics[2]),
+                H160::from(log.topics[3]),
+            )
+        }
+        _ => log.address,
+    }
+}
+
+/// Optional data fetched alongside the pool data by `AMM::populate_data_with_options` and `SyncConfig`
+#[cfg(feature = "rpc")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PopulateOptions {
+    /// Fetches the `symbol()` and `name()` of the tokens through the token store, one multicall per batch of
+    /// unknown tokens
+    pub fetch_symbols: bool,
+}
+
+#[cfg(feature = "rpc")]
+impl PopulateOptions {
+    pub fn new() -> Self {
+        PopulateOptions::default()
+    }
+
+    pub fn with_fetch_symbols(mut self, fetch_symbols: bool) -> Self {
+        self.fetch_symbols = fetch_symbols;
+        self
+    }
+}
+
+/// Middleware bound operations of an AMM, generic over the middleware and therefore not object safe
+#[cfg(feature = "rpc")]
+#[async_trait]
+pub trait AutomatedMarketMaker: AmmState {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>>;
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>>;
+}
+
+/// Evaluates `$body` with `$amm` bound to the inner AMM of every variant of an `AMM`, which is how the `AMM`
+/// impls dispatch to the inner AMMs. The body has to compile for every variant, custom AMMs deref to
+/// `dyn CustomAutomatedMarketMaker`. Matches written with it keep compiling when a variant is added.
+///
+/// ```
+/// use amms::{
+///     amm::{AmmState, AMM},
+///     for_each_amm_variant,
+/// };
+/// use ethers::types::H160;
+///
+/// fn holds(amm: &AMM, token: H160) -> bool {
+///     for_each_amm_variant!(amm, inner => inner.tokens().contains(&token))
+/// }
+///
+/// let amm = AMM::UniswapV2Pool(Default::default());
+/// assert!(holds(&amm, H160::zero()));
+/// ```
+#[macro_export]
+macro_rules! for_each_amm_variant {
+    ($value:expr, $amm:ident => $body:expr) => {
+        match $value {
+            $crate::amm::AMM::UniswapV2Pool($amm) => $body,
+            $crate::amm::AMM::UniswapV3Pool($amm) => $body,
+            $crate::amm::AMM::ERC4626Vault($amm) => $body,
+            $crate::amm::AMM::CurveStableSwapPool($amm) => $body,
+            $crate::amm::AMM::BalancerWeightedPool($amm) => $body,
+            $crate::amm::AMM::VelodromePool($amm) => $body,
+            $crate::amm::AMM::UniswapV4Pool($amm) => $body,
+            $crate::amm::AMM::LiquidityBookPool($amm) => $body,
+            $crate::amm::AMM::MaverickPool($amm) => $body,
+            $crate::amm::AMM::DodoPool($amm) => $body,
+            $crate::amm::AMM::KyberElasticPool($amm) => $body,
+            $crate::amm::AMM::AlgebraPool($amm) => $body,
+            $crate::amm::AMM::CurveCryptoPool($amm) => $body,
+            $crate::amm::AMM::BancorV3Pool($amm) => $body,
+            $crate::amm::AMM::Custom($amm) => $body,
+            //Unreachable, the macro lists every variant of the same version of the crate
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Every AMM supported by the crate. New protocols are added as variants, so matches outside of the crate need a
+/// wildcard arm, or can dispatch to every variant with `for_each_amm_variant!`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum AMM {
     UniswapV2Pool(UniswapV2Pool),
     UniswapV3Pool(UniswapV3Pool),
     ERC4626Vault(ERC4626Vault),
+    Custom(CustomAMM),
+    //Added after `Custom`, binary checkpoints encode the variants by their index
+    CurveStableSwapPool(CurveStableSwapPool),
+    BalancerWeightedPool(BalancerWeightedPool),
+    VelodromePool(VelodromePool),
+    UniswapV4Pool(UniswapV4Pool),
+    LiquidityBookPool(LiquidityBookPool),
+    MaverickPool(MaverickPool),
+    DodoPool(DodoPool),
+    KyberElasticPool(KyberElasticPool),
+    AlgebraPool(AlgebraPool),
+    CurveCryptoPool(CurveCryptoPool),
+    BancorV3Pool(BancorV3Pool),
 }
 
-#[async_trait]
-impl AutomatedMarketMaker for AMM {
+impl AmmState for AMM {
     fn address(&self) -> H160 {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.address,
-            AMM::UniswapV3Pool(pool) => pool.address,
-            AMM::ERC4626Vault(vault) => vault.vault_token,
-        }
+        for_each_amm_variant!(self, amm => amm.address())
     }
 
-    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.sync(middleware).await,
-            AMM::UniswapV3Pool(pool) => pool.sync(middleware).await,
-            AMM::ERC4626Vault(vault) => vault.sync(middleware).await,
-        }
+    fn log_address(&self) -> H160 {
+        for_each_amm_variant!(self, amm => amm.log_address())
     }
 
     fn sync_on_storage_slots(&self) -> Vec<H256> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.sync_on_storage_slots(),
-            AMM::UniswapV3Pool(pool) => pool.sync_on_storage_slots(),
-            AMM::ERC4626Vault(vault) => vault.sync_on_storage_slots(),
-        }
+        for_each_amm_variant!(self, amm => amm.sync_on_storage_slots())
     }
 
     fn sync_on_event_signatures(&self) -> Vec<H256> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.sync_on_event_signatures(),
-            AMM::UniswapV3Pool(pool) => pool.sync_on_event_signatures(),
-            AMM::ERC4626Vault(vault) => vault.sync_on_event_signatures(),
-        }
+        for_each_amm_variant!(self, amm => amm.sync_on_event_signatures())
     }
 
-    fn sync_from_log(&mut self, log: Log) -> Result<(), EventLogError> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.sync_from_log(log),
-            AMM::UniswapV3Pool(pool) => pool.sync_from_log(log),
-            AMM::ERC4626Vault(vault) => vault.sync_from_log(log),
-        }
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        for_each_amm_variant!(self, amm => amm.sync_from_log(log))
     }
 
-    fn sync_from_storage(&mut self, diff: &'_ BTreeMap<H256, H256>) -> Result<(), StorageError> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.sync_from_storage(diff),
-            AMM::UniswapV3Pool(pool) => pool.sync_from_storage(diff),
-            AMM::ERC4626Vault(vault) => vault.sync_from_storage(diff),
-        }
+    fn sync_from_storage(
+        &mut self,
+        diff: &'_ BTreeMap<H256, H256>,
+    ) -> Result<bool, StateSyncError> {
+        for_each_amm_variant!(self, amm => amm.sync_from_storage(diff))
     }
 
     fn reserves(&self) -> BTreeMap<H256, H256> {
+        for_each_amm_variant!(self, amm => amm.reserves())
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        for_each_amm_variant!(self, amm => amm.state_fingerprint())
+    }
+
+    fn requires_rpc_quote(&self) -> bool {
         match self {
-            AMM::UniswapV2Pool(pool) => pool.reserves(),
-            AMM::UniswapV3Pool(pool) => pool.reserves(),
-            AMM::ERC4626Vault(vault) => vault.reserves(),
+            AMM::Custom(amm) => amm.requires_rpc_quote(),
+            _ => false,
         }
     }
 
     fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.simulate_swap(token_in, amount_in),
-            AMM::UniswapV3Pool(pool) => pool.simulate_swap(token_in, amount_in),
-            AMM::ERC4626Vault(vault) => vault.simulate_swap(token_in, amount_in),
+        #[cfg(feature = "revm")]
+        if self.requires_rpc_quote() {
+            return crate::simulation::revm::fallback_quote(self.address(), token_in, amount_in);
         }
+
+        for_each_amm_variant!(self, amm => amm.simulate_swap(token_in, amount_in))
     }
 
     fn simulate_swap_mut(
@@ -122,34 +412,85 @@ impl AutomatedMarketMaker for AMM {
         token_in: H160,
         amount_in: U256,
     ) -> Result<U256, SwapSimulationError> {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.simulate_swap_mut(token_in, amount_in),
-            AMM::UniswapV3Pool(pool) => pool.simulate_swap_mut(token_in, amount_in),
-            AMM::ERC4626Vault(vault) => vault.simulate_swap_mut(token_in, amount_in),
-        }
+        for_each_amm_variant!(self, amm => amm.simulate_swap_mut(token_in, amount_in))
     }
 
-    // fn gradient(&self, token_in: H160, amount_in: U256) -> Result<BigFloat, SwapSimulationError> {
-    //     match self {
-    //         AMM::UniswapV2Pool(pool) => pool.gradient(token_in, amount_in),
-    //         AMM::UniswapV3Pool(pool) => pool.gradient(token_in, amount_in),
-    //         AMM::ERC4626Vault(vault) => vault.gradient(token_in, amount_in),
-    //     }
-    // }
+    fn gradient(&self, token_in: H160, amount_in: U256) -> Result<Q128x128, SwapSimulationError> {
+        for_each_amm_variant!(self, amm => amm.gradient(token_in, amount_in))
+    }
 
     fn get_token_out(&self, token_in: H160) -> H160 {
-        match self {
-            AMM::UniswapV2Pool(pool) => pool.get_token_out(token_in),
-            AMM::UniswapV3Pool(pool) => pool.get_token_out(token_in),
-            AMM::ERC4626Vault(vault) => vault.get_token_out(token_in),
-        }
+        for_each_amm_variant!(self, amm => amm.get_token_out(token_in))
     }
 
     fn opp_token(&self, token_in: H160) -> Option<H160> {
+        for_each_amm_variant!(self, amm => amm.opp_token(token_in))
+    }
+
+    fn other_tokens(&self, token_in: H160) -> Vec<H160> {
+        for_each_amm_variant!(self, amm => amm.other_tokens(token_in))
+    }
+
+    fn simulate_swap_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        for_each_amm_variant!(self, amm => amm.simulate_swap_to(token_in, token_out, amount_in))
+    }
+
+    fn simulate_swap_to_mut(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        for_each_amm_variant!(self, amm => amm.simulate_swap_to_mut(token_in, token_out, amount_in))
+    }
+
+    fn gradient_to(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+    ) -> Result<Q128x128, SwapSimulationError> {
+        for_each_amm_variant!(self, amm => amm.gradient_to(token_in, token_out, amount_in))
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        for_each_amm_variant!(self, amm => amm.tokens())
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        for_each_amm_variant!(self, amm => amm.token_symbol(token))
+    }
+
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        for_each_amm_variant!(self, amm => amm.calculate_price(base_token))
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for AMM {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
         match self {
-            AMM::UniswapV2Pool(pool) => pool.opp_token(token_in),
-            AMM::UniswapV3Pool(pool) => pool.opp_token(token_in),
-            AMM::ERC4626Vault(vault) => vault.opp_token(token_in),
+            AMM::UniswapV2Pool(pool) => pool.sync(middleware).await,
+            AMM::UniswapV3Pool(pool) => pool.sync(middleware).await,
+            AMM::ERC4626Vault(vault) => vault.sync(middleware).await,
+            AMM::CurveStableSwapPool(pool) => pool.sync(middleware).await,
+            AMM::BalancerWeightedPool(pool) => pool.sync(middleware).await,
+            AMM::VelodromePool(pool) => pool.sync(middleware).await,
+            AMM::UniswapV4Pool(pool) => pool.sync(middleware).await,
+            AMM::LiquidityBookPool(pool) => pool.sync(middleware).await,
+            AMM::MaverickPool(pool) => pool.sync(middleware).await,
+            AMM::DodoPool(pool) => pool.sync(middleware).await,
+            AMM::KyberElasticPool(pool) => pool.sync(middleware).await,
+            AMM::AlgebraPool(pool) => pool.sync(middleware).await,
+            AMM::CurveCryptoPool(pool) => pool.sync(middleware).await,
+            AMM::BancorV3Pool(pool) => pool.sync(middleware).await,
+            AMM::Custom(_) => Err(AMMError::CustomAMMOperation),
         }
     }
 
@@ -162,28 +503,453 @@ impl AutomatedMarketMaker for AMM {
             AMM::UniswapV2Pool(pool) => pool.populate_data(None, middleware).await,
             AMM::UniswapV3Pool(pool) => pool.populate_data(block_number, middleware).await,
             AMM::ERC4626Vault(vault) => vault.populate_data(None, middleware).await,
+            AMM::CurveStableSwapPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::BalancerWeightedPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::VelodromePool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::UniswapV4Pool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::LiquidityBookPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::MaverickPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::DodoPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::KyberElasticPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::AlgebraPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::CurveCryptoPool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::BancorV3Pool(pool) => pool.populate_data(block_number, middleware).await,
+            AMM::Custom(_) => Err(AMMError::CustomAMMOperation),
         }
     }
+}
 
-    fn tokens(&self) -> Vec<H160> {
+impl AMM {
+    /// Same as `populate_data`, fetching the data requested by `options`. Token metadata is read from
+    /// `token_store` and only fetched for tokens it does not know yet.
+    #[cfg(feature = "rpc")]
+    pub async fn populate_data_with_options<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+        options: PopulateOptions,
+        token_store: &TokenStore,
+    ) -> Result<(), AMMError<M>> {
+        self.populate_data(block_number, middleware.clone()).await?;
+
+        if options.fetch_symbols {
+            token_store
+                .populate_symbols(&self.tokens(), middleware)
+                .await?;
+            self.set_token_metadata(token_store);
+        }
+
+        Ok(())
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, custom AMMs are left as is
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
         match self {
-            AMM::UniswapV2Pool(pool) => pool.tokens(),
-            AMM::UniswapV3Pool(pool) => pool.tokens(),
-            AMM::ERC4626Vault(vault) => vault.tokens(),
+            AMM::UniswapV2Pool(pool) => pool.set_token_metadata(tokens),
+            AMM::UniswapV3Pool(pool) => pool.set_token_metadata(tokens),
+            AMM::ERC4626Vault(vault) => vault.set_token_metadata(tokens),
+            AMM::CurveStableSwapPool(pool) => pool.set_token_metadata(tokens),
+            AMM::BalancerWeightedPool(pool) => pool.set_token_metadata(tokens),
+            AMM::VelodromePool(pool) => pool.set_token_metadata(tokens),
+            AMM::UniswapV4Pool(pool) => pool.set_token_metadata(tokens),
+            AMM::LiquidityBookPool(pool) => pool.set_token_metadata(tokens),
+            AMM::MaverickPool(pool) => pool.set_token_metadata(tokens),
+            AMM::DodoPool(pool) => pool.set_token_metadata(tokens),
+            AMM::KyberElasticPool(pool) => pool.set_token_metadata(tokens),
+            AMM::AlgebraPool(pool) => pool.set_token_metadata(tokens),
+            AMM::CurveCryptoPool(pool) => pool.set_token_metadata(tokens),
+            AMM::BancorV3Pool(pool) => pool.set_token_metadata(tokens),
+            AMM::Custom(_) => {}
         }
     }
 
-    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+    /// Block the AMM was created at, if it was discovered from its creation log or looked up with `filters::age::populate_creation_blocks`
+    pub fn creation_block(&self) -> Option<u64> {
         match self {
-            AMM::UniswapV2Pool(pool) => pool.calculate_price(base_token),
-            AMM::UniswapV3Pool(pool) => pool.calculate_price(base_token),
-            AMM::ERC4626Vault(vault) => vault.calculate_price(base_token),
+            AMM::UniswapV2Pool(pool) => pool.creation_block,
+            AMM::UniswapV3Pool(pool) => pool.creation_block,
+            AMM::VelodromePool(pool) => pool.creation_block,
+            AMM::UniswapV4Pool(pool) => pool.pool.creation_block,
+            AMM::MaverickPool(pool) => pool.creation_block,
+            AMM::KyberElasticPool(pool) => pool.creation_block,
+            AMM::AlgebraPool(pool) => pool.pool.creation_block,
+            AMM::ERC4626Vault(_)
+            | AMM::CurveStableSwapPool(_)
+            | AMM::BalancerWeightedPool(_)
+            | AMM::LiquidityBookPool(_)
+            | AMM::DodoPool(_)
+            | AMM::CurveCryptoPool(_)
+            | AMM::BancorV3Pool(_)
+            | AMM::Custom(_) => None,
         }
     }
 }
 
+/// AMMs are equal when they have the same address, equality ignores the synced state. Use `AMM::state_eq`
+/// to compare the state as well, or `AmmState::state_fingerprint` to tell whether a pool changed since a snapshot.
 impl PartialEq for AMM {
     fn eq(&self, other: &Self) -> bool {
         self.address() == other.address()
     }
 }
+
+impl Eq for AMM {}
+
+/// Hashes the address, consistent with `PartialEq`
+impl Hash for AMM {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+/// Orders by address, consistent with `PartialEq`
+impl PartialOrd for AMM {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AMM {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.address().cmp(&other.address())
+    }
+}
+
+impl AMM {
+    /// Compares the full synced state of two AMMs, i.e. to assert that two sync paths converged to the same state.
+    /// Caches and the creation block are ignored, custom AMMs are compared by their serialized state.
+    pub fn state_eq(&self, other: &AMM) -> bool {
+        match (self, other) {
+            (AMM::UniswapV2Pool(a), AMM::UniswapV2Pool(b)) => {
+                a.address == b.address
+                    && a.token_a == b.token_a
+                    && a.token_a_decimals == b.token_a_decimals
+                    && a.token_b == b.token_b
+                    && a.token_b_decimals == b.token_b_decimals
+                    && a.reserve_0 == b.reserve_0
+                    && a.reserve_1 == b.reserve_1
+                    && a.fee == b.fee
+                    && a.fee_one_for_zero == b.fee_one_for_zero
+                    && a.stable_swap == b.stable_swap
+                    && a.fee_change_event == b.fee_change_event
+                    && a.token_a_transfer_tax == b.token_a_transfer_tax
+                    && a.token_b_transfer_tax == b.token_b_transfer_tax
+            }
+            (AMM::UniswapV3Pool(a), AMM::UniswapV3Pool(b)) => uniswap_v3_state_eq(a, b),
+            (AMM::ERC4626Vault(a), AMM::ERC4626Vault(b)) => {
+                a.vault_token == b.vault_token
+                    && a.vault_token_decimals == b.vault_token_decimals
+                    && a.asset_token == b.asset_token
+                    && a.asset_token_decimals == b.asset_token_decimals
+                    && a.vault_reserve == b.vault_reserve
+                    && a.asset_reserve == b.asset_reserve
+                    && a.deposit_fee == b.deposit_fee
+                    && a.withdraw_fee == b.withdraw_fee
+            }
+            (AMM::CurveStableSwapPool(a), AMM::CurveStableSwapPool(b)) => {
+                a.address == b.address
+                    && a.coins == b.coins
+                    && a.decimals == b.decimals
+                    && a.balances == b.balances
+                    && a.a == b.a
+                    && a.fee == b.fee
+                    && a.admin_fee == b.admin_fee
+            }
+            (AMM::BalancerWeightedPool(a), AMM::BalancerWeightedPool(b)) => {
+                a.address == b.address
+                    && a.pool_id == b.pool_id
+                    && a.vault == b.vault
+                    && a.tokens == b.tokens
+                    && a.decimals == b.decimals
+                    && a.balances == b.balances
+                    && a.weights == b.weights
+                    && a.swap_fee == b.swap_fee
+            }
+            (AMM::VelodromePool(a), AMM::VelodromePool(b)) => {
+                a.address == b.address
+                    && a.token_a == b.token_a
+                    && a.token_a_decimals == b.token_a_decimals
+                    && a.token_b == b.token_b
+                    && a.token_b_decimals == b.token_b_decimals
+                    && a.reserve_0 == b.reserve_0
+                    && a.reserve_1 == b.reserve_1
+                    && a.stable == b.stable
+                    && a.fee == b.fee
+            }
+            (AMM::UniswapV4Pool(a), AMM::UniswapV4Pool(b)) => {
+                a.pool_manager == b.pool_manager
+                    && a.pool_id == b.pool_id
+                    && a.key_fee == b.key_fee
+                    && a.hooks == b.hooks
+                    && a.protocol_fee == b.protocol_fee
+                    && uniswap_v3_state_eq(&a.pool, &b.pool)
+            }
+            (AMM::LiquidityBookPool(a), AMM::LiquidityBookPool(b)) => {
+                a.address == b.address
+                    && a.token_a == b.token_a
+                    && a.token_a_decimals == b.token_a_decimals
+                    && a.token_b == b.token_b
+                    && a.token_b_decimals == b.token_b_decimals
+                    && a.bin_step == b.bin_step
+                    && a.active_id == b.active_id
+                    && a.bins == b.bins
+                    && a.bin_range == b.bin_range
+                    && a.static_fee_parameters == b.static_fee_parameters
+                    && a.variable_fee_parameters == b.variable_fee_parameters
+            }
+            (AMM::MaverickPool(a), AMM::MaverickPool(b)) => {
+                a.address == b.address
+                    && a.token_a == b.token_a
+                    && a.token_a_decimals == b.token_a_decimals
+                    && a.token_b == b.token_b
+                    && a.token_b_decimals == b.token_b_decimals
+                    && a.fee == b.fee
+                    && a.tick_spacing == b.tick_spacing
+                    && a.active_tick == b.active_tick
+                    && a.protocol_fee_ratio == b.protocol_fee_ratio
+                    && a.bins == b.bins
+            }
+            (AMM::DodoPool(a), AMM::DodoPool(b)) => {
+                a.address == b.address
+                    && a.token_a == b.token_a
+                    && a.token_a_decimals == b.token_a_decimals
+                    && a.token_b == b.token_b
+                    && a.token_b_decimals == b.token_b_decimals
+                    && a.state == b.state
+                    && a.lp_fee_rate == b.lp_fee_rate
+                    && a.mt_fee_rate == b.mt_fee_rate
+            }
+            (AMM::KyberElasticPool(a), AMM::KyberElasticPool(b)) => {
+                a.address == b.address
+                    && a.token_a == b.token_a
+                    && a.token_a_decimals == b.token_a_decimals
+                    && a.token_b == b.token_b
+                    && a.token_b_decimals == b.token_b_decimals
+                    && a.fee == b.fee
+                    && a.tick_distance == b.tick_distance
+                    && a.sqrt_price == b.sqrt_price
+                    && a.tick == b.tick
+                    && a.base_liquidity == b.base_liquidity
+                    && a.reinvest_liquidity == b.reinvest_liquidity
+                    && a.ticks == b.ticks
+                    && a.tick_range == b.tick_range
+            }
+            (AMM::AlgebraPool(a), AMM::AlgebraPool(b)) => {
+                a.fee_one_for_zero == b.fee_one_for_zero && uniswap_v3_state_eq(&a.pool, &b.pool)
+            }
+            (AMM::CurveCryptoPool(a), AMM::CurveCryptoPool(b)) => {
+                a.address == b.address
+                    && a.coins == b.coins
+                    && a.decimals == b.decimals
+                    && a.balances == b.balances
+                    && a.a == b.a
+                    && a.gamma == b.gamma
+                    && a.price_scale == b.price_scale
+                    && a.d == b.d
+                    && a.fee_gamma == b.fee_gamma
+                    && a.mid_fee == b.mid_fee
+                    && a.out_fee == b.out_fee
+            }
+            (AMM::BancorV3Pool(a), AMM::BancorV3Pool(b)) => {
+                a.address == b.address
+                    && a.network == b.network
+                    && a.network_info == b.network_info
+                    && a.bnt == b.bnt
+                    && a.token == b.token
+                    && a.token_decimals == b.token_decimals
+                    && a.bnt_trading_liquidity == b.bnt_trading_liquidity
+                    && a.base_token_trading_liquidity == b.base_token_trading_liquidity
+                    && a.staked_balance == b.staked_balance
+                    && a.trading_fee_ppm == b.trading_fee_ppm
+                    && a.trading_enabled == b.trading_enabled
+            }
+            (AMM::Custom(a), AMM::Custom(b)) => {
+                a.protocol() == b.protocol()
+                    && a.address() == b.address()
+                    && matches!(
+                        (a.serialize_state(), b.serialize_state()),
+                        (Ok(a), Ok(b)) if a == b
+                    )
+            }
+            _ => false,
+        }
+    }
+}
+
+//Uniswap V4 pools hold their concentrated liquidity as a V3 pool
+fn uniswap_v3_state_eq(a: &UniswapV3Pool, b: &UniswapV3Pool) -> bool {
+    a.address == b.address
+        && a.token_a == b.token_a
+        && a.token_a_decimals == b.token_a_decimals
+        && a.token_b == b.token_b
+        && a.token_b_decimals == b.token_b_decimals
+        && a.liquidity == b.liquidity
+        && a.sqrt_price == b.sqrt_price
+        && a.fee == b.fee
+        && a.tick == b.tick
+        && a.tick_spacing == b.tick_spacing
+        && a.tick_bitmap == b.tick_bitmap
+        && a.ticks == b.ticks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashSet};
+
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, U256},
+    };
+
+    use super::{
+        erc_4626::ERC4626Vault,
+        uniswap_v2::{UniswapV2Pool, SYNC_EVENT_SIGNATURE},
+        uniswap_v3::{Info, UniswapV3Pool},
+        AmmState, AMM,
+    };
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    fn pool(address: u64, reserve_0: u128) -> eyre::Result<AMM> {
+        Ok(AMM::UniswapV2Pool(
+            UniswapV2Pool::builder()
+                .with_address(H160::from_low_u64_be(address))
+                .with_token_a(H160::from_low_u64_be(100), 18)
+                .with_token_b(H160::from_low_u64_be(101), 6)
+                .with_reserves(reserve_0, 1_000_000)
+                .build()?,
+        ))
+    }
+
+    #[test]
+    fn test_identity_by_address() -> eyre::Result<()> {
+        let (a, a_synced, b) = (pool(1, 1000)?, pool(1, 2000)?, pool(2, 1000)?);
+
+        //Equality, hashing and ordering ignore the state
+        assert_eq!(a, a_synced);
+        assert!(!a.state_eq(&a_synced));
+        assert!(a.state_eq(&a.clone()));
+        assert_eq!(
+            HashSet::from([a.clone(), a_synced.clone(), b.clone()]).len(),
+            2
+        );
+
+        let ordered = BTreeSet::from([b.clone(), a_synced, a.clone()]);
+        assert_eq!(
+            ordered.iter().map(|amm| amm.address()).collect::<Vec<_>>(),
+            vec![a.address(), b.address()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_eq_directional_fees() -> eyre::Result<()> {
+        let a = pool(1, 1000)?;
+        let mut b = a.clone();
+        if let AMM::UniswapV2Pool(pool) = &mut b {
+            pool.fee_one_for_zero = Some(100);
+        }
+
+        //A pair differing only by the fee of one direction has not converged
+        assert!(!a.state_eq(&b));
+        assert_ne!(a.state_fingerprint(), b.state_fingerprint());
+
+        let mut stable = a.clone();
+        if let AMM::UniswapV2Pool(pool) = &mut stable {
+            pool.stable_swap = true;
+        }
+        assert!(!a.state_eq(&stable));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_fingerprint() -> eyre::Result<()> {
+        let mut amm = pool(1, 1000)?;
+        let fingerprint = amm.state_fingerprint();
+
+        //Stable across copies and storage, and independent of the address
+        assert_eq!(amm.clone().state_fingerprint(), fingerprint);
+        let stored: AMM = serde_json::from_str(&serde_json::to_string(&amm)?)?;
+        assert_eq!(stored.state_fingerprint(), fingerprint);
+        assert_eq!(pool(2, 1000)?.state_fingerprint(), fingerprint);
+        assert_ne!(pool(1, 1001)?.state_fingerprint(), fingerprint);
+
+        //Quotes leave it unchanged, swaps change it
+        let token_a = H160::from_low_u64_be(100);
+        amm.simulate_swap(token_a, U256::from(10))?;
+        assert_eq!(amm.state_fingerprint(), fingerprint);
+        amm.simulate_swap_mut(token_a, U256::from(10))?;
+        assert_ne!(amm.state_fingerprint(), fingerprint);
+
+        //Syncing back to the original reserves restores it
+        amm.sync_from_log(&Log {
+            address: amm.address(),
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(1000)),
+                Token::Uint(U256::from(1_000_000)),
+            ])
+            .into(),
+            ..Default::default()
+        })?;
+        assert_eq!(amm.state_fingerprint(), fingerprint);
+
+        let mut vault = ERC4626Vault::new(
+            H160::from_low_u64_be(1),
+            18,
+            H160::from_low_u64_be(2),
+            6,
+            U256::exp10(18),
+            U256::exp10(6),
+            0,
+            0,
+        );
+        let fingerprint = vault.state_fingerprint();
+        vault.set_totals(U256::exp10(6), U256::exp10(18))?;
+        assert_eq!(vault.state_fingerprint(), fingerprint);
+        vault.set_totals(2 * U256::exp10(6), U256::exp10(18))?;
+        assert_ne!(vault.state_fingerprint(), fingerprint);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uniswap_v3_state_fingerprint() -> eyre::Result<()> {
+        let pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let fingerprint = pool.state_fingerprint();
+
+        let mut swapped = pool.clone();
+        swapped.simulate_swap_mut(pool.token_a, U256::exp10(9))?;
+        assert_ne!(swapped.state_fingerprint(), fingerprint);
+
+        //The tick map is part of the state, uninitialized ticks are not
+        let mut ticks_changed = pool.clone();
+        let tick = pool
+            .ticks
+            .iter()
+            .find_map(|(tick, info)| info.initialized.then_some(*tick))
+            .expect("fixture has initialized ticks");
+        ticks_changed
+            .ticks
+            .get_mut(&tick)
+            .expect("tick is loaded")
+            .liquidity_net += 1;
+        assert_ne!(ticks_changed.state_fingerprint(), fingerprint);
+
+        let mut uninitialized = pool.clone();
+        uninitialized
+            .ticks
+            .insert(pool.tick + 1_000_000, Info::default());
+        assert_eq!(uninitialized.state_fingerprint(), fingerprint);
+
+        //The protocol is part of the fingerprint, a V3 pool never matches a V2 pool
+        assert_ne!(
+            UniswapV3Pool::default().state_fingerprint(),
+            UniswapV2Pool::default().state_fingerprint()
+        );
+
+        Ok(())
+    }
+}
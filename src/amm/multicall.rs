@@ -0,0 +1,439 @@
+//! Reads of the batch requests through Multicall3, for chains and providers rejecting constructor calls.
+//!
+//! Batch requests `eth_call` the undeployed bytecode of a contract reading every pool in its constructor. zkSync Era
+//! and some providers reject calls without a `to` address or with large init code, in which case the same reads are
+//! made through the Multicall3 contract deployed on the chain and decoded into the output of the batch contract.
+
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::{
+    contract::{multicall_contract::Call3, MulticallContract, MULTICALL_ADDRESS},
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::errors::AMMError;
+
+//Number of calls aggregated into each multicall
+const MULTICALL_STEP: usize = 500;
+
+//Lowercase fragments of the errors returned by providers rejecting constructor calls
+const CONSTRUCTOR_CALL_REJECTIONS: &[&str] = &[
+    "initcode",
+    "init code",
+    "toaddressisnull",
+    "contract creation",
+    "max code size",
+    "missing to address",
+];
+
+/// How batch requests read pool data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchRequestMode {
+    /// Constructor calls, falling back to Multicall3 if the provider rejects them
+    #[default]
+    Auto,
+    /// Only constructor calls of the batch contracts
+    ConstructorCall,
+    /// Only Multicall3, skipping the constructor call on chains known to reject it
+    Multicall,
+}
+
+/// Whether `error` comes from a provider rejecting a constructor call, rather than from the batch contract itself
+pub fn is_constructor_call_rejected<M: Middleware>(error: &AMMError<M>) -> bool {
+    match error {
+        AMMError::ContractError(error) => {
+            let message = error.to_string().to_lowercase();
+            CONSTRUCTOR_CALL_REJECTIONS
+                .iter()
+                .any(|rejection| message.contains(rejection))
+        }
+        _ => false,
+    }
+}
+
+/// Call to `selector` on `target` with ABI encoded `args`, allowed to fail
+pub(crate) fn call(target: H160, selector: [u8; 4], args: &[u8]) -> Call3 {
+    Call3 {
+        target,
+        allow_failure: true,
+        call_data: [selector.as_slice(), args].concat().into(),
+    }
+}
+
+/// Runs `calls` through Multicall3 at `block_number`, returning the return data of each call or None if it failed
+pub(crate) async fn aggregate<M: Middleware>(
+    calls: Vec<Call3>,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<Option<Bytes>>, AMMError<M>> {
+    let multicall = MulticallContract::new(MULTICALL_ADDRESS, middleware);
+    let mut return_data = Vec::with_capacity(calls.len());
+
+    for chunk in calls.chunks(MULTICALL_STEP) {
+        let mut aggregate_call = multicall.aggregate_3(chunk.to_vec());
+        if let Some(block_number) = block_number {
+            aggregate_call = aggregate_call.block(block_number);
+        }
+
+        for result in aggregate_call.call().await? {
+            return_data.push(result.success.then_some(result.return_data));
+        }
+    }
+
+    Ok(return_data)
+}
+
+/// Reads the decimals of `tokens`, leaving out tokens where `decimals()` fails or returns more than 255
+pub(crate) async fn get_decimals<M: Middleware>(
+    tokens: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<HashMap<H160, u8>, AMMError<M>> {
+    let mut tokens = tokens.to_vec();
+    tokens.sort();
+    tokens.dedup();
+
+    let calls = tokens
+        .iter()
+        .map(|token| call(*token, crate::tokens::DECIMALS_SELECTOR, &[]))
+        .collect();
+
+    Ok(tokens
+        .into_iter()
+        .zip(aggregate(calls, block_number, middleware).await?)
+        .filter_map(|(token, return_data)| {
+            let decimals = word(return_data.as_ref()?, 0)?;
+            (decimals <= U256::from(u8::MAX)).then(|| (token, decimals.as_u32() as u8))
+        })
+        .collect())
+}
+
+/// Word `index` of ABI encoded return data
+pub(crate) fn word(data: &Bytes, index: usize) -> Option<U256> {
+    data.get(index * 32..(index + 1) * 32)
+        .map(U256::from_big_endian)
+}
+
+/// Address held in word `index` of ABI encoded return data
+pub(crate) fn address_word(data: &Bytes, index: usize) -> Option<H160> {
+    data.get(index * 32 + 12..(index + 1) * 32)
+        .map(H160::from_slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use async_trait::async_trait;
+    use ethers::{
+        abi::{ParamType, Token},
+        contract::MULTICALL_ADDRESS,
+        providers::{JsonRpcClient, JsonRpcError, MockError, Provider},
+        types::{Bytes, H160, I256, U256},
+    };
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use crate::amm::{
+        uniswap_v2::{self, UniswapV2Pool},
+        uniswap_v3::{self, UniswapV3Pool},
+        AMM,
+    };
+
+    use super::BatchRequestMode;
+
+    //aggregate3((address,bool,bytes)[])
+    const AGGREGATE_3_SELECTOR: [u8; 4] = [130, 173, 86, 203];
+
+    //Node answering the calls to `contracts` through Multicall3, and constructor calls with `constructor_call`
+    #[derive(Debug)]
+    struct MockNode {
+        contracts: HashMap<(H160, [u8; 4]), Vec<Token>>,
+        //Return data of the batch contract, None to reject constructor calls like zkSync Era
+        constructor_call: Option<Vec<Token>>,
+    }
+
+    impl MockNode {
+        fn aggregate_3(&self, call_data: &[u8]) -> Bytes {
+            assert_eq!(call_data[..4], AGGREGATE_3_SELECTOR);
+
+            let calls = ethers::abi::decode(
+                &[ParamType::Array(Box::new(ParamType::Tuple(vec![
+                    ParamType::Address,
+                    ParamType::Bool,
+                    ParamType::Bytes,
+                ])))],
+                &call_data[4..],
+            )
+            .expect("aggregate3 calls");
+
+            let results = calls[0]
+                .clone()
+                .into_array()
+                .expect("aggregate3 calls")
+                .into_iter()
+                .map(|call| {
+                    let call = call.into_tuple().expect("aggregate3 call");
+                    let target = call[0].clone().into_address().expect("call target");
+                    let call_data = call[2].clone().into_bytes().expect("call data");
+                    let selector = [call_data[0], call_data[1], call_data[2], call_data[3]];
+
+                    match self.contracts.get(&(target, selector)) {
+                        Some(return_data) => Token::Tuple(vec![
+                            Token::Bool(true),
+                            Token::Bytes(ethers::abi::encode(return_data)),
+                        ]),
+                        None => Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+                    }
+                })
+                .collect();
+
+            ethers::abi::encode(&[Token::Array(results)]).into()
+        }
+    }
+
+    #[async_trait]
+    impl JsonRpcClient for MockNode {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+        where
+            T: std::fmt::Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            assert_eq!(method, "eth_call");
+
+            let params = serde_json::to_value(params)?;
+            let call_data: Bytes = serde_json::from_value(params[0]["data"].clone())?;
+
+            let return_data = match params[0].get("to") {
+                Some(to) => {
+                    assert_eq!(
+                        serde_json::from_value::<H160>(to.clone())?,
+                        MULTICALL_ADDRESS
+                    );
+                    self.aggregate_3(&call_data)
+                }
+                None => match &self.constructor_call {
+                    Some(return_data) => ethers::abi::encode(return_data).into(),
+                    None => {
+                        return Err(MockError::JsonRpcError(JsonRpcError {
+                            code: -32000,
+                            message: "max initcode size exceeded".to_string(),
+                            data: None,
+                        }))
+                    }
+                },
+            };
+
+            Ok(serde_json::from_value(serde_json::to_value(return_data)?)?)
+        }
+    }
+
+    fn address(address: u64) -> H160 {
+        H160::from_low_u64_be(address)
+    }
+
+    fn uint(value: u64) -> Token {
+        Token::Uint(U256::from(value))
+    }
+
+    //Pool 10 trades token 1 with 18 decimals against token 2 with 6 decimals, pool 11 does not exist
+    fn token_contracts() -> HashMap<(H160, [u8; 4]), Vec<Token>> {
+        HashMap::from([
+            (
+                (address(1), crate::tokens::DECIMALS_SELECTOR),
+                vec![uint(18)],
+            ),
+            (
+                (address(2), crate::tokens::DECIMALS_SELECTOR),
+                vec![uint(6)],
+            ),
+            (
+                (address(10), [13, 254, 22, 129]),
+                vec![Token::Address(address(1))],
+            ),
+            (
+                (address(10), [210, 18, 32, 167]),
+                vec![Token::Address(address(2))],
+            ),
+        ])
+    }
+
+    fn empty_row(len: usize) -> Token {
+        Token::Tuple(
+            [Token::Address(H160::zero())]
+                .into_iter()
+                .chain((1..len).map(|_| uint(0)))
+                .collect(),
+        )
+    }
+
+    async fn sync_amms(
+        mut amms: Vec<AMM>,
+        contracts: &HashMap<(H160, [u8; 4]), Vec<Token>>,
+        constructor_call: Option<Vec<Token>>,
+        mode: BatchRequestMode,
+    ) -> eyre::Result<serde_json::Value> {
+        let middleware = Arc::new(Provider::new(MockNode {
+            contracts: contracts.clone(),
+            constructor_call,
+        }));
+
+        match amms[0] {
+            AMM::UniswapV2Pool(_) => {
+                uniswap_v2::batch_request::get_amm_data_batch_request_with_mode(
+                    &mut amms, mode, middleware,
+                )
+                .await?
+            }
+            _ => {
+                uniswap_v3::batch_request::get_amm_data_batch_request_with_mode(
+                    &mut amms, 100, mode, middleware,
+                )
+                .await?
+            }
+        }
+
+        Ok(serde_json::to_value(amms)?)
+    }
+
+    #[tokio::test]
+    async fn test_uniswap_v2_multicall_fallback() -> eyre::Result<()> {
+        let mut contracts = token_contracts();
+        contracts.insert(
+            (address(10), [9, 2, 241, 172]),
+            vec![uint(1000), uint(2000), uint(1690000000)],
+        );
+
+        let amms = [10, 11].map(|pool| {
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: address(pool),
+                fee: 300,
+                ..Default::default()
+            })
+        });
+        let batch_contract_output = vec![Token::Array(vec![
+            Token::Tuple(vec![
+                Token::Address(address(1)),
+                uint(18),
+                Token::Address(address(2)),
+                uint(6),
+                uint(1000),
+                uint(2000),
+            ]),
+            empty_row(6),
+        ])];
+
+        let constructor_call = sync_amms(
+            amms.to_vec(),
+            &contracts,
+            Some(batch_contract_output),
+            BatchRequestMode::Auto,
+        )
+        .await?;
+        let fallback = sync_amms(amms.to_vec(), &contracts, None, BatchRequestMode::Auto).await?;
+        let multicall =
+            sync_amms(amms.to_vec(), &contracts, None, BatchRequestMode::Multicall).await?;
+
+        assert_eq!(fallback, constructor_call);
+        assert_eq!(multicall, constructor_call);
+
+        let amms: Vec<AMM> = serde_json::from_value(fallback)?;
+        match &amms[..] {
+            [AMM::UniswapV2Pool(pool), AMM::UniswapV2Pool(missing_pool)] => {
+                assert_eq!(pool.token_a, address(1));
+                assert_eq!(pool.token_b_decimals, 6);
+                assert_eq!((pool.reserve_0, pool.reserve_1), (1000, 2000));
+                assert!(missing_pool.token_a.is_zero());
+            }
+            _ => unreachable!(),
+        }
+
+        //Constructor calls are not retried through Multicall3 when requested explicitly
+        assert!(sync_amms(
+            amms.to_vec(),
+            &contracts,
+            None,
+            BatchRequestMode::ConstructorCall
+        )
+        .await
+        .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_uniswap_v3_multicall_fallback() -> eyre::Result<()> {
+        let sqrt_price = U256::from(2).pow(U256::from(96));
+        let tick = Token::Int(I256::from(-100).into_raw());
+
+        let mut contracts = token_contracts();
+        contracts.extend([
+            ((address(10), [26, 104, 101, 2]), vec![uint(5000000)]),
+            (
+                (address(10), [56, 80, 199, 189]),
+                vec![
+                    Token::Uint(sqrt_price),
+                    tick.clone(),
+                    uint(0),
+                    uint(1),
+                    uint(1),
+                    uint(0),
+                    Token::Bool(true),
+                ],
+            ),
+            (
+                (address(10), [208, 201, 58, 124]),
+                vec![Token::Int(I256::from(60).into_raw())],
+            ),
+            ((address(10), [221, 202, 63, 67]), vec![uint(3000)]),
+        ]);
+
+        let amms = [10, 11].map(|pool| {
+            AMM::UniswapV3Pool(UniswapV3Pool {
+                address: address(pool),
+                ..Default::default()
+            })
+        });
+        let batch_contract_output = vec![Token::Array(vec![
+            Token::Tuple(vec![
+                Token::Address(address(1)),
+                uint(18),
+                Token::Address(address(2)),
+                uint(6),
+                uint(5000000),
+                Token::Uint(sqrt_price),
+                tick,
+                Token::Int(I256::from(60).into_raw()),
+                uint(3000),
+                Token::Int(I256::from(-7).into_raw()),
+            ]),
+            empty_row(10),
+        ])];
+
+        let constructor_call = sync_amms(
+            amms.to_vec(),
+            &contracts,
+            Some(batch_contract_output),
+            BatchRequestMode::Auto,
+        )
+        .await?;
+        let fallback = sync_amms(amms.to_vec(), &contracts, None, BatchRequestMode::Auto).await?;
+
+        assert_eq!(fallback, constructor_call);
+
+        let amms: Vec<AMM> = serde_json::from_value(fallback)?;
+        match &amms[..] {
+            [AMM::UniswapV3Pool(pool), AMM::UniswapV3Pool(missing_pool)] => {
+                assert_eq!(pool.token_a_decimals, 18);
+                assert_eq!(pool.sqrt_price, sqrt_price);
+                assert_eq!((pool.tick, pool.tick_spacing, pool.fee), (-100, 60, 3000));
+                assert!(missing_pool.token_a.is_zero());
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use ethers::types::{H160, U256};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::errors::SwapSimulationError;
+
+use super::{AmmState, AMM};
+
+/// Simulates swapping `amount_in` of `token_in` on every AMM, in parallel with the `parallel` feature.
+///
+/// Results are in the order of `amms`. AMMs that do not hold `token_in` yield `SwapSimulationError::TokenNotInPool`,
+/// and an AMM failing to simulate only fails its own entry.
+pub fn simulate_all(
+    amms: &[AMM],
+    token_in: H160,
+    amount_in: U256,
+) -> Vec<Result<U256, SwapSimulationError>> {
+    let simulate = |amm: &AMM| {
+        if amm.tokens().contains(&token_in) {
+            amm.simulate_swap(token_in, amount_in)
+        } else {
+            Err(SwapSimulationError::TokenNotInPool(token_in))
+        }
+    };
+
+    #[cfg(feature = "parallel")]
+    let amms = amms.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let amms = amms.iter();
+
+    amms.map(simulate).collect()
+}
+
+/// Index in `amms` and output of the AMM paying the most `token_out` for `amount_in` of `token_in`.
+///
+/// Only AMMs holding both tokens are simulated, AMMs that fail to simulate or return nothing are skipped.
+/// Ties go to the lowest index, so the result does not depend on scheduling.
+pub fn best_quote(
+    amms: &[AMM],
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+) -> Option<(usize, U256)> {
+    let quote = |(idx, amm): (usize, &AMM)| {
+        let tokens = amm.tokens();
+        if token_in == token_out || !tokens.contains(&token_in) || !tokens.contains(&token_out) {
+            return None;
+        }
+
+        amm.simulate_swap(token_in, amount_in)
+            .ok()
+            .filter(|amount_out| !amount_out.is_zero())
+            .map(|amount_out| (idx, amount_out))
+    };
+    let better = |a: &(usize, U256), b: &(usize, U256)| a.1.cmp(&b.1).then(b.0.cmp(&a.0));
+
+    #[cfg(feature = "parallel")]
+    let amms = amms.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let amms = amms.iter();
+
+    amms.enumerate().filter_map(quote).max_by(better)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, AmmState, AMM},
+        errors::SwapSimulationError,
+    };
+
+    use super::{best_quote, simulate_all};
+
+    fn pool(address: u64, token_b: u64, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: H160::from_low_u64_be(1),
+            token_b: H160::from_low_u64_be(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_simulate_all_preserves_order() -> eyre::Result<()> {
+        let token_in = H160::from_low_u64_be(1);
+        let amount_in = U256::exp10(18);
+
+        let amms = (0..1000)
+            .map(|i| {
+                pool(
+                    100 + i,
+                    2 + i % 3,
+                    10_u128.pow(24),
+                    (i as u128 + 1) * 10_u128.pow(21),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let results = simulate_all(&amms, token_in, amount_in);
+
+        assert_eq!(results.len(), amms.len());
+        for (amm, result) in amms.iter().zip(results) {
+            assert_eq!(result?, amm.simulate_swap(token_in, amount_in)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_amms_do_not_poison_the_batch() -> eyre::Result<()> {
+        let token_in = H160::from_low_u64_be(1);
+        let amount_in = U256::exp10(18);
+
+        let amms = vec![
+            pool(100, 2, 10_u128.pow(24), 10_u128.pow(24)),
+            //Does not hold the input token
+            AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: H160::from_low_u64_be(3),
+                asset_token: H160::from_low_u64_be(4),
+                ..Default::default()
+            }),
+            pool(101, 2, 10_u128.pow(24), 2 * 10_u128.pow(24)),
+        ];
+
+        let results = simulate_all(&amms, token_in, amount_in);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(SwapSimulationError::TokenNotInPool(token)) if token == token_in
+        ));
+        assert!(results[2].is_ok());
+
+        assert_eq!(
+            best_quote(&amms, token_in, H160::from_low_u64_be(2), amount_in),
+            Some((2, results[2].as_ref().copied().unwrap()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_best_quote() {
+        let token_in = H160::from_low_u64_be(1);
+        let token_out = H160::from_low_u64_be(2);
+        let amount_in = U256::exp10(18);
+
+        let amms = vec![
+            pool(100, 2, 10_u128.pow(24), 10_u128.pow(24)),
+            //Best price, but for another pair
+            pool(101, 3, 10_u128.pow(24), 10_u128.pow(26)),
+            pool(102, 2, 10_u128.pow(24), 3 * 10_u128.pow(24)),
+            pool(103, 2, 10_u128.pow(24), 3 * 10_u128.pow(24)),
+            //Empty pool
+            pool(104, 2, 0, 0),
+        ];
+
+        let (idx, amount_out) = best_quote(&amms, token_in, token_out, amount_in).unwrap();
+        assert_eq!(idx, 2);
+        assert_eq!(
+            amount_out,
+            amms[2].simulate_swap(token_in, amount_in).unwrap()
+        );
+
+        assert_eq!(best_quote(&amms[4..], token_in, token_out, amount_in), None);
+        assert_eq!(best_quote(&amms, token_in, token_in, amount_in), None);
+        assert_eq!(best_quote(&[], token_in, token_out, amount_in), None);
+    }
+}
@@ -0,0 +1,639 @@
+use std::fmt;
+
+use ethers::types::H160;
+
+use crate::{
+    filters::dedupe::Protocol,
+    math::fixed_point::u256_to_f64_lossy,
+    state_space::price::{
+        balancer_weighted_depth, bancor_v3_depth, curve_crypto_depth, curve_stable_swap_depth,
+        dodo_depth, erc_4626_depth, kyber_elastic_depth, liquidity_book_depth, maverick_depth,
+        uniswap_v2_depth, uniswap_v3_depth, velodrome_depth, DepthWeighting,
+    },
+    tokens::TokenStore,
+};
+
+use super::{
+    algebra::{self, AlgebraPool},
+    balancer::{self, BalancerWeightedPool},
+    bancor_v3::{self, BancorV3Pool},
+    curve::{self, CurveStableSwapPool},
+    curve_crypto::{self, CurveCryptoPool},
+    dodo::{self, DodoPool},
+    erc_4626::ERC4626Vault,
+    kyber_elastic::{self, KyberElasticPool},
+    liquidity_book::{self, LiquidityBookPool},
+    maverick::{self, MaverickPool},
+    uniswap_v2::{UniswapV2Pool, FEE_DENOMINATOR},
+    uniswap_v3::UniswapV3Pool,
+    uniswap_v4::UniswapV4Pool,
+    velodrome::{self, VelodromePool},
+    AmmState, AMM,
+};
+
+/// One line overview of an AMM, for logs and error context. `Display` prints it as
+/// `uniswap_v3 0x88e6a0c2ddd26feeb64f039a2c41296fcb3f5640 USDC/WETH fee 0.05% price 0.000531 depth 1021.3`,
+/// leaving out what is unknown and the addresses of tokens without a symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmmSummary {
+    /// Protocol name, as in `Protocol::name` or `CustomAutomatedMarketMaker::protocol`
+    pub protocol: &'static str,
+    pub address: H160,
+    pub tokens: Vec<H160>,
+    /// Symbol of each token, from the AMM or filled by `with_symbols`
+    pub symbols: Vec<Option<String>>,
+    /// Fee as a fraction of the amount in, the deposit fee for ERC4626 vaults
+    pub fee: Option<f64>,
+    /// Price of a whole first token in whole units of the second
+    pub price: Option<f64>,
+    /// Depth as in `aggregate_price`, in whole units of the second token
+    pub depth: Option<f64>,
+    /// Block the AMM was last synced at, pools do not track it so it is set by `with_block`
+    pub last_synced_block: Option<u64>,
+}
+
+impl AmmSummary {
+    fn new<A: AmmState + ?Sized>(amm: &A, protocol: &'static str, fee: Option<f64>) -> Self {
+        let tokens = amm.tokens();
+        let price = tokens
+            .first()
+            .and_then(|token| amm.calculate_price(*token).ok())
+            .filter(|price| price.is_finite());
+
+        AmmSummary {
+            protocol,
+            address: amm.address(),
+            symbols: tokens
+                .iter()
+                .map(|token| amm.token_symbol(*token).map(str::to_owned))
+                .collect(),
+            tokens,
+            fee,
+            price,
+            depth: None,
+            last_synced_block: None,
+        }
+    }
+
+    /// Fills the symbols of the tokens found in `tokens`, over the symbols stored on the AMM
+    pub fn with_symbols(mut self, tokens: &TokenStore) -> Self {
+        for (token, symbol) in self.tokens.iter().zip(self.symbols.iter_mut()) {
+            if let Some(token_symbol) = tokens.get(*token).and_then(|info| info.symbol) {
+                *symbol = Some(token_symbol);
+            }
+        }
+        self
+    }
+
+    pub fn with_block(mut self, block_number: u64) -> Self {
+        self.last_synced_block = Some(block_number);
+        self
+    }
+
+    //Depth in raw units of the second token, scaled to whole units
+    fn with_depth(mut self, depth: Option<f64>, token_b_decimals: u8) -> Self {
+        self.depth = depth.map(|depth| depth / 10_f64.powi(token_b_decimals as i32));
+        self
+    }
+}
+
+impl fmt::Display for AmmSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?}", self.protocol, self.address)?;
+
+        for (i, (token, symbol)) in self.tokens.iter().zip(self.symbols.iter()).enumerate() {
+            let separator = if i == 0 { " " } else { "/" };
+            match symbol {
+                Some(symbol) => write!(f, "{separator}{symbol}")?,
+                None => write!(f, "{separator}{token:?}")?,
+            }
+        }
+
+        if let Some(fee) = self.fee {
+            write!(f, " fee {}%", Significant(fee * 100.0))?;
+        }
+        if let Some(price) = self.price {
+            write!(f, " price {}", Significant(price))?;
+        }
+        if let Some(depth) = self.depth {
+            write!(f, " depth {}", Significant(depth))?;
+        }
+        if let Some(block_number) = self.last_synced_block {
+            write!(f, " block {block_number}")?;
+        }
+
+        Ok(())
+    }
+}
+
+//Six significant digits without trailing zeros, in scientific notation for very small or very large values
+struct Significant(f64);
+
+impl fmt::Display for Significant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.0;
+        if value == 0.0 || !value.is_finite() {
+            return write!(f, "{value}");
+        }
+
+        let magnitude = value.abs().log10().floor() as i32;
+        if !(-4..12).contains(&magnitude) {
+            return write!(f, "{value:.5e}");
+        }
+
+        let precision = (5 - magnitude).max(0) as usize;
+        let formatted = format!("{value:.precision$}");
+        if formatted.contains('.') {
+            f.write_str(formatted.trim_end_matches('0').trim_end_matches('.'))
+        } else {
+            f.write_str(&formatted)
+        }
+    }
+}
+
+impl UniswapV2Pool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::UniswapV2.name(),
+            Some(self.fee as f64 / FEE_DENOMINATOR as f64),
+        )
+        .with_depth(
+            Some(uniswap_v2_depth(self, self.token_b)),
+            self.token_b_decimals,
+        )
+    }
+}
+
+impl UniswapV3Pool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::UniswapV3.name(),
+            Some(self.fee as f64 / 1e6),
+        )
+        .with_depth(
+            uniswap_v3_depth(self, self.token_b, DepthWeighting::default().tick_range),
+            self.token_b_decimals,
+        )
+    }
+
+    /// Debug output without the tick data, which is replaced by its size
+    pub fn debug_brief(&self) -> impl fmt::Debug + '_ {
+        BriefUniswapV3Pool(self)
+    }
+}
+
+struct BriefUniswapV3Pool<'a>(&'a UniswapV3Pool);
+
+impl fmt::Debug for BriefUniswapV3Pool<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pool = self.0;
+        f.debug_struct("UniswapV3Pool")
+            .field("address", &pool.address)
+            .field("token_a", &pool.token_a)
+            .field("token_a_decimals", &pool.token_a_decimals)
+            .field("token_b", &pool.token_b)
+            .field("token_b_decimals", &pool.token_b_decimals)
+            .field("liquidity", &pool.liquidity)
+            .field("sqrt_price", &pool.sqrt_price)
+            .field("fee", &pool.fee)
+            .field("tick", &pool.tick)
+            .field("tick_spacing", &pool.tick_spacing)
+            .field(
+                "tick_bitmap",
+                &format_args!("{} words", pool.tick_bitmap.len()),
+            )
+            .field("ticks", &format_args!("{} ticks", pool.ticks.len()))
+            .field("creation_block", &pool.creation_block)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ERC4626Vault {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::ERC4626.name(),
+            Some(self.deposit_fee as f64 / 10000.0),
+        )
+        .with_depth(
+            Some(erc_4626_depth(self, self.asset_token)),
+            self.asset_token_decimals,
+        )
+    }
+}
+
+impl CurveStableSwapPool {
+    pub fn summary(&self) -> AmmSummary {
+        let summary = AmmSummary::new(
+            self,
+            Protocol::CurveStableSwap.name(),
+            Some(self.fee as f64 / curve::FEE_DENOMINATOR as f64),
+        );
+
+        //Depth in the second coin, like the price
+        match (self.coins.get(1), self.decimals.get(1)) {
+            (Some(coin), Some(decimals)) => {
+                summary.with_depth(curve_stable_swap_depth(self, *coin), *decimals)
+            }
+            _ => summary,
+        }
+    }
+}
+
+impl BalancerWeightedPool {
+    pub fn summary(&self) -> AmmSummary {
+        let summary = AmmSummary::new(
+            self,
+            Protocol::BalancerWeighted.name(),
+            Some(u256_to_f64_lossy(self.swap_fee) / balancer::ONE as f64),
+        );
+
+        //Depth in the second token, like the price
+        match (self.tokens.get(1), self.decimals.get(1)) {
+            (Some(token), Some(decimals)) => {
+                summary.with_depth(balancer_weighted_depth(self, *token), *decimals)
+            }
+            _ => summary,
+        }
+    }
+}
+
+impl VelodromePool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::Velodrome.name(),
+            Some(self.fee as f64 / velodrome::FEE_DENOMINATOR as f64),
+        )
+        .with_depth(velodrome_depth(self, self.token_b), self.token_b_decimals)
+    }
+}
+
+impl UniswapV4Pool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::UniswapV4.name(),
+            Some(self.pool.fee as f64 / 1e6),
+        )
+        .with_depth(
+            uniswap_v3_depth(
+                &self.pool,
+                self.pool.token_b,
+                DepthWeighting::default().tick_range,
+            ),
+            self.pool.token_b_decimals,
+        )
+    }
+}
+
+impl LiquidityBookPool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::LiquidityBook.name(),
+            Some(self.total_fee() as f64 / liquidity_book::PRECISION as f64),
+        )
+        .with_depth(
+            liquidity_book_depth(self, self.token_b, DepthWeighting::default().tick_range),
+            self.token_b_decimals,
+        )
+    }
+}
+
+impl MaverickPool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::Maverick.name(),
+            Some(self.fee as f64 / maverick::WAD as f64),
+        )
+        .with_depth(
+            maverick_depth(self, self.token_b, DepthWeighting::default().tick_range),
+            self.token_b_decimals,
+        )
+    }
+}
+
+impl DodoPool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::Dodo.name(),
+            Some(u256_to_f64_lossy(self.total_fee()) / u256_to_f64_lossy(dodo::ONE)),
+        )
+        .with_depth(dodo_depth(self, self.token_b), self.token_b_decimals)
+    }
+}
+
+impl KyberElasticPool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::KyberElastic.name(),
+            Some(self.fee as f64 / kyber_elastic::FEE_UNITS as f64),
+        )
+        .with_depth(
+            kyber_elastic_depth(self, self.token_b, DepthWeighting::default().tick_range),
+            self.token_b_decimals,
+        )
+    }
+}
+
+impl AlgebraPool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::Algebra.name(),
+            //The fee of swaps of token a for token b, the fee of both directions unless the pool splits them
+            Some(self.pool.fee as f64 / algebra::FEE_DENOMINATOR as f64),
+        )
+        .with_depth(
+            uniswap_v3_depth(
+                &self.pool,
+                self.pool.token_b,
+                DepthWeighting::default().tick_range,
+            ),
+            self.pool.token_b_decimals,
+        )
+    }
+}
+
+impl CurveCryptoPool {
+    pub fn summary(&self) -> AmmSummary {
+        //The fee of the current balances, between the mid and out fees
+        let fee = self
+            .xp()
+            .and_then(|xp| self.fee(&xp))
+            .map(u256_to_f64_lossy)
+            .unwrap_or(self.mid_fee as f64);
+        let summary = AmmSummary::new(
+            self,
+            Protocol::CurveCrypto.name(),
+            Some(fee / curve_crypto::FEE_DENOMINATOR as f64),
+        );
+
+        //Depth in the second coin, like the price
+        match (self.coins.get(1), self.decimals.get(1)) {
+            (Some(coin), Some(decimals)) => {
+                summary.with_depth(curve_crypto_depth(self, *coin), *decimals)
+            }
+            _ => summary,
+        }
+    }
+}
+
+impl BancorV3Pool {
+    pub fn summary(&self) -> AmmSummary {
+        AmmSummary::new(
+            self,
+            Protocol::BancorV3.name(),
+            Some(self.trading_fee_ppm as f64 / bancor_v3::PPM_RESOLUTION as f64),
+        )
+        .with_depth(bancor_v3_depth(self, self.bnt), bancor_v3::BNT_DECIMALS)
+    }
+}
+
+impl AMM {
+    pub fn summary(&self) -> AmmSummary {
+        match self {
+            AMM::UniswapV2Pool(pool) => pool.summary(),
+            AMM::UniswapV3Pool(pool) => pool.summary(),
+            AMM::ERC4626Vault(vault) => vault.summary(),
+            AMM::CurveStableSwapPool(pool) => pool.summary(),
+            AMM::BalancerWeightedPool(pool) => pool.summary(),
+            AMM::VelodromePool(pool) => pool.summary(),
+            AMM::UniswapV4Pool(pool) => pool.summary(),
+            AMM::LiquidityBookPool(pool) => pool.summary(),
+            AMM::MaverickPool(pool) => pool.summary(),
+            AMM::DodoPool(pool) => pool.summary(),
+            AMM::KyberElasticPool(pool) => pool.summary(),
+            AMM::AlgebraPool(pool) => pool.summary(),
+            AMM::CurveCryptoPool(pool) => pool.summary(),
+            AMM::BancorV3Pool(pool) => pool.summary(),
+            AMM::Custom(amm) => AmmSummary::new(amm.0.as_ref(), amm.protocol(), None),
+        }
+    }
+
+    /// Debug output eliding the tick data of Uniswap V3, V4 and Algebra pools, for logs and error context
+    pub fn debug_brief(&self) -> impl fmt::Debug + '_ {
+        BriefAMM(self)
+    }
+}
+
+struct BriefAMM<'a>(&'a AMM);
+
+impl fmt::Debug for BriefAMM<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            AMM::UniswapV3Pool(pool) => f
+                .debug_tuple("UniswapV3Pool")
+                .field(&pool.debug_brief())
+                .finish(),
+            AMM::UniswapV4Pool(pool) => f
+                .debug_tuple("UniswapV4Pool")
+                .field(&BriefUniswapV4Pool(pool))
+                .finish(),
+            AMM::AlgebraPool(pool) => f
+                .debug_tuple("AlgebraPool")
+                .field(&BriefAlgebraPool(pool))
+                .finish(),
+            amm => fmt::Debug::fmt(amm, f),
+        }
+    }
+}
+
+struct BriefUniswapV4Pool<'a>(&'a UniswapV4Pool);
+
+impl fmt::Debug for BriefUniswapV4Pool<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pool = self.0;
+        f.debug_struct("UniswapV4Pool")
+            .field("pool_manager", &pool.pool_manager)
+            .field("pool_id", &pool.pool_id)
+            .field("key_fee", &pool.key_fee)
+            .field("hooks", &pool.hooks)
+            .field("protocol_fee", &pool.protocol_fee)
+            .field("pool", &pool.pool.debug_brief())
+            .finish_non_exhaustive()
+    }
+}
+
+struct BriefAlgebraPool<'a>(&'a AlgebraPool);
+
+impl fmt::Debug for BriefAlgebraPool<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlgebraPool")
+            .field("pool", &self.0.pool.debug_brief())
+            .field("fee_one_for_zero", &self.0.fee_one_for_zero)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for UniswapV2Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for UniswapV3Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for ERC4626Vault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for CurveStableSwapPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for BalancerWeightedPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for VelodromePool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for UniswapV4Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for LiquidityBookPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for MaverickPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for DodoPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for KyberElasticPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for AlgebraPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for CurveCryptoPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for BancorV3Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+impl fmt::Display for AMM {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.summary(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::H160;
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AMM},
+        tokens::{TokenInfo, TokenStore},
+    };
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    #[test]
+    fn test_display() -> eyre::Result<()> {
+        let (weth, usdc) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let pool = UniswapV2Pool::builder()
+            .with_address(H160::from_low_u64_be(3))
+            .with_token_a(weth, 18)
+            .with_token_b(usdc, 6)
+            .with_reserves_human(1000.0, 2_000_000.0)
+            .build()?;
+
+        assert_eq!(
+            pool.to_string(),
+            format!(
+                "uniswap_v2 {:?} {weth:?}/{usdc:?} fee 0.3% price 2000 depth 4000000",
+                pool.address
+            )
+        );
+
+        //Symbols fetched when populating the pool are shown without a token store
+        let mut pool_with_symbols = pool.clone();
+        pool_with_symbols.token_a_symbol = Some("WETH".to_string());
+        assert!(pool_with_symbols
+            .to_string()
+            .contains(&format!(" WETH/{usdc:?} fee")));
+
+        let tokens = TokenStore::new();
+        tokens.insert(weth, TokenInfo::new(18, Some("WETH".to_string()), None));
+        tokens.insert(usdc, TokenInfo::new(6, Some("USDC".to_string()), None));
+
+        let summary = AMM::UniswapV2Pool(pool.clone())
+            .summary()
+            .with_symbols(&tokens)
+            .with_block(17700000);
+        assert_eq!(summary.price, Some(2000.0));
+        assert_eq!(summary.depth, Some(4_000_000.0));
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "uniswap_v2 {:?} WETH/USDC fee 0.3% price 2000 depth 4000000 block 17700000",
+                pool.address
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_brief() -> eyre::Result<()> {
+        let pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let brief = format!("{:?}", AMM::UniswapV3Pool(pool.clone()).debug_brief());
+
+        assert!(brief.contains(&format!("ticks: {} ticks", pool.ticks.len())));
+        assert!(brief.len() < 1000);
+        assert!(format!("{pool:?}").len() > 10 * brief.len());
+
+        //A single line, without the ticks
+        let display = pool.to_string();
+        assert!(!display.contains('\n'));
+        assert!(display.starts_with(&format!("uniswap_v3 {:?}", pool.address)));
+        assert!(display.contains(" fee 0.3% price "));
+
+        Ok(())
+    }
+}
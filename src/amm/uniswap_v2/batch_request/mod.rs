@@ -3,10 +3,13 @@ use ethers::{
     providers::Middleware,
     types::{Bytes, H160, U256},
 };
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::{
-    amm::{AutomatedMarketMaker, AMM},
+    amm::{
+        multicall::{self, BatchRequestMode},
+        AmmState, AMM,
+    },
     errors::AMMError,
 };
 
@@ -23,6 +26,15 @@ abigen!(
         "src/amm/uniswap_v2/batch_request/GetUniswapV2PoolDataBatchRequestABI.json";
 );
 
+//allPairs(uint256)
+const ALL_PAIRS_SELECTOR: [u8; 4] = [30, 61, 209, 139];
+//token0()
+const TOKEN_0_SELECTOR: [u8; 4] = [13, 254, 22, 129];
+//token1()
+const TOKEN_1_SELECTOR: [u8; 4] = [210, 18, 32, 167];
+//getReserves()
+const GET_RESERVES_SELECTOR: [u8; 4] = [9, 2, 241, 172];
+
 fn populate_pool_data_from_tokens(
     mut pool: UniswapV2Pool,
     tokens: Vec<Token>,
@@ -33,10 +45,12 @@ fn populate_pool_data_from_tokens(
     pool.token_b_decimals = tokens[3].to_owned().into_uint()?.as_u32() as u8;
     pool.reserve_0 = tokens[4].to_owned().into_uint()?.as_u128();
     pool.reserve_1 = tokens[5].to_owned().into_uint()?.as_u128();
+    pool.reset_decimal_scaling();
 
     Some(pool)
 }
 
+#[tracing::instrument(level = "debug", skip(middleware), err(Debug))]
 pub async fn get_pairs_batch_request<M: Middleware>(
     factory: H160,
     from: U256,
@@ -53,15 +67,19 @@ pub async fn get_pairs_batch_request<M: Middleware>(
         Token::Address(factory),
     ]);
 
-    let deployer = IGetUniswapV2PairsBatchRequest::deploy(middleware, constructor_args.clone())?;
-    let return_data: Bytes = match deployer.call_raw().await {
-        Ok(data) => data,
-        Err(e) => panic!(
-            "Error ({:?}) calling v2 pool data for {} to {}",
-            e,
-            from,
-            from + step
-        ),
+    let deployer =
+        IGetUniswapV2PairsBatchRequest::deploy(middleware.clone(), constructor_args.clone())?;
+    //Errors are returned rather than panicking so that paginated callers can retry from their cursor
+    let return_data: Bytes = match deployer.call_raw().await.map_err(AMMError::from) {
+        Ok(return_data) => return_data,
+        Err(err) if multicall::is_constructor_call_rejected(&err) => {
+            tracing::warn!(
+                ?err,
+                "constructor call rejected, getting pairs through multicall"
+            );
+            return get_pairs_multicall(factory, from, step, middleware).await;
+        }
+        Err(err) => return Err(err),
     };
 
     let return_data_tokens = ethers::abi::decode(
@@ -84,60 +102,189 @@ pub async fn get_pairs_batch_request<M: Middleware>(
     Ok(pairs)
 }
 
-pub async fn get_amm_data_batch_request<M: Middleware>(
-    amms: &mut [AMM],
+async fn get_pairs_multicall<M: Middleware>(
+    factory: H160,
+    from: U256,
+    step: U256,
     middleware: Arc<M>,
-) -> Result<(), AMMError<M>> {
-    tracing::info!("getting data for {} AMMs", amms.len());
+) -> Result<Vec<H160>, AMMError<M>> {
+    //Indices past the last pair revert, and are skipped like the zeroed pairs of the batch contract
+    let calls = (0..step.as_u64())
+        .map(|i| {
+            multicall::call(
+                factory,
+                ALL_PAIRS_SELECTOR,
+                &ethers::abi::encode(&[Token::Uint(from + i)]),
+            )
+        })
+        .collect();
+
+    Ok(multicall::aggregate(calls, None, middleware)
+        .await?
+        .iter()
+        .filter_map(|return_data| multicall::address_word(return_data.as_ref()?, 0))
+        .filter(|pair| !pair.is_zero())
+        .collect())
+}
+
+fn pool_data_param_type() -> ParamType {
+    ParamType::Tuple(vec![
+        ParamType::Address,   // token a
+        ParamType::Uint(8),   // token a decimals
+        ParamType::Address,   // token b
+        ParamType::Uint(8),   // token b decimals
+        ParamType::Uint(112), // reserve 0
+        ParamType::Uint(112), // reserve 1
+    ])
+}
 
-    let mut target_addresses = vec![];
-    for amm in amms.iter() {
-        target_addresses.push(Token::Address(amm.address()));
+/// Reads the data of `pools` at `block_number` as returned by the pool data batch contract, one tuple per pool.
+/// Pools that could not be read hold a zero token a.
+pub async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    mode: BatchRequestMode,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    match mode {
+        BatchRequestMode::ConstructorCall => {
+            get_pool_data_constructor_call(pools, block_number, middleware).await
+        }
+        BatchRequestMode::Multicall => {
+            get_pool_data_multicall(pools, block_number, middleware).await
+        }
+        BatchRequestMode::Auto => {
+            match get_pool_data_constructor_call(pools, block_number, middleware.clone()).await {
+                Ok(pool_data) => Ok(pool_data),
+                Err(err) if multicall::is_constructor_call_rejected(&err) => {
+                    tracing::warn!(
+                        ?err,
+                        "constructor call rejected, getting pool data through multicall"
+                    );
+                    get_pool_data_multicall(pools, block_number, middleware).await
+                }
+                Err(err) => Err(err),
+            }
+        }
     }
+}
 
-    let constructor_args = Token::Tuple(vec![Token::Array(target_addresses.clone())]);
+async fn get_pool_data_constructor_call<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    let target_addresses = pools.iter().map(|pool| Token::Address(*pool)).collect();
+    let constructor_args = Token::Tuple(vec![Token::Array(target_addresses)]);
 
-    let deployer = IGetUniswapV2PoolDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
+    let deployer = IGetUniswapV2PoolDataBatchRequest::deploy(middleware, constructor_args)?;
 
-    let return_data: Bytes = deployer.call_raw().await?;
+    let return_data: Bytes = if let Some(block_number) = block_number {
+        deployer.block(block_number).call_raw().await?
+    } else {
+        deployer.call_raw().await?
+    };
     let return_data_tokens = ethers::abi::decode(
-        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
-            ParamType::Address,   // token a
-            ParamType::Uint(8),   // token a decimals
-            ParamType::Address,   // token b
-            ParamType::Uint(8),   // token b decimals
-            ParamType::Uint(112), // reserve 0
-            ParamType::Uint(112), // reserve 1
-        ])))],
+        &[ParamType::Array(Box::new(pool_data_param_type()))],
         &return_data,
     )?;
 
-    let mut pool_idx = 0;
-
-    for tokens in return_data_tokens {
-        if let Some(tokens_arr) = tokens.into_array() {
-            for tup in tokens_arr {
-                if let Some(pool_data) = tup.into_tuple() {
-                    //If the pool token A is not zero, signaling that the pool data was populated
-                    if let Some(address) = pool_data[0].to_owned().into_address() {
-                        if !address.is_zero() {
-                            //Update the pool data
-                            if let AMM::UniswapV2Pool(uniswap_v2_pool) = amms
-                                .get_mut(pool_idx)
-                                .expect("Pool idx should be in bounds")
-                            {
-                                if let Some(pool) = populate_pool_data_from_tokens(
-                                    uniswap_v2_pool.to_owned(),
-                                    pool_data,
-                                ) {
-                                    tracing::trace!(?pool);
-                                    *uniswap_v2_pool = pool;
-                                }
-                            }
-                        }
-                    }
+    Ok(return_data_tokens
+        .into_iter()
+        .filter_map(Token::into_array)
+        .flatten()
+        .filter_map(Token::into_tuple)
+        .collect())
+}
+
+async fn get_pool_data_multicall<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| {
+            [TOKEN_0_SELECTOR, TOKEN_1_SELECTOR, GET_RESERVES_SELECTOR]
+                .map(|selector| multicall::call(*pool, selector, &[]))
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
 
-                    pool_idx += 1;
+    let tokens = return_data
+        .chunks(3)
+        .flat_map(|pool_return_data| &pool_return_data[..2])
+        .filter_map(|return_data| multicall::address_word(return_data.as_ref()?, 0))
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+
+    Ok(return_data
+        .chunks(3)
+        .map(|pool_return_data| {
+            pool_data_from_multicall(pool_return_data, &decimals).unwrap_or_else(|| {
+                vec![
+                    Token::Address(H160::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Address(H160::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Uint(U256::zero()),
+                ]
+            })
+        })
+        .collect())
+}
+
+//Pool data in the layout of the batch contract, None if any of the reads failed
+fn pool_data_from_multicall(
+    return_data: &[Option<Bytes>],
+    decimals: &HashMap<H160, u8>,
+) -> Option<Vec<Token>> {
+    let token_a = multicall::address_word(return_data[0].as_ref()?, 0)?;
+    let token_b = multicall::address_word(return_data[1].as_ref()?, 0)?;
+    let reserves = return_data[2].as_ref()?;
+
+    Some(vec![
+        Token::Address(token_a),
+        Token::Uint(U256::from(*decimals.get(&token_a)?)),
+        Token::Address(token_b),
+        Token::Uint(U256::from(*decimals.get(&token_b)?)),
+        Token::Uint(multicall::word(reserves, 0)?),
+        Token::Uint(multicall::word(reserves, 1)?),
+    ])
+}
+
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    get_amm_data_batch_request_with_mode(amms, BatchRequestMode::Auto, middleware).await
+}
+
+/// Same as `get_amm_data_batch_request`, reading the pools through `mode`
+#[tracing::instrument(level = "debug", skip_all, fields(batch_size = amms.len(), mode = ?mode), err(Debug))]
+pub async fn get_amm_data_batch_request_with_mode<M: Middleware>(
+    amms: &mut [AMM],
+    mode: BatchRequestMode,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    tracing::info!("getting data for {} AMMs", amms.len());
+
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, None, mode, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        //If the pool token A is not zero, signaling that the pool data was populated
+        if let Some(address) = pool_data[0].to_owned().into_address() {
+            if !address.is_zero() {
+                //Update the pool data
+                if let AMM::UniswapV2Pool(uniswap_v2_pool) = amm {
+                    if let Some(pool) =
+                        populate_pool_data_from_tokens(uniswap_v2_pool.to_owned(), pool_data)
+                    {
+                        tracing::trace!(%pool);
+                        *uniswap_v2_pool = pool;
+                    }
                 }
             }
         }
@@ -148,44 +295,21 @@ pub async fn get_amm_data_batch_request<M: Middleware>(
 
 pub async fn get_v2_pool_data_batch_request<M: Middleware>(
     pool: &mut UniswapV2Pool,
+    block_number: Option<u64>,
     middleware: Arc<M>,
 ) -> Result<(), AMMError<M>> {
     tracing::info!(?pool.address, "getting pool data");
-    let constructor_args = Token::Tuple(vec![Token::Array(vec![Token::Address(pool.address)])]);
-
-    let deployer =
-        IGetUniswapV2PoolDataBatchRequest::deploy(middleware.clone(), constructor_args.clone())?;
-
-    let return_data: Bytes = match deployer.call_raw().await {
-        Ok(data) => data,
-        Err(e) => panic!(
-            "Error ({:?}) calling v2 pool data for {:?}",
-            e, constructor_args
-        ),
-    };
-    let return_data_tokens = ethers::abi::decode(
-        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
-            ParamType::Address,   // token a
-            ParamType::Uint(8),   // token a decimals
-            ParamType::Address,   // token b
-            ParamType::Uint(8),   // token b decimals
-            ParamType::Uint(112), // reserve 0
-            ParamType::Uint(112), // reserve 1
-        ])))],
-        &return_data,
-    )?;
 
-    for tokens in return_data_tokens {
-        if let Some(tokens_arr) = tokens.into_array() {
-            for tup in tokens_arr {
-                let pool_data = tup
-                    .into_tuple()
-                    .ok_or(AMMError::BatchRequestError(pool.address))?;
-
-                *pool = populate_pool_data_from_tokens(pool.to_owned(), pool_data)
-                    .ok_or(AMMError::BatchRequestError(pool.address))?;
-            }
-        }
+    for pool_data in get_pool_data(
+        &[pool.address],
+        block_number,
+        BatchRequestMode::Auto,
+        middleware,
+    )
+    .await?
+    {
+        *pool = populate_pool_data_from_tokens(pool.to_owned(), pool_data)
+            .ok_or(AMMError::BatchRequestError(pool.address))?;
     }
 
     Ok(())
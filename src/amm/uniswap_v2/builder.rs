@@ -0,0 +1,309 @@
+use ethers::types::H160;
+
+use crate::{
+    amm::compat::SerdeVersion, errors::PoolBuildError, math::fixed_point::DecimalScalingCache,
+};
+
+use super::{FeeChangeEvent, TransferTax, UniswapV2Pool, FEE_DENOMINATOR, TAX_BPS_DENOMINATOR};
+
+/// Largest number of decimals accepted for a token
+pub const MAX_DECIMALS: u8 = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reserves {
+    Raw(u128, u128),
+    //Whole units, scaled by the decimals of each token when the pool is built
+    Human(f64, f64),
+}
+
+/// Builds a `UniswapV2Pool` offline, validating its fields in `build`.
+///
+/// Each token is set together with its decimals, so they can not be transposed. The fee defaults to 300, 0.3%.
+#[derive(Debug, Clone)]
+pub struct UniswapV2PoolBuilder {
+    address: H160,
+    token_a: H160,
+    token_a_decimals: u8,
+    token_b: H160,
+    token_b_decimals: u8,
+    reserves: Reserves,
+    fee: u32,
+    fee_change_event: Option<FeeChangeEvent>,
+    fee_one_for_zero: Option<u32>,
+    token_a_transfer_tax: TransferTax,
+    token_b_transfer_tax: TransferTax,
+    creation_block: Option<u64>,
+}
+
+impl Default for UniswapV2PoolBuilder {
+    fn default() -> Self {
+        UniswapV2PoolBuilder {
+            address: H160::zero(),
+            token_a: H160::zero(),
+            token_a_decimals: 0,
+            token_b: H160::zero(),
+            token_b_decimals: 0,
+            reserves: Reserves::Raw(0, 0),
+            fee: 300,
+            fee_change_event: None,
+            fee_one_for_zero: None,
+            token_a_transfer_tax: TransferTax::default(),
+            token_b_transfer_tax: TransferTax::default(),
+            creation_block: None,
+        }
+    }
+}
+
+impl UniswapV2PoolBuilder {
+    pub fn with_address(mut self, address: H160) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn with_token_a(mut self, token_a: H160, decimals: u8) -> Self {
+        self.token_a = token_a;
+        self.token_a_decimals = decimals;
+        self
+    }
+
+    pub fn with_token_b(mut self, token_b: H160, decimals: u8) -> Self {
+        self.token_b = token_b;
+        self.token_b_decimals = decimals;
+        self
+    }
+
+    /// Raw reserves of token a and token b
+    pub fn with_reserves(mut self, reserve_0: u128, reserve_1: u128) -> Self {
+        self.reserves = Reserves::Raw(reserve_0, reserve_1);
+        self
+    }
+
+    /// Reserves in whole tokens, i.e. `with_reserves_human(1000.0, 2_000_000.0)` for 1,000 of token a and two
+    /// million of token b, scaled by the decimals of each token when the pool is built
+    pub fn with_reserves_human(mut self, reserve_0: f64, reserve_1: f64) -> Self {
+        self.reserves = Reserves::Human(reserve_0, reserve_1);
+        self
+    }
+
+    /// Fee as a fraction of `FEE_DENOMINATOR`, i.e. 300 for 0.3%
+    pub fn with_fee(mut self, fee: u32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn with_fee_change_event(mut self, fee_change_event: FeeChangeEvent) -> Self {
+        self.fee_change_event = Some(fee_change_event);
+        self
+    }
+
+    /// Fees of swaps of token a for token b and of token b for token a, for pairs charging a fee for each direction
+    pub fn with_directional_fees(mut self, fee_zero_for_one: u32, fee_one_for_zero: u32) -> Self {
+        self.fee = fee_zero_for_one;
+        self.fee_one_for_zero = Some(fee_one_for_zero);
+        self
+    }
+
+    pub fn with_transfer_taxes(
+        mut self,
+        token_a_transfer_tax: TransferTax,
+        token_b_transfer_tax: TransferTax,
+    ) -> Self {
+        self.token_a_transfer_tax = token_a_transfer_tax;
+        self.token_b_transfer_tax = token_b_transfer_tax;
+        self
+    }
+
+    pub fn with_creation_block(mut self, creation_block: u64) -> Self {
+        self.creation_block = Some(creation_block);
+        self
+    }
+
+    pub fn build(self) -> Result<UniswapV2Pool, PoolBuildError> {
+        validate_tokens(
+            self.token_a,
+            self.token_a_decimals,
+            self.token_b,
+            self.token_b_decimals,
+        )?;
+
+        for fee in std::iter::once(self.fee).chain(self.fee_one_for_zero) {
+            if fee >= FEE_DENOMINATOR {
+                return Err(PoolBuildError::InvalidFee(fee));
+            }
+        }
+
+        for tax in [self.token_a_transfer_tax, self.token_b_transfer_tax] {
+            for bps in [tax.buy_bps, tax.sell_bps] {
+                if bps >= TAX_BPS_DENOMINATOR {
+                    return Err(PoolBuildError::InvalidTransferTax(bps));
+                }
+            }
+        }
+
+        let (reserve_0, reserve_1) = match self.reserves {
+            Reserves::Raw(reserve_0, reserve_1) => (reserve_0, reserve_1),
+            Reserves::Human(reserve_0, reserve_1) => (
+                scale_amount(reserve_0, self.token_a_decimals)?,
+                scale_amount(reserve_1, self.token_b_decimals)?,
+            ),
+        };
+
+        let mut pool = UniswapV2Pool {
+            address: self.address,
+            token_a: self.token_a,
+            token_a_decimals: self.token_a_decimals,
+            token_b: self.token_b,
+            token_b_decimals: self.token_b_decimals,
+            reserve_0: 0,
+            reserve_1: 0,
+            fee: self.fee,
+            fee_change_event: self.fee_change_event,
+            fee_one_for_zero: self.fee_one_for_zero,
+            stable_swap: false,
+            token_a_transfer_tax: self.token_a_transfer_tax,
+            token_b_transfer_tax: self.token_b_transfer_tax,
+            creation_block: self.creation_block,
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::new(self.token_a_decimals, self.token_b_decimals),
+        };
+
+        pool.set_reserves(reserve_0, reserve_1)
+            .map_err(|_| PoolBuildError::ReserveOverflow(reserve_0.max(reserve_1)))?;
+
+        Ok(pool)
+    }
+}
+
+//Checks the tokens of a two token pool, shared with the Uniswap V3 builder
+pub(crate) fn validate_tokens(
+    token_a: H160,
+    token_a_decimals: u8,
+    token_b: H160,
+    token_b_decimals: u8,
+) -> Result<(), PoolBuildError> {
+    if token_a == token_b {
+        return Err(PoolBuildError::IdenticalTokens(token_a));
+    }
+
+    for decimals in [token_a_decimals, token_b_decimals] {
+        if decimals > MAX_DECIMALS {
+            return Err(PoolBuildError::InvalidDecimals(decimals));
+        }
+    }
+
+    Ok(())
+}
+
+//Raw amount of `amount` whole tokens, truncated toward zero
+fn scale_amount(amount: f64, decimals: u8) -> Result<u128, PoolBuildError> {
+    let scaled = amount * 10_f64.powi(decimals as i32);
+
+    //u128::MAX as f64 rounds up to 2^128, which does not fit
+    if !scaled.is_finite() || scaled < 0.0 || scaled >= u128::MAX as f64 {
+        return Err(PoolBuildError::InvalidAmount(amount));
+    }
+
+    Ok(scaled as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::H160;
+
+    use crate::{
+        amm::{
+            uniswap_v2::{TransferTax, UniswapV2Pool},
+            AmmState,
+        },
+        errors::PoolBuildError,
+    };
+
+    #[test]
+    fn test_build() -> eyre::Result<()> {
+        let usdc = H160::from_low_u64_be(1);
+        let weth = H160::from_low_u64_be(2);
+
+        let pool = UniswapV2Pool::builder()
+            .with_address(H160::from_low_u64_be(3))
+            .with_token_a(usdc, 6)
+            .with_token_b(weth, 18)
+            .with_reserves_human(2_000_000.0, 1000.0)
+            .build()?;
+
+        assert_eq!(pool.reserve_0, 2 * 10_u128.pow(12));
+        assert_eq!(pool.reserve_1, 10_u128.pow(21));
+        assert_eq!(pool.fee, 300);
+        assert!((pool.calculate_price(weth)? - 2000.0).abs() < 1e-9);
+
+        //Decimals set after the human readable reserves still apply
+        let pool = UniswapV2Pool::builder()
+            .with_reserves_human(1.5, 2.0)
+            .with_token_a(usdc, 6)
+            .with_token_b(weth, 18)
+            .build()?;
+        assert_eq!(pool.reserve_0, 1_500_000);
+        assert_eq!(pool.reserve_1, 2 * 10_u128.pow(18));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_rejects_invalid_pools() {
+        let builder = UniswapV2Pool::builder()
+            .with_token_a(H160::from_low_u64_be(1), 18)
+            .with_token_b(H160::from_low_u64_be(2), 18);
+
+        assert_eq!(
+            builder
+                .clone()
+                .with_token_b(H160::from_low_u64_be(1), 18)
+                .build()
+                .err(),
+            Some(PoolBuildError::IdenticalTokens(H160::from_low_u64_be(1)))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .with_token_a(H160::from_low_u64_be(1), 37)
+                .build()
+                .err(),
+            Some(PoolBuildError::InvalidDecimals(37))
+        );
+        assert_eq!(
+            builder.clone().with_fee(100000).build().err(),
+            Some(PoolBuildError::InvalidFee(100000))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .with_directional_fees(300, 100000)
+                .build()
+                .err(),
+            Some(PoolBuildError::InvalidFee(100000))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .with_transfer_taxes(TransferTax::new(0, 10000), TransferTax::default())
+                .build()
+                .err(),
+            Some(PoolBuildError::InvalidTransferTax(10000))
+        );
+        assert_eq!(
+            builder.clone().with_reserves_human(-1.0, 1.0).build().err(),
+            Some(PoolBuildError::InvalidAmount(-1.0))
+        );
+        assert_eq!(
+            builder.clone().with_reserves_human(1e30, 1.0).build().err(),
+            Some(PoolBuildError::InvalidAmount(1e30))
+        );
+        assert_eq!(
+            builder.with_reserves(u128::MAX, 1).build().err(),
+            Some(PoolBuildError::ReserveOverflow(u128::MAX))
+        );
+    }
+}
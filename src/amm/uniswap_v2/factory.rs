@@ -2,20 +2,34 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use ethers::{
-    abi::RawLog,
-    prelude::EthEvent,
+    abi::Token,
+    contract::{multicall_contract::Call3, MulticallContract, MULTICALL_ADDRESS},
     providers::Middleware,
     types::{Log, H160, H256, U256},
+    utils::{get_create2_address_from_hash, keccak256},
 };
+use futures::Stream;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    amm::{factory::AutomatedMarketMakerFactory, AMM},
-    errors::AMMError,
+    amm::{
+        decode,
+        factory::{sort_tokens, AmmPage, AutomatedMarketMakerFactory},
+        AMM,
+    },
+    errors::{AMMError, EventLogError},
+    sync,
 };
 
-use super::{batch_request, UniswapV2Pool};
+use super::{batch_request, normalize_fee, FeeChangeEvent, UniswapV2Pool, FEE_DENOMINATOR};
+
+//Max batch size for the pairs batch request until codesize is too large
+pub(crate) const GET_PAIRS_STEP: u64 = 766;
+//Max batch size for the pool data batch request
+const GET_POOL_DATA_STEP: usize = 127;
+//Number of fee getter calls batched into each multicall
+const GET_POOL_FEES_STEP: usize = 500;
 
 use ethers::prelude::abigen;
 
@@ -35,11 +49,60 @@ pub const PAIR_CREATED_EVENT_SIGNATURE: H256 = H256([
     131, 85, 205, 222, 253, 227, 26, 250, 40, 208, 233,
 ]);
 
+pub const UNISWAP_V2_INIT_CODE_HASH: H256 = H256([
+    150, 232, 172, 66, 119, 25, 143, 248, 182, 247, 133, 71, 138, 169, 163, 159, 64, 60, 183, 104,
+    221, 2, 203, 238, 50, 108, 62, 125, 163, 72, 132, 95,
+]);
+
+pub const PANCAKESWAP_V2_INIT_CODE_HASH: H256 = H256([
+    0, 251, 127, 99, 7, 102, 230, 167, 150, 4, 142, 168, 125, 1, 172, 211, 6, 142, 143, 246, 125,
+    7, 129, 72, 163, 250, 63, 74, 132, 246, 155, 213,
+]);
+
+pub const BISWAP_FEE_SOURCE: FeeSource = FeeSource::PairGetter {
+    //swapFee()
+    selector: [84, 207, 42, 235],
+    denominator: 1000,
+    fee_change_event: None,
+};
+
+pub const PANCAKESWAP_V2_FEE_SOURCE: FeeSource = FeeSource::Fixed(25);
+
+pub const CAMELOT_V2_FEE_SOURCE: FeeSource = FeeSource::DirectionalPairGetter;
+
+//token0FeePercent()
+const TOKEN_0_FEE_PERCENT_SELECTOR: [u8; 4] = [98, 236, 236, 3];
+//token1FeePercent()
+const TOKEN_1_FEE_PERCENT_SELECTOR: [u8; 4] = [47, 205, 22, 146];
+//stableSwap()
+const STABLE_SWAP_SELECTOR: [u8; 4] = [158, 84, 139, 127];
+
+/// Where the swap fee of each pool is read from during population, for forks where the fee is not the same for every pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeeSource {
+    /// `selector(address pair)` on the factory, returning the fee as a fraction of `denominator`
+    FactoryGetter { selector: [u8; 4], denominator: u32 },
+    /// `selector()` on the pair, returning the fee as a fraction of `denominator`.
+    /// If a `fee_change_event` is set, the pools also sync their fee from that event.
+    PairGetter {
+        selector: [u8; 4],
+        denominator: u32,
+        fee_change_event: Option<H256>,
+    },
+    /// Fixed fee in basis points
+    Fixed(u32),
+    /// `token0FeePercent()`, `token1FeePercent()` and `stableSwap()` on Camelot pairs, which charge a fee for each
+    /// direction over `FEE_DENOMINATOR`. The pools sync their fees and curve from their logs.
+    DirectionalPairGetter,
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct UniswapV2Factory {
     pub address: H160,
     pub creation_block: u64,
     pub fee: u32,
+    #[serde(default)]
+    pub fee_source: Option<FeeSource>,
 }
 
 impl UniswapV2Factory {
@@ -48,7 +111,119 @@ impl UniswapV2Factory {
             address,
             creation_block,
             fee,
+            fee_source: None,
+        }
+    }
+
+    pub fn new_with_fee_source(
+        address: H160,
+        creation_block: u64,
+        fee: u32,
+        fee_source: FeeSource,
+    ) -> UniswapV2Factory {
+        UniswapV2Factory {
+            address,
+            creation_block,
+            fee,
+            fee_source: Some(fee_source),
+        }
+    }
+
+    /// Sets the fee of each pool from the factory's `fee_source`, if any.
+    /// Pools where the getter reverts or returns an out of range fee keep their current fee.
+    pub async fn populate_pool_fees<M: Middleware>(
+        &self,
+        amms: &mut [AMM],
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        let fee_source = match self.fee_source {
+            Some(fee_source) => fee_source,
+            None => return Ok(()),
+        };
+
+        let mut pools = amms
+            .iter_mut()
+            .filter_map(|amm| match amm {
+                AMM::UniswapV2Pool(pool) => Some(pool),
+                _ => None,
+            })
+            .collect::<Vec<&mut UniswapV2Pool>>();
+
+        let (selector, denominator) = match fee_source {
+            FeeSource::Fixed(fee) => {
+                for pool in pools {
+                    pool.fee = fee * 10;
+                }
+
+                return Ok(());
+            }
+            FeeSource::FactoryGetter {
+                selector,
+                denominator,
+            } => (selector, denominator),
+            FeeSource::DirectionalPairGetter => {
+                return populate_directional_fees(&mut pools, middleware).await;
+            }
+            FeeSource::PairGetter {
+                selector,
+                denominator,
+                fee_change_event,
+            } => {
+                if let Some(signature) = fee_change_event {
+                    for pool in pools.iter_mut() {
+                        pool.fee_change_event = Some(FeeChangeEvent {
+                            signature,
+                            denominator,
+                        });
+                    }
+                }
+
+                (selector, denominator)
+            }
+        };
+
+        let multicall = MulticallContract::new(MULTICALL_ADDRESS, middleware.clone());
+        for chunk in pools.chunks_mut(GET_POOL_FEES_STEP) {
+            let calls = chunk
+                .iter()
+                .map(|pool| match fee_source {
+                    FeeSource::FactoryGetter { .. } => Call3 {
+                        target: self.address,
+                        allow_failure: true,
+                        call_data: [
+                            selector.as_slice(),
+                            &ethers::abi::encode(&[Token::Address(pool.address)]),
+                        ]
+                        .concat()
+                        .into(),
+                    },
+                    _ => Call3 {
+                        target: pool.address,
+                        allow_failure: true,
+                        call_data: selector.to_vec().into(),
+                    },
+                })
+                .collect::<Vec<Call3>>();
+
+            let results = multicall.aggregate_3(calls).call().await?;
+            for (pool, result) in chunk.iter_mut().zip(results) {
+                let fee = if result.success {
+                    result
+                        .return_data
+                        .get(0..32)
+                        .and_then(|word| normalize_fee(U256::from_big_endian(word), denominator))
+                } else {
+                    None
+                };
+
+                match fee {
+                    Some(fee) => pool.fee = fee,
+                    None => tracing::warn!(pool = ?pool.address, "could not get pool fee"),
+                }
+            }
         }
+
+        Ok(())
     }
 
     pub async fn get_all_pairs_via_batched_calls<M: Middleware>(
@@ -104,6 +279,225 @@ impl UniswapV2Factory {
 
         Ok(amms)
     }
+
+    /// Computes the address of the token_a/token_b pair without an RPC call, using the Uniswap V2 init code hash
+    pub fn compute_pair_address(&self, token_a: H160, token_b: H160) -> H160 {
+        compute_pair_address(self.address, token_a, token_b, UNISWAP_V2_INIT_CODE_HASH)
+    }
+
+    /// Same as `compute_pair_address` for forks deployed with a different pair bytecode, i.e. `PANCAKESWAP_V2_INIT_CODE_HASH`
+    pub fn compute_pair_address_with_init_code_hash(
+        &self,
+        token_a: H160,
+        token_b: H160,
+        init_code_hash: H256,
+    ) -> H160 {
+        compute_pair_address(self.address, token_a, token_b, init_code_hash)
+    }
+
+    /// Length of the factory's `allPairs` array
+    pub async fn all_pairs_length<M: Middleware>(
+        &self,
+        middleware: Arc<M>,
+    ) -> Result<u64, AMMError<M>> {
+        let factory = IUniswapV2Factory::new(self.address, middleware);
+        Ok(factory.all_pairs_length().call().await?.as_u64())
+    }
+
+    /// Gets and populates the pairs at indices `start_idx..end_idx` of the factory's `allPairs` array
+    pub async fn get_pools_range<M: Middleware>(
+        &self,
+        start_idx: u64,
+        end_idx: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let mut amms = vec![];
+
+        let mut idx_from = start_idx;
+        while idx_from < end_idx {
+            let idx_to = (idx_from + GET_PAIRS_STEP).min(end_idx);
+
+            for address in batch_request::get_pairs_batch_request(
+                self.address,
+                U256::from(idx_from),
+                U256::from(idx_to),
+                middleware.clone(),
+            )
+            .await?
+            {
+                amms.push(AMM::UniswapV2Pool(UniswapV2Pool {
+                    address,
+                    fee: self.fee,
+                    ..Default::default()
+                }));
+            }
+
+            idx_from = idx_to;
+        }
+
+        for amm_chunk in amms.chunks_mut(GET_POOL_DATA_STEP) {
+            batch_request::get_amm_data_batch_request(amm_chunk, middleware.clone()).await?;
+        }
+        self.populate_pool_fees(&mut amms, middleware).await?;
+
+        Ok(sync::remove_empty_amms(amms))
+    }
+
+    /// Returns a cursor over the factory's pairs starting at `start_idx`, yielding `page_size` populated pools at a time
+    pub fn pools_paginated<M: 'static + Middleware>(
+        &self,
+        start_idx: u64,
+        page_size: u64,
+        middleware: Arc<M>,
+    ) -> UniswapV2PoolPages<M> {
+        UniswapV2PoolPages {
+            factory: self.clone(),
+            cursor: start_idx,
+            end_idx: None,
+            page_size: page_size.max(1),
+            middleware,
+        }
+    }
+}
+
+//Sets both fees and the curve of each Camelot pair, pairs where a getter reverts keep their current fee
+async fn populate_directional_fees<M: Middleware>(
+    pools: &mut [&mut UniswapV2Pool],
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    const SELECTORS: [[u8; 4]; 3] = [
+        TOKEN_0_FEE_PERCENT_SELECTOR,
+        TOKEN_1_FEE_PERCENT_SELECTOR,
+        STABLE_SWAP_SELECTOR,
+    ];
+
+    let multicall = MulticallContract::new(MULTICALL_ADDRESS, middleware.clone());
+    for chunk in pools.chunks_mut(GET_POOL_FEES_STEP / SELECTORS.len()) {
+        let calls = chunk
+            .iter()
+            .flat_map(|pool| {
+                SELECTORS.map(|selector| Call3 {
+                    target: pool.address,
+                    allow_failure: true,
+                    call_data: selector.to_vec().into(),
+                })
+            })
+            .collect::<Vec<Call3>>();
+
+        let results = multicall.aggregate_3(calls).call().await?;
+        for (pool, results) in chunk.iter_mut().zip(results.chunks(SELECTORS.len())) {
+            let words = results
+                .iter()
+                .map(|result| {
+                    if result.success {
+                        result.return_data.get(0..32).map(U256::from_big_endian)
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Option<Vec<U256>>>();
+
+            let fees = words.as_ref().and_then(|words| {
+                Some((
+                    normalize_fee(words[0], FEE_DENOMINATOR)?,
+                    normalize_fee(words[1], FEE_DENOMINATOR)?,
+                    !words[2].is_zero(),
+                ))
+            });
+
+            match fees {
+                Some((fee_zero_for_one, fee_one_for_zero, stable_swap)) => {
+                    pool.fee = fee_zero_for_one;
+                    pool.fee_one_for_zero = Some(fee_one_for_zero);
+                    pool.stable_swap = stable_swap;
+                }
+                None => tracing::warn!(pool = ?pool.address, "could not get pool fees"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// CREATE2 derivation of a V2 pair address, where the salt is `keccak256(abi.encodePacked(token0, token1))`
+pub fn compute_pair_address(
+    factory: H160,
+    token_a: H160,
+    token_b: H160,
+    init_code_hash: H256,
+) -> H160 {
+    let (token_0, token_1) = sort_tokens(token_a, token_b);
+    let salt = keccak256([token_0.as_bytes(), token_1.as_bytes()].concat());
+
+    get_create2_address_from_hash(factory, salt, init_code_hash)
+}
+
+/// Reads `(token0, token1, pair)` straight from the topics and data of a `PairCreated` log
+pub fn decode_pair_created_log(log: &Log) -> Result<(H160, H160, H160), EventLogError> {
+    if decode::event_signature(log)? != PAIR_CREATED_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    Ok((
+        decode::topic_address(log, 1)?,
+        decode::topic_address(log, 2)?,
+        decode::data_address(log, 0)?,
+    ))
+}
+
+#[derive(Debug)]
+pub struct UniswapV2PoolPages<M> {
+    factory: UniswapV2Factory,
+    cursor: u64,
+    end_idx: Option<u64>,
+    page_size: u64,
+    middleware: Arc<M>,
+}
+
+impl<M: 'static + Middleware> UniswapV2PoolPages<M> {
+    /// Index of the next pair to be fetched, which can be persisted and passed to `pools_paginated` to resume
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Fetches the next page, returning `None` once every pair has been yielded.
+    /// If the page fails the cursor is not advanced, so the call can be retried.
+    pub async fn next_page(&mut self) -> Result<Option<AmmPage>, AMMError<M>> {
+        let end_idx = match self.end_idx {
+            Some(end_idx) => end_idx,
+            None => {
+                let pairs_length = self
+                    .factory
+                    .all_pairs_length(self.middleware.clone())
+                    .await?;
+                self.end_idx = Some(pairs_length);
+                pairs_length
+            }
+        };
+
+        if self.cursor >= end_idx {
+            return Ok(None);
+        }
+
+        let page_end = (self.cursor + self.page_size).min(end_idx);
+        let amms = self
+            .factory
+            .get_pools_range(self.cursor, page_end, self.middleware.clone())
+            .await?;
+
+        self.cursor = page_end;
+
+        Ok(Some(AmmPage {
+            cursor: page_end,
+            amms,
+        }))
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<AmmPage, AMMError<M>>> {
+        futures::stream::try_unfold(self, |mut pages| async move {
+            Ok(pages.next_page().await?.map(|page| (page, pages)))
+        })
+    }
 }
 
 #[async_trait]
@@ -121,25 +515,27 @@ impl AutomatedMarketMakerFactory for UniswapV2Factory {
         log: Log,
         middleware: Arc<M>,
     ) -> Result<AMM, AMMError<M>> {
-        let pair_created_event: PairCreatedFilter =
-            PairCreatedFilter::decode_log(&RawLog::from(log))?;
+        let (_, _, pair) = decode_pair_created_log(&log)?;
         Ok(AMM::UniswapV2Pool(
-            UniswapV2Pool::new_from_address(pair_created_event.pair, self.fee, middleware).await?,
+            UniswapV2Pool::new_populated(pair, self.fee, None, middleware).await?,
         ))
     }
 
-    fn new_empty_amm_from_log(&self, log: Log) -> Result<AMM, ethers::abi::Error> {
-        let pair_created_event = PairCreatedFilter::decode_log(&RawLog::from(log))?;
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        let creation_block = log.block_number.map(|block_number| block_number.as_u64());
+        let (token_0, token_1, pair) = decode_pair_created_log(log)?;
 
         Ok(AMM::UniswapV2Pool(UniswapV2Pool {
-            address: pair_created_event.pair,
-            token_a: pair_created_event.token_0,
-            token_b: pair_created_event.token_1,
+            address: pair,
+            token_a: token_0,
+            token_b: token_1,
             token_a_decimals: 0,
             token_b_decimals: 0,
             reserve_0: 0,
             reserve_1: 0,
             fee: 0,
+            creation_block,
+            ..Default::default()
         }))
     }
 
@@ -167,6 +563,8 @@ impl AutomatedMarketMakerFactory for UniswapV2Factory {
         for amm_chunk in amms.chunks_mut(step) {
             batch_request::get_amm_data_batch_request(amm_chunk, middleware.clone()).await?;
         }
+        self.populate_pool_fees(amms, middleware).await?;
+
         Ok(())
     }
 
@@ -1,25 +1,48 @@
+#[cfg(feature = "rpc")]
 pub mod batch_request;
+pub mod builder;
+#[cfg(feature = "rpc")]
 pub mod factory;
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::collections::BTreeMap;
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
 
+#[cfg(feature = "rpc")]
 use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::{abi::RawLog, prelude::EthEvent, providers::Middleware};
 use ethers::{
-    abi::{ethabi::Bytes, RawLog, Token},
-    prelude::EthEvent,
-    providers::Middleware,
-    types::{Log, H160, H256, U256},
+    abi::{ethabi::Bytes, Token},
+    types::{Log, H160, H256, U256, U512},
 };
-use num_bigfloat::BigFloat;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    amm::AutomatedMarketMaker,
-    errors::{AMMError, ArithmeticError, EventLogError, StorageError, SwapSimulationError},
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, StateSyncError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{
+        fixed_point::{DecimalScaling, DecimalScalingCache},
+        mul_div, Q128x128,
+    },
+    tokens::TokenStore,
+};
+#[cfg(feature = "rpc")]
+use crate::{
+    amm::{
+        detect::{self, DetectedVariant},
+        AutomatedMarketMaker,
+    },
+    errors::AMMError,
 };
 
+pub use crate::math::fixed_point::q64_to_f64;
+
 use ethers::prelude::abigen;
 
+use self::builder::UniswapV2PoolBuilder;
+#[cfg(feature = "rpc")]
 use self::factory::PAIR_CREATED_EVENT_SIGNATURE;
 
 abigen!(
@@ -37,6 +60,13 @@ abigen!(
         function balanceOf(address account) external view returns (uint256)
         function decimals() external view returns (uint8)
     ]"#;
+
+    ICamelotPair,
+    r#"[
+        function token0FeePercent() external view returns (uint16)
+        function token1FeePercent() external view returns (uint16)
+        function stableSwap() external view returns (bool)
+    ]"#;
 );
 
 pub const U128_0X10000000000000000: u128 = 18446744073709551616;
@@ -55,92 +85,235 @@ pub const BURN_EVENT_SIGNATURE: H256 = H256([
     179, 244, 247, 137, 151, 110, 109, 129, 147, 100, 150,
 ]);
 
+//FeePercentUpdated(uint16 token0FeePercent, uint16 token1FeePercent), logged by Camelot pairs
+pub const FEE_PERCENT_UPDATED_EVENT_SIGNATURE: H256 = H256([
+    164, 135, 123, 142, 203, 90, 0, 186, 39, 126, 75, 206, 238, 177, 135, 166, 105, 231, 17, 54,
+    73, 119, 77, 251, 234, 5, 194, 89, 206, 39, 241, 123,
+]);
+
+//SetStableSwap(bool prevStableSwap, bool stableSwap), logged by Camelot pairs
+pub const SET_STABLE_SWAP_EVENT_SIGNATURE: H256 = H256([
+    182, 168, 103, 16, 189, 229, 58, 167, 251, 27, 56, 86, 39, 158, 42, 245, 180, 118, 213, 62, 45,
+    208, 144, 44, 241, 122, 9, 17, 181, 164, 58, 139,
+]);
+
+//Pool fees are expressed as a fraction of this value, i.e. a fee of 300 is 0.3%
+pub const FEE_DENOMINATOR: u32 = 100000;
+/// Largest reserve of a pair, which stores its reserves as `uint112`
+pub const MAX_RESERVE: u128 = (1 << 112) - 1;
+pub const TAX_BPS_DENOMINATOR: u32 = 10000;
+
 const RESERVES_STORAGE_SLOT: H256 = H256([
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8,
 ]);
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UniswapV2Pool {
+    //Stored names of the fields of the first release are pinned, see `amm::compat`
+    #[serde(rename = "address")]
     pub address: H160,
+    #[serde(rename = "token_a")]
     pub token_a: H160,
+    #[serde(rename = "token_a_decimals")]
     pub token_a_decimals: u8,
+    #[serde(rename = "token_b")]
     pub token_b: H160,
+    #[serde(rename = "token_b_decimals")]
     pub token_b_decimals: u8,
+    #[serde(rename = "reserve_0")]
     pub reserve_0: u128,
+    #[serde(rename = "reserve_1")]
     pub reserve_1: u128,
+    #[serde(rename = "fee")]
     pub fee: u32,
+    #[serde(default)]
+    pub fee_change_event: Option<FeeChangeEvent>,
+    /// Fee of swaps of token b for token a on pairs charging a fee for each direction such as Camelot pairs, which
+    /// sync both fees from their `FeePercentUpdated` logs. `fee` is then the fee of swaps of token a for token b.
+    #[serde(default)]
+    pub fee_one_for_zero: Option<u32>,
+    /// Camelot pairs switched to the stable curve, the swaps of which are not simulated
+    #[serde(default)]
+    pub stable_swap: bool,
+    #[serde(default)]
+    pub token_a_transfer_tax: TransferTax,
+    #[serde(default)]
+    pub token_b_transfer_tax: TransferTax,
+    //Block of the pair creation log, if the pool was discovered from it
+    #[serde(default)]
+    pub creation_block: Option<u64>,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+    #[serde(skip)]
+    pub decimal_scaling: DecimalScalingCache,
 }
 
-#[async_trait]
-impl AutomatedMarketMaker for UniswapV2Pool {
-    fn address(&self) -> H160 {
-        self.address
+/// Event emitted by the pair when its fee changes, with the new fee as the first word of the log data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeChangeEvent {
+    pub signature: H256,
+    //Denominator of the fee reported by the event, i.e. 1000 if a fee of 1 is 0.1%
+    pub denominator: u32,
+}
+
+/// Tax charged by a fee-on-transfer token, in basis points of the transferred amount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferTax {
+    //Charged when the token is transferred out of the pool
+    pub buy_bps: u32,
+    //Charged when the token is transferred into the pool
+    pub sell_bps: u32,
+}
+
+impl TransferTax {
+    pub fn new(buy_bps: u32, sell_bps: u32) -> Self {
+        TransferTax { buy_bps, sell_bps }
     }
 
-    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
-        (self.reserve_0, self.reserve_1) = self.get_reserves(middleware).await?;
+    pub fn is_zero(&self) -> bool {
+        self.buy_bps == 0 && self.sell_bps == 0
+    }
 
-        Ok(())
+    pub fn amount_after_buy_tax(&self, amount: U256) -> U256 {
+        apply_tax(amount, self.buy_bps)
     }
 
-    async fn populate_data<M: Middleware>(
-        &mut self,
-        _block_number: Option<u64>,
-        middleware: Arc<M>,
-    ) -> Result<(), AMMError<M>> {
-        batch_request::get_v2_pool_data_batch_request(self, middleware.clone()).await?;
+    pub fn amount_after_sell_tax(&self, amount: U256) -> U256 {
+        apply_tax(amount, self.sell_bps)
+    }
+}
 
-        Ok(())
+fn apply_tax(amount: U256, tax_bps: u32) -> U256 {
+    if tax_bps == 0 {
+        return amount;
+    }
+
+    amount * U256::from(TAX_BPS_DENOMINATOR - tax_bps.min(TAX_BPS_DENOMINATOR))
+        / U256::from(TAX_BPS_DENOMINATOR)
+}
+
+//Fraction of a transferred amount left after the tax
+fn tax_multiplier(tax_bps: u32) -> Option<Q128x128> {
+    Q128x128::from_ratio(
+        U256::from(TAX_BPS_DENOMINATOR - tax_bps.min(TAX_BPS_DENOMINATOR)),
+        U256::from(TAX_BPS_DENOMINATOR),
+    )
+}
+
+impl AmmState for UniswapV2Pool {
+    fn address(&self) -> H160 {
+        self.address
     }
 
     fn sync_on_event_signatures(&self) -> Vec<H256> {
-        vec![SYNC_EVENT_SIGNATURE]
+        let mut event_signatures = vec![SYNC_EVENT_SIGNATURE];
+        if let Some(fee_change_event) = self.fee_change_event {
+            event_signatures.push(fee_change_event.signature);
+        }
+        if self.fee_one_for_zero.is_some() {
+            event_signatures.push(FEE_PERCENT_UPDATED_EVENT_SIGNATURE);
+            event_signatures.push(SET_STABLE_SWAP_EVENT_SIGNATURE);
+        }
+
+        event_signatures
     }
     fn sync_on_storage_slots(&self) -> Vec<H256> {
         vec![RESERVES_STORAGE_SLOT]
     }
 
-    fn sync_from_log(&mut self, log: Log) -> Result<(), EventLogError> {
-        let event_signature = log.topics[0];
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+        let event_signature = decode::event_signature(log)?;
 
         if event_signature == SYNC_EVENT_SIGNATURE {
-            let sync_event = SyncFilter::decode_log(&RawLog::from(log))?;
+            let reserve_0 = decode::data_uint(log, 0, 112)?.low_u128();
+            let reserve_1 = decode::data_uint(log, 1, 112)?.low_u128();
+
+            self.set_reserves(reserve_0, reserve_1)
+                .map_err(|_| EventLogError::MalformedLog(log.into()))
+        } else if let Some(fee_change_event) = self
+            .fee_change_event
+            .filter(|fee_change_event| fee_change_event.signature == event_signature)
+        {
+            let fee = normalize_fee(
+                decode::data_uint(log, 0, 256)?,
+                fee_change_event.denominator,
+            )
+            .ok_or_else(|| EventLogError::MalformedLog(log.into()))?;
+
+            self.fee = fee;
 
-            self.reserve_0 = sync_event.reserve_0;
-            self.reserve_1 = sync_event.reserve_1;
+            Ok(())
+        } else if self.fee_one_for_zero.is_some()
+            && event_signature == FEE_PERCENT_UPDATED_EVENT_SIGNATURE
+        {
+            let fee = |index| {
+                normalize_fee(decode::data_uint(log, index, 16)?, FEE_DENOMINATOR)
+                    .ok_or_else(|| EventLogError::MalformedLog(log.into()))
+            };
+            let (fee_zero_for_one, fee_one_for_zero) = (fee(0)?, fee(1)?);
+
+            self.fee = fee_zero_for_one;
+            self.fee_one_for_zero = Some(fee_one_for_zero);
+
+            Ok(())
+        } else if self.fee_one_for_zero.is_some()
+            && event_signature == SET_STABLE_SWAP_EVENT_SIGNATURE
+        {
+            //A bool only fits a single bit
+            self.stable_swap = !decode::data_uint(log, 1, 1)?.is_zero();
 
             Ok(())
         } else {
-            Err(EventLogError::InvalidEventSignature)
+            Err(EventLogError::UnexpectedEvent(log.into()))
         }
     }
 
     /// Only updates the reserves, does not fill other pool values
     ///
     /// This should be used for speedy syncs, where we only need to update the reserves
-    fn sync_from_storage(&mut self, storage: &'_ BTreeMap<H256, H256>) -> Result<(), StorageError> {
+    fn sync_from_storage(
+        &mut self,
+        storage: &'_ BTreeMap<H256, H256>,
+    ) -> Result<bool, StateSyncError> {
         let value = match storage.get(&RESERVES_STORAGE_SLOT) {
             Some(value) => value,
-            None => return Err(StorageError::StorageSlotNotFound),
+            None => return Ok(false),
         };
         if value.is_zero() {
-            return Err(StorageError::EmptyStorageSlot);
+            return Err(StateSyncError::EmptySlot(RESERVES_STORAGE_SLOT));
         }
 
-        let mut reserves1 = value[4..18].to_vec();
-        while reserves1.len() < 16 {
-            reserves1.insert(0, 0);
-        }
-        let mut reserves0 = value[18..32].to_vec();
-        while reserves0.len() < 16 {
-            reserves0.insert(0, 0);
+        //The slot packs blockTimestampLast, reserve1 and reserve0 from the highest bits down
+        let packed = U256::from_big_endian(value.as_bytes());
+        let reserve_0 = (packed & U256::from(MAX_RESERVE)).as_u128();
+        let reserve_1 = ((packed >> 112) & U256::from(MAX_RESERVE)).as_u128();
+
+        let invalid_slot = |source| StateSyncError::InvalidSlot {
+            slot: RESERVES_STORAGE_SLOT,
+            source,
+        };
+        //A pair holds both tokens once minted, a mis-mapped slot rarely does
+        if (reserve_0 == 0) != (reserve_1 == 0) {
+            return Err(invalid_slot(ArithmeticError::OneSidedReserves(
+                reserve_0, reserve_1,
+            )));
         }
 
-        // These unwraps are safe due to precise indexing
-        self.reserve_0 = u128::from_be_bytes(reserves0.try_into().unwrap());
-        self.reserve_1 = u128::from_be_bytes(reserves1.try_into().unwrap());
+        let changed = (reserve_0, reserve_1) != (self.reserve_0, self.reserve_1);
+        self.set_reserves(reserve_0, reserve_1)
+            .map_err(invalid_slot)?;
 
-        Ok(())
+        Ok(changed)
     }
 
     fn reserves(&self) -> BTreeMap<H256, H256> {
@@ -161,6 +334,22 @@ impl AutomatedMarketMaker for UniswapV2Pool {
         reserves
     }
 
+    //The fee is part of the state, pools with a fee source change it from logs. Pairs charging a fee for each
+    //direction add their second fee and curve.
+    fn state_fingerprint(&self) -> H256 {
+        let mut words = vec![
+            U256::from(self.reserve_0),
+            U256::from(self.reserve_1),
+            U256::from(self.fee),
+        ];
+        if let Some(fee_one_for_zero) = self.fee_one_for_zero {
+            words.push(U256::from(fee_one_for_zero));
+            words.push(U256::from(self.stable_swap as u8));
+        }
+
+        amm::state_fingerprint(Protocol::UniswapV2.name(), &words)
+    }
+
     //Calculates base/quote, meaning the price of base token per quote (ie. exchange rate is X base per 1 quote)
     fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
         Ok(q64_to_f64(self.calculate_price_64_x_64(base_token)?))
@@ -170,22 +359,27 @@ impl AutomatedMarketMaker for UniswapV2Pool {
         vec![self.token_a, self.token_b]
     }
 
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
     fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
         tracing::info!(?token_in, ?amount_in, "simulating swap");
+        self.check_curve()?;
 
-        if self.token_a == token_in {
-            Ok(self.get_amount_out(
-                amount_in,
-                U256::from(self.reserve_0),
-                U256::from(self.reserve_1),
-            ))
-        } else {
-            Ok(self.get_amount_out(
-                amount_in,
-                U256::from(self.reserve_1),
-                U256::from(self.reserve_0),
-            ))
-        }
+        let (tax_in, tax_out) = self.transfer_taxes(token_in);
+        let amount_in = tax_in.amount_after_sell_tax(amount_in);
+
+        let (reserve_in, reserve_out) = self.reserves_in_out(token_in);
+        let amount_out = self.get_amount_out_for(token_in, amount_in, reserve_in, reserve_out);
+
+        Ok(tax_out.amount_after_buy_tax(amount_out))
     }
 
     fn simulate_swap_mut(
@@ -194,9 +388,15 @@ impl AutomatedMarketMaker for UniswapV2Pool {
         amount_in: U256,
     ) -> Result<U256, SwapSimulationError> {
         tracing::info!(?token_in, ?amount_in, "simulating swap");
+        self.check_curve()?;
+
+        //The pool only receives the amount left after the sell tax, and the recipient pays the buy tax on the output
+        let (tax_in, tax_out) = self.transfer_taxes(token_in);
+        let amount_in = tax_in.amount_after_sell_tax(amount_in);
 
         if self.token_a == token_in {
-            let amount_out = self.get_amount_out(
+            let amount_out = self.get_amount_out_for(
+                token_in,
                 amount_in,
                 U256::from(self.reserve_0),
                 U256::from(self.reserve_1),
@@ -210,9 +410,10 @@ impl AutomatedMarketMaker for UniswapV2Pool {
 
             tracing::trace!(?self.reserve_0, ?self.reserve_1, "pool reserves after");
 
-            Ok(amount_out)
+            Ok(tax_out.amount_after_buy_tax(amount_out))
         } else {
-            let amount_out = self.get_amount_out(
+            let amount_out = self.get_amount_out_for(
+                token_in,
                 amount_in,
                 U256::from(self.reserve_1),
                 U256::from(self.reserve_0),
@@ -226,10 +427,43 @@ impl AutomatedMarketMaker for UniswapV2Pool {
 
             tracing::trace!(?self.reserve_0, ?self.reserve_1, "pool reserves after");
 
-            Ok(amount_out)
+            Ok(tax_out.amount_after_buy_tax(amount_out))
         }
     }
 
+    fn gradient(&self, token_in: H160, amount_in: U256) -> Result<Q128x128, SwapSimulationError> {
+        self.check_curve()?;
+
+        let (tax_in, tax_out) = self.transfer_taxes(token_in);
+        let amount_in = tax_in.amount_after_sell_tax(amount_in);
+
+        let (reserve_in, reserve_out) = self.reserves_in_out(token_in);
+
+        if reserve_in.is_zero() || reserve_out.is_zero() {
+            return Ok(Q128x128::zero());
+        }
+
+        //The derivative of x*f*r_out / (r_in*D + x*f) is f*r_out / (r_in*D + x*f) * r_in*D / (r_in*D + x*f),
+        //each factor fits in a Q128.128 while their combined numerator does not fit in a U256
+        let fee = U256::from(FEE_DENOMINATOR - self.swap_fee(token_in));
+        let scaled_reserve_in = reserve_in * U256::from(FEE_DENOMINATOR);
+        let denominator = match amount_in
+            .checked_mul(fee)
+            .and_then(|amount_in_with_fee| amount_in_with_fee.checked_add(scaled_reserve_in))
+        {
+            Some(denominator) => denominator,
+            None => return Ok(Q128x128::zero()),
+        };
+
+        Q128x128::from_ratio(fee * reserve_out, denominator)
+            .and_then(|gradient| {
+                gradient.checked_mul(Q128x128::from_ratio(scaled_reserve_in, denominator)?)
+            })
+            .and_then(|gradient| gradient.checked_mul(tax_multiplier(tax_in.sell_bps)?))
+            .and_then(|gradient| gradient.checked_mul(tax_multiplier(tax_out.buy_bps)?))
+            .ok_or(SwapSimulationError::GradientOverflow)
+    }
+
     fn get_token_out(&self, token_in: H160) -> H160 {
         if self.token_a == token_in {
             self.token_b
@@ -249,7 +483,85 @@ impl AutomatedMarketMaker for UniswapV2Pool {
     }
 }
 
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for UniswapV2Pool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        let (reserve_0, reserve_1) = self.get_reserves(middleware.clone()).await?;
+        self.set_reserves(reserve_0, reserve_1)?;
+
+        if self.fee_one_for_zero.is_some() {
+            let (fee_zero_for_one, fee_one_for_zero, stable_swap) =
+                self.get_directional_fees(middleware).await?;
+            self.fee = fee_zero_for_one;
+            self.fee_one_for_zero = Some(fee_one_for_zero);
+            self.stable_swap = stable_swap;
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_v2_pool_data_batch_request(self, block_number, middleware.clone())
+            .await?;
+
+        Ok(())
+    }
+}
+
 impl UniswapV2Pool {
+    /// Builder for pools constructed offline, i.e. in tests or from indexed data, validating the pool when built
+    pub fn builder() -> UniswapV2PoolBuilder {
+        UniswapV2PoolBuilder::default()
+    }
+
+    /// Scaling between the decimals of token a and token b, cached on the pool
+    pub fn decimal_scaling(&self) -> DecimalScaling {
+        self.decimal_scaling
+            .get(self.token_a_decimals, self.token_b_decimals)
+    }
+
+    /// Recomputes the cached decimal scaling, called once the decimals of the pool are populated
+    pub fn reset_decimal_scaling(&mut self) {
+        self.decimal_scaling =
+            DecimalScalingCache::new(self.token_a_decimals, self.token_b_decimals);
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Sets both reserves, rejecting reserves above `MAX_RESERVE` which the pair could not hold
+    pub fn set_reserves(
+        &mut self,
+        reserve_0: u128,
+        reserve_1: u128,
+    ) -> Result<(), ArithmeticError> {
+        for reserve in [reserve_0, reserve_1] {
+            if reserve > MAX_RESERVE {
+                return Err(ArithmeticError::ReserveOverflow(reserve));
+            }
+        }
+
+        self.reserve_0 = reserve_0;
+        self.reserve_1 = reserve_1;
+
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         address: H160,
@@ -270,14 +582,57 @@ impl UniswapV2Pool {
             reserve_0,
             reserve_1,
             fee,
+            fee_change_event: None,
+            fee_one_for_zero: None,
+            stable_swap: false,
+            token_a_transfer_tax: TransferTax::default(),
+            token_b_transfer_tax: TransferTax::default(),
+            creation_block: None,
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::new(token_a_decimals, token_b_decimals),
         }
     }
 
-    //Creates a new instance of the pool from the pair address, and syncs the pool data
-    pub async fn new_from_address<M: Middleware>(
+    /// Creates the pool at `pair_address` populated at the latest block, ready to simulate swaps.
+    /// Errors with `AMMError::AMMVariantMismatch` if the contract is not a Uniswap V2 pair.
+    #[cfg(feature = "rpc")]
+    pub async fn new_from_address<M: 'static + Middleware>(
         pair_address: H160,
         fee: u32,
         middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        UniswapV2Pool::new_from_address_at_block(pair_address, fee, None, middleware).await
+    }
+
+    /// Same as `new_from_address`, populating the pool at `block_number`
+    #[cfg(feature = "rpc")]
+    pub async fn new_from_address_at_block<M: 'static + Middleware>(
+        pair_address: H160,
+        fee: u32,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        detect::expect_variant(
+            pair_address,
+            DetectedVariant::UniswapV2Pool,
+            middleware.clone(),
+        )
+        .await?;
+
+        UniswapV2Pool::new_populated(pair_address, fee, block_number, middleware).await
+    }
+
+    //Populates the pool without probing the contract, for pairs known to be V2 pools i.e. from their factory
+    #[cfg(feature = "rpc")]
+    pub(crate) async fn new_populated<M: Middleware>(
+        pair_address: H160,
+        fee: u32,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
     ) -> Result<Self, AMMError<M>> {
         let mut pool = UniswapV2Pool {
             address: pair_address,
@@ -288,9 +643,21 @@ impl UniswapV2Pool {
             reserve_0: 0,
             reserve_1: 0,
             fee,
+            fee_change_event: None,
+            fee_one_for_zero: None,
+            stable_swap: false,
+            token_a_transfer_tax: TransferTax::default(),
+            token_b_transfer_tax: TransferTax::default(),
+            creation_block: None,
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::default(),
         };
 
-        pool.populate_data(None, middleware.clone()).await?;
+        pool.populate_data(block_number, middleware).await?;
 
         if !pool.data_is_populated() {
             return Err(AMMError::PoolDataError);
@@ -298,25 +665,28 @@ impl UniswapV2Pool {
 
         Ok(pool)
     }
+    #[cfg(feature = "rpc")]
     pub async fn new_from_log<M: Middleware>(
         log: Log,
         fee: u32,
         middleware: Arc<M>,
     ) -> Result<Self, AMMError<M>> {
-        let event_signature = log.topics[0];
+        let event_signature = decode::event_signature(&log)?;
 
         if event_signature == PAIR_CREATED_EVENT_SIGNATURE {
             let pair_created_event = factory::PairCreatedFilter::decode_log(&RawLog::from(log))?;
-            UniswapV2Pool::new_from_address(pair_created_event.pair, fee, middleware).await
+            UniswapV2Pool::new_populated(pair_created_event.pair, fee, None, middleware).await
         } else {
-            Err(EventLogError::InvalidEventSignature)?
+            Err(EventLogError::UnexpectedEvent((&log).into()))?
         }
     }
 
+    #[cfg(feature = "rpc")]
     pub fn new_empty_pool_from_log(log: Log) -> Result<Self, EventLogError> {
-        let event_signature = log.topics[0];
+        let event_signature = decode::event_signature(&log)?;
 
         if event_signature == PAIR_CREATED_EVENT_SIGNATURE {
+            let creation_block = log.block_number.map(|block_number| block_number.as_u64());
             let pair_created_event = factory::PairCreatedFilter::decode_log(&RawLog::from(log))?;
 
             Ok(UniswapV2Pool {
@@ -328,9 +698,21 @@ impl UniswapV2Pool {
                 reserve_0: 0,
                 reserve_1: 0,
                 fee: 0,
+                fee_change_event: None,
+                fee_one_for_zero: None,
+                stable_swap: false,
+                token_a_transfer_tax: TransferTax::default(),
+                token_b_transfer_tax: TransferTax::default(),
+                creation_block,
+                token_a_symbol: None,
+                token_a_name: None,
+                token_b_symbol: None,
+                token_b_name: None,
+                serde_version: SerdeVersion::CURRENT,
+                decimal_scaling: DecimalScalingCache::default(),
             })
         } else {
-            Err(EventLogError::InvalidEventSignature)?
+            Err(EventLogError::UnexpectedEvent((&log).into()))?
         }
     }
 
@@ -338,6 +720,47 @@ impl UniswapV2Pool {
         self.fee
     }
 
+    /// Fee of swaps of `token_in` over `FEE_DENOMINATOR`, which only depends on `token_in` on pairs charging a fee
+    /// for each direction
+    pub fn swap_fee(&self, token_in: H160) -> u32 {
+        match self.fee_one_for_zero {
+            Some(fee_one_for_zero) if token_in == self.token_b => fee_one_for_zero,
+            _ => self.fee,
+        }
+    }
+
+    //Swaps of pairs on the stable curve are not simulated rather than priced with the constant product
+    fn check_curve(&self) -> Result<(), SwapSimulationError> {
+        if self.stable_swap {
+            return Err(SwapSimulationError::UnsupportedStableSwap(self.address));
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if either token charges a transfer tax, in which case swaps through the pool receive less than `get_amount_out`
+    pub fn has_transfer_tax(&self) -> bool {
+        !self.token_a_transfer_tax.is_zero() || !self.token_b_transfer_tax.is_zero()
+    }
+
+    //Returns the reserves of the input and output token
+    fn reserves_in_out(&self, token_in: H160) -> (U256, U256) {
+        if self.token_a == token_in {
+            (U256::from(self.reserve_0), U256::from(self.reserve_1))
+        } else {
+            (U256::from(self.reserve_1), U256::from(self.reserve_0))
+        }
+    }
+
+    //Returns the transfer taxes of the input and output token
+    fn transfer_taxes(&self, token_in: H160) -> (TransferTax, TransferTax) {
+        if self.token_a == token_in {
+            (self.token_a_transfer_tax, self.token_b_transfer_tax)
+        } else {
+            (self.token_b_transfer_tax, self.token_a_transfer_tax)
+        }
+    }
+
     pub fn data_is_populated(&self) -> bool {
         !(self.token_a.is_zero()
             || self.token_b.is_zero()
@@ -345,6 +768,7 @@ impl UniswapV2Pool {
             || self.reserve_1 == 0)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_reserves<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -364,6 +788,33 @@ impl UniswapV2Pool {
         Ok((reserve_0, reserve_1))
     }
 
+    /// Reads the fees of each direction and the curve of a Camelot pair
+    #[cfg(feature = "rpc")]
+    pub async fn get_directional_fees<M: Middleware>(
+        &self,
+        middleware: Arc<M>,
+    ) -> Result<(u32, u32, bool), AMMError<M>> {
+        let pair = ICamelotPair::new(self.address, middleware);
+
+        let token_0_fee_percent = pair.token_0_fee_percent().call().await?;
+        let token_1_fee_percent = pair.token_1_fee_percent().call().await?;
+        let stable_swap = pair.stable_swap().call().await?;
+
+        tracing::trace!(token_0_fee_percent, token_1_fee_percent, stable_swap);
+
+        //The fees of Camelot pairs are over the same denominator as the pool fees
+        match (
+            normalize_fee(U256::from(token_0_fee_percent), FEE_DENOMINATOR),
+            normalize_fee(U256::from(token_1_fee_percent), FEE_DENOMINATOR),
+        ) {
+            (Some(fee_zero_for_one), Some(fee_one_for_zero)) => {
+                Ok((fee_zero_for_one, fee_one_for_zero, stable_swap))
+            }
+            _ => Err(AMMError::PoolDataError),
+        }
+    }
+
+    #[cfg(feature = "rpc")]
     pub async fn get_token_decimals<M: Middleware>(
         &mut self,
         middleware: Arc<M>,
@@ -383,6 +834,7 @@ impl UniswapV2Pool {
         Ok((token_a_decimals, token_b_decimals))
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_token_0<M: Middleware>(
         &self,
         pair_address: H160,
@@ -398,6 +850,7 @@ impl UniswapV2Pool {
         Ok(token0)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_token_1<M: Middleware>(
         &self,
         pair_address: H160,
@@ -414,20 +867,9 @@ impl UniswapV2Pool {
     }
 
     pub fn calculate_price_64_x_64(&self, base_token: H160) -> Result<u128, ArithmeticError> {
-        let decimal_shift = self.token_a_decimals as i8 - self.token_b_decimals as i8;
-
-        let (r_0, r_1) = if decimal_shift < 0 {
-            (
-                U256::from(self.reserve_0)
-                    * U256::from(10u128.pow(decimal_shift.unsigned_abs() as u32)),
-                U256::from(self.reserve_1),
-            )
-        } else {
-            (
-                U256::from(self.reserve_0),
-                U256::from(self.reserve_1) * U256::from(10u128.pow(decimal_shift as u32)),
-            )
-        };
+        let (r_0, r_1) = self
+            .decimal_scaling()
+            .normalize(U256::from(self.reserve_0), U256::from(self.reserve_1))?;
 
         if base_token == self.token_a {
             if r_0.is_zero() {
@@ -442,32 +884,39 @@ impl UniswapV2Pool {
         }
     }
 
+    /// Output of a swap against `reserve_in` and `reserve_out` charged `fee`, which on pairs charging a fee for each
+    /// direction is only the fee of swaps of token a for token b, see `get_amount_out_for`
     pub fn get_amount_out(&self, amount_in: U256, reserve_in: U256, reserve_out: U256) -> U256 {
-        tracing::trace!(?amount_in, ?reserve_in, ?reserve_out);
-
-        if amount_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
-            return U256::zero();
-        }
-        let fee = (10000 - (self.fee / 10)) / 10; //Fee of 300 => (10,000 - 30) / 10  = 997
-        let amount_in_with_fee = amount_in * U256::from(fee);
-        let numerator = amount_in_with_fee * reserve_out;
-        let denominator = reserve_in * U256::from(1000) + amount_in_with_fee;
-
-        tracing::trace!(?fee, ?amount_in_with_fee, ?numerator, ?denominator);
-
-        numerator / denominator
+        amount_out(amount_in, reserve_in, reserve_out, self.fee)
     }
 
+    /// Input of a swap for `amount_out` charged `fee`, like `get_amount_out`
     pub fn get_amount_in(&self, amount_out: U256, reserve_in: U256, reserve_out: U256) -> U256 {
-        if amount_out.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
-            return U256::zero();
-        }
-        let fee = (10000 - (self.fee / 10)) / 10; //Fee of 300 => (10,000 - 30) / 10  = 997
-        let amount_out = amount_out * U256::from(1000);
-        let numerator = reserve_in * amount_out;
-        let denominator = (reserve_out - amount_out) * U256::from(fee);
+        amount_in(amount_out, reserve_in, reserve_out, self.fee)
+    }
 
-        (numerator / denominator) + U256::one()
+    /// Output of a swap of `amount_in` of `token_in` against `reserve_in` and `reserve_out`, charged the fee of
+    /// swaps of `token_in`
+    pub fn get_amount_out_for(
+        &self,
+        token_in: H160,
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+    ) -> U256 {
+        amount_out(amount_in, reserve_in, reserve_out, self.swap_fee(token_in))
+    }
+
+    /// Input of `token_in` of a swap for `amount_out`, charged the fee of swaps of `token_in` like
+    /// `get_amount_out_for`
+    pub fn get_amount_in_for(
+        &self,
+        token_in: H160,
+        amount_out: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+    ) -> U256 {
+        amount_in(amount_out, reserve_in, reserve_out, self.swap_fee(token_in))
     }
 
     pub fn swap_calldata(
@@ -514,6 +963,148 @@ pub const U256_8: U256 = U256([8, 0, 0, 0]);
 pub const U256_4: U256 = U256([4, 0, 0, 0]);
 pub const U256_2: U256 = U256([2, 0, 0, 0]);
 
+//Constant product output of a swap charged `fee` over `FEE_DENOMINATOR`
+fn amount_in(amount_out: U256, reserve_in: U256, reserve_out: U256, fee: u32) -> U256 {
+    if amount_out.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
+        return U256::zero();
+    }
+    let fee = FEE_DENOMINATOR - fee;
+    let denominator = (reserve_out - amount_out) * U256::from(fee);
+
+    //Rounded up as in the router, inputs too large to represent saturate
+    mul_div(
+        reserve_in,
+        amount_out * U256::from(FEE_DENOMINATOR),
+        denominator,
+    )
+    .map_or(U256::MAX, |amount_in| amount_in.saturating_add(U256::one()))
+}
+
+fn amount_out(amount_in: U256, reserve_in: U256, reserve_out: U256, fee: u32) -> U256 {
+    tracing::trace!(?amount_in, ?reserve_in, ?reserve_out);
+
+    if amount_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
+        return U256::zero();
+    }
+    let fee = FEE_DENOMINATOR - fee; //Fee of 300 => 100,000 - 300 = 99,700
+    let amount_in_with_fee = amount_in * U256::from(fee);
+    let denominator = reserve_in * U256::from(FEE_DENOMINATOR) + amount_in_with_fee;
+
+    tracing::trace!(?fee, ?amount_in_with_fee, ?reserve_out, ?denominator);
+
+    //The output is below reserve_out, so only the intermediate product can overflow
+    mul_div(amount_in_with_fee, reserve_out, denominator).unwrap_or_default()
+}
+
+/// Converts a fee expressed as a fraction of `denominator` into the pool's fee units, see `FEE_DENOMINATOR`
+pub fn normalize_fee(fee: U256, denominator: u32) -> Option<u32> {
+    if denominator == 0 {
+        return None;
+    }
+
+    let fee = fee.checked_mul(U256::from(FEE_DENOMINATOR))? / U256::from(denominator);
+    if fee > U256::from(FEE_DENOMINATOR) {
+        return None;
+    }
+
+    Some(fee.as_u32())
+}
+
+/// Optimal amount of `base_token` to sell on one of `pool_a` and `pool_b` and buy back on the other, with the profit
+/// in `base_token` it is expected to make.
+///
+/// The other token of the pair is bought on the pool paying more of it per `base_token`. Chained, the two swaps output `x * a / (b + c * x)` for an input
+/// `x`, so the optimal input solves `a * b / (b + c * x)^2 = 1` in closed form, computed with integer math on the
+/// raw reserves and the fees of each swap, which also makes the decimals of the pair irrelevant. The profit is the
+/// output of both swaps at that input, minus the input.
+/// Returns None when the pools do not trade the same pair including `base_token`, either pool has a transfer tax
+/// or is on the stable curve, or no direction is profitable.
+pub fn optimal_arb_amount(
+    pool_a: &UniswapV2Pool,
+    pool_b: &UniswapV2Pool,
+    base_token: H160,
+) -> Option<(U256, U256)> {
+    let same_pair = (pool_a.token_a == pool_b.token_a && pool_a.token_b == pool_b.token_b)
+        || (pool_a.token_a == pool_b.token_b && pool_a.token_b == pool_b.token_a);
+    if !same_pair
+        || pool_a.token_a == pool_a.token_b
+        || (pool_a.token_a != base_token && pool_a.token_b != base_token)
+        || pool_a.has_transfer_tax()
+        || pool_b.has_transfer_tax()
+        || pool_a.stable_swap
+        || pool_b.stable_swap
+    {
+        return None;
+    }
+
+    let quote_token = pool_a.get_token_out(base_token);
+    [(pool_a, pool_b), (pool_b, pool_a)]
+        .into_iter()
+        .find_map(|(buy, sell)| {
+            let amount_in = optimal_cycle_input(buy, sell, base_token, quote_token)?;
+
+            let (reserve_in, reserve_out) = buy.reserves_in_out(base_token);
+            let amount_bought =
+                amount_out(amount_in, reserve_in, reserve_out, buy.swap_fee(base_token));
+            let (reserve_in, reserve_out) = sell.reserves_in_out(quote_token);
+            let amount_out = amount_out(
+                amount_bought,
+                reserve_in,
+                reserve_out,
+                sell.swap_fee(quote_token),
+            );
+
+            amount_out
+                .checked_sub(amount_in)
+                .filter(|profit| !profit.is_zero())
+                .map(|profit| (amount_in, profit))
+        })
+}
+
+//Input maximizing the profit of selling `base_token` on `buy` and buying it back on `sell`, None if unprofitable
+fn optimal_cycle_input(
+    buy: &UniswapV2Pool,
+    sell: &UniswapV2Pool,
+    base_token: H160,
+    quote_token: H160,
+) -> Option<U256> {
+    let (reserve_in_buy, reserve_out_buy) = buy.reserves_in_out(base_token);
+    let (reserve_in_sell, reserve_out_sell) = sell.reserves_in_out(quote_token);
+    if reserve_in_buy.is_zero()
+        || reserve_out_buy.is_zero()
+        || reserve_in_sell.is_zero()
+        || reserve_out_sell.is_zero()
+    {
+        return None;
+    }
+
+    let denominator = U512::from(FEE_DENOMINATOR);
+    let fee_buy = U512::from(FEE_DENOMINATOR.checked_sub(buy.swap_fee(base_token))?);
+    let fee_sell = U512::from(FEE_DENOMINATOR.checked_sub(sell.swap_fee(quote_token))?);
+
+    //Scaled by the fee denominator squared, a = fee_buy * fee_sell * reserve_out_buy * reserve_out_sell,
+    //b = denominator^2 * reserve_in_buy * reserve_in_sell and c = fee_buy * (denominator * reserve_in_sell +
+    //fee_sell * reserve_out_buy). The optimal input is (sqrt(a * b) - b) / c, a * b only fits in a U512 once the
+    //denominator^2 is taken out of the square root.
+    let reserves_in = reserve_in_buy.full_mul(reserve_in_sell);
+    let sqrt_ab = (fee_buy * fee_sell)
+        .checked_mul(reserve_out_buy.full_mul(reserve_out_sell))?
+        .checked_mul(reserves_in)?
+        .integer_sqrt()
+        * denominator;
+    let b = denominator * denominator * reserves_in;
+    if sqrt_ab <= b {
+        return None;
+    }
+
+    let c = fee_buy
+        * (denominator * U512::from(reserve_in_sell) + fee_sell * U512::from(reserve_out_buy));
+
+    U256::try_from((sqrt_ab - b) / c)
+        .ok()
+        .filter(|amount_in| !amount_in.is_zero())
+}
+
 pub fn div_uu(x: U256, y: U256) -> Result<u128, ArithmeticError> {
     if !y.is_zero() {
         let mut answer;
@@ -596,25 +1187,32 @@ pub fn div_uu(x: U256, y: U256) -> Result<u128, ArithmeticError> {
     }
 }
 
-//Converts a Q64 fixed point to a Q16 fixed point -> f64
-pub fn q64_to_f64(x: u128) -> f64 {
-    BigFloat::from(x)
-        .div(&BigFloat::from(U128_0X10000000000000000))
-        .to_f64()
-}
-
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr, sync::Arc};
+    use std::{collections::BTreeMap, str::FromStr, sync::Arc};
 
     use ethers::{
+        abi::Token,
         providers::{Http, Provider},
-        types::{H160, U256},
+        types::{Log, H160, H256, U256},
     };
 
-    use crate::amm::AutomatedMarketMaker;
+    use crate::{
+        amm::{detect::DetectedVariant, AmmState, AutomatedMarketMaker, AMM},
+        errors::{AMMError, ArithmeticError, StateSyncError, SwapSimulationError},
+        routing::{optimize_input, Route, TradeBounds},
+        test_utils::ForkHarness,
+    };
 
-    use super::UniswapV2Pool;
+    use super::{
+        factory::{
+            IUniswapV2Factory, UniswapV2Factory, BISWAP_FEE_SOURCE, PANCAKESWAP_V2_FEE_SOURCE,
+            PANCAKESWAP_V2_INIT_CODE_HASH,
+        },
+        optimal_arb_amount, q64_to_f64, FeeChangeEvent, TransferTax, UniswapV2Pool,
+        FEE_PERCENT_UPDATED_EVENT_SIGNATURE, MAX_RESERVE, RESERVES_STORAGE_SLOT,
+        SET_STABLE_SWAP_EVENT_SIGNATURE, U128_0X10000000000000000,
+    };
 
     #[test]
     fn test_swap_calldata() -> eyre::Result<()> {
@@ -658,6 +1256,46 @@ mod tests {
         assert_eq!(pool.token_b_decimals, 18);
         assert_eq!(pool.fee, 300);
 
+        //A Uniswap V3 pool is rejected before any state is fetched
+        let error = UniswapV2Pool::new_from_address(
+            H160::from_str("0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640")?,
+            300,
+            middleware.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            AMMError::AMMVariantMismatch {
+                detected: Some(DetectedVariant::UniswapV3Pool),
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_swap_on_fork() -> eyre::Result<()> {
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+
+        let pool = UniswapV2Pool::new_from_address(
+            H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+            300,
+            fork.provider.clone(),
+        )
+        .await?;
+        let (usdc, weth) = (pool.token_a, pool.token_b);
+        let mut amm = AMM::UniswapV2Pool(pool);
+
+        fork.assert_swap_matches(&mut amm, weth, U256::exp10(19))
+            .await?;
+        fork.assert_swap_matches(&mut amm, usdc, U256::from(50_000_000_000_u64))
+            .await?;
+
         Ok(())
     }
 
@@ -695,16 +1333,14 @@ mod tests {
     fn test_calculate_price_edge_case() -> eyre::Result<()> {
         let token_a = H160::from_str("0x0d500b1d8e8ef31e21c99d1db9a6444d3adf1270")?;
         let token_b = H160::from_str("0x8f18dc399594b451eda8c5da02d0563c0b2d0f16")?;
-        let x = UniswapV2Pool {
-            address: H160::from_str("0x652a7b75c229850714d4a11e856052aac3e9b065")?,
-            token_a,
-            token_a_decimals: 18,
-            token_b,
-            token_b_decimals: 9,
-            reserve_0: 23595096345912178729927,
-            reserve_1: 154664232014390554564,
-            fee: 300,
-        };
+        let x = UniswapV2Pool::builder()
+            .with_address(H160::from_str(
+                "0x652a7b75c229850714d4a11e856052aac3e9b065",
+            )?)
+            .with_token_a(token_a, 18)
+            .with_token_b(token_b, 9)
+            .with_reserves(23595096345912178729927, 154664232014390554564)
+            .build()?;
 
         assert!(x.calculate_price(token_a)? != 0.0);
         assert!(x.calculate_price(token_b)? != 0.0);
@@ -759,4 +1395,575 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_pools_paginated() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let factory = UniswapV2Factory::new(
+            H160::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")?,
+            10000835,
+            300,
+        );
+
+        let mut pages = factory.pools_paginated(0, 10, middleware.clone());
+        let first_page = pages.next_page().await?.expect("first page");
+        assert_eq!(first_page.cursor, 10);
+        assert_eq!(pages.cursor(), 10);
+
+        //Resuming from the persisted cursor should yield the same pools as a single range
+        let mut resumed_pages = factory.pools_paginated(first_page.cursor, 10, middleware.clone());
+        let second_page = resumed_pages.next_page().await?.expect("second page");
+        assert_eq!(second_page.cursor, 20);
+
+        let range = factory.get_pools_range(0, 20, middleware).await?;
+        let paginated = first_page
+            .amms
+            .iter()
+            .chain(second_page.amms.iter())
+            .map(|amm| amm.address())
+            .collect::<Vec<H160>>();
+
+        assert_eq!(
+            range.iter().map(|amm| amm.address()).collect::<Vec<H160>>(),
+            paginated
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_pair_address() -> eyre::Result<()> {
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")?;
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")?;
+        let dai = H160::from_str("0x6B175474E89094C44Da98b954EedeAC495271d0F")?;
+
+        let uniswap_v2_factory = UniswapV2Factory::new(
+            H160::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")?,
+            10000835,
+            300,
+        );
+
+        let weth_usdc = H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?;
+        assert_eq!(
+            uniswap_v2_factory.compute_pair_address(weth, usdc),
+            weth_usdc
+        );
+        //Token order does not matter
+        assert_eq!(
+            uniswap_v2_factory.compute_pair_address(usdc, weth),
+            weth_usdc
+        );
+
+        assert_eq!(
+            uniswap_v2_factory.compute_pair_address(dai, weth),
+            H160::from_str("0xA478c2975Ab1Ea89e8196811F51A7B7Ade33eB11")?
+        );
+
+        //PancakeSwap V2 on BSC
+        let pancakeswap_v2_factory = UniswapV2Factory::new(
+            H160::from_str("0xcA143Ce32Fe78f1f7019d7d551a6402fC5350c73")?,
+            6809737,
+            250,
+        );
+        let wbnb = H160::from_str("0xbb4CdB9CBd36B01bD1cBaEBF2De08d9173bc095c")?;
+        let busd = H160::from_str("0xe9e7CEA3DedcA5984780Bafc599bD69ADd087D56")?;
+
+        assert_eq!(
+            pancakeswap_v2_factory.compute_pair_address_with_init_code_hash(
+                wbnb,
+                busd,
+                PANCAKESWAP_V2_INIT_CODE_HASH
+            ),
+            H160::from_str("0x58F876857a02D6762E0101bb5C46A8c1ED44Dc16")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_reserves() -> eyre::Result<()> {
+        let mut pool = UniswapV2Pool::builder()
+            .with_token_a(H160::from_low_u64_be(1), 18)
+            .with_token_b(H160::from_low_u64_be(2), 18)
+            .with_reserves(1000000, 1000000)
+            .build()?;
+
+        pool.set_reserves(MAX_RESERVE, 1)?;
+        assert_eq!((pool.reserve_0, pool.reserve_1), (MAX_RESERVE, 1));
+
+        assert!(matches!(
+            pool.set_reserves(1, MAX_RESERVE + 1),
+            Err(ArithmeticError::ReserveOverflow(reserve)) if reserve == MAX_RESERVE + 1
+        ));
+        assert_eq!((pool.reserve_0, pool.reserve_1), (MAX_RESERVE, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_storage() -> eyre::Result<()> {
+        let builder = UniswapV2Pool::builder()
+            .with_token_a(H160::from_low_u64_be(1), 18)
+            .with_token_b(H160::from_low_u64_be(2), 18);
+        let synced = builder.clone().with_reserves(MAX_RESERVE, 2000).build()?;
+        let mut pool = builder.with_reserves(1000000, 1000000).build()?;
+
+        assert!(pool.sync_from_storage(&synced.reserves())?);
+        assert_eq!((pool.reserve_0, pool.reserve_1), (MAX_RESERVE, 2000));
+        assert!(!pool.sync_from_storage(&synced.reserves())?);
+        assert!(!pool.sync_from_storage(&BTreeMap::new())?);
+
+        //A slot holding a single word, e.g. a counter, decodes to reserves of one token only
+        let corrupted = BTreeMap::from([(RESERVES_STORAGE_SLOT, H256::from_low_u64_be(12345))]);
+        assert!(matches!(
+            pool.sync_from_storage(&corrupted),
+            Err(StateSyncError::InvalidSlot {
+                slot,
+                source: ArithmeticError::OneSidedReserves(12345, 0),
+            }) if slot == RESERVES_STORAGE_SLOT
+        ));
+        let empty = BTreeMap::from([(RESERVES_STORAGE_SLOT, H256::zero())]);
+        assert!(matches!(
+            pool.sync_from_storage(&empty),
+            Err(StateSyncError::EmptySlot(slot)) if slot == RESERVES_STORAGE_SLOT
+        ));
+        assert_eq!((pool.reserve_0, pool.reserve_1), (MAX_RESERVE, 2000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_with_transfer_tax() -> eyre::Result<()> {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let builder = UniswapV2Pool::builder()
+            .with_token_a(token_a, 18)
+            .with_token_b(token_b, 18)
+            .with_reserves(1000000, 1000000);
+        let untaxed = builder.clone().build()?;
+        let mut pool = builder
+            .with_transfer_taxes(TransferTax::new(0, 500), TransferTax::new(1000, 0))
+            .build()?;
+
+        assert!(!untaxed.has_transfer_tax());
+        assert!(pool.has_transfer_tax());
+
+        //5% of the input is taxed before reaching the pool, and 10% of the output is taxed on the way out
+        let amount_out =
+            untaxed.get_amount_out(U256::from(950), U256::from(1000000), U256::from(1000000));
+        let expected = amount_out * U256::from(9000) / U256::from(10000);
+
+        assert_eq!(
+            pool.simulate_swap(token_a, U256::from(1000)).unwrap(),
+            expected
+        );
+        assert_eq!(
+            pool.simulate_swap_mut(token_a, U256::from(1000)).unwrap(),
+            expected
+        );
+        assert_eq!(pool.reserve_0, 1000950);
+        assert_eq!(pool.reserve_1, 1000000 - amount_out.as_u128());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimal_arb_amount() -> eyre::Result<()> {
+        let (weth, usdc) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let pool_a = UniswapV2Pool::builder()
+            .with_token_a(weth, 18)
+            .with_token_b(usdc, 6)
+            .with_reserves_human(10_000.0, 20_000_000.0)
+            .build()?;
+        //WETH is 2% more expensive, with the tokens in the other order
+        let pool_b = UniswapV2Pool::builder()
+            .with_token_a(usdc, 6)
+            .with_token_b(weth, 18)
+            .with_reserves_human(10_200_000.0, 5_000.0)
+            .with_fee(250)
+            .build()?;
+
+        //Buying USDC on pool b with WETH and selling it on pool a, whichever order the pools are given in
+        let (amount_in, profit) = optimal_arb_amount(&pool_a, &pool_b, weth).unwrap();
+        assert_eq!(
+            optimal_arb_amount(&pool_b, &pool_a, weth),
+            Some((amount_in, profit))
+        );
+
+        let cycle_profit = |amount_in: U256| {
+            let amount_bought = pool_b.simulate_swap(weth, amount_in).unwrap();
+            pool_a
+                .simulate_swap(usdc, amount_bought)
+                .unwrap()
+                .saturating_sub(amount_in)
+        };
+        assert_eq!(cycle_profit(amount_in), profit);
+        for offset in [U256::exp10(12), U256::exp10(15)] {
+            assert!(cycle_profit(amount_in - offset) <= profit);
+            assert!(cycle_profit(amount_in + offset) <= profit);
+        }
+
+        //The same trade started from USDC
+        let (amount_in, profit) = optimal_arb_amount(&pool_a, &pool_b, usdc).unwrap();
+        assert!(!amount_in.is_zero() && !profit.is_zero());
+
+        //Prices within the fees leave nothing to arbitrage
+        let pool_c = UniswapV2Pool {
+            reserve_1: 20_050_000 * 10_u128.pow(6),
+            ..pool_a.clone()
+        };
+        assert_eq!(optimal_arb_amount(&pool_a, &pool_c, weth), None);
+        assert_eq!(optimal_arb_amount(&pool_a, &pool_a, weth), None);
+
+        //Transfer taxes and mismatched pairs are not handled
+        let taxed = UniswapV2Pool {
+            token_b_transfer_tax: TransferTax::new(100, 100),
+            ..pool_b.clone()
+        };
+        assert_eq!(optimal_arb_amount(&pool_a, &taxed, weth), None);
+        let other_pair = UniswapV2Pool {
+            token_a: H160::from_low_u64_be(3),
+            ..pool_b.clone()
+        };
+        assert_eq!(optimal_arb_amount(&pool_a, &other_pair, weth), None);
+        assert_eq!(
+            optimal_arb_amount(&pool_a, &pool_b, H160::from_low_u64_be(3)),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimal_arb_amount_matches_optimizer() -> eyre::Result<()> {
+        let (base, quote) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let fees = [30, 100, 250, 300, 1000];
+
+        //xorshift64, so the cases are reproducible
+        let mut seed = 0x2545f4914f6cdd1d_u64;
+        let mut next = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+
+        for _ in 0..200 {
+            let reserve_base = (1 + next(1_000_000)) as u128 * 10_u128.pow(18);
+            let reserve_quote = (1 + next(1_000_000)) as u128 * 10_u128.pow(6);
+            //Up to 5% apart in either direction
+            let skew = 950_000 + next(100_001) as u128;
+            let depth = 1 + next(10) as u128;
+
+            let pool_a = UniswapV2Pool {
+                token_a: base,
+                token_b: quote,
+                reserve_0: reserve_base,
+                reserve_1: reserve_quote,
+                fee: fees[next(5) as usize],
+                ..Default::default()
+            };
+            let pool_b = UniswapV2Pool {
+                token_a: base,
+                token_b: quote,
+                reserve_0: reserve_base * depth,
+                reserve_1: reserve_quote * depth * skew / 1_000_000,
+                fee: fees[next(5) as usize],
+                ..Default::default()
+            };
+
+            let amms = vec![
+                AMM::UniswapV2Pool(pool_a.clone()),
+                AMM::UniswapV2Pool(pool_b.clone()),
+            ];
+            let bounds = TradeBounds::new(U256::from(reserve_base));
+            let mut best = U256::zero();
+            for hops in [vec![(0, base), (1, quote)], vec![(1, base), (0, quote)]] {
+                let trade = optimize_input(&Route::new(hops, base, &amms)?, &amms, bounds)?;
+                best = best.max(trade.profit);
+            }
+
+            match optimal_arb_amount(&pool_a, &pool_b, base) {
+                Some((_, profit)) => {
+                    let difference = if profit > best {
+                        profit - best
+                    } else {
+                        best - profit
+                    };
+                    assert!(
+                        difference <= (best / 1_000_000).max(U256::from(10)),
+                        "{profit} {best}"
+                    );
+                }
+                //Rounding can leave a few wei where the closed form finds nothing
+                None => assert!(best <= U256::from(10), "{best}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_fee_from_log() -> eyre::Result<()> {
+        let fee_change_event = FeeChangeEvent {
+            signature: H256::from_low_u64_be(1),
+            denominator: 1000,
+        };
+
+        let mut pool = UniswapV2Pool::builder()
+            .with_address(H160::from_low_u64_be(2))
+            .with_token_a(H160::from_low_u64_be(3), 18)
+            .with_token_b(H160::from_low_u64_be(4), 18)
+            .with_reserves(1000000, 1000000)
+            .with_fee_change_event(fee_change_event)
+            .build()?;
+        assert!(pool
+            .sync_on_event_signatures()
+            .contains(&fee_change_event.signature));
+
+        //A fee of 2 out of 1000 is 0.2%
+        pool.sync_from_log(&Log {
+            address: pool.address,
+            topics: vec![fee_change_event.signature],
+            data: ethers::abi::encode(&[Token::Uint(U256::from(2))]).into(),
+            ..Default::default()
+        })?;
+        assert_eq!(pool.fee, 200);
+
+        //Fees above 100% are rejected
+        assert!(pool
+            .sync_from_log(&Log {
+                address: pool.address,
+                topics: vec![fee_change_event.signature],
+                data: ethers::abi::encode(&[Token::Uint(U256::from(1001))]).into(),
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(pool.fee, 200);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_directional_fees_from_log() -> eyre::Result<()> {
+        let (token_a, token_b) = (H160::from_low_u64_be(3), H160::from_low_u64_be(4));
+        let mut pool = UniswapV2Pool::builder()
+            .with_address(H160::from_low_u64_be(2))
+            .with_token_a(token_a, 18)
+            .with_token_b(token_b, 18)
+            .with_reserves(10_u128.pow(24), 10_u128.pow(24))
+            .with_directional_fees(300, 100)
+            .build()?;
+        assert!(pool
+            .sync_on_event_signatures()
+            .contains(&FEE_PERCENT_UPDATED_EVENT_SIGNATURE));
+
+        //Each direction pays its own fee
+        let amount_in = U256::exp10(18);
+        let reserve = U256::exp10(24);
+        assert_eq!(
+            pool.simulate_swap(token_a, amount_in)?,
+            super::amount_out(amount_in, reserve, reserve, 300)
+        );
+        assert_eq!(
+            pool.simulate_swap(token_b, amount_in)?,
+            super::amount_out(amount_in, reserve, reserve, 100)
+        );
+
+        pool.sync_from_log(&Log {
+            address: pool.address,
+            topics: vec![FEE_PERCENT_UPDATED_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[Token::Uint(U256::from(500)), Token::Uint(U256::from(50))])
+                .into(),
+            ..Default::default()
+        })?;
+        assert_eq!((pool.fee, pool.fee_one_for_zero), (500, Some(50)));
+        assert_eq!(pool.swap_fee(token_a), 500);
+        assert_eq!(pool.swap_fee(token_b), 50);
+
+        //Pairs switched to the stable curve cannot be simulated
+        pool.sync_from_log(&Log {
+            address: pool.address,
+            topics: vec![SET_STABLE_SWAP_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[Token::Bool(false), Token::Bool(true)]).into(),
+            ..Default::default()
+        })?;
+        assert!(pool.stable_swap);
+        assert!(matches!(
+            pool.simulate_swap(token_a, amount_in),
+            Err(SwapSimulationError::UnsupportedStableSwap(address)) if address == pool.address
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_amount_out_with_fractional_fee() {
+        //25 bps must not be rounded to 30 bps
+        let pool = UniswapV2Pool {
+            fee: 250,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            pool.get_amount_out(U256::from(1000000), U256::exp10(18), U256::exp10(18)),
+            U256::from(997499)
+        );
+    }
+
+    #[test]
+    fn test_get_amount_in() {
+        let pool = UniswapV2Pool {
+            fee: 300,
+            ..Default::default()
+        };
+
+        let amount_in = pool.get_amount_in(U256::from(1000000), U256::exp10(18), U256::exp10(18));
+        assert_eq!(amount_in, U256::from(1003010));
+        assert!(
+            pool.get_amount_out(amount_in, U256::exp10(18), U256::exp10(18)) >= U256::from(1000000)
+        );
+
+        //The product of the reserves and the amount overflows 256 bits
+        let reserve = U256::exp10(40);
+        let amount_in = pool.get_amount_in(U256::exp10(36), reserve, reserve);
+        assert!(pool.get_amount_out(amount_in, reserve, reserve) >= U256::exp10(36));
+    }
+
+    #[test]
+    fn test_get_amounts_with_directional_fees() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let pool = UniswapV2Pool {
+            token_a,
+            token_b,
+            fee: 300,
+            fee_one_for_zero: Some(100),
+            ..Default::default()
+        };
+        let (amount, reserve) = (U256::from(1000000), U256::exp10(18));
+
+        //Swaps of token b for token a are charged 0.1% rather than 0.3%
+        assert_eq!(
+            pool.get_amount_out_for(token_a, amount, reserve, reserve),
+            U256::from(996999)
+        );
+        assert_eq!(
+            pool.get_amount_out_for(token_b, amount, reserve, reserve),
+            U256::from(998999)
+        );
+        assert_eq!(
+            pool.get_amount_in_for(token_a, amount, reserve, reserve),
+            U256::from(1003010)
+        );
+        assert_eq!(
+            pool.get_amount_in_for(token_b, amount, reserve, reserve),
+            U256::from(1001002)
+        );
+    }
+
+    #[test]
+    fn test_gradient() -> eyre::Result<()> {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let pool = UniswapV2Pool::builder()
+            .with_token_a(token_a, 18)
+            .with_token_b(token_b, 18)
+            .with_reserves(10_u128.pow(24), 2 * 10_u128.pow(21))
+            .build()?;
+
+        let (r_in, r_out, fee, denominator) = (1e24, 2e21, 99700.0, 100000.0);
+        for amount_in in [
+            U256::zero(),
+            U256::exp10(18),
+            U256::exp10(21),
+            U256::exp10(23),
+        ] {
+            let gradient = pool.gradient(token_a, amount_in)?.to_f64();
+
+            //Closed form derivative of the constant product output
+            let amount_in_with_fee = amount_in.as_u128() as f64 * fee;
+            let expected = fee * denominator * r_in * r_out
+                / (r_in * denominator + amount_in_with_fee).powi(2);
+            assert!((gradient - expected).abs() / expected < 1e-12);
+
+            //Forward difference of the simulated output
+            let step = U256::exp10(12);
+            let amount_out = pool.simulate_swap(token_a, amount_in)?;
+            let amount_out_after_step = pool.simulate_swap(token_a, amount_in + step)?;
+            let difference = (amount_out_after_step - amount_out).as_u128() as f64 / 1e12;
+            assert!((gradient - difference).abs() / gradient < 1e-6);
+        }
+
+        //Transfer taxes scale the gradient at the taxed input
+        let taxed = UniswapV2Pool {
+            token_a_transfer_tax: TransferTax::new(0, 500),
+            token_b_transfer_tax: TransferTax::new(1000, 0),
+            ..pool.clone()
+        };
+        let taxed_gradient = taxed.gradient_f64(token_a, U256::exp10(21))?;
+        let untaxed_gradient = pool.gradient_f64(token_a, U256::exp10(21) * 95 / 100)?;
+        assert!((taxed_gradient / untaxed_gradient - 0.95 * 0.9).abs() < 1e-12);
+
+        assert!(UniswapV2Pool::default()
+            .gradient(token_a, U256::exp10(18))?
+            .is_zero());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_q64_to_f64() {
+        assert_eq!(q64_to_f64(U128_0X10000000000000000), 1.0);
+        assert_eq!(q64_to_f64(3 << 62), 0.75);
+        assert_eq!(q64_to_f64(1), 2_f64.powi(-64));
+        assert_eq!(q64_to_f64(u128::MAX), 2_f64.powi(64));
+    }
+
+    #[tokio::test]
+    async fn test_populate_pool_fees() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("BSC_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let wbnb = H160::from_str("0xbb4CdB9CBd36B01bD1cBaEBF2De08d9173bc095c")?;
+        let usdt = H160::from_str("0x55d398326f99059fF775485246999027B3197955")?;
+
+        //Biswap pairs report their fee through `swapFee()`, defaulting to 0.1%
+        let biswap_factory = UniswapV2Factory::new_with_fee_source(
+            H160::from_str("0x858E3312ed3A876947EA49d572A7C42DE08af7EE")?,
+            0,
+            300,
+            BISWAP_FEE_SOURCE,
+        );
+        let pancakeswap_v2_factory = UniswapV2Factory::new_with_fee_source(
+            H160::from_str("0xcA143Ce32Fe78f1f7019d7d551a6402fC5350c73")?,
+            6809737,
+            300,
+            PANCAKESWAP_V2_FEE_SOURCE,
+        );
+
+        for (factory, expected_fee) in [(biswap_factory, 100), (pancakeswap_v2_factory, 250)] {
+            let pair = IUniswapV2Factory::new(factory.address, middleware.clone())
+                .get_pair(wbnb, usdt)
+                .call()
+                .await?;
+
+            let mut amms = vec![AMM::UniswapV2Pool(UniswapV2Pool {
+                address: pair,
+                fee: factory.fee,
+                ..Default::default()
+            })];
+            factory
+                .populate_pool_fees(&mut amms, middleware.clone())
+                .await?;
+
+            if let AMM::UniswapV2Pool(pool) = &amms[0] {
+                assert_eq!(pool.fee, expected_fee);
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -1,13 +1,16 @@
-use std::{sync::Arc, vec};
+use std::{collections::HashMap, sync::Arc, vec};
 
 use ethers::{
     abi::{ParamType, Token},
     providers::Middleware,
-    types::{Bytes, I256, U256, U64},
+    types::{Bytes, H160, I256, U256, U64},
 };
 
 use crate::{
-    amm::{AutomatedMarketMaker, AMM},
+    amm::{
+        multicall::{self, BatchRequestMode},
+        AmmState, AMM,
+    },
     errors::AMMError,
 };
 
@@ -25,6 +28,28 @@ abigen!(
 
 );
 
+//token0()
+const TOKEN_0_SELECTOR: [u8; 4] = [13, 254, 22, 129];
+//token1()
+const TOKEN_1_SELECTOR: [u8; 4] = [210, 18, 32, 167];
+//liquidity()
+const LIQUIDITY_SELECTOR: [u8; 4] = [26, 104, 101, 2];
+//slot0()
+const SLOT_0_SELECTOR: [u8; 4] = [56, 80, 199, 189];
+//tickSpacing()
+const TICK_SPACING_SELECTOR: [u8; 4] = [208, 201, 58, 124];
+//fee()
+const FEE_SELECTOR: [u8; 4] = [221, 202, 63, 67];
+
+const POOL_DATA_SELECTORS: [[u8; 4]; 6] = [
+    TOKEN_0_SELECTOR,
+    TOKEN_1_SELECTOR,
+    LIQUIDITY_SELECTOR,
+    SLOT_0_SELECTOR,
+    TICK_SPACING_SELECTOR,
+    FEE_SELECTOR,
+];
+
 fn populate_pool_data_from_tokens(
     mut pool: UniswapV3Pool,
     tokens: Vec<Token>,
@@ -38,6 +63,7 @@ fn populate_pool_data_from_tokens(
     pool.tick = I256::from_raw(tokens[6].to_owned().into_int()?).as_i32();
     pool.tick_spacing = I256::from_raw(tokens[7].to_owned().into_int()?).as_i32();
     pool.fee = tokens[8].to_owned().into_uint()?.as_u64() as u32;
+    pool.reset_decimal_scaling();
 
     Some(pool)
 }
@@ -48,9 +74,77 @@ pub async fn get_v3_pool_data_batch_request<M: Middleware>(
     middleware: Arc<M>,
 ) -> Result<(), AMMError<M>> {
     tracing::info!(?pool.address, "getting pool data");
-    let constructor_args = Token::Tuple(vec![Token::Array(vec![Token::Address(pool.address)])]);
 
-    let deployer = IGetUniswapV3PoolDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
+    //Update pool data
+    for pool_data in get_pool_data(
+        &[pool.address],
+        block_number,
+        BatchRequestMode::Auto,
+        middleware,
+    )
+    .await?
+    {
+        *pool = populate_pool_data_from_tokens(pool.to_owned(), pool_data)
+            .ok_or(AMMError::BatchRequestError(pool.address))?;
+    }
+    Ok(())
+}
+
+fn pool_data_param_type() -> ParamType {
+    ParamType::Tuple(vec![
+        ParamType::Address,   // token a
+        ParamType::Uint(8),   // token a decimals
+        ParamType::Address,   // token b
+        ParamType::Uint(8),   // token b decimals
+        ParamType::Uint(128), // liquidity
+        ParamType::Uint(160), // sqrtPrice
+        ParamType::Int(24),   // tick
+        ParamType::Int(24),   // tickSpacing
+        ParamType::Uint(24),  // fee
+        ParamType::Int(128),  // liquidityNet
+    ])
+}
+
+/// Reads the data of `pools` at `block_number` as returned by the pool data batch contract, one tuple per pool.
+/// Pools that could not be read hold a zero token a.
+pub async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    mode: BatchRequestMode,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    match mode {
+        BatchRequestMode::ConstructorCall => {
+            get_pool_data_constructor_call(pools, block_number, middleware).await
+        }
+        BatchRequestMode::Multicall => {
+            get_pool_data_multicall(pools, block_number, middleware).await
+        }
+        BatchRequestMode::Auto => {
+            match get_pool_data_constructor_call(pools, block_number, middleware.clone()).await {
+                Ok(pool_data) => Ok(pool_data),
+                Err(err) if multicall::is_constructor_call_rejected(&err) => {
+                    tracing::warn!(
+                        ?err,
+                        "constructor call rejected, getting pool data through multicall"
+                    );
+                    get_pool_data_multicall(pools, block_number, middleware).await
+                }
+                Err(err) => Err(err),
+            }
+        }
+    }
+}
+
+async fn get_pool_data_constructor_call<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    let target_addresses = pools.iter().map(|pool| Token::Address(*pool)).collect();
+    let constructor_args = Token::Tuple(vec![Token::Array(target_addresses)]);
+
+    let deployer = IGetUniswapV3PoolDataBatchRequest::deploy(middleware, constructor_args)?;
 
     let return_data: Bytes = if let Some(block_number) = block_number {
         deployer.block(block_number).call_raw().await?
@@ -59,35 +153,80 @@ pub async fn get_v3_pool_data_batch_request<M: Middleware>(
     };
 
     let return_data_tokens = ethers::abi::decode(
-        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
-            ParamType::Address,   // token a
-            ParamType::Uint(8),   // token a decimals
-            ParamType::Address,   // token b
-            ParamType::Uint(8),   // token b decimals
-            ParamType::Uint(128), // liquidity
-            ParamType::Uint(160), // sqrtPrice
-            ParamType::Int(24),   // tick
-            ParamType::Int(24),   // tickSpacing
-            ParamType::Uint(24),  // fee
-            ParamType::Int(128),  // liquidityNet
-        ])))],
+        &[ParamType::Array(Box::new(pool_data_param_type()))],
         &return_data,
     )?;
 
-    //Update pool data
-    for tokens in return_data_tokens {
-        if let Some(tokens_arr) = tokens.into_array() {
-            for tup in tokens_arr {
-                let pool_data = tup
-                    .into_tuple()
-                    .ok_or(AMMError::BatchRequestError(pool.address))?;
+    Ok(return_data_tokens
+        .into_iter()
+        .filter_map(Token::into_array)
+        .flatten()
+        .filter_map(Token::into_tuple)
+        .collect())
+}
 
-                *pool = populate_pool_data_from_tokens(pool.to_owned(), pool_data)
-                    .ok_or(AMMError::BatchRequestError(pool.address))?;
-            }
-        }
-    }
-    Ok(())
+async fn get_pool_data_multicall<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<Token>>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| POOL_DATA_SELECTORS.map(|selector| multicall::call(*pool, selector, &[])))
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let tokens = return_data
+        .chunks(POOL_DATA_SELECTORS.len())
+        .flat_map(|pool_return_data| &pool_return_data[..2])
+        .filter_map(|return_data| multicall::address_word(return_data.as_ref()?, 0))
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+
+    Ok(return_data
+        .chunks(POOL_DATA_SELECTORS.len())
+        .map(|pool_return_data| {
+            pool_data_from_multicall(pool_return_data, &decimals).unwrap_or_else(|| {
+                vec![
+                    Token::Address(H160::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Address(H160::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Int(U256::zero()),
+                    Token::Int(U256::zero()),
+                    Token::Uint(U256::zero()),
+                    Token::Int(U256::zero()),
+                ]
+            })
+        })
+        .collect())
+}
+
+//Pool data in the layout of the batch contract, None if any of the reads failed.
+//Signed words are sign extended by the ABI encoding, so they are passed on as the raw ints of the batch contract.
+fn pool_data_from_multicall(
+    return_data: &[Option<Bytes>],
+    decimals: &HashMap<H160, u8>,
+) -> Option<Vec<Token>> {
+    let token_a = multicall::address_word(return_data[0].as_ref()?, 0)?;
+    let token_b = multicall::address_word(return_data[1].as_ref()?, 0)?;
+    let slot_0 = return_data[3].as_ref()?;
+
+    Some(vec![
+        Token::Address(token_a),
+        Token::Uint(U256::from(*decimals.get(&token_a)?)),
+        Token::Address(token_b),
+        Token::Uint(U256::from(*decimals.get(&token_b)?)),
+        Token::Uint(multicall::word(return_data[2].as_ref()?, 0)?),
+        Token::Uint(multicall::word(slot_0, 0)?),
+        Token::Int(multicall::word(slot_0, 1)?),
+        Token::Int(multicall::word(return_data[4].as_ref()?, 0)?),
+        Token::Uint(multicall::word(return_data[5].as_ref()?, 0)?),
+        //The liquidity net is not read by the population of pools
+        Token::Int(U256::zero()),
+    ])
 }
 
 pub struct UniswapV3TickData {
@@ -96,6 +235,11 @@ pub struct UniswapV3TickData {
     pub liquidity_net: i128,
 }
 
+/// Returns the initialized ticks from `tick_start` and the block they were read at.
+///
+/// The block is `block_number` if set. Otherwise it is `block.number` as seen by the batch contract, which on Arbitrum
+/// is an approximation of the L1 block number rather than the number of the L2 block the call ran on.
+#[tracing::instrument(level = "debug", skip(pool, middleware), fields(pool = ?pool.address), err(Debug))]
 pub async fn get_uniswap_v3_tick_data_batch_request<M: Middleware>(
     pool: &UniswapV3Pool,
     tick_start: i32,
@@ -169,12 +313,18 @@ pub async fn get_uniswap_v3_tick_data_batch_request<M: Middleware>(
         }
     }
 
-    let block_number = return_data_tokens[1]
-        .to_owned()
-        .into_uint()
-        .ok_or(AMMError::BatchRequestError(pool.address))?;
+    let block_number = match block_number {
+        Some(block_number) => block_number,
+        None => U64::from(
+            return_data_tokens[1]
+                .to_owned()
+                .into_uint()
+                .ok_or(AMMError::BatchRequestError(pool.address))?
+                .as_u64(),
+        ),
+    };
 
-    Ok((tick_data, U64::from(block_number.as_u64())))
+    Ok((tick_data, block_number))
 }
 
 pub async fn sync_v3_pool_batch_request<M: Middleware>(
@@ -236,60 +386,35 @@ pub async fn get_amm_data_batch_request<M: Middleware>(
     block_number: u64,
     middleware: Arc<M>,
 ) -> Result<(), AMMError<M>> {
-    tracing::info!(block_number, "getting data for {} AMMs", amms.len());
-
-    let mut target_addresses = vec![];
-
-    for amm in amms.iter() {
-        target_addresses.push(Token::Address(amm.address()));
-    }
-
-    let constructor_args = Token::Tuple(vec![Token::Array(target_addresses)]);
-    let deployer = IGetUniswapV3PoolDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
-
-    let return_data: Bytes = deployer.block(block_number).call_raw().await?;
+    get_amm_data_batch_request_with_mode(amms, block_number, BatchRequestMode::Auto, middleware)
+        .await
+}
 
-    let return_data_tokens = ethers::abi::decode(
-        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
-            ParamType::Address,   // token a
-            ParamType::Uint(8),   // token a decimals
-            ParamType::Address,   // token b
-            ParamType::Uint(8),   // token b decimals
-            ParamType::Uint(128), // liquidity
-            ParamType::Uint(160), // sqrtPrice
-            ParamType::Int(24),   // tick
-            ParamType::Int(24),   // tickSpacing
-            ParamType::Uint(24),  // fee
-            ParamType::Int(128),  // liquidityNet
-        ])))],
-        &return_data,
-    )?;
+/// Same as `get_amm_data_batch_request`, reading the pools through `mode`
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request_with_mode<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: u64,
+    mode: BatchRequestMode,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    tracing::info!(block_number, "getting data for {} AMMs", amms.len());
 
-    let mut pool_idx = 0;
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, Some(block_number), mode, middleware).await?;
 
     //Update pool data
-    for tokens in return_data_tokens {
-        if let Some(tokens_arr) = tokens.into_array() {
-            for tup in tokens_arr {
-                if let Some(pool_data) = tup.into_tuple() {
-                    if let Some(address) = pool_data[0].to_owned().into_address() {
-                        if !address.is_zero() {
-                            //Update the pool data
-                            if let AMM::UniswapV3Pool(uniswap_v3_pool) = amms
-                                .get_mut(pool_idx)
-                                .expect("Pool idx should be in bounds")
-                            {
-                                if let Some(pool) = populate_pool_data_from_tokens(
-                                    uniswap_v3_pool.to_owned(),
-                                    pool_data,
-                                ) {
-                                    tracing::trace!(?pool);
-                                    *uniswap_v3_pool = pool;
-                                }
-                            }
-                        }
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let Some(address) = pool_data[0].to_owned().into_address() {
+            if !address.is_zero() {
+                //Update the pool data
+                if let AMM::UniswapV3Pool(uniswap_v3_pool) = amm {
+                    if let Some(pool) =
+                        populate_pool_data_from_tokens(uniswap_v3_pool.to_owned(), pool_data)
+                    {
+                        tracing::trace!(pool = ?pool.debug_brief());
+                        *uniswap_v3_pool = pool;
                     }
-                    pool_idx += 1;
                 }
             }
         }
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+
+use ethers::types::{H160, U256};
+
+use crate::{
+    amm::{compat::SerdeVersion, uniswap_v2::builder::validate_tokens},
+    errors::PoolBuildError,
+    math::fixed_point::{f64_to_sqrt_price_x96, DecimalScalingCache},
+};
+
+use super::{
+    tick_cache::TickCache, Info, UniswapV3Pool, MAX_SQRT_RATIO, MAX_TICK, MIN_SQRT_RATIO, MIN_TICK,
+};
+
+//Fees are expressed in hundredths of a basis point, i.e. 3000 is 0.3%
+const FEE_DENOMINATOR: u32 = 1_000_000;
+
+/// Tick spacing the Uniswap V3 factory enables for `fee`, None for fees it does not enable by default
+pub fn standard_tick_spacing(fee: u32) -> Option<i32> {
+    match fee {
+        100 => Some(1),
+        500 => Some(10),
+        3000 => Some(60),
+        10000 => Some(200),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Price {
+    SqrtPrice(U256),
+    //Whole units of token b per token a, scaled by the decimals of each token when the pool is built
+    Human(f64),
+}
+
+/// Builds a `UniswapV3Pool` offline, validating its fields in `build`.
+///
+/// Each token is set together with its decimals, so they can not be transposed. The fee defaults to 3000 and the
+/// tick spacing to the standard spacing of the fee. The price is set by `with_sqrt_price`, `with_price_human` or
+/// `with_tick`, and defaults to 1. A tick given along with a price must be the tick of that price.
+#[derive(Debug, Clone)]
+pub struct UniswapV3PoolBuilder {
+    address: H160,
+    token_a: H160,
+    token_a_decimals: u8,
+    token_b: H160,
+    token_b_decimals: u8,
+    fee: u32,
+    tick_spacing: Option<i32>,
+    liquidity: u128,
+    price: Option<Price>,
+    tick: Option<i32>,
+    tick_bitmap: HashMap<i16, U256>,
+    ticks: HashMap<i32, Info>,
+    positions: Vec<(i32, i32, u128)>,
+    creation_block: Option<u64>,
+}
+
+impl Default for UniswapV3PoolBuilder {
+    fn default() -> Self {
+        UniswapV3PoolBuilder {
+            address: H160::zero(),
+            token_a: H160::zero(),
+            token_a_decimals: 0,
+            token_b: H160::zero(),
+            token_b_decimals: 0,
+            fee: 3000,
+            tick_spacing: None,
+            liquidity: 0,
+            price: None,
+            tick: None,
+            tick_bitmap: HashMap::new(),
+            ticks: HashMap::new(),
+            positions: vec![],
+            creation_block: None,
+        }
+    }
+}
+
+impl UniswapV3PoolBuilder {
+    pub fn with_address(mut self, address: H160) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn with_token_a(mut self, token_a: H160, decimals: u8) -> Self {
+        self.token_a = token_a;
+        self.token_a_decimals = decimals;
+        self
+    }
+
+    pub fn with_token_b(mut self, token_b: H160, decimals: u8) -> Self {
+        self.token_b = token_b;
+        self.token_b_decimals = decimals;
+        self
+    }
+
+    /// Fee in hundredths of a basis point, i.e. 3000 for 0.3%
+    pub fn with_fee(mut self, fee: u32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn with_tick_spacing(mut self, tick_spacing: i32) -> Self {
+        self.tick_spacing = Some(tick_spacing);
+        self
+    }
+
+    /// Liquidity in range at the current price, in addition to the positions covering it
+    pub fn with_liquidity(mut self, liquidity: u128) -> Self {
+        self.liquidity = liquidity;
+        self
+    }
+
+    pub fn with_sqrt_price(mut self, sqrt_price: U256) -> Self {
+        self.price = Some(Price::SqrtPrice(sqrt_price));
+        self
+    }
+
+    /// Price of a whole token a in whole units of token b, i.e. `with_price_human(2000.0)` for WETH/USDC at
+    /// 2,000 USDC, scaled by the decimals of each token when the pool is built
+    pub fn with_price_human(mut self, price: f64) -> Self {
+        self.price = Some(Price::Human(price));
+        self
+    }
+
+    /// Current tick, which sets the price to the price of the tick when no price is given
+    pub fn with_tick(mut self, tick: i32) -> Self {
+        self.tick = Some(tick);
+        self
+    }
+
+    /// Initialized ticks and their bitmap, as read from the pool
+    pub fn with_ticks(
+        mut self,
+        tick_bitmap: HashMap<i16, U256>,
+        ticks: HashMap<i32, Info>,
+    ) -> Self {
+        self.tick_bitmap = tick_bitmap;
+        self.ticks = ticks;
+        self
+    }
+
+    /// Mints `liquidity` between `tick_lower` and `tick_upper`, initializing both ticks
+    pub fn with_position(mut self, tick_lower: i32, tick_upper: i32, liquidity: u128) -> Self {
+        self.positions.push((tick_lower, tick_upper, liquidity));
+        self
+    }
+
+    pub fn with_creation_block(mut self, creation_block: u64) -> Self {
+        self.creation_block = Some(creation_block);
+        self
+    }
+
+    pub fn build(self) -> Result<UniswapV3Pool, PoolBuildError> {
+        validate_tokens(
+            self.token_a,
+            self.token_a_decimals,
+            self.token_b,
+            self.token_b_decimals,
+        )?;
+
+        if self.fee >= FEE_DENOMINATOR {
+            return Err(PoolBuildError::InvalidFee(self.fee));
+        }
+
+        let tick_spacing = match self.tick_spacing {
+            Some(tick_spacing) => tick_spacing,
+            None => standard_tick_spacing(self.fee)
+                .ok_or(PoolBuildError::MissingTickSpacing(self.fee))?,
+        };
+        if tick_spacing <= 0 {
+            return Err(PoolBuildError::InvalidTickSpacing(tick_spacing));
+        }
+
+        let sqrt_price = match (self.price, self.tick) {
+            (Some(Price::SqrtPrice(sqrt_price)), _) => sqrt_price,
+            (Some(Price::Human(price)), _) => {
+                f64_to_sqrt_price_x96(price, self.token_a_decimals, self.token_b_decimals)
+                    .map_err(|_| PoolBuildError::InvalidPrice(price))?
+            }
+            (None, Some(tick)) => uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(tick)
+                .map_err(|_| PoolBuildError::InvalidTick(tick))?,
+            (None, None) => U256::one() << 96,
+        };
+
+        if sqrt_price < MIN_SQRT_RATIO || sqrt_price >= MAX_SQRT_RATIO {
+            return Err(PoolBuildError::SqrtPriceOutOfRange(sqrt_price));
+        }
+
+        let expected = uniswap_v3_math::tick_math::get_tick_at_sqrt_ratio(sqrt_price)
+            .map_err(|_| PoolBuildError::SqrtPriceOutOfRange(sqrt_price))?;
+        let tick = match self.tick {
+            Some(tick) if tick != expected => {
+                return Err(PoolBuildError::TickMismatch { tick, expected })
+            }
+            _ => expected,
+        };
+
+        let mut pool = UniswapV3Pool {
+            address: self.address,
+            token_a: self.token_a,
+            token_a_decimals: self.token_a_decimals,
+            token_b: self.token_b,
+            token_b_decimals: self.token_b_decimals,
+            liquidity: 0,
+            sqrt_price: U256::one() << 96,
+            fee: self.fee,
+            tick: 0,
+            tick_spacing,
+            tick_bitmap: self.tick_bitmap,
+            ticks: self.ticks,
+            creation_block: self.creation_block,
+            tick_cache: TickCache::default(),
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::new(self.token_a_decimals, self.token_b_decimals),
+        };
+
+        pool.set_slot0(sqrt_price, tick)
+            .map_err(|_| PoolBuildError::SqrtPriceOutOfRange(sqrt_price))?;
+        pool.set_liquidity(self.liquidity)
+            .map_err(|_| PoolBuildError::LiquidityOverflow(self.liquidity))?;
+
+        for (tick_lower, tick_upper, liquidity) in self.positions {
+            let initializable =
+                |tick: i32| (MIN_TICK..=MAX_TICK).contains(&tick) && tick % tick_spacing == 0;
+
+            if tick_lower >= tick_upper
+                || !initializable(tick_lower)
+                || !initializable(tick_upper)
+                || liquidity > i128::MAX as u128
+            {
+                return Err(PoolBuildError::InvalidPosition {
+                    tick_lower,
+                    tick_upper,
+                });
+            }
+
+            pool.modify_position(tick_lower, tick_upper, liquidity as i128);
+        }
+
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{
+            uniswap_v3::{UniswapV3Pool, MAX_SQRT_RATIO, MIN_SQRT_RATIO},
+            AmmState,
+        },
+        errors::PoolBuildError,
+    };
+
+    #[test]
+    fn test_build() -> eyre::Result<()> {
+        let weth = H160::from_low_u64_be(1);
+        let usdc = H160::from_low_u64_be(2);
+
+        let pool = UniswapV3Pool::builder()
+            .with_address(H160::from_low_u64_be(3))
+            .with_token_a(weth, 18)
+            .with_token_b(usdc, 6)
+            .with_fee(500)
+            .with_price_human(2000.0)
+            .with_position(-887270, 887270, 10_u128.pow(18))
+            .build()?;
+
+        assert_eq!(pool.tick_spacing, 10);
+        assert_eq!(
+            pool.tick,
+            uniswap_v3_math::tick_math::get_tick_at_sqrt_ratio(pool.sqrt_price)?
+        );
+        assert_eq!(pool.liquidity, 10_u128.pow(18));
+        assert_eq!(pool.ticks.len(), 2);
+        assert!((pool.calculate_price(weth)? / 2000.0 - 1.0).abs() < 1e-3);
+
+        //The price defaults to the price of the tick
+        let pool = UniswapV3Pool::builder()
+            .with_token_a(weth, 18)
+            .with_token_b(usdc, 6)
+            .with_tick(-200000)
+            .build()?;
+        assert_eq!(
+            pool.sqrt_price,
+            uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(-200000)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_rejects_invalid_pools() {
+        let builder = UniswapV3Pool::builder()
+            .with_token_a(H160::from_low_u64_be(1), 18)
+            .with_token_b(H160::from_low_u64_be(2), 18);
+
+        assert_eq!(
+            builder
+                .clone()
+                .with_token_b(H160::from_low_u64_be(1), 18)
+                .build()
+                .err(),
+            Some(PoolBuildError::IdenticalTokens(H160::from_low_u64_be(1)))
+        );
+        assert_eq!(
+            builder.clone().with_fee(1_000_000).build().err(),
+            Some(PoolBuildError::InvalidFee(1_000_000))
+        );
+        assert_eq!(
+            builder.clone().with_fee(2500).build().err(),
+            Some(PoolBuildError::MissingTickSpacing(2500))
+        );
+        assert_eq!(
+            builder.clone().with_tick_spacing(0).build().err(),
+            Some(PoolBuildError::InvalidTickSpacing(0))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .with_sqrt_price(MAX_SQRT_RATIO)
+                .build()
+                .err(),
+            Some(PoolBuildError::SqrtPriceOutOfRange(MAX_SQRT_RATIO))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .with_sqrt_price(MIN_SQRT_RATIO - 1)
+                .build()
+                .err(),
+            Some(PoolBuildError::SqrtPriceOutOfRange(MIN_SQRT_RATIO - 1))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .with_sqrt_price(U256::one() << 96)
+                .with_tick(1)
+                .build()
+                .err(),
+            Some(PoolBuildError::TickMismatch {
+                tick: 1,
+                expected: 0
+            })
+        );
+        assert_eq!(
+            builder.clone().with_tick(900000).build().err(),
+            Some(PoolBuildError::InvalidTick(900000))
+        );
+        assert_eq!(
+            builder.clone().with_price_human(-1.0).build().err(),
+            Some(PoolBuildError::InvalidPrice(-1.0))
+        );
+        assert_eq!(
+            builder.clone().with_liquidity(u128::MAX).build().err(),
+            Some(PoolBuildError::LiquidityOverflow(u128::MAX))
+        );
+        assert_eq!(
+            builder.with_position(-60, 30, 1000).build().err(),
+            Some(PoolBuildError::InvalidPosition {
+                tick_lower: -60,
+                tick_upper: 30
+            })
+        );
+    }
+}
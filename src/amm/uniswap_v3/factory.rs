@@ -1,27 +1,35 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    sync::Arc,
-};
+use std::{collections::HashMap, sync::Arc};
 
 use async_trait::async_trait;
 use ethers::{
-    abi::RawLog,
-    prelude::{abigen, EthEvent},
+    abi::Token,
+    prelude::abigen,
     providers::Middleware,
-    types::{BlockNumber, Filter, Log, H160, H256, U256, U64},
+    types::{BlockNumber, Filter, Log, ValueOrArray, H160, H256, U256, U64},
+    utils::{get_create2_address_from_hash, keccak256},
 };
+use futures::Stream;
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 
 use crate::{
     amm::{
-        factory::{AutomatedMarketMakerFactory, TASK_LIMIT},
-        AutomatedMarketMaker, AMM,
+        compat::SerdeVersion,
+        decode,
+        factory::{
+            sort_tokens, AmmPage, AutomatedMarketMakerFactory, TASK_LIMIT, UNISWAP_V3_FEE_TIERS,
+        },
+        AmmState, AMM,
     },
     errors::{AMMError, EventLogError},
+    math::fixed_point::DecimalScalingCache,
+    sync,
 };
 
-use super::{batch_request, UniswapV3Pool, BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE};
+use super::{
+    batch_request, builder::standard_tick_spacing, tick_cache::TickCache, UniswapV3Pool,
+    BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE,
+};
 
 abigen!(
     IUniswapV3Factory,
@@ -38,10 +46,80 @@ pub const POOL_CREATED_EVENT_SIGNATURE: H256 = H256([
     53, 122, 46, 139, 29, 155, 43, 78, 107, 113, 24,
 ]);
 
+pub const UNISWAP_V3_POOL_INIT_CODE_HASH: H256 = H256([
+    227, 79, 25, 155, 25, 178, 180, 244, 127, 104, 68, 38, 25, 213, 85, 82, 125, 36, 79, 120, 163,
+    41, 126, 168, 147, 37, 248, 67, 248, 123, 139, 84,
+]);
+
+pub const PANCAKESWAP_V3_POOL_INIT_CODE_HASH: H256 = H256([
+    108, 232, 235, 71, 47, 168, 45, 245, 70, 156, 106, 182, 212, 133, 241, 124, 58, 209, 60, 140,
+    215, 175, 89, 179, 212, 168, 2, 108, 92, 224, 247, 226,
+]);
+
+//PancakeSwap V3 pools are deployed by a separate deployer contract rather than the factory
+pub const PANCAKESWAP_V3_POOL_DEPLOYER: H160 = H160([
+    65, 255, 154, 167, 225, 107, 139, 26, 138, 141, 196, 240, 239, 172, 217, 61, 2, 208, 113, 201,
+]);
+
+//Fee tiers of Uniswap V3 and PancakeSwap V3 and their tick spacing
+const UNISWAP_V3_TICK_SPACED_FEE_TIERS: [(u32, i32); 4] =
+    [(100, 1), (500, 10), (3000, 60), (10000, 200)];
+const PANCAKESWAP_V3_FEE_TIERS: [(u32, i32); 4] = [(100, 1), (500, 10), (2500, 50), (10000, 200)];
+
+/// V3 deployments whose fee tiers or pool address derivation differ from Uniswap V3
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UniswapV3Fork {
+    #[default]
+    UniswapV3,
+    /// PancakeSwap V3, which shares its factory, deployer and init code hash across chains
+    PancakeSwapV3,
+}
+
+impl UniswapV3Fork {
+    /// Fees enabled by the fork and the tick spacing of each, pools found by `get_pools_for_pair` with another
+    /// tick spacing than that of their fee are dropped
+    pub fn fee_tiers(&self) -> &'static [(u32, i32)] {
+        match self {
+            UniswapV3Fork::UniswapV3 => &UNISWAP_V3_TICK_SPACED_FEE_TIERS,
+            UniswapV3Fork::PancakeSwapV3 => &PANCAKESWAP_V3_FEE_TIERS,
+        }
+    }
+
+    /// Contract deploying the pools, None if the factory deploys them itself
+    pub fn pool_deployer(&self) -> Option<H160> {
+        match self {
+            UniswapV3Fork::UniswapV3 => None,
+            UniswapV3Fork::PancakeSwapV3 => Some(PANCAKESWAP_V3_POOL_DEPLOYER),
+        }
+    }
+
+    pub fn pool_init_code_hash(&self) -> H256 {
+        match self {
+            UniswapV3Fork::UniswapV3 => UNISWAP_V3_POOL_INIT_CODE_HASH,
+            UniswapV3Fork::PancakeSwapV3 => PANCAKESWAP_V3_POOL_INIT_CODE_HASH,
+        }
+    }
+
+    pub fn fees(&self) -> Vec<u32> {
+        self.fee_tiers().iter().map(|(fee, _)| *fee).collect()
+    }
+
+    /// Tick spacing of the `fee` tier, None if the fork does not enable it
+    pub fn tick_spacing(&self, fee: u32) -> Option<i32> {
+        self.fee_tiers()
+            .iter()
+            .find(|(tier, _)| *tier == fee)
+            .map(|(_, tick_spacing)| *tick_spacing)
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct UniswapV3Factory {
     pub address: H160,
     pub creation_block: u64,
+    /// Deployment the factory belongs to, Uniswap V3 if None
+    #[serde(default)]
+    pub fork: Option<UniswapV3Fork>,
 }
 
 #[async_trait]
@@ -64,10 +142,10 @@ impl AutomatedMarketMakerFactory for UniswapV3Factory {
         middleware: Arc<M>,
     ) -> Result<AMM, AMMError<M>> {
         if let Some(block_number) = log.block_number {
-            let pool_created_filter = PoolCreatedFilter::decode_log(&RawLog::from(log))?;
+            let pool_created_event = decode_pool_created_log(&log)?;
             Ok(AMM::UniswapV3Pool(
-                UniswapV3Pool::new_from_address(
-                    pool_created_filter.pool,
+                UniswapV3Pool::new_from_creation_block(
+                    pool_created_event.pool,
                     block_number.as_u64(),
                     middleware,
                 )
@@ -119,8 +197,9 @@ impl AutomatedMarketMakerFactory for UniswapV3Factory {
         Ok(())
     }
 
-    fn new_empty_amm_from_log(&self, log: Log) -> Result<AMM, ethers::abi::Error> {
-        let pool_created_event = PoolCreatedFilter::decode_log(&RawLog::from(log))?;
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        let creation_block = log.block_number.map(|block_number| block_number.as_u64());
+        let pool_created_event = decode_pool_created_log(log)?;
 
         Ok(AMM::UniswapV3Pool(UniswapV3Pool {
             address: pool_created_event.pool,
@@ -131,10 +210,18 @@ impl AutomatedMarketMakerFactory for UniswapV3Factory {
             fee: pool_created_event.fee,
             liquidity: 0,
             sqrt_price: U256::zero(),
-            tick_spacing: 0,
+            tick_spacing: pool_created_event.tick_spacing,
             tick: 0,
             tick_bitmap: HashMap::new(),
             ticks: HashMap::new(),
+            creation_block,
+            tick_cache: TickCache::default(),
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::default(),
         }))
     }
 }
@@ -144,12 +231,41 @@ impl UniswapV3Factory {
         UniswapV3Factory {
             address,
             creation_block,
+            fork: None,
+        }
+    }
+
+    pub fn new_with_fork(
+        address: H160,
+        creation_block: u64,
+        fork: UniswapV3Fork,
+    ) -> UniswapV3Factory {
+        UniswapV3Factory {
+            address,
+            creation_block,
+            fork: Some(fork),
+        }
+    }
+
+    /// Fee tiers enabled by the factory, the Uniswap V3 tiers unless a fork is set
+    pub fn fee_tiers(&self) -> Vec<u32> {
+        match &self.fork {
+            Some(fork) => fork.fees(),
+            None => UNISWAP_V3_FEE_TIERS.to_vec(),
+        }
+    }
+
+    /// Tick spacing of the `fee` tier of the factory, None if it is not one of its fee tiers
+    pub fn tick_spacing(&self, fee: u32) -> Option<i32> {
+        match &self.fork {
+            Some(fork) => fork.tick_spacing(fee),
+            None => standard_tick_spacing(fee),
         }
     }
 
     //Function to get all pair created events for a given Dex factory address and sync pool data
     pub async fn get_all_pools_from_logs<M: 'static + Middleware>(
-        self,
+        &self,
         to_block: u64,
         step: u64,
         middleware: Arc<M>,
@@ -157,7 +273,6 @@ impl UniswapV3Factory {
         //Unwrap can be used here because the creation block was verified within `Dex::new()`
         let mut from_block = self.creation_block;
         let mut aggregated_amms: HashMap<H160, AMM> = HashMap::new();
-        let mut ordered_logs: BTreeMap<U64, Vec<Log>> = BTreeMap::new();
 
         tracing::info!(from_block, to_block, step, "getting all pools from logs");
 
@@ -172,20 +287,27 @@ impl UniswapV3Factory {
                 target_block = to_block;
             }
 
+            let pool_created_filter = Filter::new()
+                .topic0(ValueOrArray::Value(POOL_CREATED_EVENT_SIGNATURE))
+                .address(self.address)
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            //Positions can only be filtered by topic, the pools emitting them are not known before the range is synced
+            let position_filter = Filter::new()
+                .topic0(vec![BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE])
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
             handles.push(tokio::spawn(async move {
-                let logs = middleware
-                    .get_logs(
-                        &Filter::new()
-                            .topic0(vec![
-                                POOL_CREATED_EVENT_SIGNATURE,
-                                BURN_EVENT_SIGNATURE,
-                                MINT_EVENT_SIGNATURE,
-                            ])
-                            .from_block(BlockNumber::Number(U64([from_block])))
-                            .to_block(BlockNumber::Number(U64([target_block]))),
-                    )
-                    .await
-                    .map_err(AMMError::MiddlewareError)?;
+                let (mut logs, position_logs) = tokio::try_join!(
+                    middleware.get_logs(&pool_created_filter),
+                    middleware.get_logs(&position_filter)
+                )
+                .map_err(AMMError::MiddlewareError)?;
+
+                logs.extend(position_logs);
+                logs.sort_by_key(|log| (log.block_number, log.log_index));
 
                 Ok::<Vec<Log>, AMMError<M>>(logs)
             }));
@@ -195,68 +317,181 @@ impl UniswapV3Factory {
             tasks += 1;
             //Here we are limiting the number of green threads that can be spun up to not have the node time out
             if tasks == TASK_LIMIT {
-                self.process_logs_from_handles(handles, &mut ordered_logs)
+                self.sync_logs_from_handles(handles, &mut aggregated_amms)
                     .await?;
                 handles = vec![];
                 tasks = 0;
             }
         }
 
-        self.process_logs_from_handles(handles, &mut ordered_logs)
+        self.sync_logs_from_handles(handles, &mut aggregated_amms)
             .await?;
 
-        for (_, log_group) in ordered_logs {
-            for log in log_group {
-                let event_signature = log.topics[0];
+        Ok(aggregated_amms.into_values().collect::<Vec<AMM>>())
+    }
+
+    /// Computes the address of the token_a/token_b/fee pool without an RPC call, using the deployer and init code
+    /// hash of the fork
+    pub fn compute_pool_address(&self, token_a: H160, token_b: H160, fee: u32) -> H160 {
+        let (deployer, init_code_hash) = match &self.fork {
+            Some(fork) => (
+                fork.pool_deployer().unwrap_or(self.address),
+                fork.pool_init_code_hash(),
+            ),
+            None => (self.address, UNISWAP_V3_POOL_INIT_CODE_HASH),
+        };
+
+        compute_pool_address(deployer, token_a, token_b, fee, init_code_hash)
+    }
+
+    /// Returns a cursor over the pools created between `from_block` and `to_block`, scanning `page_size` blocks at a time.
+    /// Pools are populated with their price and liquidity at `to_block`, tick data can be populated with `UniswapV3Pool::populate_tick_data`.
+    pub fn pools_paginated<M: 'static + Middleware>(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        page_size: u64,
+        middleware: Arc<M>,
+    ) -> UniswapV3PoolPages<M> {
+        UniswapV3PoolPages {
+            factory: *self,
+            cursor: from_block,
+            to_block,
+            page_size: page_size.max(1),
+            middleware,
+        }
+    }
+
+    //Handles resolve in the order their block ranges were spawned, so logs are applied in chronological order
+    //as each range arrives, without holding the logs of the whole history
+    async fn sync_logs_from_handles<M: Middleware>(
+        &self,
+        handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
+        aggregated_amms: &mut HashMap<H160, AMM>,
+    ) -> Result<(), AMMError<M>> {
+        for handle in handles {
+            for log in handle.await?? {
+                if log.block_number.is_none() {
+                    return Err(EventLogError::LogBlockNumberNotFound((&log).into()))?;
+                }
+
+                let event_signature = decode::event_signature(&log)?;
 
                 //If the event sig is the pool created event sig, then the log is coming from the factory
                 if event_signature == POOL_CREATED_EVENT_SIGNATURE {
                     if log.address == self.address {
-                        let mut new_pool = self.new_empty_amm_from_log(log)?;
-
-                        if let AMM::UniswapV3Pool(ref mut pool) = new_pool {
-                            pool.tick_spacing = pool.get_tick_spacing(middleware.clone()).await?;
-                        }
-
+                        let new_pool = self.new_empty_amm_from_log(&log)?;
                         aggregated_amms.insert(new_pool.address(), new_pool);
                     }
                 } else if event_signature == BURN_EVENT_SIGNATURE {
                     //If the event sig is the BURN_EVENT_SIGNATURE log is coming from the pool
                     if let Some(AMM::UniswapV3Pool(pool)) = aggregated_amms.get_mut(&log.address) {
-                        pool.sync_from_burn_log(log)?;
+                        pool.sync_from_burn_log(&log)?;
                     }
                 } else if event_signature == MINT_EVENT_SIGNATURE {
                     if let Some(AMM::UniswapV3Pool(pool)) = aggregated_amms.get_mut(&log.address) {
-                        pool.sync_from_mint_log(log)?;
+                        pool.sync_from_mint_log(&log)?;
                     }
                 }
             }
         }
 
-        Ok(aggregated_amms.into_values().collect::<Vec<AMM>>())
+        Ok(())
     }
+}
 
-    async fn process_logs_from_handles<M: Middleware>(
-        &self,
-        handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
-        ordered_logs: &mut BTreeMap<U64, Vec<Log>>,
-    ) -> Result<(), AMMError<M>> {
-        // group the logs from each thread by block number and then sync the logs in chronological order
-        for handle in handles {
-            let logs = handle.await??;
-
-            for log in logs {
-                if let Some(log_block_number) = log.block_number {
-                    if let Some(log_group) = ordered_logs.get_mut(&log_block_number) {
-                        log_group.push(log);
-                    } else {
-                        ordered_logs.insert(log_block_number, vec![log]);
-                    }
-                } else {
-                    return Err(EventLogError::LogBlockNumberNotFound)?;
-                }
-            }
+/// Reads a `PoolCreated` log straight from its topics and data
+pub fn decode_pool_created_log(log: &Log) -> Result<PoolCreatedFilter, EventLogError> {
+    if decode::event_signature(log)? != POOL_CREATED_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    Ok(PoolCreatedFilter {
+        token_0: decode::topic_address(log, 1)?,
+        token_1: decode::topic_address(log, 2)?,
+        fee: decode::topic_uint(log, 3, 24)?.low_u32(),
+        tick_spacing: decode::data_int(log, 0, 24)?.low_i32(),
+        pool: decode::data_address(log, 1)?,
+    })
+}
+
+/// CREATE2 derivation of a V3 pool address, where the salt is `keccak256(abi.encode(token0, token1, fee))`.
+/// `deployer` is the factory for Uniswap V3, forks such as PancakeSwap V3 deploy pools from a separate contract.
+pub fn compute_pool_address(
+    deployer: H160,
+    token_a: H160,
+    token_b: H160,
+    fee: u32,
+    init_code_hash: H256,
+) -> H160 {
+    let (token_0, token_1) = sort_tokens(token_a, token_b);
+    let salt = keccak256(ethers::abi::encode(&[
+        Token::Address(token_0),
+        Token::Address(token_1),
+        Token::Uint(U256::from(fee)),
+    ]));
+
+    get_create2_address_from_hash(deployer, salt, init_code_hash)
+}
+
+#[derive(Debug)]
+pub struct UniswapV3PoolPages<M> {
+    factory: UniswapV3Factory,
+    cursor: u64,
+    to_block: u64,
+    page_size: u64,
+    middleware: Arc<M>,
+}
+
+impl<M: 'static + Middleware> UniswapV3PoolPages<M> {
+    /// Next block to be scanned, which can be persisted and passed to `pools_paginated` to resume
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Fetches the pools created in the next block range, returning `None` once `to_block` has been scanned.
+    /// If the page fails the cursor is not advanced, so the call can be retried.
+    pub async fn next_page(&mut self) -> Result<Option<AmmPage>, AMMError<M>> {
+        if self.cursor > self.to_block {
+            return Ok(None);
         }
-        Ok(())
+
+        let target_block = (self.cursor + self.page_size - 1).min(self.to_block);
+
+        let logs = self
+            .middleware
+            .get_logs(
+                &Filter::new()
+                    .topic0(ValueOrArray::Value(POOL_CREATED_EVENT_SIGNATURE))
+                    .address(self.factory.address)
+                    .from_block(BlockNumber::Number(U64([self.cursor])))
+                    .to_block(BlockNumber::Number(U64([target_block]))),
+            )
+            .await
+            .map_err(AMMError::MiddlewareError)?;
+
+        let mut amms = vec![];
+        for log in logs.iter() {
+            amms.push(self.factory.new_empty_amm_from_log(log)?);
+        }
+
+        if !amms.is_empty() {
+            let step = amms.len() as u64;
+            sync::populate_amms(&mut amms, self.to_block, self.middleware.clone(), step).await?;
+            amms = sync::remove_empty_amms(amms);
+        }
+
+        self.cursor = target_block + 1;
+
+        Ok(Some(AmmPage {
+            cursor: self.cursor,
+            amms,
+        }))
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<AmmPage, AMMError<M>>> {
+        futures::stream::try_unfold(self, |mut pages| async move {
+            Ok(pages.next_page().await?.map(|page| (page, pages)))
+        })
     }
 }
@@ -1,31 +1,57 @@
+#[cfg(feature = "rpc")]
 pub mod batch_request;
+pub mod builder;
+#[cfg(feature = "rpc")]
 pub mod factory;
+pub mod tick_cache;
+pub mod tick_serde;
 
-use std::{
-    cmp::Ordering,
-    collections::{BTreeMap, HashMap},
-    sync::Arc,
-};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
 
 use crate::{
-    amm::AutomatedMarketMaker,
-    errors::{AMMError, ArithmeticError, EventLogError, StorageError, SwapSimulationError},
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, StateSyncError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::{
+        fixed_point::{sqrt_price_x96_to_price_x128, DecimalScaling, DecimalScalingCache},
+        mul_div, mul_shift_right, Q128x128,
+    },
+    tokens::TokenStore,
+};
+#[cfg(feature = "rpc")]
+use crate::{
+    amm::{
+        detect::{self, DetectedVariant},
+        AutomatedMarketMaker,
+    },
+    errors::AMMError,
 };
+#[cfg(feature = "rpc")]
 use async_trait::async_trait;
+#[cfg(feature = "rpc")]
 use ethers::{
-    abi::{ethabi::Bytes, RawLog, Token},
-    prelude::{AbiError, EthEvent},
+    abi::RawLog,
+    prelude::EthEvent,
     providers::Middleware,
-    types::{BlockNumber, Filter, Log, H160, H256, I256, U256, U64},
+    types::{BlockNumber, Filter, U64},
+};
+use ethers::{
+    abi::{ethabi::Bytes, Token},
+    types::{Log, H160, H256, I256, U256, U512},
 };
-use num_bigfloat::BigFloat;
 use serde::{Deserialize, Serialize};
 
 use ethers::prelude::abigen;
+#[cfg(feature = "rpc")]
 use tokio::task::JoinHandle;
 
+#[cfg(feature = "rpc")]
 use self::factory::POOL_CREATED_EVENT_SIGNATURE;
+use self::{builder::UniswapV3PoolBuilder, tick_cache::TickCache};
 
+#[cfg(feature = "rpc")]
 use super::factory::TASK_LIMIT;
 
 abigen!(
@@ -84,23 +110,60 @@ pub const MINT_EVENT_SIGNATURE: H256 = H256([
 pub const U256_TWO: U256 = U256([2, 0, 0, 0]);
 pub const Q128: U256 = U256([0, 0, 1, 0]);
 pub const Q224: U256 = U256([0, 0, 0, 4294967296]);
+
+//Storage slots of the pool, slot0 packs the sqrt price and the tick with the oracle and fee state
+const SLOT0_STORAGE_SLOT: H256 = H256::zero();
+const LIQUIDITY_STORAGE_SLOT: H256 = H256([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4,
+]);
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UniswapV3Pool {
+    //Stored names of the fields of the first release are pinned, see `amm::compat`
+    #[serde(rename = "address")]
     pub address: H160,
+    #[serde(rename = "token_a")]
     pub token_a: H160,
+    #[serde(rename = "token_a_decimals")]
     pub token_a_decimals: u8,
+    #[serde(rename = "token_b")]
     pub token_b: H160,
+    #[serde(rename = "token_b_decimals")]
     pub token_b_decimals: u8,
+    #[serde(rename = "liquidity")]
     pub liquidity: u128,
+    #[serde(rename = "sqrt_price")]
     pub sqrt_price: U256,
+    #[serde(rename = "fee")]
     pub fee: u32,
+    #[serde(rename = "tick")]
     pub tick: i32,
+    #[serde(rename = "tick_spacing")]
     pub tick_spacing: i32,
+    #[serde(rename = "tick_bitmap", with = "tick_serde::tick_bitmap")]
     pub tick_bitmap: HashMap<i16, U256>,
+    #[serde(rename = "ticks", with = "tick_serde::ticks")]
     pub ticks: HashMap<i32, Info>,
+    //Block of the pool creation log, if the pool was discovered from it
+    #[serde(default)]
+    pub creation_block: Option<u64>,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+    #[serde(skip)]
+    pub tick_cache: TickCache,
+    #[serde(skip)]
+    pub decimal_scaling: DecimalScalingCache,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Info {
     pub liquidity_gross: u128,
     pub liquidity_net: i128,
@@ -117,17 +180,11 @@ impl Info {
     }
 }
 
-#[async_trait]
-impl AutomatedMarketMaker for UniswapV3Pool {
+impl AmmState for UniswapV3Pool {
     fn address(&self) -> H160 {
         self.address
     }
 
-    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
-        batch_request::sync_v3_pool_batch_request(self, middleware.clone()).await?;
-        Ok(())
-    }
-
     //This defines the event signatures to listen to that will produce events to be passed into AMM::sync_from_log()
     fn sync_on_event_signatures(&self) -> Vec<H256> {
         vec![
@@ -137,11 +194,12 @@ impl AutomatedMarketMaker for UniswapV3Pool {
         ]
     }
     fn sync_on_storage_slots(&self) -> Vec<H256> {
-        todo!();
+        vec![SLOT0_STORAGE_SLOT, LIQUIDITY_STORAGE_SLOT]
     }
 
-    fn sync_from_log(&mut self, log: Log) -> Result<(), EventLogError> {
-        let event_signature = log.topics[0];
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+        let event_signature = decode::event_signature(log)?;
 
         if event_signature == BURN_EVENT_SIGNATURE {
             self.sync_from_burn_log(log)?;
@@ -150,36 +208,96 @@ impl AutomatedMarketMaker for UniswapV3Pool {
         } else if event_signature == SWAP_EVENT_SIGNATURE {
             self.sync_from_swap_log(log)?;
         } else {
-            Err(EventLogError::InvalidEventSignature)?
+            Err(EventLogError::UnexpectedEvent(log.into()))?
         }
 
         Ok(())
     }
 
+    /// Updates the price, the tick and the liquidity in range, the tick data is synced from logs
     fn sync_from_storage(
         &mut self,
-        _storage: &'_ BTreeMap<H256, H256>,
-    ) -> Result<(), StorageError> {
-        todo!();
+        storage: &'_ BTreeMap<H256, H256>,
+    ) -> Result<bool, StateSyncError> {
+        let invalid_slot = |slot: H256| {
+            move |source: ArithmeticError| StateSyncError::InvalidSlot { slot, source }
+        };
+
+        let liquidity = match storage.get(&LIQUIDITY_STORAGE_SLOT) {
+            Some(value) => {
+                let liquidity = U256::from_big_endian(value.as_bytes());
+                if liquidity > U256::from(u128::MAX) {
+                    return Err(invalid_slot(LIQUIDITY_STORAGE_SLOT)(
+                        ArithmeticError::U128ConversionError,
+                    ));
+                }
+                Some(liquidity.as_u128())
+            }
+            None => None,
+        };
+
+        let slot0 = match storage.get(&SLOT0_STORAGE_SLOT) {
+            Some(value) if value.is_zero() => {
+                return Err(StateSyncError::EmptySlot(SLOT0_STORAGE_SLOT))
+            }
+            Some(value) => {
+                let packed = U256::from_big_endian(value.as_bytes());
+                let sqrt_price = packed & ((U256::one() << 160) - 1);
+                //The int24 tick sits above the uint160 price, the shifts sign extend it
+                let tick = ((((packed >> 160).low_u32() & 0xffffff) << 8) as i32) >> 8;
+                Some((sqrt_price, tick))
+            }
+            None => None,
+        };
+
+        let previous = (self.sqrt_price, self.tick, self.liquidity);
+        if let Some(liquidity) = liquidity {
+            self.set_liquidity(liquidity)
+                .map_err(invalid_slot(LIQUIDITY_STORAGE_SLOT))?;
+        }
+        if let Some((sqrt_price, tick)) = slot0 {
+            if let Err(source) = self.set_slot0(sqrt_price, tick) {
+                self.liquidity = previous.2;
+                return Err(invalid_slot(SLOT0_STORAGE_SLOT)(source));
+            }
+        }
+
+        Ok((self.sqrt_price, self.tick, self.liquidity) != previous)
     }
 
     fn reserves(&self) -> BTreeMap<H256, H256> {
         todo!()
     }
 
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::UniswapV3.name(),
+            &[
+                self.sqrt_price,
+                I256::from(self.tick).into_raw(),
+                U256::from(self.liquidity),
+                U256::from_big_endian(self.tick_digest().as_bytes()),
+            ],
+        )
+    }
+
     fn tokens(&self) -> Vec<H160> {
         vec![self.token_a, self.token_b]
     }
 
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
     fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
         let tick = uniswap_v3_math::tick_math::get_tick_at_sqrt_ratio(self.sqrt_price)?;
-        let shift = self.token_a_decimals as i8 - self.token_b_decimals as i8;
-
-        let price = match shift.cmp(&0) {
-            Ordering::Less => 1.0001_f64.powi(tick) / 10_f64.powi(-shift as i32),
-            Ordering::Greater => 1.0001_f64.powi(tick) * 10_f64.powi(shift as i32),
-            Ordering::Equal => 1.0001_f64.powi(tick),
-        };
+        let price = self.decimal_scaling().whole_price(1.0001_f64.powi(tick));
 
         if base_token == self.token_a {
             Ok(price)
@@ -187,16 +305,6 @@ impl AutomatedMarketMaker for UniswapV3Pool {
             Ok(1.0 / price)
         }
     }
-    // NOTE: This function will not populate the tick_bitmap and ticks, if you want to populate those, you must call populate_tick_data on an initialized pool
-    async fn populate_data<M: Middleware>(
-        &mut self,
-        block_number: Option<u64>,
-        middleware: Arc<M>,
-    ) -> Result<(), AMMError<M>> {
-        batch_request::get_v3_pool_data_batch_request(self, block_number, middleware.clone())
-            .await?;
-        Ok(())
-    }
 
     fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
         tracing::info!(?token_in, ?amount_in, "simulating swap");
@@ -205,145 +313,165 @@ impl AutomatedMarketMaker for UniswapV3Pool {
             return Ok(U256::zero());
         }
 
-        let zero_for_one = token_in == self.token_a;
+        let current_state = self.simulate_swap_state(token_in, amount_in, self.fee)?;
 
-        //Set sqrt_price_limit_x_96 to the max or min sqrt price in the pool depending on zero_for_one
-        let sqrt_price_limit_x_96 = if zero_for_one {
-            MIN_SQRT_RATIO + 1
-        } else {
-            MAX_SQRT_RATIO - 1
-        };
-
-        //Initialize a mutable state state struct to hold the dynamic simulated state of the pool
-        let mut current_state = CurrentState {
-            sqrt_price_x_96: self.sqrt_price, //Active price on the pool
-            amount_calculated: I256::zero(),  //Amount of token_out that has been calculated
-            amount_specified_remaining: I256::from_raw(amount_in), //Amount of token_in that has not been swapped
-            tick: self.tick,                                       //Current i24 tick of the pool
-            liquidity: self.liquidity, //Current available liquidity in the tick range
-        };
+        let amount_out = (-current_state.amount_calculated).into_raw();
 
-        while current_state.amount_specified_remaining != I256::zero()
-            && current_state.sqrt_price_x_96 != sqrt_price_limit_x_96
-        {
-            //Initialize a new step struct to hold the dynamic state of the pool at each step
-            let mut step = StepComputations {
-                sqrt_price_start_x_96: current_state.sqrt_price_x_96, //Set the sqrt_price_start_x_96 to the current sqrt_price_x_96
-                ..Default::default()
-            };
+        tracing::trace!(?amount_out);
 
-            //Get the next tick from the current tick
-            (step.tick_next, step.initialized) =
-                uniswap_v3_math::tick_bitmap::next_initialized_tick_within_one_word(
-                    &self.tick_bitmap,
-                    current_state.tick,
-                    self.tick_spacing,
-                    zero_for_one,
-                )?;
+        Ok(amount_out)
+    }
 
-            // ensure that we do not overshoot the min/max tick, as the tick bitmap is not aware of these bounds
-            //Note: this could be removed as we are clamping in the batch contract
-            step.tick_next = step.tick_next.clamp(MIN_TICK, MAX_TICK);
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        tracing::info!(?token_in, ?amount_in, "simulating swap");
 
-            //Get the next sqrt price from the input amount
-            step.sqrt_price_next_x96 =
-                uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(step.tick_next)?;
+        if amount_in.is_zero() {
+            return Ok(U256::zero());
+        }
 
-            //Target spot price
-            let swap_target_sqrt_ratio = if zero_for_one {
-                if step.sqrt_price_next_x96 < sqrt_price_limit_x_96 {
-                    sqrt_price_limit_x_96
-                } else {
-                    step.sqrt_price_next_x96
-                }
-            } else if step.sqrt_price_next_x96 > sqrt_price_limit_x_96 {
-                sqrt_price_limit_x_96
-            } else {
-                step.sqrt_price_next_x96
-            };
+        let current_state = self.simulate_swap_state(token_in, amount_in, self.fee)?;
 
-            //Compute swap step and update the current state
-            (
-                current_state.sqrt_price_x_96,
-                step.amount_in,
-                step.amount_out,
-                step.fee_amount,
-            ) = uniswap_v3_math::swap_math::compute_swap_step(
-                current_state.sqrt_price_x_96,
-                swap_target_sqrt_ratio,
-                current_state.liquidity,
-                current_state.amount_specified_remaining,
-                self.fee,
-            )?;
+        //Update the pool state
+        self.liquidity = current_state.liquidity;
+        self.sqrt_price = current_state.sqrt_price_x_96;
+        self.tick = current_state.tick;
 
-            //Decrement the amount remaining to be swapped and amount received from the step
-            current_state.amount_specified_remaining = current_state
-                .amount_specified_remaining
-                .overflowing_sub(I256::from_raw(
-                    step.amount_in.overflowing_add(step.fee_amount).0,
-                ))
-                .0;
+        let amount_out = (-current_state.amount_calculated).into_raw();
 
-            current_state.amount_calculated -= I256::from_raw(step.amount_out);
+        tracing::trace!(?amount_out);
 
-            //If the price moved all the way to the next price, recompute the liquidity change for the next iteration
-            if current_state.sqrt_price_x_96 == step.sqrt_price_next_x96 {
-                if step.initialized {
-                    let mut liquidity_net = if let Some(info) = self.ticks.get(&step.tick_next) {
-                        info.liquidity_net
-                    } else {
-                        0
-                    };
+        Ok(amount_out)
+    }
 
-                    // we are on a tick boundary, and the next tick is initialized, so we must charge a protocol fee
-                    if zero_for_one {
-                        liquidity_net = -liquidity_net;
-                    }
+    fn gradient(&self, token_in: H160, amount_in: U256) -> Result<Q128x128, SwapSimulationError> {
+        let zero_for_one = token_in == self.token_a;
+        let current_state = self.simulate_swap_state(token_in, amount_in, self.fee)?;
 
-                    current_state.liquidity = if liquidity_net < 0 {
-                        if current_state.liquidity < (-liquidity_net as u128) {
-                            return Err(SwapSimulationError::LiquidityUnderflow);
-                        } else {
-                            current_state.liquidity - (-liquidity_net as u128)
-                        }
-                    } else {
-                        current_state.liquidity + (liquidity_net as u128)
-                    };
-                }
-                //Increment the current tick
-                current_state.tick = if zero_for_one {
-                    step.tick_next.wrapping_sub(1)
-                } else {
-                    step.tick_next
-                }
-                //If the current_state sqrt price is not equal to the step sqrt price, then we are not on the same tick.
-                //Update the current_state.tick to the tick at the current_state.sqrt_price_x_96
-            } else if current_state.sqrt_price_x_96 != step.sqrt_price_start_x_96 {
-                current_state.tick = uniswap_v3_math::tick_math::get_tick_at_sqrt_ratio(
-                    current_state.sqrt_price_x_96,
-                )?;
-            }
+        //Swaps that run out of liquidity before using the whole input can not output more
+        if current_state.liquidity == 0 || !current_state.amount_specified_remaining.is_zero() {
+            return Ok(Q128x128::zero());
         }
 
-        let amount_out = (-current_state.amount_calculated).into_raw();
+        //The fee is taken from the input, so the marginal output is the price reached by the swap net of the fee
+        let fee = Q128x128::from_ratio(U256::from(1_000_000 - self.fee), U256::from(1_000_000));
+        marginal_price(current_state.sqrt_price_x_96, zero_for_one)
+            .and_then(|price| price.checked_mul(fee?))
+            .ok_or(SwapSimulationError::GradientOverflow)
+    }
 
-        tracing::trace!(?amount_out);
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token_a == token_in {
+            self.token_b
+        } else {
+            self.token_a
+        }
+    }
 
-        Ok(amount_out)
+    fn opp_token(&self, token_in: H160) -> Option<H160> {
+        if self.token_a == token_in {
+            Some(self.token_b)
+        } else if self.token_b == token_in {
+            Some(self.token_a)
+        } else {
+            None
+        }
     }
+}
 
-    fn simulate_swap_mut(
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for UniswapV3Pool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::sync_v3_pool_batch_request(self, middleware.clone()).await?;
+        Ok(())
+    }
+
+    // NOTE: This function will not populate the tick_bitmap and ticks, if you want to populate those, you must call populate_tick_data on an initialized pool
+    #[tracing::instrument(level = "debug", skip(self, middleware), fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
         &mut self,
-        token_in: H160,
-        amount_in: U256,
-    ) -> Result<U256, SwapSimulationError> {
-        tracing::info!(?token_in, ?amount_in, "simulating swap");
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_v3_pool_data_batch_request(self, block_number, middleware.clone())
+            .await?;
+        Ok(())
+    }
+}
 
-        if amount_in.is_zero() {
-            return Ok(U256::zero());
+impl UniswapV3Pool {
+    /// Builder for pools constructed offline, i.e. in tests or from indexed data, validating the pool when built
+    pub fn builder() -> UniswapV3PoolBuilder {
+        UniswapV3PoolBuilder::default()
+    }
+
+    /// Scaling between the decimals of token a and token b, cached on the pool
+    pub fn decimal_scaling(&self) -> DecimalScaling {
+        self.decimal_scaling
+            .get(self.token_a_decimals, self.token_b_decimals)
+    }
+
+    /// Recomputes the cached decimal scaling, called once the decimals of the pool are populated
+    pub fn reset_decimal_scaling(&mut self) {
+        self.decimal_scaling =
+            DecimalScalingCache::new(self.token_a_decimals, self.token_b_decimals);
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Sets the price and the current tick, rejecting a tick more than one tick away from the tick of the price.
+    /// A swap ending exactly on an initialized tick while moving down leaves the pool one tick below its price.
+    pub fn set_slot0(&mut self, sqrt_price: U256, tick: i32) -> Result<(), ArithmeticError> {
+        if sqrt_price < MIN_SQRT_RATIO || sqrt_price >= MAX_SQRT_RATIO {
+            return Err(ArithmeticError::SqrtPriceOutOfRange(sqrt_price));
+        }
+
+        let expected = uniswap_v3_math::tick_math::get_tick_at_sqrt_ratio(sqrt_price)?;
+        if tick.abs_diff(expected) > 1 {
+            return Err(ArithmeticError::TickMismatch { tick, expected });
+        }
+
+        self.sqrt_price = sqrt_price;
+        self.tick = tick;
+
+        Ok(())
+    }
+
+    /// Sets the liquidity in range, which ticks add to and remove from as an `i128`
+    pub fn set_liquidity(&mut self, liquidity: u128) -> Result<(), ArithmeticError> {
+        if liquidity > i128::MAX as u128 {
+            return Err(ArithmeticError::LiquidityOverflow(liquidity));
         }
 
+        self.liquidity = liquidity;
+
+        Ok(())
+    }
+
+    //Walks the ticks for a swap of `amount_in` paying `fee` without mutating the pool, returning the state reached.
+    //Uniswap V4 pools charge a fee depending on the direction of the swap, so it is not always the fee of the pool.
+    pub(crate) fn simulate_swap_state(
+        &self,
+        token_in: H160,
+        amount_in: U256,
+        fee: u32,
+    ) -> Result<CurrentState, SwapSimulationError> {
         let zero_for_one = token_in == self.token_a;
+        let tick_table = self.tick_cache.get(&self.ticks, &self.tick_bitmap);
 
         //Set sqrt_price_limit_x_96 to the max or min sqrt price in the pool depending on zero_for_one
         let sqrt_price_limit_x_96 = if zero_for_one {
@@ -371,13 +499,11 @@ impl AutomatedMarketMaker for UniswapV3Pool {
             };
 
             //Get the next tick from the current tick
-            (step.tick_next, step.initialized) =
-                uniswap_v3_math::tick_bitmap::next_initialized_tick_within_one_word(
-                    &self.tick_bitmap,
-                    current_state.tick,
-                    self.tick_spacing,
-                    zero_for_one,
-                )?;
+            (step.tick_next, step.initialized) = tick_table.next_initialized_tick_within_one_word(
+                current_state.tick,
+                self.tick_spacing,
+                zero_for_one,
+            );
 
             // ensure that we do not overshoot the min/max tick, as the tick bitmap is not aware of these bounds
             //Note: this could be removed as we are clamping in the batch contract
@@ -411,7 +537,7 @@ impl AutomatedMarketMaker for UniswapV3Pool {
                 swap_target_sqrt_ratio,
                 current_state.liquidity,
                 current_state.amount_specified_remaining,
-                self.fee,
+                fee,
             )?;
 
             //Decrement the amount remaining to be swapped and amount received from the step
@@ -427,11 +553,7 @@ impl AutomatedMarketMaker for UniswapV3Pool {
             //If the price moved all the way to the next price, recompute the liquidity change for the next iteration
             if current_state.sqrt_price_x_96 == step.sqrt_price_next_x96 {
                 if step.initialized {
-                    let mut liquidity_net = if let Some(info) = self.ticks.get(&step.tick_next) {
-                        info.liquidity_net
-                    } else {
-                        0
-                    };
+                    let mut liquidity_net = tick_table.liquidity_net(step.tick_next);
 
                     // we are on a tick boundary, and the next tick is initialized, so we must charge a protocol fee
                     if zero_for_one {
@@ -463,38 +585,8 @@ impl AutomatedMarketMaker for UniswapV3Pool {
             }
         }
 
-        //Update the pool state
-        self.liquidity = current_state.liquidity;
-        self.sqrt_price = current_state.sqrt_price_x_96;
-        self.tick = current_state.tick;
-
-        let amount_out = (-current_state.amount_calculated).into_raw();
-
-        tracing::trace!(?amount_out);
-
-        Ok(amount_out)
-    }
-
-    fn get_token_out(&self, token_in: H160) -> H160 {
-        if self.token_a == token_in {
-            self.token_b
-        } else {
-            self.token_a
-        }
-    }
-
-    fn opp_token(&self, token_in: H160) -> Option<H160> {
-        if self.token_a == token_in {
-            Some(self.token_b)
-        } else if self.token_b == token_in {
-            Some(self.token_a)
-        } else {
-            None
-        }
+        Ok(current_state)
     }
-}
-
-impl UniswapV3Pool {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         address: H160,
@@ -523,11 +615,75 @@ impl UniswapV3Pool {
             tick_spacing,
             tick_bitmap,
             ticks,
+            creation_block: None,
+            tick_cache: TickCache::default(),
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::new(token_a_decimals, token_b_decimals),
         }
     }
 
-    // Creates a new instance of the pool from the pair address
+    /// Creates the pool at `pair_address` populated at the latest block, with up to `tick_window` initialized ticks
+    /// on each side of the current tick. Errors with `AMMError::AMMVariantMismatch` if the contract is not a
+    /// Uniswap V3 pool.
+    #[cfg(feature = "rpc")]
     pub async fn new_from_address<M: 'static + Middleware>(
+        pair_address: H160,
+        tick_window: u16,
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        UniswapV3Pool::new_from_address_at_block(pair_address, tick_window, None, middleware).await
+    }
+
+    /// Same as `new_from_address`, populating the pool at `block_number`
+    #[cfg(feature = "rpc")]
+    pub async fn new_from_address_at_block<M: 'static + Middleware>(
+        pair_address: H160,
+        tick_window: u16,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        detect::expect_variant(
+            pair_address,
+            DetectedVariant::UniswapV3Pool,
+            middleware.clone(),
+        )
+        .await?;
+
+        //The pool data and the ticks are read at the same block
+        let block_number = match block_number {
+            Some(block_number) => block_number,
+            None => middleware
+                .get_block_number()
+                .await
+                .map_err(AMMError::MiddlewareError)?
+                .as_u64(),
+        };
+
+        let mut pool = UniswapV3Pool {
+            address: pair_address,
+            ..Default::default()
+        };
+        pool.populate_data(Some(block_number), middleware.clone())
+            .await?;
+
+        if !pool.data_is_populated() {
+            return Err(AMMError::PoolDataError);
+        }
+
+        pool.populate_tick_window(tick_window, Some(block_number), middleware)
+            .await?;
+
+        Ok(pool)
+    }
+
+    /// Creates the pool at `pair_address` with its full tick data, replayed from the mint and burn logs since
+    /// `creation_block`
+    #[cfg(feature = "rpc")]
+    pub async fn new_from_creation_block<M: 'static + Middleware>(
         pair_address: H160,
         creation_block: u64,
         middleware: Arc<M>,
@@ -545,6 +701,14 @@ impl UniswapV3Pool {
             fee: 0,
             tick_bitmap: HashMap::new(),
             ticks: HashMap::new(),
+            creation_block: Some(creation_block),
+            tick_cache: TickCache::default(),
+            token_a_symbol: None,
+            token_a_name: None,
+            token_b_symbol: None,
+            token_b_name: None,
+            serde_version: SerdeVersion::CURRENT,
+            decimal_scaling: DecimalScalingCache::default(),
         };
 
         //We need to get tick spacing before populating tick data because tick spacing can not be uninitialized when syncing burn and mint logs
@@ -564,34 +728,37 @@ impl UniswapV3Pool {
         Ok(pool)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn new_from_log<M: 'static + Middleware>(
         log: Log,
         middleware: Arc<M>,
     ) -> Result<Self, AMMError<M>> {
-        let event_signature = log.topics[0];
+        let event_signature = decode::event_signature(&log)?;
 
         if event_signature == POOL_CREATED_EVENT_SIGNATURE {
             if let Some(block_number) = log.block_number {
                 let pool_created_event = PoolCreatedFilter::decode_log(&RawLog::from(log))?;
 
-                UniswapV3Pool::new_from_address(
+                UniswapV3Pool::new_from_creation_block(
                     pool_created_event.pool,
                     block_number.as_u64(),
                     middleware,
                 )
                 .await
             } else {
-                Err(EventLogError::LogBlockNumberNotFound)?
+                Err(EventLogError::LogBlockNumberNotFound((&log).into()))?
             }
         } else {
-            Err(EventLogError::InvalidEventSignature)?
+            Err(EventLogError::UnexpectedEvent((&log).into()))?
         }
     }
 
+    #[cfg(feature = "rpc")]
     pub fn new_empty_pool_from_log(log: Log) -> Result<Self, EventLogError> {
-        let event_signature = log.topics[0];
+        let event_signature = decode::event_signature(&log)?;
 
         if event_signature == POOL_CREATED_EVENT_SIGNATURE {
+            let creation_block = log.block_number.map(|block_number| block_number.as_u64());
             let pool_created_event = PoolCreatedFilter::decode_log(&RawLog::from(log))?;
 
             Ok(UniswapV3Pool {
@@ -607,12 +774,68 @@ impl UniswapV3Pool {
                 tick: 0,
                 tick_bitmap: HashMap::new(),
                 ticks: HashMap::new(),
+                creation_block,
+                tick_cache: TickCache::default(),
+                token_a_symbol: None,
+                token_a_name: None,
+                token_b_symbol: None,
+                token_b_name: None,
+                serde_version: SerdeVersion::CURRENT,
+                decimal_scaling: DecimalScalingCache::default(),
             })
         } else {
-            Err(EventLogError::InvalidEventSignature)
+            Err(EventLogError::UnexpectedEvent((&log).into()))
+        }
+    }
+
+    /// Loads up to `tick_window` initialized ticks on each side of the current tick with the tick data batch contract,
+    /// returning the block they were read at. Swaps moving the price past the loaded ticks are not simulated accurately.
+    #[cfg(feature = "rpc")]
+    pub async fn populate_tick_window<M: Middleware>(
+        &mut self,
+        tick_window: u16,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<u64, AMMError<M>> {
+        let mut synced_block = block_number.map(U64::from);
+
+        for zero_for_one in [true, false] {
+            let (tick_data, block_number) = batch_request::get_uniswap_v3_tick_data_batch_request(
+                self,
+                self.tick,
+                zero_for_one,
+                tick_window,
+                synced_block,
+                middleware.clone(),
+            )
+            .await?;
+            synced_block = Some(block_number);
+
+            for tick_data in tick_data {
+                //Both directions may return the current tick
+                if !tick_data.initialized || self.ticks.contains_key(&tick_data.tick) {
+                    continue;
+                }
+
+                //The batch contract only returns the net liquidity, which the gross liquidity is at least
+                self.ticks.insert(
+                    tick_data.tick,
+                    Info::new(
+                        tick_data.liquidity_net.unsigned_abs(),
+                        tick_data.liquidity_net,
+                        true,
+                    ),
+                );
+                self.flip_tick(tick_data.tick, self.tick_spacing);
+            }
         }
+
+        self.invalidate_tick_cache();
+
+        Ok(synced_block.unwrap_or_default().as_u64())
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn populate_tick_data<M: 'static + Middleware>(
         &mut self,
         mut from_block: u64,
@@ -669,13 +892,14 @@ impl UniswapV3Pool {
 
         for (_, log_group) in ordered_logs {
             for log in log_group {
-                self.sync_from_log(log)?;
+                self.sync_from_log(&log)?;
             }
         }
 
         Ok(current_block)
     }
 
+    #[cfg(feature = "rpc")]
     async fn process_logs_from_handles<M: Middleware>(
         &self,
         handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
@@ -693,7 +917,7 @@ impl UniswapV3Pool {
                         ordered_logs.insert(log_block_number, vec![log]);
                     }
                 } else {
-                    return Err(EventLogError::LogBlockNumberNotFound)?;
+                    return Err(EventLogError::LogBlockNumberNotFound((&log).into()))?;
                 }
             }
         }
@@ -708,6 +932,7 @@ impl UniswapV3Pool {
         !(self.token_a.is_zero() || self.token_b.is_zero())
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_tick_word<M: Middleware>(
         &self,
         tick: i32,
@@ -718,6 +943,7 @@ impl UniswapV3Pool {
         Ok(v3_pool.tick_bitmap(word_position).call().await?)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_next_word<M: Middleware>(
         &self,
         word_position: i16,
@@ -727,6 +953,7 @@ impl UniswapV3Pool {
         Ok(v3_pool.tick_bitmap(word_position).call().await?)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_tick_spacing<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -735,10 +962,12 @@ impl UniswapV3Pool {
         Ok(v3_pool.tick_spacing().call().await?)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_tick<M: Middleware>(&self, middleware: Arc<M>) -> Result<i32, AMMError<M>> {
         Ok(self.get_slot_0(middleware).await?.1)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_tick_info<M: Middleware>(
         &self,
         tick: i32,
@@ -760,6 +989,7 @@ impl UniswapV3Pool {
         ))
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_liquidity_net<M: Middleware>(
         &self,
         tick: i32,
@@ -769,6 +999,7 @@ impl UniswapV3Pool {
         Ok(tick_info.1)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_initialized<M: Middleware>(
         &self,
         tick: i32,
@@ -778,6 +1009,7 @@ impl UniswapV3Pool {
         Ok(tick_info.7)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_slot_0<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -786,6 +1018,7 @@ impl UniswapV3Pool {
         Ok(v3_pool.slot_0().call().await?)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_liquidity<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -794,6 +1027,7 @@ impl UniswapV3Pool {
         Ok(v3_pool.liquidity().call().await?)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_sqrt_price<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -801,26 +1035,56 @@ impl UniswapV3Pool {
         Ok(self.get_slot_0(middleware).await?.0)
     }
 
-    pub fn sync_from_burn_log(&mut self, log: Log) -> Result<(), AbiError> {
-        let burn_event = BurnFilter::decode_log(&RawLog::from(log))?;
-
-        self.modify_position(
-            burn_event.tick_lower,
-            burn_event.tick_upper,
-            -(burn_event.amount as i128),
-        );
+    pub fn sync_from_burn_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let (tick_lower, tick_upper, amount) = decode_position_log(log, 0)?;
+        self.checked_modify_position(log, tick_lower, tick_upper, -amount)
+    }
 
-        Ok(())
+    pub fn sync_from_mint_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let (tick_lower, tick_upper, amount) = decode_position_log(log, 1)?;
+        self.checked_modify_position(log, tick_lower, tick_upper, amount)
     }
 
-    pub fn sync_from_mint_log(&mut self, log: Log) -> Result<(), AbiError> {
-        let mint_event = MintFilter::decode_log(&RawLog::from(log))?;
+    //Logs from the pool always describe a valid update, anything that would corrupt the ticks is rejected instead of applied
+    pub(crate) fn checked_modify_position(
+        &mut self,
+        log: &Log,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity_delta: i128,
+    ) -> Result<(), EventLogError> {
+        let valid_tick =
+            |tick: i32| (MIN_TICK..=MAX_TICK).contains(&tick) && tick % self.tick_spacing == 0;
+        let valid_update = |tick: i32, upper: bool| {
+            let (liquidity_gross, liquidity_net) = self
+                .ticks
+                .get(&tick)
+                .map_or((0, 0), |info| (info.liquidity_gross, info.liquidity_net));
+            let liquidity_net = if upper {
+                liquidity_net.checked_sub(liquidity_delta)
+            } else {
+                liquidity_net.checked_add(liquidity_delta)
+            };
 
-        self.modify_position(
-            mint_event.tick_lower,
-            mint_event.tick_upper,
-            mint_event.amount as i128,
-        );
+            liquidity_gross
+                .checked_add_signed(liquidity_delta)
+                .is_some()
+                && liquidity_net.is_some()
+        };
+
+        let in_range = self.tick > tick_lower && self.tick < tick_upper;
+        if self.tick_spacing <= 0
+            || tick_lower >= tick_upper
+            || !valid_tick(tick_lower)
+            || !valid_tick(tick_upper)
+            || !valid_update(tick_lower, false)
+            || !valid_update(tick_upper, true)
+            || (in_range && self.liquidity.checked_add_signed(liquidity_delta).is_none())
+        {
+            return Err(EventLogError::MalformedLog(log.into()));
+        }
+
+        self.modify_position(tick_lower, tick_upper, liquidity_delta);
 
         Ok(())
     }
@@ -860,10 +1124,12 @@ impl UniswapV3Pool {
         if liquidity_delta < 0 {
             if flipped_lower {
                 self.ticks.remove(&tick_lower);
+                self.tick_cache.update_tick(tick_lower, None);
             }
 
             if flipped_upper {
                 self.ticks.remove(&tick_upper);
+                self.tick_cache.update_tick(tick_upper, None);
             }
         }
     }
@@ -903,6 +1169,9 @@ impl UniswapV3Pool {
             info.liquidity_net + liquidity_delta
         };
 
+        let info = info.clone();
+        self.tick_cache.update_tick(tick, Some(&info));
+
         flipped
     }
 
@@ -910,23 +1179,56 @@ impl UniswapV3Pool {
         let (word_pos, bit_pos) = uniswap_v3_math::tick_bitmap::position(tick / tick_spacing);
         let mask = U256::one() << bit_pos;
 
-        if let Some(word) = self.tick_bitmap.get_mut(&word_pos) {
-            *word ^= mask;
-        } else {
-            self.tick_bitmap.insert(word_pos, mask);
-        }
+        let word = self.tick_bitmap.entry(word_pos).or_default();
+        *word ^= mask;
+
+        self.tick_cache.update_word(word_pos, *word);
     }
 
-    pub fn sync_from_swap_log(&mut self, log: Log) -> Result<(), AbiError> {
-        let swap_event = SwapFilter::decode_log(&RawLog::from(log))?;
+    /// Drops the cached copy of `ticks` and `tick_bitmap`, needed after writing to them directly
+    pub fn invalidate_tick_cache(&mut self) {
+        self.tick_cache.invalidate();
+    }
 
-        self.sqrt_price = swap_event.sqrt_price_x96;
-        self.liquidity = swap_event.liquidity;
-        self.tick = swap_event.tick;
+    //Hash of the initialized ticks in ascending order, the bitmap is derived from them
+    fn tick_digest(&self) -> H256 {
+        let mut ticks = self
+            .ticks
+            .iter()
+            .filter(|(_, info)| info.initialized)
+            .collect::<Vec<_>>();
+        ticks.sort_unstable_by_key(|(tick, _)| **tick);
+
+        let words = ticks
+            .into_iter()
+            .flat_map(|(tick, info)| {
+                [
+                    I256::from(*tick).into_raw(),
+                    U256::from(info.liquidity_gross),
+                    I256::from(info.liquidity_net).into_raw(),
+                ]
+            })
+            .collect::<Vec<_>>();
 
-        Ok(())
+        amm::state_fingerprint("ticks", &words)
     }
 
+    pub fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let sqrt_price = decode::data_uint(log, 2, 160)?;
+        let liquidity = decode::data_uint(log, 3, 128)?.low_u128();
+        let tick = decode::data_int(log, 4, 24)?.low_i32();
+
+        //The liquidity is restored if the price is rejected, so a bad log leaves the pool untouched
+        let liquidity_before = self.liquidity;
+        let malformed_log = |_| EventLogError::MalformedLog(log.into());
+        self.set_liquidity(liquidity).map_err(malformed_log)?;
+        self.set_slot0(sqrt_price, tick).map_err(|error| {
+            self.liquidity = liquidity_before;
+            malformed_log(error)
+        })
+    }
+
+    #[cfg(feature = "rpc")]
     pub async fn get_token_decimals<M: Middleware>(
         &mut self,
         middleware: Arc<M>,
@@ -944,6 +1246,7 @@ impl UniswapV3Pool {
         Ok((token_a_decimals, token_b_decimals))
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_fee<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<u32, AMMError<M>> {
         let fee = IUniswapV3Pool::new(self.address, middleware)
             .fee()
@@ -953,6 +1256,7 @@ impl UniswapV3Pool {
         Ok(fee)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_token_0<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -967,6 +1271,7 @@ impl UniswapV3Pool {
         Ok(token_0)
     }
 
+    #[cfg(feature = "rpc")]
     pub async fn get_token_1<M: Middleware>(
         &self,
         middleware: Arc<M>,
@@ -990,26 +1295,28 @@ impl UniswapV3Pool {
         let tick = uniswap_v3_math::tick_math::get_tick_at_sqrt_ratio(self.sqrt_price)?;
         let price = 1.0001_f64.powi(tick);
 
-        let sqrt_price = BigFloat::from_f64(price.sqrt());
-        let liquidity = BigFloat::from_u128(self.liquidity);
+        let sqrt_price = Q128x128::from_f64(price.sqrt())
+            .ok_or(ArithmeticError::SqrtPriceOverflow)?
+            .into_raw()
+            .into_raw();
+        let liquidity = U256::from(self.liquidity);
 
         let (reserve_0, reserve_1) = if !sqrt_price.is_zero() {
-            let reserve_x = liquidity.div(&sqrt_price);
-            let reserve_y = liquidity.mul(&sqrt_price);
+            let reserve_x = mul_div(liquidity, U256::one() << 128, sqrt_price)?;
+            let reserve_y = mul_shift_right(liquidity, sqrt_price, 128)?;
 
             (reserve_x, reserve_y)
         } else {
-            (BigFloat::from(0), BigFloat::from(0))
+            (U256::zero(), U256::zero())
         };
 
-        Ok((
-            reserve_0
-                .to_u128()
-                .ok_or(ArithmeticError::U128ConversionError)?,
-            reserve_1
-                .to_u128()
-                .ok_or(ArithmeticError::U128ConversionError)?,
-        ))
+        let to_u128 = |reserve: U256| {
+            (reserve <= U256::from(u128::MAX))
+                .then(|| reserve.as_u128())
+                .ok_or(ArithmeticError::U128ConversionError)
+        };
+
+        Ok((to_u128(reserve_0)?, to_u128(reserve_1)?))
     }
 
     pub fn calculate_compressed(&self, tick: i32) -> i32 {
@@ -1046,12 +1353,39 @@ impl UniswapV3Pool {
     }
 }
 
+//Price of the input token in terms of the output token at `sqrt_price_x_96`, as a Q128.128
+fn marginal_price(sqrt_price_x_96: U256, zero_for_one: bool) -> Option<Q128x128> {
+    let price = if zero_for_one {
+        sqrt_price_x96_to_price_x128(sqrt_price_x_96, 0, 0).ok()?
+    } else {
+        //2^320 / sqrt_price^2 does not have a 256-bit numerator
+        let sqrt_price_squared = sqrt_price_x_96.full_mul(sqrt_price_x_96);
+        if sqrt_price_squared.is_zero() {
+            return None;
+        }
+
+        U256::try_from((U512::one() << 320) / sqrt_price_squared).ok()?
+    };
+
+    Q128x128::from_unsigned_raw(price)
+}
+
+//Ticks of the position and the liquidity amount of a mint or burn log, both index the ticks in topics 2 and 3
+fn decode_position_log(log: &Log, amount_index: usize) -> Result<(i32, i32, i128), EventLogError> {
+    let tick_lower = decode::topic_int(log, 2, 24)?.low_i32();
+    let tick_upper = decode::topic_int(log, 3, 24)?.low_i32();
+    let amount = i128::try_from(decode::data_uint(log, amount_index, 128)?.low_u128())
+        .map_err(|_| EventLogError::MalformedLog(log.into()))?;
+
+    Ok((tick_lower, tick_upper, amount))
+}
+
 pub struct CurrentState {
-    amount_specified_remaining: I256,
-    amount_calculated: I256,
-    sqrt_price_x_96: U256,
-    tick: i32,
-    liquidity: u128,
+    pub(crate) amount_specified_remaining: I256,
+    pub(crate) amount_calculated: I256,
+    pub(crate) sqrt_price_x_96: U256,
+    pub(crate) tick: i32,
+    pub(crate) liquidity: u128,
 }
 
 #[derive(Default)]
@@ -1086,7 +1420,12 @@ mod test {
     #[allow(unused)]
     use super::UniswapV3Pool;
 
-    use crate::amm::AutomatedMarketMaker;
+    use super::{LIQUIDITY_STORAGE_SLOT, SLOT0_STORAGE_SLOT};
+    use crate::{
+        amm::{AmmState, AutomatedMarketMaker, AMM},
+        errors::{ArithmeticError, StateSyncError},
+        test_utils::ForkHarness,
+    };
 
     #[allow(unused)]
     use ethers::providers::Middleware;
@@ -1095,16 +1434,21 @@ mod test {
     use ethers::{
         prelude::abigen,
         providers::{Http, Provider},
-        types::{H160, U256},
+        types::{H160, H256, U256},
     };
     #[allow(unused)]
     use std::error::Error;
     #[allow(unused)]
-    use std::{str::FromStr, sync::Arc};
+    use std::{collections::BTreeMap, str::FromStr, sync::Arc};
     abigen!(
         IQuoter,
     r#"[
         function quoteExactInputSingle(address tokenIn, address tokenOut,uint24 fee, uint256 amountIn, uint160 sqrtPriceLimitX96) external returns (uint256 amountOut)
+    ]"#;
+
+        IQuoterV2,
+    r#"[
+        function quoteExactInputSingle((address, address, uint256, uint24, uint160) params) external returns (uint256 amountOut, uint160 sqrtPriceX96After, uint32 initializedTicksCrossed, uint256 gasEstimate)
     ]"#;);
 
     async fn initialize_usdc_weth_pool<M: 'static + Middleware>(
@@ -1145,8 +1489,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_usdc_weth() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_usdc_weth_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1227,8 +1574,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_weth_usdc() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_usdc_weth_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1310,8 +1660,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_link_weth() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_weth_link_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1393,8 +1746,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_weth_link() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_weth_link_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1476,8 +1832,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_mut_usdc_weth() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_usdc_weth_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1558,8 +1917,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_mut_weth_usdc() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_usdc_weth_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1641,8 +2003,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_mut_link_weth() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_weth_link_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1724,8 +2089,11 @@ mod test {
 
     #[tokio::test]
     async fn test_simulate_swap_mut_weth_link() -> eyre::Result<()> {
-        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
-        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
 
         let (pool, synced_block) = initialize_weth_link_pool(middleware.clone()).await?;
         let quoter = IQuoter::new(
@@ -1805,12 +2173,32 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_swap_on_fork() -> eyre::Result<()> {
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+
+        let (pool, _) = initialize_usdc_weth_pool(fork.provider.clone()).await?;
+        let (usdc, weth) = (pool.token_a, pool.token_b);
+        let mut amm = AMM::UniswapV3Pool(pool);
+
+        //Large enough to cross ticks in both directions
+        fork.assert_swap_matches(&mut amm, usdc, U256::from(10_000_000_000_000_u64))
+            .await?;
+        fork.assert_swap_matches(&mut amm, weth, U256::exp10(22))
+            .await?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_new_from_address() -> eyre::Result<()> {
         let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
         let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
 
-        let pool = UniswapV3Pool::new_from_address(
+        let pool = UniswapV3Pool::new_from_creation_block(
             H160::from_str("0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640")?,
             12369620,
             middleware.clone(),
@@ -1941,4 +2329,297 @@ mod test {
 
         Ok(())
     }
+
+    fn single_range_pool() -> UniswapV3Pool {
+        //Price of 1 with the liquidity spread over the whole bitmap window
+        UniswapV3Pool::builder()
+            .with_token_a(H160::from_low_u64_be(1), 18)
+            .with_token_b(H160::from_low_u64_be(2), 18)
+            .with_liquidity(10_u128.pow(24))
+            .with_tick(0)
+            .build()
+            .expect("valid pool")
+    }
+
+    #[test]
+    fn test_set_slot0() -> eyre::Result<()> {
+        let mut pool = single_range_pool();
+        let sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(600)?;
+
+        //A swap ending on a tick while moving down leaves the pool at the tick below
+        pool.set_slot0(sqrt_price, 599)?;
+        assert_eq!((pool.sqrt_price, pool.tick), (sqrt_price, 599));
+
+        assert!(matches!(
+            pool.set_slot0(sqrt_price, 0),
+            Err(ArithmeticError::TickMismatch {
+                tick: 0,
+                expected: 600
+            })
+        ));
+        assert!(matches!(
+            pool.set_slot0(U256::zero(), 0),
+            Err(ArithmeticError::SqrtPriceOutOfRange(_))
+        ));
+        assert!(matches!(
+            pool.set_liquidity(u128::MAX),
+            Err(ArithmeticError::LiquidityOverflow(u128::MAX))
+        ));
+
+        //Rejected values leave the pool untouched
+        assert_eq!((pool.sqrt_price, pool.tick), (sqrt_price, 599));
+        assert_eq!(pool.liquidity, 10_u128.pow(24));
+
+        Ok(())
+    }
+
+    //Slot0 as stored by the pool, with the pool unlocked
+    fn packed_slot0(sqrt_price: U256, tick: i32) -> H256 {
+        let packed =
+            sqrt_price | (U256::from(tick as u32 & 0xffffff) << 160) | (U256::one() << 240);
+        let mut value = [0_u8; 32];
+        packed.to_big_endian(&mut value);
+        H256(value)
+    }
+
+    #[test]
+    fn test_sync_from_storage() -> eyre::Result<()> {
+        let mut pool = single_range_pool();
+        let sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(-600)?;
+        let diff = BTreeMap::from([
+            (SLOT0_STORAGE_SLOT, packed_slot0(sqrt_price, -600)),
+            (LIQUIDITY_STORAGE_SLOT, H256::from_low_u64_be(5000)),
+        ]);
+
+        assert!(pool.sync_from_storage(&diff)?);
+        assert_eq!(
+            (pool.sqrt_price, pool.tick, pool.liquidity),
+            (sqrt_price, -600, 5000)
+        );
+        assert!(!pool.sync_from_storage(&diff)?);
+
+        //A tick inconsistent with the price fails the whole diff, the liquidity included
+        let corrupted = BTreeMap::from([
+            (SLOT0_STORAGE_SLOT, packed_slot0(sqrt_price, 0)),
+            (LIQUIDITY_STORAGE_SLOT, H256::from_low_u64_be(7000)),
+        ]);
+        assert!(matches!(
+            pool.sync_from_storage(&corrupted),
+            Err(StateSyncError::InvalidSlot {
+                slot,
+                source: ArithmeticError::TickMismatch { tick: 0, expected: -600 },
+            }) if slot == SLOT0_STORAGE_SLOT
+        ));
+
+        let corrupted = BTreeMap::from([(SLOT0_STORAGE_SLOT, packed_slot0(U256::one(), 0))]);
+        assert!(matches!(
+            pool.sync_from_storage(&corrupted),
+            Err(StateSyncError::InvalidSlot {
+                slot,
+                source: ArithmeticError::SqrtPriceOutOfRange(_),
+            }) if slot == SLOT0_STORAGE_SLOT
+        ));
+
+        //Liquidity is a uint128 alone in its slot
+        let corrupted = BTreeMap::from([(LIQUIDITY_STORAGE_SLOT, H256::repeat_byte(0xff))]);
+        assert!(matches!(
+            pool.sync_from_storage(&corrupted),
+            Err(StateSyncError::InvalidSlot {
+                slot,
+                source: ArithmeticError::U128ConversionError,
+            }) if slot == LIQUIDITY_STORAGE_SLOT
+        ));
+
+        assert_eq!(
+            (pool.sqrt_price, pool.tick, pool.liquidity),
+            (sqrt_price, -600, 5000)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gradient() -> eyre::Result<()> {
+        let pool = single_range_pool();
+
+        for token_in in pool.tokens() {
+            //At the current price the gradient is the price net of the fee
+            assert!((pool.gradient_f64(token_in, U256::zero())? - 0.997).abs() < 1e-15);
+
+            for amount_in in [U256::exp10(18), U256::exp10(21), U256::exp10(22)] {
+                let gradient = pool.gradient_f64(token_in, amount_in)?;
+
+                //Forward difference of the simulated output
+                let step = U256::exp10(12);
+                let amount_out = pool.simulate_swap(token_in, amount_in)?;
+                let amount_out_after_step = pool.simulate_swap(token_in, amount_in + step)?;
+                let difference = (amount_out_after_step - amount_out).as_u128() as f64 / 1e12;
+                assert!((gradient - difference).abs() / gradient < 1e-6);
+            }
+        }
+
+        let empty_pool = UniswapV3Pool {
+            liquidity: 0,
+            ..single_range_pool()
+        };
+        assert!(empty_pool
+            .gradient(H160::from_low_u64_be(1), U256::exp10(18))?
+            .is_zero());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_virtual_reserves_offline() -> eyre::Result<()> {
+        let mut pool = single_range_pool();
+        assert_eq!(
+            pool.calculate_virtual_reserves()?,
+            (10_u128.pow(24), 10_u128.pow(24))
+        );
+
+        pool.sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(-20000)?;
+        let sqrt_price = 1.0001_f64.powi(-20000).sqrt();
+        let (reserve_0, reserve_1) = pool.calculate_virtual_reserves()?;
+        assert!((reserve_0 as f64 / (1e24 / sqrt_price) - 1.0).abs() < 1e-12);
+        assert!((reserve_1 as f64 / (1e24 * sqrt_price) - 1.0).abs() < 1e-12);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_pool_address() -> eyre::Result<()> {
+        use crate::amm::uniswap_v3::factory::{
+            compute_pool_address, UniswapV3Factory, UniswapV3Fork, PANCAKESWAP_V3_POOL_DEPLOYER,
+            PANCAKESWAP_V3_POOL_INIT_CODE_HASH,
+        };
+
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")?;
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")?;
+
+        let factory = UniswapV3Factory::new(
+            H160::from_str("0x1F98431c8aD98523631AE4a59f267346ea31F984")?,
+            12369621,
+        );
+
+        assert_eq!(
+            factory.compute_pool_address(usdc, weth, 500),
+            H160::from_str("0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640")?
+        );
+        assert_eq!(
+            factory.compute_pool_address(weth, usdc, 3000),
+            H160::from_str("0x8ad599c3A0ff1De082011EFDDc58f1908eb6e6D8")?
+        );
+
+        //PancakeSwap V3 on BSC
+        let wbnb = H160::from_str("0xbb4CdB9CBd36B01bD1cBaEBF2De08d9173bc095c")?;
+        let busd = H160::from_str("0xe9e7CEA3DedcA5984780Bafc599bD69ADd087D56")?;
+
+        assert_eq!(
+            compute_pool_address(
+                PANCAKESWAP_V3_POOL_DEPLOYER,
+                wbnb,
+                busd,
+                500,
+                PANCAKESWAP_V3_POOL_INIT_CODE_HASH
+            ),
+            H160::from_str("0x85FAac652b707FDf6907EF726751087F9E0b6687")?
+        );
+
+        //The fork config derives the same address from the factory
+        let pancakeswap_v3_factory = UniswapV3Factory::new_with_fork(
+            H160::from_str("0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865")?,
+            26956207,
+            UniswapV3Fork::PancakeSwapV3,
+        );
+        assert_eq!(
+            pancakeswap_v3_factory.compute_pool_address(busd, wbnb, 500),
+            H160::from_str("0x85FAac652b707FDf6907EF726751087F9E0b6687")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fork_config() {
+        use crate::amm::uniswap_v3::factory::{UniswapV3Factory, UniswapV3Fork};
+
+        let uniswap_v3 = UniswapV3Fork::default();
+        assert_eq!(uniswap_v3.fees(), vec![100, 500, 3000, 10000]);
+        assert_eq!(uniswap_v3.tick_spacing(3000), Some(60));
+        assert_eq!(uniswap_v3.tick_spacing(2500), None);
+
+        let pancakeswap_v3 = UniswapV3Fork::PancakeSwapV3;
+        assert_eq!(pancakeswap_v3.fees(), vec![100, 500, 2500, 10000]);
+        assert_eq!(pancakeswap_v3.tick_spacing(2500), Some(50));
+        assert_eq!(pancakeswap_v3.tick_spacing(3000), None);
+
+        let factory = UniswapV3Factory::new_with_fork(H160::zero(), 0, pancakeswap_v3);
+        assert_eq!(factory.fee_tiers(), vec![100, 500, 2500, 10000]);
+        assert_eq!(factory.tick_spacing(500), Some(10));
+        let factory = UniswapV3Factory::new(H160::zero(), 0);
+        assert_eq!(factory.fee_tiers(), vec![100, 500, 3000, 10000]);
+        assert_eq!(factory.tick_spacing(500), Some(10));
+        assert_eq!(factory.tick_spacing(2500), None);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_swap_pancakeswap_v3() -> eyre::Result<()> {
+        use crate::amm::{
+            factory::{get_pools_for_pair, Factory},
+            uniswap_v3::factory::{UniswapV3Factory, UniswapV3Fork},
+        };
+
+        let rpc_endpoint = std::env::var("BSC_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let wbnb = H160::from_str("0xbb4CdB9CBd36B01bD1cBaEBF2De08d9173bc095c")?;
+        let usdt = H160::from_str("0x55d398326f99059fF775485246999027B3197955")?;
+
+        let pancakeswap_v3_factory = UniswapV3Factory::new_with_fork(
+            H160::from_str("0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865")?,
+            26956207,
+            UniswapV3Fork::PancakeSwapV3,
+        );
+        let factories = vec![Factory::UniswapV3Factory(pancakeswap_v3_factory)];
+
+        //The 0.25% tier is checked from the fee tiers of the fork
+        let pools = get_pools_for_pair(wbnb, usdt, &factories, middleware.clone()).await?;
+        let mut pool = pools
+            .into_iter()
+            .find_map(|amm| match amm {
+                AMM::UniswapV3Pool(pool) if pool.fee == 2500 => Some(pool),
+                _ => None,
+            })
+            .expect("Expected a 0.25% WBNB/USDT pool");
+        assert_eq!(
+            pool.address,
+            pancakeswap_v3_factory.compute_pool_address(wbnb, usdt, 2500)
+        );
+        assert_eq!(pool.tick_spacing, 50);
+
+        let synced_block = pool
+            .populate_tick_data(pancakeswap_v3_factory.creation_block, middleware.clone())
+            .await?;
+        pool.populate_data(Some(synced_block), middleware.clone())
+            .await?;
+
+        let quoter = IQuoterV2::new(
+            H160::from_str("0xB048Bbc1Ee6b733FFfCFb9e9CeF7375518e25997")?,
+            middleware.clone(),
+        );
+
+        //Large enough to cross ticks 50 apart
+        for amount_in in [U256::exp10(18), U256::exp10(22)] {
+            let amount_out = pool.simulate_swap(wbnb, amount_in)?;
+            let (expected_amount_out, _, _, _) = quoter
+                .quote_exact_input_single((wbnb, usdt, amount_in, 2500, U256::zero()))
+                .block(synced_block)
+                .call()
+                .await?;
+
+            assert_eq!(amount_out, expected_amount_out);
+        }
+
+        Ok(())
+    }
 }
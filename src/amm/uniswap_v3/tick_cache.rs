@@ -0,0 +1,250 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use ethers::types::U256;
+
+use super::Info;
+
+/// Read optimized copy of a pool's `ticks` and `tick_bitmap`, built on the first simulation after it is invalidated.
+///
+/// Mutations made through the pool keep the cache up to date. If `ticks` or `tick_bitmap` are written to directly,
+/// call `UniswapV3Pool::invalidate_tick_cache` afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct TickCache(OnceLock<TickTable>);
+
+#[derive(Debug, Clone)]
+pub(crate) struct TickTable {
+    //Sorted by tick
+    ticks: Vec<(i32, Info)>,
+    //Words of the tick bitmap from `min_word` up to the highest word loaded, missing words are zero
+    min_word: i16,
+    words: Vec<U256>,
+}
+
+impl TickCache {
+    pub(crate) fn get(
+        &self,
+        ticks: &HashMap<i32, Info>,
+        tick_bitmap: &HashMap<i16, U256>,
+    ) -> &TickTable {
+        self.0.get_or_init(|| TickTable::new(ticks, tick_bitmap))
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.0.get().is_some()
+    }
+
+    pub fn invalidate(&mut self) {
+        self.0 = OnceLock::new();
+    }
+
+    pub(crate) fn update_tick(&mut self, tick: i32, info: Option<&Info>) {
+        if let Some(table) = self.0.get_mut() {
+            match (
+                table.ticks.binary_search_by_key(&tick, |(tick, _)| *tick),
+                info,
+            ) {
+                (Ok(idx), Some(info)) => table.ticks[idx].1 = info.clone(),
+                (Ok(idx), None) => {
+                    table.ticks.remove(idx);
+                }
+                (Err(idx), Some(info)) => table.ticks.insert(idx, (tick, info.clone())),
+                (Err(_), None) => {}
+            }
+        }
+    }
+
+    pub(crate) fn update_word(&mut self, word_pos: i16, word: U256) {
+        if let Some(table) = self.0.get_mut() {
+            match table.word_mut(word_pos) {
+                Some(cached) => *cached = word,
+                //Words outside of the loaded window are rare, rebuilding is simpler than growing the window
+                None => self.invalidate(),
+            }
+        }
+    }
+}
+
+impl TickTable {
+    fn new(ticks: &HashMap<i32, Info>, tick_bitmap: &HashMap<i16, U256>) -> Self {
+        let mut sorted_ticks = ticks
+            .iter()
+            .map(|(tick, info)| (*tick, info.clone()))
+            .collect::<Vec<_>>();
+        sorted_ticks.sort_unstable_by_key(|(tick, _)| *tick);
+
+        let min_word = tick_bitmap.keys().min().copied().unwrap_or_default();
+        let max_word = tick_bitmap.keys().max().copied().unwrap_or_default();
+
+        let mut words = vec![U256::zero(); (max_word as i32 - min_word as i32 + 1) as usize];
+        for (word_pos, word) in tick_bitmap {
+            words[(*word_pos as i32 - min_word as i32) as usize] = *word;
+        }
+
+        TickTable {
+            ticks: sorted_ticks,
+            min_word,
+            words,
+        }
+    }
+
+    fn word(&self, word_pos: i16) -> U256 {
+        usize::try_from(word_pos as i32 - self.min_word as i32)
+            .ok()
+            .and_then(|idx| self.words.get(idx))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn word_mut(&mut self, word_pos: i16) -> Option<&mut U256> {
+        usize::try_from(word_pos as i32 - self.min_word as i32)
+            .ok()
+            .and_then(|idx| self.words.get_mut(idx))
+    }
+
+    pub(crate) fn liquidity_net(&self, tick: i32) -> i128 {
+        self.ticks
+            .binary_search_by_key(&tick, |(tick, _)| *tick)
+            .map_or(0, |idx| self.ticks[idx].1.liquidity_net)
+    }
+
+    /// Same as `uniswap_v3_math::tick_bitmap::next_initialized_tick_within_one_word`, reading words from the flat array
+    pub(crate) fn next_initialized_tick_within_one_word(
+        &self,
+        tick: i32,
+        tick_spacing: i32,
+        lte: bool,
+    ) -> (i32, bool) {
+        //Round toward negative infinity
+        let compressed = tick.div_euclid(tick_spacing);
+
+        if lte {
+            let (word_pos, bit_pos) = position(compressed);
+            let mask = (U256::one() << bit_pos) - U256::one() + (U256::one() << bit_pos);
+            let masked = self.word(word_pos) & mask;
+
+            if masked.is_zero() {
+                ((compressed - bit_pos as i32) * tick_spacing, false)
+            } else {
+                let most_significant_bit = masked.bits() as i32 - 1;
+                (
+                    (compressed - (bit_pos as i32 - most_significant_bit)) * tick_spacing,
+                    true,
+                )
+            }
+        } else {
+            let (word_pos, bit_pos) = position(compressed + 1);
+            let mask = !((U256::one() << bit_pos) - U256::one());
+            let masked = self.word(word_pos) & mask;
+
+            if masked.is_zero() {
+                (
+                    (compressed + 1 + (255 - bit_pos as i32)) * tick_spacing,
+                    false,
+                )
+            } else {
+                let least_significant_bit = masked.trailing_zeros() as i32;
+                (
+                    (compressed + 1 + (least_significant_bit - bit_pos as i32)) * tick_spacing,
+                    true,
+                )
+            }
+        }
+    }
+}
+
+fn position(compressed: i32) -> (i16, u8) {
+    ((compressed >> 8) as i16, (compressed & 0xff) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethers::{
+        core::rand::{thread_rng, Rng},
+        types::{H160, U256},
+    };
+
+    use crate::amm::{uniswap_v3::UniswapV3Pool, AmmState};
+
+    use super::TickCache;
+
+    #[test]
+    fn test_next_initialized_tick_matches_tick_bitmap() {
+        let mut rng = thread_rng();
+
+        for tick_spacing in [1, 10, 60, 200] {
+            let mut tick_bitmap = HashMap::new();
+            for word_pos in -4_i16..4 {
+                if rng.gen_bool(0.75) {
+                    tick_bitmap.insert(
+                        word_pos,
+                        U256(rng.gen::<[u64; 4]>()) >> rng.gen_range(0..256_usize),
+                    );
+                }
+            }
+
+            let cache = TickCache::default();
+            let table = cache.get(&HashMap::new(), &tick_bitmap);
+
+            for _ in 0..2000 {
+                let tick = rng.gen_range(-1300..1300) * tick_spacing / 4;
+                for lte in [true, false] {
+                    assert_eq!(
+                        table.next_initialized_tick_within_one_word(tick, tick_spacing, lte),
+                        uniswap_v3_math::tick_bitmap::next_initialized_tick_within_one_word(
+                            &tick_bitmap,
+                            tick,
+                            tick_spacing,
+                            lte
+                        )
+                        .unwrap(),
+                        "tick {tick}, spacing {tick_spacing}, lte {lte}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cache_follows_mutations() -> eyre::Result<()> {
+        let token_a = H160::from_low_u64_be(1);
+        let mut pool = UniswapV3Pool::builder()
+            .with_token_a(token_a, 18)
+            .with_token_b(H160::from_low_u64_be(2), 18)
+            .with_tick(30)
+            .with_position(-600, 600, 10_u128.pow(18))
+            .with_position(-120, 60, 10_u128.pow(17))
+            .build()?;
+
+        let amount_in = U256::exp10(17);
+        pool.simulate_swap(token_a, amount_in)?;
+        assert!(pool.tick_cache.is_built());
+
+        //Mints, burns and positions outside of the loaded bitmap words update the cache in place or rebuild it
+        pool.modify_position(-240, 0, 10_i128.pow(17));
+        pool.modify_position(-120, 60, -(10_i128.pow(17)));
+        pool.modify_position(-60000, 60000, 10_i128.pow(16));
+
+        let mut rebuilt = pool.clone();
+        rebuilt.invalidate_tick_cache();
+
+        for amount_in in [amount_in, U256::exp10(18), U256::exp10(20)] {
+            assert_eq!(
+                pool.simulate_swap(token_a, amount_in)?,
+                rebuilt.simulate_swap(token_a, amount_in)?
+            );
+            assert_eq!(
+                pool.simulate_swap(pool.token_b, amount_in)?,
+                rebuilt.simulate_swap(pool.token_b, amount_in)?
+            );
+        }
+
+        let table = pool.tick_cache.get(&pool.ticks, &pool.tick_bitmap);
+        assert_eq!(table.liquidity_net(-120), 0);
+        assert_eq!(table.liquidity_net(-240), 10_i128.pow(17));
+        assert_eq!(table.liquidity_net(0), pool.ticks[&0].liquidity_net);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+//! Compact serde for the tick maps of `UniswapV3Pool`.
+//!
+//! `ticks` are written as parallel arrays sorted by tick, with `initialized` packed into a bitset of u64 words,
+//! and `tick_bitmap` as `(word_pos, word)` pairs sorted by word position. Human readable formats also accept
+//! the map form written by earlier versions, so old checkpoints keep loading.
+
+use std::{collections::HashMap, fmt};
+
+use ethers::types::U256;
+use serde::{
+    de::{Error, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::Info;
+
+#[derive(Serialize, Deserialize)]
+struct CompactTicks {
+    ticks: Vec<i32>,
+    liquidity_net: Vec<i128>,
+    liquidity_gross: Vec<u128>,
+    initialized: Vec<u64>,
+}
+
+impl CompactTicks {
+    fn new(ticks: &HashMap<i32, Info>) -> Self {
+        let mut sorted = ticks.iter().collect::<Vec<_>>();
+        sorted.sort_unstable_by_key(|(tick, _)| **tick);
+
+        let mut initialized = vec![0_u64; sorted.len().div_ceil(64)];
+        for (i, (_, info)) in sorted.iter().enumerate() {
+            if info.initialized {
+                initialized[i / 64] |= 1 << (i % 64);
+            }
+        }
+
+        CompactTicks {
+            ticks: sorted.iter().map(|(tick, _)| **tick).collect(),
+            liquidity_net: sorted.iter().map(|(_, info)| info.liquidity_net).collect(),
+            liquidity_gross: sorted
+                .iter()
+                .map(|(_, info)| info.liquidity_gross)
+                .collect(),
+            initialized,
+        }
+    }
+
+    fn into_ticks<E: Error>(self) -> Result<HashMap<i32, Info>, E> {
+        let len = self.ticks.len();
+        if self.liquidity_net.len() != len
+            || self.liquidity_gross.len() != len
+            || self.initialized.len() != len.div_ceil(64)
+        {
+            return Err(E::custom("tick arrays have different lengths"));
+        }
+
+        Ok(self
+            .ticks
+            .into_iter()
+            .zip(self.liquidity_net)
+            .zip(self.liquidity_gross)
+            .enumerate()
+            .map(|(i, ((tick, liquidity_net), liquidity_gross))| {
+                let initialized = self.initialized[i / 64] & (1 << (i % 64)) != 0;
+                (tick, Info::new(liquidity_gross, liquidity_net, initialized))
+            })
+            .collect())
+    }
+}
+
+pub mod ticks {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        ticks: &HashMap<i32, Info>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        CompactTicks::new(ticks).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<i32, Info>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_map(TicksVisitor)
+        } else {
+            CompactTicks::deserialize(deserializer)?.into_ticks()
+        }
+    }
+
+    //Accepts both the compact form and the `{ "<tick>": Info }` map written by earlier versions
+    struct TicksVisitor;
+
+    impl<'de> Visitor<'de> for TicksVisitor {
+        type Value = HashMap<i32, Info>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("compact tick arrays or a map of ticks")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut ticks = None;
+            let mut liquidity_net = None;
+            let mut liquidity_gross = None;
+            let mut initialized = None;
+            let mut legacy = HashMap::new();
+
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "ticks" => ticks = Some(map.next_value()?),
+                    "liquidity_net" => liquidity_net = Some(map.next_value()?),
+                    "liquidity_gross" => liquidity_gross = Some(map.next_value()?),
+                    "initialized" => initialized = Some(map.next_value()?),
+                    tick => {
+                        let tick = tick.parse::<i32>().map_err(A::Error::custom)?;
+                        legacy.insert(tick, map.next_value::<Info>()?);
+                    }
+                }
+            }
+
+            match (ticks, liquidity_net, liquidity_gross, initialized) {
+                (None, None, None, None) => Ok(legacy),
+                (Some(ticks), Some(liquidity_net), Some(liquidity_gross), Some(initialized))
+                    if legacy.is_empty() =>
+                {
+                    CompactTicks {
+                        ticks,
+                        liquidity_net,
+                        liquidity_gross,
+                        initialized,
+                    }
+                    .into_ticks()
+                }
+                _ => Err(A::Error::custom("incomplete compact tick arrays")),
+            }
+        }
+    }
+}
+
+pub mod tick_bitmap {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        tick_bitmap: &HashMap<i16, U256>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut words = tick_bitmap.iter().collect::<Vec<_>>();
+        words.sort_unstable_by_key(|(word_pos, _)| **word_pos);
+
+        serializer.collect_seq(words)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<i16, U256>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(TickBitmapVisitor)
+        } else {
+            Ok(Vec::<(i16, U256)>::deserialize(deserializer)?
+                .into_iter()
+                .collect())
+        }
+    }
+
+    //Accepts both `(word_pos, word)` pairs and the `{ "<word_pos>": word }` map written by earlier versions
+    struct TickBitmapVisitor;
+
+    impl<'de> Visitor<'de> for TickBitmapVisitor {
+        type Value = HashMap<i16, U256>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a sequence of (word_pos, word) pairs or a map of words")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut tick_bitmap = HashMap::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some((word_pos, word)) = seq.next_element::<(i16, U256)>()? {
+                tick_bitmap.insert(word_pos, word);
+            }
+
+            Ok(tick_bitmap)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut tick_bitmap = HashMap::with_capacity(map.size_hint().unwrap_or_default());
+            while let Some((word_pos, word)) = map.next_entry::<String, U256>()? {
+                let word_pos = word_pos.parse::<i16>().map_err(A::Error::custom)?;
+                tick_bitmap.insert(word_pos, word);
+            }
+
+            Ok(tick_bitmap)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethers::types::U256;
+
+    use crate::amm::uniswap_v3::{Info, UniswapV3Pool};
+
+    const FIXTURE: &str = include_str!("../../../benches/fixtures/uniswap_v3_pool.json");
+
+    #[test]
+    fn test_round_trip() -> eyre::Result<()> {
+        let mut pool = UniswapV3Pool {
+            tick_spacing: 10,
+            ..Default::default()
+        };
+        //More than 64 ticks so the initialized bitset spans several words
+        for tick in -50..50 {
+            pool.ticks.insert(
+                tick * 10,
+                Info::new(
+                    tick.unsigned_abs() as u128,
+                    tick as i128 * 1000,
+                    tick % 3 != 0,
+                ),
+            );
+        }
+        pool.tick_bitmap.insert(-1, U256::MAX);
+        pool.tick_bitmap.insert(0, U256::from(0b1011));
+
+        let json: UniswapV3Pool = serde_json::from_str(&serde_json::to_string(&pool)?)?;
+        assert_eq!(json.ticks, pool.ticks);
+        assert_eq!(json.tick_bitmap, pool.tick_bitmap);
+
+        let empty: UniswapV3Pool =
+            serde_json::from_str(&serde_json::to_string(&UniswapV3Pool::default())?)?;
+        assert!(empty.ticks.is_empty() && empty.tick_bitmap.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_legacy_maps() -> eyre::Result<()> {
+        let legacy = serde_json::from_str::<serde_json::Value>(FIXTURE)?;
+        let pool: UniswapV3Pool = serde_json::from_value(legacy.clone())?;
+
+        let legacy_ticks: HashMap<i32, Info> = serde_json::from_value(legacy["ticks"].clone())?;
+        let legacy_tick_bitmap: HashMap<i16, U256> =
+            serde_json::from_value(legacy["tick_bitmap"].clone())?;
+        assert_eq!(pool.ticks, legacy_ticks);
+        assert_eq!(pool.tick_bitmap, legacy_tick_bitmap);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_size() -> eyre::Result<()> {
+        let pool: UniswapV3Pool = serde_json::from_str(FIXTURE)?;
+
+        let legacy_size = serde_json::to_string(&pool.ticks)?.len()
+            + serde_json::to_string(&pool.tick_bitmap)?.len();
+        let compact_size = serde_json::to_string(&pool)?.len();
+
+        assert!(
+            compact_size * 2 < legacy_size,
+            "compact pool is {compact_size} bytes, legacy tick maps are {legacy_size} bytes"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_malformed_ticks() {
+        for ticks in [
+            r#"{"ticks":[0,10],"liquidity_net":[1],"liquidity_gross":[1,1],"initialized":[3]}"#,
+            r#"{"ticks":[0,10],"liquidity_net":[1,1],"liquidity_gross":[1,1],"initialized":[]}"#,
+            r#"{"ticks":[0,10],"liquidity_net":[1,1],"liquidity_gross":[1,1]}"#,
+        ] {
+            let mut deserializer = serde_json::Deserializer::from_str(ticks);
+            assert!(
+                super::ticks::deserialize(&mut deserializer).is_err(),
+                "{ticks}"
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::{
+    providers::Middleware,
+    types::{H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AMM},
+    errors::AMMError,
+};
+
+use super::{UniswapV4Pool, NATIVE_DECIMALS, PIPS_DENOMINATOR};
+
+//extsload(bytes32), called on the PoolManager
+const EXTSLOAD_SELECTOR: [u8; 4] = [30, 46, 174, 175];
+
+/// Reads the price, liquidity and fees of `pool` from its PoolManager at `block_number`, erroring with
+/// `AMMError::BatchRequestError` if the pool is not initialized
+pub async fn get_v4_pool_data_batch_request<M: Middleware>(
+    pool: &mut UniswapV4Pool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[&*pool], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Uniswap V4 pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be
+/// read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms
+        .iter()
+        .filter_map(|amm| match amm {
+            AMM::UniswapV4Pool(pool) => Some(pool),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut pool_data = get_pool_data(&pools, block_number, middleware)
+        .await?
+        .into_iter();
+
+    for amm in amms.iter_mut() {
+        if let AMM::UniswapV4Pool(v4_pool) = amm {
+            let pool_data = pool_data.next().unwrap_or_default();
+            if let Some(pool) = populate_pool_data(v4_pool.to_owned(), pool_data) {
+                *v4_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    slot0: Option<U256>,
+    liquidity: Option<U256>,
+    decimals: (Option<u8>, Option<u8>),
+}
+
+fn populate_pool_data(mut pool: UniswapV4Pool, pool_data: PoolData) -> Option<UniswapV4Pool> {
+    //Pools that are not initialized have an empty slot0
+    let slot0 = pool_data.slot0.filter(|slot0| !slot0.is_zero())?;
+    let liquidity = pool_data.liquidity?;
+    if liquidity > U256::from(u128::MAX) {
+        return None;
+    }
+
+    //uint160 sqrtPriceX96, int24 tick, uint24 protocolFee, uint24 lpFee, packed from the low bits
+    let sqrt_price = slot0 & ((U256::one() << 160) - 1);
+    let tick = ((((slot0 >> 160).low_u32() & 0xffffff) << 8) as i32) >> 8;
+    let protocol_fee = (slot0 >> 184).low_u32() & 0xffffff;
+    let lp_fee = (slot0 >> 208).low_u32() & 0xffffff;
+    if lp_fee >= PIPS_DENOMINATOR {
+        return None;
+    }
+
+    pool.pool.set_slot0(sqrt_price, tick).ok()?;
+    pool.pool.set_liquidity(liquidity.as_u128()).ok()?;
+    pool.pool.fee = lp_fee;
+    pool.protocol_fee = protocol_fee;
+    (pool.pool.token_a_decimals, pool.pool.token_b_decimals) =
+        (pool_data.decimals.0?, pool_data.decimals.1?);
+    pool.pool.reset_decimal_scaling();
+
+    Some(pool)
+}
+
+//Reads the slot0 and liquidity of each pool from its PoolManager in one multicall, then the decimals of the
+//currencies of every pool
+async fn get_pool_data<M: Middleware>(
+    pools: &[&UniswapV4Pool],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| {
+            [pool.state_slot(), pool.liquidity_slot()]
+                .map(|slot| multicall::call(pool.pool_manager, EXTSLOAD_SELECTOR, slot.as_bytes()))
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let tokens = pools
+        .iter()
+        .flat_map(|pool| [pool.pool.token_a, pool.pool.token_b])
+        .filter(|token| !token.is_zero())
+        .collect::<Vec<H160>>();
+    let decimals = multicall::get_decimals(&tokens, block_number, middleware).await?;
+
+    Ok(pools
+        .iter()
+        .zip(return_data.chunks(2))
+        .map(|(pool, pool_return_data)| PoolData {
+            slot0: pool_return_data[0]
+                .as_ref()
+                .and_then(|return_data| multicall::word(return_data, 0)),
+            liquidity: pool_return_data[1]
+                .as_ref()
+                .and_then(|return_data| multicall::word(return_data, 0)),
+            decimals: (
+                currency_decimals(pool.pool.token_a, &decimals),
+                currency_decimals(pool.pool.token_b, &decimals),
+            ),
+        })
+        .collect())
+}
+
+//The native currency has no `decimals()`
+fn currency_decimals(currency: H160, decimals: &HashMap<H160, u8>) -> Option<u8> {
+    if currency.is_zero() {
+        Some(NATIVE_DECIMALS)
+    } else {
+        decimals.get(&currency).copied()
+    }
+}
@@ -0,0 +1,365 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, Filter, Log, H160, H256, U64},
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    amm::{
+        decode,
+        factory::{AutomatedMarketMakerFactory, TASK_LIMIT},
+        AutomatedMarketMaker, AMM,
+    },
+    errors::{AMMError, EventLogError},
+};
+
+use super::{batch_request, UniswapV4Pool, MODIFY_LIQUIDITY_EVENT_SIGNATURE};
+
+//Initialize(bytes32 indexed id, address indexed currency0, address indexed currency1, uint24 fee, int24 tickSpacing, address hooks, uint160 sqrtPriceX96, int24 tick)
+pub const INITIALIZE_EVENT_SIGNATURE: H256 = H256([
+    221, 70, 110, 103, 78, 165, 87, 245, 98, 149, 226, 208, 33, 138, 18, 94, 164, 180, 240, 246,
+    243, 48, 123, 149, 248, 94, 97, 16, 131, 141, 100, 56,
+]);
+
+/// Uniswap V4 PoolManager, discovering its pools from their `Initialize` logs. The PoolManager holds every pool
+/// of the chain, so it stands in for the factory of the pools.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UniswapV4Factory {
+    /// Address of the PoolManager
+    pub address: H160,
+    pub creation_block: u64,
+}
+
+impl UniswapV4Factory {
+    pub fn new(address: H160, creation_block: u64) -> UniswapV4Factory {
+        UniswapV4Factory {
+            address,
+            creation_block,
+        }
+    }
+
+    /// Gets every pool initialized up to `to_block` with its ticks synced from the `ModifyLiquidity` logs of the
+    /// PoolManager, scanning `step` blocks at a time
+    pub async fn get_all_pools_from_logs<M: 'static + Middleware>(
+        self,
+        to_block: u64,
+        step: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let mut from_block = self.creation_block;
+        let mut aggregated_amms: HashMap<H256, AMM> = HashMap::new();
+
+        tracing::info!(from_block, to_block, step, "getting all pools from logs");
+
+        let mut handles = vec![];
+
+        while from_block < to_block {
+            let middleware = middleware.clone();
+            let target_block = (from_block + step - 1).min(to_block);
+
+            //Pools are initialized and modified through the PoolManager, so one filter covers both
+            let filter = Filter::new()
+                .topic0(vec![
+                    INITIALIZE_EVENT_SIGNATURE,
+                    MODIFY_LIQUIDITY_EVENT_SIGNATURE,
+                ])
+                .address(self.address)
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            handles.push(tokio::spawn(async move {
+                let mut logs = middleware
+                    .get_logs(&filter)
+                    .await
+                    .map_err(AMMError::MiddlewareError)?;
+                logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+                Ok::<Vec<Log>, AMMError<M>>(logs)
+            }));
+
+            from_block += step;
+
+            //Here we are limiting the number of green threads that can be spun up to not have the node time out
+            if handles.len() == TASK_LIMIT {
+                self.sync_logs_from_handles(handles, &mut aggregated_amms)
+                    .await?;
+                handles = vec![];
+            }
+        }
+
+        self.sync_logs_from_handles(handles, &mut aggregated_amms)
+            .await?;
+
+        Ok(aggregated_amms.into_values().collect::<Vec<AMM>>())
+    }
+
+    //Handles resolve in the order their block ranges were spawned, so logs are applied in chronological order
+    async fn sync_logs_from_handles<M: Middleware>(
+        &self,
+        handles: Vec<JoinHandle<Result<Vec<Log>, AMMError<M>>>>,
+        aggregated_amms: &mut HashMap<H256, AMM>,
+    ) -> Result<(), AMMError<M>> {
+        for handle in handles {
+            for log in handle.await?? {
+                if log.block_number.is_none() {
+                    return Err(EventLogError::LogBlockNumberNotFound((&log).into()))?;
+                }
+
+                let event_signature = decode::event_signature(&log)?;
+                let pool_id = *log
+                    .topics
+                    .get(1)
+                    .ok_or_else(|| EventLogError::MissingTopic {
+                        index: 1,
+                        context: (&log).into(),
+                    })?;
+
+                if event_signature == INITIALIZE_EVENT_SIGNATURE {
+                    let new_pool = self.new_empty_amm_from_log(&log)?;
+                    aggregated_amms.insert(pool_id, new_pool);
+                } else if event_signature == MODIFY_LIQUIDITY_EVENT_SIGNATURE {
+                    if let Some(AMM::UniswapV4Pool(pool)) = aggregated_amms.get_mut(&pool_id) {
+                        pool.sync_from_modify_liquidity_log(&log)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads `(pool_id, pool)` from an `Initialize` log, with the price and tick the pool is initialized at
+pub fn decode_initialize_log(
+    pool_manager: H160,
+    log: &Log,
+) -> Result<(H256, UniswapV4Pool), EventLogError> {
+    if decode::event_signature(log)? != INITIALIZE_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    let pool_id = *log
+        .topics
+        .get(1)
+        .ok_or_else(|| EventLogError::MissingTopic {
+            index: 1,
+            context: log.into(),
+        })?;
+    let mut pool = UniswapV4Pool::new(
+        pool_manager,
+        decode::topic_address(log, 2)?,
+        0,
+        decode::topic_address(log, 3)?,
+        0,
+        decode::data_uint(log, 0, 24)?.low_u32(),
+        decode::data_int(log, 1, 24)?.low_i32(),
+        decode::data_address(log, 2)?,
+    );
+
+    //The id is the hash of the key, a log naming another id does not describe this key
+    if pool.pool_id != pool_id {
+        return Err(EventLogError::MalformedLog(log.into()));
+    }
+
+    let sqrt_price = decode::data_uint(log, 3, 160)?;
+    let tick = decode::data_int(log, 4, 24)?.low_i32();
+    pool.pool
+        .set_slot0(sqrt_price, tick)
+        .map_err(|_| EventLogError::MalformedLog(log.into()))?;
+
+    Ok((pool_id, pool))
+}
+
+#[async_trait]
+impl AutomatedMarketMakerFactory for UniswapV4Factory {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn creation_block(&self) -> u64 {
+        self.creation_block
+    }
+
+    fn amm_created_event_signature(&self) -> H256 {
+        INITIALIZE_EVENT_SIGNATURE
+    }
+
+    async fn new_amm_from_log<M: 'static + Middleware>(
+        &self,
+        log: Log,
+        middleware: Arc<M>,
+    ) -> Result<AMM, AMMError<M>> {
+        let mut amm = self.new_empty_amm_from_log(&log)?;
+        let block_number = log.block_number.map(|block_number| block_number.as_u64());
+        amm.populate_data(block_number, middleware).await?;
+
+        Ok(amm)
+    }
+
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        decode::check_address(log, self.address)?;
+        let creation_block = log.block_number.map(|block_number| block_number.as_u64());
+        let (_, mut pool) = decode_initialize_log(self.address, log)?;
+        pool.pool.creation_block = creation_block;
+
+        Ok(AMM::UniswapV4Pool(pool))
+    }
+
+    async fn get_all_amms<M: 'static + Middleware>(
+        &self,
+        to_block: Option<u64>,
+        middleware: Arc<M>,
+        step: u64,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        match to_block {
+            Some(block) => self.get_all_pools_from_logs(block, step, middleware).await,
+            None => Err(AMMError::BlockNumberNotFound),
+        }
+    }
+
+    async fn populate_amm_data<M: Middleware>(
+        &self,
+        amms: &mut [AMM],
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+        _step: u64,
+    ) -> Result<(), AMMError<M>> {
+        //Multicall3 chunks its calls itself
+        batch_request::get_amm_data_batch_request(amms, block_number, middleware).await
+    }
+}
+
+/// The pool of `pool_id` in the PoolManager as read at `block_number`, given its key from `initialize_log`
+pub async fn get_pool_from_initialize_log<M: Middleware>(
+    initialize_log: &Log,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<UniswapV4Pool, AMMError<M>> {
+    let (_, mut pool) = decode_initialize_log(initialize_log.address, initialize_log)?;
+    batch_request::get_v4_pool_data_batch_request(&mut pool, block_number, middleware).await?;
+
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use ethers::{
+        abi::Token,
+        providers::{Http, Middleware, Provider},
+        types::{Filter, Log, H160, H256, I256, U256, U64},
+    };
+
+    use crate::{
+        amm::{factory::AutomatedMarketMakerFactory, uniswap_v4::compute_pool_id, AmmState, AMM},
+        errors::EventLogError,
+    };
+
+    use super::{get_pool_from_initialize_log, UniswapV4Factory, INITIALIZE_EVENT_SIGNATURE};
+
+    fn initialize_log(
+        factory: &UniswapV4Factory,
+        pool_id: H256,
+        sqrt_price: U256,
+        tick: i32,
+    ) -> Log {
+        Log {
+            address: factory.address,
+            topics: vec![
+                INITIALIZE_EVENT_SIGNATURE,
+                pool_id,
+                H256::from(H160::zero()),
+                H256::from(H160::from_low_u64_be(2)),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(500)),
+                Token::Int(I256::from(10).into_raw()),
+                Token::Address(H160::zero()),
+                Token::Uint(sqrt_price),
+                Token::Int(I256::from(tick).into_raw()),
+            ])
+            .into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_new_empty_amm_from_log() -> eyre::Result<()> {
+        let factory = UniswapV4Factory::new(H160::from_low_u64_be(100), 0);
+        let pool_id = compute_pool_id(
+            H160::zero(),
+            H160::from_low_u64_be(2),
+            500,
+            10,
+            H160::zero(),
+        );
+        let sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(-200)?;
+
+        match factory
+            .new_empty_amm_from_log(&initialize_log(&factory, pool_id, sqrt_price, -200))?
+        {
+            AMM::UniswapV4Pool(pool) => {
+                assert_eq!(pool.pool_id(), pool_id);
+                assert_eq!(pool.log_address(), factory.address);
+                assert_eq!(pool.tokens(), vec![H160::zero(), H160::from_low_u64_be(2)]);
+                assert_eq!(
+                    (pool.key_fee, pool.pool.fee, pool.pool.tick_spacing),
+                    (500, 500, 10)
+                );
+                assert_eq!((pool.pool.sqrt_price, pool.pool.tick), (sqrt_price, -200));
+                assert_eq!(pool.pool.creation_block, Some(10));
+            }
+            _ => panic!("Expected a new Uniswap V4 pool"),
+        }
+
+        //An id that is not the hash of the key in the log is rejected
+        let mismatched_log = initialize_log(&factory, H256::from_low_u64_be(1), sqrt_price, -200);
+        assert!(matches!(
+            factory.new_empty_amm_from_log(&mismatched_log),
+            Err(EventLogError::MalformedLog(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_pool_from_initialize_log() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //The ETH/USDC 0.05% pool of the mainnet PoolManager
+        let pool_manager = H160::from_str("0x000000000004444c5dc75cB358380D2e3dE08A90")?;
+        let pool_id =
+            H256::from_str("0x21c67e77068de97969ba93d4aab21826d33ca12bb9f565d8496e8fda8a82ca27")?;
+
+        let logs = middleware
+            .get_logs(
+                &Filter::new()
+                    .address(pool_manager)
+                    .topic0(INITIALIZE_EVENT_SIGNATURE)
+                    .topic1(pool_id)
+                    .from_block(21688329),
+            )
+            .await?;
+        let pool = get_pool_from_initialize_log(&logs[0], None, middleware).await?;
+
+        assert!(pool.data_is_populated());
+        assert_eq!(
+            (pool.pool.token_a_decimals, pool.pool.token_b_decimals),
+            (18, 6)
+        );
+        assert_eq!(pool.pool.fee, 500);
+
+        //USDC per ETH
+        let price = pool.calculate_price(H160::zero())?;
+        assert!(price > 100.0 && price < 100_000.0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,739 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+#[cfg(feature = "rpc")]
+pub mod factory;
+
+#[cfg(feature = "rpc")]
+use std::{collections::BTreeMap, sync::Arc};
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+use ethers::{
+    abi::Token,
+    types::{Log, H160, H256, U256},
+    utils::keccak256,
+};
+#[cfg(feature = "rpc")]
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, Filter, U64},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, uniswap_v3::UniswapV3Pool, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    tokens::TokenStore,
+};
+#[cfg(feature = "rpc")]
+use crate::{
+    amm::{factory::TASK_LIMIT, uniswap_v3::POPULATE_TICK_DATA_STEP, AutomatedMarketMaker},
+    errors::AMMError,
+};
+
+//Swap(bytes32 indexed id, address indexed sender, int128 amount0, int128 amount1, uint160 sqrtPriceX96, uint128 liquidity, int24 tick, uint24 fee)
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    64, 233, 206, 203, 159, 95, 31, 28, 91, 156, 151, 222, 194, 145, 123, 126, 233, 46, 87, 186,
+    85, 99, 112, 141, 172, 169, 77, 216, 74, 215, 17, 47,
+]);
+//ModifyLiquidity(bytes32 indexed id, address indexed sender, int24 tickLower, int24 tickUpper, int256 liquidityDelta, bytes32 salt)
+pub const MODIFY_LIQUIDITY_EVENT_SIGNATURE: H256 = H256([
+    242, 8, 244, 145, 39, 130, 253, 37, 199, 241, 20, 202, 55, 35, 162, 213, 221, 111, 59, 204, 58,
+    200, 219, 90, 246, 59, 170, 133, 247, 17, 213, 236,
+]);
+//ProtocolFeeUpdated(bytes32 indexed id, uint24 protocolFee)
+pub const PROTOCOL_FEE_UPDATED_EVENT_SIGNATURE: H256 = H256([
+    233, 196, 37, 147, 231, 31, 132, 64, 59, 132, 53, 44, 209, 104, 214, 147, 226, 201, 252, 209,
+    253, 188, 195, 254, 178, 29, 146, 180, 62, 102, 150, 249,
+]);
+/// Events of the PoolManager naming the pool they apply to by its id in their first topic
+pub const POOL_MANAGER_EVENT_SIGNATURES: [H256; 3] = [
+    SWAP_EVENT_SIGNATURE,
+    MODIFY_LIQUIDITY_EVENT_SIGNATURE,
+    PROTOCOL_FEE_UPDATED_EVENT_SIGNATURE,
+];
+
+/// Fee of the pool key marking the LP fee as set by the hooks of the pool rather than by the key
+pub const DYNAMIC_FEE_FLAG: u32 = 0x800000;
+//Flags of the hooks the PoolManager calls on a swap, held in the low bits of the hooks address
+const BEFORE_SWAP_FLAG: u64 = 1 << 7;
+const BEFORE_SWAP_RETURNS_DELTA_FLAG: u64 = 1 << 3;
+const AFTER_SWAP_RETURNS_DELTA_FLAG: u64 = 1 << 2;
+//Denominator of the LP and protocol fees, i.e. a fee of 3000 is 0.3%
+const PIPS_DENOMINATOR: u32 = 1_000_000;
+//Slot of the `pools` mapping of the PoolManager, read through `extsload`
+const POOLS_SLOT: u64 = 6;
+//Offset of the liquidity in range from the slot0 of a pool in the `pools` mapping
+const LIQUIDITY_OFFSET: u64 = 3;
+/// Decimals of the native currency, the currency at the zero address
+pub const NATIVE_DECIMALS: u8 = 18;
+
+/// Uniswap V4 pool, held by the singleton PoolManager with every other pool of the chain.
+///
+/// The PoolManager emits the logs of every pool and names each pool by its id, the hash of its key, so the pool
+/// syncs from the logs of `pool_manager` carrying its `pool_id`. As the pools share the PoolManager, `address()` is
+/// the first 20 bytes of the pool id, which keys the pool in the state space, and `log_address()` is the
+/// PoolManager. The concentrated liquidity of the pool follows Uniswap V3 and is held in `pool`. Swaps are
+/// simulated with the V3 math, pools whose hooks can change the amounts or the fee of a swap are not simulated.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UniswapV4Pool {
+    pub pool_manager: H160,
+    /// Id of the pool in the PoolManager, the hash of its key
+    pub pool_id: H256,
+    /// Fee of the pool key over 1e6, `DYNAMIC_FEE_FLAG` for pools whose LP fee is set by their hooks
+    pub key_fee: u32,
+    pub hooks: H160,
+    /// Protocol fee of swaps of token a for token b in the low 12 bits and of token b for token a in the next 12,
+    /// each over 1e6
+    pub protocol_fee: u32,
+    /// Liquidity, price and ticks of the pool with its LP fee as `fee`, keyed by `address()`. The native currency
+    /// is token a at the zero address.
+    pub pool: UniswapV3Pool,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+impl AmmState for UniswapV4Pool {
+    fn address(&self) -> H160 {
+        self.pool.address
+    }
+
+    fn log_address(&self) -> H160 {
+        self.pool_manager
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        self.pool.tokens()
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        self.pool.token_symbol(token)
+    }
+
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        self.pool.calculate_price(base_token)
+    }
+
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        POOL_MANAGER_EVENT_SIGNATURES.to_vec()
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.pool_manager)?;
+        let event_signature = decode::event_signature(log)?;
+        decode::check_pool_id(log, self.pool_id)?;
+
+        if event_signature == SWAP_EVENT_SIGNATURE {
+            self.sync_from_swap_log(log)
+        } else if event_signature == MODIFY_LIQUIDITY_EVENT_SIGNATURE {
+            self.sync_from_modify_liquidity_log(log)
+        } else if event_signature == PROTOCOL_FEE_UPDATED_EVENT_SIGNATURE {
+            self.protocol_fee = decode::data_uint(log, 0, 24)?.low_u32();
+            Ok(())
+        } else {
+            Err(EventLogError::UnexpectedEvent(log.into()))
+        }
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::UniswapV4.name(),
+            &[
+                U256::from_big_endian(self.pool.state_fingerprint().as_bytes()),
+                U256::from(self.pool.fee),
+                U256::from(self.protocol_fee),
+            ],
+        )
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        let swap_fee = self.check_swap(token_in)?;
+        if amount_in.is_zero() {
+            return Ok(U256::zero());
+        }
+
+        let current_state = self
+            .pool
+            .simulate_swap_state(token_in, amount_in, swap_fee)?;
+
+        Ok((-current_state.amount_calculated).into_raw())
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        let swap_fee = self.check_swap(token_in)?;
+        if amount_in.is_zero() {
+            return Ok(U256::zero());
+        }
+
+        let current_state = self
+            .pool
+            .simulate_swap_state(token_in, amount_in, swap_fee)?;
+
+        self.pool.liquidity = current_state.liquidity;
+        self.pool.sqrt_price = current_state.sqrt_price_x_96;
+        self.pool.tick = current_state.tick;
+
+        Ok((-current_state.amount_calculated).into_raw())
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        self.pool.get_token_out(token_in)
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        self.pool.opp_token(token)
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for UniswapV4Pool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_v4_pool_data_batch_request(self, None, middleware).await
+    }
+
+    //The key of the pool is only known from its Initialize log, so the pool id and tokens must already be set.
+    //Ticks are not read, see `populate_tick_data`.
+    #[tracing::instrument(level = "debug", skip_all, fields(pool_id = ?self.pool_id), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_v4_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl UniswapV4Pool {
+    /// Pool of the key `(currency_0, currency_1, fee, tick_spacing, hooks)` in `pool_manager`, before its price and
+    /// liquidity are set
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pool_manager: H160,
+        currency_0: H160,
+        currency_0_decimals: u8,
+        currency_1: H160,
+        currency_1_decimals: u8,
+        fee: u32,
+        tick_spacing: i32,
+        hooks: H160,
+    ) -> UniswapV4Pool {
+        let pool_id = compute_pool_id(currency_0, currency_1, fee, tick_spacing, hooks);
+        let mut pool = UniswapV3Pool {
+            address: pool_address(pool_id),
+            token_a: currency_0,
+            token_a_decimals: currency_0_decimals,
+            token_b: currency_1,
+            token_b_decimals: currency_1_decimals,
+            //Dynamic fee pools start with an LP fee of zero until their hooks set it
+            fee: if fee == DYNAMIC_FEE_FLAG { 0 } else { fee },
+            tick_spacing,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        };
+        pool.reset_decimal_scaling();
+
+        UniswapV4Pool {
+            pool_manager,
+            pool_id,
+            key_fee: fee,
+            hooks,
+            protocol_fee: 0,
+            pool,
+            serde_version: SerdeVersion::CURRENT,
+        }
+    }
+
+    /// Id of the pool in the PoolManager, the `PoolId` of its key
+    pub fn pool_id(&self) -> H256 {
+        self.pool_id
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.pool_id.is_zero()
+            || self.pool_manager.is_zero()
+            || self.pool.token_b.is_zero()
+            || self.pool.sqrt_price.is_zero())
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.pool.token_a {
+            Some(self.pool.token_a_decimals)
+        } else if token == self.pool.token_b {
+            Some(self.pool.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of the tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        self.pool.set_token_metadata(tokens);
+    }
+
+    /// Whether the LP fee of the pool is set by its hooks
+    pub fn is_dynamic_fee(&self) -> bool {
+        self.key_fee == DYNAMIC_FEE_FLAG
+    }
+
+    /// Whether the hooks of the pool can change the amounts of a swap or override its fee, in which case swaps are
+    /// not simulated
+    pub fn hooks_alter_swaps(&self) -> bool {
+        let flags = self.hooks.to_low_u64_be();
+
+        flags & (BEFORE_SWAP_RETURNS_DELTA_FLAG | AFTER_SWAP_RETURNS_DELTA_FLAG) != 0
+            || (self.is_dynamic_fee() && flags & BEFORE_SWAP_FLAG != 0)
+    }
+
+    /// Fee over 1e6 charged on swaps in the direction of `zero_for_one`, the LP fee with the protocol fee of the
+    /// direction taken first, as in `ProtocolFeeLibrary.calculateSwapFee`
+    pub fn swap_fee(&self, zero_for_one: bool) -> u32 {
+        let protocol_fee = self.direction_protocol_fee(zero_for_one);
+        if protocol_fee == 0 {
+            return self.pool.fee;
+        }
+
+        let lp_fee = self.pool.fee;
+        protocol_fee + lp_fee
+            - (protocol_fee as u64 * lp_fee as u64 / PIPS_DENOMINATOR as u64) as u32
+    }
+
+    /// Slot of the state of the pool in the `pools` mapping of the PoolManager, starting with its slot0
+    pub fn state_slot(&self) -> H256 {
+        H256(keccak256(ethers::abi::encode(&[
+            Token::FixedBytes(self.pool_id.as_bytes().to_vec()),
+            Token::Uint(U256::from(POOLS_SLOT)),
+        ])))
+    }
+
+    /// Slot of the liquidity in range of the pool in the PoolManager
+    pub fn liquidity_slot(&self) -> H256 {
+        let slot = U256::from_big_endian(self.state_slot().as_bytes()) + LIQUIDITY_OFFSET;
+        let mut word = [0_u8; 32];
+        slot.to_big_endian(&mut word);
+
+        H256(word)
+    }
+
+    /// Sets the price, the tick, the liquidity in range and the LP fee of dynamic fee pools from a `Swap` log
+    pub fn sync_from_swap_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let zero_for_one = decode::data_int(log, 0, 128)?.is_negative();
+        let fee = decode::data_uint(log, 5, 24)?.low_u32();
+
+        //The price, liquidity and tick follow the amounts as in the Swap of V3
+        self.pool.sync_from_swap_log(log)?;
+
+        //The log carries the swap fee, which is the LP fee unless the protocol takes a share of the direction
+        if self.is_dynamic_fee()
+            && self.direction_protocol_fee(zero_for_one) == 0
+            && fee < PIPS_DENOMINATOR
+        {
+            self.pool.fee = fee;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the liquidity delta of a `ModifyLiquidity` log to the ticks of the position
+    pub fn sync_from_modify_liquidity_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        let tick_lower = decode::data_int(log, 0, 24)?.low_i32();
+        let tick_upper = decode::data_int(log, 1, 24)?.low_i32();
+        let liquidity_delta = decode::data_int(log, 2, 128)?.low_i128();
+
+        self.pool
+            .checked_modify_position(log, tick_lower, tick_upper, liquidity_delta)
+    }
+
+    /// Syncs the ticks of the pool from the `ModifyLiquidity` logs of the PoolManager naming it, from `from_block` to
+    /// the current block, returning the current block
+    #[cfg(feature = "rpc")]
+    pub async fn populate_tick_data<M: 'static + Middleware>(
+        &mut self,
+        mut from_block: u64,
+        middleware: Arc<M>,
+    ) -> Result<u64, AMMError<M>> {
+        let current_block = middleware
+            .get_block_number()
+            .await
+            .map_err(AMMError::MiddlewareError)?
+            .as_u64();
+
+        let mut ordered_logs: BTreeMap<(U64, U256), Log> = BTreeMap::new();
+        let mut handles = vec![];
+
+        while from_block < current_block {
+            let middleware = middleware.clone();
+            let target_block = (from_block + POPULATE_TICK_DATA_STEP - 1).min(current_block);
+
+            let filter = Filter::new()
+                .topic0(MODIFY_LIQUIDITY_EVENT_SIGNATURE)
+                .topic1(self.pool_id)
+                .address(self.pool_manager)
+                .from_block(BlockNumber::Number(U64([from_block])))
+                .to_block(BlockNumber::Number(U64([target_block])));
+
+            handles.push(tokio::spawn(async move {
+                middleware
+                    .get_logs(&filter)
+                    .await
+                    .map_err(AMMError::MiddlewareError)
+            }));
+
+            from_block += POPULATE_TICK_DATA_STEP;
+            //Here we are limiting the number of green threads that can be spun up to not have the node time out
+            if handles.len() == TASK_LIMIT {
+                for handle in handles.drain(..) {
+                    order_logs(handle.await??, &mut ordered_logs)?;
+                }
+            }
+        }
+
+        for handle in handles {
+            order_logs(handle.await??, &mut ordered_logs)?;
+        }
+
+        for log in ordered_logs.into_values() {
+            self.sync_from_modify_liquidity_log(&log)?;
+        }
+
+        Ok(current_block)
+    }
+
+    //Protocol fee of swaps in the direction of `zero_for_one`, 12 bits each in `protocol_fee`
+    fn direction_protocol_fee(&self, zero_for_one: bool) -> u32 {
+        if zero_for_one {
+            self.protocol_fee & 0xfff
+        } else {
+            (self.protocol_fee >> 12) & 0xfff
+        }
+    }
+
+    //The swap fee of the direction of `token_in`, if the pool holds it and its hooks leave swaps to the V3 math
+    fn check_swap(&self, token_in: H160) -> Result<u32, SwapSimulationError> {
+        if self.opp_token(token_in).is_none() {
+            return Err(SwapSimulationError::TokenNotInPool(token_in));
+        }
+        if self.hooks_alter_swaps() {
+            return Err(SwapSimulationError::UnsupportedHooks(self.hooks));
+        }
+
+        Ok(self.swap_fee(token_in == self.pool.token_a))
+    }
+}
+
+/// `PoolId` of a pool key, the hash of its ABI encoding
+pub fn compute_pool_id(
+    currency_0: H160,
+    currency_1: H160,
+    fee: u32,
+    tick_spacing: i32,
+    hooks: H160,
+) -> H256 {
+    H256(keccak256(ethers::abi::encode(&[
+        Token::Address(currency_0),
+        Token::Address(currency_1),
+        Token::Uint(U256::from(fee)),
+        Token::Int(ethers::types::I256::from(tick_spacing).into_raw()),
+        Token::Address(hooks),
+    ])))
+}
+
+/// Address keying the pool of `pool_id`, its first 20 bytes
+pub fn pool_address(pool_id: H256) -> H160 {
+    H160::from_slice(&pool_id[..20])
+}
+
+//Logs are applied in the order they were emitted, whichever range they were fetched in
+#[cfg(feature = "rpc")]
+fn order_logs(
+    logs: Vec<Log>,
+    ordered_logs: &mut BTreeMap<(U64, U256), Log>,
+) -> Result<(), EventLogError> {
+    for log in logs {
+        match (log.block_number, log.log_index) {
+            (Some(block_number), Some(log_index)) => {
+                ordered_logs.insert((block_number, log_index), log);
+            }
+            _ => return Err(EventLogError::LogBlockNumberNotFound((&log).into())),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, H256, I256, U256},
+    };
+
+    use crate::{
+        amm::{self, uniswap_v3::UniswapV3Pool, AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{
+        compute_pool_id, pool_address, UniswapV4Pool, DYNAMIC_FEE_FLAG,
+        MODIFY_LIQUIDITY_EVENT_SIGNATURE, PROTOCOL_FEE_UPDATED_EVENT_SIGNATURE,
+        SWAP_EVENT_SIGNATURE,
+    };
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    //Price of 1 with the liquidity spread over the whole bitmap window, as `single_range_pool` of V3
+    fn single_range_pool(fee: u32, hooks: H160) -> UniswapV4Pool {
+        let mut pool = UniswapV4Pool::new(token(100), token(1), 18, token(2), 18, fee, 60, hooks);
+        pool.pool
+            .set_liquidity(10_u128.pow(24))
+            .expect("valid liquidity");
+        pool.pool
+            .set_slot0(
+                uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(0).expect("valid tick"),
+                0,
+            )
+            .expect("valid slot0");
+
+        pool
+    }
+
+    fn v3_pool(fee: u32) -> UniswapV3Pool {
+        UniswapV3Pool::builder()
+            .with_token_a(token(1), 18)
+            .with_token_b(token(2), 18)
+            .with_liquidity(10_u128.pow(24))
+            .with_tick(0)
+            .with_fee(fee)
+            .with_tick_spacing(60)
+            .build()
+            .expect("valid pool")
+    }
+
+    fn swap_log(
+        pool: &UniswapV4Pool,
+        amount_0: i128,
+        sqrt_price: U256,
+        tick: i32,
+        fee: u32,
+    ) -> Log {
+        Log {
+            address: pool.pool_manager,
+            topics: vec![SWAP_EVENT_SIGNATURE, pool.pool_id, H256::from(token(7))],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(amount_0).into_raw()),
+                Token::Int(I256::from(-amount_0).into_raw()),
+                Token::Uint(sqrt_price),
+                Token::Uint(U256::from(10_u128.pow(23))),
+                Token::Int(I256::from(tick).into_raw()),
+                Token::Uint(U256::from(fee)),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    fn modify_liquidity_log(
+        pool: &UniswapV4Pool,
+        tick_lower: i32,
+        tick_upper: i32,
+        delta: i128,
+    ) -> Log {
+        Log {
+            address: pool.pool_manager,
+            topics: vec![
+                MODIFY_LIQUIDITY_EVENT_SIGNATURE,
+                pool.pool_id,
+                H256::from(token(7)),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Int(I256::from(tick_lower).into_raw()),
+                Token::Int(I256::from(tick_upper).into_raw()),
+                Token::Int(I256::from(delta).into_raw()),
+                Token::FixedBytes(vec![0; 32]),
+            ])
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compute_pool_id() -> eyre::Result<()> {
+        //The ETH/USDC 0.05% pool of mainnet
+        let pool_id = compute_pool_id(
+            H160::zero(),
+            H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")?,
+            500,
+            10,
+            H160::zero(),
+        );
+        assert_eq!(
+            pool_id,
+            H256::from_str("0x21c67e77068de97969ba93d4aab21826d33ca12bb9f565d8496e8fda8a82ca27")?
+        );
+        assert_eq!(pool_address(pool_id), H160::from_slice(&pool_id[..20]));
+
+        let pool = single_range_pool(3000, H160::zero());
+        assert_eq!(pool.address(), pool_address(pool.pool_id()));
+        assert_eq!(pool.log_address(), token(100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        let amount_in = U256::exp10(21);
+
+        //Without a protocol fee, pools swap as V3 pools of the same fee
+        let mut pool = single_range_pool(3000, H160::zero());
+        for token_in in [token(1), token(2)] {
+            assert_eq!(
+                pool.simulate_swap(token_in, amount_in)?,
+                v3_pool(3000).simulate_swap(token_in, amount_in)?
+            );
+        }
+
+        //The protocol fee of each direction is taken first
+        pool.protocol_fee = 1000 | (500 << 12);
+        assert_eq!((pool.swap_fee(true), pool.swap_fee(false)), (3997, 3499));
+        assert_eq!(
+            pool.simulate_swap(token(1), amount_in)?,
+            v3_pool(3997).simulate_swap(token(1), amount_in)?
+        );
+        assert_eq!(
+            pool.simulate_swap(token(2), amount_in)?,
+            v3_pool(3499).simulate_swap(token(2), amount_in)?
+        );
+
+        assert_eq!(pool.simulate_swap(token(1), U256::zero())?, U256::zero());
+        assert!(matches!(
+            pool.simulate_swap(token(3), amount_in),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks_alter_swaps() -> eyre::Result<()> {
+        //Hooks only called after the swap leave its amounts alone
+        let pool = single_range_pool(3000, H160::from_low_u64_be(1 << 6));
+        assert!(!pool.hooks_alter_swaps());
+        assert!(pool.simulate_swap(token(1), U256::exp10(18)).is_ok());
+
+        //Hooks returning deltas change the amounts of the swap
+        for hooks in [1 << 3, 1 << 2] {
+            let hooks = H160::from_low_u64_be(hooks);
+            let mut pool = single_range_pool(3000, hooks);
+            assert!(matches!(
+                pool.simulate_swap_mut(token(1), U256::exp10(18)),
+                Err(SwapSimulationError::UnsupportedHooks(unsupported)) if unsupported == hooks
+            ));
+        }
+
+        //Hooks called before the swap of a dynamic fee pool can override its fee, but not of a static fee pool
+        let hooks = H160::from_low_u64_be(1 << 7);
+        assert!(!single_range_pool(3000, hooks).hooks_alter_swaps());
+        assert!(single_range_pool(DYNAMIC_FEE_FLAG, hooks).hooks_alter_swaps());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_mut() -> eyre::Result<()> {
+        let mut pool = single_range_pool(3000, H160::zero());
+        let mut v3_pool = v3_pool(3000);
+
+        let amount_out = pool.simulate_swap_mut(token(1), U256::exp10(21))?;
+        assert_eq!(
+            amount_out,
+            v3_pool.simulate_swap_mut(token(1), U256::exp10(21))?
+        );
+        assert_eq!(
+            (pool.pool.sqrt_price, pool.pool.tick, pool.pool.liquidity),
+            (v3_pool.sqrt_price, v3_pool.tick, v3_pool.liquidity)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = single_range_pool(3000, H160::zero());
+        let mut amm = AMM::UniswapV4Pool(pool.clone());
+
+        let sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(-600)?;
+        let log = swap_log(&pool, 1000, sqrt_price, -600, 3000);
+        assert_eq!(amm::log_amm_address(&log), pool.address());
+        pool.sync_from_log(&log)?;
+        amm.sync_from_log(&log)?;
+        assert_eq!(
+            (pool.pool.sqrt_price, pool.pool.tick, pool.pool.liquidity),
+            (sqrt_price, -600, 10_u128.pow(23))
+        );
+        assert!(AMM::UniswapV4Pool(pool.clone()).state_eq(&amm));
+
+        let log = modify_liquidity_log(&pool, -1200, 1200, 10_i128.pow(20));
+        pool.sync_from_log(&log)?;
+        assert_eq!(pool.pool.ticks[&-1200].liquidity_net, 10_i128.pow(20));
+        assert_eq!(pool.pool.ticks[&1200].liquidity_net, -(10_i128.pow(20)));
+        assert_eq!(pool.pool.liquidity, 10_u128.pow(23) + 10_u128.pow(20));
+
+        let protocol_fee_log = Log {
+            address: pool.pool_manager,
+            topics: vec![PROTOCOL_FEE_UPDATED_EVENT_SIGNATURE, pool.pool_id],
+            data: ethers::abi::encode(&[Token::Uint(U256::from(1000 | (1000 << 12)))]).into(),
+            ..Default::default()
+        };
+        pool.sync_from_log(&protocol_fee_log)?;
+        assert_eq!(pool.swap_fee(true), 3997);
+
+        //Logs of other pools and of other contracts are rejected
+        let mut other_pool_log = log.clone();
+        other_pool_log.topics[1] = H256::from_low_u64_be(1);
+        assert!(matches!(
+            pool.sync_from_log(&other_pool_log),
+            Err(EventLogError::PoolIdMismatch { .. })
+        ));
+
+        let mut foreign_log = log;
+        foreign_log.address = token(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_dynamic_fee_from_swap_log() -> eyre::Result<()> {
+        let mut pool = single_range_pool(DYNAMIC_FEE_FLAG, H160::zero());
+        assert_eq!(pool.pool.fee, 0);
+
+        let sqrt_price = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(60)?;
+        pool.sync_from_log(&swap_log(&pool, -1000, sqrt_price, 60, 2500))?;
+        assert_eq!(pool.pool.fee, 2500);
+
+        //With a protocol fee the log carries the swap fee, not the LP fee
+        pool.protocol_fee = 1000;
+        pool.sync_from_log(&swap_log(&pool, -1000, sqrt_price, 60, 3497))?;
+        assert_eq!(pool.pool.fee, 2500);
+
+        //Static fee pools keep the fee of their key
+        let mut pool = single_range_pool(3000, H160::zero());
+        pool.sync_from_log(&swap_log(&pool, 1000, sqrt_price, 60, 2500))?;
+        assert_eq!(pool.pool.fee, 3000);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{multicall, AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::{VelodromePool, FEE_DENOMINATOR};
+
+//metadata()
+const METADATA_SELECTOR: [u8; 4] = [57, 47, 55, 233];
+//factory()
+const FACTORY_SELECTOR: [u8; 4] = [196, 90, 1, 85];
+//getFee(address,bool), called on the factory
+const GET_FEE_SELECTOR: [u8; 4] = [204, 86, 178, 197];
+
+/// Reads `pool` at `block_number`, erroring with `AMMError::BatchRequestError` if it is not a Velodrome pool
+pub async fn get_velodrome_pool_data_batch_request<M: Middleware>(
+    pool: &mut VelodromePool,
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pool_data = get_pool_data(&[pool.address], block_number, middleware).await?;
+
+    *pool = pool_data
+        .into_iter()
+        .next()
+        .and_then(|pool_data| populate_pool_data(pool.to_owned(), pool_data))
+        .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+    Ok(())
+}
+
+/// Reads every Velodrome pool of `amms` at `block_number` through Multicall3, leaving the pools that could not be
+/// read as they were
+#[tracing::instrument(level = "debug", skip(amms, middleware), fields(batch_size = amms.len()), err(Debug))]
+pub async fn get_amm_data_batch_request<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let pools = amms.iter().map(|amm| amm.address()).collect::<Vec<H160>>();
+    let pool_data = get_pool_data(&pools, block_number, middleware).await?;
+
+    for (amm, pool_data) in amms.iter_mut().zip(pool_data) {
+        if let AMM::VelodromePool(velodrome_pool) = amm {
+            if let Some(pool) = populate_pool_data(velodrome_pool.to_owned(), pool_data) {
+                *velodrome_pool = pool;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Fields of a pool as read through Multicall3, None where a read failed
+#[derive(Default)]
+struct PoolData {
+    metadata: Option<Metadata>,
+    factory: Option<H160>,
+    fee: Option<u32>,
+}
+
+//dec0, dec1, r0, r1, st, t0, t1 as returned by `metadata()`, with the scales of the tokens turned into decimals
+struct Metadata {
+    decimals: (u8, u8),
+    reserves: (U256, U256),
+    stable: bool,
+    tokens: (H160, H160),
+}
+
+fn populate_pool_data(mut pool: VelodromePool, pool_data: PoolData) -> Option<VelodromePool> {
+    let metadata = pool_data.metadata?;
+
+    (pool.token_a, pool.token_b) = metadata.tokens;
+    (pool.token_a_decimals, pool.token_b_decimals) = metadata.decimals;
+    (pool.reserve_0, pool.reserve_1) = metadata.reserves;
+    pool.stable = metadata.stable;
+
+    match pool_data.fee {
+        Some(fee) => pool.fee = fee,
+        None => {
+            tracing::warn!(pool = ?pool.address, factory = ?pool_data.factory, "could not get pool fee")
+        }
+    }
+
+    Some(pool)
+}
+
+//Reads the metadata and factory of each pool, then the fee of every pool from its factory, in one multicall each
+async fn get_pool_data<M: Middleware>(
+    pools: &[H160],
+    block_number: Option<u64>,
+    middleware: Arc<M>,
+) -> Result<Vec<PoolData>, AMMError<M>> {
+    let calls = pools
+        .iter()
+        .flat_map(|pool| {
+            [METADATA_SELECTOR, FACTORY_SELECTOR]
+                .map(|selector| multicall::call(*pool, selector, &[]))
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware.clone()).await?;
+
+    let mut pool_data = return_data
+        .chunks(2)
+        .map(|pool_return_data| PoolData {
+            metadata: pool_return_data[0].as_ref().and_then(decode_metadata),
+            factory: pool_return_data[1]
+                .as_ref()
+                .and_then(|return_data| multicall::address_word(return_data, 0)),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    //Only pools that returned their metadata and factory are looked up in their factory
+    let fee_reads = pool_data
+        .iter()
+        .enumerate()
+        .filter_map(|(i, data)| Some((i, data.factory?, data.metadata.as_ref()?.stable)))
+        .collect::<Vec<_>>();
+    let calls = fee_reads
+        .iter()
+        .map(|(i, factory, stable)| {
+            multicall::call(
+                *factory,
+                GET_FEE_SELECTOR,
+                &ethers::abi::encode(&[Token::Address(pools[*i]), Token::Bool(*stable)]),
+            )
+        })
+        .collect();
+    let return_data = multicall::aggregate(calls, block_number, middleware).await?;
+
+    for ((i, _, _), return_data) in fee_reads.iter().zip(return_data) {
+        pool_data[*i].fee = return_data
+            .as_ref()
+            .and_then(|return_data| multicall::word(return_data, 0))
+            .filter(|fee| *fee <= U256::from(FEE_DENOMINATOR))
+            .map(|fee| fee.as_u32());
+    }
+
+    Ok(pool_data)
+}
+
+fn decode_metadata(return_data: &Bytes) -> Option<Metadata> {
+    let stable = multicall::word(return_data, 4)?;
+    if stable > U256::one() {
+        return None;
+    }
+
+    Some(Metadata {
+        decimals: (
+            decimals_of_scale(multicall::word(return_data, 0)?)?,
+            decimals_of_scale(multicall::word(return_data, 1)?)?,
+        ),
+        reserves: (
+            multicall::word(return_data, 2)?,
+            multicall::word(return_data, 3)?,
+        ),
+        stable: stable == U256::one(),
+        tokens: (
+            multicall::address_word(return_data, 5)?,
+            multicall::address_word(return_data, 6)?,
+        ),
+    })
+}
+
+//Pools store `10 ** decimals` of their tokens
+fn decimals_of_scale(scale: U256) -> Option<u8> {
+    (0..=38_u8).find(|decimals| U256::exp10(*decimals as usize) == scale)
+}
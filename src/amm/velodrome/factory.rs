@@ -0,0 +1,385 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ethers::{
+    abi::Token,
+    prelude::abigen,
+    providers::Middleware,
+    types::{Log, H160, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{decode, factory::AutomatedMarketMakerFactory, multicall, AutomatedMarketMaker, AMM},
+    errors::{AMMError, EventLogError},
+    sync,
+};
+
+use super::{batch_request, VelodromePool};
+
+//Number of pools populated by each multicall when paging through the factory
+const GET_POOL_DATA_STEP: usize = 500;
+
+abigen!(
+    IVelodromeFactory,
+    r#"[
+        function allPoolsLength() external view returns (uint256)
+        function allPools(uint256 index) external view returns (address)
+        function getPool(address tokenA, address tokenB, bool stable) external view returns (address)
+        function getFee(address pool, bool stable) external view returns (uint256)
+    ]"#;
+);
+
+//PoolCreated(address indexed token0, address indexed token1, bool indexed stable, address pool, uint256)
+pub const POOL_CREATED_EVENT_SIGNATURE: H256 = H256([
+    33, 40, 216, 141, 20, 200, 12, 176, 129, 193, 37, 42, 90, 207, 247, 162, 100, 103, 27, 241,
+    153, 206, 34, 107, 83, 120, 143, 178, 96, 101, 0, 94,
+]);
+
+//allPools(uint256)
+const ALL_POOLS_SELECTOR: [u8; 4] = [65, 209, 222, 151];
+
+/// Velodrome V2 pool factory, such as the Aerodrome factory on Base. The factory lists its pools in `allPools`,
+/// the `allPairs` of Uniswap V2 and Velodrome V1, and sets the fee of each pool through `getFee`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VelodromeFactory {
+    pub address: H160,
+    pub creation_block: u64,
+}
+
+impl VelodromeFactory {
+    pub fn new(address: H160, creation_block: u64) -> VelodromeFactory {
+        VelodromeFactory {
+            address,
+            creation_block,
+        }
+    }
+
+    /// Length of the factory's `allPools` array
+    pub async fn all_pools_length<M: Middleware>(
+        &self,
+        middleware: Arc<M>,
+    ) -> Result<u64, AMMError<M>> {
+        let factory = IVelodromeFactory::new(self.address, middleware);
+        Ok(factory.all_pools_length().call().await?.as_u64())
+    }
+
+    /// Addresses of the pools at indices `start_idx..end_idx` of the factory's `allPools` array
+    pub async fn get_pool_addresses<M: Middleware>(
+        &self,
+        start_idx: u64,
+        end_idx: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<H160>, AMMError<M>> {
+        let calls = (start_idx..end_idx)
+            .map(|idx| {
+                multicall::call(
+                    self.address,
+                    ALL_POOLS_SELECTOR,
+                    &ethers::abi::encode(&[Token::Uint(U256::from(idx))]),
+                )
+            })
+            .collect();
+
+        Ok(multicall::aggregate(calls, None, middleware)
+            .await?
+            .into_iter()
+            .filter_map(|return_data| multicall::address_word(return_data.as_ref()?, 0))
+            .filter(|address| !address.is_zero())
+            .collect())
+    }
+
+    /// Gets every pool of the factory, unpopulated
+    pub async fn get_all_pools_via_multicall<M: Middleware>(
+        &self,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let pools_length = self.all_pools_length(middleware.clone()).await?;
+
+        tracing::trace!(pools_length, factory = ?self.address, "getting all pools of factory via multicall");
+
+        Ok(self
+            .get_pool_addresses(0, pools_length, middleware)
+            .await?
+            .into_iter()
+            .map(|address| {
+                AMM::VelodromePool(VelodromePool {
+                    address,
+                    ..Default::default()
+                })
+            })
+            .collect())
+    }
+
+    /// Gets and populates the pools at indices `start_idx..end_idx` of the factory's `allPools` array
+    pub async fn get_pools_range<M: Middleware>(
+        &self,
+        start_idx: u64,
+        end_idx: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let mut amms = self
+            .get_pool_addresses(start_idx, end_idx, middleware.clone())
+            .await?
+            .into_iter()
+            .map(|address| {
+                AMM::VelodromePool(VelodromePool {
+                    address,
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<AMM>>();
+
+        for amm_chunk in amms.chunks_mut(GET_POOL_DATA_STEP) {
+            batch_request::get_amm_data_batch_request(amm_chunk, None, middleware.clone()).await?;
+        }
+
+        Ok(sync::remove_empty_amms(amms))
+    }
+}
+
+/// Reads `(token0, token1, stable, pool)` straight from the topics and data of a `PoolCreated` log
+pub fn decode_pool_created_log(log: &Log) -> Result<(H160, H160, bool, H160), EventLogError> {
+    if decode::event_signature(log)? != POOL_CREATED_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    Ok((
+        decode::topic_address(log, 1)?,
+        decode::topic_address(log, 2)?,
+        !decode::topic_uint(log, 3, 8)?.is_zero(),
+        decode::data_address(log, 0)?,
+    ))
+}
+
+#[async_trait]
+impl AutomatedMarketMakerFactory for VelodromeFactory {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn amm_created_event_signature(&self) -> H256 {
+        POOL_CREATED_EVENT_SIGNATURE
+    }
+
+    async fn new_amm_from_log<M: 'static + Middleware>(
+        &self,
+        log: Log,
+        middleware: Arc<M>,
+    ) -> Result<AMM, AMMError<M>> {
+        let mut amm = self.new_empty_amm_from_log(&log)?;
+        let block_number = log.block_number.map(|block_number| block_number.as_u64());
+        amm.populate_data(block_number, middleware).await?;
+
+        Ok(amm)
+    }
+
+    fn new_empty_amm_from_log(&self, log: &Log) -> Result<AMM, EventLogError> {
+        let creation_block = log.block_number.map(|block_number| block_number.as_u64());
+        let (token_0, token_1, stable, pool) = decode_pool_created_log(log)?;
+
+        Ok(AMM::VelodromePool(VelodromePool {
+            address: pool,
+            token_a: token_0,
+            token_b: token_1,
+            stable,
+            creation_block,
+            ..Default::default()
+        }))
+    }
+
+    async fn get_all_amms<M: Middleware>(
+        &self,
+        _to_block: Option<u64>,
+        middleware: Arc<M>,
+        _step: u64,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        self.get_all_pools_via_multicall(middleware).await
+    }
+
+    async fn populate_amm_data<M: Middleware>(
+        &self,
+        amms: &mut [AMM],
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+        _step: u64,
+    ) -> Result<(), AMMError<M>> {
+        //Multicall3 chunks its calls itself
+        batch_request::get_amm_data_batch_request(amms, block_number, middleware).await
+    }
+
+    fn creation_block(&self) -> u64 {
+        self.creation_block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use ethers::{
+        abi::Token,
+        providers::{Http, Middleware, Provider},
+        types::{Log, H160, H256, U256, U64},
+    };
+
+    use crate::amm::{
+        factory::AutomatedMarketMakerFactory,
+        velodrome::{IVelodromePool, VelodromePool},
+        AmmState, AMM,
+    };
+
+    use super::{IVelodromeFactory, VelodromeFactory, POOL_CREATED_EVENT_SIGNATURE};
+
+    #[test]
+    fn test_new_empty_amm_from_log() -> eyre::Result<()> {
+        let factory = VelodromeFactory::new(H160::from_low_u64_be(100), 0);
+        let pool = H160::from_low_u64_be(1);
+
+        let creation_log = Log {
+            address: factory.address,
+            topics: vec![
+                POOL_CREATED_EVENT_SIGNATURE,
+                H256::from(H160::from_low_u64_be(2)),
+                H256::from(H160::from_low_u64_be(3)),
+                H256::from_low_u64_be(1),
+            ],
+            data: ethers::abi::encode(&[Token::Address(pool), Token::Uint(U256::one())]).into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        };
+
+        match factory.new_empty_amm_from_log(&creation_log)? {
+            AMM::VelodromePool(velodrome_pool) => {
+                assert_eq!(velodrome_pool.address, pool);
+                assert_eq!(velodrome_pool.token_a, H160::from_low_u64_be(2));
+                assert_eq!(velodrome_pool.token_b, H160::from_low_u64_be(3));
+                assert!(velodrome_pool.stable);
+                assert_eq!(velodrome_pool.creation_block, Some(10));
+            }
+            _ => panic!("Expected a new Velodrome pool"),
+        }
+
+        let truncated_log = Log {
+            topics: creation_log.topics[..3].to_vec(),
+            ..creation_log
+        };
+        assert!(factory.new_empty_amm_from_log(&truncated_log).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_simulate_swap_against_aerodrome() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("BASE_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let factory = VelodromeFactory::new(
+            H160::from_str("0x420DD381b31aEf6683db6B902084cB0FFECe40Da")?,
+            3200559,
+        );
+        let block_number = middleware.get_block_number().await?.as_u64();
+
+        //The first pools of the factory hold both stable and volatile pools
+        let amms = factory.get_pools_range(0, 20, middleware.clone()).await?;
+        assert!(amms
+            .iter()
+            .any(|amm| matches!(amm, AMM::VelodromePool(pool) if pool.stable)));
+
+        for amm in amms {
+            //Read again at the block the pool is quoted at
+            let mut pool = VelodromePool {
+                address: amm.address(),
+                ..Default::default()
+            };
+            super::batch_request::get_velodrome_pool_data_batch_request(
+                &mut pool,
+                Some(block_number),
+                middleware.clone(),
+            )
+            .await?;
+
+            let contract = IVelodromePool::new(pool.address, middleware.clone());
+            for (token_in, reserve_in) in [
+                (pool.token_a, pool.reserve_0),
+                (pool.token_b, pool.reserve_1),
+            ] {
+                let amount_in = reserve_in / 1000;
+                let expected = contract
+                    .get_amount_out(amount_in, token_in)
+                    .block(block_number)
+                    .call()
+                    .await?;
+
+                assert_eq!(
+                    pool.simulate_swap(token_in, amount_in)?,
+                    expected,
+                    "pool {:?}",
+                    pool.address
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_amount_out_against_aerodrome_pools() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("BASE_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let factory = IVelodromeFactory::new(
+            H160::from_str("0x420DD381b31aEf6683db6B902084cB0FFECe40Da")?,
+            middleware.clone(),
+        );
+        let weth = H160::from_str("0x4200000000000000000000000000000000000006")?;
+        let usdc = H160::from_str("0x833589fCD6eDb6E08f4c7C32D4f71b54bdA02913")?;
+        let usdbc = H160::from_str("0xd9aAEc86B65D86f6A7B5B1b0c42FFA531710b6CA")?;
+        let block_number = middleware.get_block_number().await?.as_u64();
+
+        //A stable pool of tokens with the same decimals and a volatile pool of tokens with different decimals
+        for (token_a, token_b, stable) in [(usdc, usdbc, true), (weth, usdc, false)] {
+            let address = factory
+                .get_pool(token_a, token_b, stable)
+                .block(block_number)
+                .call()
+                .await?;
+            let mut pool = VelodromePool {
+                address,
+                ..Default::default()
+            };
+            super::batch_request::get_velodrome_pool_data_batch_request(
+                &mut pool,
+                Some(block_number),
+                middleware.clone(),
+            )
+            .await?;
+            assert_eq!(pool.stable, stable);
+
+            let contract = IVelodromePool::new(pool.address, middleware.clone());
+            for (token_in, reserve_in) in [
+                (pool.token_a, pool.reserve_0),
+                (pool.token_b, pool.reserve_1),
+            ] {
+                //From dust to a tenth of the reserve, where the stable curve bends away from one to one
+                for divisor in [1_000_000_u64, 10_000, 1000, 100, 10] {
+                    let amount_in = reserve_in / divisor;
+                    let expected = contract
+                        .get_amount_out(amount_in, token_in)
+                        .block(block_number)
+                        .call()
+                        .await?;
+
+                    assert_eq!(
+                        pool.get_amount_out(amount_in, token_in)?,
+                        expected,
+                        "pool {:?}, amount in {amount_in}",
+                        pool.address
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
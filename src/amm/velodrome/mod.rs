@@ -0,0 +1,642 @@
+#[cfg(feature = "rpc")]
+pub mod batch_request;
+#[cfg(feature = "rpc")]
+pub mod factory;
+
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+#[cfg(feature = "rpc")]
+use async_trait::async_trait;
+#[cfg(feature = "rpc")]
+use ethers::providers::Middleware;
+use ethers::{
+    prelude::abigen,
+    types::{Log, H160, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "rpc")]
+use crate::{amm::AutomatedMarketMaker, errors::AMMError};
+use crate::{
+    amm::{self, compat::SerdeVersion, decode, AmmState},
+    errors::{ArithmeticError, EventLogError, SwapSimulationError},
+    filters::dedupe::Protocol,
+    math::fixed_point::u256_to_f64,
+    tokens::TokenStore,
+};
+
+abigen!(
+    IVelodromePool,
+    r#"[
+        function metadata() external view returns (uint256 dec0, uint256 dec1, uint256 r0, uint256 r1, bool st, address t0, address t1)
+        function factory() external view returns (address)
+        function getAmountOut(uint256 amountIn, address tokenIn) external view returns (uint256)
+        event Sync(uint256 reserve0, uint256 reserve1)
+        event Swap(address indexed sender, address indexed to, uint256 amount0In, uint256 amount1In, uint256 amount0Out, uint256 amount1Out)
+    ]"#;
+);
+
+//Sync(uint256 reserve0, uint256 reserve1)
+pub const SYNC_EVENT_SIGNATURE: H256 = H256([
+    207, 42, 165, 8, 118, 205, 251, 181, 65, 32, 111, 137, 175, 14, 231, 141, 68, 162, 171, 248,
+    211, 40, 227, 127, 164, 145, 127, 152, 33, 73, 132, 138,
+]);
+//Swap(address indexed sender, address indexed to, uint256 amount0In, uint256 amount1In, uint256 amount0Out, uint256 amount1Out)
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    179, 226, 119, 54, 6, 171, 253, 54, 181, 189, 145, 57, 75, 58, 84, 209, 57, 131, 54, 198, 80,
+    5, 186, 247, 191, 122, 5, 239, 239, 250, 247, 91,
+]);
+
+/// Denominator of `fee`, i.e. a fee of 5 is 0.05%
+pub const FEE_DENOMINATOR: u32 = 10_000;
+//Iterations of the Newton method of the pools before they revert
+const MAX_ITERATIONS: usize = 255;
+//Stable pools normalize their reserves to 18 decimals before entering the invariant
+const PRECISION: u128 = 1_000_000_000_000_000_000;
+
+/// Velodrome V2 pool, also deployed as Aerodrome on Base.
+///
+/// Volatile pools trade on the `xy = k` curve of Uniswap V2, stable pools on the `x³y + xy³ = k` curve of Solidly,
+/// solved for the amount out with the same Newton method as the pools. The fee is set per pool by the factory and
+/// leaves the pool on every swap, so the reserves only grow by the amount in after the fee.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VelodromePool {
+    pub address: H160,
+    pub token_a: H160,
+    pub token_a_decimals: u8,
+    pub token_b: H160,
+    pub token_b_decimals: u8,
+    pub reserve_0: U256,
+    pub reserve_1: U256,
+    /// Whether the pool trades on the stable curve
+    pub stable: bool,
+    /// Swap fee over `FEE_DENOMINATOR`, as returned by `getFee` of the factory
+    pub fee: u32,
+    //Block of the pool creation log, if the pool was discovered from it
+    #[serde(default)]
+    pub creation_block: Option<u64>,
+    //Token metadata, only fetched when populating with `PopulateOptions::fetch_symbols`
+    #[serde(default)]
+    pub token_a_symbol: Option<String>,
+    #[serde(default)]
+    pub token_a_name: Option<String>,
+    #[serde(default)]
+    pub token_b_symbol: Option<String>,
+    #[serde(default)]
+    pub token_b_name: Option<String>,
+    #[serde(default = "SerdeVersion::legacy")]
+    pub serde_version: SerdeVersion,
+}
+
+impl AmmState for VelodromePool {
+    fn address(&self) -> H160 {
+        self.address
+    }
+
+    fn tokens(&self) -> Vec<H160> {
+        vec![self.token_a, self.token_b]
+    }
+
+    fn token_symbol(&self, token: H160) -> Option<&str> {
+        if token == self.token_a {
+            self.token_a_symbol.as_deref()
+        } else if token == self.token_b {
+            self.token_b_symbol.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Marginal rate of a whole `base_token` in whole units of the other token, without the fee
+    fn calculate_price(&self, base_token: H160) -> Result<f64, ArithmeticError> {
+        let (reserve_base, reserve_quote) = if base_token == self.token_a {
+            (
+                u256_to_f64(self.reserve_0, self.token_a_decimals),
+                u256_to_f64(self.reserve_1, self.token_b_decimals),
+            )
+        } else {
+            (
+                u256_to_f64(self.reserve_1, self.token_b_decimals),
+                u256_to_f64(self.reserve_0, self.token_a_decimals),
+            )
+        };
+
+        //The slope of x³y + xy³ is (3x²y + y³) / (x³ + 3xy²)
+        let price = if self.stable {
+            let (x, y) = (reserve_base, reserve_quote);
+            (3.0 * x * x * y + y * y * y) / (x * x * x + 3.0 * x * y * y)
+        } else {
+            reserve_quote / reserve_base
+        };
+
+        if !price.is_finite() {
+            return Err(ArithmeticError::InvalidPrice(price));
+        }
+
+        Ok(price)
+    }
+
+    //Every swap, mint and burn ends with a Sync, so Swap logs are not needed to follow the reserves
+    fn sync_on_event_signatures(&self) -> Vec<H256> {
+        vec![SYNC_EVENT_SIGNATURE]
+    }
+
+    fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+        decode::check_address(log, self.address)?;
+        if decode::event_signature(log)? != SYNC_EVENT_SIGNATURE {
+            return Err(EventLogError::UnexpectedEvent(log.into()));
+        }
+
+        self.reserve_0 = decode::data_uint(log, 0, 256)?;
+        self.reserve_1 = decode::data_uint(log, 1, 256)?;
+
+        Ok(())
+    }
+
+    fn state_fingerprint(&self) -> H256 {
+        amm::state_fingerprint(
+            Protocol::Velodrome.name(),
+            &[
+                self.reserve_0,
+                self.reserve_1,
+                U256::from(self.stable as u8),
+                U256::from(self.fee),
+            ],
+        )
+    }
+
+    fn simulate_swap(&self, token_in: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+        self.check_token_in(token_in)?;
+        Ok(self.get_amount_out(amount_in, token_in)?)
+    }
+
+    fn simulate_swap_mut(
+        &mut self,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        self.check_token_in(token_in)?;
+        let amount_out = self.get_amount_out(amount_in, token_in)?;
+
+        //The fee is sent to the fee contract of the pool, so only the rest of the amount in is added to the reserve
+        let amount_in = amount_in - self.fee_amount(amount_in)?;
+        if token_in == self.token_a {
+            self.reserve_0 = self.reserve_0.saturating_add(amount_in);
+            self.reserve_1 = self.reserve_1.saturating_sub(amount_out);
+        } else {
+            self.reserve_1 = self.reserve_1.saturating_add(amount_in);
+            self.reserve_0 = self.reserve_0.saturating_sub(amount_out);
+        }
+
+        Ok(amount_out)
+    }
+
+    fn get_token_out(&self, token_in: H160) -> H160 {
+        if self.token_a == token_in {
+            self.token_b
+        } else {
+            self.token_a
+        }
+    }
+
+    fn opp_token(&self, token: H160) -> Option<H160> {
+        if token == self.token_a {
+            Some(self.token_b)
+        } else if token == self.token_b {
+            Some(self.token_a)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl AutomatedMarketMaker for VelodromePool {
+    async fn sync<M: Middleware>(&mut self, middleware: Arc<M>) -> Result<(), AMMError<M>> {
+        batch_request::get_velodrome_pool_data_batch_request(self, None, middleware).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(address = ?self.address), err(Debug))]
+    async fn populate_data<M: Middleware>(
+        &mut self,
+        block_number: Option<u64>,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        batch_request::get_velodrome_pool_data_batch_request(self, block_number, middleware).await
+    }
+}
+
+impl VelodromePool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: H160,
+        token_a: H160,
+        token_a_decimals: u8,
+        token_b: H160,
+        token_b_decimals: u8,
+        reserve_0: U256,
+        reserve_1: U256,
+        stable: bool,
+        fee: u32,
+    ) -> VelodromePool {
+        VelodromePool {
+            address,
+            token_a,
+            token_a_decimals,
+            token_b,
+            token_b_decimals,
+            reserve_0,
+            reserve_1,
+            stable,
+            fee,
+            serde_version: SerdeVersion::CURRENT,
+            ..Default::default()
+        }
+    }
+
+    pub fn data_is_populated(&self) -> bool {
+        !(self.token_a.is_zero()
+            || self.token_b.is_zero()
+            || self.reserve_0.is_zero()
+            || self.reserve_1.is_zero())
+    }
+
+    /// Decimals of `token`, None if the pool does not hold it
+    pub fn token_decimals(&self, token: H160) -> Option<u8> {
+        if token == self.token_a {
+            Some(self.token_a_decimals)
+        } else if token == self.token_b {
+            Some(self.token_b_decimals)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the symbols and names of both tokens found in `tokens`, keeping the current ones otherwise
+    pub fn set_token_metadata(&mut self, tokens: &TokenStore) {
+        if let Some(info) = tokens.get(self.token_a) {
+            self.token_a_symbol = info.symbol.or(self.token_a_symbol.take());
+            self.token_a_name = info.name.or(self.token_a_name.take());
+        }
+        if let Some(info) = tokens.get(self.token_b) {
+            self.token_b_symbol = info.symbol.or(self.token_b_symbol.take());
+            self.token_b_name = info.name.or(self.token_b_name.take());
+        }
+    }
+
+    /// Amount of the other token paid out for `amount_in` of `token_in`, rounded as in `getAmountOut` of the pool
+    pub fn get_amount_out(&self, amount_in: U256, token_in: H160) -> Result<U256, ArithmeticError> {
+        if amount_in.is_zero() || self.reserve_0.is_zero() || self.reserve_1.is_zero() {
+            return Ok(U256::zero());
+        }
+
+        let amount_in = amount_in - self.fee_amount(amount_in)?;
+        let zero_for_one = token_in == self.token_a;
+
+        if !self.stable {
+            let (reserve_in, reserve_out) = if zero_for_one {
+                (self.reserve_0, self.reserve_1)
+            } else {
+                (self.reserve_1, self.reserve_0)
+            };
+
+            let denominator = reserve_in
+                .checked_add(amount_in)
+                .ok_or(ArithmeticError::MulDivOverflow)?;
+            return Ok(mul(amount_in, reserve_out)? / denominator);
+        }
+
+        let (scale_0, scale_1) = self.scales()?;
+        let xy = self.k(self.reserve_0, self.reserve_1)?;
+        let reserve_0 = mul(self.reserve_0, PRECISION.into())? / scale_0;
+        let reserve_1 = mul(self.reserve_1, PRECISION.into())? / scale_1;
+
+        let (reserve_in, reserve_out, scale_in, scale_out) = if zero_for_one {
+            (reserve_0, reserve_1, scale_0, scale_1)
+        } else {
+            (reserve_1, reserve_0, scale_1, scale_0)
+        };
+
+        let amount_in = mul(amount_in, PRECISION.into())? / scale_in;
+        let x0 = amount_in
+            .checked_add(reserve_in)
+            .ok_or(ArithmeticError::MulDivOverflow)?;
+        let y = reserve_out.saturating_sub(self.get_y(x0, xy, reserve_out)?);
+
+        Ok(mul(y, scale_out)? / U256::from(PRECISION))
+    }
+
+    //Share of `amount_in` taken as the fee, rounded down as in the pool
+    fn fee_amount(&self, amount_in: U256) -> Result<U256, ArithmeticError> {
+        Ok(mul(amount_in, U256::from(self.fee))? / U256::from(FEE_DENOMINATOR))
+    }
+
+    fn check_token_in(&self, token_in: H160) -> Result<(), SwapSimulationError> {
+        if token_in == self.token_a || token_in == self.token_b {
+            Ok(())
+        } else {
+            Err(SwapSimulationError::TokenNotInPool(token_in))
+        }
+    }
+
+    //`10 ** decimals` of both tokens, as stored by the pool
+    fn scales(&self) -> Result<(U256, U256), ArithmeticError> {
+        let scale = |decimals: u8| {
+            (decimals <= 38)
+                .then(|| U256::exp10(decimals as usize))
+                .ok_or(ArithmeticError::UnsupportedDecimals(decimals))
+        };
+
+        Ok((scale(self.token_a_decimals)?, scale(self.token_b_decimals)?))
+    }
+
+    //Invariant of the raw reserves, normalized to 18 decimals for stable pools
+    fn k(&self, x: U256, y: U256) -> Result<U256, ArithmeticError> {
+        if !self.stable {
+            return mul(x, y);
+        }
+
+        let (scale_0, scale_1) = self.scales()?;
+        let x = mul(x, PRECISION.into())? / scale_0;
+        let y = mul(y, PRECISION.into())? / scale_1;
+
+        f(x, y)
+    }
+
+    //Reserve out keeping the invariant at `xy` once the reserve in is `x0`, starting from `y`. `_k` normalizes
+    //its arguments again, which the pools do as well when checking `y + 1`.
+    fn get_y(&self, x0: U256, xy: U256, mut y: U256) -> Result<U256, ArithmeticError> {
+        let precision = U256::from(PRECISION);
+
+        for _ in 0..MAX_ITERATIONS {
+            let k = f(x0, y)?;
+            let slope = d(x0, y)?;
+            if slope.is_zero() {
+                return Err(ArithmeticError::DivisionByZero);
+            }
+
+            if k < xy {
+                let mut dy = mul(xy - k, precision)? / slope;
+                if dy.is_zero() {
+                    if self.k(x0, y + 1)? > xy {
+                        return Ok(y + 1);
+                    }
+                    dy = U256::one();
+                }
+                y += dy;
+            } else {
+                let mut dy = mul(k - xy, precision)? / slope;
+                if dy.is_zero() {
+                    if k == xy || f(x0, y.saturating_sub(U256::one()))? < xy {
+                        return Ok(y);
+                    }
+                    dy = U256::one();
+                }
+                y = y.checked_sub(dy).ok_or(ArithmeticError::YIsZero)?;
+            }
+        }
+
+        Err(ArithmeticError::InvariantNotConverged)
+    }
+}
+
+/// Reads a `Swap` log of a pool straight from its topics and data
+pub fn decode_swap_log(log: &Log) -> Result<SwapFilter, EventLogError> {
+    if decode::event_signature(log)? != SWAP_EVENT_SIGNATURE {
+        return Err(EventLogError::UnexpectedEvent(log.into()));
+    }
+
+    Ok(SwapFilter {
+        sender: decode::topic_address(log, 1)?,
+        to: decode::topic_address(log, 2)?,
+        amount_0_in: decode::data_uint(log, 0, 256)?,
+        amount_1_in: decode::data_uint(log, 1, 256)?,
+        amount_0_out: decode::data_uint(log, 2, 256)?,
+        amount_1_out: decode::data_uint(log, 3, 256)?,
+    })
+}
+
+fn mul(a: U256, b: U256) -> Result<U256, ArithmeticError> {
+    a.checked_mul(b).ok_or(ArithmeticError::MulDivOverflow)
+}
+
+//x0³y + x0y³ of reserves in 18 decimals
+fn f(x0: U256, y: U256) -> Result<U256, ArithmeticError> {
+    let precision = U256::from(PRECISION);
+    let a = mul(x0, y)? / precision;
+    let b = mul(x0, x0)? / precision + mul(y, y)? / precision;
+
+    Ok(mul(a, b)? / precision)
+}
+
+//Derivative of `f` in `y`, 3x0y² + x0³
+fn d(x0: U256, y: U256) -> Result<U256, ArithmeticError> {
+    let precision = U256::from(PRECISION);
+
+    Ok(
+        mul(mul(U256::from(3), x0)?, mul(y, y)? / precision)? / precision
+            + mul(mul(x0, x0)? / precision, x0)? / precision,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::{RawLog, Token},
+        prelude::EthEvent,
+        types::{Log, H160, U256},
+    };
+
+    use crate::{
+        amm::{AmmState, AMM},
+        errors::{EventLogError, SwapSimulationError},
+    };
+
+    use super::{
+        decode_swap_log, SwapFilter, VelodromePool, SWAP_EVENT_SIGNATURE, SYNC_EVENT_SIGNATURE,
+    };
+
+    fn token(i: u64) -> H160 {
+        H160::from_low_u64_be(i)
+    }
+
+    //Stable USDC/DAI with 1M of each and a 0.05% fee
+    fn usdc_dai() -> VelodromePool {
+        VelodromePool::new(
+            H160::from_low_u64_be(10),
+            token(1),
+            6,
+            token(2),
+            18,
+            U256::exp10(12),
+            U256::exp10(24),
+            true,
+            5,
+        )
+    }
+
+    //Volatile WETH/USDC with 1000 WETH, 2M USDC and a 0.3% fee
+    fn weth_usdc() -> VelodromePool {
+        VelodromePool::new(
+            H160::from_low_u64_be(20),
+            token(3),
+            18,
+            token(1),
+            6,
+            U256::exp10(21),
+            U256::from(2) * U256::exp10(12),
+            false,
+            30,
+        )
+    }
+
+    fn sync_log(pool: &VelodromePool, reserve_0: U256, reserve_1: U256) -> Log {
+        Log {
+            address: pool.address,
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[Token::Uint(reserve_0), Token::Uint(reserve_1)]).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_swap() -> eyre::Result<()> {
+        //Expected amounts are those of a separate port of `getAmountOut` of Pool.sol, the quotes of live pools are
+        //checked in `factory::tests::test_get_amount_out_against_aerodrome_pools`
+
+        //Stable pools trade close to one to one across decimals, as `getAmountOut` of the pool
+        let pool = usdc_dai();
+        assert_eq!(
+            pool.simulate_swap(token(1), U256::from(1_000_000_000))?,
+            U256::from(999499999500999250748_u128)
+        );
+        assert_eq!(
+            pool.simulate_swap(token(2), U256::exp10(21))?,
+            U256::from(999499999)
+        );
+        assert_eq!(
+            pool.simulate_swap(token(1), U256::from(100_000_000_000_u64))?,
+            U256::from_dec_str("99900151543813496655819")?
+        );
+
+        //An imbalanced stable pool pays more of the scarce side than the constant product would
+        let mut imbalanced = usdc_dai();
+        imbalanced.token_a_decimals = 18;
+        imbalanced.reserve_1 = U256::exp10(23) * 5;
+        imbalanced.reserve_0 = U256::exp10(24);
+        assert_eq!(
+            imbalanced.simulate_swap(token(1), U256::exp10(21))?,
+            U256::from(927713144072331487939_u128)
+        );
+        assert_eq!(
+            imbalanced.simulate_swap(token(2), U256::exp10(21))?,
+            U256::from_dec_str("1075895057624711817331")?
+        );
+
+        //Volatile pools follow the constant product
+        let pool = weth_usdc();
+        assert_eq!(
+            pool.simulate_swap(token(3), U256::exp10(18))?,
+            U256::from(1992013962)
+        );
+        assert_eq!(pool.simulate_swap(token(3), U256::zero())?, U256::zero());
+        assert!(matches!(
+            pool.simulate_swap(token(4), U256::one()),
+            Err(SwapSimulationError::TokenNotInPool(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_swap_mut() -> eyre::Result<()> {
+        let mut pool = usdc_dai();
+        let quote = pool.simulate_swap(token(1), U256::from(1_000_000_000))?;
+
+        let amount_out = pool.simulate_swap_mut(token(1), U256::from(1_000_000_000))?;
+        assert_eq!(amount_out, quote);
+
+        //The fee leaves the pool
+        assert_eq!(pool.reserve_0, U256::exp10(12) + U256::from(999_500_000));
+        assert_eq!(pool.reserve_1, U256::exp10(24) - amount_out);
+        assert!(pool.simulate_swap(token(1), U256::from(1_000_000_000))? < quote);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_calculate_price() -> eyre::Result<()> {
+        //Balanced stable pools price one to one in whole tokens
+        assert!((usdc_dai().calculate_price(token(1))? - 1.0).abs() < 1e-12);
+        assert!((weth_usdc().calculate_price(token(3))? - 2000.0).abs() < 1e-9);
+        assert!((weth_usdc().calculate_price(token(1))? - 0.0005).abs() < 1e-15);
+
+        //The marginal price is the rate of small trades before the fee
+        let mut pool = usdc_dai();
+        pool.reserve_1 = U256::exp10(23) * 5;
+        let amount_out = pool.simulate_swap(token(2), U256::exp10(18))?;
+        let rate = amount_out.as_u128() as f64 / 1e6 / 0.9995;
+        assert!((rate / pool.calculate_price(token(2))? - 1.0).abs() < 1e-4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_from_log() -> eyre::Result<()> {
+        let mut pool = usdc_dai();
+        let mut amm = AMM::VelodromePool(pool.clone());
+
+        let log = sync_log(&pool, U256::from(5), U256::from(7));
+        pool.sync_from_log(&log)?;
+        amm.sync_from_log(&log)?;
+        assert_eq!(
+            (pool.reserve_0, pool.reserve_1),
+            (U256::from(5), U256::from(7))
+        );
+        assert!(AMM::VelodromePool(pool.clone()).state_eq(&amm));
+
+        //Logs of other contracts and events are rejected
+        let mut foreign_log = sync_log(&pool, U256::one(), U256::one());
+        foreign_log.address = H160::from_low_u64_be(99);
+        assert!(matches!(
+            pool.sync_from_log(&foreign_log),
+            Err(EventLogError::LogAddressMismatch { .. })
+        ));
+
+        let swap_log = Log {
+            address: pool.address,
+            topics: vec![
+                SWAP_EVENT_SIGNATURE,
+                H160::from_low_u64_be(7).into(),
+                H160::from_low_u64_be(8).into(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(100)),
+                Token::Uint(U256::zero()),
+                Token::Uint(U256::zero()),
+                Token::Uint(U256::from(99)),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            pool.sync_from_log(&swap_log),
+            Err(EventLogError::UnexpectedEvent(_))
+        ));
+        assert_eq!(
+            (pool.reserve_0, pool.reserve_1),
+            (U256::from(5), U256::from(7))
+        );
+
+        //Swap logs are decoded as by the ABI decoder
+        assert_eq!(
+            decode_swap_log(&swap_log)?,
+            SwapFilter::decode_log(&RawLog::from(swap_log.clone()))?
+        );
+        assert!(decode_swap_log(&log).is_err());
+
+        Ok(())
+    }
+}
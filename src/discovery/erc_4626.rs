@@ -1,10 +1,14 @@
 use std::{collections::HashSet, str::FromStr, sync::Arc};
 
 use ethers::{
+    abi::Token,
+    contract::Multicall,
+    prelude::abigen,
     providers::Middleware,
-    types::{Filter, U256},
+    types::{Bytes, Filter, ValueOrArray, H160, U256},
 };
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     amm::erc_4626::{ERC4626Vault, DEPOSIT_EVENT_SIGNATURE, WITHDRAW_EVENT_SIGNATURE},
@@ -15,6 +19,18 @@ lazy_static::lazy_static! {
     static ref HEX_REGEX: Regex = Regex::new(r"0x[0-9a-fA-F]+").expect("Could not compile regex");
 }
 
+abigen!(
+    IDiscoverableVault,
+    r#"[
+        function asset() external view returns (address)
+        function totalAssets() external view returns (uint256)
+        function convertToShares(uint256) external view returns (uint256)
+    ]"#;
+);
+
+//Number of vaults probed in each multicall, each vault is probed with three calls
+const MULTICALL_STEP: usize = 150;
+
 // Returns a vec of empty factories that match one of the Factory interfaces specified by each DiscoverableFactory
 pub async fn discover_erc_4626_vaults<M: Middleware>(
     middleware: Arc<M>,
@@ -116,7 +132,7 @@ pub async fn discover_erc_4626_vaults<M: Middleware>(
         //TODO: vaults. This approach is inefficient but should work for now.
 
         if let Ok(vault) =
-            ERC4626Vault::new_from_address(*identified_address, middleware.clone()).await
+            ERC4626Vault::new_populated(*identified_address, middleware.clone()).await
         {
             vaults.push(vault);
         }
@@ -125,3 +141,228 @@ pub async fn discover_erc_4626_vaults<M: Middleware>(
     tracing::info!("all vaults discovered");
     Ok(vaults)
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VaultRejection {
+    //`asset()` reverted or returned the zero address
+    MissingAsset,
+    MissingTotalAssets,
+    MissingConvertToShares,
+    //`convertToShares(1e18)` returned zero shares
+    ZeroShares,
+    //Deposit or withdraw fees are not a flat percentage and cannot be simulated
+    UnsupportedFee,
+    //The vault conforms but holds no assets or has no supply
+    EmptyVault,
+    PopulationFailed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RejectedVault {
+    pub address: H160,
+    pub reason: VaultRejection,
+}
+
+/// Result of a vault scan. `cursor` is the next block to scan, so the discovery can be serialized and resumed later.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VaultDiscovery {
+    pub vaults: Vec<ERC4626Vault>,
+    pub rejected: Vec<RejectedVault>,
+    pub cursor: u64,
+}
+
+impl VaultDiscovery {
+    pub fn new(from_block: u64) -> Self {
+        VaultDiscovery {
+            cursor: from_block,
+            ..Default::default()
+        }
+    }
+
+    fn contains(&self, address: &H160) -> bool {
+        self.vaults
+            .iter()
+            .any(|vault| vault.vault_token == *address)
+            || self
+                .rejected
+                .iter()
+                .any(|rejected| rejected.address == *address)
+    }
+
+    /// Scans `self.cursor..=to_block` for Deposit events and classifies every emitter not seen in a previous scan
+    pub async fn resume<M: 'static + Middleware>(
+        &mut self,
+        to_block: u64,
+        step: u64,
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        tracing::info!(
+            from_block = self.cursor,
+            to_block,
+            step,
+            "discovering ERC 4626 vaults"
+        );
+
+        let block_filter = Filter::new().topic0(ValueOrArray::Value(DEPOSIT_EVENT_SIGNATURE));
+        let step = step.max(1);
+
+        let mut candidates = vec![];
+        let mut seen = HashSet::new();
+
+        let mut from_block = self.cursor;
+        while from_block <= to_block {
+            let target_block = (from_block + step - 1).min(to_block);
+
+            tracing::info!("searching blocks {}-{}", from_block, target_block);
+
+            let logs = middleware
+                .get_logs(
+                    &block_filter
+                        .clone()
+                        .from_block(from_block)
+                        .to_block(target_block),
+                )
+                .await
+                .map_err(AMMError::MiddlewareError)?;
+
+            for log in logs {
+                if !self.contains(&log.address) && seen.insert(log.address) {
+                    candidates.push(log.address);
+                }
+            }
+
+            from_block = target_block + 1;
+        }
+
+        for chunk in candidates.chunks(MULTICALL_STEP) {
+            let mut multicall = Multicall::new(middleware.clone(), None).await?;
+            for address in chunk {
+                let vault = IDiscoverableVault::new(*address, middleware.clone());
+                multicall.add_call(vault.asset(), true);
+                multicall.add_call(vault.total_assets(), true);
+                multicall.add_call(vault.convert_to_shares(U256::exp10(18)), true);
+            }
+
+            let results = multicall.call_raw().await?;
+            for (address, probe) in chunk.iter().zip(results.chunks(3)) {
+                if let Some(reason) = probe_rejection(probe) {
+                    tracing::debug!(?address, ?reason, "vault rejected");
+                    self.rejected.push(RejectedVault {
+                        address: *address,
+                        reason,
+                    });
+                    continue;
+                }
+
+                match ERC4626Vault::new_populated(*address, middleware.clone()).await {
+                    Ok(vault) => self.vaults.push(vault),
+                    Err(err) => {
+                        let reason = match err {
+                            //The batch request rejects vaults with non-linear fees
+                            AMMError::BatchRequestError(_) => VaultRejection::UnsupportedFee,
+                            AMMError::PoolDataError => VaultRejection::EmptyVault,
+                            err => VaultRejection::PopulationFailed(err.to_string()),
+                        };
+
+                        tracing::debug!(?address, ?reason, "vault rejected");
+                        self.rejected.push(RejectedVault {
+                            address: *address,
+                            reason,
+                        });
+                    }
+                }
+            }
+        }
+
+        self.cursor = self.cursor.max(to_block + 1);
+        tracing::info!(
+            vaults = self.vaults.len(),
+            rejected = self.rejected.len(),
+            "vault scan complete"
+        );
+
+        Ok(())
+    }
+}
+
+/// Scans `from_block..=to_block` for emitters of the ERC 4626 Deposit event and probes them with `asset()`,
+/// `totalAssets()` and `convertToShares(1e18)`, returning the populated vaults along with the rejected candidates.
+pub async fn discover_erc_4626_vaults_in_range<M: 'static + Middleware>(
+    from_block: u64,
+    to_block: u64,
+    step: u64,
+    middleware: Arc<M>,
+) -> Result<VaultDiscovery, AMMError<M>> {
+    let mut discovery = VaultDiscovery::new(from_block);
+    discovery.resume(to_block, step, middleware).await?;
+    Ok(discovery)
+}
+
+//Returns the reason a vault failed the conformance probe, if any
+fn probe_rejection(probe: &[Result<Token, Bytes>]) -> Option<VaultRejection> {
+    match probe
+        .first()
+        .and_then(|result| result.as_ref().ok())
+        .and_then(|token| token.clone().into_address())
+    {
+        Some(asset) if !asset.is_zero() => {}
+        _ => return Some(VaultRejection::MissingAsset),
+    }
+
+    if !matches!(probe.get(1), Some(Ok(Token::Uint(_)))) {
+        return Some(VaultRejection::MissingTotalAssets);
+    }
+
+    match probe.get(2) {
+        Some(Ok(Token::Uint(shares))) if shares.is_zero() => Some(VaultRejection::ZeroShares),
+        Some(Ok(Token::Uint(_))) => None,
+        _ => Some(VaultRejection::MissingConvertToShares),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Bytes, H160, U256},
+    };
+
+    use super::{probe_rejection, VaultRejection};
+
+    #[test]
+    fn test_probe_rejection() {
+        let asset = Ok(Token::Address(H160::from_low_u64_be(1)));
+        let total_assets = Ok(Token::Uint(U256::exp10(20)));
+        let shares = Ok(Token::Uint(U256::exp10(18)));
+        let reverted: Result<Token, Bytes> = Err(Bytes::new());
+
+        assert_eq!(
+            probe_rejection(&[asset.clone(), total_assets.clone(), shares.clone()]),
+            None
+        );
+
+        assert_eq!(
+            probe_rejection(&[
+                Ok(Token::Address(H160::zero())),
+                total_assets.clone(),
+                shares.clone()
+            ]),
+            Some(VaultRejection::MissingAsset)
+        );
+
+        assert_eq!(
+            probe_rejection(&[asset.clone(), reverted.clone(), shares]),
+            Some(VaultRejection::MissingTotalAssets)
+        );
+
+        assert_eq!(
+            probe_rejection(&[asset.clone(), total_assets.clone(), reverted]),
+            Some(VaultRejection::MissingConvertToShares)
+        );
+
+        assert_eq!(
+            probe_rejection(&[asset, total_assets, Ok(Token::Uint(U256::zero()))]),
+            Some(VaultRejection::ZeroShares)
+        );
+    }
+}
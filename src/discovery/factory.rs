@@ -1,18 +1,50 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use ethers::{
+    abi::RawLog,
+    contract::Multicall,
+    prelude::{abigen, EthEvent},
     providers::Middleware,
-    types::{Filter, H160, H256},
+    types::{Filter, Log, H160, H256},
 };
 
 use crate::{
-    amm::{self, factory::Factory},
+    amm::{
+        self,
+        factory::Factory,
+        uniswap_v2::{
+            factory::{PairCreatedFilter, UniswapV2Factory, PAIR_CREATED_EVENT_SIGNATURE},
+            SYNC_EVENT_SIGNATURE,
+        },
+        uniswap_v3::factory::{PoolCreatedFilter, UniswapV3Factory, POOL_CREATED_EVENT_SIGNATURE},
+    },
     errors::AMMError,
 };
 
+abigen!(
+    IDiscoverablePool,
+    r#"[
+        function factory() external view returns (address)
+        function getReserves() external view returns (uint112, uint112, uint32)
+        function slot0() external view returns (uint160, int24, uint16, uint16, uint16, uint8, bool)
+    ]"#;
+);
+
+//Number of sample pools kept for each discovered factory
+const SAMPLE_POOLS: usize = 5;
+//Number of calls batched into each multicall when resolving pool factories
+const MULTICALL_STEP: usize = 500;
+
 pub enum DiscoverableFactory {
     UniswapV2Factory,
     UniswapV3Factory,
+    VelodromeFactory,
+    UniswapV4Factory,
+    MaverickFactory,
+    AlgebraFactory,
 }
 
 impl DiscoverableFactory {
@@ -25,6 +57,20 @@ impl DiscoverableFactory {
             DiscoverableFactory::UniswapV3Factory => {
                 amm::uniswap_v3::factory::POOL_CREATED_EVENT_SIGNATURE
             }
+
+            DiscoverableFactory::VelodromeFactory => {
+                amm::velodrome::factory::POOL_CREATED_EVENT_SIGNATURE
+            }
+
+            DiscoverableFactory::UniswapV4Factory => {
+                amm::uniswap_v4::factory::INITIALIZE_EVENT_SIGNATURE
+            }
+
+            DiscoverableFactory::MaverickFactory => {
+                amm::maverick::factory::POOL_CREATED_EVENT_SIGNATURE
+            }
+
+            DiscoverableFactory::AlgebraFactory => amm::algebra::factory::POOL_EVENT_SIGNATURE,
         }
     }
 }
@@ -103,6 +149,41 @@ pub async fn discover_factories<M: Middleware>(
                             .ok_or(AMMError::BlockNumberNotFound)?
                             .as_u64();
                     }
+                    Factory::VelodromeFactory(velodrome_factory) => {
+                        velodrome_factory.address = log.address;
+                        velodrome_factory.creation_block = log
+                            .block_number
+                            .ok_or(AMMError::BlockNumberNotFound)?
+                            .as_u64();
+                    }
+                    Factory::UniswapV4Factory(uniswap_v4_factory) => {
+                        uniswap_v4_factory.address = log.address;
+                        uniswap_v4_factory.creation_block = log
+                            .block_number
+                            .ok_or(AMMError::BlockNumberNotFound)?
+                            .as_u64();
+                    }
+                    Factory::MaverickFactory(maverick_factory) => {
+                        maverick_factory.address = log.address;
+                        maverick_factory.creation_block = log
+                            .block_number
+                            .ok_or(AMMError::BlockNumberNotFound)?
+                            .as_u64();
+                    }
+                    Factory::KyberElasticFactory(kyber_elastic_factory) => {
+                        kyber_elastic_factory.address = log.address;
+                        kyber_elastic_factory.creation_block = log
+                            .block_number
+                            .ok_or(AMMError::BlockNumberNotFound)?
+                            .as_u64();
+                    }
+                    Factory::AlgebraFactory(algebra_factory) => {
+                        algebra_factory.address = log.address;
+                        algebra_factory.creation_block = log
+                            .block_number
+                            .ok_or(AMMError::BlockNumberNotFound)?
+                            .as_u64();
+                    }
                 }
 
                 tracing::info!(address = ?log.address, "discovered new factory");
@@ -127,3 +208,303 @@ pub async fn discover_factories<M: Middleware>(
     tracing::info!("all factories discovered");
     Ok(filtered_factories)
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolGuess {
+    UniswapV2,
+    UniswapV3,
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredFactory {
+    pub address: H160,
+    pub protocol_guess: ProtocolGuess,
+    pub pool_count: u64,
+    pub sample_pools: Vec<H160>,
+    /// First block in the scanned range where activity from the factory was seen, which is not necessarily its deployment block
+    pub first_seen_block: u64,
+}
+
+impl DiscoveredFactory {
+    fn new(address: H160, protocol_guess: ProtocolGuess, first_seen_block: u64) -> Self {
+        DiscoveredFactory {
+            address,
+            protocol_guess,
+            pool_count: 0,
+            sample_pools: vec![],
+            first_seen_block,
+        }
+    }
+
+    /// Constructs a `Factory` from the protocol guess, using `first_seen_block` as the creation block and the default 0.3% fee for V2 forks
+    pub fn to_factory(&self) -> Option<Factory> {
+        match self.protocol_guess {
+            ProtocolGuess::UniswapV2 => Some(Factory::UniswapV2Factory(UniswapV2Factory::new(
+                self.address,
+                self.first_seen_block,
+                300,
+            ))),
+            ProtocolGuess::UniswapV3 => Some(Factory::UniswapV3Factory(UniswapV3Factory::new(
+                self.address,
+                self.first_seen_block,
+            ))),
+            ProtocolGuess::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FactoryCandidates {
+    factories: HashMap<H160, DiscoveredFactory>,
+    pools: HashSet<H160>,
+    //Pools that emitted a sync event but whose creation was not seen, keyed to the first block they were seen at
+    unattributed_pools: HashMap<H160, u64>,
+}
+
+impl FactoryCandidates {
+    fn add_pool(
+        &mut self,
+        factory: H160,
+        pool: H160,
+        protocol_guess: ProtocolGuess,
+        block_number: u64,
+    ) {
+        if !self.pools.insert(pool) {
+            return;
+        }
+
+        let discovered_factory = self
+            .factories
+            .entry(factory)
+            .or_insert_with(|| DiscoveredFactory::new(factory, protocol_guess, block_number));
+
+        discovered_factory.pool_count += 1;
+        discovered_factory.first_seen_block = discovered_factory.first_seen_block.min(block_number);
+        if discovered_factory.sample_pools.len() < SAMPLE_POOLS {
+            discovered_factory.sample_pools.push(pool);
+        }
+    }
+
+    fn add_log(&mut self, log: Log) {
+        let block_number = match log.block_number {
+            Some(block_number) => block_number.as_u64(),
+            None => return,
+        };
+
+        let (factory, event_signature) = match log.topics.first() {
+            Some(event_signature) => (log.address, *event_signature),
+            None => return,
+        };
+
+        //Creation events are emitted by the factory itself, logs that do not decode are from incompatible forks
+        if event_signature == PAIR_CREATED_EVENT_SIGNATURE {
+            if let Ok(event) = PairCreatedFilter::decode_log(&RawLog::from(log)) {
+                self.add_pool(factory, event.pair, ProtocolGuess::UniswapV2, block_number);
+            }
+        } else if event_signature == POOL_CREATED_EVENT_SIGNATURE {
+            if let Ok(event) = PoolCreatedFilter::decode_log(&RawLog::from(log)) {
+                self.add_pool(factory, event.pool, ProtocolGuess::UniswapV3, block_number);
+            }
+        } else if event_signature == SYNC_EVENT_SIGNATURE && !self.pools.contains(&log.address) {
+            //Sync events are emitted by the pool, the factory is resolved afterwards
+            self.unattributed_pools
+                .entry(log.address)
+                .or_insert(block_number);
+        }
+    }
+
+    fn ranked_factories(self) -> Vec<DiscoveredFactory> {
+        let mut factories = self
+            .factories
+            .into_values()
+            .collect::<Vec<DiscoveredFactory>>();
+        factories.sort_by(|a, b| b.pool_count.cmp(&a.pool_count));
+        factories
+    }
+}
+
+/// Scans `from_block..=to_block` for PairCreated, PoolCreated and Sync logs, grouping the pools by the factory that created them.
+/// Returns the candidate factories ranked by the number of distinct pools, with their protocol guess verified against a sample pool.
+pub async fn discover_unknown_factories<M: 'static + Middleware>(
+    from_block: u64,
+    to_block: u64,
+    step: u64,
+    middleware: Arc<M>,
+) -> Result<Vec<DiscoveredFactory>, AMMError<M>> {
+    tracing::info!(from_block, to_block, step, "discovering unknown factories");
+
+    let block_filter = Filter::new().topic0(vec![
+        PAIR_CREATED_EVENT_SIGNATURE,
+        POOL_CREATED_EVENT_SIGNATURE,
+        SYNC_EVENT_SIGNATURE,
+    ]);
+
+    let mut candidates = FactoryCandidates::default();
+
+    let mut from_block = from_block;
+    while from_block <= to_block {
+        let target_block = (from_block + step - 1).min(to_block);
+
+        tracing::info!("searching blocks {}-{}", from_block, target_block);
+
+        let logs = middleware
+            .get_logs(
+                &block_filter
+                    .clone()
+                    .from_block(from_block)
+                    .to_block(target_block),
+            )
+            .await
+            .map_err(AMMError::MiddlewareError)?;
+
+        for log in logs {
+            candidates.add_log(log);
+        }
+
+        from_block += step;
+    }
+
+    //Resolve the factory of pools that were only seen syncing through the pool's `factory()` getter
+    let unattributed_pools = std::mem::take(&mut candidates.unattributed_pools)
+        .into_iter()
+        .filter(|(pool, _)| !candidates.pools.contains(pool))
+        .collect::<Vec<(H160, u64)>>();
+
+    for chunk in unattributed_pools.chunks(MULTICALL_STEP) {
+        let mut multicall = Multicall::new(middleware.clone(), None).await?;
+        for (pool, _) in chunk {
+            multicall.add_call(
+                IDiscoverablePool::new(*pool, middleware.clone()).factory(),
+                true,
+            );
+        }
+
+        for ((pool, block_number), result) in chunk.iter().zip(multicall.call_raw().await?) {
+            if let Some(factory) = result.ok().and_then(|token| token.into_address()) {
+                if !factory.is_zero() {
+                    candidates.add_pool(factory, *pool, ProtocolGuess::UniswapV2, *block_number);
+                }
+            }
+        }
+    }
+
+    let mut discovered_factories = candidates.ranked_factories();
+    verify_discovered_factories(&mut discovered_factories, middleware).await?;
+
+    tracing::info!(
+        "discovered {} candidate factories",
+        discovered_factories.len()
+    );
+    Ok(discovered_factories)
+}
+
+/// Probes a sample pool of each factory for `getReserves` (V2) and `slot0` (V3) to firm up the protocol guess
+pub async fn verify_discovered_factories<M: 'static + Middleware>(
+    discovered_factories: &mut [DiscoveredFactory],
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    for chunk in discovered_factories.chunks_mut(MULTICALL_STEP / 2) {
+        let mut multicall = Multicall::new(middleware.clone(), None).await?;
+        let mut probed = vec![];
+
+        for (idx, discovered_factory) in chunk.iter().enumerate() {
+            if let Some(pool) = discovered_factory.sample_pools.first() {
+                let pool = IDiscoverablePool::new(*pool, middleware.clone());
+                multicall.add_call(pool.get_reserves(), true);
+                multicall.add_call(pool.slot_0(), true);
+                probed.push(idx);
+            }
+        }
+
+        if probed.is_empty() {
+            continue;
+        }
+
+        let results = multicall.call_raw().await?;
+        for (idx, probe) in probed.into_iter().zip(results.chunks(2)) {
+            chunk[idx].protocol_guess = match (probe[0].is_ok(), probe[1].is_ok()) {
+                (true, false) => ProtocolGuess::UniswapV2,
+                (false, true) => ProtocolGuess::UniswapV3,
+                //Keep the guess from the event signature if the probe is inconclusive
+                (true, true) => chunk[idx].protocol_guess,
+                (false, false) => ProtocolGuess::Unknown,
+            };
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        abi::Token,
+        types::{Log, H160, H256, U256, U64},
+    };
+
+    use crate::amm::uniswap_v2::{factory::PAIR_CREATED_EVENT_SIGNATURE, SYNC_EVENT_SIGNATURE};
+
+    use super::{FactoryCandidates, ProtocolGuess};
+
+    fn pair_created_log(factory: H160, pair: H160, block_number: u64) -> Log {
+        Log {
+            address: factory,
+            topics: vec![
+                PAIR_CREATED_EVENT_SIGNATURE,
+                H256::from(H160::from_low_u64_be(1000)),
+                H256::from(H160::from_low_u64_be(1001)),
+            ],
+            data: ethers::abi::encode(&[Token::Address(pair), Token::Uint(U256::one())]).into(),
+            block_number: Some(U64::from(block_number)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rank_factory_candidates() {
+        let large_factory = H160::from_low_u64_be(1);
+        let small_factory = H160::from_low_u64_be(2);
+
+        let mut candidates = FactoryCandidates::default();
+        for pool in 10..17 {
+            candidates.add_log(pair_created_log(
+                large_factory,
+                H160::from_low_u64_be(pool),
+                pool,
+            ));
+        }
+        candidates.add_log(pair_created_log(
+            small_factory,
+            H160::from_low_u64_be(20),
+            5,
+        ));
+        //Duplicate creation logs are only counted once
+        candidates.add_log(pair_created_log(
+            small_factory,
+            H160::from_low_u64_be(20),
+            5,
+        ));
+
+        //Sync events from known pools are not left for factory resolution
+        candidates.add_log(Log {
+            address: H160::from_low_u64_be(10),
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            block_number: Some(U64::from(30)),
+            ..Default::default()
+        });
+        assert!(candidates.unattributed_pools.is_empty());
+
+        let ranked = candidates.ranked_factories();
+        assert_eq!(ranked.len(), 2);
+
+        assert_eq!(ranked[0].address, large_factory);
+        assert_eq!(ranked[0].pool_count, 7);
+        assert_eq!(ranked[0].sample_pools.len(), 5);
+        assert_eq!(ranked[0].first_seen_block, 10);
+        assert_eq!(ranked[0].protocol_guess, ProtocolGuess::UniswapV2);
+
+        assert_eq!(ranked[1].address, small_factory);
+        assert_eq!(ranked[1].pool_count, 1);
+    }
+}
@@ -1,11 +1,18 @@
-use ethers::prelude::{AbiError, ContractError};
-use ethers::providers::{Middleware, ProviderError};
-use ethers::types::{H160, U256};
-use std::time::SystemTimeError;
+use ethers::prelude::{AbiError, ContractError, MulticallError};
+use ethers::providers::{JsonRpcError, Middleware, MiddlewareError, ProviderError};
+use ethers::types::{Bytes, Log, H160, H256, U256};
+use std::{
+    fmt,
+    time::{Duration, SystemTimeError},
+};
 use thiserror::Error;
+#[cfg(feature = "rpc")]
 use tokio::task::JoinError;
 use uniswap_v3_math::error::UniswapV3MathError;
 
+#[cfg(feature = "rpc")]
+use crate::amm::detect::DetectedVariant;
+
 #[derive(Error, Debug)]
 pub enum AMMError<M>
 where
@@ -21,6 +28,7 @@ where
     ABICodecError(#[from] AbiError),
     #[error("Eth ABI error")]
     EthABIError(#[from] ethers::abi::Error),
+    #[cfg(feature = "rpc")]
     #[error("Join error")]
     JoinError(#[from] JoinError),
     #[error("Serde json error")]
@@ -61,6 +69,226 @@ where
     CheckpointError(#[from] CheckpointError),
     #[error("Invalid token address")]
     InvalidTokenAddress,
+    #[error("Multicall error")]
+    MulticallError(#[from] MulticallError<M>),
+    #[error("No known factories for chain {0}")]
+    NoKnownFactories(u64),
+    #[error("Expected chain {expected}, the provider is on chain {provider}")]
+    ChainIdMismatch { expected: u64, provider: u64 },
+    #[error("Custom AMMs must be synced and populated through their AmmFactory")]
+    CustomAMMOperation,
+    #[error("No contract code at {0:?}")]
+    NoContractCode(H160),
+    #[error("Could not detect the AMM type of {0:?}")]
+    UndetectedAMM(H160),
+    #[cfg(feature = "rpc")]
+    #[error(
+        "Expected a {expected} at {address:?}, {}",
+        .detected.map_or("no known AMM was detected".to_string(), |detected| format!("found a {detected}"))
+    )]
+    AMMVariantMismatch {
+        address: H160,
+        expected: DetectedVariant,
+        detected: Option<DetectedVariant>,
+    },
+}
+
+impl<M: Middleware> AMMError<M> {
+    /// Kind of the provider failure behind the error, None for errors not raised by the provider
+    pub fn provider_error_kind(&self) -> Option<ProviderErrorKind> {
+        match self {
+            AMMError::MiddlewareError(error) => Some(ProviderErrorKind::classify(error)),
+            AMMError::ProviderError(error) => Some(ProviderErrorKind::classify(error)),
+            AMMError::ContractError(error) => contract_error_kind(error),
+            AMMError::MulticallError(MulticallError::ContractError(error)) => {
+                contract_error_kind(error)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the call can succeed if sent again, i.e. after a rate limit or a dropped connection
+    pub fn is_retryable(&self) -> bool {
+        self.provider_error_kind()
+            .is_some_and(|kind| kind.is_retryable())
+    }
+
+    /// How long the provider asked to wait before retrying, when it says so
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            AMMError::MiddlewareError(error) => retry_after(error.as_error_response()?),
+            AMMError::ProviderError(error) => retry_after(error.as_error_response()?),
+            AMMError::ContractError(ContractError::MiddlewareError { e }) => {
+                retry_after(e.as_error_response()?)
+            }
+            AMMError::ContractError(ContractError::ProviderError { e }) => {
+                retry_after(e.as_error_response()?)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn contract_error_kind<M: Middleware>(error: &ContractError<M>) -> Option<ProviderErrorKind> {
+    match error {
+        ContractError::MiddlewareError { e } => Some(ProviderErrorKind::classify(e)),
+        ContractError::ProviderError { e } => Some(ProviderErrorKind::classify(e)),
+        ContractError::Revert(data) => Some(ProviderErrorKind::Revert(data.clone())),
+        _ => None,
+    }
+}
+
+/// Why a provider request failed, from the JSON-RPC error code and the messages of common providers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProviderErrorKind {
+    /// The provider throttled the request, i.e. HTTP 429 or Infura's -32005
+    RateLimited,
+    Timeout,
+    /// The request did not reach the provider or the connection dropped before the response
+    Connection,
+    /// The call reverted, with the revert data when the provider returned it
+    Revert(Bytes),
+    /// The response exceeds the limits of the provider, i.e. an `eth_getLogs` range returning too many logs.
+    /// Sending the same request again fails again, it has to be split.
+    ResponseTooLarge,
+    Other,
+}
+
+impl ProviderErrorKind {
+    pub fn classify<E: MiddlewareError>(error: &E) -> Self {
+        match error.as_error_response() {
+            Some(response) => ProviderErrorKind::from_response(response),
+            None => ProviderErrorKind::from_message(&error.to_string()),
+        }
+    }
+
+    /// Classifies a JSON-RPC error response by its code, then by its message
+    pub fn from_response(response: &JsonRpcError) -> Self {
+        let message = response.message.to_lowercase();
+
+        //Infura answers both rate limits and oversized log queries with -32005, so the message is checked first
+        if is_response_too_large(&message) {
+            return ProviderErrorKind::ResponseTooLarge;
+        }
+
+        match response.code {
+            3 => return ProviderErrorKind::Revert(revert_data(response).unwrap_or_default()),
+            429 | -32005 | -32007 | -32090 => return ProviderErrorKind::RateLimited,
+            _ => {}
+        }
+
+        if message.contains("revert") {
+            ProviderErrorKind::Revert(revert_data(response).unwrap_or_default())
+        } else {
+            ProviderErrorKind::from_message(&message)
+        }
+    }
+
+    /// Classifies a transport error, which only has its message to go by
+    pub fn from_message(message: &str) -> Self {
+        let message = message.to_lowercase();
+
+        if is_response_too_large(&message) {
+            ProviderErrorKind::ResponseTooLarge
+        } else if [
+            "rate limit",
+            "too many requests",
+            "429",
+            "capacity",
+            "request limit",
+            "exceeded the quota",
+            "exceeds the quota",
+        ]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+        {
+            ProviderErrorKind::RateLimited
+        } else if ["timed out", "timeout", "deadline exceeded"]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+        {
+            ProviderErrorKind::Timeout
+        } else if [
+            "connection",
+            "connect",
+            "broken pipe",
+            "unexpected eof",
+            "error sending request",
+            "dns error",
+            "502 bad gateway",
+            "503 service unavailable",
+            "504 gateway",
+        ]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+        {
+            ProviderErrorKind::Connection
+        } else {
+            ProviderErrorKind::Other
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ProviderErrorKind::RateLimited
+                | ProviderErrorKind::Timeout
+                | ProviderErrorKind::Connection
+        )
+    }
+
+    /// Label of the kind, as recorded by `Metrics::rpc_retry`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderErrorKind::RateLimited => "rate_limited",
+            ProviderErrorKind::Timeout => "timeout",
+            ProviderErrorKind::Connection => "connection",
+            ProviderErrorKind::Revert(_) => "revert",
+            ProviderErrorKind::ResponseTooLarge => "response_too_large",
+            ProviderErrorKind::Other => "other",
+        }
+    }
+}
+
+//Messages of oversized eth_getLogs responses, from Infura, Alchemy, QuickNode and Ankr
+fn is_response_too_large(message: &str) -> bool {
+    [
+        "query returned more than",
+        "response size exceeded",
+        "response size should not",
+        "block range is too wide",
+        "block range too large",
+        "limited to a",
+        "is limited to",
+        "too many results",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+//Geth style providers return the revert data as a hex string, some wrap it in an object
+fn revert_data(response: &JsonRpcError) -> Option<Bytes> {
+    let data = response.data.as_ref()?;
+    let data = data.get("data").unwrap_or(data);
+
+    serde_json::from_value(data.clone()).ok()
+}
+
+//Infura returns `{"rate": {"backoff_seconds": 30}}`, other providers a top level `retry_after` or `backoff_seconds`
+fn retry_after(response: &JsonRpcError) -> Option<Duration> {
+    let data = response.data.as_ref()?;
+
+    [
+        data.get("rate")
+            .and_then(|rate| rate.get("backoff_seconds")),
+        data.get("backoff_seconds"),
+        data.get("retry_after"),
+    ]
+    .into_iter()
+    .flatten()
+    .find_map(|seconds| seconds.as_f64())
+    .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+    .map(Duration::from_secs_f64)
 }
 
 #[derive(Error, Debug)]
@@ -75,28 +303,145 @@ pub enum ArithmeticError {
     SqrtPriceOverflow,
     #[error("U128 conversion error")]
     U128ConversionError,
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Mul div overflow")]
+    MulDivOverflow,
+    #[error("Subtraction underflow")]
+    SubtractionUnderflow,
+    #[error("Reserve {0} does not fit in a uint112")]
+    ReserveOverflow(u128),
+    #[error("Reserves {0} and {1} are empty on one side only")]
+    OneSidedReserves(u128, u128),
+    #[error("Liquidity {0} exceeds i128::MAX")]
+    LiquidityOverflow(u128),
+    #[error("Sqrt price {0} is outside of [MIN_SQRT_RATIO, MAX_SQRT_RATIO)")]
+    SqrtPriceOutOfRange(U256),
+    #[error("Tick {tick} is more than one tick away from the tick of the sqrt price, {expected}")]
+    TickMismatch { tick: i32, expected: i32 },
+    #[error("Invalid price {0}")]
+    InvalidPrice(f64),
+    #[error("Invariant did not converge")]
+    InvariantNotConverged,
+    #[error("Balances or parameters are outside of the range of the crypto invariant")]
+    UnsafeInvariantInput,
+    #[error("Tokens with {0} decimals are not supported")]
+    UnsupportedDecimals(u8),
     #[error("Uniswap v3 math error")]
     UniswapV3MathError(#[from] UniswapV3MathError),
 }
 
+/// Errors decoding or applying a log. Variants built from a log carry its `LogContext`, so the offending log
+/// can be found among the logs of a sync.
 #[derive(Error, Debug)]
 pub enum EventLogError {
-    #[error("Invalid event signature")]
-    InvalidEventSignature,
-    #[error("Log Block number not found")]
-    LogBlockNumberNotFound,
+    /// The log is not one of the events the AMM or factory syncs on
+    #[error("Unexpected event, {0}")]
+    UnexpectedEvent(LogContext),
+    #[error("Log block number not found, {0}")]
+    LogBlockNumberNotFound(LogContext),
     #[error("Eth abi error")]
     EthABIError(#[from] ethers::abi::Error),
     #[error("ABI error")]
     ABIError(#[from] AbiError),
+    /// The log decodes but its values are out of range, or would corrupt the state of the AMM
+    #[error("Malformed log, {0}")]
+    MalformedLog(LogContext),
+    #[error("Missing topic {index}, {context}")]
+    MissingTopic { index: usize, context: LogContext },
+    #[error("Truncated log data, {expected} bytes expected but {actual} found, {context}")]
+    TruncatedData {
+        expected: usize,
+        actual: usize,
+        context: LogContext,
+    },
+    /// The log was emitted by another contract than the AMM it was applied to
+    #[error("Log address mismatch, expected {expected:?} but got {got:?}, {context}")]
+    LogAddressMismatch {
+        expected: H160,
+        got: H160,
+        context: LogContext,
+    },
+    /// The log was emitted by the singleton contract of the AMM for another pool
+    #[error("Pool id mismatch, expected {expected:?} but got {got:?}, {context}")]
+    PoolIdMismatch {
+        expected: H256,
+        got: H256,
+        context: LogContext,
+    },
+}
+
+impl EventLogError {
+    /// Context of the log the error was raised for, None for ABI errors
+    pub fn context(&self) -> Option<&LogContext> {
+        match self {
+            EventLogError::UnexpectedEvent(context)
+            | EventLogError::LogBlockNumberNotFound(context)
+            | EventLogError::MalformedLog(context)
+            | EventLogError::MissingTopic { context, .. }
+            | EventLogError::TruncatedData { context, .. }
+            | EventLogError::LogAddressMismatch { context, .. }
+            | EventLogError::PoolIdMismatch { context, .. } => Some(context),
+            EventLogError::EthABIError(_) | EventLogError::ABIError(_) => None,
+        }
+    }
+}
+
+/// Where a log was emitted, for locating it from an `EventLogError`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogContext {
+    pub address: H160,
+    /// Signature of the event, None for anonymous events
+    pub topic0: Option<H256>,
+    pub block_number: Option<u64>,
+    pub log_index: Option<U256>,
 }
 
+impl From<&Log> for LogContext {
+    fn from(log: &Log) -> Self {
+        LogContext {
+            address: log.address,
+            topic0: log.topics.first().copied(),
+            block_number: log.block_number.map(|block_number| block_number.as_u64()),
+            log_index: log.log_index,
+        }
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "log from {:?}", self.address)?;
+        match self.topic0 {
+            Some(topic0) => write!(f, " with topic {topic0:?}")?,
+            None => write!(f, " without topics")?,
+        }
+        if let Some(block_number) = self.block_number {
+            write!(f, " in block {block_number}")?;
+        }
+        if let Some(log_index) = self.log_index {
+            write!(f, " at index {log_index}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Errors applying a storage diff, which leave the AMM untouched. Variants built from a slot carry it, so a
+/// mis-mapped slot can be told from a bad value.
 #[derive(Error, Debug)]
-pub enum StorageError {
-    #[error("Storage slot not found")]
-    StorageSlotNotFound,
-    #[error("Empty storage slot")]
-    EmptyStorageSlot,
+pub enum StateSyncError {
+    /// The AMM is not synced from storage, only from logs
+    #[error("Storage sync is not supported by the AMM")]
+    Unsupported,
+    #[error("Empty storage slot {0:?}")]
+    EmptySlot(H256),
+    /// The slot decodes to a state the contract could not be in
+    #[error("Storage slot {slot:?} holds an invalid state")]
+    InvalidSlot {
+        slot: H256,
+        #[source]
+        source: ArithmeticError,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -107,6 +452,138 @@ pub enum SwapSimulationError {
     UniswapV3MathError(#[from] UniswapV3MathError),
     #[error("Liquidity underflow")]
     LiquidityUnderflow,
+    #[error("Gradient overflow")]
+    GradientOverflow,
+    #[error("Token {0:?} is not in the pool")]
+    TokenNotInPool(H160),
+    #[error("Token out must be given to swap {0:?} on a pool holding more than two tokens")]
+    TokenOutRequired(H160),
+    #[error("No RPC backed quote available for AMM {0:?}")]
+    RpcQuoteUnavailable(H160),
+    #[error("No AMM found for hop {0}")]
+    AmmNotFound(usize),
+    #[error("Swap {step} of the bundle failed")]
+    BundleStep {
+        step: usize,
+        #[source]
+        source: Box<SwapSimulationError>,
+    },
+    #[error("Hop {hop} of the route failed")]
+    RouteHop {
+        hop: usize,
+        #[source]
+        source: Box<SwapSimulationError>,
+    },
+    #[error("Output {actual} is below the minimum output of {expected_min}")]
+    InsufficientOutput { expected_min: U256, actual: U256 },
+    #[error("Arithmetic error")]
+    ArithmeticError(#[from] ArithmeticError),
+    #[error("Amount in exceeds the share of the {0:?} balance a weighted pool accepts in a swap")]
+    MaxInRatioExceeded(H160),
+    #[error("Hooks {0:?} of the pool can change the amounts or the fee of its swaps")]
+    UnsupportedHooks(H160),
+    #[error("Pair {0:?} prices its swaps on the stable curve")]
+    UnsupportedStableSwap(H160),
+    #[error("The bins of the pool that were read can not fill the swap")]
+    BinsExhausted,
+    #[error("The ticks of the pool that were read can not fill the swap")]
+    TicksExhausted,
+    #[error("Trading is disabled on the pool of {0:?}")]
+    TradingDisabled(H160),
+}
+
+impl SwapSimulationError {
+    /// Passes `actual` through, or fails with `InsufficientOutput` like a router reverting when it is below
+    /// `expected_min`
+    pub fn check_output(actual: U256, expected_min: U256) -> Result<U256, SwapSimulationError> {
+        if actual < expected_min {
+            return Err(SwapSimulationError::InsufficientOutput {
+                expected_min,
+                actual,
+            });
+        }
+
+        Ok(actual)
+    }
+
+    /// Amount the output fell short of the minimum by, looking through bundle steps and route hops
+    pub fn shortfall(&self) -> Option<U256> {
+        match self {
+            SwapSimulationError::InsufficientOutput {
+                expected_min,
+                actual,
+            } => Some(*expected_min - *actual),
+            SwapSimulationError::BundleStep { source, .. }
+            | SwapSimulationError::RouteHop { source, .. } => source.shortfall(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RouteError {
+    #[error("Route has no hops")]
+    EmptyRoute,
+    #[error("No AMM found for hop {0}")]
+    AmmNotFound(usize),
+    #[error("AMM of hop {hop} does not trade {token_in:?} for {token_out:?}")]
+    InvalidHop {
+        hop: usize,
+        token_in: H160,
+        token_out: H160,
+    },
+    #[error("AMM of hop {0} can not be swapped through the router")]
+    UnsupportedHop(usize),
+    #[error("No route connects the tokens")]
+    NoRoute,
+    #[error("Every route connecting the tokens breaks the route constraints")]
+    ConstraintsUnsatisfied,
+    #[error("Arithmetic error")]
+    ArithmeticError(#[from] ArithmeticError),
+    #[error("Swap simulation error")]
+    SwapSimulationError(#[from] SwapSimulationError),
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum PoolBuildError {
+    #[error("Token a and token b are both {0:?}")]
+    IdenticalTokens(H160),
+    #[error("Token decimals {0} exceed the maximum of 36")]
+    InvalidDecimals(u8),
+    #[error("Fee {0} is out of range")]
+    InvalidFee(u32),
+    #[error("Transfer tax of {0} bps is out of range")]
+    InvalidTransferTax(u32),
+    #[error("Amount {0} does not fit in a u128 once scaled by the token decimals")]
+    InvalidAmount(f64),
+    #[error("Reserve {0} does not fit in a uint112")]
+    ReserveOverflow(u128),
+    #[error("Reserves {0} and {1} are empty on one side only")]
+    OneSidedReserves(u128, u128),
+    #[error("Liquidity {0} exceeds i128::MAX")]
+    LiquidityOverflow(u128),
+    #[error("Invalid price {0}")]
+    InvalidPrice(f64),
+    #[error("Sqrt price {0} is outside of [MIN_SQRT_RATIO, MAX_SQRT_RATIO)")]
+    SqrtPriceOutOfRange(U256),
+    #[error("Tick {0} is outside of [MIN_TICK, MAX_TICK]")]
+    InvalidTick(i32),
+    #[error("Tick {tick} does not match the sqrt price, which is at tick {expected}")]
+    TickMismatch { tick: i32, expected: i32 },
+    #[error("Tick spacing {0} is not positive")]
+    InvalidTickSpacing(i32),
+    #[error("No standard tick spacing for fee {0}, it must be given")]
+    MissingTickSpacing(u32),
+    #[error("Position from tick {tick_lower} to {tick_upper} is not a valid range of initializable ticks")]
+    InvalidPosition { tick_lower: i32, tick_upper: i32 },
+}
+
+#[derive(Error, Debug)]
+pub enum QuoteError {
+    #[error("Route error")]
+    RouteError(#[from] RouteError),
+    #[error("The quoter has stopped")]
+    QuoterStopped,
 }
 
 #[derive(Error, Debug)]
@@ -117,4 +594,173 @@ pub enum CheckpointError {
     SerdeJsonError(#[from] serde_json::error::Error),
     #[error("IO error")]
     IOError(#[from] std::io::Error),
+    #[error("No checkpoint has been written to the store")]
+    NotFound,
+    #[error("Checkpoint store error")]
+    Store(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Checkpoint was synced on chain {checkpoint}, the provider is on chain {provider}")]
+    ChainIdMismatch { checkpoint: u64, provider: u64 },
+    #[error("Bincode error")]
+    BincodeError(#[from] bincode::Error),
+    #[error("MessagePack encode error")]
+    MessagePackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decode error")]
+    MessagePackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("Checkpoint version {0} is not supported by this version of the crate")]
+    UnsupportedVersion(u32),
+}
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Serde json error")]
+    SerdeJsonError(#[from] serde_json::error::Error),
+    #[error("IO error")]
+    IOError(#[from] std::io::Error),
+    #[error("Unknown protocol {0}")]
+    UnknownProtocol(String),
+}
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Serde json error")]
+    SerdeJsonError(#[from] serde_json::error::Error),
+    #[error("IO error")]
+    IOError(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        providers::{Http, HttpClientError, JsonRpcError, Provider, ProviderError},
+        types::Bytes,
+    };
+    use serde_json::json;
+    use std::time::Duration;
+
+    use super::{AMMError, ProviderErrorKind};
+
+    type Error = AMMError<Provider<Http>>;
+
+    fn response_error(code: i64, message: &str, data: Option<serde_json::Value>) -> Error {
+        AMMError::ProviderError(ProviderError::JsonRpcClientError(Box::new(
+            HttpClientError::JsonRpcError(JsonRpcError {
+                code,
+                message: message.to_string(),
+                data,
+            }),
+        )))
+    }
+
+    #[test]
+    fn test_provider_error_kind() {
+        let kind = |error: &Error| error.provider_error_kind().unwrap();
+
+        //Infura
+        let infura_rate_limit = response_error(
+            -32005,
+            "daily request count exceeded, request rate limited",
+            Some(
+                json!({ "rate": { "allowed_rps": 1, "backoff_seconds": 30, "current_rps": 1.4 } }),
+            ),
+        );
+        assert_eq!(kind(&infura_rate_limit), ProviderErrorKind::RateLimited);
+        assert!(infura_rate_limit.is_retryable());
+        assert_eq!(
+            infura_rate_limit.retry_after(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            kind(&response_error(
+                -32005,
+                "query returned more than 10000 results",
+                None
+            )),
+            ProviderErrorKind::ResponseTooLarge
+        );
+
+        //Alchemy
+        let alchemy_rate_limit = response_error(
+            429,
+            "Your app has exceeded its compute units per second capacity.",
+            None,
+        );
+        assert_eq!(kind(&alchemy_rate_limit), ProviderErrorKind::RateLimited);
+        assert_eq!(alchemy_rate_limit.retry_after(), None);
+        assert_eq!(
+            kind(&response_error(
+                -32602,
+                "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range.",
+                None
+            )),
+            ProviderErrorKind::ResponseTooLarge
+        );
+
+        //QuickNode and Ankr
+        assert_eq!(
+            kind(&response_error(-32007, "request limit reached", None)),
+            ProviderErrorKind::RateLimited
+        );
+        assert_eq!(
+            kind(&response_error(
+                -32000,
+                "eth_getLogs is limited to a 10,000 range",
+                None
+            )),
+            ProviderErrorKind::ResponseTooLarge
+        );
+        assert_eq!(
+            kind(&response_error(-32600, "block range is too wide", None)),
+            ProviderErrorKind::ResponseTooLarge
+        );
+
+        //Geth reverts, with and without data
+        let revert = response_error(3, "execution reverted", Some(json!("0x08c379a0")));
+        assert_eq!(
+            kind(&revert),
+            ProviderErrorKind::Revert(Bytes::from(vec![0x08, 0xc3, 0x79, 0xa0]))
+        );
+        assert!(!revert.is_retryable());
+        assert_eq!(
+            kind(&response_error(-32000, "execution reverted", None)),
+            ProviderErrorKind::Revert(Bytes::default())
+        );
+
+        assert_eq!(
+            kind(&response_error(
+                -32601,
+                "the method eth_foo does not exist",
+                None
+            )),
+            ProviderErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn test_transport_error_kind() {
+        let kind = |message: &str| {
+            Error::ProviderError(ProviderError::CustomError(message.to_string()))
+                .provider_error_kind()
+                .unwrap()
+        };
+
+        assert_eq!(
+            kind("error sending request for url (http://localhost:8545/): error trying to connect: tcp connect error: Connection refused (os error 111)"),
+            ProviderErrorKind::Connection
+        );
+        assert_eq!(
+            kind("error sending request for url (https://rpc.ankr.com/eth): operation timed out"),
+            ProviderErrorKind::Timeout
+        );
+        assert_eq!(
+            kind("Deserialization Error: expected value at line 1 column 1. Response: 429 Too Many Requests"),
+            ProviderErrorKind::RateLimited
+        );
+        assert_eq!(
+            kind("Deserialization Error: expected value at line 1 column 1. Response: 502 Bad Gateway"),
+            ProviderErrorKind::Connection
+        );
+        assert_eq!(kind("ens name not found"), ProviderErrorKind::Other);
+
+        assert!(Error::PoolDataError.provider_error_kind().is_none());
+    }
 }
@@ -0,0 +1,581 @@
+//! Flat exports of synced pools for analytics, one row per pool and token pair.
+//!
+//! Rows are written as they are built, so exporting is constant in memory however many pools are passed. Pass a
+//! buffered writer, i.e. `BufWriter<File>`, since every row is a separate write.
+
+use std::io::{BufRead, Write};
+
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{
+        algebra::{AlgebraPool, TICK_SPACING},
+        balancer::BalancerWeightedPool,
+        curve::CurveStableSwapPool,
+        curve_crypto::CurveCryptoPool,
+        dodo::DodoPool,
+        erc_4626::ERC4626Vault,
+        kyber_elastic::KyberElasticPool,
+        liquidity_book::LiquidityBookPool,
+        maverick::MaverickPool,
+        uniswap_v2::UniswapV2Pool,
+        uniswap_v3::UniswapV3Pool,
+        velodrome::VelodromePool,
+        AmmState, AMM,
+    },
+    errors::ExportError,
+    filters::dedupe::Protocol,
+    math::fixed_point::u256_to_f64_lossy,
+    state_space::{
+        price::{pair_price, pool_depth},
+        DepthWeighting,
+    },
+    tokens::TokenStore,
+};
+
+const CSV_HEADER: &str = "protocol,address,token0,token1,token0_symbol,token1_symbol,token0_decimals,token1_decimals,fee_bps,mid_price,depth0,depth1";
+
+/// Pool and token pair exported by `write_jsonl` and `write_csv`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportRow {
+    pub protocol: String,
+    pub address: H160,
+    pub token0: H160,
+    pub token1: H160,
+    pub token0_symbol: Option<String>,
+    pub token1_symbol: Option<String>,
+    pub token0_decimals: Option<u8>,
+    pub token1_decimals: Option<u8>,
+    /// Swap fee in basis points, None for vaults and custom AMMs
+    pub fee_bps: Option<f64>,
+    /// Price of whole `token0` in whole `token1`, from `calculate_price` or the marginal rate of the pair for Curve
+    /// and Balancer pools
+    pub mid_price: Option<f64>,
+    /// Depth of the pool valued in raw units of `token0`, see `aggregate_price`
+    pub depth0: Option<f64>,
+    /// Depth of the pool valued in raw units of `token1`
+    pub depth1: Option<f64>,
+}
+
+impl ExportRow {
+    /// Rows of `amm`, one per pair of its tokens, with the symbols and decimals of `token_store` when it has them
+    pub fn from_amm(amm: &AMM, token_store: Option<&TokenStore>) -> Vec<ExportRow> {
+        let tokens = amm.tokens();
+        let tick_range = DepthWeighting::default().tick_range;
+
+        let mut rows = vec![];
+        for (i, token0) in tokens.iter().enumerate() {
+            for token1 in tokens.iter().skip(i + 1) {
+                let (token0_symbol, token0_decimals) = token_metadata(amm, *token0, token_store);
+                let (token1_symbol, token1_decimals) = token_metadata(amm, *token1, token_store);
+
+                rows.push(ExportRow {
+                    protocol: Protocol::of(amm).name().to_owned(),
+                    address: amm.address(),
+                    token0: *token0,
+                    token1: *token1,
+                    token0_symbol,
+                    token1_symbol,
+                    token0_decimals,
+                    token1_decimals,
+                    fee_bps: fee_bps(amm),
+                    mid_price: finite(pair_price(amm, *token0, *token1).ok()),
+                    depth0: finite(pool_depth(amm, *token0, tick_range)),
+                    depth1: finite(pool_depth(amm, *token1, tick_range)),
+                });
+            }
+        }
+
+        rows
+    }
+
+    /// Skeleton AMM with the address, protocol and tokens of the row, to be populated by address.
+    /// None for custom AMMs, which can only be rebuilt through their factory, and for Uniswap V4 pools, whose key
+    /// is only known from their `Initialize` log.
+    pub fn to_skeleton_amm(&self) -> Result<Option<AMM>, ExportError> {
+        let protocol = match Protocol::from_name(&self.protocol) {
+            Some(protocol) => protocol,
+            None => return Err(ExportError::UnknownProtocol(self.protocol.clone())),
+        };
+
+        let fee = |denominator: f64| {
+            self.fee_bps
+                .map(|fee_bps| (fee_bps * denominator).round() as u32)
+                .unwrap_or_default()
+        };
+
+        Ok(match protocol {
+            Protocol::UniswapV2 => Some(AMM::UniswapV2Pool(UniswapV2Pool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                fee: fee(10.0),
+                ..Default::default()
+            })),
+            Protocol::UniswapV3 => Some(AMM::UniswapV3Pool(UniswapV3Pool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                fee: fee(100.0),
+                ..Default::default()
+            })),
+            Protocol::ERC4626 => Some(AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: self.address,
+                vault_token_decimals: self.token0_decimals.unwrap_or_default(),
+                asset_token: self.token1,
+                asset_token_decimals: self.token1_decimals.unwrap_or_default(),
+                ..Default::default()
+            })),
+            Protocol::CurveStableSwap => Some(AMM::CurveStableSwapPool(CurveStableSwapPool {
+                address: self.address,
+                coins: vec![self.token0, self.token1],
+                decimals: vec![
+                    self.token0_decimals.unwrap_or_default(),
+                    self.token1_decimals.unwrap_or_default(),
+                ],
+                fee: fee(1_000_000.0) as u64,
+                ..Default::default()
+            })),
+            Protocol::BalancerWeighted => Some(AMM::BalancerWeightedPool(BalancerWeightedPool {
+                address: self.address,
+                tokens: vec![self.token0, self.token1],
+                decimals: vec![
+                    self.token0_decimals.unwrap_or_default(),
+                    self.token1_decimals.unwrap_or_default(),
+                ],
+                swap_fee: U256::from(fee(100.0)) * U256::exp10(12),
+                ..Default::default()
+            })),
+            //Whether the pool is stable is not exported, it is read back when the pool is populated
+            Protocol::Velodrome => Some(AMM::VelodromePool(VelodromePool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                fee: fee(1.0),
+                ..Default::default()
+            })),
+            //The bin step and the fee parameters are not exported, they are read back when the pool is populated
+            Protocol::LiquidityBook => Some(AMM::LiquidityBookPool(LiquidityBookPool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                ..Default::default()
+            })),
+            Protocol::Maverick => Some(AMM::MaverickPool(MaverickPool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                fee: (self.fee_bps.unwrap_or_default() * 1e14).round() as u64,
+                ..Default::default()
+            })),
+            //The PMM state and the fee rates are not exported, they are read back when the pool is populated
+            Protocol::Dodo => Some(AMM::DodoPool(DodoPool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                ..Default::default()
+            })),
+            //The tick distance, the price, the liquidity and the ticks are not exported, they are read back when the
+            //pool is populated
+            Protocol::KyberElastic => Some(AMM::KyberElasticPool(KyberElasticPool {
+                address: self.address,
+                token_a: self.token0,
+                token_a_decimals: self.token0_decimals.unwrap_or_default(),
+                token_b: self.token1,
+                token_b_decimals: self.token1_decimals.unwrap_or_default(),
+                fee: fee(10.0),
+                ..Default::default()
+            })),
+            //The fee is read back with the price and liquidity when the pool is populated
+            Protocol::Algebra => Some(AMM::AlgebraPool(AlgebraPool::new(
+                self.address,
+                self.token0,
+                self.token0_decimals.unwrap_or_default(),
+                self.token1,
+                self.token1_decimals.unwrap_or_default(),
+                fee(100.0),
+                TICK_SPACING,
+            ))),
+            //The other coin, the invariant parameters and the price scale are not exported, they are read back when
+            //the pool is populated
+            Protocol::CurveCrypto => Some(AMM::CurveCryptoPool(CurveCryptoPool {
+                address: self.address,
+                coins: vec![self.token0, self.token1],
+                decimals: vec![
+                    self.token0_decimals.unwrap_or_default(),
+                    self.token1_decimals.unwrap_or_default(),
+                ],
+                mid_fee: fee(1_000_000.0) as u64,
+                ..Default::default()
+            })),
+            //The network Bancor V3 pools live in is not exported
+            Protocol::UniswapV4 | Protocol::BancorV3 | Protocol::Custom => None,
+        })
+    }
+
+    fn write_csv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let fields = [
+            self.protocol.clone(),
+            format!("{:?}", self.address),
+            format!("{:?}", self.token0),
+            format!("{:?}", self.token1),
+            self.token0_symbol.clone().unwrap_or_default(),
+            self.token1_symbol.clone().unwrap_or_default(),
+            optional_field(self.token0_decimals),
+            optional_field(self.token1_decimals),
+            optional_field(self.fee_bps),
+            optional_field(self.mid_price),
+            optional_field(self.depth0),
+            optional_field(self.depth1),
+        ];
+
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",")?;
+            }
+            writer.write_all(csv_escape(field).as_bytes())?;
+        }
+        writer.write_all(b"\n")
+    }
+}
+
+//Symbol and decimals of `token`, falling back to the symbol and decimals stored in the AMM
+fn token_metadata(
+    amm: &AMM,
+    token: H160,
+    token_store: Option<&TokenStore>,
+) -> (Option<String>, Option<u8>) {
+    if let Some(info) = token_store.and_then(|token_store| token_store.get(token)) {
+        return (info.symbol, Some(info.decimals));
+    }
+
+    let decimals = match amm {
+        AMM::UniswapV2Pool(pool) if pool.token_a == token => Some(pool.token_a_decimals),
+        AMM::UniswapV2Pool(pool) if pool.token_b == token => Some(pool.token_b_decimals),
+        AMM::UniswapV3Pool(pool) if pool.token_a == token => Some(pool.token_a_decimals),
+        AMM::UniswapV3Pool(pool) if pool.token_b == token => Some(pool.token_b_decimals),
+        AMM::ERC4626Vault(vault) if vault.vault_token == token => Some(vault.vault_token_decimals),
+        AMM::ERC4626Vault(vault) if vault.asset_token == token => Some(vault.asset_token_decimals),
+        AMM::CurveStableSwapPool(pool) => pool.coin_decimals(token),
+        AMM::BalancerWeightedPool(pool) => pool.token_decimals(token),
+        AMM::VelodromePool(pool) => pool.token_decimals(token),
+        AMM::UniswapV4Pool(pool) => pool.token_decimals(token),
+        AMM::LiquidityBookPool(pool) => pool.token_decimals(token),
+        AMM::MaverickPool(pool) => pool.token_decimals(token),
+        AMM::DodoPool(pool) => pool.token_decimals(token),
+        AMM::KyberElasticPool(pool) => pool.token_decimals(token),
+        AMM::AlgebraPool(pool) => pool.token_decimals(token),
+        AMM::CurveCryptoPool(pool) => pool.coin_decimals(token),
+        AMM::BancorV3Pool(pool) => pool.token_decimals(token),
+        _ => None,
+    };
+
+    (amm.token_symbol(token).map(str::to_owned), decimals)
+}
+
+//V2 fees are in thousandths of a percent, V3 and V4 fees in hundredths of a basis point, Curve fees over 1e10, Balancer
+//fees over 1e18, Velodrome fees in basis points, Liquidity Book fees over 1e18, of which the current total is exported,
+//Maverick fees over 1e18, DODO fees over 1e18, of which the LP and maintainer fees of the zero address are exported,
+//KyberSwap Elastic fees in tenths of a basis point, Algebra fees in hundredths of a basis point, of which the fee
+//of swaps of token a for token b is exported, Curve crypto fees over 1e10, of which the mid fee is exported, and
+//Bancor V3 fees over 1e6
+fn fee_bps(amm: &AMM) -> Option<f64> {
+    match amm {
+        AMM::UniswapV2Pool(pool) => Some(pool.fee as f64 / 10.0),
+        AMM::UniswapV3Pool(pool) => Some(pool.fee as f64 / 100.0),
+        AMM::CurveStableSwapPool(pool) => Some(pool.fee as f64 / 1_000_000.0),
+        AMM::BalancerWeightedPool(pool) => Some(u256_to_f64_lossy(pool.swap_fee) / 1e14),
+        AMM::VelodromePool(pool) => Some(pool.fee as f64),
+        AMM::UniswapV4Pool(pool) => Some(pool.pool.fee as f64 / 100.0),
+        AMM::LiquidityBookPool(pool) => Some(pool.total_fee() as f64 / 1e14),
+        AMM::MaverickPool(pool) => Some(pool.fee as f64 / 1e14),
+        AMM::DodoPool(pool) => Some(u256_to_f64_lossy(pool.total_fee()) / 1e14),
+        AMM::KyberElasticPool(pool) => Some(pool.fee as f64 / 10.0),
+        AMM::AlgebraPool(pool) => Some(pool.pool.fee as f64 / 100.0),
+        AMM::CurveCryptoPool(pool) => Some(pool.mid_fee as f64 / 1_000_000.0),
+        AMM::BancorV3Pool(pool) => Some(pool.trading_fee_ppm as f64 / 100.0),
+        AMM::ERC4626Vault(_) | AMM::Custom(_) => None,
+    }
+}
+
+fn finite(value: Option<f64>) -> Option<f64> {
+    value.filter(|value| value.is_finite())
+}
+
+fn optional_field<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+//Quotes fields containing a separator, a quote or a line break, doubling the quotes they contain
+fn csv_escape(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Writes the rows of `amms` to `writer` as JSON Lines, returning the number of rows written
+pub fn write_jsonl<'a, W: Write>(
+    amms: impl IntoIterator<Item = &'a AMM>,
+    writer: W,
+) -> Result<usize, ExportError> {
+    write_jsonl_with_token_store(amms, None, writer)
+}
+
+/// Same as `write_jsonl`, taking the symbols and decimals of the tokens from `token_store` when it has them
+pub fn write_jsonl_with_token_store<'a, W: Write>(
+    amms: impl IntoIterator<Item = &'a AMM>,
+    token_store: Option<&TokenStore>,
+    mut writer: W,
+) -> Result<usize, ExportError> {
+    let mut rows = 0;
+    for amm in amms {
+        for row in ExportRow::from_amm(amm, token_store) {
+            serde_json::to_writer(&mut writer, &row)?;
+            writer.write_all(b"\n")?;
+            rows += 1;
+        }
+    }
+    writer.flush()?;
+
+    Ok(rows)
+}
+
+/// Writes the rows of `amms` to `writer` as CSV with a header, returning the number of rows written
+pub fn write_csv<'a, W: Write>(
+    amms: impl IntoIterator<Item = &'a AMM>,
+    writer: W,
+) -> Result<usize, ExportError> {
+    write_csv_with_token_store(amms, None, writer)
+}
+
+/// Same as `write_csv`, taking the symbols and decimals of the tokens from `token_store` when it has them
+pub fn write_csv_with_token_store<'a, W: Write>(
+    amms: impl IntoIterator<Item = &'a AMM>,
+    token_store: Option<&TokenStore>,
+    mut writer: W,
+) -> Result<usize, ExportError> {
+    writeln!(writer, "{CSV_HEADER}")?;
+
+    let mut rows = 0;
+    for amm in amms {
+        for row in ExportRow::from_amm(amm, token_store) {
+            row.write_csv(&mut writer)?;
+            rows += 1;
+        }
+    }
+    writer.flush()?;
+
+    Ok(rows)
+}
+
+/// Reads the rows written by `write_jsonl` back into skeleton AMMs, see `ExportRow::to_skeleton_amm`.
+/// Rows of the same pool are expected to be consecutive, as they are written, and custom AMMs are skipped.
+pub fn read_jsonl<R: BufRead>(reader: R) -> Result<Vec<AMM>, ExportError> {
+    let mut amms: Vec<AMM> = vec![];
+    let mut last_address = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let row: ExportRow = serde_json::from_str(&line)?;
+        if last_address == Some(row.address) {
+            continue;
+        }
+        last_address = Some(row.address);
+
+        if let Some(amm) = row.to_skeleton_amm()? {
+            amms.push(amm);
+        }
+    }
+
+    Ok(amms)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Log, H160, H256, U256};
+
+    use crate::{
+        amm::{
+            custom::{CustomAMM, CustomAutomatedMarketMaker},
+            uniswap_v2::UniswapV2Pool,
+            uniswap_v3::UniswapV3Pool,
+            AmmState, AMM,
+        },
+        errors::{ArithmeticError, EventLogError, SwapSimulationError},
+        tokens::{TokenInfo, TokenStore},
+    };
+
+    use super::{read_jsonl, write_csv, write_jsonl, write_jsonl_with_token_store, ExportRow};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../benches/fixtures/uniswap_v3_pool.json");
+
+    //Three token pool exporting a row per pair
+    #[derive(Debug, Clone)]
+    struct TriPool;
+
+    impl AmmState for TriPool {
+        fn address(&self) -> H160 {
+            H160::from_low_u64_be(300)
+        }
+
+        fn sync_on_event_signatures(&self) -> Vec<H256> {
+            vec![]
+        }
+
+        fn tokens(&self) -> Vec<H160> {
+            (1..=3).map(H160::from_low_u64_be).collect()
+        }
+
+        fn calculate_price(&self, _base_token: H160) -> Result<f64, ArithmeticError> {
+            Ok(1.0)
+        }
+
+        fn sync_from_log(&mut self, _log: &Log) -> Result<(), EventLogError> {
+            Ok(())
+        }
+
+        fn simulate_swap(&self, _: H160, amount_in: U256) -> Result<U256, SwapSimulationError> {
+            Ok(amount_in)
+        }
+
+        fn simulate_swap_mut(
+            &mut self,
+            _: H160,
+            amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            Ok(amount_in)
+        }
+
+        fn get_token_out(&self, token_in: H160) -> H160 {
+            token_in
+        }
+
+        fn opp_token(&self, _token: H160) -> Option<H160> {
+            None
+        }
+    }
+
+    impl CustomAutomatedMarketMaker for TriPool {
+        fn protocol(&self) -> &'static str {
+            "tri_pool"
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomAutomatedMarketMaker> {
+            Box::new(self.clone())
+        }
+
+        fn serialize_state(&self) -> Result<String, serde_json::Error> {
+            Ok("{}".to_owned())
+        }
+    }
+
+    fn amms() -> eyre::Result<Vec<AMM>> {
+        let v3_pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let (usdc, weth) = (v3_pool.token_a, v3_pool.token_b);
+
+        Ok(vec![
+            AMM::UniswapV3Pool(v3_pool),
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(100),
+                token_a: usdc,
+                token_a_decimals: 6,
+                token_b: weth,
+                token_b_decimals: 18,
+                reserve_0: 2 * 10_u128.pow(12),
+                reserve_1: 10_u128.pow(21),
+                fee: 300,
+                ..Default::default()
+            }),
+            AMM::Custom(CustomAMM::new(TriPool)),
+        ])
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() -> eyre::Result<()> {
+        let amms = amms()?;
+
+        let mut jsonl = vec![];
+        assert_eq!(write_jsonl(&amms, &mut jsonl)?, 5);
+
+        let rows = std::str::from_utf8(&jsonl)?
+            .lines()
+            .map(serde_json::from_str::<ExportRow>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rows[1].fee_bps, Some(30.0));
+        assert_eq!(rows[1].token0_decimals, Some(6));
+        assert!((rows[1].mid_price.expect("V2 pool is priced") - 0.0005).abs() < 1e-9);
+        assert_eq!(rows[1].depth1, Some(2e21));
+        assert_eq!(
+            rows.iter().filter(|row| row.protocol == "custom").count(),
+            3
+        );
+
+        let skeletons = read_jsonl(jsonl.as_slice())?;
+        assert_eq!(skeletons.len(), 2);
+        for (skeleton, amm) in skeletons.iter().zip(amms.iter()) {
+            assert_eq!(skeleton.address(), amm.address());
+            assert_eq!(skeleton.tokens(), amm.tokens());
+        }
+        match (&skeletons[1], &amms[1]) {
+            (AMM::UniswapV2Pool(skeleton), AMM::UniswapV2Pool(pool)) => {
+                assert_eq!(skeleton.fee, pool.fee);
+                assert_eq!(skeleton.reserve_0, 0);
+            }
+            _ => panic!("V2 pool is rebuilt as a V2 pool"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_escapes_symbols() -> eyre::Result<()> {
+        let amms = amms()?;
+        let token_store = TokenStore::new();
+        token_store.insert(
+            amms[1].tokens()[0],
+            TokenInfo::new(6, Some("US\"D,C".to_owned()), None),
+        );
+
+        let mut csv = vec![];
+        assert_eq!(write_csv(&amms[..2], &mut csv)?, 2);
+        let csv = String::from_utf8(csv)?;
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.starts_with("protocol,address,token0,token1,"));
+
+        let mut jsonl = vec![];
+        write_jsonl_with_token_store(&amms[1..2], Some(&token_store), &mut jsonl)?;
+        let row: ExportRow = serde_json::from_slice(&jsonl)?;
+        assert_eq!(row.token0_symbol.as_deref(), Some("US\"D,C"));
+
+        let mut csv = vec![];
+        super::write_csv_with_token_store(&amms[1..2], Some(&token_store), &mut csv)?;
+        let line = String::from_utf8(csv)?
+            .lines()
+            .nth(1)
+            .unwrap_or_default()
+            .to_owned();
+        assert!(line.contains(",\"US\"\"D,C\","));
+        assert_eq!(line.matches(',').count(), 12);
+
+        Ok(())
+    }
+}
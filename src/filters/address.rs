@@ -1,6 +1,105 @@
-use crate::amm::{AutomatedMarketMaker, AMM};
-use ethers::types::H160;
-use std::collections::HashSet;
+use crate::{
+    amm::{AmmState, AMM},
+    errors::FilterError,
+};
+use async_trait::async_trait;
+use ethers::{providers::Middleware, types::H160};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::read_to_string,
+    path::Path,
+};
+
+use super::pipeline::{AmmFilter, DroppedAmm, FilterCost};
+
+/// Blacklist of token and pool addresses that can be persisted to a JSON file and extended at runtime
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlacklistFilter {
+    #[serde(default)]
+    pub tokens: BTreeSet<H160>,
+    #[serde(default)]
+    pub pools: BTreeSet<H160>,
+}
+
+impl BlacklistFilter {
+    pub fn new(tokens: Vec<H160>, pools: Vec<H160>) -> Self {
+        BlacklistFilter {
+            tokens: tokens.into_iter().collect(),
+            pools: pools.into_iter().collect(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FilterError> {
+        Ok(serde_json::from_str(&read_to_string(path)?)?)
+    }
+
+    /// Loads the blacklist at `path`, or an empty blacklist if the file does not exist yet
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self, FilterError> {
+        if path.as_ref().exists() {
+            BlacklistFilter::load(path)
+        } else {
+            Ok(BlacklistFilter::default())
+        }
+    }
+
+    /// Writes the blacklist to `path`, addresses are deduplicated and sorted so the file diffs cleanly
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), FilterError> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Returns true if the token was not blacklisted yet
+    pub fn blacklist_token(&mut self, token: H160) -> bool {
+        self.tokens.insert(token)
+    }
+
+    /// Returns true if the pool was not blacklisted yet
+    pub fn blacklist_pool(&mut self, pool: H160) -> bool {
+        self.pools.insert(pool)
+    }
+
+    pub fn merge(&mut self, other: BlacklistFilter) {
+        self.tokens.extend(other.tokens);
+        self.pools.extend(other.pools);
+    }
+
+    /// Returns true if the AMM is blacklisted or contains a blacklisted token
+    pub fn is_blacklisted(&self, amm: &AMM) -> bool {
+        self.pools.contains(&amm.address())
+            || amm.tokens().iter().any(|token| self.tokens.contains(token))
+    }
+
+    pub fn filter(&self, amms: Vec<AMM>) -> Vec<AMM> {
+        amms.into_iter()
+            .filter(|amm| !self.is_blacklisted(amm))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<M: 'static + Middleware> AmmFilter<M> for BlacklistFilter {
+    fn name(&self) -> &str {
+        "blacklist"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Local
+    }
+
+    fn check(&self, amm: &AMM) -> Option<DroppedAmm> {
+        if self.pools.contains(&amm.address()) {
+            Some(DroppedAmm::new(amm.address(), "blacklisted pool"))
+        } else {
+            amm.tokens()
+                .into_iter()
+                .find(|token| self.tokens.contains(token))
+                .map(|token| {
+                    DroppedAmm::new(amm.address(), format!("blacklisted token {:?}", token))
+                })
+        }
+    }
+}
 
 //Filters out AMMs that contain a blacklisted token
 pub fn filter_blacklisted_tokens(amms: Vec<AMM>, blacklisted_addresses: Vec<H160>) -> Vec<AMM> {
@@ -66,3 +165,59 @@ pub fn filter_blacklisted_addresses(amms: Vec<AMM>, blacklisted_addresses: Vec<H
 
     filtered_amms
 }
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::H160;
+
+    use crate::amm::{uniswap_v2::UniswapV2Pool, AMM};
+
+    use super::BlacklistFilter;
+
+    fn pool(address: u64, token_a: u64, token_b: u64) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: H160::from_low_u64_be(token_a),
+            token_b: H160::from_low_u64_be(token_b),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_blacklist_filter() {
+        let mut blacklist = BlacklistFilter::new(vec![H160::from_low_u64_be(1)], vec![]);
+        assert!(blacklist.blacklist_pool(H160::from_low_u64_be(20)));
+        assert!(!blacklist.blacklist_token(H160::from_low_u64_be(1)));
+
+        let amms = blacklist.filter(vec![pool(10, 1, 2), pool(20, 3, 4), pool(30, 3, 4)]);
+        assert_eq!(amms.len(), 1);
+        assert!(!blacklist.is_blacklisted(&amms[0]));
+    }
+
+    #[test]
+    fn test_blacklist_persistence() -> eyre::Result<()> {
+        let path = std::env::temp_dir().join(format!("blacklist-{}.json", std::process::id()));
+
+        let mut blacklist = BlacklistFilter::load_or_default(&path)?;
+        assert_eq!(blacklist, BlacklistFilter::default());
+
+        blacklist.blacklist_token(H160::from_low_u64_be(2));
+        //Runtime additions merge with the existing entries without duplicating them
+        blacklist.merge(BlacklistFilter::new(
+            vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)],
+            vec![H160::from_low_u64_be(10)],
+        ));
+        blacklist.save(&path)?;
+
+        let loaded = BlacklistFilter::load(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(loaded, blacklist);
+        assert_eq!(
+            loaded.tokens.into_iter().collect::<Vec<H160>>(),
+            vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)]
+        );
+
+        Ok(())
+    }
+}
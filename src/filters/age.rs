@@ -0,0 +1,257 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{BlockId, BlockNumber, H160, U64},
+};
+use futures::future::try_join_all;
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::pipeline::{AmmFilter, DroppedAmm, FilterCost, FilterOutcome};
+
+//Number of creation block lookups run concurrently, each one makes around 25 sequential `eth_getCode` calls
+const CREATION_BLOCK_LOOKUP_STEP: usize = 20;
+
+/// Removes AMMs created less than `min_blocks_old` or more than `max_blocks_old` blocks ago.
+/// Only the creation block is needed, so the filter can run on pools that have just been discovered, before their data is populated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AgeFilter {
+    pub min_blocks_old: u64,
+    pub max_blocks_old: Option<u64>,
+}
+
+impl AgeFilter {
+    pub fn new(min_blocks_old: u64, max_blocks_old: Option<u64>) -> Self {
+        AgeFilter {
+            min_blocks_old,
+            max_blocks_old,
+        }
+    }
+
+    pub fn accepts(&self, creation_block: u64, current_block: u64) -> bool {
+        let blocks_old = current_block.saturating_sub(creation_block);
+
+        blocks_old >= self.min_blocks_old
+            && self
+                .max_blocks_old
+                .map_or(true, |max_blocks_old| blocks_old <= max_blocks_old)
+    }
+
+    /// Filters AMMs by the age they have at `current_block`. AMMs without a known creation block are kept.
+    pub fn filter(&self, amms: Vec<AMM>, current_block: u64) -> Vec<AMM> {
+        amms.into_iter()
+            .filter(|amm| match amm.creation_block() {
+                Some(creation_block) => self.accepts(creation_block, current_block),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Looks up the creation block of AMMs missing it, i.e. pools loaded from an older checkpoint, and filters them by their age at the latest block
+    pub async fn filter_with_lookup<M: Middleware>(
+        &self,
+        mut amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        let current_block = middleware
+            .get_block_number()
+            .await
+            .map_err(AMMError::MiddlewareError)?
+            .as_u64();
+
+        populate_creation_blocks(&mut amms, current_block, middleware).await?;
+
+        Ok(self.filter(amms, current_block))
+    }
+}
+
+#[async_trait]
+impl<M: 'static + Middleware> AmmFilter<M> for AgeFilter {
+    fn name(&self) -> &str {
+        "age"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Rpc
+    }
+
+    async fn filter(
+        &self,
+        mut amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<FilterOutcome, AMMError<M>> {
+        let current_block = middleware
+            .get_block_number()
+            .await
+            .map_err(AMMError::MiddlewareError)?
+            .as_u64();
+
+        populate_creation_blocks(&mut amms, current_block, middleware).await?;
+
+        Ok(FilterOutcome::partition(amms, |amm| {
+            let creation_block = amm.creation_block()?;
+
+            (!self.accepts(creation_block, current_block)).then(|| {
+                DroppedAmm::new(
+                    amm.address(),
+                    format!(
+                        "{} blocks old",
+                        current_block.saturating_sub(creation_block)
+                    ),
+                )
+            })
+        }))
+    }
+}
+
+/// Finds the first block at which `address` has code by binary searching `eth_getCode` up to `to_block`,
+/// returning None if there is no code at `to_block`. Blocks before the last few hundred need an archive node.
+pub async fn get_creation_block<M: Middleware>(
+    address: H160,
+    to_block: u64,
+    middleware: Arc<M>,
+) -> Result<Option<u64>, AMMError<M>> {
+    if !has_code_at(address, to_block, middleware.clone()).await? {
+        return Ok(None);
+    }
+
+    let (mut low, mut high) = (0, to_block);
+    while low < high {
+        let mid = low + (high - low) / 2;
+
+        if has_code_at(address, mid, middleware.clone()).await? {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+
+    Ok(Some(low))
+}
+
+async fn has_code_at<M: Middleware>(
+    address: H160,
+    block_number: u64,
+    middleware: Arc<M>,
+) -> Result<bool, AMMError<M>> {
+    let code = middleware
+        .get_code(
+            address,
+            Some(BlockId::Number(BlockNumber::Number(U64::from(
+                block_number,
+            )))),
+        )
+        .await
+        .map_err(AMMError::MiddlewareError)?;
+
+    Ok(!code.is_empty())
+}
+
+/// Looks up the creation block of every Uniswap V2 and V3 pool that does not have one, searching up to `to_block`
+pub async fn populate_creation_blocks<M: Middleware>(
+    amms: &mut [AMM],
+    to_block: u64,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let mut missing_creation_blocks = amms
+        .iter_mut()
+        .filter(|amm| {
+            matches!(amm, AMM::UniswapV2Pool(_) | AMM::UniswapV3Pool(_))
+                && amm.creation_block().is_none()
+        })
+        .collect::<Vec<&mut AMM>>();
+
+    for chunk in missing_creation_blocks.chunks_mut(CREATION_BLOCK_LOOKUP_STEP) {
+        let creation_blocks = try_join_all(
+            chunk
+                .iter()
+                .map(|amm| get_creation_block(amm.address(), to_block, middleware.clone())),
+        )
+        .await?;
+
+        for (amm, creation_block) in chunk.iter_mut().zip(creation_blocks) {
+            match amm {
+                AMM::UniswapV2Pool(pool) => pool.creation_block = creation_block,
+                AMM::UniswapV3Pool(pool) => pool.creation_block = creation_block,
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use ethers::{
+        providers::{Http, Provider},
+        types::H160,
+    };
+
+    use crate::amm::{erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, AmmState, AMM};
+
+    use super::{get_creation_block, AgeFilter};
+
+    fn pool(address: u64, creation_block: Option<u64>) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            creation_block,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_age_filter() {
+        let amms = vec![
+            pool(1, Some(990)),
+            pool(2, Some(500)),
+            pool(3, Some(10)),
+            pool(4, None),
+            AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: H160::from_low_u64_be(5),
+                ..Default::default()
+            }),
+        ];
+
+        let filtered = AgeFilter::new(100, Some(900)).filter(amms.clone(), 1000);
+        assert_eq!(
+            filtered
+                .iter()
+                .map(|amm| amm.address())
+                .collect::<Vec<H160>>(),
+            vec![
+                H160::from_low_u64_be(2),
+                H160::from_low_u64_be(4),
+                H160::from_low_u64_be(5)
+            ]
+        );
+
+        //Pools created after the current block are treated as brand new
+        assert_eq!(AgeFilter::new(0, None).filter(amms.clone(), 0).len(), 5);
+        assert_eq!(AgeFilter::new(1, None).filter(amms, 0).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_creation_block() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //WETH/USDC was created at block 10008355
+        let creation_block = get_creation_block(
+            H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+            17000000,
+            middleware,
+        )
+        .await?;
+        assert_eq!(creation_block, Some(10008355));
+
+        Ok(())
+    }
+}
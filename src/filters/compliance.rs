@@ -0,0 +1,371 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use ethers::{
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, Bytes, TransactionRequest, H160},
+};
+use futures::future::{join_all, try_join_all};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::AMMError,
+};
+
+use super::proxy::{get_code_and_proxy_kind, instructions, ProxyKind};
+
+//Views reporting whether an address is frozen: isBlacklisted(address) as in USDC, isBlackListed(address) and
+//getBlackListStatus(address) as in USDT, and isFrozen(address) as in USDP
+const BLACKLIST_SELECTORS: [[u8; 4]; 4] = [
+    [254, 87, 90, 135],
+    [228, 125, 96, 96],
+    [89, 191, 26, 190],
+    [229, 131, 152, 54],
+];
+//paused()
+const PAUSED_SELECTOR: [u8; 4] = [92, 151, 90, 187];
+
+const PUSH4: u8 = 0x63;
+
+//Number of tokens checked concurrently
+const COMPLIANCE_CHECK_STEP: usize = 50;
+
+/// Central controls found on a token, or on any token of an AMM once aggregated
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComplianceFlags {
+    pub blacklistable: bool,
+    pub pausable: bool,
+    /// Checked addresses that are currently blacklisted
+    pub blocked_for: Vec<H160>,
+}
+
+impl ComplianceFlags {
+    pub fn is_flagged(&self) -> bool {
+        self.blacklistable || self.pausable
+    }
+
+    pub fn merge(&mut self, other: &ComplianceFlags) {
+        self.blacklistable |= other.blacklistable;
+        self.pausable |= other.pausable;
+
+        for address in other.blocked_for.iter() {
+            if !self.blocked_for.contains(address) {
+                self.blocked_for.push(*address);
+            }
+        }
+    }
+}
+
+/// Flags from the function selectors dispatched by `code`. Selectors are only looked for in PUSH4 instructions, so this is a heuristic
+/// that can miss tokens built without a standard dispatcher.
+pub fn compliance_flags_from_code(code: &[u8]) -> ComplianceFlags {
+    let mut flags = ComplianceFlags::default();
+
+    for (opcode, push_data) in instructions(code) {
+        if opcode != PUSH4 {
+            continue;
+        }
+
+        if BLACKLIST_SELECTORS
+            .iter()
+            .any(|selector| push_data == selector)
+        {
+            flags.blacklistable = true;
+        } else if push_data == PAUSED_SELECTOR {
+            flags.pausable = true;
+        }
+    }
+
+    flags
+}
+
+/// Looks for blacklisting and pausing functions in the code of every token, following proxies to their implementation.
+/// If any addresses are set, blacklistable tokens are also asked whether each of them is blacklisted.
+#[derive(Debug, Clone, Default)]
+pub struct ComplianceFilter {
+    addresses: Vec<H160>,
+}
+
+impl ComplianceFilter {
+    pub fn new() -> Self {
+        ComplianceFilter::default()
+    }
+
+    /// Addresses to check against the blacklist of each token, i.e. the searcher's contracts and EOAs
+    pub fn with_addresses(mut self, addresses: Vec<H160>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+
+    pub async fn get_token_flags<M: Middleware>(
+        &self,
+        amms: &[AMM],
+        middleware: Arc<M>,
+    ) -> Result<HashMap<H160, ComplianceFlags>, AMMError<M>> {
+        let mut tokens = vec![];
+        let mut seen_tokens = HashSet::new();
+        for token in amms.iter().flat_map(|amm| amm.tokens()) {
+            if seen_tokens.insert(token) {
+                tokens.push(token);
+            }
+        }
+
+        let mut token_flags = HashMap::new();
+        for chunk in tokens.chunks(COMPLIANCE_CHECK_STEP) {
+            let flags = try_join_all(
+                chunk
+                    .iter()
+                    .map(|token| self.get_flags(*token, middleware.clone())),
+            )
+            .await?;
+
+            token_flags.extend(chunk.iter().copied().zip(flags));
+        }
+
+        Ok(token_flags)
+    }
+
+    /// Flags of every AMM by address, merged from the flags of its tokens
+    pub async fn get_amm_flags<M: Middleware>(
+        &self,
+        amms: &[AMM],
+        middleware: Arc<M>,
+    ) -> Result<HashMap<H160, ComplianceFlags>, AMMError<M>> {
+        let token_flags = self.get_token_flags(amms, middleware).await?;
+        Ok(aggregate_amm_flags(amms, &token_flags))
+    }
+
+    async fn get_flags<M: Middleware>(
+        &self,
+        token: H160,
+        middleware: Arc<M>,
+    ) -> Result<ComplianceFlags, AMMError<M>> {
+        let (code, proxy_kind) = get_code_and_proxy_kind(token, middleware.clone()).await?;
+
+        //The functions of a proxied token live in its implementation, beacon proxies are only checked by their own code
+        let implementation = match proxy_kind {
+            Some(ProxyKind::ImplementationSlot { implementation, .. })
+            | Some(ProxyKind::MinimalProxy { implementation }) => Some(implementation),
+            _ => None,
+        };
+
+        let mut flags = compliance_flags_from_code(&code);
+        if let Some(implementation) = implementation {
+            let implementation_code = middleware
+                .get_code(implementation, None)
+                .await
+                .map_err(AMMError::MiddlewareError)?;
+
+            flags.merge(&compliance_flags_from_code(&implementation_code));
+        }
+
+        if flags.blacklistable {
+            flags.blocked_for = self.get_blocked_addresses(token, middleware).await;
+        }
+
+        Ok(flags)
+    }
+
+    //Calls every blacklist view for every address, a reverting call means the token does not implement that view
+    async fn get_blocked_addresses<M: Middleware>(
+        &self,
+        token: H160,
+        middleware: Arc<M>,
+    ) -> Vec<H160> {
+        let calls = self
+            .addresses
+            .iter()
+            .flat_map(|address| {
+                BLACKLIST_SELECTORS.iter().map(move |selector| {
+                    let mut data = selector.to_vec();
+                    data.extend_from_slice(&[0; 12]);
+                    data.extend_from_slice(address.as_bytes());
+
+                    (*address, data)
+                })
+            })
+            .map(|(address, data)| {
+                let middleware = middleware.clone();
+
+                async move {
+                    let tx: TypedTransaction =
+                        TransactionRequest::new().to(token).data(data).into();
+                    let blocked = match middleware.call(&tx, None).await {
+                        Ok(result) => is_true(&result),
+                        Err(err) => {
+                            tracing::trace!(?token, ?address, "blacklist view reverted: {}", err);
+                            false
+                        }
+                    };
+
+                    (address, blocked)
+                }
+            });
+
+        let mut blocked_for = vec![];
+        for (address, blocked) in join_all(calls).await {
+            if blocked && !blocked_for.contains(&address) {
+                blocked_for.push(address);
+            }
+        }
+
+        blocked_for
+    }
+}
+
+fn is_true(result: &Bytes) -> bool {
+    result.len() >= 32 && result[..32].iter().any(|byte| *byte != 0)
+}
+
+/// Merges the flags of the tokens of every AMM, keyed by AMM address
+pub fn aggregate_amm_flags(
+    amms: &[AMM],
+    token_flags: &HashMap<H160, ComplianceFlags>,
+) -> HashMap<H160, ComplianceFlags> {
+    amms.iter()
+        .map(|amm| {
+            let mut flags = ComplianceFlags::default();
+            for token in amm.tokens() {
+                if let Some(token_flags) = token_flags.get(&token) {
+                    flags.merge(token_flags);
+                }
+            }
+
+            (amm.address(), flags)
+        })
+        .collect()
+}
+
+/// Removes AMMs with a token that blacklists any of the checked addresses
+pub fn filter_blocked_amms(amms: Vec<AMM>, amm_flags: &HashMap<H160, ComplianceFlags>) -> Vec<AMM> {
+    amms.into_iter()
+        .filter(|amm| {
+            amm_flags
+                .get(&amm.address())
+                .map_or(true, |flags| flags.blocked_for.is_empty())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+    use ethers::{
+        providers::{Http, Provider},
+        types::H160,
+    };
+
+    use crate::amm::{uniswap_v2::UniswapV2Pool, AmmState, AMM};
+
+    use super::{
+        aggregate_amm_flags, compliance_flags_from_code, filter_blocked_amms, ComplianceFilter,
+        ComplianceFlags,
+    };
+
+    #[test]
+    fn test_compliance_flags_from_code() {
+        //PUSH4 isBlacklisted(address) EQ, PUSH4 paused() EQ
+        let code = [0x63, 254, 87, 90, 135, 0x14, 0x63, 92, 151, 90, 187, 0x14];
+        assert_eq!(
+            compliance_flags_from_code(&code),
+            ComplianceFlags {
+                blacklistable: true,
+                pausable: true,
+                blocked_for: vec![],
+            }
+        );
+
+        //The selector is part of a PUSH32, not a function dispatch
+        let mut code = vec![0x7f];
+        code.extend_from_slice(&[0x63, 254, 87, 90, 135]);
+        code.extend_from_slice(&[0; 27]);
+        assert!(!compliance_flags_from_code(&code).is_flagged());
+    }
+
+    #[test]
+    fn test_aggregate_amm_flags() {
+        let usdc = H160::from_low_u64_be(1);
+        let weth = H160::from_low_u64_be(2);
+        let blocked = H160::from_low_u64_be(3);
+
+        let amms = vec![
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(10),
+                token_a: usdc,
+                token_b: weth,
+                ..Default::default()
+            }),
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(11),
+                token_a: weth,
+                token_b: H160::from_low_u64_be(4),
+                ..Default::default()
+            }),
+        ];
+
+        let token_flags = HashMap::from([
+            (
+                usdc,
+                ComplianceFlags {
+                    blacklistable: true,
+                    pausable: false,
+                    blocked_for: vec![blocked],
+                },
+            ),
+            (
+                weth,
+                ComplianceFlags {
+                    pausable: true,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let amm_flags = aggregate_amm_flags(&amms, &token_flags);
+        assert_eq!(
+            amm_flags[&H160::from_low_u64_be(10)],
+            ComplianceFlags {
+                blacklistable: true,
+                pausable: true,
+                blocked_for: vec![blocked],
+            }
+        );
+        assert!(!amm_flags[&H160::from_low_u64_be(11)].blacklistable);
+
+        let filtered = filter_blocked_amms(amms, &amm_flags);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].address(), H160::from_low_u64_be(11));
+    }
+
+    #[tokio::test]
+    async fn test_get_token_flags() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")?;
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")?;
+        let pool = AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+            token_a: usdc,
+            token_b: weth,
+            ..Default::default()
+        });
+
+        let token_flags = ComplianceFilter::new()
+            .with_addresses(vec![H160::from_low_u64_be(1)])
+            .get_token_flags(&[pool], middleware)
+            .await?;
+
+        //USDC is a proxy, its blacklist lives in the implementation
+        assert!(token_flags[&usdc].blacklistable);
+        assert!(token_flags[&usdc].pausable);
+        assert!(token_flags[&usdc].blocked_for.is_empty());
+        assert!(!token_flags[&weth].is_flagged());
+
+        Ok(())
+    }
+}
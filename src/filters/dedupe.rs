@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+
+use ethers::types::H160;
+
+use crate::{
+    amm::{bancor_v3, uniswap_v3::UniswapV3Pool, AmmState, AMM},
+    math::fixed_point::{u256_to_f64, u256_to_f64_lossy},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    UniswapV2,
+    UniswapV3,
+    ERC4626,
+    CurveStableSwap,
+    BalancerWeighted,
+    Velodrome,
+    UniswapV4,
+    LiquidityBook,
+    Maverick,
+    Dodo,
+    KyberElastic,
+    Algebra,
+    CurveCrypto,
+    BancorV3,
+    Custom,
+}
+
+impl Protocol {
+    pub fn of(amm: &AMM) -> Self {
+        match amm {
+            AMM::UniswapV2Pool(_) => Protocol::UniswapV2,
+            AMM::UniswapV3Pool(_) => Protocol::UniswapV3,
+            AMM::ERC4626Vault(_) => Protocol::ERC4626,
+            AMM::CurveStableSwapPool(_) => Protocol::CurveStableSwap,
+            AMM::BalancerWeightedPool(_) => Protocol::BalancerWeighted,
+            AMM::VelodromePool(_) => Protocol::Velodrome,
+            AMM::UniswapV4Pool(_) => Protocol::UniswapV4,
+            AMM::LiquidityBookPool(_) => Protocol::LiquidityBook,
+            AMM::MaverickPool(_) => Protocol::Maverick,
+            AMM::DodoPool(_) => Protocol::Dodo,
+            AMM::KyberElasticPool(_) => Protocol::KyberElastic,
+            AMM::AlgebraPool(_) => Protocol::Algebra,
+            AMM::CurveCryptoPool(_) => Protocol::CurveCrypto,
+            AMM::BancorV3Pool(_) => Protocol::BancorV3,
+            AMM::Custom(_) => Protocol::Custom,
+        }
+    }
+
+    /// Identifier of the protocol in exports and storage
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::UniswapV2 => "uniswap_v2",
+            Protocol::UniswapV3 => "uniswap_v3",
+            Protocol::ERC4626 => "erc4626",
+            Protocol::CurveStableSwap => "curve_stableswap",
+            Protocol::BalancerWeighted => "balancer_weighted",
+            Protocol::Velodrome => "velodrome",
+            Protocol::UniswapV4 => "uniswap_v4",
+            Protocol::LiquidityBook => "liquidity_book",
+            Protocol::Maverick => "maverick",
+            Protocol::Dodo => "dodo",
+            Protocol::KyberElastic => "kyber_elastic",
+            Protocol::Algebra => "algebra",
+            Protocol::CurveCrypto => "curve_crypto",
+            Protocol::BancorV3 => "bancor_v3",
+            Protocol::Custom => "custom",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uniswap_v2" => Some(Protocol::UniswapV2),
+            "uniswap_v3" => Some(Protocol::UniswapV3),
+            "erc4626" => Some(Protocol::ERC4626),
+            "curve_stableswap" => Some(Protocol::CurveStableSwap),
+            "balancer_weighted" => Some(Protocol::BalancerWeighted),
+            "velodrome" => Some(Protocol::Velodrome),
+            "uniswap_v4" => Some(Protocol::UniswapV4),
+            "liquidity_book" => Some(Protocol::LiquidityBook),
+            "maverick" => Some(Protocol::Maverick),
+            "dodo" => Some(Protocol::Dodo),
+            "kyber_elastic" => Some(Protocol::KyberElastic),
+            "algebra" => Some(Protocol::Algebra),
+            "curve_crypto" => Some(Protocol::CurveCrypto),
+            "bancor_v3" => Some(Protocol::BancorV3),
+            "custom" => Some(Protocol::Custom),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupePolicy {
+    /// Number of pools kept for each group
+    pub keep: usize,
+    /// Groups pools by protocol as well as by token set, so the deepest pools of every protocol are kept
+    pub by_protocol: bool,
+}
+
+impl Default for DedupePolicy {
+    fn default() -> Self {
+        DedupePolicy {
+            keep: 1,
+            by_protocol: false,
+        }
+    }
+}
+
+impl DedupePolicy {
+    pub fn new(keep: usize) -> Self {
+        DedupePolicy {
+            keep,
+            ..Default::default()
+        }
+    }
+
+    pub fn by_protocol(mut self) -> Self {
+        self.by_protocol = true;
+        self
+    }
+}
+
+/// Pool removed because deeper pools with the same tokens were kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedPool {
+    pub amm: H160,
+    pub depth: f64,
+    //Rank of the pool in its group, starting at 1 for the deepest pool
+    pub rank: usize,
+    //Pools kept for the group, deepest first
+    pub kept: Vec<H160>,
+}
+
+/// Liquidity depth of an AMM in whole tokens, comparable between AMMs regardless of token decimals.
+/// Uniswap V2 pools use the geometric mean of their reserves, Uniswap V3, V4, KyberSwap Elastic and Algebra pools their active liquidity, vaults their total assets
+/// and Curve pools the sum of their balances. Balancer pools use the geometric mean of their balances weighted by
+/// the weights of the tokens. Velodrome pools are valued like Uniswap V2 pools when volatile and like Curve pools
+/// when stable, Liquidity Book and Maverick pools use the geometric mean of the reserves of their bins, DODO and
+/// Curve crypto pools the geometric mean of their reserves and Bancor V3 pools the geometric mean of their trading
+/// liquidity.
+pub fn pool_depth(amm: &AMM) -> f64 {
+    match amm {
+        AMM::UniswapV2Pool(pool) => {
+            let reserve_0 = pool.reserve_0 as f64 / 10_f64.powi(pool.token_a_decimals as i32);
+            let reserve_1 = pool.reserve_1 as f64 / 10_f64.powi(pool.token_b_decimals as i32);
+
+            (reserve_0 * reserve_1).sqrt()
+        }
+        AMM::UniswapV3Pool(pool) => uniswap_v3_depth(pool),
+        AMM::UniswapV4Pool(pool) => uniswap_v3_depth(&pool.pool),
+        AMM::ERC4626Vault(vault) => u256_to_f64(vault.asset_reserve, vault.asset_token_decimals),
+        AMM::CurveStableSwapPool(pool) => pool
+            .balances
+            .iter()
+            .zip(pool.decimals.iter())
+            .map(|(balance, decimals)| u256_to_f64(*balance, *decimals))
+            .sum(),
+        AMM::BalancerWeightedPool(pool) => pool
+            .balances
+            .iter()
+            .zip(pool.decimals.iter().zip(pool.weights.iter()))
+            .map(|(balance, (decimals, weight))| {
+                u256_to_f64(*balance, *decimals).powf(u256_to_f64(*weight, 18))
+            })
+            .product(),
+        AMM::VelodromePool(pool) => {
+            let reserve_0 = u256_to_f64(pool.reserve_0, pool.token_a_decimals);
+            let reserve_1 = u256_to_f64(pool.reserve_1, pool.token_b_decimals);
+
+            if pool.stable {
+                reserve_0 + reserve_1
+            } else {
+                (reserve_0 * reserve_1).sqrt()
+            }
+        }
+        AMM::LiquidityBookPool(pool) => {
+            let (reserve_x, reserve_y) = pool.total_reserves();
+
+            (u256_to_f64(reserve_x, pool.token_a_decimals)
+                * u256_to_f64(reserve_y, pool.token_b_decimals))
+            .sqrt()
+        }
+        AMM::MaverickPool(pool) => {
+            let (reserve_a, reserve_b) = pool.total_reserves();
+
+            (u256_to_f64(reserve_a, pool.token_a_decimals)
+                * u256_to_f64(reserve_b, pool.token_b_decimals))
+            .sqrt()
+        }
+        AMM::DodoPool(pool) => {
+            let base_reserve = u256_to_f64(pool.state.base_reserve, pool.token_a_decimals);
+            let quote_reserve = u256_to_f64(pool.state.quote_reserve, pool.token_b_decimals);
+
+            (base_reserve * quote_reserve).sqrt()
+        }
+        AMM::KyberElasticPool(pool) => {
+            u256_to_f64_lossy(pool.liquidity())
+                / 10_f64.powf((pool.token_a_decimals as f64 + pool.token_b_decimals as f64) / 2.0)
+        }
+        AMM::AlgebraPool(pool) => uniswap_v3_depth(&pool.pool),
+        AMM::CurveCryptoPool(pool) => pool
+            .balances
+            .iter()
+            .zip(pool.decimals.iter())
+            .map(|(balance, decimals)| {
+                u256_to_f64(*balance, *decimals).powf(1.0 / pool.balances.len() as f64)
+            })
+            .product(),
+        AMM::BancorV3Pool(pool) => {
+            let token_liquidity = u256_to_f64(
+                pool.base_token_trading_liquidity.into(),
+                pool.token_decimals,
+            );
+            let bnt_liquidity =
+                u256_to_f64(pool.bnt_trading_liquidity.into(), bancor_v3::BNT_DECIMALS);
+
+            (token_liquidity * bnt_liquidity).sqrt()
+        }
+        AMM::Custom(_) => 0.0,
+    }
+}
+
+//Liquidity is the geometric mean of the virtual reserves, so it is scaled by the mean of the decimals
+fn uniswap_v3_depth(pool: &UniswapV3Pool) -> f64 {
+    pool.liquidity as f64
+        / 10_f64.powf((pool.token_a_decimals as f64 + pool.token_b_decimals as f64) / 2.0)
+}
+
+/// Groups AMMs by their set of tokens, and by protocol if the policy says so, keeping the `policy.keep` deepest AMMs of every group.
+/// Kept AMMs are returned in their original order along with a report of every dropped AMM.
+pub fn dedupe_pools(amms: Vec<AMM>, policy: DedupePolicy) -> (Vec<AMM>, Vec<DroppedPool>) {
+    let depths = amms.iter().map(pool_depth).collect::<Vec<f64>>();
+
+    let mut groups: HashMap<(Vec<H160>, Option<Protocol>), Vec<usize>> = HashMap::new();
+    for (amm_idx, amm) in amms.iter().enumerate() {
+        let mut tokens = amm.tokens();
+        tokens.sort();
+        tokens.dedup();
+
+        let protocol = policy.by_protocol.then(|| Protocol::of(amm));
+        groups.entry((tokens, protocol)).or_default().push(amm_idx);
+    }
+
+    let mut keep = vec![true; amms.len()];
+    let mut dropped = vec![];
+    for group in groups.into_values() {
+        if group.len() <= policy.keep {
+            continue;
+        }
+
+        let mut ranked = group;
+        //Ties keep the AMM that came first
+        ranked.sort_by(|a, b| depths[*b].total_cmp(&depths[*a]).then(a.cmp(b)));
+
+        let kept = ranked
+            .iter()
+            .take(policy.keep)
+            .map(|amm_idx| amms[*amm_idx].address())
+            .collect::<Vec<H160>>();
+
+        for (rank, amm_idx) in ranked.iter().enumerate().skip(policy.keep) {
+            keep[*amm_idx] = false;
+            dropped.push(DroppedPool {
+                amm: amms[*amm_idx].address(),
+                depth: depths[*amm_idx],
+                rank: rank + 1,
+                kept: kept.clone(),
+            });
+        }
+    }
+
+    let kept_amms = amms
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(amm, _)| amm)
+        .collect();
+
+    (kept_amms, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::amm::{
+        erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AmmState, AMM,
+    };
+
+    use super::{dedupe_pools, pool_depth, DedupePolicy};
+
+    fn pool(address: u64, token_a: u64, token_b: u64, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: H160::from_low_u64_be(token_a),
+            token_a_decimals: 18,
+            token_b: H160::from_low_u64_be(token_b),
+            token_b_decimals: 18,
+            reserve_0,
+            reserve_1,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_dedupe_pools() {
+        let v3_pool = AMM::UniswapV3Pool(UniswapV3Pool {
+            address: H160::from_low_u64_be(14),
+            token_a: H160::from_low_u64_be(1),
+            token_a_decimals: 18,
+            token_b: H160::from_low_u64_be(2),
+            token_b_decimals: 18,
+            liquidity: 10_u128.pow(18),
+            ..Default::default()
+        });
+
+        let amms = vec![
+            pool(10, 1, 2, 10_u128.pow(18), 10_u128.pow(18)),
+            //Same pair with the tokens in the other order
+            pool(11, 2, 1, 100 * 10_u128.pow(18), 100 * 10_u128.pow(18)),
+            pool(12, 1, 2, 10_u128.pow(17), 10_u128.pow(17)),
+            pool(13, 1, 3, 10, 10),
+            v3_pool,
+        ];
+
+        let (kept, dropped) = dedupe_pools(amms.clone(), DedupePolicy::default());
+        assert_eq!(
+            kept.iter().map(|amm| amm.address()).collect::<Vec<H160>>(),
+            vec![H160::from_low_u64_be(11), H160::from_low_u64_be(13)]
+        );
+        assert_eq!(dropped.len(), 3);
+        assert!(dropped
+            .iter()
+            .all(|dropped| dropped.kept == vec![H160::from_low_u64_be(11)]));
+
+        let dropped_12 = dropped
+            .iter()
+            .find(|dropped| dropped.amm == H160::from_low_u64_be(12))
+            .unwrap();
+        assert_eq!(dropped_12.rank, 4);
+
+        //The deepest pool of each protocol is kept
+        let (kept, dropped) = dedupe_pools(amms, DedupePolicy::new(1).by_protocol());
+        assert_eq!(kept.len(), 3);
+        assert_eq!(dropped.len(), 2);
+    }
+
+    #[test]
+    fn test_pool_depth_is_normalized_by_decimals() {
+        //1 WBTC with 8 decimals against 50000 USDC with 6 decimals, and the same pool between 18 decimal tokens
+        let wbtc_pool = AMM::UniswapV2Pool(UniswapV2Pool {
+            token_a_decimals: 8,
+            token_b_decimals: 6,
+            reserve_0: 10_u128.pow(8),
+            reserve_1: 50000 * 10_u128.pow(6),
+            ..Default::default()
+        });
+        let pool = pool(10, 1, 2, 10_u128.pow(18), 50000 * 10_u128.pow(18));
+        assert!((pool_depth(&wbtc_pool) - pool_depth(&pool)).abs() < 1e-6);
+
+        let v3_pool = AMM::UniswapV3Pool(UniswapV3Pool {
+            token_a_decimals: 8,
+            token_b_decimals: 6,
+            liquidity: 10_u128.pow(7),
+            ..Default::default()
+        });
+        assert!((pool_depth(&v3_pool) - 1.0).abs() < 1e-9);
+
+        let vault = AMM::ERC4626Vault(ERC4626Vault {
+            asset_token_decimals: 6,
+            asset_reserve: U256::from(5 * 10_u128.pow(6)),
+            ..Default::default()
+        });
+        assert_eq!(pool_depth(&vault), 5.0);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_to_string,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use ethers::{
+    providers::Middleware,
+    types::{H160, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{uniswap_v2::TAX_BPS_DENOMINATOR, AmmState, AMM},
+    errors::{AMMError, FilterError},
+};
+
+use super::tax::{
+    get_token_holders, probe_transfers, tax_bps, ProbeTransfer, PROBE_ACCOUNTS, PROBE_RESULT_WORDS,
+    TAX_PROBE_STEP,
+};
+
+//Backoff before the first retry of a failed probe call, doubled on every further retry. Longer waits asked for by the
+//provider are honored.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HoneypotStatus {
+    /// The token could be sold back into the AMM right after buying it
+    Sellable {
+        sell_tax_bps: u32,
+    },
+    /// Sells go through, but the token takes more than the accepted sell tax
+    Partial {
+        sell_tax_bps: u32,
+    },
+    Unsellable,
+    /// Buying reverted, or only the buyer was blocked from selling as with cooldowns. The token may be sellable later.
+    Inconclusive,
+}
+
+/// Detects tokens that can be bought but not sold by simulating a round trip through the AMM holding the most of each token.
+/// The round trip runs in a single `eth_call`, with the probe from `contracts/TransferTaxProbe.asm` passed as a state override.
+/// Results are cached by token, except for inconclusive ones which are probed again on the next check.
+#[derive(Debug, Clone)]
+pub struct HoneypotFilter {
+    statuses: HashMap<H160, HoneypotStatus>,
+    max_sell_tax_bps: u32,
+    step: usize,
+    request_delay: Duration,
+    max_retries: u32,
+}
+
+impl HoneypotFilter {
+    /// Tokens with a sell tax above `max_sell_tax_bps` are classified as partial
+    pub fn new(max_sell_tax_bps: u32) -> Self {
+        HoneypotFilter {
+            statuses: HashMap::new(),
+            max_sell_tax_bps,
+            step: TAX_PROBE_STEP,
+            request_delay: Duration::ZERO,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Number of tokens probed in each `eth_call`
+    pub fn with_step(mut self, step: usize) -> Self {
+        self.step = step.max(1);
+        self
+    }
+
+    /// Delay between consecutive probe calls, for providers with strict rate limits
+    pub fn with_request_delay(mut self, request_delay: Duration) -> Self {
+        self.request_delay = request_delay;
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Adds previously computed statuses to the cache
+    pub fn with_statuses(mut self, statuses: HashMap<H160, HoneypotStatus>) -> Self {
+        self.statuses.extend(statuses);
+        self
+    }
+
+    /// Adds the statuses saved at `path` to the cache, if the file exists
+    pub fn load_statuses(&mut self, path: impl AsRef<Path>) -> Result<(), FilterError> {
+        if path.as_ref().exists() {
+            let statuses: HashMap<H160, HoneypotStatus> =
+                serde_json::from_str(&read_to_string(path)?)?;
+            self.statuses.extend(statuses);
+        }
+
+        Ok(())
+    }
+
+    pub fn save_statuses(&self, path: impl AsRef<Path>) -> Result<(), FilterError> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.statuses)?)?;
+        Ok(())
+    }
+
+    pub fn status(&self, token: H160) -> Option<HoneypotStatus> {
+        self.statuses.get(&token).copied()
+    }
+
+    pub fn statuses(&self) -> &HashMap<H160, HoneypotStatus> {
+        &self.statuses
+    }
+
+    /// Probes every token of `amms` that is not cached yet. Tokens no AMM holds a balance of are left unclassified.
+    pub async fn check_tokens<M: 'static + Middleware>(
+        &mut self,
+        amms: &[AMM],
+        middleware: Arc<M>,
+    ) -> Result<(), AMMError<M>> {
+        let holders = get_token_holders(amms, middleware.clone())
+            .await?
+            .into_iter()
+            .filter(|(token, _, _)| self.status(*token).is_none())
+            .collect::<Vec<(H160, H160, U256)>>();
+
+        for (chunk_idx, chunk) in holders.chunks(self.step).enumerate() {
+            if chunk_idx > 0 && !self.request_delay.is_zero() {
+                tokio::time::sleep(self.request_delay).await;
+            }
+
+            let transfers = chunk
+                .iter()
+                .flat_map(|(token, holder, amount)| round_trip_transfers(*token, *holder, *amount))
+                .collect::<Vec<ProbeTransfer>>();
+
+            let results = self
+                .probe_with_retries(&transfers, middleware.clone())
+                .await?;
+
+            for ((token, _, _), token_results) in chunk.iter().zip(results.chunks(3)) {
+                let status = classify(token_results, self.max_sell_tax_bps);
+                tracing::debug!(?token, ?status, "probed token");
+
+                if status == HoneypotStatus::Inconclusive {
+                    self.statuses.remove(token);
+                } else {
+                    self.statuses.insert(*token, status);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes AMMs containing an unsellable token or a token selling with more than the accepted tax.
+    /// AMMs with inconclusive or unclassified tokens are kept.
+    pub async fn filter<M: 'static + Middleware>(
+        &mut self,
+        amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        self.check_tokens(&amms, middleware).await?;
+
+        let honeypots = self
+            .statuses
+            .iter()
+            .filter(|(_, status)| {
+                matches!(
+                    status,
+                    HoneypotStatus::Unsellable | HoneypotStatus::Partial { .. }
+                )
+            })
+            .map(|(token, _)| *token)
+            .collect::<HashSet<H160>>();
+
+        Ok(amms
+            .into_iter()
+            .filter(|amm| amm.tokens().iter().all(|token| !honeypots.contains(token)))
+            .collect())
+    }
+
+    async fn probe_with_retries<M: Middleware>(
+        &self,
+        transfers: &[ProbeTransfer],
+        middleware: Arc<M>,
+    ) -> Result<Vec<[U256; PROBE_RESULT_WORDS]>, AMMError<M>> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            match probe_transfers(transfers, middleware.clone()).await {
+                Ok(results) => return Ok(results),
+                //Reverts and oversized responses fail the same way on every attempt
+                Err(err) if retries < self.max_retries && err.is_retryable() => {
+                    tracing::warn!(
+                        retries,
+                        kind = err.provider_error_kind().map(|kind| kind.name()),
+                        "honeypot probe failed, retrying: {}",
+                        err
+                    );
+                    tokio::time::sleep(err.retry_after().unwrap_or(backoff).max(backoff)).await;
+
+                    backoff *= 2;
+                    retries += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+//Buys into the first probe account and sells straight back, then buys into the second probe account and moves the tokens
+//to the third one before selling, which tells restrictions on the buyer apart from restrictions on selling
+fn round_trip_transfers(token: H160, holder: H160, amount: U256) -> [ProbeTransfer; 3] {
+    [
+        ProbeTransfer {
+            holder,
+            token,
+            to: PROBE_ACCOUNTS[0],
+            amount,
+            next: holder,
+        },
+        ProbeTransfer {
+            holder,
+            token,
+            to: PROBE_ACCOUNTS[1],
+            amount,
+            next: PROBE_ACCOUNTS[2],
+        },
+        ProbeTransfer {
+            holder: PROBE_ACCOUNTS[2],
+            token,
+            to: holder,
+            amount: U256::zero(),
+            next: H160::zero(),
+        },
+    ]
+}
+
+//Classifies the results of the transfers from `round_trip_transfers`, each word is described in the probe source
+fn classify(results: &[[U256; PROBE_RESULT_WORDS]], max_sell_tax_bps: u32) -> HoneypotStatus {
+    let (round_trip, moved, moved_sell) = (&results[0], &results[1], &results[2]);
+
+    //Buying reverted, trading might not be enabled yet
+    if round_trip[0].is_zero() {
+        return HoneypotStatus::Inconclusive;
+    }
+
+    if !round_trip[3].is_zero() {
+        let bought = round_trip[2].saturating_sub(round_trip[1]);
+        let sell_tax_bps = tax_bps(bought, round_trip[5].saturating_sub(round_trip[4]));
+
+        return if sell_tax_bps >= TAX_BPS_DENOMINATOR {
+            HoneypotStatus::Unsellable
+        } else if sell_tax_bps > max_sell_tax_bps {
+            HoneypotStatus::Partial { sell_tax_bps }
+        } else {
+            HoneypotStatus::Sellable { sell_tax_bps }
+        };
+    }
+
+    //The buyer could not sell, but tokens that did not come straight from the AMM could
+    let moved_to_third_account = !moved[3].is_zero() && moved[5] > moved[4];
+    let sold_from_third_account = !moved_sell[0].is_zero() && moved_sell[2] > moved_sell[1];
+    if moved_to_third_account && sold_from_third_account {
+        HoneypotStatus::Inconclusive
+    } else {
+        HoneypotStatus::Unsellable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethers::types::{H160, U256};
+
+    use super::{classify, HoneypotFilter, HoneypotStatus, PROBE_RESULT_WORDS};
+
+    fn results(words: [[u64; PROBE_RESULT_WORDS]; 3]) -> Vec<[U256; PROBE_RESULT_WORDS]> {
+        words.iter().map(|result| result.map(U256::from)).collect()
+    }
+
+    #[test]
+    fn test_classify_round_trips() {
+        let sellable = results([
+            [1, 0, 1000, 1, 999000, 1000000],
+            [1, 0, 1000, 1, 0, 1000],
+            [1, 999000, 1000000, 0, 0, 0],
+        ]);
+        assert_eq!(
+            classify(&sellable, 1000),
+            HoneypotStatus::Sellable { sell_tax_bps: 0 }
+        );
+
+        //20% of every sell is taken
+        let taxed = results([
+            [1, 0, 1000, 1, 999000, 999800],
+            [1, 0, 1000, 1, 0, 1000],
+            [1, 998800, 999600, 0, 0, 0],
+        ]);
+        assert_eq!(
+            classify(&taxed, 1000),
+            HoneypotStatus::Partial { sell_tax_bps: 2000 }
+        );
+        assert_eq!(
+            classify(&taxed, 2000),
+            HoneypotStatus::Sellable { sell_tax_bps: 2000 }
+        );
+
+        let unsellable = results([
+            [1, 0, 1000, 0, 999000, 999000],
+            [1, 0, 1000, 1, 0, 1000],
+            [0, 998000, 998000, 0, 0, 0],
+        ]);
+        assert_eq!(classify(&unsellable, 1000), HoneypotStatus::Unsellable);
+
+        //Only the buyer is blocked from selling, as with a cooldown after buying
+        let cooldown = results([
+            [1, 0, 1000, 0, 999000, 999000],
+            [1, 0, 1000, 1, 0, 1000],
+            [1, 998000, 999000, 0, 0, 0],
+        ]);
+        assert_eq!(classify(&cooldown, 1000), HoneypotStatus::Inconclusive);
+
+        let unbuyable = results([[0; PROBE_RESULT_WORDS]; 3]);
+        assert_eq!(classify(&unbuyable, 1000), HoneypotStatus::Inconclusive);
+    }
+
+    #[test]
+    fn test_honeypot_statuses_round_trip() -> eyre::Result<()> {
+        let path = std::env::temp_dir().join(format!("honeypots-{}.json", std::process::id()));
+
+        let filter = HoneypotFilter::new(1000).with_statuses(HashMap::from([
+            (H160::from_low_u64_be(1), HoneypotStatus::Unsellable),
+            (
+                H160::from_low_u64_be(2),
+                HoneypotStatus::Sellable { sell_tax_bps: 100 },
+            ),
+        ]));
+        filter.save_statuses(&path)?;
+
+        let mut loaded = HoneypotFilter::new(1000);
+        loaded.load_statuses(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(loaded.statuses(), filter.statuses());
+        assert_eq!(
+            loaded.status(H160::from_low_u64_be(1)),
+            Some(HoneypotStatus::Unsellable)
+        );
+
+        Ok(())
+    }
+}
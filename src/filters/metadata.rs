@@ -0,0 +1,347 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use ethers::{
+    core::rand::{seq::SliceRandom, thread_rng},
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, Bytes, TransactionRequest, H160, U256},
+};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::AMMError,
+    tokens::{decode_symbol, recorded_decimals},
+};
+
+use super::pipeline::{AmmFilter, DroppedAmm, FilterCost, FilterOutcome};
+
+//symbol()
+const SYMBOL_SELECTOR: [u8; 4] = [149, 216, 155, 65];
+//decimals()
+const DECIMALS_SELECTOR: [u8; 4] = [49, 60, 229, 103];
+
+//Number of tokens queried concurrently
+const METADATA_STEP: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetadataRejection {
+    DecimalsOutOfBounds {
+        token: H160,
+        decimals: u8,
+    },
+    /// symbol() reverted or returned an empty string
+    MissingSymbol {
+        token: H160,
+    },
+    SymbolTooLong {
+        token: H160,
+        len: usize,
+    },
+    /// The symbol is not valid UTF-8 or contains control characters
+    InvalidSymbol {
+        token: H160,
+    },
+    /// The decimals recorded on the AMM differ from what the token reports
+    DecimalsMismatch {
+        token: H160,
+        recorded: u8,
+        probed: u8,
+    },
+}
+
+/// AMM removed by the metadata sanity filter, with every check it failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RejectedAmm {
+    pub amm: H160,
+    pub reasons: Vec<MetadataRejection>,
+}
+
+/// Removes AMMs with tokens reporting absurd decimals or missing or garbage symbols. Decimals are read from the AMM,
+/// so the filter runs after population, and the recorded decimals of a random sample of AMMs are checked against `decimals()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataSanityFilter {
+    pub min_decimals: u8,
+    pub max_decimals: u8,
+    pub require_symbol: bool,
+    pub max_symbol_len: usize,
+    /// Number of AMMs whose recorded decimals are compared to a fresh `decimals()` call
+    pub decimals_sample_size: usize,
+}
+
+impl Default for MetadataSanityFilter {
+    fn default() -> Self {
+        MetadataSanityFilter {
+            min_decimals: 1,
+            max_decimals: 30,
+            require_symbol: true,
+            max_symbol_len: 32,
+            decimals_sample_size: 100,
+        }
+    }
+}
+
+impl MetadataSanityFilter {
+    pub fn new(min_decimals: u8, max_decimals: u8) -> Self {
+        MetadataSanityFilter {
+            min_decimals,
+            max_decimals,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_require_symbol(mut self, require_symbol: bool) -> Self {
+        self.require_symbol = require_symbol;
+        self
+    }
+
+    pub fn with_max_symbol_len(mut self, max_symbol_len: usize) -> Self {
+        self.max_symbol_len = max_symbol_len;
+        self
+    }
+
+    pub fn with_decimals_sample_size(mut self, decimals_sample_size: usize) -> Self {
+        self.decimals_sample_size = decimals_sample_size;
+        self
+    }
+
+    pub fn check_decimals(&self, token: H160, decimals: u8) -> Option<MetadataRejection> {
+        if decimals < self.min_decimals || decimals > self.max_decimals {
+            Some(MetadataRejection::DecimalsOutOfBounds { token, decimals })
+        } else {
+            None
+        }
+    }
+
+    /// Checks the raw return data of `symbol()`, None if the call reverted
+    pub fn check_symbol(&self, token: H160, symbol: Option<&[u8]>) -> Option<MetadataRejection> {
+        let symbol = match symbol {
+            Some(symbol) if !symbol.is_empty() => symbol,
+            _ if self.require_symbol => return Some(MetadataRejection::MissingSymbol { token }),
+            _ => return None,
+        };
+
+        match std::str::from_utf8(symbol) {
+            Ok(symbol) if symbol.chars().any(char::is_control) => {
+                Some(MetadataRejection::InvalidSymbol { token })
+            }
+            Ok(symbol) if symbol.chars().count() > self.max_symbol_len => {
+                Some(MetadataRejection::SymbolTooLong {
+                    token,
+                    len: symbol.chars().count(),
+                })
+            }
+            Ok(_) => None,
+            Err(_) => Some(MetadataRejection::InvalidSymbol { token }),
+        }
+    }
+
+    pub async fn filter<M: Middleware>(
+        &self,
+        amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<(Vec<AMM>, Vec<RejectedAmm>), AMMError<M>> {
+        let mut tokens = vec![];
+        let mut seen_tokens = HashSet::new();
+        for token in amms.iter().flat_map(|amm| amm.tokens()) {
+            if seen_tokens.insert(token) {
+                tokens.push(token);
+            }
+        }
+
+        let symbols = call_tokens(&tokens, SYMBOL_SELECTOR, middleware.clone()).await;
+
+        let mut token_rejections: HashMap<H160, Vec<MetadataRejection>> = HashMap::new();
+        for (token, symbol) in tokens.iter().zip(symbols) {
+            let symbol = symbol.map(|symbol| decode_symbol(&symbol));
+            if let Some(rejection) = self.check_symbol(*token, symbol.as_deref()) {
+                token_rejections.entry(*token).or_default().push(rejection);
+            }
+        }
+
+        let probed_decimals = self.probe_decimals(&amms, middleware).await;
+
+        let mut kept = vec![];
+        let mut rejected = vec![];
+        for amm in amms {
+            let mut reasons = vec![];
+
+            for token in amm.tokens() {
+                if let Some(rejections) = token_rejections.get(&token) {
+                    reasons.extend(rejections.iter().cloned());
+                }
+            }
+
+            for (token, decimals) in recorded_decimals(&amm) {
+                reasons.extend(self.check_decimals(token, decimals));
+
+                match probed_decimals.get(&token) {
+                    Some(probed) if *probed != decimals => {
+                        tracing::warn!(?token, decimals, probed, amm = ?amm.address(), "recorded decimals do not match the token");
+
+                        reasons.push(MetadataRejection::DecimalsMismatch {
+                            token,
+                            recorded: decimals,
+                            probed: *probed,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+
+            if reasons.is_empty() {
+                kept.push(amm);
+            } else {
+                rejected.push(RejectedAmm {
+                    amm: amm.address(),
+                    reasons,
+                });
+            }
+        }
+
+        Ok((kept, rejected))
+    }
+
+    //Calls `decimals()` on the tokens of a random sample of AMMs, tokens whose call fails are left out
+    async fn probe_decimals<M: Middleware>(
+        &self,
+        amms: &[AMM],
+        middleware: Arc<M>,
+    ) -> HashMap<H160, u8> {
+        let mut tokens = amms
+            .choose_multiple(&mut thread_rng(), self.decimals_sample_size)
+            .flat_map(|amm| recorded_decimals(amm).into_iter().map(|(token, _)| token))
+            .collect::<Vec<H160>>();
+        tokens.sort();
+        tokens.dedup();
+
+        let results = call_tokens(&tokens, DECIMALS_SELECTOR, middleware).await;
+
+        tokens
+            .into_iter()
+            .zip(results)
+            .filter_map(|(token, result)| {
+                let result = result?;
+                if result.len() < 32 {
+                    return None;
+                }
+
+                let decimals = U256::from_big_endian(&result[..32]);
+                (decimals <= U256::from(u8::MAX)).then(|| (token, decimals.as_u32() as u8))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<M: 'static + Middleware> AmmFilter<M> for MetadataSanityFilter {
+    fn name(&self) -> &str {
+        "metadata"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Rpc
+    }
+
+    async fn filter(
+        &self,
+        amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<FilterOutcome, AMMError<M>> {
+        let (kept, rejected) = MetadataSanityFilter::filter(self, amms, middleware).await?;
+
+        Ok(FilterOutcome {
+            kept,
+            dropped: rejected
+                .into_iter()
+                .map(|rejected| DroppedAmm::new(rejected.amm, format!("{:?}", rejected.reasons)))
+                .collect(),
+        })
+    }
+}
+
+//Calls a view without arguments on every token, returning None for calls that failed
+async fn call_tokens<M: Middleware>(
+    tokens: &[H160],
+    selector: [u8; 4],
+    middleware: Arc<M>,
+) -> Vec<Option<Bytes>> {
+    let mut results = vec![];
+
+    for chunk in tokens.chunks(METADATA_STEP) {
+        let calls = chunk.iter().map(|token| {
+            let middleware = middleware.clone();
+            let tx: TypedTransaction = TransactionRequest::new()
+                .to(*token)
+                .data(selector.to_vec())
+                .into();
+
+            async move { middleware.call(&tx, None).await.ok() }
+        });
+
+        results.extend(join_all(calls).await);
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{abi::Token, types::H160};
+
+    use super::{decode_symbol, MetadataRejection, MetadataSanityFilter};
+
+    #[test]
+    fn test_decode_symbol() {
+        let encoded = ethers::abi::encode(&[Token::String("WETH".to_string())]);
+        assert_eq!(decode_symbol(&encoded), b"WETH");
+
+        let mut bytes32 = [0_u8; 32];
+        bytes32[..3].copy_from_slice(b"MKR");
+        assert_eq!(decode_symbol(&bytes32), b"MKR");
+
+        assert!(decode_symbol(&[0; 32]).is_empty());
+    }
+
+    #[test]
+    fn test_metadata_checks() {
+        let token = H160::from_low_u64_be(1);
+        let filter = MetadataSanityFilter::default().with_max_symbol_len(8);
+
+        assert_eq!(filter.check_decimals(token, 18), None);
+        assert_eq!(
+            filter.check_decimals(token, 0),
+            Some(MetadataRejection::DecimalsOutOfBounds { token, decimals: 0 })
+        );
+        assert!(filter.check_decimals(token, 31).is_some());
+
+        assert_eq!(filter.check_symbol(token, Some(b"USDC")), None);
+        assert_eq!(
+            filter.check_symbol(token, None),
+            Some(MetadataRejection::MissingSymbol { token })
+        );
+        assert_eq!(
+            filter.check_symbol(token, Some(b"")),
+            Some(MetadataRejection::MissingSymbol { token })
+        );
+        assert_eq!(
+            filter.check_symbol(token, Some(b"VERYLONGSYMBOL")),
+            Some(MetadataRejection::SymbolTooLong { token, len: 14 })
+        );
+        assert_eq!(
+            filter.check_symbol(token, Some(&[0xff, 0xfe])),
+            Some(MetadataRejection::InvalidSymbol { token })
+        );
+        assert_eq!(
+            filter.check_symbol(token, Some(b"US\x00DC")),
+            Some(MetadataRejection::InvalidSymbol { token })
+        );
+
+        let filter = filter.with_require_symbol(false);
+        assert_eq!(filter.check_symbol(token, None), None);
+    }
+}
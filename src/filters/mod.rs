@@ -1,2 +1,37 @@
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
 pub mod address;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod age;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod compliance;
+pub mod dedupe;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod honeypot;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod metadata;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod pipeline;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod proxy;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod tax;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
 pub mod value;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod whitelist;
+
+pub use dedupe::{dedupe_pools, DedupePolicy, DroppedPool};
+#[cfg(feature = "rpc")]
+pub use pipeline::{
+    AmmFilter, DroppedAmm, FilterCost, FilterOutcome, FilterPipeline, FilterProgress, FilterReport,
+};
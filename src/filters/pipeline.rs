@@ -0,0 +1,447 @@
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use ethers::{providers::Middleware, types::H160};
+use futures::{stream, StreamExt, TryStreamExt};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::UnboundedSender, Semaphore};
+
+use crate::{
+    amm::{factory::TASK_LIMIT, AMM},
+    errors::AMMError,
+};
+
+//Number of AMMs passed to each call of an RPC filter
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilterCost {
+    /// Decides each AMM from its data alone through `AmmFilter::check`, which the pipeline runs in parallel
+    Local,
+    /// Makes RPC calls in `AmmFilter::filter`, which the pipeline calls on concurrent batches of AMMs
+    Rpc,
+}
+
+/// AMM removed by a filter, along with why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DroppedAmm {
+    pub amm: H160,
+    pub reason: String,
+}
+
+impl DroppedAmm {
+    pub fn new(amm: H160, reason: impl Into<String>) -> Self {
+        DroppedAmm {
+            amm,
+            reason: reason.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FilterOutcome {
+    pub kept: Vec<AMM>,
+    pub dropped: Vec<DroppedAmm>,
+}
+
+impl FilterOutcome {
+    /// Splits `amms` into the ones `drop_reason` returns None for, and the ones it returns a drop reason for
+    pub fn partition(
+        amms: Vec<AMM>,
+        mut drop_reason: impl FnMut(&AMM) -> Option<DroppedAmm>,
+    ) -> Self {
+        let mut outcome = FilterOutcome::default();
+
+        for amm in amms {
+            match drop_reason(&amm) {
+                Some(dropped) => outcome.dropped.push(dropped),
+                None => outcome.kept.push(amm),
+            }
+        }
+
+        outcome
+    }
+}
+
+#[async_trait]
+pub trait AmmFilter<M: 'static + Middleware>: Debug + Send + Sync {
+    /// Name the filter's drops are reported under
+    fn name(&self) -> &str;
+
+    fn cost(&self) -> FilterCost;
+
+    /// Returns why a single AMM is dropped, or None if it is kept. Filters of `FilterCost::Local` must implement this.
+    fn check(&self, _amm: &AMM) -> Option<DroppedAmm> {
+        None
+    }
+
+    async fn filter(
+        &self,
+        amms: Vec<AMM>,
+        _middleware: Arc<M>,
+    ) -> Result<FilterOutcome, AMMError<M>> {
+        Ok(FilterOutcome::partition(amms, |amm| self.check(amm)))
+    }
+}
+
+/// AMMs dropped by a pipeline, grouped by the filter that dropped them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterReport {
+    pub input: usize,
+    pub kept: usize,
+    pub dropped: BTreeMap<String, Vec<DroppedAmm>>,
+}
+
+impl FilterReport {
+    pub fn drop_counts(&self) -> BTreeMap<&str, usize> {
+        self.dropped
+            .iter()
+            .map(|(filter, dropped)| (filter.as_str(), dropped.len()))
+            .collect()
+    }
+
+    pub fn total_dropped(&self) -> usize {
+        self.dropped.values().map(Vec::len).sum()
+    }
+
+    /// Adds the counts and drops of a report over a disjoint set of AMMs
+    pub fn merge(&mut self, other: FilterReport) {
+        self.input += other.input;
+        self.kept += other.kept;
+
+        for (filter, dropped) in other.dropped {
+            self.dropped.entry(filter).or_default().extend(dropped);
+        }
+    }
+}
+
+/// Sent every time a filter finishes a batch of AMMs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterProgress {
+    pub filter: String,
+    /// AMMs the filter has processed so far, out of `total`
+    pub processed: usize,
+    pub total: usize,
+    /// AMMs the filter has dropped so far
+    pub dropped: usize,
+}
+
+/// Chains filters, running the ones that decide locally before any that make RPC calls so cheaply rejected AMMs
+/// are never sent to the node. Filters of the same cost run in the order they were added.
+///
+/// Local filters check AMMs in parallel on the rayon thread pool with the `parallel` feature. RPC filters are called on
+/// batches of AMMs, with at most `concurrency` batches in flight across every run sharing the pipeline's semaphore,
+/// i.e. every factory of a sync.
+/// Batches are polled within the future returned by `run`, so dropping it cancels every request without leaving partial results.
+#[derive(Debug)]
+pub struct FilterPipeline<M: 'static + Middleware> {
+    filters: Vec<Box<dyn AmmFilter<M>>>,
+    batch_size: usize,
+    concurrency: usize,
+    semaphore: Arc<Semaphore>,
+    progress: Option<UnboundedSender<FilterProgress>>,
+}
+
+impl<M: 'static + Middleware> Default for FilterPipeline<M> {
+    fn default() -> Self {
+        FilterPipeline {
+            filters: vec![],
+            batch_size: DEFAULT_BATCH_SIZE,
+            concurrency: TASK_LIMIT,
+            semaphore: Arc::new(Semaphore::new(TASK_LIMIT)),
+            progress: None,
+        }
+    }
+}
+
+impl<M: 'static + Middleware> FilterPipeline<M> {
+    pub fn new() -> Self {
+        FilterPipeline::default()
+    }
+
+    pub fn with_filter(mut self, filter: impl AmmFilter<M> + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn with_boxed_filter(mut self, filter: Box<dyn AmmFilter<M>>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Number of AMMs passed to each call of an RPC filter
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Maximum number of RPC filter batches in flight, replacing the pipeline's semaphore
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self.semaphore = Arc::new(Semaphore::new(self.concurrency));
+        self
+    }
+
+    /// Bounds RPC filter batches by a semaphore shared with other pipelines or other RPC heavy work
+    pub fn with_semaphore(mut self, semaphore: Arc<Semaphore>) -> Self {
+        self.concurrency = semaphore.available_permits().max(1);
+        self.semaphore = semaphore;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: UnboundedSender<FilterProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn semaphore(&self) -> Arc<Semaphore> {
+        self.semaphore.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub async fn run(
+        &self,
+        amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<(Vec<AMM>, FilterReport), AMMError<M>> {
+        let mut report = FilterReport {
+            input: amms.len(),
+            ..Default::default()
+        };
+
+        let mut filters = self.filters.iter().collect::<Vec<_>>();
+        filters.sort_by_key(|filter| filter.cost());
+
+        let mut amms = amms;
+        for filter in filters {
+            if amms.is_empty() {
+                break;
+            }
+
+            let total = amms.len();
+            let outcome = match filter.cost() {
+                FilterCost::Local => self.run_local(filter.as_ref(), amms),
+                FilterCost::Rpc => {
+                    self.run_rpc(filter.as_ref(), amms, middleware.clone())
+                        .await?
+                }
+            };
+
+            tracing::debug!(
+                filter = filter.name(),
+                kept = outcome.kept.len(),
+                dropped = outcome.dropped.len(),
+                "filter stage complete"
+            );
+
+            if filter.cost() == FilterCost::Local {
+                self.send_progress(filter.name(), total, total, outcome.dropped.len());
+            }
+
+            if !outcome.dropped.is_empty() {
+                report
+                    .dropped
+                    .entry(filter.name().to_string())
+                    .or_default()
+                    .extend(outcome.dropped);
+            }
+            amms = outcome.kept;
+        }
+
+        report.kept = amms.len();
+        Ok((amms, report))
+    }
+
+    fn run_local(&self, filter: &dyn AmmFilter<M>, amms: Vec<AMM>) -> FilterOutcome {
+        #[cfg(feature = "parallel")]
+        let checked = amms.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let checked = amms.iter();
+
+        let drop_reasons = checked
+            .map(|amm| filter.check(amm))
+            .collect::<Vec<Option<DroppedAmm>>>();
+
+        let mut drop_reasons = drop_reasons.into_iter();
+        FilterOutcome::partition(amms, |_| drop_reasons.next().flatten())
+    }
+
+    //Batches complete out of order but are collected in order, so the output does not depend on the concurrency
+    async fn run_rpc(
+        &self,
+        filter: &dyn AmmFilter<M>,
+        mut amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<FilterOutcome, AMMError<M>> {
+        let total = amms.len();
+
+        let mut batches = vec![];
+        while !amms.is_empty() {
+            let rest = amms.split_off(self.batch_size.min(amms.len()));
+            batches.push(amms);
+            amms = rest;
+        }
+
+        let mut processed = 0;
+        let mut dropped = 0;
+        let outcomes = stream::iter(batches)
+            .map(|batch| {
+                let middleware = middleware.clone();
+
+                async move {
+                    let _permit = self
+                        .semaphore
+                        .acquire()
+                        .await
+                        .expect("Filter pipeline semaphore is never closed");
+
+                    let batch_len = batch.len();
+                    filter
+                        .filter(batch, middleware)
+                        .await
+                        .map(|outcome| (batch_len, outcome))
+                }
+            })
+            .buffered(self.concurrency)
+            .map_ok(|(batch_len, outcome)| {
+                processed += batch_len;
+                dropped += outcome.dropped.len();
+                self.send_progress(filter.name(), processed, total, dropped);
+
+                outcome
+            })
+            .try_collect::<Vec<FilterOutcome>>()
+            .await?;
+
+        let mut outcome = FilterOutcome::default();
+        for batch_outcome in outcomes {
+            outcome.kept.extend(batch_outcome.kept);
+            outcome.dropped.extend(batch_outcome.dropped);
+        }
+
+        Ok(outcome)
+    }
+
+    fn send_progress(&self, filter: &str, processed: usize, total: usize, dropped: usize) {
+        if let Some(progress) = &self.progress {
+            //The receiver may have been dropped if the caller stopped listening
+            let _ = progress.send(FilterProgress {
+                filter: filter.to_string(),
+                processed,
+                total,
+                dropped,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use ethers::{
+        providers::{Http, Middleware, Provider},
+        types::H160,
+    };
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AmmState, AMM},
+        errors::AMMError,
+        filters::address::BlacklistFilter,
+    };
+
+    use super::{AmmFilter, DroppedAmm, FilterCost, FilterOutcome, FilterPipeline};
+
+    //Drops every other AMM it sees, failing if it sees one the blacklist should have dropped first
+    #[derive(Debug)]
+    struct AlternatingFilter {
+        blacklisted: H160,
+    }
+
+    #[async_trait]
+    impl<M: 'static + Middleware> AmmFilter<M> for AlternatingFilter {
+        fn name(&self) -> &str {
+            "alternating"
+        }
+
+        fn cost(&self) -> FilterCost {
+            FilterCost::Rpc
+        }
+
+        async fn filter(
+            &self,
+            amms: Vec<AMM>,
+            _middleware: Arc<M>,
+        ) -> Result<FilterOutcome, AMMError<M>> {
+            assert!(amms.iter().all(|amm| amm.address() != self.blacklisted));
+            assert!(amms.len() <= 2);
+
+            let mut idx = 0;
+            Ok(FilterOutcome::partition(amms, |amm| {
+                idx += 1;
+                (idx % 2 == 0).then(|| DroppedAmm::new(amm.address(), "second in batch"))
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_pipeline() -> eyre::Result<()> {
+        let (progress, mut progress_events) = tokio::sync::mpsc::unbounded_channel();
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let amms = (1..=6)
+            .map(|address| {
+                AMM::UniswapV2Pool(UniswapV2Pool {
+                    address: H160::from_low_u64_be(address),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<AMM>>();
+
+        //The RPC filter is added first but runs after the blacklist, in batches of two
+        let pipeline = FilterPipeline::new()
+            .with_filter(AlternatingFilter {
+                blacklisted: H160::from_low_u64_be(1),
+            })
+            .with_filter(BlacklistFilter::new(vec![], vec![H160::from_low_u64_be(1)]))
+            .with_batch_size(2)
+            .with_concurrency(2)
+            .with_progress(progress);
+
+        let (kept, report) = pipeline.run(amms, middleware).await?;
+        assert_eq!(
+            kept.iter().map(|amm| amm.address()).collect::<Vec<H160>>(),
+            vec![
+                H160::from_low_u64_be(2),
+                H160::from_low_u64_be(4),
+                H160::from_low_u64_be(6)
+            ]
+        );
+
+        assert_eq!(report.input, 6);
+        assert_eq!(report.kept, 3);
+        assert_eq!(report.drop_counts()["blacklist"], 1);
+        assert_eq!(report.drop_counts()["alternating"], 2);
+
+        let mut events = vec![];
+        while let Ok(event) = progress_events.try_recv() {
+            events.push((event.filter, event.processed, event.total, event.dropped));
+        }
+        assert_eq!(
+            events,
+            vec![
+                ("blacklist".to_string(), 6, 6, 1),
+                ("alternating".to_string(), 2, 5, 1),
+                ("alternating".to_string(), 4, 5, 2),
+                ("alternating".to_string(), 5, 5, 2),
+            ]
+        );
+
+        Ok(())
+    }
+}
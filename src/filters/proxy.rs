@@ -0,0 +1,476 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use ethers::{
+    providers::Middleware,
+    types::{Bytes, H160, H256},
+};
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::AMMError,
+};
+
+//keccak256("eip1967.proxy.implementation") - 1
+pub const EIP1967_IMPLEMENTATION_SLOT: H256 = H256([
+    54, 8, 148, 161, 59, 161, 163, 33, 6, 103, 200, 40, 73, 45, 185, 141, 202, 62, 32, 118, 204,
+    55, 53, 169, 32, 163, 202, 80, 93, 56, 43, 188,
+]);
+
+//keccak256("eip1967.proxy.beacon") - 1
+pub const EIP1967_BEACON_SLOT: H256 = H256([
+    163, 240, 173, 116, 229, 66, 58, 235, 253, 128, 211, 239, 67, 70, 87, 131, 53, 169, 167, 42,
+    234, 238, 89, 255, 108, 179, 88, 43, 53, 19, 61, 80,
+]);
+
+//keccak256("PROXIABLE")
+pub const EIP1822_PROXIABLE_SLOT: H256 = H256([
+    197, 241, 111, 15, 204, 99, 159, 164, 138, 105, 71, 131, 109, 152, 80, 245, 4, 121, 133, 35,
+    191, 140, 154, 58, 135, 213, 135, 108, 246, 34, 188, 247,
+]);
+
+//keccak256("org.zeppelinos.proxy.implementation"), used by older OpenZeppelin proxies such as USDC
+pub const ZEPPELINOS_IMPLEMENTATION_SLOT: H256 = H256([
+    112, 80, 201, 224, 244, 202, 118, 156, 105, 189, 58, 142, 247, 64, 188, 55, 147, 79, 142, 44,
+    3, 110, 90, 114, 63, 216, 238, 4, 142, 211, 248, 195,
+]);
+
+const PROXY_SLOTS: [H256; 4] = [
+    EIP1967_IMPLEMENTATION_SLOT,
+    EIP1822_PROXIABLE_SLOT,
+    ZEPPELINOS_IMPLEMENTATION_SLOT,
+    EIP1967_BEACON_SLOT,
+];
+
+//EIP-1167 minimal proxy runtime code, around the 20 byte implementation address
+const MINIMAL_PROXY_PREFIX: [u8; 10] = [54, 61, 61, 55, 61, 61, 61, 54, 61, 115];
+const MINIMAL_PROXY_SUFFIX: [u8; 15] = [
+    90, 244, 61, 130, 128, 62, 144, 61, 145, 96, 43, 87, 253, 91, 243,
+];
+
+const DELEGATECALL: u8 = 0xf4;
+const PUSH1: u8 = 0x60;
+const PUSH32: u8 = 0x7f;
+
+//Number of contracts checked concurrently
+const PROXY_CHECK_STEP: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProxyKind {
+    /// `implementation` is stored at `slot`, and can be upgraded by writing to it
+    ImplementationSlot { slot: H256, implementation: H160 },
+    /// The implementation is read from the beacon stored at the EIP-1967 beacon slot
+    Beacon { beacon: H160 },
+    /// EIP-1167 clone, delegating to an implementation that cannot be changed
+    MinimalProxy { implementation: H160 },
+    /// The code delegates calls without a standard implementation slot
+    Delegatecall,
+}
+
+impl ProxyKind {
+    /// Returns true unless the proxy always delegates to the same implementation
+    pub fn is_upgradeable(&self) -> bool {
+        !matches!(self, ProxyKind::MinimalProxy { .. })
+    }
+
+    //Slot holding the implementation or beacon, with its current value
+    fn watched_slot(&self) -> Option<(H256, H256)> {
+        match self {
+            ProxyKind::ImplementationSlot {
+                slot,
+                implementation,
+            } => Some((*slot, H256::from(*implementation))),
+            ProxyKind::Beacon { beacon } => Some((EIP1967_BEACON_SLOT, H256::from(*beacon))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyInfo {
+    pub pool_is_proxy: bool,
+    pub token_proxies: Vec<H160>,
+    //Kind of every proxy among the pool and its tokens
+    pub proxies: Vec<(H160, ProxyKind)>,
+}
+
+impl ProxyInfo {
+    pub fn is_proxied(&self) -> bool {
+        self.pool_is_proxy || !self.token_proxies.is_empty()
+    }
+}
+
+/// Returns true if the code contains a DELEGATECALL instruction, skipping over push data
+pub fn code_contains_delegatecall(code: &[u8]) -> bool {
+    instructions(code).any(|(opcode, _)| opcode == DELEGATECALL)
+}
+
+//Iterates over the opcodes of `code` along with their push data, which is truncated if the code ends before it
+pub(crate) fn instructions(code: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pc = 0;
+
+    std::iter::from_fn(move || {
+        let opcode = *code.get(pc)?;
+        pc += 1;
+
+        let push_data_len = if (PUSH1..=PUSH32).contains(&opcode) {
+            (opcode - PUSH1 + 1) as usize
+        } else {
+            0
+        };
+        let push_data = &code[pc.min(code.len())..(pc + push_data_len).min(code.len())];
+        pc += push_data_len;
+
+        Some((opcode, push_data))
+    })
+}
+
+/// Classifies a contract from its code and the values of its proxy slots
+pub fn detect_proxy_kind(code: &[u8], slot_values: &[(H256, H256)]) -> Option<ProxyKind> {
+    let minimal_proxy_len = MINIMAL_PROXY_PREFIX.len() + 20 + MINIMAL_PROXY_SUFFIX.len();
+    if code.len() == minimal_proxy_len
+        && code.starts_with(&MINIMAL_PROXY_PREFIX)
+        && code.ends_with(&MINIMAL_PROXY_SUFFIX)
+    {
+        return Some(ProxyKind::MinimalProxy {
+            implementation: H160::from_slice(
+                &code[MINIMAL_PROXY_PREFIX.len()..MINIMAL_PROXY_PREFIX.len() + 20],
+            ),
+        });
+    }
+
+    for (slot, value) in slot_values {
+        if value.is_zero() {
+            continue;
+        }
+
+        let address = H160::from(*value);
+        if *slot == EIP1967_BEACON_SLOT {
+            return Some(ProxyKind::Beacon { beacon: address });
+        } else {
+            return Some(ProxyKind::ImplementationSlot {
+                slot: *slot,
+                implementation: address,
+            });
+        }
+    }
+
+    if code_contains_delegatecall(code) {
+        Some(ProxyKind::Delegatecall)
+    } else {
+        None
+    }
+}
+
+/// Checks the proxy slots and code of every AMM and token address, returning the proxy info of each AMM by address
+pub async fn get_proxy_info<M: Middleware>(
+    amms: &[AMM],
+    middleware: Arc<M>,
+) -> Result<HashMap<H160, ProxyInfo>, AMMError<M>> {
+    let mut addresses = vec![];
+    let mut seen_addresses = HashSet::new();
+    for amm in amms {
+        for address in std::iter::once(amm.address()).chain(amm.tokens()) {
+            if seen_addresses.insert(address) {
+                addresses.push(address);
+            }
+        }
+    }
+
+    let mut proxy_kinds = HashMap::new();
+    for chunk in addresses.chunks(PROXY_CHECK_STEP) {
+        let kinds = try_join_all(
+            chunk
+                .iter()
+                .map(|address| get_code_and_proxy_kind(*address, middleware.clone())),
+        )
+        .await?;
+
+        for (address, (_, kind)) in chunk.iter().zip(kinds) {
+            if let Some(kind) = kind {
+                proxy_kinds.insert(*address, kind);
+            }
+        }
+    }
+
+    Ok(amms
+        .iter()
+        .map(|amm| {
+            let mut proxy_info = ProxyInfo {
+                pool_is_proxy: proxy_kinds.contains_key(&amm.address()),
+                ..Default::default()
+            };
+
+            for address in std::iter::once(amm.address()).chain(amm.tokens()) {
+                if let Some(kind) = proxy_kinds.get(&address) {
+                    if address != amm.address() {
+                        proxy_info.token_proxies.push(address);
+                    }
+                    proxy_info.proxies.push((address, *kind));
+                }
+            }
+
+            (amm.address(), proxy_info)
+        })
+        .collect())
+}
+
+//Returns the code at `address` along with its proxy kind
+pub(crate) async fn get_code_and_proxy_kind<M: Middleware>(
+    address: H160,
+    middleware: Arc<M>,
+) -> Result<(Bytes, Option<ProxyKind>), AMMError<M>> {
+    let code = middleware
+        .get_code(address, None)
+        .await
+        .map_err(AMMError::MiddlewareError)?;
+
+    if code.is_empty() {
+        return Ok((code, None));
+    }
+
+    let slot_values = try_join_all(
+        PROXY_SLOTS
+            .iter()
+            .map(|slot| middleware.get_storage_at(address, *slot, None)),
+    )
+    .await
+    .map_err(AMMError::MiddlewareError)?;
+
+    let proxy_kind = detect_proxy_kind(
+        &code,
+        &PROXY_SLOTS.into_iter().zip(slot_values).collect::<Vec<_>>(),
+    );
+
+    Ok((code, proxy_kind))
+}
+
+/// Removes AMMs that are proxies, and AMMs pairing proxied tokens if `include_tokens` is set.
+/// AMMs missing from `proxy_infos` are kept.
+pub fn filter_proxied_amms(
+    amms: Vec<AMM>,
+    proxy_infos: &HashMap<H160, ProxyInfo>,
+    include_tokens: bool,
+) -> Vec<AMM> {
+    amms.into_iter()
+        .filter(|amm| match proxy_infos.get(&amm.address()) {
+            Some(proxy_info) => {
+                !(proxy_info.pool_is_proxy
+                    || include_tokens && !proxy_info.token_proxies.is_empty())
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Tracks the implementation slots of proxied pools and tokens, so that AMMs can be resynced after an upgrade
+#[derive(Debug, Clone, Default)]
+pub struct ProxyWatcher {
+    //Last seen value of each watched slot, with the AMMs depending on the proxy
+    slots: HashMap<(H160, H256), (H256, Vec<H160>)>,
+}
+
+impl ProxyWatcher {
+    pub fn new(proxy_infos: &HashMap<H160, ProxyInfo>) -> Self {
+        let mut slots: HashMap<(H160, H256), (H256, Vec<H160>)> = HashMap::new();
+
+        for (amm, proxy_info) in proxy_infos {
+            for (proxy, kind) in proxy_info.proxies.iter() {
+                if let Some((slot, value)) = kind.watched_slot() {
+                    slots
+                        .entry((*proxy, slot))
+                        .or_insert((value, vec![]))
+                        .1
+                        .push(*amm);
+                }
+            }
+        }
+
+        ProxyWatcher { slots }
+    }
+
+    /// Storage slots to subscribe to, by contract address
+    pub fn watched_slots(&self) -> Vec<(H160, H256)> {
+        self.slots.keys().copied().collect()
+    }
+
+    /// Applies a storage diff of `address`, as passed to `AmmState::sync_from_storage`,
+    /// returning the AMMs that must be populated again because the pool or one of its tokens was upgraded
+    pub fn apply_storage_diff(&mut self, address: H160, diff: &BTreeMap<H256, H256>) -> Vec<H160> {
+        let mut upgraded_amms = vec![];
+
+        for (slot, new_value) in diff {
+            if let Some((value, amms)) = self.slots.get_mut(&(address, *slot)) {
+                if value != new_value {
+                    *value = *new_value;
+                    upgraded_amms.extend(amms.iter().copied());
+                }
+            }
+        }
+
+        upgraded_amms.sort();
+        upgraded_amms.dedup();
+        upgraded_amms
+    }
+
+    /// Reads every watched slot, returning the AMMs upgraded since the watcher was created or last polled
+    pub async fn poll<M: Middleware>(
+        &mut self,
+        middleware: Arc<M>,
+    ) -> Result<Vec<H160>, AMMError<M>> {
+        let watched_slots = self.watched_slots();
+        let mut upgraded_amms = vec![];
+
+        for chunk in watched_slots.chunks(PROXY_CHECK_STEP) {
+            let values = try_join_all(
+                chunk
+                    .iter()
+                    .map(|(address, slot)| middleware.get_storage_at(*address, *slot, None)),
+            )
+            .await
+            .map_err(AMMError::MiddlewareError)?;
+
+            for ((address, slot), value) in chunk.iter().zip(values) {
+                upgraded_amms
+                    .extend(self.apply_storage_diff(*address, &BTreeMap::from([(*slot, value)])));
+            }
+        }
+
+        upgraded_amms.sort();
+        upgraded_amms.dedup();
+        Ok(upgraded_amms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        str::FromStr,
+        sync::Arc,
+    };
+
+    use ethers::{
+        providers::{Http, Provider},
+        types::{H160, H256},
+    };
+
+    use crate::amm::{uniswap_v2::UniswapV2Pool, AMM};
+
+    use super::{
+        code_contains_delegatecall, detect_proxy_kind, filter_proxied_amms, get_proxy_info,
+        ProxyInfo, ProxyKind, ProxyWatcher, EIP1967_BEACON_SLOT, EIP1967_IMPLEMENTATION_SLOT,
+        MINIMAL_PROXY_PREFIX, MINIMAL_PROXY_SUFFIX,
+    };
+
+    #[test]
+    fn test_detect_proxy_kind() {
+        //PUSH2 0xf4f4 is push data, not a DELEGATECALL
+        assert!(!code_contains_delegatecall(&[0x61, 0xf4, 0xf4, 0x00]));
+        assert!(code_contains_delegatecall(&[0x61, 0xf4, 0xf4, 0xf4]));
+
+        let implementation = H160::from_low_u64_be(1);
+        let mut clone_code = MINIMAL_PROXY_PREFIX.to_vec();
+        clone_code.extend_from_slice(implementation.as_bytes());
+        clone_code.extend_from_slice(&MINIMAL_PROXY_SUFFIX);
+        assert_eq!(
+            detect_proxy_kind(&clone_code, &[]),
+            Some(ProxyKind::MinimalProxy { implementation })
+        );
+
+        let slot_values = [
+            (EIP1967_IMPLEMENTATION_SLOT, H256::zero()),
+            (EIP1967_BEACON_SLOT, H256::from(implementation)),
+        ];
+        assert_eq!(
+            detect_proxy_kind(&[0xf4], &slot_values),
+            Some(ProxyKind::Beacon {
+                beacon: implementation
+            })
+        );
+        assert_eq!(detect_proxy_kind(&[0x00], &slot_values[..1]), None);
+    }
+
+    #[test]
+    fn test_proxy_watcher() {
+        let pool = H160::from_low_u64_be(10);
+        let token = H160::from_low_u64_be(1);
+        let kind = ProxyKind::ImplementationSlot {
+            slot: EIP1967_IMPLEMENTATION_SLOT,
+            implementation: H160::from_low_u64_be(2),
+        };
+
+        let proxy_infos = HashMap::from([(
+            pool,
+            ProxyInfo {
+                pool_is_proxy: false,
+                token_proxies: vec![token],
+                proxies: vec![(token, kind)],
+            },
+        )]);
+
+        let amms = vec![AMM::UniswapV2Pool(UniswapV2Pool {
+            address: pool,
+            token_a: token,
+            ..Default::default()
+        })];
+        assert_eq!(
+            filter_proxied_amms(amms.clone(), &proxy_infos, false).len(),
+            1
+        );
+        assert!(filter_proxied_amms(amms, &proxy_infos, true).is_empty());
+
+        let mut watcher = ProxyWatcher::new(&proxy_infos);
+        assert_eq!(
+            watcher.watched_slots(),
+            vec![(token, EIP1967_IMPLEMENTATION_SLOT)]
+        );
+
+        let unchanged = BTreeMap::from([(
+            EIP1967_IMPLEMENTATION_SLOT,
+            H256::from(H160::from_low_u64_be(2)),
+        )]);
+        assert!(watcher.apply_storage_diff(token, &unchanged).is_empty());
+
+        let upgraded = BTreeMap::from([(
+            EIP1967_IMPLEMENTATION_SLOT,
+            H256::from(H160::from_low_u64_be(3)),
+        )]);
+        assert_eq!(watcher.apply_storage_diff(token, &upgraded), vec![pool]);
+        assert!(watcher.apply_storage_diff(token, &upgraded).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_proxy_info() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //WETH/USDC, USDC is an upgradeable proxy
+        let pool = AMM::UniswapV2Pool(
+            UniswapV2Pool::new_from_address(
+                H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+                300,
+                middleware.clone(),
+            )
+            .await?,
+        );
+
+        let proxy_infos = get_proxy_info(&[pool.clone()], middleware).await?;
+        let proxy_info =
+            &proxy_infos[&H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?];
+
+        assert!(!proxy_info.pool_is_proxy);
+        assert_eq!(
+            proxy_info.token_proxies,
+            vec![H160::from_str(
+                "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+            )?]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use ethers::{
+    contract::Multicall,
+    providers::{call_raw::spoof, Middleware, RawCall},
+    types::{transaction::eip2718::TypedTransaction, Bytes, TransactionRequest, H160, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{
+        uniswap_v2::{IErc20, TransferTax, TAX_BPS_DENOMINATOR},
+        AmmState, AMM,
+    },
+    errors::AMMError,
+};
+
+use super::pipeline::{AmmFilter, DroppedAmm, FilterCost, FilterOutcome};
+
+//Runtime code assembled from contracts/TransferTaxProbe.asm
+const TRANSFER_TAX_PROBE_CODE: &str = "60003560e01c80632350213a14610054576300e8573414610144573660006000376000600036600073ffffffffffffffffffffffffffffffffffffffff30185af43d600060003e61004f573d6000fd5b3d6000f35b63a9059cbb60e01b610100526024356101045260443561012452604435610094576370a0823160e01b600052306004526020610124602460006004355afa505b6370a0823160e01b6000526024356004526020610220602460006004355afa506020610300604461010060006004355af13d156103005115151716610200526020610240602460006004355afa506064351561020051151761013d57632350213a60e01b6104005260043561040452606435610424526102205161024051036104445260006000608461040060006024355af11561013d5760603d1061013d57606060006102603e5b60c0610200f35b61050060045b3681101561019857632350213a60e01b6104005260808160200161040437600060006084610400600085355af11561018b5760c03d1061018b5760c06000833e5b60a0019060c0019061014a565b506105008103610500f3";
+//probeTransfers()
+const PROBE_TRANSFERS_SELECTOR: [u8; 4] = [0, 232, 87, 52];
+//Words returned by the probe for each transfer
+pub(crate) const PROBE_RESULT_WORDS: usize = 6;
+
+//Address the probe is called at, followed by the accounts tokens are transferred to. None of them holds any tokens.
+const PROBE_DRIVER: H160 = H160([0xd0; 20]);
+pub(crate) const PROBE_ACCOUNTS: [H160; 3] = [H160([0xa1; 20]), H160([0xa2; 20]), H160([0xa3; 20])];
+const PROBE_RECEIVERS: [H160; 2] = [PROBE_ACCOUNTS[0], PROBE_ACCOUNTS[1]];
+
+//Number of tokens probed in a single eth_call, the code of every holder is sent as a state override
+pub(crate) const TAX_PROBE_STEP: usize = 50;
+//Number of calls batched into each multicall when getting token balances
+const MULTICALL_STEP: usize = 500;
+//The probe transfers this fraction of the holder's balance
+const PROBE_AMOUNT_DIVISOR: u64 = 1000;
+//Taxes measured for different receivers only differ by rounding unless the tax depends on the receiver
+const TAX_TOLERANCE_BPS: u32 = 1;
+
+/// Transfer taxes of a token, measured by transferring it out of an AMM holding it (buy) and back into the AMM (sell)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenTaxReport {
+    pub token: H160,
+    pub buy_tax_bps: u32,
+    pub sell_tax_bps: u32,
+    pub transfer_reverts: bool,
+    //Set when the receivers were treated differently, i.e. by anti-bot lists. The taxes are then the highest measured.
+    pub nondeterministic: bool,
+}
+
+impl TokenTaxReport {
+    pub fn is_taxed(&self) -> bool {
+        self.buy_tax_bps > 0 || self.sell_tax_bps > 0
+    }
+
+    /// Returns true if the token can be bought and sold with a tax of at most `max_tax_bps`
+    pub fn is_tradable(&self, max_tax_bps: u32) -> bool {
+        !self.transfer_reverts
+            && !self.nondeterministic
+            && self.buy_tax_bps <= max_tax_bps
+            && self.sell_tax_bps <= max_tax_bps
+    }
+
+    pub fn transfer_tax(&self) -> TransferTax {
+        TransferTax::new(self.buy_tax_bps, self.sell_tax_bps)
+    }
+}
+
+//Taxes measured for a single receiver, None if the transfer reverted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TransferMeasurement {
+    buy_tax_bps: Option<u32>,
+    sell_tax_bps: Option<u32>,
+}
+
+impl TransferMeasurement {
+    //Decodes the (ok, before, after, sell_ok, sell_before, sell_after) words returned by the probe
+    fn from_probe_result(amount: U256, words: &[U256]) -> Self {
+        if words[0].is_zero() {
+            return TransferMeasurement {
+                buy_tax_bps: None,
+                sell_tax_bps: None,
+            };
+        }
+
+        let received = words[2].saturating_sub(words[1]);
+        let sell_tax_bps = if words[3].is_zero() {
+            None
+        } else {
+            Some(tax_bps(received, words[5].saturating_sub(words[4])))
+        };
+
+        TransferMeasurement {
+            buy_tax_bps: Some(tax_bps(amount, received)),
+            sell_tax_bps,
+        }
+    }
+
+    fn matches(&self, other: &Self) -> bool {
+        let within_tolerance = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (Some(a), Some(b)) => a.abs_diff(b) <= TAX_TOLERANCE_BPS,
+            (None, None) => true,
+            _ => false,
+        };
+
+        within_tolerance(self.buy_tax_bps, other.buy_tax_bps)
+            && within_tolerance(self.sell_tax_bps, other.sell_tax_bps)
+    }
+}
+
+pub(crate) fn tax_bps(sent: U256, received: U256) -> u32 {
+    if sent.is_zero() {
+        return TAX_BPS_DENOMINATOR;
+    }
+
+    if received >= sent {
+        return 0;
+    }
+
+    ((sent - received) * U256::from(TAX_BPS_DENOMINATOR) / sent).as_u32()
+}
+
+fn report_from_measurements(token: H160, measurements: &[TransferMeasurement]) -> TokenTaxReport {
+    let nondeterministic = measurements
+        .windows(2)
+        .any(|pair| !pair[0].matches(&pair[1]));
+
+    TokenTaxReport {
+        token,
+        buy_tax_bps: measurements
+            .iter()
+            .filter_map(|measurement| measurement.buy_tax_bps)
+            .max()
+            .unwrap_or_default(),
+        sell_tax_bps: measurements
+            .iter()
+            .filter_map(|measurement| measurement.sell_tax_bps)
+            .max()
+            .unwrap_or_default(),
+        transfer_reverts: measurements.iter().any(|measurement| {
+            measurement.buy_tax_bps.is_none() || measurement.sell_tax_bps.is_none()
+        }),
+        nondeterministic,
+    }
+}
+
+/// Measures the transfer taxes of every token in `amms` through `eth_call` with state overrides. Each token is transferred
+/// out of the AMM holding the most of it to two receivers and back, so taxes on buys, sells and receiver dependent taxes
+/// are all detected while the AMM keeps working through its original code. Tokens no AMM holds a balance of are not reported.
+pub async fn get_token_tax_reports<M: 'static + Middleware>(
+    amms: &[AMM],
+    middleware: Arc<M>,
+) -> Result<HashMap<H160, TokenTaxReport>, AMMError<M>> {
+    let holders = get_token_holders(amms, middleware.clone()).await?;
+
+    let mut reports = HashMap::new();
+    for chunk in holders.chunks(TAX_PROBE_STEP) {
+        let transfers = chunk
+            .iter()
+            .flat_map(|(token, holder, amount)| {
+                PROBE_RECEIVERS.map(|receiver| ProbeTransfer {
+                    holder: *holder,
+                    token: *token,
+                    to: receiver,
+                    amount: *amount,
+                    next: *holder,
+                })
+            })
+            .collect::<Vec<ProbeTransfer>>();
+
+        let results = probe_transfers(&transfers, middleware.clone()).await?;
+
+        for ((token, _, amount), token_results) in
+            chunk.iter().zip(results.chunks(PROBE_RECEIVERS.len()))
+        {
+            let measurements = token_results
+                .iter()
+                .map(|words| TransferMeasurement::from_probe_result(*amount, words))
+                .collect::<Vec<TransferMeasurement>>();
+
+            reports.insert(*token, report_from_measurements(*token, &measurements));
+        }
+    }
+
+    Ok(reports)
+}
+
+//Transfer run by the probe, see contracts/TransferTaxProbe.asm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ProbeTransfer {
+    pub holder: H160,
+    pub token: H160,
+    pub to: H160,
+    //Zero transfers the whole balance of the holder
+    pub amount: U256,
+    //If not zero, `to` transfers what it received on to `next`
+    pub next: H160,
+}
+
+//Runs the transfers in a single eth_call, in order and on the same state. Holders that are not probe accounts
+//have their code moved to the complement of their address, which the probe delegates unknown calls to.
+pub(crate) async fn probe_transfers<M: Middleware>(
+    transfers: &[ProbeTransfer],
+    middleware: Arc<M>,
+) -> Result<Vec<[U256; PROBE_RESULT_WORDS]>, AMMError<M>> {
+    let probe_code = Bytes::from_str(TRANSFER_TAX_PROBE_CODE).expect("Probe code is valid hex");
+
+    let mut state = spoof::state();
+    let mut probe_accounts = HashSet::from(PROBE_ACCOUNTS);
+    probe_accounts.insert(PROBE_DRIVER);
+    for account in probe_accounts.iter() {
+        state.account(*account).code(probe_code.clone());
+    }
+
+    let mut calldata = PROBE_TRANSFERS_SELECTOR.to_vec();
+    for transfer in transfers {
+        if probe_accounts.insert(transfer.holder) {
+            let holder_code = middleware
+                .get_code(transfer.holder, None)
+                .await
+                .map_err(AMMError::MiddlewareError)?;
+
+            state.account(transfer.holder).code(probe_code.clone());
+            state.account(complement(transfer.holder)).code(holder_code);
+        }
+
+        for word in [
+            address_word(transfer.holder),
+            address_word(transfer.token),
+            address_word(transfer.to),
+            u256_word(transfer.amount),
+            address_word(transfer.next),
+        ] {
+            calldata.extend_from_slice(&word);
+        }
+    }
+
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(PROBE_DRIVER)
+        .data(calldata)
+        .into();
+    let return_data = middleware.provider().call_raw(&tx).state(&state).await?;
+
+    if return_data.len() != transfers.len() * PROBE_RESULT_WORDS * 32 {
+        return Err(AMMError::BatchRequestError(PROBE_DRIVER));
+    }
+
+    Ok(return_data
+        .chunks(PROBE_RESULT_WORDS * 32)
+        .map(|result| {
+            let mut words = [U256::zero(); PROBE_RESULT_WORDS];
+            for (word, data) in words.iter_mut().zip(result.chunks(32)) {
+                *word = U256::from_big_endian(data);
+            }
+            words
+        })
+        .collect())
+}
+
+//Returns each token with the AMM holding the largest balance of it, and the amount the probe transfers out of that AMM
+pub(crate) async fn get_token_holders<M: 'static + Middleware>(
+    amms: &[AMM],
+    middleware: Arc<M>,
+) -> Result<Vec<(H160, H160, U256)>, AMMError<M>> {
+    let mut balances = vec![];
+    let mut seen_balances = HashSet::new();
+    for amm in amms {
+        for token in amm.tokens() {
+            if seen_balances.insert((amm.address(), token)) {
+                balances.push((amm.address(), token));
+            }
+        }
+    }
+
+    let mut holders: HashMap<H160, (H160, U256)> = HashMap::new();
+    for chunk in balances.chunks(MULTICALL_STEP) {
+        let mut multicall = Multicall::new(middleware.clone(), None).await?;
+        for (amm, token) in chunk {
+            multicall.add_call(
+                IErc20::new(*token, middleware.clone()).balance_of(*amm),
+                true,
+            );
+        }
+
+        for ((amm, token), result) in chunk.iter().zip(multicall.call_raw().await?) {
+            if let Some(balance) = result.ok().and_then(|balance| balance.into_uint()) {
+                let holder = holders.entry(*token).or_insert((*amm, U256::zero()));
+                if balance > holder.1 {
+                    *holder = (*amm, balance);
+                }
+            }
+        }
+    }
+
+    Ok(balances
+        .iter()
+        .filter_map(|(_, token)| {
+            let (holder, balance) = holders.remove(token)?;
+            let amount = balance / U256::from(PROBE_AMOUNT_DIVISOR);
+
+            (!amount.is_zero()).then_some((*token, holder, amount))
+        })
+        .collect())
+}
+
+/// Removes AMMs with a token that reverts on transfer, has a receiver dependent tax or a tax above `max_tax_bps`.
+/// AMMs with tokens missing from `reports` are kept.
+pub fn filter_taxed_amms(
+    amms: Vec<AMM>,
+    reports: &HashMap<H160, TokenTaxReport>,
+    max_tax_bps: u32,
+) -> Vec<AMM> {
+    amms.into_iter()
+        .filter(|amm| {
+            amm.tokens().iter().all(|token| {
+                reports
+                    .get(token)
+                    .map_or(true, |report| report.is_tradable(max_tax_bps))
+            })
+        })
+        .collect()
+}
+
+/// Pipeline filter dropping AMMs with tokens that are not tradable with a tax of at most `max_tax_bps`, see `filter_taxed_amms`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferTaxFilter {
+    pub max_tax_bps: u32,
+}
+
+impl TransferTaxFilter {
+    pub fn new(max_tax_bps: u32) -> Self {
+        TransferTaxFilter { max_tax_bps }
+    }
+}
+
+#[async_trait]
+impl<M: 'static + Middleware> AmmFilter<M> for TransferTaxFilter {
+    fn name(&self) -> &str {
+        "transfer_tax"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Rpc
+    }
+
+    async fn filter(
+        &self,
+        amms: Vec<AMM>,
+        middleware: Arc<M>,
+    ) -> Result<FilterOutcome, AMMError<M>> {
+        let reports = get_token_tax_reports(&amms, middleware).await?;
+
+        Ok(FilterOutcome::partition(amms, |amm| {
+            amm.tokens()
+                .iter()
+                .filter_map(|token| reports.get(token))
+                .find(|report| !report.is_tradable(self.max_tax_bps))
+                .map(|report| {
+                    let reason = if report.transfer_reverts {
+                        format!("token {:?} reverts on transfer", report.token)
+                    } else if report.nondeterministic {
+                        format!("token {:?} taxes depend on the receiver", report.token)
+                    } else {
+                        format!(
+                            "token {:?} taxed {} bps on buys and {} bps on sells",
+                            report.token, report.buy_tax_bps, report.sell_tax_bps
+                        )
+                    };
+
+                    DroppedAmm::new(amm.address(), reason)
+                })
+        }))
+    }
+}
+
+/// Removes AMMs containing fee-on-transfer tokens, or tokens that cannot be transferred out of and back into the AMM
+pub async fn filter_fee_on_transfer_tokens<M: 'static + Middleware>(
+    amms: Vec<AMM>,
+    middleware: Arc<M>,
+) -> Result<Vec<AMM>, AMMError<M>> {
+    let reports = get_token_tax_reports(&amms, middleware).await?;
+
+    Ok(filter_taxed_amms(amms, &reports, 0))
+}
+
+/// Sets the transfer taxes of the tokens in Uniswap V2 pools so that swap simulations account for them.
+/// Nondeterministic reports are skipped since their taxes do not apply to every trader.
+pub fn apply_transfer_taxes(amms: &mut [AMM], reports: &HashMap<H160, TokenTaxReport>) {
+    for amm in amms {
+        if let AMM::UniswapV2Pool(pool) = amm {
+            let transfer_tax = |token: &H160| {
+                reports
+                    .get(token)
+                    .filter(|report| !report.nondeterministic)
+                    .map(TokenTaxReport::transfer_tax)
+            };
+
+            if let Some(tax) = transfer_tax(&pool.token_a) {
+                pool.token_a_transfer_tax = tax;
+            }
+            if let Some(tax) = transfer_tax(&pool.token_b) {
+                pool.token_b_transfer_tax = tax;
+            }
+        }
+    }
+}
+
+fn complement(address: H160) -> H160 {
+    H160(address.0.map(|byte| !byte))
+}
+
+fn address_word(address: H160) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address.as_bytes());
+    word
+}
+
+fn u256_word(value: U256) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+    use ethers::{
+        providers::{Http, Provider},
+        types::{Bytes, H160, U256},
+    };
+
+    use crate::amm::{uniswap_v2::UniswapV2Pool, AMM};
+
+    use super::{
+        apply_transfer_taxes, filter_taxed_amms, get_token_tax_reports, report_from_measurements,
+        TokenTaxReport, TransferMeasurement, TRANSFER_TAX_PROBE_CODE,
+    };
+
+    fn words(values: [u64; 6]) -> Vec<U256> {
+        values.into_iter().map(U256::from).collect()
+    }
+
+    fn report(token: u64, buy_tax_bps: u32, sell_tax_bps: u32) -> TokenTaxReport {
+        TokenTaxReport {
+            token: H160::from_low_u64_be(token),
+            buy_tax_bps,
+            sell_tax_bps,
+            transfer_reverts: false,
+            nondeterministic: false,
+        }
+    }
+
+    fn pool(address: u64, token_a: u64, token_b: u64) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: H160::from_low_u64_be(token_a),
+            token_b: H160::from_low_u64_be(token_b),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_probe_code_is_valid_hex() {
+        assert!(!Bytes::from_str(TRANSFER_TAX_PROBE_CODE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_measure_transfer_taxes() {
+        let amount = U256::from(1000);
+
+        //5% is taxed on the way out of the pool and 10% of the received 950 on the way back
+        let taxed =
+            TransferMeasurement::from_probe_result(amount, &words([1, 0, 950, 1, 999000, 999855]));
+        assert_eq!(taxed.buy_tax_bps, Some(500));
+        assert_eq!(taxed.sell_tax_bps, Some(1000));
+
+        let unsellable =
+            TransferMeasurement::from_probe_result(amount, &words([1, 0, 1000, 0, 0, 0]));
+        assert_eq!(unsellable.buy_tax_bps, Some(0));
+        assert_eq!(unsellable.sell_tax_bps, None);
+
+        let reverted = TransferMeasurement::from_probe_result(amount, &words([0; 6]));
+        let report = report_from_measurements(H160::zero(), &[taxed, taxed]);
+        assert!(!report.transfer_reverts && !report.nondeterministic && report.is_taxed());
+
+        let report = report_from_measurements(H160::zero(), &[unsellable, unsellable]);
+        assert!(report.transfer_reverts && !report.nondeterministic);
+
+        //A receiver being blocked means the outcome depends on who trades
+        let report = report_from_measurements(H160::zero(), &[taxed, reverted]);
+        assert!(report.transfer_reverts && report.nondeterministic);
+        assert_eq!((report.buy_tax_bps, report.sell_tax_bps), (500, 1000));
+    }
+
+    #[test]
+    fn test_filter_taxed_amms() {
+        let mut reports = HashMap::new();
+        reports.insert(H160::from_low_u64_be(1), report(1, 0, 0));
+        reports.insert(H160::from_low_u64_be(2), report(2, 300, 300));
+        reports.insert(
+            H160::from_low_u64_be(3),
+            TokenTaxReport {
+                nondeterministic: true,
+                ..report(3, 0, 0)
+            },
+        );
+
+        let amms = vec![pool(10, 1, 2), pool(20, 1, 3), pool(30, 1, 4)];
+        assert_eq!(filter_taxed_amms(amms.clone(), &reports, 0).len(), 1);
+        assert_eq!(filter_taxed_amms(amms.clone(), &reports, 500).len(), 2);
+
+        let mut amms = amms;
+        apply_transfer_taxes(&mut amms, &reports);
+        if let AMM::UniswapV2Pool(pool) = &amms[0] {
+            assert!(pool.token_a_transfer_tax.is_zero());
+            assert_eq!(pool.token_b_transfer_tax.sell_bps, 300);
+        }
+        if let AMM::UniswapV2Pool(pool) = &amms[1] {
+            assert!(!pool.has_transfer_tax());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_token_tax_reports() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //WETH/USDC
+        let pool = AMM::UniswapV2Pool(
+            UniswapV2Pool::new_from_address(
+                H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+                300,
+                middleware.clone(),
+            )
+            .await?,
+        );
+
+        let reports = get_token_tax_reports(&[pool], middleware).await?;
+        assert_eq!(reports.len(), 2);
+        for report in reports.values() {
+            assert!(report.is_tradable(0), "{:?}", report);
+        }
+
+        Ok(())
+    }
+}
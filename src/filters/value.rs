@@ -1,19 +1,113 @@
+use async_trait::async_trait;
 use ethers::{
     abi::{ParamType, Token},
+    contract::Multicall,
     prelude::abigen,
     providers::Middleware,
     types::{Bytes, H160, U256},
 };
-use std::sync::Arc;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
 use crate::{
-    amm::{factory::AutomatedMarketMakerFactory, factory::Factory, AutomatedMarketMaker, AMM},
+    amm::{
+        bancor_v3, factory::AutomatedMarketMakerFactory, factory::Factory, uniswap_v2::IErc20,
+        uniswap_v3::UniswapV3Pool, AmmState, AMM,
+    },
     errors::AMMError,
+    math::fixed_point::u256_to_f64,
+    tokens::TokenStore,
 };
 
+use super::pipeline::{AmmFilter, DroppedAmm, FilterCost};
+
 pub const U256_10_POW_18: U256 = U256([1000000000000000000, 0, 0, 0]);
 pub const U256_10_POW_6: U256 = U256([1000000, 0, 0, 0]);
 
+//Value multiplier applied to pools that are only priced through a single thin connector pool
+pub const THIN_CONNECTOR_DISCOUNT: f64 = 0.5;
+//Number of calls batched into each multicall when getting token balances
+const MULTICALL_STEP: usize = 500;
+
+abigen!(
+    IPriceFeed,
+    r#"[
+        function latestRoundData() external view returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)
+        function decimals() external view returns (uint8)
+    ]"#;
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NumerairePrice {
+    /// USD price of one whole token
+    Fixed(f64),
+    /// Chainlink style aggregator reporting the USD price through `latestRoundData`
+    Feed(H160),
+}
+
+/// Token with a known USD price that pools are valued against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Numeraire {
+    pub token: H160,
+    pub price: NumerairePrice,
+}
+
+impl Numeraire {
+    pub fn fixed(token: H160, usd_price: f64) -> Self {
+        Numeraire {
+            token,
+            price: NumerairePrice::Fixed(usd_price),
+        }
+    }
+
+    pub fn feed(token: H160, price_feed: H160) -> Self {
+        Numeraire {
+            token,
+            price: NumerairePrice::Feed(price_feed),
+        }
+    }
+}
+
+/// WETH, WBTC, USDC, USDT and DAI on Ethereum, with WETH and WBTC priced by the Chainlink ETH/USD and BTC/USD feeds
+pub fn ethereum_numeraires() -> Vec<Numeraire> {
+    let address = |address: &str| H160::from_str(address).expect("Numeraire address is valid");
+
+    vec![
+        Numeraire::feed(
+            address("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"),
+            address("0x5f4eC3Df9cbd43714FE2740f5E3616155c5b8419"),
+        ),
+        Numeraire::feed(
+            address("0x2260FAC5E5542a773Aa44fBCfeDf7C193bc2C599"),
+            address("0xF4030086522a5bEEa4988F8cA5B36dbC97BeE88c"),
+        ),
+        Numeraire::fixed(address("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"), 1.0),
+        Numeraire::fixed(address("0xdAC17F958D2ee523a2206206994597C13D831ec7"), 1.0),
+        Numeraire::fixed(address("0x6B175474E89094C44Da98b954EedeAC495271d0F"), 1.0),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValuationPath {
+    /// The AMM holds the numeraire
+    Direct {
+        numeraire: H160,
+    },
+    /// `token` is priced through the `connector` AMM pairing it with a numeraire
+    OneHop {
+        token: H160,
+        connector: H160,
+        discounted: bool,
+    },
+    Unvalued,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmmValuation {
+    pub amm: H160,
+    pub usd_value: f64,
+    pub path: ValuationPath,
+}
+
 #[allow(clippy::too_many_arguments)]
 //Filter that removes AMMs with that contain less than a specified usd value
 pub async fn filter_amms_below_usd_threshold<M: Middleware>(
@@ -44,9 +138,7 @@ pub async fn filter_amms_below_usd_threshold<M: Middleware>(
     .await?;
 
     for (i, weth_value) in weth_values_in_pools.iter().enumerate() {
-        if (weth_value / U256_10_POW_18).as_u64() as f64 * weth_usd_price
-            >= usd_value_in_pool_threshold
-        {
+        if u256_to_f64(*weth_value, 18) * weth_usd_price >= usd_value_in_pool_threshold {
             //TODO: using clone for now since we only do this once but find a better way in a future update
             filtered_amms.push(amms[i].clone());
         }
@@ -150,11 +242,29 @@ async fn get_weth_value_in_amm_batch_request<M: Middleware>(
         .map(|a| Token::Address(a.address()))
         .collect::<Vec<Token>>();
 
+    //The batch contract looks pairs up with `getPair` and `getPool(address,address,uint24)`, which Velodrome
+    //factories, the Uniswap V4 PoolManager, Maverick, KyberSwap Elastic and Algebra factories do not implement, KyberSwap
+    //Elastic factories taking fees in units the V3 fee tiers of the batch contract do not cover and Algebra factories
+    //looking pairs up with `poolByPair`
+    let factories = factories
+        .iter()
+        .filter(|factory| {
+            !matches!(
+                factory,
+                Factory::VelodromeFactory(_)
+                    | Factory::UniswapV4Factory(_)
+                    | Factory::MaverickFactory(_)
+                    | Factory::KyberElasticFactory(_)
+                    | Factory::AlgebraFactory(_)
+            )
+        })
+        .collect::<Vec<&Factory>>();
+
     let factory_is_uni_v3 = factories
         .iter()
         .map(|d| match d {
-            Factory::UniswapV2Factory(_) => Token::Bool(false),
             Factory::UniswapV3Factory(_) => Token::Bool(true),
+            _ => Token::Bool(false),
         })
         .collect::<Vec<Token>>();
 
@@ -191,3 +301,762 @@ async fn get_weth_value_in_amm_batch_request<M: Middleware>(
 
     Ok(weth_values_in_pools)
 }
+
+/// Same as `filter_amms_below_usd_threshold`, valuing each AMM through any of the `numeraires` it holds or one hop away.
+/// Returns the AMMs at or above the threshold along with the valuation of every AMM.
+pub async fn filter_amms_below_usd_threshold_with_numeraires<M: 'static + Middleware>(
+    amms: Vec<AMM>,
+    numeraires: &[Numeraire],
+    usd_value_in_pool_threshold: f64,
+    thin_connector_usd_value: f64, // Tokens only priced through a single connector below this value are discounted
+    middleware: Arc<M>,
+) -> Result<(Vec<AMM>, Vec<AmmValuation>), AMMError<M>> {
+    tracing::info!("filtering AMMs below USD threshold with numeraires");
+
+    let valuations =
+        get_usd_values_in_amms(&amms, numeraires, thin_connector_usd_value, middleware).await?;
+
+    let filtered_amms = amms
+        .into_iter()
+        .zip(valuations.iter())
+        .filter(|(_, valuation)| valuation.usd_value >= usd_value_in_pool_threshold)
+        .map(|(amm, _)| amm)
+        .collect();
+
+    tracing::info!("all AMMs filtered");
+    Ok((filtered_amms, valuations))
+}
+
+/// Values each AMM from its token balances, taking the best estimate through any numeraire it holds.
+/// AMMs without a numeraire are valued through the deepest AMM in `amms` pairing one of their tokens with a numeraire.
+pub async fn get_usd_values_in_amms<M: 'static + Middleware>(
+    amms: &[AMM],
+    numeraires: &[Numeraire],
+    thin_connector_usd_value: f64,
+    middleware: Arc<M>,
+) -> Result<Vec<AmmValuation>, AMMError<M>> {
+    get_usd_values_in_amms_with_token_store(
+        amms,
+        numeraires,
+        thin_connector_usd_value,
+        &TokenStore::new(),
+        middleware,
+    )
+    .await
+}
+
+/// Same as `get_usd_values_in_amms`, reading token decimals from `token_store` and only querying tokens it does not know
+pub async fn get_usd_values_in_amms_with_token_store<M: 'static + Middleware>(
+    amms: &[AMM],
+    numeraires: &[Numeraire],
+    thin_connector_usd_value: f64,
+    token_store: &TokenStore,
+    middleware: Arc<M>,
+) -> Result<Vec<AmmValuation>, AMMError<M>> {
+    let numeraire_prices = get_numeraire_usd_prices(numeraires, middleware.clone()).await?;
+    let token_amounts = get_token_amounts_in_amms(amms, token_store, middleware).await?;
+
+    Ok(value_amms(
+        amms,
+        &token_amounts,
+        &numeraire_prices,
+        thin_connector_usd_value,
+    ))
+}
+
+async fn get_numeraire_usd_prices<M: 'static + Middleware>(
+    numeraires: &[Numeraire],
+    middleware: Arc<M>,
+) -> Result<HashMap<H160, f64>, AMMError<M>> {
+    let mut numeraire_prices = HashMap::new();
+    let mut price_feeds = vec![];
+
+    for numeraire in numeraires {
+        match numeraire.price {
+            NumerairePrice::Fixed(usd_price) => {
+                numeraire_prices.insert(numeraire.token, usd_price);
+            }
+            NumerairePrice::Feed(price_feed) => price_feeds.push((numeraire.token, price_feed)),
+        }
+    }
+
+    if price_feeds.is_empty() {
+        return Ok(numeraire_prices);
+    }
+
+    let mut multicall = Multicall::new(middleware.clone(), None).await?;
+    for (_, price_feed) in price_feeds.iter() {
+        let price_feed = IPriceFeed::new(*price_feed, middleware.clone());
+        multicall.add_call(price_feed.latest_round_data(), true);
+        multicall.add_call(price_feed.decimals(), true);
+    }
+
+    let results = multicall.call_raw().await?;
+    for ((token, price_feed), result) in price_feeds.into_iter().zip(results.chunks(2)) {
+        let answer = result[0]
+            .clone()
+            .ok()
+            .and_then(|round_data| round_data.into_tuple())
+            .and_then(|round_data| round_data.get(1).cloned())
+            .and_then(|answer| answer.into_int());
+        let decimals = result[1]
+            .clone()
+            .ok()
+            .and_then(|decimals| decimals.into_uint());
+
+        match (answer, decimals) {
+            //Negative answers are invalid prices
+            (Some(answer), Some(decimals)) if !answer.bit(255) => {
+                numeraire_prices.insert(token, u256_to_f64(answer, decimals.as_u32() as u8));
+            }
+            _ => tracing::warn!(?token, ?price_feed, "could not get numeraire price"),
+        }
+    }
+
+    Ok(numeraire_prices)
+}
+
+//Gets the balance of each token held by each AMM, in whole tokens and in the order of `amm.tokens()`
+async fn get_token_amounts_in_amms<M: 'static + Middleware>(
+    amms: &[AMM],
+    token_store: &TokenStore,
+    middleware: Arc<M>,
+) -> Result<Vec<Vec<f64>>, AMMError<M>> {
+    //Populated AMMs already hold the decimals of their tokens, only the remaining tokens are queried
+    token_store.record_amm_decimals(amms);
+    let tokens = amms
+        .iter()
+        .flat_map(|amm| amm.tokens())
+        .collect::<Vec<H160>>();
+    token_store.populate(&tokens, middleware.clone()).await?;
+
+    let mut token_amounts = amms
+        .iter()
+        .map(|amm| vec![0.0; amm.tokens().len()])
+        .collect::<Vec<Vec<f64>>>();
+
+    let balances = amms
+        .iter()
+        .enumerate()
+        .flat_map(|(amm_idx, amm)| {
+            amm.tokens()
+                .into_iter()
+                .enumerate()
+                .map(move |(token_idx, token)| (amm_idx, token_idx, amm.address(), token))
+        })
+        .collect::<Vec<(usize, usize, H160, H160)>>();
+
+    for chunk in balances.chunks(MULTICALL_STEP) {
+        let mut multicall = Multicall::new(middleware.clone(), None).await?;
+        for (_, _, amm, token) in chunk {
+            multicall.add_call(
+                IErc20::new(*token, middleware.clone()).balance_of(*amm),
+                true,
+            );
+        }
+
+        for ((amm_idx, token_idx, _, token), result) in
+            chunk.iter().zip(multicall.call_raw().await?)
+        {
+            //Tokens without decimals cannot be valued and are left at zero
+            if let (Some(balance), Some(decimals)) = (
+                result.ok().and_then(|balance| balance.into_uint()),
+                token_store.decimals(*token),
+            ) {
+                token_amounts[*amm_idx][*token_idx] = u256_to_f64(balance, decimals);
+            }
+        }
+    }
+
+    Ok(token_amounts)
+}
+
+//Deepest AMM pairing a token with a numeraire, used to price that token
+struct Connector {
+    amm: H160,
+    usd_price: f64,
+    usd_value: f64,
+    count: usize,
+}
+
+fn value_amms(
+    amms: &[AMM],
+    token_amounts: &[Vec<f64>],
+    numeraire_prices: &HashMap<H160, f64>,
+    thin_connector_usd_value: f64,
+) -> Vec<AmmValuation> {
+    //Value the AMMs holding a numeraire, assuming the value of each token in the AMM is equal
+    let mut valuations = amms
+        .iter()
+        .zip(token_amounts)
+        .map(|(amm, amounts)| {
+            let tokens = amm.tokens();
+            let mut valuation = AmmValuation {
+                amm: amm.address(),
+                usd_value: 0.0,
+                path: ValuationPath::Unvalued,
+            };
+
+            for (token, amount) in tokens.iter().zip(amounts) {
+                if let Some(usd_price) = numeraire_prices.get(token) {
+                    let usd_value = amount * usd_price * tokens.len() as f64;
+                    if usd_value > valuation.usd_value {
+                        valuation.usd_value = usd_value;
+                        valuation.path = ValuationPath::Direct { numeraire: *token };
+                    }
+                }
+            }
+
+            valuation
+        })
+        .collect::<Vec<AmmValuation>>();
+
+    //Price the other token of each directly valued pair through the deepest pair it is found in
+    let mut connectors: HashMap<H160, Connector> = HashMap::new();
+    for (amm, valuation) in amms.iter().zip(valuations.iter()) {
+        let numeraire = match valuation.path {
+            ValuationPath::Direct { numeraire } if amm.tokens().len() == 2 => numeraire,
+            _ => continue,
+        };
+
+        let token = match amm.opp_token(numeraire) {
+            Some(token) if !numeraire_prices.contains_key(&token) => token,
+            _ => continue,
+        };
+
+        let usd_price = match amm.calculate_price(token) {
+            Ok(price) if price.is_finite() && price > 0.0 => price * numeraire_prices[&numeraire],
+            _ => continue,
+        };
+
+        let connector = connectors.entry(token).or_insert(Connector {
+            amm: amm.address(),
+            usd_price,
+            usd_value: valuation.usd_value,
+            count: 0,
+        });
+
+        connector.count += 1;
+        if valuation.usd_value > connector.usd_value {
+            connector.amm = amm.address();
+            connector.usd_price = usd_price;
+            connector.usd_value = valuation.usd_value;
+        }
+    }
+
+    //Value the remaining AMMs one hop away, never above the depth of the connector they are priced through
+    for ((amm, amounts), valuation) in amms.iter().zip(token_amounts).zip(valuations.iter_mut()) {
+        if valuation.path != ValuationPath::Unvalued {
+            continue;
+        }
+
+        let tokens = amm.tokens();
+        for (token, amount) in tokens.iter().zip(amounts) {
+            if let Some(connector) = connectors.get(token) {
+                let mut usd_value =
+                    (amount * connector.usd_price * tokens.len() as f64).min(connector.usd_value);
+
+                let discounted =
+                    connector.count == 1 && connector.usd_value < thin_connector_usd_value;
+                if discounted {
+                    usd_value *= THIN_CONNECTOR_DISCOUNT;
+                }
+
+                if usd_value > valuation.usd_value {
+                    valuation.usd_value = usd_value;
+                    valuation.path = ValuationPath::OneHop {
+                        token: *token,
+                        connector: connector.amm,
+                        discounted,
+                    };
+                }
+            }
+        }
+    }
+
+    valuations
+}
+
+/// Source of USD prices for whole tokens
+pub trait PriceOracle {
+    fn usd_price(&self, token: H160) -> Option<f64>;
+}
+
+impl PriceOracle for HashMap<H160, f64> {
+    fn usd_price(&self, token: H160) -> Option<f64> {
+        self.get(&token).copied()
+    }
+}
+
+/// Prices fetched from Chainlink style aggregators, along with any fixed numeraire prices
+#[derive(Debug, Clone, Default)]
+pub struct ChainlinkOracle {
+    prices: HashMap<H160, f64>,
+}
+
+impl ChainlinkOracle {
+    /// Fetches the latest answer of every price feed in `numeraires` in a single multicall
+    pub async fn new<M: 'static + Middleware>(
+        numeraires: &[Numeraire],
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        Ok(ChainlinkOracle {
+            prices: get_numeraire_usd_prices(numeraires, middleware).await?,
+        })
+    }
+
+    pub fn prices(&self) -> &HashMap<H160, f64> {
+        &self.prices
+    }
+}
+
+impl PriceOracle for ChainlinkOracle {
+    fn usd_price(&self, token: H160) -> Option<f64> {
+        self.prices.usd_price(token)
+    }
+}
+
+/// Prices derived from the AMMs themselves, starting from the anchor prices and walking the pool graph breadth first.
+/// Each token is priced through the fewest hops from an anchor, using the AMM with the most anchored liquidity at that depth.
+#[derive(Debug, Clone, Default)]
+pub struct AmmPriceOracle {
+    prices: HashMap<H160, f64>,
+}
+
+impl AmmPriceOracle {
+    /// AMMs with less than `min_usd_liquidity` of an already priced token are not used to price their other tokens
+    pub fn new(amms: &[AMM], anchors: &HashMap<H160, f64>, min_usd_liquidity: f64) -> Self {
+        let mut amms_by_token: HashMap<H160, Vec<usize>> = HashMap::new();
+        for (amm_idx, amm) in amms.iter().enumerate() {
+            for token in amm.tokens() {
+                amms_by_token.entry(token).or_default().push(amm_idx);
+            }
+        }
+
+        let mut prices = anchors.clone();
+        let mut frontier = anchors.keys().copied().collect::<Vec<H160>>();
+        while !frontier.is_empty() {
+            //Best (usd_price, usd_liquidity) found for each token at this depth
+            let mut candidates: HashMap<H160, (f64, f64)> = HashMap::new();
+
+            for token in frontier {
+                let token_price = prices[&token];
+
+                for amm_idx in amms_by_token.get(&token).into_iter().flatten() {
+                    let amm = &amms[*amm_idx];
+                    let tokens = amm.tokens();
+                    let reserves = token_reserves(amm);
+
+                    let usd_liquidity = tokens
+                        .iter()
+                        .zip(reserves.iter())
+                        .find(|(reserve_token, _)| **reserve_token == token)
+                        .map(|(_, reserve)| reserve * token_price)
+                        .unwrap_or_default();
+                    if usd_liquidity < min_usd_liquidity {
+                        continue;
+                    }
+
+                    for other_token in tokens {
+                        if prices.contains_key(&other_token) {
+                            continue;
+                        }
+
+                        //calculate_price returns the amount of `token` per `other_token` in two token AMMs
+                        let usd_price = match amm.calculate_price(other_token) {
+                            Ok(price) if price.is_finite() && price > 0.0 => price * token_price,
+                            _ => continue,
+                        };
+
+                        let candidate = candidates
+                            .entry(other_token)
+                            .or_insert((usd_price, usd_liquidity));
+                        if usd_liquidity > candidate.1 {
+                            *candidate = (usd_price, usd_liquidity);
+                        }
+                    }
+                }
+            }
+
+            frontier = candidates.keys().copied().collect();
+            prices.extend(
+                candidates
+                    .into_iter()
+                    .map(|(token, (usd_price, _))| (token, usd_price)),
+            );
+        }
+
+        AmmPriceOracle { prices }
+    }
+
+    pub fn prices(&self) -> &HashMap<H160, f64> {
+        &self.prices
+    }
+}
+
+impl PriceOracle for AmmPriceOracle {
+    fn usd_price(&self, token: H160) -> Option<f64> {
+        self.prices.usd_price(token)
+    }
+}
+
+/// Keeps AMMs whose USD value computed from their synced reserves is at or above a threshold. No calls are made,
+/// so the filter scales to any number of AMMs once the oracle is built.
+#[derive(Debug, Clone)]
+pub struct UsdValueFilter<O: PriceOracle> {
+    oracle: O,
+    usd_threshold: f64,
+}
+
+impl<O: PriceOracle> UsdValueFilter<O> {
+    pub fn new(oracle: O, usd_threshold: f64) -> Self {
+        UsdValueFilter {
+            oracle,
+            usd_threshold,
+        }
+    }
+
+    pub fn oracle(&self) -> &O {
+        &self.oracle
+    }
+
+    pub fn usd_threshold(&self) -> f64 {
+        self.usd_threshold
+    }
+
+    /// Returns the USD value locked in the AMM, or None if none of its tokens has a price.
+    /// Tokens without a price are assumed to be worth as much as the priced tokens on average.
+    pub fn usd_value(&self, amm: &AMM) -> Option<f64> {
+        let tokens = amm.tokens();
+        let mut priced_tokens = 0;
+        let mut usd_value = 0.0;
+
+        for (token, reserve) in tokens.iter().zip(token_reserves(amm)) {
+            if let Some(usd_price) = self.oracle.usd_price(*token) {
+                priced_tokens += 1;
+                usd_value += reserve * usd_price;
+            }
+        }
+
+        if priced_tokens == 0 {
+            None
+        } else {
+            Some(usd_value * tokens.len() as f64 / priced_tokens as f64)
+        }
+    }
+
+    pub fn filter(&self, amms: Vec<AMM>) -> Vec<AMM> {
+        self.filter_with_usd_values(amms).0
+    }
+
+    /// Same as `filter`, also returning the USD value of every valued AMM so that the threshold can be tuned
+    pub fn filter_with_usd_values(&self, amms: Vec<AMM>) -> (Vec<AMM>, HashMap<H160, f64>) {
+        let mut usd_values = HashMap::with_capacity(amms.len());
+
+        let filtered_amms = amms
+            .into_iter()
+            .filter(|amm| match self.usd_value(amm) {
+                Some(usd_value) => {
+                    usd_values.insert(amm.address(), usd_value);
+                    usd_value >= self.usd_threshold
+                }
+                None => false,
+            })
+            .collect();
+
+        (filtered_amms, usd_values)
+    }
+}
+
+#[async_trait]
+impl<M, O> AmmFilter<M> for UsdValueFilter<O>
+where
+    M: 'static + Middleware,
+    O: PriceOracle + std::fmt::Debug + Send + Sync,
+{
+    fn name(&self) -> &str {
+        "usd_value"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Local
+    }
+
+    fn check(&self, amm: &AMM) -> Option<DroppedAmm> {
+        match self.usd_value(amm) {
+            Some(usd_value) if usd_value >= self.usd_threshold => None,
+            Some(usd_value) => Some(DroppedAmm::new(
+                amm.address(),
+                format!("usd value {:.2} below threshold", usd_value),
+            )),
+            None => Some(DroppedAmm::new(amm.address(), "no priced tokens")),
+        }
+    }
+}
+
+//Reserves of an AMM in whole tokens, in the order of `amm.tokens()`. Uniswap V3, V4, KyberSwap Elastic and Algebra pools use the virtual reserves of the active liquidity.
+fn token_reserves(amm: &AMM) -> Vec<f64> {
+    match amm {
+        AMM::UniswapV2Pool(pool) => vec![
+            pool.reserve_0 as f64 / 10_f64.powi(pool.token_a_decimals as i32),
+            pool.reserve_1 as f64 / 10_f64.powi(pool.token_b_decimals as i32),
+        ],
+        AMM::UniswapV3Pool(pool) => uniswap_v3_reserves(pool),
+        AMM::UniswapV4Pool(pool) => uniswap_v3_reserves(&pool.pool),
+        AMM::ERC4626Vault(vault) => vec![
+            u256_to_f64(vault.vault_reserve, vault.vault_token_decimals),
+            u256_to_f64(vault.asset_reserve, vault.asset_token_decimals),
+        ],
+        AMM::CurveStableSwapPool(pool) => pool
+            .balances
+            .iter()
+            .zip(pool.decimals.iter())
+            .map(|(balance, decimals)| u256_to_f64(*balance, *decimals))
+            .collect(),
+        AMM::BalancerWeightedPool(pool) => pool
+            .balances
+            .iter()
+            .zip(pool.decimals.iter())
+            .map(|(balance, decimals)| u256_to_f64(*balance, *decimals))
+            .collect(),
+        AMM::VelodromePool(pool) => vec![
+            u256_to_f64(pool.reserve_0, pool.token_a_decimals),
+            u256_to_f64(pool.reserve_1, pool.token_b_decimals),
+        ],
+        AMM::LiquidityBookPool(pool) => {
+            let (reserve_x, reserve_y) = pool.total_reserves();
+
+            vec![
+                u256_to_f64(reserve_x, pool.token_a_decimals),
+                u256_to_f64(reserve_y, pool.token_b_decimals),
+            ]
+        }
+        AMM::MaverickPool(pool) => {
+            let (reserve_a, reserve_b) = pool.total_reserves();
+
+            vec![
+                u256_to_f64(reserve_a, pool.token_a_decimals),
+                u256_to_f64(reserve_b, pool.token_b_decimals),
+            ]
+        }
+        AMM::DodoPool(pool) => vec![
+            u256_to_f64(pool.state.base_reserve, pool.token_a_decimals),
+            u256_to_f64(pool.state.quote_reserve, pool.token_b_decimals),
+        ],
+        AMM::KyberElasticPool(pool) => virtual_reserves(
+            pool.sqrt_price,
+            u256_to_f64(pool.liquidity(), 0),
+            (pool.token_a_decimals, pool.token_b_decimals),
+        ),
+        AMM::AlgebraPool(pool) => uniswap_v3_reserves(&pool.pool),
+        AMM::CurveCryptoPool(pool) => pool
+            .balances
+            .iter()
+            .zip(pool.decimals.iter())
+            .map(|(balance, decimals)| u256_to_f64(*balance, *decimals))
+            .collect(),
+        AMM::BancorV3Pool(pool) => vec![
+            u256_to_f64(
+                pool.base_token_trading_liquidity.into(),
+                pool.token_decimals,
+            ),
+            u256_to_f64(pool.bnt_trading_liquidity.into(), bancor_v3::BNT_DECIMALS),
+        ],
+        AMM::Custom(amm) => vec![0.0; amm.tokens().len()],
+    }
+}
+
+fn uniswap_v3_reserves(pool: &UniswapV3Pool) -> Vec<f64> {
+    virtual_reserves(
+        pool.sqrt_price,
+        pool.liquidity as f64,
+        (pool.token_a_decimals, pool.token_b_decimals),
+    )
+}
+
+fn virtual_reserves(sqrt_price: U256, liquidity: f64, decimals: (u8, u8)) -> Vec<f64> {
+    let sqrt_price = u256_to_f64(sqrt_price, 0) / 2_f64.powi(96);
+    if sqrt_price == 0.0 {
+        return vec![0.0, 0.0];
+    }
+
+    vec![
+        liquidity / sqrt_price / 10_f64.powi(decimals.0 as i32),
+        liquidity * sqrt_price / 10_f64.powi(decimals.1 as i32),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethers::types::{H160, U256};
+
+    use crate::amm::{uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AMM};
+
+    use super::{
+        token_reserves, u256_to_f64, value_amms, AmmPriceOracle, PriceOracle, UsdValueFilter,
+        ValuationPath,
+    };
+
+    fn pool(address: u64, token_a: H160, token_b: H160, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a,
+            token_a_decimals: 18,
+            token_b,
+            token_b_decimals: 18,
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_value_amms() {
+        let weth = H160::from_low_u64_be(1);
+        let usdc = H160::from_low_u64_be(2);
+        let dai = H160::from_low_u64_be(3);
+        let token = H160::from_low_u64_be(4);
+        let other_token = H160::from_low_u64_be(5);
+
+        let numeraire_prices = HashMap::from([(weth, 2000.0), (usdc, 1.0), (dai, 1.0)]);
+
+        let amms = vec![
+            //1 token = 0.0005 weth = $1
+            pool(
+                10,
+                weth,
+                token,
+                10 * 10_u128.pow(18),
+                20000 * 10_u128.pow(18),
+            ),
+            pool(11, token, other_token, 0, 0),
+            pool(12, usdc, dai, 0, 0),
+            pool(13, other_token, H160::from_low_u64_be(6), 0, 0),
+        ];
+        let token_amounts = vec![
+            vec![10.0, 20000.0],
+            vec![5000.0, 1.0],
+            vec![100.0, 100.0],
+            vec![1.0, 1.0],
+        ];
+
+        let valuations = value_amms(&amms, &token_amounts, &numeraire_prices, 100000.0);
+
+        assert_eq!(valuations[0].usd_value, 40000.0);
+        assert_eq!(
+            valuations[0].path,
+            ValuationPath::Direct { numeraire: weth }
+        );
+
+        //Valued through a single connector pool below the thin connector value
+        assert!((valuations[1].usd_value - 5000.0).abs() < 1e-6);
+        assert_eq!(
+            valuations[1].path,
+            ValuationPath::OneHop {
+                token,
+                connector: H160::from_low_u64_be(10),
+                discounted: true
+            }
+        );
+
+        //Stable only pools are valued without a weth path
+        assert_eq!(valuations[2].usd_value, 200.0);
+
+        //Pools two hops away are not valued
+        assert_eq!(valuations[3].usd_value, 0.0);
+        assert_eq!(valuations[3].path, ValuationPath::Unvalued);
+
+        let valuations = value_amms(&amms, &token_amounts, &numeraire_prices, 1000.0);
+        assert!((valuations[1].usd_value - 10000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_amm_price_oracle() {
+        let weth = H160::from_low_u64_be(1);
+        let token = H160::from_low_u64_be(2);
+        let other_token = H160::from_low_u64_be(3);
+        let unlinked_token = H160::from_low_u64_be(4);
+
+        let amms = vec![
+            //1 token = 0.0005 weth = $1
+            pool(
+                10,
+                weth,
+                token,
+                10 * 10_u128.pow(18),
+                20000 * 10_u128.pow(18),
+            ),
+            //Shallower pool at the same depth quoting 1 token = $2
+            pool(11, weth, token, 10_u128.pow(18), 1000 * 10_u128.pow(18)),
+            //1 other_token = 4 token = $4
+            pool(
+                12,
+                token,
+                other_token,
+                4000 * 10_u128.pow(18),
+                1000 * 10_u128.pow(18),
+            ),
+            //Too shallow to price unlinked_token
+            pool(
+                13,
+                other_token,
+                unlinked_token,
+                10_u128.pow(18),
+                10_u128.pow(18),
+            ),
+        ];
+
+        let anchors = HashMap::from([(weth, 2000.0)]);
+        let oracle = AmmPriceOracle::new(&amms, &anchors, 100.0);
+
+        assert!((oracle.usd_price(token).unwrap() - 1.0).abs() < 1e-9);
+        assert!((oracle.usd_price(other_token).unwrap() - 4.0).abs() < 1e-9);
+        assert_eq!(oracle.usd_price(unlinked_token), None);
+
+        let filter = UsdValueFilter::new(oracle, 1000.0);
+        let (filtered_amms, usd_values) = filter.filter_with_usd_values(amms);
+
+        assert_eq!(filtered_amms.len(), 3);
+        assert!((usd_values[&H160::from_low_u64_be(10)] - 40000.0).abs() < 1e-6);
+        //Only other_token is priced, so the pool is valued at twice its side
+        assert!((usd_values[&H160::from_low_u64_be(13)] - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_usd_value_filter_skips_unpriced_amms() {
+        let filter = UsdValueFilter::new(HashMap::from([(H160::from_low_u64_be(1), 1.0)]), 0.0);
+        let amm = pool(
+            10,
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(3),
+            10,
+            10,
+        );
+
+        assert_eq!(filter.usd_value(&amm), None);
+        assert!(filter.filter(vec![amm]).is_empty());
+    }
+
+    #[test]
+    fn test_uniswap_v3_virtual_reserves() {
+        //sqrt price of 1 at equal decimals, so both virtual reserves equal the liquidity
+        let amm = AMM::UniswapV3Pool(UniswapV3Pool {
+            token_a_decimals: 18,
+            token_b_decimals: 18,
+            liquidity: 10_u128.pow(18),
+            sqrt_price: U256::one() << 96,
+            ..Default::default()
+        });
+
+        assert_eq!(token_reserves(&amm), vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_u256_to_f64() {
+        assert_eq!(u256_to_f64(U256::exp10(18) * 5, 18), 5.0);
+        assert_eq!(u256_to_f64(U256::from(1500000), 6), 1.5);
+    }
+}
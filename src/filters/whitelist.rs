@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use ethers::{providers::Middleware, types::H160};
+use serde::Deserialize;
+
+use crate::amm::{AmmState, AMM};
+
+use super::pipeline::{AmmFilter, DroppedAmm, FilterCost};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhitelistMode {
+    /// Every token of the AMM must be whitelisted
+    #[default]
+    All,
+    /// At least one token of the AMM must be whitelisted
+    Any,
+}
+
+//Subset of the Uniswap token list schema, https://github.com/Uniswap/token-lists
+#[derive(Deserialize)]
+struct TokenList {
+    tokens: Vec<TokenInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenInfo {
+    chain_id: u64,
+    address: H160,
+}
+
+/// Keeps AMMs whose tokens are whitelisted. The filter only reads `tokens()`, so it can also be applied to the unpopulated
+/// AMMs returned by `Factory::amms_from_logs` before populating them. ERC4626 vaults are checked on both the share and asset token.
+#[derive(Debug, Clone, Default)]
+pub struct WhitelistFilter {
+    tokens: HashSet<H160>,
+    mode: WhitelistMode,
+}
+
+impl WhitelistFilter {
+    pub fn from_addresses(addresses: Vec<H160>) -> Self {
+        WhitelistFilter {
+            tokens: addresses.into_iter().collect(),
+            mode: WhitelistMode::All,
+        }
+    }
+
+    /// Whitelists the tokens of a token list deployed on `chain_id`
+    pub fn from_token_list_json(json: &str, chain_id: u64) -> Result<Self, serde_json::Error> {
+        let token_list: TokenList = serde_json::from_str(json)?;
+
+        Ok(WhitelistFilter::from_addresses(
+            token_list
+                .tokens
+                .into_iter()
+                .filter(|token| token.chain_id == chain_id)
+                .map(|token| token.address)
+                .collect(),
+        ))
+    }
+
+    /// Keeps AMMs with at least one whitelisted token instead of requiring all of them
+    pub fn permissive(mut self) -> Self {
+        self.mode = WhitelistMode::Any;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: WhitelistMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> WhitelistMode {
+        self.mode
+    }
+
+    pub fn tokens(&self) -> &HashSet<H160> {
+        &self.tokens
+    }
+
+    pub fn insert(&mut self, token: H160) -> bool {
+        self.tokens.insert(token)
+    }
+
+    pub fn is_whitelisted(&self, amm: &AMM) -> bool {
+        let tokens = amm.tokens();
+
+        match self.mode {
+            WhitelistMode::All => tokens.iter().all(|token| self.tokens.contains(token)),
+            WhitelistMode::Any => tokens.iter().any(|token| self.tokens.contains(token)),
+        }
+    }
+
+    pub fn filter(&self, amms: Vec<AMM>) -> Vec<AMM> {
+        amms.into_iter()
+            .filter(|amm| self.is_whitelisted(amm))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<M: 'static + Middleware> AmmFilter<M> for WhitelistFilter {
+    fn name(&self) -> &str {
+        "whitelist"
+    }
+
+    fn cost(&self) -> FilterCost {
+        FilterCost::Local
+    }
+
+    fn check(&self, amm: &AMM) -> Option<DroppedAmm> {
+        (!self.is_whitelisted(amm)).then(|| DroppedAmm::new(amm.address(), "not whitelisted"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::H160;
+
+    use crate::amm::{erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, AMM};
+
+    use super::WhitelistFilter;
+
+    const TOKEN_LIST: &str = r#"{
+        "name": "Test List",
+        "timestamp": "2023-01-01T00:00:00.000Z",
+        "version": { "major": 1, "minor": 0, "patch": 0 },
+        "tokens": [
+            {
+                "chainId": 1,
+                "address": "0x0000000000000000000000000000000000000001",
+                "name": "Token A",
+                "symbol": "A",
+                "decimals": 18
+            },
+            {
+                "chainId": 1,
+                "address": "0x0000000000000000000000000000000000000002",
+                "name": "Token B",
+                "symbol": "B",
+                "decimals": 6,
+                "logoURI": "ipfs://token-b"
+            },
+            {
+                "chainId": 10,
+                "address": "0x0000000000000000000000000000000000000003",
+                "name": "Token C",
+                "symbol": "C",
+                "decimals": 18
+            }
+        ]
+    }"#;
+
+    fn pool(token_a: u64, token_b: u64) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            token_a: H160::from_low_u64_be(token_a),
+            token_b: H160::from_low_u64_be(token_b),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_whitelist_from_token_list() -> eyre::Result<()> {
+        let whitelist = WhitelistFilter::from_token_list_json(TOKEN_LIST, 1)?;
+
+        //Tokens listed on other chains are ignored
+        assert_eq!(whitelist.tokens().len(), 2);
+        assert!(!whitelist.tokens().contains(&H160::from_low_u64_be(3)));
+
+        let amms = vec![pool(1, 2), pool(1, 3), pool(3, 4)];
+        assert_eq!(whitelist.filter(amms.clone()).len(), 1);
+        assert_eq!(whitelist.permissive().filter(amms).len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_whitelist_erc_4626_vault() {
+        let whitelist = WhitelistFilter::from_addresses(vec![
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+        ]);
+
+        let vault = |vault_token: u64, asset_token: u64| {
+            AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: H160::from_low_u64_be(vault_token),
+                asset_token: H160::from_low_u64_be(asset_token),
+                ..Default::default()
+            })
+        };
+
+        assert!(whitelist.is_whitelisted(&vault(1, 2)));
+        assert!(!whitelist.is_whitelisted(&vault(5, 2)));
+    }
+}
@@ -0,0 +1,358 @@
+use std::{collections::BTreeMap, fmt::Debug, marker::PhantomData};
+
+use ::alloy::{
+    primitives::{Address, Bytes as AlloyBytes, LogData, B256, U256 as AlloyU256},
+    providers::Provider as AlloyProvider,
+    rpc::types::Log as AlloyLog,
+    transports::{BoxTransport, RpcError as AlloyRpcError, Transport, TransportErrorKind},
+};
+use async_trait::async_trait;
+use ethers::{
+    providers::{JsonRpcClient, JsonRpcError, Provider, ProviderError, RpcError},
+    types::{Bytes, Log, H160, H256, U256, U64},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+/// Conversion of an alloy type to the ethers type used by the crate.
+///
+/// Both types are foreign to this crate, so `From` can not be implemented between them.
+pub trait ToEthers {
+    type Ethers;
+
+    fn to_ethers(self) -> Self::Ethers;
+}
+
+/// Conversion of an ethers type returned by the crate to its alloy equivalent
+pub trait ToAlloy {
+    type Alloy;
+
+    fn to_alloy(self) -> Self::Alloy;
+}
+
+impl ToEthers for Address {
+    type Ethers = H160;
+
+    fn to_ethers(self) -> H160 {
+        H160(self.into_array())
+    }
+}
+
+impl ToAlloy for H160 {
+    type Alloy = Address;
+
+    fn to_alloy(self) -> Address {
+        Address::new(self.0)
+    }
+}
+
+impl ToEthers for B256 {
+    type Ethers = H256;
+
+    fn to_ethers(self) -> H256 {
+        H256(self.0)
+    }
+}
+
+impl ToAlloy for H256 {
+    type Alloy = B256;
+
+    fn to_alloy(self) -> B256 {
+        B256::new(self.0)
+    }
+}
+
+impl ToEthers for AlloyU256 {
+    type Ethers = U256;
+
+    fn to_ethers(self) -> U256 {
+        //Both are little endian limbs
+        U256(*self.as_limbs())
+    }
+}
+
+impl ToAlloy for U256 {
+    type Alloy = AlloyU256;
+
+    fn to_alloy(self) -> AlloyU256 {
+        AlloyU256::from_limbs(self.0)
+    }
+}
+
+impl ToEthers for AlloyBytes {
+    type Ethers = Bytes;
+
+    fn to_ethers(self) -> Bytes {
+        Bytes(self.0)
+    }
+}
+
+impl ToAlloy for Bytes {
+    type Alloy = AlloyBytes;
+
+    fn to_alloy(self) -> AlloyBytes {
+        AlloyBytes(self.0)
+    }
+}
+
+impl ToEthers for AlloyLog {
+    type Ethers = Log;
+
+    fn to_ethers(self) -> Log {
+        Log {
+            address: self.inner.address.to_ethers(),
+            topics: self
+                .inner
+                .data
+                .topics()
+                .iter()
+                .map(|topic| topic.to_ethers())
+                .collect(),
+            data: self.inner.data.data.clone().to_ethers(),
+            block_hash: self.block_hash.map(ToEthers::to_ethers),
+            block_number: self.block_number.map(U64::from),
+            transaction_hash: self.transaction_hash.map(ToEthers::to_ethers),
+            transaction_index: self.transaction_index.map(U64::from),
+            log_index: self.log_index.map(U256::from),
+            transaction_log_index: None,
+            log_type: None,
+            removed: Some(self.removed),
+        }
+    }
+}
+
+impl ToAlloy for Log {
+    type Alloy = AlloyLog;
+
+    fn to_alloy(self) -> AlloyLog {
+        AlloyLog {
+            inner: ::alloy::primitives::Log {
+                address: self.address.to_alloy(),
+                data: LogData::new_unchecked(
+                    self.topics.into_iter().map(ToAlloy::to_alloy).collect(),
+                    self.data.to_alloy(),
+                ),
+            },
+            block_hash: self.block_hash.map(ToAlloy::to_alloy),
+            block_number: self.block_number.map(|block_number| block_number.as_u64()),
+            block_timestamp: None,
+            transaction_hash: self.transaction_hash.map(ToAlloy::to_alloy),
+            transaction_index: self.transaction_index.map(|index| index.as_u64()),
+            log_index: self.log_index.map(|index| index.as_u64()),
+            removed: self.removed.unwrap_or_default(),
+        }
+    }
+}
+
+//Reserves of an AMM, as returned by `AmmState::reserves` and taken by `AmmState::sync_from_storage`
+impl ToEthers for BTreeMap<B256, B256> {
+    type Ethers = BTreeMap<H256, H256>;
+
+    fn to_ethers(self) -> BTreeMap<H256, H256> {
+        self.into_iter()
+            .map(|(slot, value)| (slot.to_ethers(), value.to_ethers()))
+            .collect()
+    }
+}
+
+impl ToAlloy for BTreeMap<H256, H256> {
+    type Alloy = BTreeMap<B256, B256>;
+
+    fn to_alloy(self) -> BTreeMap<B256, B256> {
+        self.into_iter()
+            .map(|(slot, value)| (slot.to_alloy(), value.to_alloy()))
+            .collect()
+    }
+}
+
+impl<T: ToEthers> ToEthers for Vec<T> {
+    type Ethers = Vec<T::Ethers>;
+
+    fn to_ethers(self) -> Self::Ethers {
+        self.into_iter().map(ToEthers::to_ethers).collect()
+    }
+}
+
+impl<T: ToAlloy> ToAlloy for Vec<T> {
+    type Alloy = Vec<T::Alloy>;
+
+    fn to_alloy(self) -> Self::Alloy {
+        self.into_iter().map(ToAlloy::to_alloy).collect()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AlloyClientError {
+    #[error("Alloy transport error")]
+    Transport(#[from] AlloyRpcError<TransportErrorKind>),
+    #[error("JSON-RPC error response")]
+    ErrorResponse(JsonRpcError),
+    #[error("Serde JSON error")]
+    SerdeJson(#[from] serde_json::Error),
+}
+
+impl RpcError for AlloyClientError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            AlloyClientError::ErrorResponse(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            AlloyClientError::SerdeJson(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<AlloyClientError> for ProviderError {
+    fn from(error: AlloyClientError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(error))
+    }
+}
+
+/// JSON-RPC client sending the requests of an ethers `Provider` through an alloy provider and its transport.
+///
+/// `Provider<AlloyClient<P>>` is a `Middleware`, so it can be passed to `sync`, `populate_data` and the discovery
+/// and state space functions of the crate. The state space block stream needs a pubsub middleware, which this client
+/// does not implement, so `StateSpaceManager` still takes an ethers pubsub provider as its stream middleware.
+pub struct AlloyClient<P, T = BoxTransport> {
+    provider: P,
+    _transport: PhantomData<fn() -> T>,
+}
+
+impl<P, T> AlloyClient<P, T>
+where
+    P: AlloyProvider<T>,
+    T: Transport + Clone,
+{
+    pub fn new(provider: P) -> Self {
+        AlloyClient {
+            provider,
+            _transport: PhantomData,
+        }
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+}
+
+impl<P, T> Debug for AlloyClient<P, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlloyClient").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<P, T> JsonRpcClient for AlloyClient<P, T>
+where
+    P: AlloyProvider<T> + 'static,
+    T: Transport + Clone,
+{
+    type Error = AlloyClientError;
+
+    async fn request<R, D>(&self, method: &str, params: R) -> Result<D, Self::Error>
+    where
+        R: Debug + Serialize + Send + Sync,
+        D: DeserializeOwned + Send,
+    {
+        //Going through JSON values satisfies the bounds alloy puts on params and responses
+        let params = serde_json::to_value(params)?;
+        let response = self
+            .provider
+            .raw_request::<_, serde_json::Value>(method.to_owned().into(), params)
+            .await
+            .map_err(|error| match error {
+                AlloyRpcError::ErrorResp(payload) => {
+                    AlloyClientError::ErrorResponse(JsonRpcError {
+                        code: payload.code,
+                        message: payload.message.to_string(),
+                        data: payload
+                            .data
+                            .and_then(|data| serde_json::from_str(data.get()).ok()),
+                    })
+                }
+                error => AlloyClientError::Transport(error),
+            })?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+}
+
+/// Ethers middleware on top of an alloy provider, to sync and discover AMMs over alloy transports
+pub fn middleware<P, T>(provider: P) -> Provider<AlloyClient<P, T>>
+where
+    P: AlloyProvider<T> + 'static,
+    T: Transport + Clone,
+{
+    Provider::new(AlloyClient::new(provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ::alloy::{
+        primitives::{address, b256, Address, LogData, B256, U256 as AlloyU256},
+        rpc::types::Log as AlloyLog,
+    };
+    use ethers::types::{H160, U256, U64};
+
+    use super::{ToAlloy, ToEthers};
+
+    #[test]
+    fn test_round_trips() {
+        let address = address!("C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
+        assert_eq!(
+            address.to_ethers(),
+            "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+                .parse::<H160>()
+                .unwrap()
+        );
+        assert_eq!(address.to_ethers().to_alloy(), address);
+
+        for value in [U256::zero(), U256::from(1), U256::exp10(30), U256::MAX] {
+            assert_eq!(value.to_alloy().to_ethers(), value);
+        }
+        assert_eq!(
+            U256::exp10(30).to_alloy(),
+            AlloyU256::from(10).pow(AlloyU256::from(30))
+        );
+
+        let slot = b256!("0000000000000000000000000000000000000000000000000000000000000008");
+        let reserves = BTreeMap::from([(slot, B256::repeat_byte(1))]);
+        assert_eq!(reserves.clone().to_ethers().to_alloy(), reserves);
+    }
+
+    #[test]
+    fn test_log_round_trip() {
+        let log = AlloyLog {
+            inner: ::alloy::primitives::Log {
+                address: Address::repeat_byte(2),
+                data: LogData::new_unchecked(
+                    vec![B256::repeat_byte(3), B256::repeat_byte(4)],
+                    vec![5_u8; 64].into(),
+                ),
+            },
+            block_hash: Some(B256::repeat_byte(6)),
+            block_number: Some(17_000_000),
+            block_timestamp: None,
+            transaction_hash: Some(B256::repeat_byte(7)),
+            transaction_index: Some(8),
+            log_index: Some(9),
+            removed: false,
+        };
+
+        let ethers_log = log.clone().to_ethers();
+        assert_eq!(ethers_log.address, H160::repeat_byte(2));
+        assert_eq!(ethers_log.topics.len(), 2);
+        assert_eq!(ethers_log.data.len(), 64);
+        assert_eq!(ethers_log.block_number, Some(U64::from(17_000_000)));
+        assert_eq!(ethers_log.log_index, Some(U256::from(9)));
+
+        assert_eq!(ethers_log.to_alloy(), log);
+    }
+}
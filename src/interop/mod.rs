@@ -0,0 +1,3 @@
+/// Requires the `alloy` feature
+#[cfg(feature = "alloy")]
+pub mod alloy;
@@ -1,6 +1,43 @@
+//! Library to interact with automated market makers across EVM chains.
+//!
+//! Modules that need a feature say so in their docs, every other module is always built. The features are:
+//!
+//! - `rpc`: syncing, discovery, filters, token metadata and everything else sending requests through a `Middleware`.
+//!   Transport agnostic, it enables no ethers transport by itself.
+//! - `rpc-http`: `rpc` with TLS for `https` endpoints, the plain `Http` transport is always available.
+//! - `ws` / `ipc`: `rpc` with the ethers websocket or IPC transport, which the state space needs to subscribe to blocks.
+//! - `filters`, `state-space`, `known-factories`: the pool filters, the state space manager and its quoting service, and the
+//!   addresses of known factories.
+//! - `parallel`: rayon for batch simulations and filter pipelines.
+//! - `alloy`, `revm`, `sqlite`, `metrics`, `test-utils`: integrations, off by default.
+//! - `verification`: differential testing of the local quotes against on chain quoters, off by default.
+//!
+//! The default features are `rpc-http`, `ws`, `ipc`, `filters`, `state-space`, `known-factories` and `parallel`.
+
 pub mod amm;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
 pub mod discovery;
 pub mod errors;
+pub mod export;
 pub mod filters;
+pub mod interop;
+pub mod math;
+pub mod metrics;
+pub mod routing;
+/// Requires the `state-space` feature
+#[cfg(feature = "state-space")]
+pub mod service;
+pub mod simulation;
 pub mod state_space;
+pub mod storage;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
 pub mod sync;
+/// Requires the `test-utils` feature
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod tokens;
+/// Requires the `verification` feature
+#[cfg(feature = "verification")]
+pub mod verification;
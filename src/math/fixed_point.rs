@@ -0,0 +1,382 @@
+use std::{cmp::Ordering, sync::OnceLock};
+
+use ethers::types::{U256, U512};
+
+use crate::{
+    amm::uniswap_v3::{MAX_SQRT_RATIO, MIN_SQRT_RATIO},
+    errors::ArithmeticError,
+};
+
+use super::full_math::mul_shift_right;
+
+//Largest power of ten that fits in a U256
+const MAX_U256_EXP10: usize = 77;
+
+/// Nearest f64 to `value`, ties are rounded to even. Values above 2^53 lose their low bits.
+pub fn u256_to_f64_lossy(value: U256) -> f64 {
+    let bits = value.bits();
+    if bits <= 64 {
+        return value.low_u64() as f64;
+    }
+
+    //Keep the top 64 bits and fold the rest into the lowest one, so the cast only sees a tie when the value is exactly halfway
+    let shift = bits - 64;
+    let mut top = (value >> shift).low_u64();
+    if !(value & ((U256::one() << shift) - U256::one())).is_zero() {
+        top |= 1;
+    }
+
+    top as f64 * 2_f64.powi(shift as i32)
+}
+
+//Converts a token amount to whole tokens
+pub(crate) fn u256_to_f64(amount: U256, decimals: u8) -> f64 {
+    u256_to_f64_lossy(amount) / 10_f64.powi(decimals as i32)
+}
+
+/// Nearest f64 to a Q64.64, ties are rounded to even
+pub fn q64_to_f64(x: u128) -> f64 {
+    //Dividing by a power of two is exact, so only the cast rounds
+    x as f64 / 2_f64.powi(64)
+}
+
+/// Nearest f64 to a Q128.128, ties are rounded to even
+pub fn price_x128_to_f64(price: U256) -> f64 {
+    u256_to_f64_lossy(price) / 2_f64.powi(128)
+}
+
+/// Price of token 0 in whole units of token 1 at `sqrt_price`, a Q64.96 as stored by Uniswap V3 pools, returned as a Q128.128.
+/// The result is rounded down, so prices below 2^-128 are zero.
+pub fn sqrt_price_x96_to_price_x128(
+    sqrt_price: U256,
+    decimals_0: u8,
+    decimals_1: u8,
+) -> Result<U256, ArithmeticError> {
+    match decimals_0.cmp(&decimals_1) {
+        //(sqrt_price / 2^96)^2 * 2^128 * 10^(decimals_0 - decimals_1), scaled before the shift so no bits are lost
+        Ordering::Greater => {
+            let exponent = (decimals_0 - decimals_1) as usize;
+            if exponent > MAX_U256_EXP10 {
+                return Err(ArithmeticError::MulDivOverflow);
+            }
+
+            let price = sqrt_price
+                .full_mul(sqrt_price)
+                .checked_mul(U512::from(U256::exp10(exponent)))
+                .ok_or(ArithmeticError::MulDivOverflow)?
+                >> 64;
+
+            U256::try_from(price).map_err(|_| ArithmeticError::MulDivOverflow)
+        }
+        //Dividing the rounded down price by an integer gives the same result as dividing the exact price
+        Ordering::Less => {
+            let exponent = (decimals_1 - decimals_0) as usize;
+            if exponent > MAX_U256_EXP10 {
+                return Ok(U256::zero());
+            }
+
+            Ok(mul_shift_right(sqrt_price, sqrt_price, 64)? / U256::exp10(exponent))
+        }
+        Ordering::Equal => mul_shift_right(sqrt_price, sqrt_price, 64),
+    }
+}
+
+/// Sqrt price as a Q64.96 for a price of token 0 in whole units of token 1. The square root is taken in f64,
+/// so the result has around 53 significant bits and is rounded toward zero from there.
+/// Prices whose sqrt price falls outside of the range supported by Uniswap V3 are rejected.
+pub fn f64_to_sqrt_price_x96(
+    price: f64,
+    decimals_0: u8,
+    decimals_1: u8,
+) -> Result<U256, ArithmeticError> {
+    if !price.is_finite() || price <= 0.0 {
+        return Err(ArithmeticError::InvalidPrice(price));
+    }
+
+    let raw_price = price * 10_f64.powi(decimals_1 as i32 - decimals_0 as i32);
+    let sqrt_price =
+        f64_to_u256(raw_price.sqrt() * 2_f64.powi(96)).ok_or(ArithmeticError::SqrtPriceOverflow)?;
+
+    if sqrt_price < MIN_SQRT_RATIO || sqrt_price >= MAX_SQRT_RATIO {
+        return Err(ArithmeticError::SqrtPriceOverflow);
+    }
+
+    Ok(sqrt_price)
+}
+
+/// Factor normalizing amounts of a token with `decimals_0` to a token with `decimals_1`, or the other way around
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecimalScaling {
+    pub decimals_0: u8,
+    pub decimals_1: u8,
+    /// 10^|decimals_0 - decimals_1|, None if it does not fit in a U256
+    pub factor: Option<U256>,
+    /// 10^|decimals_0 - decimals_1|
+    pub factor_f64: f64,
+}
+
+impl DecimalScaling {
+    pub fn new(decimals_0: u8, decimals_1: u8) -> Self {
+        let exponent = decimals_0.abs_diff(decimals_1);
+
+        DecimalScaling {
+            decimals_0,
+            decimals_1,
+            factor: (exponent as usize <= MAX_U256_EXP10).then(|| U256::exp10(exponent as usize)),
+            factor_f64: 10_f64.powi(exponent as i32),
+        }
+    }
+
+    /// Scales the raw amounts of token 0 and token 1 to the same number of decimals
+    pub fn normalize(
+        &self,
+        amount_0: U256,
+        amount_1: U256,
+    ) -> Result<(U256, U256), ArithmeticError> {
+        let scale = |amount: U256| {
+            self.factor
+                .and_then(|factor| amount.checked_mul(factor))
+                .ok_or(ArithmeticError::MulDivOverflow)
+        };
+
+        match self.decimals_0.cmp(&self.decimals_1) {
+            Ordering::Less => Ok((scale(amount_0)?, amount_1)),
+            Ordering::Greater => Ok((amount_0, scale(amount_1)?)),
+            Ordering::Equal => Ok((amount_0, amount_1)),
+        }
+    }
+
+    /// Price of a whole token 0 in whole units of token 1, from the price of their raw amounts
+    pub fn whole_price(&self, raw_price: f64) -> f64 {
+        match self.decimals_0.cmp(&self.decimals_1) {
+            Ordering::Less => raw_price / self.factor_f64,
+            Ordering::Greater => raw_price * self.factor_f64,
+            Ordering::Equal => raw_price,
+        }
+    }
+}
+
+/// Decimal scaling of a pool, computed once when the pool is populated or on first use after deserialization.
+/// If the decimals of the pool no longer match the cached ones, the scaling is computed on every call until the cache is reset.
+#[derive(Debug, Clone, Default)]
+pub struct DecimalScalingCache(OnceLock<DecimalScaling>);
+
+impl DecimalScalingCache {
+    pub fn new(decimals_0: u8, decimals_1: u8) -> Self {
+        DecimalScalingCache(OnceLock::from(DecimalScaling::new(decimals_0, decimals_1)))
+    }
+
+    pub fn get(&self, decimals_0: u8, decimals_1: u8) -> DecimalScaling {
+        let scaling = *self
+            .0
+            .get_or_init(|| DecimalScaling::new(decimals_0, decimals_1));
+
+        if scaling.decimals_0 == decimals_0 && scaling.decimals_1 == decimals_1 {
+            scaling
+        } else {
+            DecimalScaling::new(decimals_0, decimals_1)
+        }
+    }
+}
+
+//Truncates a finite, non negative f64 toward zero
+fn f64_to_u256(value: f64) -> Option<U256> {
+    if !value.is_finite() || value < 0.0 {
+        return None;
+    }
+
+    if value < 1.0 {
+        return Some(U256::zero());
+    }
+
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i32 - 1075;
+    let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+
+    if exponent >= 0 {
+        if exponent > 256 - 53 {
+            return None;
+        }
+
+        Some(U256::from(mantissa) << exponent as usize)
+    } else {
+        Some(U256::from(mantissa >> -exponent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        core::rand::{thread_rng, Rng},
+        types::U256,
+    };
+
+    use crate::amm::uniswap_v3::{MAX_SQRT_RATIO, MIN_SQRT_RATIO};
+
+    use super::{
+        f64_to_sqrt_price_x96, f64_to_u256, price_x128_to_f64, q64_to_f64,
+        sqrt_price_x96_to_price_x128, u256_to_f64_lossy, DecimalScaling, DecimalScalingCache,
+    };
+
+    #[test]
+    fn test_u256_to_f64_lossy() {
+        assert_eq!(u256_to_f64_lossy(U256::zero()), 0.0);
+        assert_eq!(u256_to_f64_lossy(U256::from(u64::MAX)), 2_f64.powi(64));
+        assert_eq!(u256_to_f64_lossy(U256::MAX), 2_f64.powi(256));
+
+        //Exact ties round to even, anything above a tie rounds up
+        let base = U256::one() << 100;
+        let half_ulp = U256::one() << 47;
+        assert_eq!(u256_to_f64_lossy(base + half_ulp), 2_f64.powi(100));
+        assert_eq!(
+            u256_to_f64_lossy(base + half_ulp + U256::one()),
+            2_f64.powi(100) + 2_f64.powi(48)
+        );
+        assert_eq!(
+            u256_to_f64_lossy(base + half_ulp * U256::from(3)),
+            2_f64.powi(100) + 2_f64.powi(49)
+        );
+    }
+
+    #[test]
+    fn test_u256_to_f64_lossy_error_bound() {
+        let mut rng = thread_rng();
+
+        for _ in 0..10000 {
+            let words: [u64; 4] = rng.gen();
+            let value = U256(words) >> rng.gen_range(0..256_usize);
+
+            //The result is never more than half an ulp away from the value
+            let rounded = f64_to_u256(u256_to_f64_lossy(value)).unwrap();
+            let error = if rounded > value {
+                rounded - value
+            } else {
+                value - rounded
+            };
+            let half_ulp = if value.bits() > 53 {
+                U256::one() << (value.bits() - 54)
+            } else {
+                U256::zero()
+            };
+
+            assert!(error <= half_ulp, "{value} rounded to {rounded}");
+        }
+    }
+
+    #[test]
+    fn test_sqrt_price_x96_to_price_x128() -> eyre::Result<()> {
+        let one = U256::one() << 128;
+        let sqrt_price = U256::one() << 96;
+
+        assert_eq!(sqrt_price_x96_to_price_x128(sqrt_price, 18, 18)?, one);
+        assert_eq!(
+            sqrt_price_x96_to_price_x128(sqrt_price, 6, 18)?,
+            one / U256::exp10(12)
+        );
+        assert_eq!(
+            sqrt_price_x96_to_price_x128(sqrt_price, 18, 6)?,
+            one * U256::exp10(12)
+        );
+        assert_eq!(price_x128_to_f64(one * 3 / 2), 1.5);
+        assert_eq!(q64_to_f64(3 << 63), 1.5);
+
+        //USDC/WETH at 1 WETH = 1644.40 USDC
+        let sqrt_price = U256::from_dec_str("1953780855133784655959437960079435")?;
+        let price = price_x128_to_f64(sqrt_price_x96_to_price_x128(sqrt_price, 6, 18)?);
+        assert!((1.0 / price - 1644.4).abs() < 1e-9);
+
+        assert!(sqrt_price_x96_to_price_x128(MAX_SQRT_RATIO, 18, 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_f64_to_sqrt_price_x96() -> eyre::Result<()> {
+        assert_eq!(f64_to_sqrt_price_x96(1.0, 18, 18)?, U256::one() << 96);
+        assert_eq!(f64_to_sqrt_price_x96(0.25, 6, 6)?, U256::one() << 95);
+
+        for tick in [-887000, -200000, -1, 0, 1, 200000, 887000] {
+            let sqrt_price = f64_to_sqrt_price_x96(1.0001_f64.powi(tick), 0, 0)?;
+            let expected = uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick(tick)?;
+
+            let error = u256_to_f64_lossy(sqrt_price) / u256_to_f64_lossy(expected) - 1.0;
+            assert!(error.abs() < 1e-9);
+        }
+
+        assert!(f64_to_sqrt_price_x96(0.0, 0, 0).is_err());
+        assert!(f64_to_sqrt_price_x96(-1.0, 0, 0).is_err());
+        assert!(f64_to_sqrt_price_x96(f64::NAN, 0, 0).is_err());
+        assert!(f64_to_sqrt_price_x96(1e60, 0, 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_price_round_trip() -> eyre::Result<()> {
+        let mut rng = thread_rng();
+
+        for _ in 0..10000 {
+            let sqrt_price = MIN_SQRT_RATIO
+                + U256::from(rng.gen::<u128>()) * U256::from(rng.gen::<u64>())
+                    % (MAX_SQRT_RATIO - MIN_SQRT_RATIO);
+            let (decimals_0, decimals_1) = (rng.gen_range(0..=18), rng.gen_range(0..=18));
+
+            let price_x128 = sqrt_price_x96_to_price_x128(sqrt_price, decimals_0, decimals_1)?;
+            //Rounding to a Q128.128 loses the precision of very small prices, and truncating to an integer that of very small sqrt prices
+            if price_x128 < U256::one() << 64 || sqrt_price < U256::one() << 64 {
+                continue;
+            }
+
+            let round_trip =
+                f64_to_sqrt_price_x96(price_x128_to_f64(price_x128), decimals_0, decimals_1)?;
+
+            let error = u256_to_f64_lossy(round_trip) / u256_to_f64_lossy(sqrt_price) - 1.0;
+            assert!(
+                error.abs() < 1e-12,
+                "{sqrt_price} round tripped to {round_trip}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decimal_scaling() -> eyre::Result<()> {
+        let scaling = DecimalScaling::new(6, 18);
+        assert_eq!(scaling.factor, Some(U256::exp10(12)));
+        assert_eq!(scaling.factor_f64, 1e12);
+        assert_eq!(
+            scaling.normalize(U256::from(2), U256::from(3))?,
+            (U256::from(2) * U256::exp10(12), U256::from(3))
+        );
+        assert_eq!(scaling.whole_price(1e12), 1.0);
+
+        let scaling = DecimalScaling::new(18, 6);
+        assert_eq!(
+            scaling.normalize(U256::from(2), U256::from(3))?,
+            (U256::from(2), U256::from(3) * U256::exp10(12))
+        );
+        assert_eq!(scaling.whole_price(1e-12), 1.0);
+
+        assert!(DecimalScaling::new(0, 78).factor.is_none());
+        assert!(DecimalScaling::new(0, 78)
+            .normalize(U256::one(), U256::one())
+            .is_err());
+        assert!(DecimalScaling::new(0, 77)
+            .normalize(U256::from(100), U256::one())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decimal_scaling_cache() {
+        //An empty cache, as left by deserialization, is filled on first use
+        let cache = DecimalScalingCache::default();
+        assert_eq!(cache.get(6, 18), DecimalScaling::new(6, 18));
+        assert_eq!(cache.get(6, 18), DecimalScaling::new(6, 18));
+
+        //Decimals changed after the cache was filled are still honored
+        assert_eq!(cache.get(18, 18), DecimalScaling::new(18, 18));
+    }
+}
@@ -0,0 +1,116 @@
+use ethers::types::{U256, U512};
+
+use crate::errors::ArithmeticError;
+
+/// `floor(a * b / denominator)` computed with a 512-bit product, as `FullMath.mulDiv`.
+/// The product may overflow 256 bits as long as the result fits.
+pub fn mul_div(a: U256, b: U256, denominator: U256) -> Result<U256, ArithmeticError> {
+    if denominator.is_zero() {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+
+    let quotient = a.full_mul(b) / U512::from(denominator);
+    U256::try_from(quotient).map_err(|_| ArithmeticError::MulDivOverflow)
+}
+
+/// `ceil(a * b / denominator)` computed with a 512-bit product, as `FullMath.mulDivRoundingUp`
+pub fn mul_div_rounding_up(a: U256, b: U256, denominator: U256) -> Result<U256, ArithmeticError> {
+    if denominator.is_zero() {
+        return Err(ArithmeticError::DivisionByZero);
+    }
+
+    let product = a.full_mul(b);
+    let denominator = U512::from(denominator);
+
+    let mut quotient = product / denominator;
+    if !(product % denominator).is_zero() {
+        quotient += U512::one();
+    }
+
+    U256::try_from(quotient).map_err(|_| ArithmeticError::MulDivOverflow)
+}
+
+/// `floor(a * b / 2^shift)` computed with a 512-bit product, i.e. the product of two fixed point numbers with `shift` fractional bits
+pub fn mul_shift_right(a: U256, b: U256, shift: usize) -> Result<U256, ArithmeticError> {
+    U256::try_from(a.full_mul(b) >> shift).map_err(|_| ArithmeticError::MulDivOverflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::{
+        core::rand::{thread_rng, Rng},
+        types::U256,
+    };
+
+    use super::{mul_div, mul_div_rounding_up, mul_shift_right};
+
+    //Random value with a random bit length, so that small, large and overflowing products are all covered
+    fn random_u256(rng: &mut impl Rng) -> U256 {
+        let words: [u64; 4] = rng.gen();
+        U256(words) >> rng.gen_range(0..=256_usize)
+    }
+
+    #[test]
+    fn test_mul_div() {
+        //Phantom overflow, the product does not fit in 256 bits but the result does
+        let large = U256::one() << 200;
+        assert_eq!(mul_div(large, large, large).unwrap(), large);
+        assert_eq!(mul_div(U256::MAX, U256::MAX, U256::MAX).unwrap(), U256::MAX);
+        assert_eq!(
+            mul_div(U256::MAX, U256::from(3), U256::from(6)).unwrap(),
+            U256::MAX / 2
+        );
+
+        assert!(mul_div(U256::MAX, U256::MAX, U256::MAX - 1).is_err());
+        assert!(mul_div(U256::one(), U256::one(), U256::zero()).is_err());
+
+        assert_eq!(
+            mul_div_rounding_up(U256::from(5), U256::from(3), U256::from(4)).unwrap(),
+            U256::from(4)
+        );
+        assert_eq!(mul_div_rounding_up(large, large, large).unwrap(), large);
+        //Rounding up past the largest value overflows
+        assert!(mul_div_rounding_up(U256::MAX, U256::from(2), U256::from(2)).is_ok());
+        assert!(mul_div_rounding_up(U256::MAX, U256::MAX, U256::MAX - 1).is_err());
+        assert!(mul_div_rounding_up(U256::MAX, U256::from(3), U256::from(2)).is_err());
+
+        assert_eq!(mul_shift_right(large, large, 200).unwrap(), large);
+        assert_eq!(
+            mul_shift_right(U256::MAX, U256::MAX, 512).unwrap(),
+            U256::zero()
+        );
+        assert!(mul_shift_right(large, large, 100).is_err());
+    }
+
+    //The V3 pool math relies on the port of FullMath in uniswap_v3_math, which must agree with these helpers bit for bit
+    #[test]
+    fn test_mul_div_matches_full_math() {
+        let mut rng = thread_rng();
+
+        for _ in 0..20000 {
+            let (a, b, denominator) = (
+                random_u256(&mut rng),
+                random_u256(&mut rng),
+                random_u256(&mut rng),
+            );
+
+            assert_eq!(
+                mul_div(a, b, denominator).ok(),
+                uniswap_v3_math::full_math::mul_div(a, b, denominator).ok(),
+                "mul_div({a}, {b}, {denominator})"
+            );
+            assert_eq!(
+                mul_div_rounding_up(a, b, denominator).ok(),
+                uniswap_v3_math::full_math::mul_div_rounding_up(a, b, denominator).ok(),
+                "mul_div_rounding_up({a}, {b}, {denominator})"
+            );
+
+            let shift = rng.gen_range(0..256);
+            assert_eq!(
+                mul_shift_right(a, b, shift).ok(),
+                uniswap_v3_math::full_math::mul_div(a, b, U256::one() << shift).ok(),
+                "mul_shift_right({a}, {b}, {shift})"
+            );
+        }
+    }
+}
@@ -0,0 +1,6 @@
+pub mod fixed_point;
+pub mod full_math;
+pub mod q128;
+
+pub use full_math::{mul_div, mul_div_rounding_up, mul_shift_right};
+pub use q128::Q128x128;
@@ -0,0 +1,215 @@
+use ethers::types::{I256, U256};
+
+use super::{
+    fixed_point::price_x128_to_f64,
+    full_math::{mul_div, mul_shift_right},
+};
+
+const FRACTIONAL_BITS: usize = 128;
+
+/// Signed Q128.128 fixed point number, stored as an `I256` scaled by 2^128.
+///
+/// Products and quotients go through 512-bit intermediates and are truncated toward zero,
+/// so results are deterministic across platforms. Every operation that can overflow returns None.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Q128x128(I256);
+
+impl Q128x128 {
+    pub fn zero() -> Self {
+        Q128x128(I256::zero())
+    }
+
+    pub fn one() -> Self {
+        Q128x128(I256::from_raw(U256::one() << FRACTIONAL_BITS))
+    }
+
+    pub fn from_raw(raw: I256) -> Self {
+        Q128x128(raw)
+    }
+
+    /// Non negative value from its raw representation, None if it does not fit in an `I256`
+    pub fn from_unsigned_raw(raw: U256) -> Option<Self> {
+        Self::from_parts(false, raw)
+    }
+
+    pub fn into_raw(self) -> I256 {
+        self.0
+    }
+
+    pub fn from_integer(value: i128) -> Option<Self> {
+        Self::from_parts(
+            value < 0,
+            U256::from(value.unsigned_abs()) << FRACTIONAL_BITS,
+        )
+    }
+
+    /// `numerator / denominator` rounded down, None if the denominator is zero or the result overflows
+    pub fn from_ratio(numerator: U256, denominator: U256) -> Option<Self> {
+        let quotient = mul_div(numerator, U256::one() << FRACTIONAL_BITS, denominator).ok()?;
+        Self::from_parts(false, quotient)
+    }
+
+    /// Exact conversion of a finite f64, bits below 2^-128 are truncated
+    pub fn from_f64(value: f64) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+
+        let bits = value.abs().to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i32;
+        let mantissa = bits & ((1 << 52) - 1);
+
+        //The value is mantissa * 2^shift once scaled, subnormals have no implicit leading bit
+        let (mantissa, shift) = if exponent == 0 {
+            (mantissa, FRACTIONAL_BITS as i32 - 1074)
+        } else {
+            (
+                mantissa | (1 << 52),
+                exponent - 1075 + FRACTIONAL_BITS as i32,
+            )
+        };
+
+        let magnitude = if shift >= 0 {
+            if 64 - mantissa.leading_zeros() as i32 + shift > 255 {
+                return None;
+            }
+
+            U256::from(mantissa) << shift as usize
+        } else if shift > -64 {
+            U256::from(mantissa >> -shift)
+        } else {
+            U256::zero()
+        };
+
+        Self::from_parts(value.is_sign_negative(), magnitude)
+    }
+
+    /// Nearest f64 to the value, ties are rounded to even
+    pub fn to_f64(self) -> f64 {
+        let value = price_x128_to_f64(self.0.unsigned_abs());
+
+        if self.is_negative() {
+            -value
+        } else {
+            value
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.is_negative()
+    }
+
+    pub fn checked_neg(self) -> Option<Self> {
+        self.0.checked_neg().map(Q128x128)
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Q128x128)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Q128x128)
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        let product =
+            mul_shift_right(self.0.unsigned_abs(), rhs.0.unsigned_abs(), FRACTIONAL_BITS).ok()?;
+
+        Self::from_parts(self.is_negative() != rhs.is_negative(), product)
+    }
+
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        let quotient = mul_div(
+            self.0.unsigned_abs(),
+            U256::one() << FRACTIONAL_BITS,
+            rhs.0.unsigned_abs(),
+        )
+        .ok()?;
+
+        Self::from_parts(self.is_negative() != rhs.is_negative(), quotient)
+    }
+
+    fn from_parts(negative: bool, magnitude: U256) -> Option<Self> {
+        if magnitude > I256::MAX.into_raw() {
+            return None;
+        }
+
+        let raw = I256::from_raw(magnitude);
+        Some(Q128x128(if negative { -raw } else { raw }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{I256, U256};
+
+    use super::Q128x128;
+
+    #[test]
+    fn test_from_ratio() {
+        let half = Q128x128::from_ratio(U256::from(1), U256::from(2)).unwrap();
+        assert_eq!(half.into_raw(), I256::from_raw(U256::one() << 127));
+        assert_eq!(half.to_f64(), 0.5);
+
+        //1/3 is truncated toward zero
+        let third = Q128x128::from_ratio(U256::from(1), U256::from(3)).unwrap();
+        assert_eq!(
+            third.into_raw().into_raw(),
+            (U256::one() << 128) / U256::from(3)
+        );
+
+        assert_eq!(Q128x128::from_ratio(U256::from(1), U256::zero()), None);
+        assert_eq!(Q128x128::from_ratio(U256::MAX, U256::one()), None);
+        assert_eq!(
+            Q128x128::from_ratio(U256::from(u128::MAX), U256::from(u128::MAX)),
+            Some(Q128x128::one())
+        );
+    }
+
+    #[test]
+    fn test_from_f64() {
+        for value in [
+            0.0,
+            1.0,
+            -1.0,
+            0.5,
+            1.0001_f64.powi(1000),
+            -123456.789,
+            1e-20,
+        ] {
+            assert_eq!(Q128x128::from_f64(value).unwrap().to_f64(), value);
+        }
+
+        //Values below 2^-128 are truncated
+        assert!(Q128x128::from_f64(1e-40).unwrap().is_zero());
+        assert_eq!(Q128x128::from_f64(2_f64.powi(127)), None);
+        assert_eq!(Q128x128::from_f64(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let two = Q128x128::from_integer(2).unwrap();
+        let three = Q128x128::from_integer(3).unwrap();
+        let minus_two = two.checked_neg().unwrap();
+
+        assert_eq!(two.checked_add(three), Q128x128::from_integer(5));
+        assert_eq!(two.checked_sub(three), Q128x128::from_integer(-1));
+        assert_eq!(minus_two.checked_mul(three), Q128x128::from_integer(-6));
+        assert_eq!(minus_two.checked_mul(minus_two), Q128x128::from_integer(4));
+        assert_eq!(three.checked_div(minus_two).unwrap().to_f64(), -1.5);
+        assert_eq!(three.checked_div(Q128x128::zero()), None);
+        assert!(minus_two < Q128x128::zero() && Q128x128::zero() < two);
+
+        //Products only need to fit once shifted back down
+        let large = Q128x128::from_integer(1 << 100).unwrap();
+        assert_eq!(
+            large.checked_div(large).unwrap().checked_mul(large),
+            Some(large)
+        );
+        assert_eq!(large.checked_mul(large), None);
+    }
+}
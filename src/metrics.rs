@@ -0,0 +1,401 @@
+//! Prometheus instrumentation of syncing and of the state space.
+//!
+//! `Metrics` is passed to syncs through `SyncConfig`, to the state space through `StateSpaceManager::with_metrics` and
+//! to the quoter through `Quoter::with_metrics`.
+//! Without the `metrics` feature it is zero sized and every recording method is empty, so the instrumentation
+//! compiles away.
+
+#[cfg(feature = "metrics")]
+use std::{sync::Arc, time::Instant};
+
+#[cfg(feature = "metrics")]
+use async_trait::async_trait;
+#[cfg(feature = "metrics")]
+use ethers::providers::JsonRpcClient;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    proto::MetricFamily, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry,
+};
+#[cfg(feature = "metrics")]
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Handle recording sync and state space metrics, every method is a no-op on the default handle
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    #[cfg(feature = "metrics")]
+    inner: Option<Arc<PrometheusMetrics>>,
+}
+
+#[cfg(feature = "metrics")]
+struct PrometheusMetrics {
+    registry: Registry,
+    sync_phase_seconds: HistogramVec,
+    pools_populated: IntCounterVec,
+    rpc_calls: IntCounterVec,
+    rpc_errors: IntCounterVec,
+    rpc_retries: IntCounterVec,
+    block_lag: IntGauge,
+    block_apply_seconds: Histogram,
+    reorgs: IntCounter,
+    log_errors: IntCounterVec,
+    channel_depth: IntGaugeVec,
+    quote_seconds: Histogram,
+}
+
+#[cfg(feature = "metrics")]
+impl std::fmt::Debug for PrometheusMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrometheusMetrics").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl PrometheusMetrics {
+    fn new(registry: Registry) -> Result<Self, prometheus::Error> {
+        let metrics = PrometheusMetrics {
+            sync_phase_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "amms_sync_phase_seconds",
+                    "Duration of each phase of a sync",
+                )
+                .buckets(prometheus::exponential_buckets(0.1, 2.0, 14)?),
+                &["phase"],
+            )?,
+            pools_populated: IntCounterVec::new(
+                Opts::new("amms_pools_populated_total", "Pools populated by syncs"),
+                &["protocol"],
+            )?,
+            rpc_calls: IntCounterVec::new(
+                Opts::new("amms_rpc_calls_total", "JSON-RPC requests sent"),
+                &["method"],
+            )?,
+            rpc_errors: IntCounterVec::new(
+                Opts::new("amms_rpc_errors_total", "JSON-RPC requests that failed"),
+                &["method"],
+            )?,
+            rpc_retries: IntCounterVec::new(
+                Opts::new("amms_rpc_retries_total", "Failed calls that were retried"),
+                &["kind"],
+            )?,
+            block_lag: IntGauge::new(
+                "amms_state_space_block_lag",
+                "Blocks between the chain head and the last block applied to the state space",
+            )?,
+            block_apply_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "amms_state_space_block_apply_seconds",
+                    "Time taken to apply the state changes of a block",
+                )
+                .buckets(prometheus::exponential_buckets(0.001, 2.0, 14)?),
+            )?,
+            reorgs: IntCounter::new("amms_state_space_reorgs_total", "Reorgs unwound")?,
+            log_errors: IntCounterVec::new(
+                Opts::new(
+                    "amms_state_space_log_errors_total",
+                    "Logs skipped because an AMM failed to sync from them",
+                ),
+                &["kind"],
+            )?,
+            channel_depth: IntGaugeVec::new(
+                Opts::new("amms_channel_depth", "Messages waiting in a channel"),
+                &["channel"],
+            )?,
+            quote_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "amms_quote_seconds",
+                    "Time taken to answer a quote request, including the time spent queued",
+                )
+                .buckets(prometheus::exponential_buckets(0.0001, 2.0, 14)?),
+            )?,
+            registry,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.sync_phase_seconds.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.pools_populated.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.rpc_calls.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.rpc_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.rpc_retries.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.block_lag.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.block_apply_seconds.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.reorgs.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.log_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.channel_depth.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quote_seconds.clone()))?;
+
+        Ok(metrics)
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+impl Metrics {
+    /// Registers the metrics of the crate with `registry`, which can be shared with the metrics of the host application
+    #[cfg(feature = "metrics")]
+    pub fn new(registry: Registry) -> Result<Self, prometheus::Error> {
+        Ok(Metrics {
+            inner: Some(Arc::new(PrometheusMetrics::new(registry)?)),
+        })
+    }
+
+    /// Current value of every metric of the registry, to be encoded and served by the host application
+    #[cfg(feature = "metrics")]
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        match &self.inner {
+            Some(inner) => inner.registry.gather(),
+            None => vec![],
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn registry(&self) -> Option<&Registry> {
+        self.inner.as_ref().map(|inner| &inner.registry)
+    }
+
+    /// Times a phase of a sync, i.e. `discover` or `populate`, until the returned timer is dropped
+    #[inline]
+    pub fn sync_phase_timer(&self, phase: &str) -> Timer {
+        Timer {
+            #[cfg(feature = "metrics")]
+            observed: self.inner.as_ref().map(|inner| {
+                (
+                    inner.sync_phase_seconds.with_label_values(&[phase]),
+                    Instant::now(),
+                )
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn pools_populated(&self, protocol: &str, pools: usize) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .pools_populated
+                .with_label_values(&[protocol])
+                .inc_by(pools as u64);
+        }
+    }
+
+    #[inline]
+    pub fn rpc_call(&self, method: &str, failed: bool) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.rpc_calls.with_label_values(&[method]).inc();
+            if failed {
+                inner.rpc_errors.with_label_values(&[method]).inc();
+            }
+        }
+    }
+
+    #[inline]
+    pub fn rpc_retry(&self, kind: &str) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.rpc_retries.with_label_values(&[kind]).inc();
+        }
+    }
+
+    #[inline]
+    pub fn block_lag(&self, blocks: u64) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.block_lag.set(blocks as i64);
+        }
+    }
+
+    /// Times the application of a block to the state space until the returned timer is dropped
+    #[inline]
+    pub fn block_apply_timer(&self) -> Timer {
+        Timer {
+            #[cfg(feature = "metrics")]
+            observed: self
+                .inner
+                .as_ref()
+                .map(|inner| (inner.block_apply_seconds.clone(), Instant::now())),
+        }
+    }
+
+    #[inline]
+    pub fn reorg(&self) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.reorgs.inc();
+        }
+    }
+
+    #[inline]
+    pub fn log_error(&self, kind: &str) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.log_errors.with_label_values(&[kind]).inc();
+        }
+    }
+
+    /// Times a quote request from the moment it is sent to the quoter until the returned timer is dropped
+    #[inline]
+    pub fn quote_timer(&self) -> Timer {
+        Timer {
+            #[cfg(feature = "metrics")]
+            observed: self
+                .inner
+                .as_ref()
+                .map(|inner| (inner.quote_seconds.clone(), Instant::now())),
+        }
+    }
+
+    #[inline]
+    pub fn channel_depth(&self, channel: &str, depth: usize) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .channel_depth
+                .with_label_values(&[channel])
+                .set(depth as i64);
+        }
+    }
+}
+
+/// Observes the time elapsed since it was created when dropped, zero sized without the `metrics` feature
+#[must_use = "the timer observes the elapsed time when it is dropped"]
+pub struct Timer {
+    #[cfg(feature = "metrics")]
+    observed: Option<(Histogram, Instant)>,
+}
+
+impl Timer {
+    /// Observes the elapsed time now rather than at the end of the scope
+    #[inline]
+    pub fn stop(self) {}
+}
+
+impl std::fmt::Debug for Timer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Timer").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some((histogram, start)) = self.observed.take() {
+            histogram.observe(start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// JSON-RPC client counting the requests of an ethers `Provider` by method, i.e.
+/// `Provider::new(MetricsClient::new(Http::from_str(url)?, metrics))`
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+pub struct MetricsClient<C> {
+    inner: C,
+    metrics: Metrics,
+}
+
+#[cfg(feature = "metrics")]
+impl<C> MetricsClient<C> {
+    pub fn new(inner: C, metrics: Metrics) -> Self {
+        MetricsClient { inner, metrics }
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[async_trait]
+impl<C: JsonRpcClient> JsonRpcClient for MetricsClient<C> {
+    type Error = C::Error;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: std::fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let response = self.inner.request(method, params).await;
+        self.metrics.rpc_call(method, response.is_err());
+
+        response
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use prometheus::Registry;
+
+    use super::Metrics;
+
+    #[test]
+    fn test_gather() -> eyre::Result<()> {
+        let metrics = Metrics::new(Registry::new())?;
+
+        {
+            let _timer = metrics.sync_phase_timer("populate");
+            metrics.pools_populated("uniswap_v2", 10);
+            metrics.rpc_call("eth_call", false);
+            metrics.rpc_call("eth_call", true);
+            metrics.block_lag(3);
+            metrics.reorg();
+        }
+
+        let families = metrics.gather();
+        let value = |name: &str| {
+            families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .map(|family| family.get_metric().to_vec())
+                .unwrap_or_default()
+        };
+
+        assert_eq!(
+            value("amms_sync_phase_seconds")[0]
+                .get_histogram()
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(
+            value("amms_pools_populated_total")[0]
+                .get_counter()
+                .get_value(),
+            10.0
+        );
+        assert_eq!(
+            value("amms_rpc_calls_total")[0].get_counter().get_value(),
+            2.0
+        );
+        assert_eq!(
+            value("amms_rpc_errors_total")[0].get_counter().get_value(),
+            1.0
+        );
+        assert_eq!(
+            value("amms_state_space_block_lag")[0]
+                .get_gauge()
+                .get_value(),
+            3.0
+        );
+
+        //Registering the same metrics twice is rejected by the registry
+        assert!(Metrics::new(metrics.registry().cloned().unwrap_or_default()).is_err());
+
+        Ok(())
+    }
+}
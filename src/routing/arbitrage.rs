@@ -0,0 +1,282 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+};
+
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::amm::AmmState;
+
+use super::{AmmLookup, Route, TokenGraph};
+
+/// Pools of each token pair kept as edges by the cycle search, the ones with the best marginal rate.
+/// Keeping more than one lets the search find cycles between parallel pools of the same pair.
+pub const EDGES_PER_PAIR: usize = 2;
+
+/// Cycle selling and buying back `route.token_in()`, and its log profit at infinitesimal size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArbitrageCycle<K = usize> {
+    pub route: Route<K>,
+    /// Sum of the log marginal rates of the hops, net of fees. The cycle returns `exp(log_profit)` per unit in
+    /// for an infinitesimal amount.
+    pub log_profit: f64,
+}
+
+//Cheapest path found from the start token to a token, with the sum of its edge weights
+struct Label<K> {
+    weight: f64,
+    hops: Vec<(K, H160)>,
+}
+
+/// Profitable cycles through at most `max_len` AMMs starting and ending at one of `start_tokens`, most
+/// profitable first.
+///
+/// Runs a Bellman-Ford relaxation from each start token with edge weights of `-ln(marginal rate)`, where the
+/// marginal rate is the gradient of the pool at zero amount and so is net of fees. Every path of the relaxation
+/// that can close back to the start token with a negative total weight is a candidate cycle. Cycles never use
+/// a pool or pass through a token more than once, and a cycle reachable from several start tokens is only
+/// returned once, anchored at the first of `start_tokens` it goes through.
+pub fn find_arbitrage_cycles<K, L>(
+    graph: &TokenGraph<K>,
+    amms: &L,
+    start_tokens: &[H160],
+    max_len: usize,
+) -> Vec<ArbitrageCycle<K>>
+where
+    K: Copy + Ord + Hash,
+    L: AmmLookup<K> + ?Sized,
+{
+    let mut cycles = vec![];
+    let mut seen = HashSet::new();
+    let mut edges: HashMap<H160, Vec<(H160, K, f64)>> = HashMap::new();
+
+    for start in start_tokens.iter() {
+        let mut layer = BTreeMap::from([(
+            *start,
+            Label {
+                weight: 0.0,
+                hops: vec![],
+            },
+        )]);
+
+        for _ in 0..max_len {
+            let mut next: BTreeMap<H160, Label<K>> = BTreeMap::new();
+
+            for (token, label) in layer.iter() {
+                let token_edges = edges
+                    .entry(*token)
+                    .or_insert_with(|| best_edges(graph, amms, *token));
+
+                for (neighbor, id, weight) in token_edges.iter() {
+                    if label.hops.iter().any(|(used, _)| used == id) {
+                        continue;
+                    }
+
+                    let weight = label.weight + weight;
+                    if neighbor == start {
+                        if weight < 0.0 {
+                            let mut hops = label.hops.clone();
+                            hops.push((*id, *token));
+
+                            if seen.insert(canonical_rotation(&hops)) {
+                                cycles.push(ArbitrageCycle {
+                                    route: Route {
+                                        hops,
+                                        token_out: *start,
+                                    },
+                                    log_profit: -weight,
+                                });
+                            }
+                        }
+                    } else if !label.hops.iter().any(|(_, token_in)| token_in == neighbor)
+                        && !next
+                            .get(neighbor)
+                            .is_some_and(|relaxed| relaxed.weight <= weight)
+                    {
+                        let mut hops = label.hops.clone();
+                        hops.push((*id, *token));
+                        next.insert(*neighbor, Label { weight, hops });
+                    }
+                }
+            }
+
+            if next.is_empty() {
+                break;
+            }
+            layer = next;
+        }
+    }
+
+    cycles.sort_by(|a, b| {
+        b.log_profit
+            .total_cmp(&a.log_profit)
+            .then_with(|| a.route.hops.cmp(&b.route.hops))
+    });
+
+    cycles
+}
+
+//Edges out of `token` as (token out, AMM, -ln(marginal rate)), keeping the best `EDGES_PER_PAIR` AMMs of each pair
+fn best_edges<K, L>(graph: &TokenGraph<K>, amms: &L, token: H160) -> Vec<(H160, K, f64)>
+where
+    K: Copy + Ord + Hash,
+    L: AmmLookup<K> + ?Sized,
+{
+    let mut neighbors = graph.neighbors(token).collect::<Vec<_>>();
+    neighbors.sort_unstable();
+
+    let mut edges = vec![];
+    for neighbor in neighbors {
+        let mut pair_edges = graph
+            .pools_for_pair(token, neighbor)
+            .iter()
+            .filter_map(|id| {
+                amms.get_amm(*id)?
+                    .gradient_to(token, neighbor, U256::zero())
+                    .ok()
+                    .map(|gradient| gradient.to_f64())
+                    .filter(|rate| *rate > 0.0 && rate.is_finite())
+                    .map(|rate| (neighbor, *id, -rate.ln()))
+            })
+            .collect::<Vec<_>>();
+
+        pair_edges.sort_unstable_by(|a, b| a.2.total_cmp(&b.2).then(a.1.cmp(&b.1)));
+        pair_edges.truncate(EDGES_PER_PAIR);
+        edges.extend(pair_edges);
+    }
+
+    edges
+}
+
+//Rotation of the hops of a cycle starting at its smallest hop, equal for every rotation of the same cycle
+fn canonical_rotation<K: Copy + Ord>(hops: &[(K, H160)]) -> Vec<(K, H160)> {
+    let first = hops
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, hop)| **hop)
+        .map(|(idx, _)| idx)
+        .unwrap_or_default();
+
+    hops[first..]
+        .iter()
+        .chain(hops[..first].iter())
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AMM},
+        routing::TokenGraph,
+    };
+
+    use super::find_arbitrage_cycles;
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn pool(address: u64, token_a: u64, token_b: u64, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: token(token_a),
+            token_b: token(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    //1 -> 2 at 2, 2 -> 3 at 1 and 3 -> 1 at 0.6, so going around returns 1.2 before fees
+    fn triangle() -> Vec<AMM> {
+        vec![
+            pool(100, 1, 2, 10_u128.pow(24), 2 * 10_u128.pow(24)),
+            pool(101, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(102, 3, 1, 10_u128.pow(25), 6 * 10_u128.pow(24)),
+        ]
+    }
+
+    #[test]
+    fn test_triangle_cycle() -> eyre::Result<()> {
+        let amms = triangle();
+        let graph = TokenGraph::new(&amms);
+
+        let cycles = find_arbitrage_cycles(&graph, &amms, &[token(1)], 3);
+        assert_eq!(cycles.len(), 1);
+
+        let cycle = &cycles[0];
+        assert_eq!(
+            cycle.route.hops(),
+            &[(0, token(1)), (1, token(2)), (2, token(3))]
+        );
+        assert_eq!(cycle.route.token_out(), token(1));
+        cycle.route.validate(&amms)?;
+
+        let expected = 1.2_f64.ln() + 3.0 * 0.997_f64.ln();
+        assert!((cycle.log_profit - expected).abs() < 1e-9);
+
+        //A small trade around the cycle realizes close to the theoretical profit
+        let amount_in = U256::exp10(15);
+        let amount_out = cycle.route.simulate(amount_in, &amms)?;
+        let realized = (amount_out.as_u128() as f64 / amount_in.as_u128() as f64).ln();
+        assert!((realized - cycle.log_profit).abs() < 1e-6);
+
+        //The cycle is too long for two hops
+        assert!(find_arbitrage_cycles(&graph, &amms, &[token(1)], 2).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cycles_are_deduplicated_across_start_tokens() {
+        let amms = triangle();
+        let graph = TokenGraph::new(&amms);
+
+        let cycles = find_arbitrage_cycles(&graph, &amms, &[token(2), token(1)], 4);
+        assert_eq!(cycles.len(), 1);
+        //Anchored at the first start token
+        assert_eq!(cycles[0].route.token_in(), token(2));
+        assert_eq!(
+            cycles[0].route.hops(),
+            &[(1, token(2)), (2, token(3)), (0, token(1))]
+        );
+    }
+
+    #[test]
+    fn test_parallel_pool_cycle() {
+        let mut amms = triangle();
+        //Prices 1 -> 2 at 1.8, so buying 2 on pool 0 and selling it here is profitable
+        amms.push(pool(103, 1, 2, 10_u128.pow(24), 18 * 10_u128.pow(23)));
+        let graph = TokenGraph::new(&amms);
+
+        let cycles = find_arbitrage_cycles(&graph, &amms, &[token(1)], 2);
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].route.hops(), &[(0, token(1)), (3, token(2))]);
+
+        let expected = (2.0_f64 / 1.8).ln() + 2.0 * 0.997_f64.ln();
+        assert!((cycles[0].log_profit - expected).abs() < 1e-9);
+
+        //Longer cycles through pool 3 come after, most profitable first
+        let cycles = find_arbitrage_cycles(&graph, &amms, &[token(1)], 3);
+        for window in cycles.windows(2) {
+            assert!(window[0].log_profit >= window[1].log_profit);
+        }
+    }
+
+    #[test]
+    fn test_no_cycle_in_consistent_prices() {
+        let amms = vec![
+            pool(100, 1, 2, 10_u128.pow(24), 2 * 10_u128.pow(24)),
+            pool(101, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(102, 3, 1, 2 * 10_u128.pow(24), 10_u128.pow(24)),
+        ];
+        let graph = TokenGraph::new(&amms);
+
+        assert!(find_arbitrage_cycles(&graph, &amms, &[token(1), token(2)], 3).is_empty());
+    }
+}
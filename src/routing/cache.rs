@@ -0,0 +1,461 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use ethers::types::{H160, H256, U256};
+use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+
+use crate::{amm::AmmState, state_space::state::StateSpace};
+
+use super::{find_best_route, GasCostModel, RouteConstraints, RouteQuote, TokenGraph};
+
+/// Swap whose best routes are kept by a `RouteCache`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RouteRequest {
+    pub token_in: H160,
+    pub token_out: H160,
+    pub amount_in: U256,
+}
+
+impl RouteRequest {
+    pub fn new(token_in: H160, token_out: H160, amount_in: U256) -> Self {
+        RouteRequest {
+            token_in,
+            token_out,
+            amount_in,
+        }
+    }
+}
+
+//Best routes of a request, and the state fingerprints of the pools they depend on when they were searched
+#[derive(Debug, Clone, Default)]
+struct CachedRoutes {
+    quotes: Vec<RouteQuote<H160>>,
+    dependencies: HashMap<H160, H256>,
+}
+
+impl CachedRoutes {
+    //Whether a pool the routes depend on is now in a different state
+    fn is_stale(&self, fingerprints: &HashMap<H160, H256>) -> bool {
+        fingerprints.iter().any(|(address, fingerprint)| {
+            self.dependencies
+                .get(address)
+                .is_some_and(|searched| searched != fingerprint)
+        })
+    }
+}
+
+//Fingerprint of the AMM at `address`, zero once it is removed from the state space
+fn fingerprint(state: &StateSpace, address: H160) -> H256 {
+    state
+        .get(&address)
+        .map(|amm| amm.state_fingerprint())
+        .unwrap_or_default()
+}
+
+/// Best routes of registered requests, kept up to date from the AMMs updated in each block.
+///
+/// A request depends on every pool of the pairs its cached routes go through, or of the pairs around its tokens
+/// when it has no route. An update only searches again the requests depending on an updated pool whose
+/// `state_fingerprint` differs from the one its routes were searched with, the others keep their routes. Routes through pairs a request does not depend on can still become better, so every
+/// `full_refresh_interval` updates the graph is rebuilt from the state space and every request is searched again.
+///
+/// Searches run without holding the lock on the cached routes, which readers only wait on while the new routes
+/// of an update are swapped in.
+#[derive(Debug)]
+pub struct RouteCache {
+    max_hops: usize,
+    top_k: usize,
+    constraints: RouteConstraints,
+    gas_model: Option<GasCostModel>,
+    full_refresh_interval: u64,
+    graph: RwLock<TokenGraph<H160>>,
+    entries: RwLock<HashMap<RouteRequest, CachedRoutes>>,
+    updates: AtomicU64,
+    hits: AtomicU64,
+    recomputes: AtomicU64,
+}
+
+impl RouteCache {
+    pub fn new(state: &StateSpace, max_hops: usize, top_k: usize) -> Self {
+        RouteCache {
+            max_hops,
+            top_k,
+            constraints: RouteConstraints::default(),
+            gas_model: None,
+            full_refresh_interval: 100,
+            graph: RwLock::new(TokenGraph::from_state_space(state)),
+            entries: RwLock::new(HashMap::new()),
+            updates: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            recomputes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_constraints(mut self, constraints: RouteConstraints) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    pub fn with_gas_model(mut self, gas_model: GasCostModel) -> Self {
+        self.gas_model = Some(gas_model);
+        self
+    }
+
+    /// Updates between full refreshes, 0 never refreshes
+    pub fn with_full_refresh_interval(mut self, full_refresh_interval: u64) -> Self {
+        self.full_refresh_interval = full_refresh_interval;
+        self
+    }
+
+    /// Searches the routes of `request` and keeps them up to date, returning them best first
+    pub fn register(&self, request: RouteRequest, state: &StateSpace) -> Vec<RouteQuote<H160>> {
+        let cached = self.search(request, state);
+        let quotes = cached.quotes.clone();
+
+        self.entries
+            .write()
+            .expect("Route cache poisoned")
+            .insert(request, cached);
+
+        quotes
+    }
+
+    /// Stops keeping the routes of `request`, returning whether it was registered
+    pub fn deregister(&self, request: &RouteRequest) -> bool {
+        self.entries
+            .write()
+            .expect("Route cache poisoned")
+            .remove(request)
+            .is_some()
+    }
+
+    /// Cached routes of `request` best first, None if it is not registered
+    pub fn get(&self, request: &RouteRequest) -> Option<Vec<RouteQuote<H160>>> {
+        self.entries
+            .read()
+            .expect("Route cache poisoned")
+            .get(request)
+            .map(|cached| cached.quotes.clone())
+    }
+
+    /// Cached best route of `request`
+    pub fn best(&self, request: &RouteRequest) -> Option<RouteQuote<H160>> {
+        self.entries
+            .read()
+            .expect("Route cache poisoned")
+            .get(request)
+            .and_then(|cached| cached.quotes.first().cloned())
+    }
+
+    /// Requests kept without a new search by an update
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Searches run by registrations and updates
+    pub fn recomputes(&self) -> u64 {
+        self.recomputes.load(Ordering::Relaxed)
+    }
+
+    /// Applies the AMMs of `state` updated in a block, searching again the requests depending on those whose state
+    /// changed, or every request on a full refresh. Returns the number of requests searched again.
+    pub fn update(&self, amms_updated: &[H160], state: &StateSpace) -> usize {
+        let updates = self.updates.fetch_add(1, Ordering::Relaxed) + 1;
+        let full_refresh =
+            self.full_refresh_interval != 0 && updates % self.full_refresh_interval == 0;
+
+        {
+            let mut graph = self.graph.write().expect("Route cache poisoned");
+            if full_refresh {
+                *graph = TokenGraph::from_state_space(state);
+            } else {
+                //Pools added to the state space since the graph was built join it as they are updated
+                for address in amms_updated.iter() {
+                    match state.get(address) {
+                        Some(amm) if !graph.contains(*address) => graph.insert(*address, amm),
+                        None => {
+                            graph.remove(*address);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let fingerprints = amms_updated
+            .iter()
+            .map(|address| (*address, fingerprint(state, *address)))
+            .collect::<HashMap<_, _>>();
+        let stale = {
+            let entries = self.entries.read().expect("Route cache poisoned");
+            let stale = entries
+                .iter()
+                .filter(|(_, cached)| full_refresh || cached.is_stale(&fingerprints))
+                .map(|(request, _)| *request)
+                .collect::<Vec<_>>();
+
+            self.hits
+                .fetch_add((entries.len() - stale.len()) as u64, Ordering::Relaxed);
+            stale
+        };
+
+        let refreshed = stale
+            .iter()
+            .map(|request| (*request, self.search(*request, state)))
+            .collect::<Vec<_>>();
+
+        let mut entries = self.entries.write().expect("Route cache poisoned");
+        for (request, cached) in refreshed {
+            //Requests deregistered during the search are not added back
+            if let Some(entry) = entries.get_mut(&request) {
+                *entry = cached;
+            }
+        }
+
+        stale.len()
+    }
+
+    /// Updates the cache with the AMMs updated in each block, as sent by
+    /// `StateSpaceManager::listen_for_state_changes`, until the channel closes.
+    pub fn listen(
+        self: Arc<Self>,
+        state: Arc<tokio::sync::RwLock<StateSpace>>,
+        mut amms_updated: Receiver<Vec<H160>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(amms_updated) = amms_updated.recv().await {
+                let state = state.read().await;
+                let recomputed = self.update(&amms_updated, &state);
+                tracing::debug!(
+                    amms_updated = amms_updated.len(),
+                    recomputed,
+                    "updated route cache"
+                );
+            }
+        })
+    }
+
+    fn search(&self, request: RouteRequest, state: &StateSpace) -> CachedRoutes {
+        self.recomputes.fetch_add(1, Ordering::Relaxed);
+        let graph = self.graph.read().expect("Route cache poisoned");
+
+        let quotes = find_best_route(
+            &graph,
+            state,
+            request.token_in,
+            request.token_out,
+            request.amount_in,
+            self.max_hops,
+            self.top_k,
+            self.gas_model.as_ref(),
+            &self.constraints,
+        )
+        .unwrap_or_default();
+
+        let mut dependencies = HashSet::new();
+        for quote in quotes.iter() {
+            let tokens = quote
+                .route
+                .hops()
+                .iter()
+                .map(|(_, token_in)| *token_in)
+                .chain([quote.route.token_out()])
+                .collect::<Vec<_>>();
+
+            for pair in tokens.windows(2) {
+                dependencies.extend(graph.pools_for_pair(pair[0], pair[1]).iter().copied());
+            }
+        }
+
+        //Without a route, any pool next to either token may open one
+        if quotes.is_empty() {
+            for token in [request.token_in, request.token_out] {
+                for neighbor in graph.neighbors(token) {
+                    dependencies.extend(graph.pools_for_pair(token, neighbor).iter().copied());
+                }
+            }
+        }
+
+        CachedRoutes {
+            quotes,
+            dependencies: dependencies
+                .into_iter()
+                .map(|address| (address, fingerprint(state, address)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AMM},
+        state_space::state::{initialize_state_space, StateSpace},
+    };
+
+    use super::{RouteCache, RouteRequest};
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn pool(address: u64, token_a: u64, token_b: u64, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: token(token_a),
+            token_b: token(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    fn state() -> StateSpace {
+        initialize_state_space(vec![
+            pool(100, 1, 3, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(101, 1, 2, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(102, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+            //Unrelated to requests from 1 to 3
+            pool(103, 4, 5, 10_u128.pow(24), 10_u128.pow(24)),
+        ])
+    }
+
+    fn set_reserves(state: &mut StateSpace, address: u64, reserve_0: u128, reserve_1: u128) {
+        if let Some(AMM::UniswapV2Pool(pool)) = state.get_mut(&token(address)) {
+            pool.reserve_0 = reserve_0;
+            pool.reserve_1 = reserve_1;
+        }
+    }
+
+    //Changes the state of a pool without moving its price noticeably
+    fn touch(state: &mut StateSpace, address: u64) {
+        if let Some(AMM::UniswapV2Pool(pool)) = state.get_mut(&token(address)) {
+            pool.reserve_0 += 1;
+        }
+    }
+
+    #[test]
+    fn test_updates_only_dependent_requests() {
+        let mut state = state();
+        let cache = RouteCache::new(&state, 2, 3).with_full_refresh_interval(0);
+        let request = RouteRequest::new(token(1), token(3), U256::exp10(18));
+        let other = RouteRequest::new(token(4), token(5), U256::exp10(18));
+
+        let quotes = cache.register(request, &state);
+        assert_eq!(quotes[0].route.hops(), &[(token(100), token(1))]);
+        cache.register(other, &state);
+        assert_eq!(cache.recomputes(), 2);
+
+        //An update of the unrelated pool only searches the request through it again
+        touch(&mut state, 103);
+        assert_eq!(cache.update(&[token(103)], &state), 1);
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.recomputes(), 3);
+
+        //Draining the direct pool moves the best route to the two hop one
+        set_reserves(&mut state, 100, 10_u128.pow(24), 10_u128.pow(23));
+        assert_eq!(cache.update(&[token(100)], &state), 1);
+        assert_eq!(
+            cache.best(&request).unwrap().route.hops(),
+            &[(token(101), token(1)), (token(102), token(2))]
+        );
+        assert_eq!(cache.hits(), 2);
+
+        //The route now depends on the pools of both of its pairs
+        touch(&mut state, 102);
+        assert_eq!(cache.update(&[token(102)], &state), 1);
+        touch(&mut state, 101);
+        touch(&mut state, 103);
+        assert_eq!(cache.update(&[token(103), token(101)], &state), 2);
+
+        assert!(cache.deregister(&other));
+        assert_eq!(cache.get(&other), None);
+        touch(&mut state, 103);
+        assert_eq!(cache.update(&[token(103)], &state), 0);
+    }
+
+    #[test]
+    fn test_unchanged_state_is_a_hit() {
+        let mut state = state();
+        let cache = RouteCache::new(&state, 2, 3).with_full_refresh_interval(0);
+        let request = RouteRequest::new(token(1), token(3), U256::exp10(18));
+        cache.register(request, &state);
+
+        //Pools reported as updated in a state they were already searched with keep the routes
+        assert_eq!(cache.update(&[token(100), token(101)], &state), 0);
+        assert_eq!(cache.hits(), 1);
+
+        //A change is searched once, the same state reported again is a hit
+        set_reserves(&mut state, 100, 10_u128.pow(24), 10_u128.pow(23));
+        assert_eq!(cache.update(&[token(100)], &state), 1);
+        assert_eq!(cache.update(&[token(100)], &state), 0);
+
+        //A removed pool has no state to compare, the routes through it are searched again
+        state.remove(&token(102));
+        assert_eq!(cache.update(&[token(102)], &state), 1);
+        assert_eq!(cache.recomputes(), 3);
+    }
+
+    #[test]
+    fn test_full_refresh() {
+        let mut state = state();
+        let cache = RouteCache::new(&state, 2, 1).with_full_refresh_interval(2);
+        let request = RouteRequest::new(token(1), token(3), U256::exp10(18));
+        cache.register(request, &state);
+
+        //A new pool is not a dependency, but is found by the next full refresh
+        let new_pool = pool(104, 1, 3, 10_u128.pow(24), 2 * 10_u128.pow(24));
+        state.insert(token(104), new_pool);
+
+        assert_eq!(cache.update(&[], &state), 0);
+        assert_eq!(
+            cache.best(&request).unwrap().route.hops(),
+            &[(token(100), token(1))]
+        );
+
+        assert_eq!(cache.update(&[], &state), 1);
+        assert_eq!(
+            cache.best(&request).unwrap().route.hops(),
+            &[(token(104), token(1))]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_reads_during_updates() {
+        let mut state = state();
+        let cache = RouteCache::new(&state, 2, 3);
+        let request = RouteRequest::new(token(1), token(3), U256::exp10(18));
+        cache.register(request, &state);
+        set_reserves(&mut state, 100, 10_u128.pow(24), 10_u128.pow(23));
+
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let quotes = cache.get(&request).expect("request is registered");
+                        assert!(!quotes.is_empty());
+                    }
+                });
+            }
+
+            for _ in 0..100 {
+                touch(&mut state, 100);
+                cache.update(&[token(100)], &state);
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(cache.recomputes(), 101);
+    }
+}
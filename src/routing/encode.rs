@@ -0,0 +1,551 @@
+use ethers::{
+    abi::{AbiEncode, Token},
+    prelude::abigen,
+    types::{Bytes, H160, U256},
+};
+
+use crate::{
+    amm::{
+        known_factories::{known_router, KnownRouterKind},
+        AMM,
+    },
+    errors::RouteError,
+};
+
+use super::{AmmLookup, Route};
+
+abigen!(
+    IUniswapV2Router,
+    r#"[
+        function swapExactTokensForTokens(uint256 amountIn, uint256 amountOutMin, address[] calldata path, address to, uint256 deadline) external returns (uint256[] memory amounts)
+        function swapExactTokensForTokensSupportingFeeOnTransferTokens(uint256 amountIn, uint256 amountOutMin, address[] calldata path, address to, uint256 deadline) external
+    ]"#;
+
+    ISwapRouter,
+    r#"[
+        struct ExactInputParams { bytes path; address recipient; uint256 deadline; uint256 amountIn; uint256 amountOutMinimum; }
+        function exactInput(ExactInputParams calldata params) external payable returns (uint256 amountOut)
+    ]"#;
+
+    IUniversalRouter,
+    r#"[
+        function execute(bytes calldata commands, bytes[] calldata inputs, uint256 deadline) external payable
+    ]"#;
+);
+
+/// UniversalRouter command swapping an exact amount in through a V3 path
+pub const V3_SWAP_EXACT_IN: u8 = 0x00;
+/// UniversalRouter command swapping an exact amount in through a V2 path
+pub const V2_SWAP_EXACT_IN: u8 = 0x08;
+/// Recipient standing for the UniversalRouter itself
+pub const ADDRESS_THIS: H160 = H160([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+/// Router a route is encoded for, and its address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouterTarget {
+    /// `swapExactTokensForTokens` on a UniswapV2Router02, for routes of V2 pools only
+    UniswapV2Router(H160),
+    /// `exactInput` on a Uniswap V3 SwapRouter, for routes of V3 pools only
+    UniswapV3Router(H160),
+    /// `execute` on a UniversalRouter, with a swap command for each run of hops on the same protocol
+    UniversalRouter(H160),
+}
+
+impl RouterTarget {
+    pub fn uniswap_v2_router(chain_id: u64) -> Option<Self> {
+        known_router(chain_id, KnownRouterKind::UniswapV2Router).map(RouterTarget::UniswapV2Router)
+    }
+
+    pub fn uniswap_v3_router(chain_id: u64) -> Option<Self> {
+        known_router(chain_id, KnownRouterKind::UniswapV3SwapRouter)
+            .map(RouterTarget::UniswapV3Router)
+    }
+
+    pub fn universal_router(chain_id: u64) -> Option<Self> {
+        known_router(chain_id, KnownRouterKind::UniversalRouter).map(RouterTarget::UniversalRouter)
+    }
+
+    pub fn address(&self) -> H160 {
+        match self {
+            RouterTarget::UniswapV2Router(address)
+            | RouterTarget::UniswapV3Router(address)
+            | RouterTarget::UniversalRouter(address) => *address,
+        }
+    }
+}
+
+/// Encodes a swap of `amount_in` through `route` for `target`, returning the address to call, the calldata and
+/// the native value to send.
+///
+/// Routers do not swap on the pools of the route but on the pools their factory derives for each pair, and fee for
+/// V3 hops, so the route should only go through pools of the canonical Uniswap factories. The input is pulled from
+/// the sender, which must have approved the router, or Permit2 for the UniversalRouter, so the value is always zero.
+/// On the UniversalRouter, runs of hops on the same protocol are swapped by one command, each run but the last
+/// leaving its output in the router for the next one to swap its whole balance.
+pub fn encode_route<K, L>(
+    route: &Route<K>,
+    amms: &L,
+    amount_in: U256,
+    min_out: U256,
+    recipient: H160,
+    deadline: U256,
+    target: RouterTarget,
+) -> Result<(H160, Bytes, U256), RouteError>
+where
+    K: Copy + PartialEq,
+    L: AmmLookup<K> + ?Sized,
+{
+    route.validate(amms)?;
+    let segments = segments(route, amms)?;
+
+    let calldata = match target {
+        RouterTarget::UniswapV2Router(_) => match segments.as_slice() {
+            [Segment::V2 {
+                tokens,
+                transfer_tax,
+            }] => {
+                let path = tokens.clone();
+                if *transfer_tax {
+                    SwapExactTokensForTokensSupportingFeeOnTransferTokensCall {
+                        amount_in,
+                        amount_out_min: min_out,
+                        path,
+                        to: recipient,
+                        deadline,
+                    }
+                    .encode()
+                } else {
+                    SwapExactTokensForTokensCall {
+                        amount_in,
+                        amount_out_min: min_out,
+                        path,
+                        to: recipient,
+                        deadline,
+                    }
+                    .encode()
+                }
+            }
+            _ => return Err(RouteError::UnsupportedHop(first_hop_of(&segments, false))),
+        },
+
+        RouterTarget::UniswapV3Router(_) => match segments.as_slice() {
+            [Segment::V3 { tokens, fees }] => ExactInputCall {
+                params: ExactInputParams {
+                    path: encode_v3_path(tokens, fees),
+                    recipient,
+                    deadline,
+                    amount_in,
+                    amount_out_minimum: min_out,
+                },
+            }
+            .encode(),
+            _ => return Err(RouteError::UnsupportedHop(first_hop_of(&segments, true))),
+        },
+
+        RouterTarget::UniversalRouter(_) => {
+            let mut commands = vec![];
+            let mut inputs = vec![];
+
+            for (idx, segment) in segments.iter().enumerate() {
+                let first = idx == 0;
+                let last = idx == segments.len() - 1;
+
+                //Later segments swap the whole balance the previous one left in the router
+                let segment_recipient = if last { recipient } else { ADDRESS_THIS };
+                let segment_amount_in = if first { amount_in } else { U256::one() << 255 };
+                let segment_min_out = if last { min_out } else { U256::zero() };
+
+                let (command, path) = match segment {
+                    Segment::V2 { tokens, .. } => (
+                        V2_SWAP_EXACT_IN,
+                        Token::Array(tokens.iter().map(|token| Token::Address(*token)).collect()),
+                    ),
+                    Segment::V3 { tokens, fees } => (
+                        V3_SWAP_EXACT_IN,
+                        Token::Bytes(encode_v3_path(tokens, fees).to_vec()),
+                    ),
+                };
+
+                commands.push(command);
+                inputs.push(Bytes::from(ethers::abi::encode(&[
+                    Token::Address(segment_recipient),
+                    Token::Uint(segment_amount_in),
+                    Token::Uint(segment_min_out),
+                    path,
+                    Token::Bool(first),
+                ])));
+            }
+
+            ExecuteCall {
+                commands: commands.into(),
+                inputs,
+                deadline,
+            }
+            .encode()
+        }
+    };
+
+    Ok((target.address(), calldata.into(), U256::zero()))
+}
+
+/// Packs `tokens` and the `fees` of the pools between them into the path of a V3 multi-hop swap, each token as
+/// 20 bytes followed by the fee of the next pool as 3 bytes.
+pub fn encode_v3_path(tokens: &[H160], fees: &[u32]) -> Bytes {
+    let mut path = Vec::with_capacity(tokens.len() * 20 + fees.len() * 3);
+    for (idx, token) in tokens.iter().enumerate() {
+        path.extend_from_slice(token.as_bytes());
+        if let Some(fee) = fees.get(idx) {
+            path.extend_from_slice(&fee.to_be_bytes()[1..]);
+        }
+    }
+
+    path.into()
+}
+
+/// Unpacks the tokens and fees of a V3 path, None if the path is malformed
+pub fn decode_v3_path(path: &[u8]) -> Option<(Vec<H160>, Vec<u32>)> {
+    if path.len() < 20 || (path.len() - 20) % 23 != 0 {
+        return None;
+    }
+
+    let mut tokens = vec![H160::from_slice(&path[..20])];
+    let mut fees = vec![];
+    for hop in path[20..].chunks(23) {
+        fees.push(u32::from_be_bytes([0, hop[0], hop[1], hop[2]]));
+        tokens.push(H160::from_slice(&hop[3..]));
+    }
+
+    Some((tokens, fees))
+}
+
+//Run of consecutive hops on the same protocol, swapped by one router call or command
+#[derive(Debug)]
+enum Segment {
+    V2 {
+        tokens: Vec<H160>,
+        transfer_tax: bool,
+    },
+    V3 {
+        tokens: Vec<H160>,
+        fees: Vec<u32>,
+    },
+}
+
+//Splits the route into runs of hops on the same protocol, the first hop that is neither V2 nor V3 is unsupported
+fn segments<K, L>(route: &Route<K>, amms: &L) -> Result<Vec<Segment>, RouteError>
+where
+    K: Copy + PartialEq,
+    L: AmmLookup<K> + ?Sized,
+{
+    let mut segments: Vec<Segment> = vec![];
+    for (hop, (id, token_in)) in route.hops.iter().enumerate() {
+        let token_out = route
+            .hops
+            .get(hop + 1)
+            .map(|(_, token)| *token)
+            .unwrap_or(route.token_out);
+
+        match (amms.get_amm(*id), segments.last_mut()) {
+            (
+                Some(AMM::UniswapV2Pool(pool)),
+                Some(Segment::V2 {
+                    tokens,
+                    transfer_tax,
+                }),
+            ) => {
+                tokens.push(token_out);
+                *transfer_tax |= pool.has_transfer_tax();
+            }
+            (Some(AMM::UniswapV2Pool(pool)), _) => segments.push(Segment::V2 {
+                tokens: vec![*token_in, token_out],
+                transfer_tax: pool.has_transfer_tax(),
+            }),
+            (Some(AMM::UniswapV3Pool(pool)), Some(Segment::V3 { tokens, fees })) => {
+                tokens.push(token_out);
+                fees.push(pool.fee);
+            }
+            (Some(AMM::UniswapV3Pool(pool)), _) => segments.push(Segment::V3 {
+                tokens: vec![*token_in, token_out],
+                fees: vec![pool.fee],
+            }),
+            (Some(_), _) => return Err(RouteError::UnsupportedHop(hop)),
+            (None, _) => return Err(RouteError::AmmNotFound(hop)),
+        }
+    }
+
+    Ok(segments)
+}
+
+//Index of the first hop of the first segment not on the protocol of the router, V3 when `v3`
+fn first_hop_of(segments: &[Segment], v3: bool) -> usize {
+    let mut hop = 0;
+    for segment in segments.iter() {
+        match segment {
+            Segment::V2 { .. } if v3 => return hop,
+            Segment::V3 { .. } if !v3 => return hop,
+            Segment::V2 { tokens, .. } | Segment::V3 { tokens, .. } => hop += tokens.len() - 1,
+        }
+    }
+
+    hop
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::{
+        abi::{AbiDecode, ParamType, Token},
+        types::{H160, U256},
+    };
+
+    use crate::{
+        amm::{
+            erc_4626::ERC4626Vault, known_factories::ETHEREUM, uniswap_v2::UniswapV2Pool,
+            uniswap_v3::UniswapV3Pool, AMM,
+        },
+        errors::RouteError,
+        routing::Route,
+    };
+
+    use super::{
+        decode_v3_path, encode_route, encode_v3_path, ExactInputCall, ExecuteCall, RouterTarget,
+        SwapExactTokensForTokensCall, ADDRESS_THIS, V2_SWAP_EXACT_IN, V3_SWAP_EXACT_IN,
+    };
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn v2_pool(token_a: u64, token_b: u64) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            token_a: token(token_a),
+            token_b: token(token_b),
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    fn v3_pool(token_a: u64, token_b: u64, fee: u32) -> AMM {
+        AMM::UniswapV3Pool(UniswapV3Pool {
+            token_a: token(token_a),
+            token_b: token(token_b),
+            fee,
+            ..Default::default()
+        })
+    }
+
+    fn router() -> H160 {
+        token(0xdead)
+    }
+
+    #[test]
+    fn test_v3_path_round_trip() {
+        let tokens = vec![token(1), token(2), token(3)];
+        let fees = vec![500, 10_000];
+
+        let path = encode_v3_path(&tokens, &fees);
+        assert_eq!(path.len(), 66);
+        assert_eq!(&path[20..23], &[0x00, 0x01, 0xf4]);
+        assert_eq!(decode_v3_path(&path), Some((tokens, fees)));
+
+        assert_eq!(decode_v3_path(&path[..65]), None);
+        assert_eq!(decode_v3_path(&[]), None);
+    }
+
+    #[test]
+    fn test_encode_v2_router() -> eyre::Result<()> {
+        let amms = vec![v2_pool(1, 2), v2_pool(3, 2), v2_pool(3, 4)];
+        let route = Route::new(
+            vec![(0, token(1)), (1, token(2)), (2, token(3))],
+            token(4),
+            &amms,
+        )?;
+
+        let (to, calldata, value) = encode_route(
+            &route,
+            &amms,
+            U256::exp10(18),
+            U256::from(990),
+            token(7),
+            U256::from(1_700_000_000),
+            RouterTarget::UniswapV2Router(router()),
+        )?;
+        assert_eq!(to, router());
+        assert!(value.is_zero());
+
+        let call = SwapExactTokensForTokensCall::decode(&calldata)?;
+        assert_eq!(call.amount_in, U256::exp10(18));
+        assert_eq!(call.amount_out_min, U256::from(990));
+        assert_eq!(call.path, vec![token(1), token(2), token(3), token(4)]);
+        assert_eq!(call.to, token(7));
+        assert_eq!(call.deadline, U256::from(1_700_000_000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_v3_router() -> eyre::Result<()> {
+        let amms = vec![v3_pool(1, 2, 500), v3_pool(2, 3, 3000)];
+        let route = Route::new(vec![(0, token(1)), (1, token(2))], token(3), &amms)?;
+
+        let (_, calldata, _) = encode_route(
+            &route,
+            &amms,
+            U256::from(1_000_000),
+            U256::one(),
+            token(7),
+            U256::MAX,
+            RouterTarget::UniswapV3Router(router()),
+        )?;
+
+        let call = ExactInputCall::decode(&calldata)?;
+        assert_eq!(call.params.recipient, token(7));
+        assert_eq!(call.params.deadline, U256::MAX);
+        assert_eq!(call.params.amount_in, U256::from(1_000_000));
+        assert_eq!(call.params.amount_out_minimum, U256::one());
+        assert_eq!(
+            decode_v3_path(&call.params.path),
+            Some((vec![token(1), token(2), token(3)], vec![500, 3000]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_universal_router() -> eyre::Result<()> {
+        let amms = vec![v2_pool(1, 2), v2_pool(2, 3), v3_pool(3, 4, 100)];
+        let route = Route::new(
+            vec![(0, token(1)), (1, token(2)), (2, token(3))],
+            token(4),
+            &amms,
+        )?;
+
+        let (_, calldata, _) = encode_route(
+            &route,
+            &amms,
+            U256::exp10(18),
+            U256::from(990),
+            token(7),
+            U256::from(1_700_000_000),
+            RouterTarget::UniversalRouter(router()),
+        )?;
+
+        let call = ExecuteCall::decode(&calldata)?;
+        assert_eq!(
+            call.commands.to_vec(),
+            vec![V2_SWAP_EXACT_IN, V3_SWAP_EXACT_IN]
+        );
+        assert_eq!(call.deadline, U256::from(1_700_000_000));
+
+        //The V2 run is paid by the user and leaves its output in the router
+        let v2_input = ethers::abi::decode(
+            &[
+                ParamType::Address,
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Array(Box::new(ParamType::Address)),
+                ParamType::Bool,
+            ],
+            &call.inputs[0],
+        )?;
+        assert_eq!(
+            v2_input,
+            vec![
+                Token::Address(ADDRESS_THIS),
+                Token::Uint(U256::exp10(18)),
+                Token::Uint(U256::zero()),
+                Token::Array(vec![
+                    Token::Address(token(1)),
+                    Token::Address(token(2)),
+                    Token::Address(token(3)),
+                ]),
+                Token::Bool(true),
+            ]
+        );
+
+        //The V3 run swaps the whole balance of the router to the recipient
+        let v3_input = ethers::abi::decode(
+            &[
+                ParamType::Address,
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Bytes,
+                ParamType::Bool,
+            ],
+            &call.inputs[1],
+        )?;
+        assert_eq!(v3_input[0], Token::Address(token(7)));
+        assert_eq!(v3_input[1], Token::Uint(U256::one() << 255));
+        assert_eq!(v3_input[2], Token::Uint(U256::from(990)));
+        assert_eq!(v3_input[4], Token::Bool(false));
+        match &v3_input[3] {
+            Token::Bytes(path) => assert_eq!(
+                decode_v3_path(path),
+                Some((vec![token(3), token(4)], vec![100]))
+            ),
+            other => panic!("Unexpected path {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_hops() -> eyre::Result<()> {
+        let mut amms = vec![v2_pool(1, 2), v3_pool(2, 3, 500)];
+        amms.push(AMM::ERC4626Vault(ERC4626Vault {
+            vault_token: token(4),
+            asset_token: token(3),
+            ..Default::default()
+        }));
+        let route = Route::new(vec![(0, token(1)), (1, token(2))], token(3), &amms)?;
+
+        let encode = |route: &Route, target| {
+            encode_route(
+                route,
+                &amms,
+                U256::one(),
+                U256::zero(),
+                token(7),
+                U256::MAX,
+                target,
+            )
+        };
+
+        assert!(matches!(
+            encode(&route, RouterTarget::UniswapV2Router(router())),
+            Err(RouteError::UnsupportedHop(1))
+        ));
+        assert!(matches!(
+            encode(&route, RouterTarget::UniswapV3Router(router())),
+            Err(RouteError::UnsupportedHop(0))
+        ));
+
+        let route = Route::new(
+            vec![(0, token(1)), (1, token(2)), (2, token(3))],
+            token(4),
+            &amms,
+        )?;
+        assert!(matches!(
+            encode(&route, RouterTarget::UniversalRouter(router())),
+            Err(RouteError::UnsupportedHop(2))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_routers() -> eyre::Result<()> {
+        assert_eq!(
+            RouterTarget::uniswap_v2_router(ETHEREUM),
+            Some(RouterTarget::UniswapV2Router(H160::from_str(
+                "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D"
+            )?))
+        );
+        assert!(RouterTarget::uniswap_v3_router(ETHEREUM).is_some());
+        assert!(RouterTarget::universal_router(ETHEREUM).is_some());
+        assert_eq!(RouterTarget::universal_router(0), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use ethers::types::{H160, U256};
+
+use crate::{
+    amm::{AmmState, AMM},
+    filters::dedupe::Protocol,
+};
+
+/// Gas of a swap on a protocol missing from `per_protocol_overhead`
+pub const DEFAULT_SWAP_GAS: u64 = 150_000;
+
+/// Prices the gas of the swaps of a route or split order, so they can be scored on their output net of gas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasCostModel {
+    pub gas_price_wei: U256,
+    /// Token gas is paid in, usually the wrapped native token, gas costs are converted from it into the token out
+    pub native_token: H160,
+    /// Gas of each swap on an AMM of the protocol
+    pub per_protocol_overhead: HashMap<Protocol, u64>,
+}
+
+impl GasCostModel {
+    /// Model with typical swap gas for each protocol, adjust them with `with_overhead`
+    pub fn new(gas_price_wei: U256, native_token: H160) -> Self {
+        GasCostModel {
+            gas_price_wei,
+            native_token,
+            per_protocol_overhead: HashMap::from([
+                (Protocol::UniswapV2, 60_000),
+                (Protocol::UniswapV3, 100_000),
+                (Protocol::ERC4626, 80_000),
+                (Protocol::CurveStableSwap, 130_000),
+                (Protocol::BalancerWeighted, 110_000),
+                (Protocol::Velodrome, 100_000),
+                (Protocol::UniswapV4, 90_000),
+                (Protocol::LiquidityBook, 120_000),
+                (Protocol::Maverick, 120_000),
+                (Protocol::Dodo, 100_000),
+                (Protocol::KyberElastic, 110_000),
+                (Protocol::Algebra, 110_000),
+                (Protocol::CurveCrypto, 180_000),
+                (Protocol::BancorV3, 150_000),
+            ]),
+        }
+    }
+
+    pub fn with_overhead(mut self, protocol: Protocol, gas: u64) -> Self {
+        self.per_protocol_overhead.insert(protocol, gas);
+        self
+    }
+
+    /// Gas of a swap on `amm`
+    pub fn swap_gas(&self, amm: &AMM) -> u64 {
+        self.per_protocol_overhead
+            .get(&Protocol::of(amm))
+            .copied()
+            .unwrap_or(DEFAULT_SWAP_GAS)
+    }
+
+    /// Cost of `gas` in the native token
+    pub fn native_cost(&self, gas: u64) -> U256 {
+        self.gas_price_wei.saturating_mul(U256::from(gas))
+    }
+
+    /// Cost of `gas` in `token`, sold on the pool of `pools` paying the most `token` for the native token.
+    /// None when no pool trades the native token for `token`.
+    pub fn cost_in<'a>(
+        &self,
+        gas: u64,
+        token: H160,
+        pools: impl IntoIterator<Item = &'a AMM>,
+    ) -> Option<U256> {
+        let native_cost = self.native_cost(gas);
+        if token == self.native_token || native_cost.is_zero() {
+            return Some(native_cost);
+        }
+
+        pools
+            .into_iter()
+            .filter_map(|amm| {
+                amm.simulate_swap_to(self.native_token, token, native_cost)
+                    .ok()
+            })
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AMM},
+        filters::dedupe::Protocol,
+    };
+
+    use super::{GasCostModel, DEFAULT_SWAP_GAS};
+
+    #[test]
+    fn test_gas_cost() {
+        let (weth, usdc, dai) = (
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(3),
+        );
+        let pools = vec![
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                token_a: usdc,
+                token_b: weth,
+                reserve_0: 20_000_000 * 10_u128.pow(6),
+                reserve_1: 10_000 * 10_u128.pow(18),
+                fee: 300,
+                ..Default::default()
+            }),
+            AMM::ERC4626Vault(Default::default()),
+        ];
+
+        let model =
+            GasCostModel::new(U256::exp10(10), weth).with_overhead(Protocol::UniswapV2, 125_000);
+        assert_eq!(model.swap_gas(&pools[0]), 125_000);
+        assert_eq!(model.swap_gas(&pools[1]), 80_000);
+
+        //200k gas at 10 gwei is 0.002 WETH, about 4 USDC
+        assert_eq!(
+            model.cost_in(200_000, weth, &pools),
+            Some(U256::exp10(15) * U256::from(2))
+        );
+        let cost = model.cost_in(200_000, usdc, &pools).unwrap();
+        assert!(cost > U256::from(3_980_000) && cost < U256::from(4_000_000));
+        assert_eq!(model.cost_in(200_000, dai, &pools), None);
+
+        let mut model = model;
+        model.per_protocol_overhead.clear();
+        assert_eq!(model.swap_gas(&pools[0]), DEFAULT_SWAP_GAS);
+    }
+}
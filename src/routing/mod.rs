@@ -0,0 +1,996 @@
+pub mod arbitrage;
+/// Requires the `rpc` feature
+#[cfg(feature = "rpc")]
+pub mod cache;
+pub mod encode;
+pub mod gas;
+pub mod optimize;
+pub mod replay;
+pub mod search;
+pub mod split;
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::{RouteError, SwapSimulationError},
+    math::fixed_point::u256_to_f64_lossy,
+    state_space::state::StateSpace,
+};
+
+#[cfg(feature = "rpc")]
+pub use self::cache::{RouteCache, RouteRequest};
+pub use self::{
+    arbitrage::{find_arbitrage_cycles, ArbitrageCycle},
+    encode::{encode_route, RouterTarget},
+    gas::GasCostModel,
+    optimize::{optimize_input, OptimalTrade, TradeBounds},
+    replay::{replay, PoolSnapshot, QuoteContext},
+    search::{find_best_route, RouteConstraints, RouteQuote},
+    split::{split_order, SplitOrder},
+};
+
+/// Index of the AMMs connecting each pair of tokens, for routing and arbitrage path finding.
+///
+/// AMMs are identified by `K`, their index in the slice the graph was built from or their address when
+/// built from a state space. AMMs holding more than two tokens connect every pair of their tokens.
+#[derive(Debug, Clone)]
+pub struct TokenGraph<K = usize> {
+    //token -> neighbor token -> AMMs trading the pair
+    adjacency: HashMap<H160, HashMap<H160, Vec<K>>>,
+    //Tokens of each AMM in the graph, so AMMs can be removed without the AMM itself
+    amm_tokens: HashMap<K, Vec<H160>>,
+}
+
+impl<K> Default for TokenGraph<K> {
+    fn default() -> Self {
+        TokenGraph {
+            adjacency: HashMap::new(),
+            amm_tokens: HashMap::new(),
+        }
+    }
+}
+
+impl TokenGraph<usize> {
+    /// Builds the graph of `amms`, identifying each AMM by its index in the slice.
+    pub fn new(amms: &[AMM]) -> Self {
+        let mut graph = TokenGraph::default();
+        for (idx, amm) in amms.iter().enumerate() {
+            graph.insert(idx, amm);
+        }
+
+        graph
+    }
+}
+
+impl TokenGraph<H160> {
+    /// Builds the graph of a state space, identifying each AMM by its address.
+    /// AMMs added to the state space later can be inserted with the addresses returned by `add_amms`.
+    pub fn from_state_space(state: &StateSpace) -> Self {
+        let mut graph = TokenGraph::default();
+        for (address, amm) in state {
+            graph.insert(*address, amm);
+        }
+
+        graph
+    }
+}
+
+impl<K: Copy + Eq + Hash> TokenGraph<K> {
+    /// Adds `amm` under `id`, replacing the AMM previously inserted under the same id.
+    pub fn insert(&mut self, id: K, amm: &AMM) {
+        self.remove(id);
+
+        let mut tokens = amm.tokens();
+        tokens.sort_unstable();
+        tokens.dedup();
+
+        for token in tokens.iter() {
+            let neighbors = self.adjacency.entry(*token).or_default();
+            for neighbor in tokens.iter().filter(|neighbor| *neighbor != token) {
+                neighbors.entry(*neighbor).or_default().push(id);
+            }
+        }
+
+        self.amm_tokens.insert(id, tokens);
+    }
+
+    /// Removes the AMM inserted under `id`, returning whether it was in the graph.
+    pub fn remove(&mut self, id: K) -> bool {
+        let tokens = match self.amm_tokens.remove(&id) {
+            Some(tokens) => tokens,
+            None => return false,
+        };
+
+        for token in tokens.iter() {
+            if let Some(neighbors) = self.adjacency.get_mut(token) {
+                for neighbor in tokens.iter().filter(|neighbor| *neighbor != token) {
+                    if let Some(amms) = neighbors.get_mut(neighbor) {
+                        amms.retain(|amm| *amm != id);
+                        if amms.is_empty() {
+                            neighbors.remove(neighbor);
+                        }
+                    }
+                }
+
+                if neighbors.is_empty() {
+                    self.adjacency.remove(token);
+                }
+            }
+        }
+
+        true
+    }
+
+    pub fn contains(&self, id: K) -> bool {
+        self.amm_tokens.contains_key(&id)
+    }
+
+    /// Number of AMMs in the graph
+    pub fn len(&self) -> usize {
+        self.amm_tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.amm_tokens.is_empty()
+    }
+
+    /// AMMs trading `token_a` for `token_b`, in the order they were inserted.
+    pub fn pools_for_pair(&self, token_a: H160, token_b: H160) -> &[K] {
+        self.adjacency
+            .get(&token_a)
+            .and_then(|neighbors| neighbors.get(&token_b))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Tokens that can be reached from `token` through a single AMM.
+    pub fn neighbors(&self, token: H160) -> impl Iterator<Item = H160> + '_ {
+        self.adjacency
+            .get(&token)
+            .into_iter()
+            .flat_map(|neighbors| neighbors.keys().copied())
+    }
+
+    /// Every route from `token_in` to `token_out` through at most `max_hops` AMMs, as the AMMs of each hop.
+    ///
+    /// Routes do not pass through a token more than once and are returned in no particular order.
+    /// The number of routes grows quickly with `max_hops` in well connected graphs.
+    pub fn paths(&self, token_in: H160, token_out: H160, max_hops: usize) -> Vec<Vec<K>> {
+        let mut paths = vec![];
+        if token_in == token_out || max_hops == 0 {
+            return paths;
+        }
+
+        let mut visited = HashSet::from([token_in]);
+        let mut path = vec![];
+        self.extend_paths(
+            token_in,
+            token_out,
+            max_hops,
+            &mut visited,
+            &mut path,
+            &mut paths,
+        );
+
+        paths
+    }
+
+    //Depth first search from `token`, pushing a copy of `path` for every AMM reaching `token_out`
+    fn extend_paths(
+        &self,
+        token: H160,
+        token_out: H160,
+        hops_left: usize,
+        visited: &mut HashSet<H160>,
+        path: &mut Vec<K>,
+        paths: &mut Vec<Vec<K>>,
+    ) {
+        let neighbors = match self.adjacency.get(&token) {
+            Some(neighbors) => neighbors,
+            None => return,
+        };
+
+        for (neighbor, amms) in neighbors {
+            if *neighbor == token_out {
+                for amm in amms {
+                    path.push(*amm);
+                    paths.push(path.clone());
+                    path.pop();
+                }
+            } else if hops_left > 1 && visited.insert(*neighbor) {
+                for amm in amms {
+                    path.push(*amm);
+                    self.extend_paths(*neighbor, token_out, hops_left - 1, visited, path, paths);
+                    path.pop();
+                }
+
+                visited.remove(neighbor);
+            }
+        }
+    }
+}
+
+/// AMMs a route or graph can refer to by `K`.
+pub trait AmmLookup<K> {
+    fn get_amm(&self, id: K) -> Option<&AMM>;
+}
+
+impl AmmLookup<usize> for [AMM] {
+    fn get_amm(&self, id: usize) -> Option<&AMM> {
+        self.get(id)
+    }
+}
+
+impl AmmLookup<usize> for Vec<AMM> {
+    fn get_amm(&self, id: usize) -> Option<&AMM> {
+        self.get(id)
+    }
+}
+
+impl AmmLookup<H160> for StateSpace {
+    fn get_amm(&self, id: H160) -> Option<&AMM> {
+        self.get(&id)
+    }
+}
+
+/// Swap path through one or more AMMs, each hop being the AMM and the token sold to it.
+///
+/// The AMMs themselves are not held by the route, they are looked up by `K` when simulating, so a route
+/// can be kept and reused while the AMMs are synced.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Route<K = usize> {
+    hops: Vec<(K, H160)>,
+    token_out: H160,
+}
+
+impl<K: Copy + PartialEq> Route<K> {
+    /// Builds a route selling `hops[0].1` and buying `token_out`, checking that each AMM
+    /// swaps the token in of its hop for the token in of the next hop.
+    pub fn new<L: AmmLookup<K> + ?Sized>(
+        hops: Vec<(K, H160)>,
+        token_out: H160,
+        amms: &L,
+    ) -> Result<Self, RouteError> {
+        let route = Route { hops, token_out };
+        route.validate(amms)?;
+
+        Ok(route)
+    }
+
+    /// Checks the route against `amms`, for routes that were deserialized or whose AMMs may have changed.
+    pub fn validate<L: AmmLookup<K> + ?Sized>(&self, amms: &L) -> Result<(), RouteError> {
+        if self.hops.is_empty() {
+            return Err(RouteError::EmptyRoute);
+        }
+
+        for (hop, (id, token_in)) in self.hops.iter().enumerate() {
+            let amm = amms.get_amm(*id).ok_or(RouteError::AmmNotFound(hop))?;
+            let token_out = self.hop_token_out(hop);
+
+            if *token_in == token_out || !amm.other_tokens(*token_in).contains(&token_out) {
+                return Err(RouteError::InvalidHop {
+                    hop,
+                    token_in: *token_in,
+                    token_out,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn hops(&self) -> &[(K, H160)] {
+        &self.hops
+    }
+
+    pub fn token_in(&self) -> H160 {
+        self.hops[0].1
+    }
+
+    pub fn token_out(&self) -> H160 {
+        self.token_out
+    }
+
+    //Token bought on `hop`, which is sold on the next hop
+    fn hop_token_out(&self, hop: usize) -> H160 {
+        self.hops
+            .get(hop + 1)
+            .map(|(_, token_in)| *token_in)
+            .unwrap_or(self.token_out)
+    }
+
+    /// Amount of `token_out` received for `amount_in` of `token_in` through every hop.
+    ///
+    /// AMMs used by more than one hop are simulated on a copy, so later hops see the state left by earlier ones.
+    pub fn simulate<L: AmmLookup<K> + ?Sized>(
+        &self,
+        amount_in: U256,
+        amms: &L,
+    ) -> Result<U256, SwapSimulationError> {
+        self.swap_through(amount_in, amms, |_, _, _, _| Ok(()))
+    }
+
+    /// `simulate`, failing like a router when the output of the last hop is below `min_out`, with
+    /// `SwapSimulationError::RouteHop` of the last hop wrapping `SwapSimulationError::InsufficientOutput`
+    pub fn simulate_checked<L: AmmLookup<K> + ?Sized>(
+        &self,
+        amount_in: U256,
+        min_out: U256,
+        amms: &L,
+    ) -> Result<U256, SwapSimulationError> {
+        let amount_out = self.simulate(amount_in, amms)?;
+
+        SwapSimulationError::check_output(amount_out, min_out).map_err(|source| {
+            SwapSimulationError::RouteHop {
+                hop: self.hops.len() - 1,
+                source: Box::new(source),
+            }
+        })
+    }
+
+    /// Derivative of the output of `simulate` with respect to `amount_in`, the product of the gradient of every hop
+    /// at the amount reaching it.
+    pub fn gradient_f64<L: AmmLookup<K> + ?Sized>(
+        &self,
+        amount_in: U256,
+        amms: &L,
+    ) -> Result<f64, SwapSimulationError> {
+        let mut gradient = 1.0;
+        self.swap_through(amount_in, amms, |amm, token_in, token_out, amount| {
+            gradient *= amm.gradient_to(token_in, token_out, amount)?.to_f64();
+            Ok(())
+        })?;
+
+        Ok(gradient)
+    }
+
+    //Swaps `amount_in` through every hop, calling `visit` with the AMM, tokens and amount of each hop before it swaps
+    fn swap_through<L, F>(
+        &self,
+        amount_in: U256,
+        amms: &L,
+        mut visit: F,
+    ) -> Result<U256, SwapSimulationError>
+    where
+        L: AmmLookup<K> + ?Sized,
+        F: FnMut(&AMM, H160, H160, U256) -> Result<(), SwapSimulationError>,
+    {
+        //Copies of the AMMs reused later in the route, updated by each hop through them
+        let mut touched: Vec<(K, AMM)> = vec![];
+        let mut amount = amount_in;
+
+        for (hop, (id, token_in)) in self.hops.iter().enumerate() {
+            let token_out = self.hop_token_out(hop);
+            if let Some((_, amm)) = touched.iter_mut().find(|(touched, _)| touched == id) {
+                visit(amm, *token_in, token_out, amount)?;
+                amount = amm.simulate_swap_to_mut(*token_in, token_out, amount)?;
+                continue;
+            }
+
+            let amm = amms
+                .get_amm(*id)
+                .ok_or(SwapSimulationError::AmmNotFound(hop))?;
+            visit(amm, *token_in, token_out, amount)?;
+
+            if self.hops[hop + 1..].iter().any(|(next, _)| next == id) {
+                let mut amm = amm.clone();
+                amount = amm.simulate_swap_to_mut(*token_in, token_out, amount)?;
+                touched.push((*id, amm));
+            } else {
+                amount = amm.simulate_swap_to(*token_in, token_out, amount)?;
+            }
+        }
+
+        Ok(amount)
+    }
+
+    /// Spot price of `token_in` in `token_out`, as the product of the price of every hop.
+    pub fn price<L: AmmLookup<K> + ?Sized>(&self, amms: &L) -> Result<f64, RouteError> {
+        self.hops
+            .iter()
+            .enumerate()
+            .try_fold(1.0, |price, (hop, (id, token_in))| {
+                let amm = amms.get_amm(*id).ok_or(RouteError::AmmNotFound(hop))?;
+                Ok(price * amm.calculate_price(*token_in)?)
+            })
+    }
+
+    /// Share of the output lost to the size of `amount_in`, compared to swapping at the marginal rate of every hop.
+    ///
+    /// The marginal rates are net of fees, so fees do not count as price impact.
+    pub fn price_impact<L: AmmLookup<K> + ?Sized>(
+        &self,
+        amount_in: U256,
+        amms: &L,
+    ) -> Result<f64, RouteError> {
+        if amount_in.is_zero() {
+            return Ok(0.0);
+        }
+
+        let mut marginal_rate = 1.0;
+        for (hop, (id, token_in)) in self.hops.iter().enumerate() {
+            let amm = amms.get_amm(*id).ok_or(RouteError::AmmNotFound(hop))?;
+            marginal_rate *= amm
+                .gradient_to(*token_in, self.hop_token_out(hop), U256::zero())?
+                .to_f64();
+        }
+
+        if marginal_rate == 0.0 {
+            return Ok(1.0);
+        }
+
+        let amount_out = self.simulate(amount_in, amms)?;
+        let rate = u256_to_f64_lossy(amount_out) / u256_to_f64_lossy(amount_in);
+
+        Ok(1.0 - rate / marginal_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, str::FromStr, sync::Arc};
+
+    use ethers::{
+        prelude::abigen,
+        providers::{Http, Middleware, Provider},
+        types::{Log, H160, H256, U256},
+    };
+
+    use crate::{
+        amm::{
+            custom::{CustomAMM, CustomAutomatedMarketMaker},
+            uniswap_v2::{IUniswapV2Pair, UniswapV2Pool},
+            AmmState, AMM,
+        },
+        errors::{ArithmeticError, EventLogError, RouteError, SwapSimulationError},
+        math::Q128x128,
+        state_space::state::initialize_state_space,
+    };
+
+    use super::{find_best_route, Route, RouteConstraints, TokenGraph};
+
+    abigen!(
+        IUniswapV2Router,
+        r#"[
+        function getAmountsOut(uint amountIn, address[] memory path) external view returns (uint[] memory amounts)
+    ]"#;);
+
+    //Stands in for a Curve or Balancer pool holding more than two tokens, swapping them one for one less a 4 bps fee
+    #[derive(Debug, Clone)]
+    struct MultiTokenPool {
+        address: H160,
+        tokens: Vec<H160>,
+        balances: Vec<U256>,
+    }
+
+    impl MultiTokenPool {
+        fn new(address: u64, tokens: &[u64], balance: U256) -> Self {
+            MultiTokenPool {
+                address: H160::from_low_u64_be(address),
+                tokens: tokens.iter().map(|id| token(*id)).collect(),
+                balances: vec![balance; tokens.len()],
+            }
+        }
+
+        fn index_of(&self, token: H160) -> Result<usize, SwapSimulationError> {
+            self.tokens
+                .iter()
+                .position(|held| *held == token)
+                .ok_or(SwapSimulationError::TokenNotInPool(token))
+        }
+    }
+
+    impl AmmState for MultiTokenPool {
+        fn address(&self) -> H160 {
+            self.address
+        }
+
+        fn sync_on_event_signatures(&self) -> Vec<H256> {
+            vec![]
+        }
+
+        fn tokens(&self) -> Vec<H160> {
+            self.tokens.clone()
+        }
+
+        fn calculate_price(&self, _base_token: H160) -> Result<f64, ArithmeticError> {
+            Ok(1.0)
+        }
+
+        fn sync_from_log(&mut self, log: &Log) -> Result<(), EventLogError> {
+            Err(EventLogError::UnexpectedEvent(log.into()))
+        }
+
+        fn simulate_swap(
+            &self,
+            token_in: H160,
+            _amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            Err(SwapSimulationError::TokenOutRequired(token_in))
+        }
+
+        fn simulate_swap_mut(
+            &mut self,
+            token_in: H160,
+            _amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            Err(SwapSimulationError::TokenOutRequired(token_in))
+        }
+
+        fn simulate_swap_to(
+            &self,
+            token_in: H160,
+            token_out: H160,
+            amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            self.clone()
+                .simulate_swap_to_mut(token_in, token_out, amount_in)
+        }
+
+        fn simulate_swap_to_mut(
+            &mut self,
+            token_in: H160,
+            token_out: H160,
+            amount_in: U256,
+        ) -> Result<U256, SwapSimulationError> {
+            let (idx_in, idx_out) = (self.index_of(token_in)?, self.index_of(token_out)?);
+            let amount_out =
+                (amount_in * U256::from(9996) / U256::from(10000)).min(self.balances[idx_out]);
+
+            self.balances[idx_in] += amount_in;
+            self.balances[idx_out] -= amount_out;
+            Ok(amount_out)
+        }
+
+        fn gradient_to(
+            &self,
+            token_in: H160,
+            token_out: H160,
+            _amount_in: U256,
+        ) -> Result<Q128x128, SwapSimulationError> {
+            self.index_of(token_in)?;
+            self.index_of(token_out)?;
+
+            Q128x128::from_ratio(U256::from(9996), U256::from(10000))
+                .ok_or(SwapSimulationError::GradientOverflow)
+        }
+
+        fn get_token_out(&self, token_in: H160) -> H160 {
+            self.tokens
+                .iter()
+                .copied()
+                .find(|token| *token != token_in)
+                .unwrap_or_default()
+        }
+
+        fn opp_token(&self, _token: H160) -> Option<H160> {
+            None
+        }
+    }
+
+    impl CustomAutomatedMarketMaker for MultiTokenPool {
+        fn protocol(&self) -> &'static str {
+            "multi_token"
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomAutomatedMarketMaker> {
+            Box::new(self.clone())
+        }
+
+        fn serialize_state(&self) -> Result<String, serde_json::Error> {
+            Ok(String::new())
+        }
+    }
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn pool(address: u64, token_a: u64, token_b: u64) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: token(token_a),
+            token_b: token(token_b),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_token_graph() {
+        let amms = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 1, 2),
+            AMM::Custom(CustomAMM::new(MultiTokenPool::new(
+                103,
+                &[3, 4, 5],
+                U256::zero(),
+            ))),
+        ];
+
+        let graph = TokenGraph::new(&amms);
+        assert_eq!(graph.len(), 4);
+
+        assert_eq!(graph.pools_for_pair(token(1), token(2)), &[0, 2]);
+        assert_eq!(graph.pools_for_pair(token(2), token(1)), &[0, 2]);
+        assert!(graph.pools_for_pair(token(1), token(3)).is_empty());
+
+        //Every pair of the multi token pool is connected
+        assert_eq!(graph.pools_for_pair(token(4), token(5)), &[3]);
+        assert_eq!(
+            graph.neighbors(token(3)).collect::<HashSet<_>>(),
+            HashSet::from([token(2), token(4), token(5)])
+        );
+        assert_eq!(graph.neighbors(token(6)).count(), 0);
+    }
+
+    #[test]
+    fn test_paths() {
+        let amms = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 1, 2),
+            pool(103, 1, 3),
+            pool(104, 3, 4),
+        ];
+        let graph = TokenGraph::new(&amms);
+
+        let paths = |max_hops| {
+            graph
+                .paths(token(1), token(3), max_hops)
+                .into_iter()
+                .collect::<HashSet<_>>()
+        };
+
+        assert_eq!(paths(1), HashSet::from([vec![3]]));
+        assert_eq!(paths(2), HashSet::from([vec![3], vec![0, 1], vec![2, 1]]));
+        //Routes never revisit a token, so more hops do not add cycles through 1 or 3
+        assert_eq!(paths(5), paths(2));
+
+        assert_eq!(graph.paths(token(1), token(4), 2), vec![vec![3, 4]]);
+        assert!(graph.paths(token(1), token(1), 3).is_empty());
+        assert!(graph.paths(token(1), token(3), 0).is_empty());
+    }
+
+    #[test]
+    fn test_incremental_updates() {
+        let state = initialize_state_space(vec![pool(100, 1, 2), pool(101, 2, 3)]);
+        let mut graph = TokenGraph::from_state_space(&state);
+
+        let (pool_12, pool_23) = (H160::from_low_u64_be(100), H160::from_low_u64_be(101));
+        assert_eq!(
+            graph.paths(token(1), token(3), 2),
+            vec![vec![pool_12, pool_23]]
+        );
+
+        let pool_13 = pool(102, 1, 3);
+        graph.insert(pool_13.address(), &pool_13);
+        assert_eq!(
+            graph.paths(token(1), token(3), 1),
+            vec![vec![pool_13.address()]]
+        );
+
+        assert!(graph.remove(pool_23));
+        assert!(!graph.remove(pool_23));
+        assert!(!graph.contains(pool_23));
+        assert!(graph.pools_for_pair(token(2), token(3)).is_empty());
+        assert_eq!(
+            graph.neighbors(token(2)).collect::<Vec<_>>(),
+            vec![token(1)]
+        );
+
+        //Reinserting an AMM under the same id replaces its pairs
+        graph.insert(pool_12, &pool(100, 1, 4));
+        assert!(graph.pools_for_pair(token(1), token(2)).is_empty());
+        assert_eq!(graph.neighbors(token(2)).count(), 0);
+        assert_eq!(graph.pools_for_pair(token(4), token(1)), &[pool_12]);
+        assert_eq!(graph.len(), 2);
+    }
+
+    fn funded_pool(
+        address: u64,
+        token_a: u64,
+        token_b: u64,
+        reserve_0: u128,
+        reserve_1: u128,
+    ) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: token(token_a),
+            token_b: token(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_route_validation() {
+        let amms = vec![pool(100, 1, 2), pool(101, 2, 3)];
+
+        let route = Route::new(vec![(0, token(1)), (1, token(2))], token(3), &amms).unwrap();
+        assert_eq!(route.token_in(), token(1));
+        assert_eq!(route.token_out(), token(3));
+        assert_eq!(route.hops().len(), 2);
+
+        assert!(matches!(
+            Route::new(vec![], token(3), &amms),
+            Err(RouteError::EmptyRoute)
+        ));
+        assert!(matches!(
+            Route::new(vec![(0, token(1)), (2, token(2))], token(3), &amms),
+            Err(RouteError::AmmNotFound(1))
+        ));
+        //The first hop buys token 2, but the second hop sells token 3
+        assert!(matches!(
+            Route::new(vec![(0, token(1)), (1, token(3))], token(2), &amms),
+            Err(RouteError::InvalidHop { hop: 0, .. })
+        ));
+        //The last hop does not buy the route's token out
+        assert!(matches!(
+            Route::new(vec![(0, token(1)), (1, token(2))], token(4), &amms),
+            Err(RouteError::InvalidHop { hop: 1, .. })
+        ));
+        assert!(matches!(
+            Route::new(vec![(0, token(1))], token(1), &amms),
+            Err(RouteError::InvalidHop { hop: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_simulate_four_hops() -> eyre::Result<()> {
+        let amms = vec![
+            funded_pool(100, 1, 2, 10_u128.pow(24), 2 * 10_u128.pow(24)),
+            funded_pool(101, 3, 2, 10_u128.pow(22), 10_u128.pow(24)),
+            funded_pool(102, 3, 4, 10_u128.pow(22), 10_u128.pow(22)),
+            funded_pool(103, 5, 4, 4 * 10_u128.pow(24), 10_u128.pow(22)),
+        ];
+        let route = Route::new(
+            vec![(0, token(1)), (1, token(2)), (2, token(3)), (3, token(4))],
+            token(5),
+            &amms,
+        )?;
+
+        let amount_in = U256::exp10(18);
+        let mut expected = amount_in;
+        for (idx, (_, token_in)) in route.hops().iter().enumerate() {
+            expected = amms[idx].simulate_swap(*token_in, expected)?;
+        }
+        assert_eq!(route.simulate(amount_in, &amms)?, expected);
+
+        let expected_price = amms
+            .iter()
+            .zip(route.hops())
+            .map(|(amm, (_, token_in))| amm.calculate_price(*token_in).unwrap())
+            .product::<f64>();
+        assert!((route.price(&amms)? - expected_price).abs() < 1e-12 * expected_price);
+
+        //Fees are not price impact, small trades have next to none and large trades move every pool
+        assert!(route.price_impact(U256::exp10(12), &amms)?.abs() < 1e-6);
+        let impact = route.price_impact(U256::exp10(23), &amms)?;
+        assert!(impact > 0.01 && impact < 1.0, "{impact}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_checked() -> eyre::Result<()> {
+        let amms = vec![
+            funded_pool(100, 1, 2, 10_u128.pow(24), 2 * 10_u128.pow(24)),
+            funded_pool(101, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+        ];
+        let route = Route::new(vec![(0, token(1)), (1, token(2))], token(3), &amms)?;
+        let amount_in = U256::exp10(18);
+        let amount_out = route.simulate(amount_in, &amms)?;
+
+        assert_eq!(
+            route.simulate_checked(amount_in, amount_out, &amms)?,
+            amount_out
+        );
+
+        //Fails at the last hop, by exactly the missing amount
+        let min_out = amount_out + 7;
+        match route.simulate_checked(amount_in, min_out, &amms) {
+            Err(error @ SwapSimulationError::RouteHop { hop: 1, .. }) => {
+                assert_eq!(error.shortfall(), Some(U256::from(7)));
+            }
+            result => panic!("expected the last hop to fail, got {result:?}"),
+        }
+
+        //A single pool fails the same way as a router would
+        let error = amms[0]
+            .simulate_swap_checked(token(1), amount_in, U256::MAX)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            SwapSimulationError::InsufficientOutput { expected_min, .. } if expected_min == U256::MAX
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_through_multi_token_pool() -> eyre::Result<()> {
+        let stable_pool = MultiTokenPool::new(101, &[2, 3, 4], U256::exp10(24));
+        let state = initialize_state_space(vec![
+            funded_pool(100, 1, 2, 10_u128.pow(22), 10_u128.pow(22)),
+            AMM::Custom(CustomAMM::new(stable_pool.clone())),
+            funded_pool(102, 4, 5, 10_u128.pow(22), 10_u128.pow(22)),
+            //Direct pool, too shallow to compete with the route through the stable pool
+            funded_pool(103, 1, 5, 10_u128.pow(18), 10_u128.pow(18)),
+        ]);
+        let (pool_12, stable, pool_45) = (
+            H160::from_low_u64_be(100),
+            H160::from_low_u64_be(101),
+            H160::from_low_u64_be(102),
+        );
+
+        //The stable pool sells token 2 for token 4, skipping token 3
+        let route = Route::new(
+            vec![(pool_12, token(1)), (stable, token(2)), (pool_45, token(4))],
+            token(5),
+            &state,
+        )?;
+        assert!(matches!(
+            Route::new(
+                vec![(pool_12, token(1)), (stable, token(2))],
+                token(5),
+                &state
+            ),
+            Err(RouteError::InvalidHop { hop: 1, .. })
+        ));
+
+        let amount_in = U256::exp10(20);
+        let mut expected = state[&pool_12].simulate_swap(token(1), amount_in)?;
+        expected = stable_pool.simulate_swap_to(token(2), token(4), expected)?;
+        expected = state[&pool_45].simulate_swap(token(4), expected)?;
+        assert_eq!(route.simulate(amount_in, &state)?, expected);
+
+        //Only the V2 pools move with the amount, the stable pool swaps at its marginal rate
+        let impact = route.price_impact(amount_in, &state)?;
+        assert!(impact > 0.0 && impact < 0.05, "{impact}");
+
+        let graph = TokenGraph::from_state_space(&state);
+        let quotes = find_best_route(
+            &graph,
+            &state,
+            token(1),
+            token(5),
+            amount_in,
+            3,
+            1,
+            None,
+            &RouteConstraints::new(500, 1000),
+        )?;
+        assert_eq!(quotes[0].route, route);
+        assert_eq!(quotes[0].amount_out, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reused_pool_sees_earlier_hops() -> eyre::Result<()> {
+        let amms = vec![
+            funded_pool(100, 1, 2, 10_u128.pow(22), 10_u128.pow(22)),
+            funded_pool(101, 2, 3, 10_u128.pow(22), 10_u128.pow(22)),
+            funded_pool(102, 3, 1, 10_u128.pow(22), 10_u128.pow(22)),
+        ];
+        //Goes around the triangle twice, through every pool twice
+        let route = Route::new(
+            vec![
+                (0, token(1)),
+                (1, token(2)),
+                (2, token(3)),
+                (0, token(1)),
+                (1, token(2)),
+            ],
+            token(3),
+            &amms,
+        )?;
+
+        let amount_in = U256::exp10(21);
+        let mut pools = amms.clone();
+        let mut expected = amount_in;
+        for (id, token_in) in route.hops() {
+            expected = pools[*id].simulate_swap_mut(*token_in, expected)?;
+        }
+
+        let mut stateless = amount_in;
+        for (id, token_in) in route.hops() {
+            stateless = amms[*id].simulate_swap(*token_in, stateless)?;
+        }
+
+        assert_eq!(route.simulate(amount_in, &amms)?, expected);
+        assert!(expected < stateless);
+        //The AMMs themselves are left untouched
+        assert_eq!(route.simulate(amount_in, &amms)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_serde() -> eyre::Result<()> {
+        let state = initialize_state_space(vec![pool(100, 1, 2), pool(101, 2, 3)]);
+        let route = Route::new(
+            vec![
+                (H160::from_low_u64_be(100), token(1)),
+                (H160::from_low_u64_be(101), token(2)),
+            ],
+            token(3),
+            &state,
+        )?;
+
+        let deserialized: Route<H160> = serde_json::from_str(&serde_json::to_string(&route)?)?;
+        assert_eq!(deserialized, route);
+        deserialized.validate(&state)?;
+
+        let state = initialize_state_space(vec![pool(100, 1, 2)]);
+        assert!(matches!(
+            deserialized.validate(&state),
+            Err(RouteError::AmmNotFound(1))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_two_hop_route_matches_router() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let block_number = middleware.get_block_number().await?.as_u64();
+
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")?;
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")?;
+        let dai = H160::from_str("0x6B175474E89094C44Da98b954EedeAC495271d0F")?;
+
+        let mut amms = vec![];
+        for address in [
+            "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc",
+            "0xAE461cA67B15dc8dc81CE7615e0320dA1A9aB8D5",
+        ] {
+            let mut pool =
+                UniswapV2Pool::new_from_address(H160::from_str(address)?, 300, middleware.clone())
+                    .await?;
+            //Populating reads the latest state, pin the reserves to the block the router is quoted at
+            let (reserve_0, reserve_1, _) = IUniswapV2Pair::new(pool.address, middleware.clone())
+                .get_reserves()
+                .block(block_number)
+                .call()
+                .await?;
+            pool.reserve_0 = reserve_0;
+            pool.reserve_1 = reserve_1;
+            amms.push(AMM::UniswapV2Pool(pool));
+        }
+
+        let route = Route::new(vec![(0, weth), (1, usdc)], dai, &amms)?;
+        let router = IUniswapV2Router::new(
+            H160::from_str("0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D")?,
+            middleware.clone(),
+        );
+
+        for amount_in in [U256::exp10(16), U256::exp10(18), U256::exp10(21)] {
+            let amounts = router
+                .get_amounts_out(amount_in, vec![weth, usdc, dai])
+                .block(block_number)
+                .call()
+                .await?;
+
+            assert_eq!(route.simulate(amount_in, &amms)?, amounts[2]);
+        }
+
+        //WETH is priced in DAI around the price of ether, and a 1000 WETH trade moves the pools
+        let price = route.price(&amms)?;
+        assert!(price > 100.0 && price < 100_000.0, "{price}");
+        assert!(route.price_impact(U256::exp10(21), &amms)? > 0.0);
+
+        Ok(())
+    }
+}
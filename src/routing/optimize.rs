@@ -0,0 +1,363 @@
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::RouteError,
+    math::{fixed_point::u256_to_f64_lossy, mul_shift_right, Q128x128},
+};
+
+use super::{AmmLookup, Route};
+
+//Ternary search steps narrowing the whole input range before switching to Newton steps
+const TERNARY_STEPS: usize = 16;
+
+/// Input range and stopping criteria of `optimize_input`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TradeBounds {
+    /// Largest amount of token in the trade may use
+    pub max_amount_in: U256,
+    /// The search stops once the optimal amount is bracketed within this many units of token in
+    pub tolerance: U256,
+    /// Ternary and Newton steps taken before the search stops
+    pub max_iterations: usize,
+    /// Units of token out one unit of token in is worth, one for cycles and the price of token in elsewhere for routes
+    pub input_value: Q128x128,
+}
+
+impl TradeBounds {
+    pub fn new(max_amount_in: U256) -> Self {
+        TradeBounds {
+            max_amount_in,
+            tolerance: U256::one(),
+            max_iterations: 256,
+            input_value: Q128x128::one(),
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: U256) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn with_input_value(mut self, input_value: Q128x128) -> Self {
+        self.input_value = input_value;
+        self
+    }
+}
+
+/// Most profitable trade through a route, all zero when no amount is profitable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OptimalTrade {
+    pub amount_in: U256,
+    pub amount_out: U256,
+    /// `amount_out` less the value of `amount_in`, in units of token out
+    pub profit: U256,
+}
+
+//Output and input value of a trade, compared without going through signed profits
+#[derive(Debug, Clone, Copy)]
+struct Evaluation {
+    amount_in: U256,
+    amount_out: U256,
+    cost: U256,
+}
+
+impl Evaluation {
+    fn is_better_than(&self, other: &Evaluation) -> bool {
+        self.amount_out.saturating_add(other.cost) > other.amount_out.saturating_add(self.cost)
+    }
+}
+
+/// Amount of `route.token_in()` maximizing `amount_out - amount_in * input_value`, up to `bounds.max_amount_in`.
+///
+/// The profit of a route is concave in the input, so it is unimodal. A few ternary search steps first narrow the
+/// whole range, then Newton steps on the derivative of the profit, the product of the gradient of every hop less
+/// the input value, shrink the bracket around the optimum. A step that fails to halve the bracket, as happens
+/// where the derivative is discontinuous like at a V3 tick boundary, is followed by a bisection step.
+/// The search stops once the bracket is within `bounds.tolerance` or after `bounds.max_iterations` steps.
+pub fn optimize_input<K, L>(
+    route: &Route<K>,
+    amms: &L,
+    bounds: TradeBounds,
+) -> Result<OptimalTrade, RouteError>
+where
+    K: Copy + PartialEq,
+    L: AmmLookup<K> + ?Sized,
+{
+    let value = bounds.input_value.into_raw().into_raw();
+    let evaluate = |amount_in: U256| -> Result<Evaluation, RouteError> {
+        Ok(Evaluation {
+            amount_in,
+            amount_out: route.simulate(amount_in, amms)?,
+            cost: mul_shift_right(amount_in, value, 128)?,
+        })
+    };
+    let input_value = bounds.input_value.to_f64();
+    let derivative = |amount_in: U256| -> Result<f64, RouteError> {
+        Ok(route.gradient_f64(amount_in, amms)? - input_value)
+    };
+
+    let tolerance = bounds.tolerance.max(U256::one());
+    let (mut lo, mut hi) = (U256::zero(), bounds.max_amount_in);
+    let mut iterations = 0;
+
+    while iterations < TERNARY_STEPS.min(bounds.max_iterations)
+        && hi - lo > tolerance.max(U256::from(2))
+    {
+        let third = (hi - lo) / 3;
+        let (left, right) = (lo + third, hi - third);
+        if evaluate(left)?.is_better_than(&evaluate(right)?) {
+            hi = right;
+        } else {
+            lo = left;
+        }
+
+        iterations += 1;
+    }
+
+    let (mut lo_derivative, mut hi_derivative) = (derivative(lo)?, derivative(hi)?);
+    //When the derivative keeps its sign over the bracket the optimum is one of its ends
+    if lo_derivative > 0.0 && hi_derivative < 0.0 {
+        let mut bisect = false;
+
+        while iterations < bounds.max_iterations && hi - lo > tolerance {
+            let width = hi - lo;
+            let step = if bisect {
+                width / 2
+            } else {
+                let fraction = lo_derivative / (lo_derivative - hi_derivative);
+                U256::from_f64_lossy(u256_to_f64_lossy(width) * fraction)
+            };
+
+            let amount_in = (lo + step).clamp(lo + 1, hi - 1);
+            let amount_derivative = derivative(amount_in)?;
+            if amount_derivative > 0.0 {
+                lo = amount_in;
+                lo_derivative = amount_derivative;
+            } else {
+                hi = amount_in;
+                hi_derivative = amount_derivative;
+            }
+
+            bisect = hi - lo > width / 2;
+            iterations += 1;
+        }
+    }
+
+    let (lo, hi) = (evaluate(lo)?, evaluate(hi)?);
+    let best = if hi.is_better_than(&lo) { hi } else { lo };
+
+    if best.amount_out <= best.cost {
+        return Ok(OptimalTrade::default());
+    }
+
+    Ok(OptimalTrade {
+        amount_in: best.amount_in,
+        amount_out: best.amount_out,
+        profit: best.amount_out - best.cost,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AmmState, AMM},
+        math::Q128x128,
+        routing::Route,
+    };
+
+    use super::{optimize_input, OptimalTrade, TradeBounds};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+    const GRID_POINTS: u64 = 1000;
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn pool(address: u64, token_a: H160, token_b: H160, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a,
+            token_b,
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    //Most profitable amount of a cycle on an even grid over [0, max_amount_in]
+    fn grid_search(route: &Route, amms: &[AMM], max_amount_in: U256) -> (U256, U256) {
+        (0..=GRID_POINTS)
+            .map(|point| {
+                let amount_in = max_amount_in * U256::from(point) / U256::from(GRID_POINTS);
+                let amount_out = route.simulate(amount_in, amms).unwrap();
+                (amount_in, amount_out.saturating_sub(amount_in))
+            })
+            .max_by_key(|(_, profit)| *profit)
+            .unwrap()
+    }
+
+    fn assert_optimal(route: &Route, amms: &[AMM], trade: OptimalTrade, max_amount_in: U256) {
+        let (grid_amount_in, grid_profit) = grid_search(route, amms, max_amount_in);
+        assert!(
+            trade.profit >= grid_profit,
+            "{trade:?} is less profitable than {grid_profit} at {grid_amount_in}"
+        );
+
+        //Neighboring amounts are less profitable
+        let step = max_amount_in / U256::from(GRID_POINTS);
+        for amount_in in [trade.amount_in - step, trade.amount_in + step] {
+            let amount_out = route.simulate(amount_in, amms).unwrap();
+            assert!(amount_out.saturating_sub(amount_in) <= trade.profit);
+        }
+
+        assert_eq!(
+            trade.amount_out,
+            route.simulate(trade.amount_in, amms).unwrap()
+        );
+        assert_eq!(trade.profit, trade.amount_out - trade.amount_in);
+    }
+
+    #[test]
+    fn test_v2_cycle_matches_grid_search() -> eyre::Result<()> {
+        //1 -> 2 at 2, 2 -> 3 at 1 and 3 -> 1 at 0.6
+        let amms = vec![
+            pool(
+                100,
+                token(1),
+                token(2),
+                10_u128.pow(24),
+                2 * 10_u128.pow(24),
+            ),
+            pool(101, token(2), token(3), 10_u128.pow(24), 10_u128.pow(24)),
+            pool(
+                102,
+                token(3),
+                token(1),
+                10_u128.pow(25),
+                6 * 10_u128.pow(24),
+            ),
+        ];
+        let route = Route::new(
+            vec![(0, token(1)), (1, token(2)), (2, token(3))],
+            token(1),
+            &amms,
+        )?;
+
+        let max_amount_in = U256::exp10(24);
+        let trade = optimize_input(&route, &amms, TradeBounds::new(max_amount_in))?;
+        assert!(!trade.profit.is_zero());
+        assert_optimal(&route, &amms, trade, max_amount_in);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v3_cycle_matches_grid_search() -> eyre::Result<()> {
+        let v3_pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let (token_a, token_b) = (v3_pool.token_a, v3_pool.token_b);
+
+        //Pays 3% more token a for token b than the V3 pool, so buying token b on the V3 pool is profitable
+        let rate = v3_pool.gradient_f64(token_a, U256::zero())? / 0.997;
+        let reserve_0 = 2 * 10_u128.pow(13);
+        let reserve_1 = (reserve_0 as f64 * rate / 1.03) as u128;
+
+        let amms = vec![
+            AMM::UniswapV3Pool(v3_pool.clone()),
+            pool(100, token_a, token_b, reserve_0, reserve_1),
+        ];
+        let route = Route::new(vec![(0, token_a), (1, token_b)], token_a, &amms)?;
+
+        let max_amount_in = U256::from(2 * 10_u128.pow(12));
+        let trade = optimize_input(&route, &amms, TradeBounds::new(max_amount_in))?;
+        assert_optimal(&route, &amms, trade, max_amount_in);
+
+        //The optimal trade crosses initialized ticks, so the profit has kinks on the way there
+        let mut moved = v3_pool.clone();
+        moved.simulate_swap_mut(token_a, trade.amount_in)?;
+        assert!(v3_pool.tick - moved.tick > v3_pool.tick_spacing);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_against_input_value() -> eyre::Result<()> {
+        let (reserve_0, reserve_1) = (10_u128.pow(24), 2 * 10_u128.pow(24));
+        let amms = vec![pool(100, token(1), token(2), reserve_0, reserve_1)];
+        let route = Route::new(vec![(0, token(1))], token(2), &amms)?;
+
+        //Token 1 is worth 1.5 token 2 elsewhere, so the optimum brings the marginal rate of the pool down to 1.5
+        let bounds = TradeBounds::new(U256::exp10(24))
+            .with_input_value(Q128x128::from_ratio(U256::from(3), U256::from(2)).unwrap());
+        let trade = optimize_input(&route, &amms, bounds)?;
+
+        let fee = 0.997;
+        let (reserve_0, reserve_1) = (reserve_0 as f64, reserve_1 as f64);
+        let expected = ((fee * reserve_0 * reserve_1 / 1.5).sqrt() - reserve_0) / fee;
+        let amount_in = trade.amount_in.as_u128() as f64;
+        assert!((amount_in - expected).abs() / expected < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounds() -> eyre::Result<()> {
+        //Consistent prices, going around loses the fees
+        let amms = vec![
+            pool(
+                100,
+                token(1),
+                token(2),
+                10_u128.pow(24),
+                2 * 10_u128.pow(24),
+            ),
+            pool(
+                101,
+                token(2),
+                token(1),
+                2 * 10_u128.pow(24),
+                10_u128.pow(24),
+            ),
+        ];
+        let route = Route::new(vec![(0, token(1)), (1, token(2))], token(1), &amms)?;
+        assert_eq!(
+            optimize_input(&route, &amms, TradeBounds::new(U256::exp10(24)))?,
+            OptimalTrade::default()
+        );
+
+        //Profitable past the maximum input, so the trade uses all of it
+        let amms = vec![
+            pool(
+                100,
+                token(1),
+                token(2),
+                10_u128.pow(24),
+                2 * 10_u128.pow(24),
+            ),
+            pool(101, token(2), token(1), 10_u128.pow(24), 10_u128.pow(24)),
+        ];
+        let max_amount_in = U256::exp10(18);
+        let trade = optimize_input(&route, &amms, TradeBounds::new(max_amount_in))?;
+        assert_eq!(trade.amount_in, max_amount_in);
+
+        //A loose tolerance stops the search early, close to the optimum
+        let exact = optimize_input(&route, &amms, TradeBounds::new(U256::exp10(24)))?;
+        let loose = optimize_input(
+            &route,
+            &amms,
+            TradeBounds::new(U256::exp10(24)).with_tolerance(U256::exp10(18)),
+        )?;
+        assert!(loose.profit >= exact.profit * U256::from(999) / U256::from(1000));
+
+        Ok(())
+    }
+}
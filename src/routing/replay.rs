@@ -0,0 +1,213 @@
+use ethers::types::{H160, H256, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::RouteError,
+    state_space::state::{initialize_state_space, StateSpace},
+};
+
+use super::RouteQuote;
+
+/// Pool of a quoted route as it was when the route was simulated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolSnapshot {
+    /// `AmmState::state_fingerprint` of the pool
+    pub fingerprint: H256,
+    /// Copy of the pool replayed by `replay`, V3 pools keeping their tick map
+    pub amm: AMM,
+}
+
+/// Quote recorded with the state it was simulated against, to be stored and replayed for audit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuoteContext {
+    pub quote: RouteQuote<H160>,
+    pub amount_in: U256,
+    /// Last block synced by the state space when the quote was simulated
+    pub block_number: u64,
+    pub pools: Vec<PoolSnapshot>,
+}
+
+impl QuoteContext {
+    /// Records `quote` for `amount_in` with the pools of its route in `state`, synced up to `block_number`
+    pub fn new(
+        quote: RouteQuote<H160>,
+        amount_in: U256,
+        state: &StateSpace,
+        block_number: u64,
+    ) -> Result<Self, RouteError> {
+        let mut pools: Vec<PoolSnapshot> = vec![];
+        for (hop, (address, _)) in quote.route.hops().iter().enumerate() {
+            if pools.iter().any(|pool| pool.amm.address() == *address) {
+                continue;
+            }
+
+            let amm = state.get(address).ok_or(RouteError::AmmNotFound(hop))?;
+            pools.push(PoolSnapshot {
+                fingerprint: amm.state_fingerprint(),
+                amm: amm.clone(),
+            });
+        }
+
+        Ok(QuoteContext {
+            quote,
+            amount_in,
+            block_number,
+            pools,
+        })
+    }
+
+    /// Pools of the route whose state in `state` differs from the recorded one, or that are missing from it
+    pub fn changed_pools(&self, state: &StateSpace) -> Vec<H160> {
+        self.pools
+            .iter()
+            .filter(|pool| {
+                !state
+                    .get(&pool.amm.address())
+                    .is_some_and(|amm| amm.state_fingerprint() == pool.fingerprint)
+            })
+            .map(|pool| pool.amm.address())
+            .collect()
+    }
+}
+
+/// Simulates the route of `context` again from its recorded pools, returning its output.
+///
+/// Panics if a recorded pool does not match its fingerprint, or if the simulation fails or does not reproduce the
+/// recorded output, which points to state corrupted in storage or nondeterministic swap math.
+pub fn replay(context: &QuoteContext) -> U256 {
+    for pool in context.pools.iter() {
+        assert_eq!(
+            pool.amm.state_fingerprint(),
+            pool.fingerprint,
+            "Recorded state of pool {:?} does not match its fingerprint",
+            pool.amm.address()
+        );
+    }
+
+    let state = initialize_state_space(context.pools.iter().map(|pool| pool.amm.clone()).collect());
+    let amount_out = context
+        .quote
+        .route
+        .simulate(context.amount_in, &state)
+        .expect("Recorded route could not be simulated");
+
+    assert_eq!(
+        amount_out, context.quote.amount_out,
+        "Replayed output of the route at block {} differs from the quote",
+        context.block_number
+    );
+
+    amount_out
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, H256, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AMM},
+        routing::{find_best_route, RouteConstraints, TokenGraph},
+        state_space::state::{initialize_state_space, StateSpace},
+    };
+
+    use super::{replay, QuoteContext};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    //Quotes of the direct V3 route and of a route through two V2 pools, for 1000 USDC
+    fn contexts() -> eyre::Result<(Vec<QuoteContext>, StateSpace)> {
+        let v3_pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let (usdc, weth) = (v3_pool.token_a, v3_pool.token_b);
+
+        let dai = H160::from_low_u64_be(1);
+        let state = initialize_state_space(vec![
+            AMM::UniswapV3Pool(v3_pool),
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(100),
+                token_a: usdc,
+                token_b: dai,
+                reserve_0: 10_u128.pow(12),
+                reserve_1: 10_u128.pow(24),
+                fee: 300,
+                ..Default::default()
+            }),
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(101),
+                token_a: dai,
+                token_b: weth,
+                reserve_0: 10_u128.pow(24),
+                reserve_1: 10_u128.pow(21) / 2,
+                fee: 300,
+                ..Default::default()
+            }),
+        ]);
+
+        let quotes = find_best_route(
+            &TokenGraph::from_state_space(&state),
+            &state,
+            usdc,
+            weth,
+            U256::exp10(9),
+            2,
+            2,
+            None,
+            &RouteConstraints::default(),
+        )?;
+        assert_eq!(quotes.len(), 2);
+
+        let contexts = quotes
+            .into_iter()
+            .map(|quote| QuoteContext::new(quote, U256::exp10(9), &state, 17_000_000))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((contexts, state))
+    }
+
+    #[test]
+    fn test_replay() -> eyre::Result<()> {
+        let (contexts, mut state) = contexts()?;
+
+        for context in contexts.iter() {
+            assert_eq!(replay(context), context.quote.amount_out);
+
+            //Replays the same after a round trip through storage
+            let stored: QuoteContext = serde_json::from_str(&serde_json::to_string(context)?)?;
+            assert_eq!(stored.quote, context.quote);
+            assert_eq!(stored.block_number, 17_000_000);
+            assert_eq!(replay(&stored), context.quote.amount_out);
+
+            assert!(context.changed_pools(&state).is_empty());
+        }
+
+        let two_hops = contexts
+            .iter()
+            .find(|context| context.pools.len() == 2)
+            .expect("route through the V2 pools is quoted");
+        let synced = H160::from_low_u64_be(101);
+        if let Some(AMM::UniswapV2Pool(pool)) = state.get_mut(&synced) {
+            pool.reserve_0 += 1;
+        }
+        assert_eq!(two_hops.changed_pools(&state), vec![synced]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "differs from the quote")]
+    fn test_replay_detects_a_different_output() {
+        let (mut contexts, _) = contexts().unwrap();
+        contexts[0].quote.amount_out += U256::one();
+
+        replay(&contexts[0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not match its fingerprint")]
+    fn test_replay_detects_corrupted_state() {
+        let (mut contexts, _) = contexts().unwrap();
+        contexts[0].pools[0].fingerprint = H256::zero();
+
+        replay(&contexts[0]);
+    }
+}
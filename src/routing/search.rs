@@ -0,0 +1,761 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::RouteError,
+    math::fixed_point::u256_to_f64_lossy,
+    state_space::price::{pool_depth, DepthWeighting},
+};
+
+use super::{AmmLookup, GasCostModel, Route, TokenGraph};
+
+/// Partial routes kept for each token at every depth of the search
+pub const BEAM_WIDTH: usize = 8;
+/// Pools of each token pair considered by the search, the deepest ones for the amount first reaching the pair
+pub const CANDIDATES_PER_PAIR: usize = 4;
+
+/// Route found by `find_best_route`, with its output before and after gas
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteQuote<K = usize> {
+    pub route: Route<K>,
+    /// Output of the route for the amount in, before gas
+    pub amount_out: U256,
+    /// Gas of every swap of the route, zero without a gas model
+    pub gas_used: u64,
+    /// `gas_used` priced in token out
+    pub gas_cost: U256,
+    pub net_amount_out: U256,
+}
+
+/// Bounds on the routes returned by `find_best_route`, enforced on every hop while searching.
+///
+/// Price impact is the shortfall of the execution rate from the marginal rate at zero amount, net of fees, as in
+/// `Route::price_impact`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteConstraints {
+    /// Largest price impact of a single hop, in basis points
+    pub max_hop_impact_bps: u32,
+    /// Largest price impact of the whole route, in basis points
+    pub max_total_impact_bps: u32,
+    /// Smallest depth of a pool as a multiple of the amount swapped through it, with depth measured as in
+    /// `aggregate_price` in units of the token in. AMMs without a measurable depth fail any positive minimum.
+    pub min_pool_depth: f64,
+}
+
+impl Default for RouteConstraints {
+    fn default() -> Self {
+        RouteConstraints {
+            max_hop_impact_bps: 10_000,
+            max_total_impact_bps: 10_000,
+            min_pool_depth: 0.0,
+        }
+    }
+}
+
+impl RouteConstraints {
+    pub fn new(max_hop_impact_bps: u32, max_total_impact_bps: u32) -> Self {
+        RouteConstraints {
+            max_hop_impact_bps,
+            max_total_impact_bps,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_min_pool_depth(mut self, min_pool_depth: f64) -> Self {
+        self.min_pool_depth = min_pool_depth;
+        self
+    }
+
+    fn is_unconstrained(&self) -> bool {
+        self.max_hop_impact_bps >= 10_000
+            && self.max_total_impact_bps >= 10_000
+            && self.min_pool_depth <= 0.0
+    }
+}
+
+//Route being extended by the search, `amount` of `token` has been bought through `hops`
+#[derive(Debug, Clone)]
+struct PartialRoute<K> {
+    hops: Vec<(K, H160)>,
+    token: H160,
+    amount: U256,
+    //Product of the execution rate over the marginal rate of each hop, one minus the price impact of the route
+    rate_ratio: f64,
+}
+
+impl<K: Copy + Ord> PartialRoute<K> {
+    fn visits(&self, token: H160) -> bool {
+        self.hops.iter().any(|(_, token_in)| *token_in == token)
+    }
+
+    fn uses(&self, id: K) -> bool {
+        self.hops.iter().any(|(used, _)| *used == id)
+    }
+}
+
+/// Up to `top_k` routes from `token_in` to `token_out` through at most `max_hops` AMMs, quoted for `amount_in`
+/// and best first.
+///
+/// Routes are ranked by their output net of gas, priced with `gas_model` and converted into `token_out` on the
+/// pools trading it for the native token. Gas that can not be converted, for lack of such a pool, costs nothing.
+/// Without a gas model routes are ranked by their output.
+///
+/// The search extends every partial route one hop at a time, simulating the amount it carries on each
+/// candidate pool. Hops breaking `constraints` are dropped as they are simulated, so every returned route
+/// satisfies them. Partial routes reaching the same token are ranked by amount, which ranks them by negative
+/// log execution price, and only the best `BEAM_WIDTH` are extended. A partial route reaching a token with less
+/// than a shorter route already did is dominated and dropped. The pools of a pair are ranked once, by
+/// simulating the first amount reaching the pair, and only the best `CANDIDATES_PER_PAIR` are simulated after that,
+/// so pairs with thousands of pools cost one pass over their pools.
+///
+/// Routes never use a pool or pass through a token more than once. Ties are broken by hop count and then by
+/// the hops themselves, so the result only depends on the inputs.
+///
+/// Errors with `RouteError::ConstraintsUnsatisfied` when routes exist but all of them break the constraints,
+/// and with `RouteError::NoRoute` when there is no route at all.
+#[allow(clippy::too_many_arguments)]
+pub fn find_best_route<K, L>(
+    graph: &TokenGraph<K>,
+    amms: &L,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+    max_hops: usize,
+    top_k: usize,
+    gas_model: Option<&GasCostModel>,
+    constraints: &RouteConstraints,
+) -> Result<Vec<RouteQuote<K>>, RouteError>
+where
+    K: Copy + Ord + Hash,
+    L: AmmLookup<K> + ?Sized,
+{
+    if top_k == 0 {
+        return Ok(vec![]);
+    }
+
+    let (routes, pruned) = search(
+        graph,
+        amms,
+        token_in,
+        token_out,
+        amount_in,
+        max_hops,
+        constraints,
+    );
+    if routes.is_empty() {
+        //Only searched again when constraints dropped a hop, to tell whether any route exists
+        let unconstrained = RouteConstraints::default();
+        if pruned
+            && !search(
+                graph,
+                amms,
+                token_in,
+                token_out,
+                amount_in,
+                max_hops,
+                &unconstrained,
+            )
+            .0
+            .is_empty()
+        {
+            return Err(RouteError::ConstraintsUnsatisfied);
+        }
+
+        return Err(RouteError::NoRoute);
+    }
+
+    //Routes with the same gas share its conversion into token out
+    let mut gas_costs: HashMap<u64, U256> = HashMap::new();
+    let mut quotes = routes
+        .into_iter()
+        .map(|(route, amount_out)| {
+            let (gas_used, gas_cost) = match gas_model {
+                Some(gas_model) => {
+                    let gas_used = route
+                        .hops
+                        .iter()
+                        .filter_map(|(id, _)| amms.get_amm(*id))
+                        .map(|amm| gas_model.swap_gas(amm))
+                        .sum::<u64>();
+                    let gas_cost = *gas_costs.entry(gas_used).or_insert_with(|| {
+                        let pools = graph
+                            .pools_for_pair(gas_model.native_token, token_out)
+                            .iter()
+                            .filter_map(|id| amms.get_amm(*id));
+                        gas_model
+                            .cost_in(gas_used, token_out, pools)
+                            .unwrap_or_default()
+                    });
+
+                    (gas_used, gas_cost)
+                }
+                None => (0, U256::zero()),
+            };
+
+            RouteQuote {
+                route,
+                amount_out,
+                gas_used,
+                gas_cost,
+                net_amount_out: amount_out.saturating_sub(gas_cost),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    quotes.sort_by(|a, b| {
+        b.net_amount_out
+            .cmp(&a.net_amount_out)
+            .then_with(|| a.route.hops.len().cmp(&b.route.hops.len()))
+            .then_with(|| a.route.hops.cmp(&b.route.hops))
+    });
+    quotes.truncate(top_k);
+
+    Ok(quotes)
+}
+
+//Every route reaching `token_out` with its output, and whether any hop was dropped for breaking `constraints`
+fn search<K, L>(
+    graph: &TokenGraph<K>,
+    amms: &L,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+    max_hops: usize,
+    constraints: &RouteConstraints,
+) -> (Vec<(Route<K>, U256)>, bool)
+where
+    K: Copy + Ord + Hash,
+    L: AmmLookup<K> + ?Sized,
+{
+    let mut routes = vec![];
+    let mut pruned = false;
+    if token_in == token_out || amount_in.is_zero() {
+        return (routes, pruned);
+    }
+
+    let mut frontier = vec![PartialRoute {
+        hops: vec![],
+        token: token_in,
+        amount: amount_in,
+        rate_ratio: 1.0,
+    }];
+    //Most of each token bought by the partial routes of earlier depths
+    let mut best_amounts = HashMap::from([(token_in, amount_in)]);
+    let mut candidates: HashMap<(H160, H160), Vec<K>> = HashMap::new();
+    let mut marginal_rates: HashMap<(K, H160, H160), f64> = HashMap::new();
+
+    for _ in 0..max_hops {
+        //Ordered by token so ranking and ties do not depend on hashing
+        let mut expanded: BTreeMap<H160, Vec<PartialRoute<K>>> = BTreeMap::new();
+
+        for partial in frontier.iter() {
+            let mut neighbors = graph.neighbors(partial.token).collect::<Vec<_>>();
+            neighbors.sort_unstable();
+
+            for neighbor in neighbors {
+                if neighbor == partial.token || partial.visits(neighbor) {
+                    continue;
+                }
+
+                let pair_candidates =
+                    candidates
+                        .entry((partial.token, neighbor))
+                        .or_insert_with(|| {
+                            rank_candidates(graph, amms, partial.token, neighbor, partial.amount)
+                        });
+
+                for id in pair_candidates.iter() {
+                    if partial.uses(*id) {
+                        continue;
+                    }
+
+                    let amm = match amms.get_amm(*id) {
+                        Some(amm) => amm,
+                        None => continue,
+                    };
+                    let amount = amm.simulate_swap_to(partial.token, neighbor, partial.amount);
+                    let amount = match amount {
+                        Ok(amount) if !amount.is_zero() => amount,
+                        _ => continue,
+                    };
+
+                    let rate_ratio = if constraints.is_unconstrained() {
+                        1.0
+                    } else {
+                        let marginal_rate = *marginal_rates
+                            .entry((*id, partial.token, neighbor))
+                            .or_insert_with(|| {
+                                amm.gradient_to(partial.token, neighbor, U256::zero())
+                                    .map(|gradient| gradient.to_f64())
+                                    .unwrap_or_default()
+                            });
+
+                        match check_hop(constraints, amm, partial, amount, marginal_rate) {
+                            Some(rate_ratio) => rate_ratio,
+                            None => {
+                                pruned = true;
+                                continue;
+                            }
+                        }
+                    };
+
+                    let mut hops = partial.hops.clone();
+                    hops.push((*id, partial.token));
+                    expanded.entry(neighbor).or_default().push(PartialRoute {
+                        hops,
+                        token: neighbor,
+                        amount,
+                        rate_ratio,
+                    });
+                }
+            }
+        }
+
+        if let Some(complete) = expanded.remove(&token_out) {
+            routes.extend(complete.into_iter().map(|partial| {
+                (
+                    Route {
+                        hops: partial.hops,
+                        token_out,
+                    },
+                    partial.amount,
+                )
+            }));
+        }
+
+        frontier.clear();
+        for (token, mut partials) in expanded {
+            if let Some(best_amount) = best_amounts.get(&token) {
+                partials.retain(|partial| partial.amount > *best_amount);
+            }
+
+            partials.sort_by(|a, b| b.amount.cmp(&a.amount).then_with(|| a.hops.cmp(&b.hops)));
+            partials.truncate(BEAM_WIDTH);
+
+            if let Some(best) = partials.first() {
+                best_amounts.insert(token, best.amount);
+            }
+            frontier.extend(partials);
+        }
+
+        if frontier.is_empty() {
+            break;
+        }
+    }
+
+    (routes, pruned)
+}
+
+//Rate ratio of `partial` extended by swapping its amount for `amount_out` on `amm`, None if the hop breaks
+//`constraints`
+fn check_hop<K>(
+    constraints: &RouteConstraints,
+    amm: &AMM,
+    partial: &PartialRoute<K>,
+    amount_out: U256,
+    marginal_rate: f64,
+) -> Option<f64> {
+    if constraints.min_pool_depth > 0.0 {
+        let depth = pool_depth(amm, partial.token, DepthWeighting::default().tick_range)?;
+        if depth < constraints.min_pool_depth * u256_to_f64_lossy(partial.amount) {
+            return None;
+        }
+    }
+
+    if marginal_rate <= 0.0 || !marginal_rate.is_finite() {
+        return None;
+    }
+    let hop_ratio =
+        u256_to_f64_lossy(amount_out) / u256_to_f64_lossy(partial.amount) / marginal_rate;
+    let rate_ratio = partial.rate_ratio * hop_ratio;
+
+    let impact_bps = |ratio: f64| (1.0 - ratio) * 10_000.0;
+    if impact_bps(hop_ratio) > constraints.max_hop_impact_bps as f64
+        || impact_bps(rate_ratio) > constraints.max_total_impact_bps as f64
+    {
+        return None;
+    }
+
+    Some(rate_ratio)
+}
+
+//Pools swapping `token_in` for `token_out`, deepest first for `amount_in`
+fn rank_candidates<K, L>(
+    graph: &TokenGraph<K>,
+    amms: &L,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+) -> Vec<K>
+where
+    K: Copy + Ord + Hash,
+    L: AmmLookup<K> + ?Sized,
+{
+    let mut ranked = graph
+        .pools_for_pair(token_in, token_out)
+        .iter()
+        .filter_map(|id| {
+            //AMMs holding more than two tokens are simulated for the pair they are ranked on
+            amms.get_amm(*id)?
+                .simulate_swap_to(token_in, token_out, amount_in)
+                .ok()
+                .filter(|amount_out| !amount_out.is_zero())
+                .map(|amount_out| (*id, amount_out))
+        })
+        .collect::<Vec<_>>();
+
+    ranked.sort_unstable_by(|(a, a_out), (b, b_out)| b_out.cmp(a_out).then(a.cmp(b)));
+    ranked.truncate(CANDIDATES_PER_PAIR);
+
+    ranked.into_iter().map(|(id, _)| id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AmmState, AMM},
+        errors::RouteError,
+        filters::dedupe::Protocol,
+        routing::{GasCostModel, TokenGraph},
+    };
+
+    use super::{find_best_route, RouteConstraints, RouteQuote};
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn pool(address: u64, token_a: u64, token_b: u64, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: token(token_a),
+            token_b: token(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_prefers_deeper_multi_hop_route() -> eyre::Result<()> {
+        let amms = vec![
+            //Better spot price, but too shallow for the amount
+            pool(100, 1, 3, 10_u128.pow(19), 2 * 10_u128.pow(19)),
+            pool(101, 1, 2, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(102, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(103, 2, 3, 10_u128.pow(22), 10_u128.pow(22)),
+        ];
+        let graph = TokenGraph::new(&amms);
+        let amount_in = U256::exp10(20);
+
+        let routes = find_best_route(
+            &graph,
+            &amms,
+            token(1),
+            token(3),
+            amount_in,
+            3,
+            5,
+            None,
+            &RouteConstraints::default(),
+        )?;
+        assert_eq!(routes.len(), 3);
+
+        let best = &routes[0];
+        assert_eq!(best.route.hops(), &[(1, token(1)), (2, token(2))]);
+        assert_eq!(best.amount_out, best.route.simulate(amount_in, &amms)?);
+
+        //Best first, and every output is the simulated output of its route
+        for window in routes.windows(2) {
+            assert!(window[0].amount_out >= window[1].amount_out);
+        }
+        for quote in routes.iter() {
+            assert_eq!(quote.amount_out, quote.route.simulate(amount_in, &amms)?);
+            assert_eq!(quote.net_amount_out, quote.amount_out);
+            quote.route.validate(&amms)?;
+        }
+
+        //A small amount takes the better spot price
+        let routes = find_best_route(
+            &graph,
+            &amms,
+            token(1),
+            token(3),
+            U256::exp10(12),
+            3,
+            1,
+            None,
+            &RouteConstraints::default(),
+        )?;
+        assert_eq!(routes[0].route.hops(), &[(0, token(1))]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_never_revisits_a_pool_or_token() -> eyre::Result<()> {
+        //A dense graph where every token pair has two pools
+        let mut amms = vec![];
+        for token_a in 1..=5 {
+            for token_b in token_a + 1..=5 {
+                for depth in 1..=2 {
+                    let reserve = depth * 10_u128.pow(21 + token_a as u32);
+                    amms.push(pool(
+                        amms.len() as u64 + 100,
+                        token_a,
+                        token_b,
+                        reserve,
+                        reserve,
+                    ));
+                }
+            }
+        }
+        let graph = TokenGraph::new(&amms);
+
+        let routes = find_best_route(
+            &graph,
+            &amms,
+            token(1),
+            token(5),
+            U256::exp10(21),
+            4,
+            100,
+            None,
+            &RouteConstraints::default(),
+        )?;
+        assert!(!routes.is_empty());
+
+        for RouteQuote { route, .. } in routes.iter() {
+            let pools = route
+                .hops()
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<HashSet<_>>();
+            let tokens = route
+                .hops()
+                .iter()
+                .map(|(_, token_in)| *token_in)
+                .collect::<HashSet<_>>();
+            assert_eq!(pools.len(), route.hops().len());
+            assert_eq!(tokens.len(), route.hops().len());
+            assert!(route.hops().len() <= 4);
+        }
+
+        let distinct = routes
+            .iter()
+            .map(|quote| &quote.route)
+            .collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), routes.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_thousands_of_pools_per_pair() -> eyre::Result<()> {
+        let pools_per_pair = 2000;
+        let mut amms = vec![];
+        for idx in 0..pools_per_pair {
+            let depth = (idx * 7919 % pools_per_pair + 1) as u128;
+            amms.push(pool(
+                idx,
+                1,
+                2,
+                depth * 10_u128.pow(20),
+                depth * 10_u128.pow(20),
+            ));
+            amms.push(pool(
+                idx + pools_per_pair,
+                2,
+                3,
+                depth * 10_u128.pow(20),
+                depth * 2 * 10_u128.pow(20),
+            ));
+        }
+        let graph = TokenGraph::new(&amms);
+        let amount_in = U256::exp10(21);
+
+        //With a single path, the best route takes the best pool of each hop
+        let mut expected = amount_in;
+        for token_in in [token(1), token(2)] {
+            expected = amms
+                .iter()
+                .filter(|amm| amm.tokens()[0] == token_in)
+                .map(|amm| amm.simulate_swap(token_in, expected).unwrap())
+                .max()
+                .unwrap();
+        }
+
+        let quote = || {
+            find_best_route(
+                &graph,
+                &amms,
+                token(1),
+                token(3),
+                amount_in,
+                2,
+                3,
+                None,
+                &RouteConstraints::default(),
+            )
+        };
+        let routes = quote()?;
+        assert_eq!(routes[0].amount_out, expected);
+
+        //The same inputs give the same routes
+        assert_eq!(routes, quote()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gas_aware_ranking() -> eyre::Result<()> {
+        //A direct pool from 2 to 3, and a deeper three hop route paying 0.1% more before gas
+        let amms = vec![
+            pool(100, 2, 3, 10_u128.pow(25), 10_u128.pow(25)),
+            pool(101, 2, 4, 10_u128.pow(26), 100_703 * 10_u128.pow(21)),
+            pool(102, 4, 5, 10_u128.pow(26), 10_u128.pow(26)),
+            pool(103, 5, 3, 10_u128.pow(26), 10_u128.pow(26)),
+            //Token 1 is the native token, worth 2000 of token 3
+            pool(104, 1, 3, 10_u128.pow(22), 2 * 10_u128.pow(25)),
+        ];
+        let graph = TokenGraph::new(&amms);
+        //The three hop route costs 250k more gas, 0.005 of the native token at 20 gwei
+        let gas_model = GasCostModel::new(U256::from(20) * U256::exp10(9), token(1))
+            .with_overhead(Protocol::UniswapV2, 125_000);
+
+        let quote = |amount_in, gas_model| {
+            find_best_route(
+                &graph,
+                &amms,
+                token(2),
+                token(3),
+                amount_in,
+                3,
+                5,
+                gas_model,
+                &RouteConstraints::default(),
+            )
+        };
+
+        let small = U256::exp10(21);
+        assert_eq!(quote(small, None)?[0].route.hops().len(), 3);
+
+        let quotes = quote(small, Some(&gas_model))?;
+        let best = &quotes[0];
+        assert_eq!(best.route.hops(), &[(0, token(2))]);
+        assert_eq!(best.gas_used, 125_000);
+        assert_eq!(best.net_amount_out, best.amount_out - best.gas_cost);
+        //About 5 of token 3 for a single swap
+        assert!(
+            best.gas_cost > U256::exp10(18) * U256::from(4)
+                && best.gas_cost < U256::exp10(18) * U256::from(5)
+        );
+
+        let three_hops = quotes
+            .iter()
+            .find(|quote| quote.route.hops().len() == 3)
+            .unwrap();
+        assert_eq!(three_hops.gas_used, 375_000);
+        assert!(three_hops.amount_out > best.amount_out);
+        assert!(three_hops.net_amount_out < best.net_amount_out);
+
+        //At size the better price outweighs the gas
+        let large = U256::exp10(24);
+        assert_eq!(quote(large, Some(&gas_model))?[0].route.hops().len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_constraints_change_the_best_route() -> eyre::Result<()> {
+        let amms = vec![
+            //Best output, but through a thin direct pool
+            pool(100, 1, 3, 10_u128.pow(22), 2 * 10_u128.pow(22)),
+            pool(101, 1, 2, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(102, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+        ];
+        let graph = TokenGraph::new(&amms);
+        let amount_in = U256::exp10(20);
+
+        let quote = |constraints: &RouteConstraints| {
+            find_best_route(
+                &graph,
+                &amms,
+                token(1),
+                token(3),
+                amount_in,
+                3,
+                5,
+                None,
+                constraints,
+            )
+        };
+
+        //About 1% impact on the direct pool against 0.01% on each deep pool
+        let unconstrained = quote(&RouteConstraints::default())?;
+        assert_eq!(unconstrained[0].route.hops(), &[(0, token(1))]);
+        assert!(unconstrained[0].route.price_impact(amount_in, &amms)? > 0.009);
+
+        let constraints = RouteConstraints::new(50, 100);
+        let constrained = quote(&constraints)?;
+        assert_eq!(constrained.len(), 1);
+        assert_eq!(constrained[0].route.hops(), &[(1, token(1)), (2, token(2))]);
+        assert!(constrained[0].amount_out < unconstrained[0].amount_out);
+        assert!(constrained[0].route.price_impact(amount_in, &amms)? < 0.01);
+
+        //The direct pool holds 200 times the amount in, the deep pools 20,000 times
+        let deep_only = quote(&RouteConstraints::default().with_min_pool_depth(1000.0))?;
+        assert_eq!(deep_only[0].route.hops(), &[(1, token(1)), (2, token(2))]);
+
+        //A total bound tighter than any route is distinguished from a missing route
+        assert!(matches!(
+            quote(&RouteConstraints::new(10_000, 1)),
+            Err(RouteError::ConstraintsUnsatisfied)
+        ));
+        assert!(matches!(
+            quote(&RouteConstraints::default().with_min_pool_depth(1e9)),
+            Err(RouteError::ConstraintsUnsatisfied)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_route() {
+        let amms = vec![pool(100, 1, 2, 10_u128.pow(24), 10_u128.pow(24))];
+        let graph = TokenGraph::new(&amms);
+        let no_route = |token_in, token_out, amount_in, max_hops| {
+            matches!(
+                find_best_route(
+                    &graph,
+                    &amms,
+                    token_in,
+                    token_out,
+                    amount_in,
+                    max_hops,
+                    5,
+                    None,
+                    &RouteConstraints::new(1, 1),
+                ),
+                Err(RouteError::NoRoute)
+            )
+        };
+
+        assert!(no_route(token(1), token(3), U256::exp10(18), 3));
+        assert!(no_route(token(1), token(2), U256::exp10(18), 0));
+        assert!(no_route(token(1), token(1), U256::exp10(18), 3));
+        assert!(no_route(token(1), token(2), U256::zero(), 3));
+    }
+}
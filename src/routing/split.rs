@@ -0,0 +1,531 @@
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amm::{
+        uniswap_v2::{UniswapV2Pool, FEE_DENOMINATOR},
+        AmmState, AMM,
+    },
+    errors::SwapSimulationError,
+    math::fixed_point::u256_to_f64_lossy,
+};
+
+use super::GasCostModel;
+
+//Bisection steps on the marginal rate shared by the pools when water filling
+const WATER_FILL_ITERATIONS: usize = 48;
+//Amounts reaching a marginal rate are found to within 2^-32 of the order
+const WATER_FILL_PRECISION_BITS: usize = 32;
+
+/// Order split across pools of the same pair
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitOrder {
+    /// Index in `pools` and amount of token in of each pool the order uses, in index order
+    pub allocations: Vec<(usize, U256)>,
+    /// Sum of the output of every allocation, before gas
+    pub amount_out: U256,
+    /// Gas of every swap of the split, zero without a gas model
+    pub gas_used: u64,
+    /// `gas_used` priced in token out
+    pub gas_cost: U256,
+    pub net_amount_out: U256,
+}
+
+/// Splits `amount_in` of `token_in` across at most `max_splits` of `pools` to maximize the total output.
+///
+/// `pools` are expected to trade the same pair, pools not holding `token_in` are skipped. The pools paying the most
+/// for the whole amount are kept, then the input is allocated so their marginal rates are equal, in closed form when
+/// they are all V2 pools without transfer taxes and by water filling on `gradient` otherwise. Allocations always sum
+/// to `amount_in`, the wei left over by rounding go to the pool with the best marginal rate, the lowest index on ties.
+///
+/// With a gas model every swap costs gas, converted into token out on the pools of `pools` trading it for the native
+/// token, and the split over the number of pools with the best output net of gas is returned. The split never does
+/// worse than the best pool alone.
+pub fn split_order(
+    pools: &[AMM],
+    token_in: H160,
+    amount_in: U256,
+    max_splits: usize,
+    gas_model: Option<&GasCostModel>,
+) -> Result<SplitOrder, SwapSimulationError> {
+    let mut candidates = pools
+        .iter()
+        .enumerate()
+        .filter(|(_, amm)| amm.tokens().contains(&token_in))
+        .filter_map(|(idx, amm)| {
+            amm.simulate_swap(token_in, amount_in)
+                .ok()
+                .map(|amount_out| (idx, amm, amount_out))
+        })
+        .collect::<Vec<_>>();
+
+    if candidates.is_empty() {
+        return Err(SwapSimulationError::TokenNotInPool(token_in));
+    }
+
+    candidates.sort_by(|(a, _, a_out), (b, _, b_out)| b_out.cmp(a_out).then(a.cmp(b)));
+    candidates.truncate(max_splits.max(1));
+    let token_out = candidates[0].1.get_token_out(token_in);
+
+    let mut best: Option<SplitOrder> = None;
+    for splits in 1..=candidates.len() {
+        //Without gas only the best pool alone and the widest split are compared
+        if gas_model.is_none() && splits != 1 && splits != candidates.len() {
+            continue;
+        }
+
+        let (allocations, amount_out) = if splits == 1 {
+            let (idx, _, amount_out) = candidates[0];
+            (vec![(idx, amount_in)], amount_out)
+        } else {
+            allocate(&candidates[..splits], token_in, amount_in)?
+        };
+
+        let (gas_used, gas_cost) = match gas_model {
+            Some(gas_model) => {
+                let gas_used = allocations
+                    .iter()
+                    .map(|(idx, _)| gas_model.swap_gas(&pools[*idx]))
+                    .sum::<u64>();
+                let gas_cost = gas_model
+                    .cost_in(gas_used, token_out, pools)
+                    .unwrap_or_default();
+
+                (gas_used, gas_cost)
+            }
+            None => (0, U256::zero()),
+        };
+
+        let order = SplitOrder {
+            allocations,
+            amount_out,
+            gas_used,
+            gas_cost,
+            net_amount_out: amount_out.saturating_sub(gas_cost),
+        };
+
+        //Ties go to fewer pools
+        if !best
+            .as_ref()
+            .is_some_and(|best| order.net_amount_out <= best.net_amount_out)
+        {
+            best = Some(order);
+        }
+    }
+
+    Ok(best.expect("candidates are not empty"))
+}
+
+//Allocations equalizing the marginal rates of `candidates` in index order, and their total output
+fn allocate(
+    candidates: &[(usize, &AMM, U256)],
+    token_in: H160,
+    amount_in: U256,
+) -> Result<(Vec<(usize, U256)>, U256), SwapSimulationError> {
+    let mut candidates = candidates.to_vec();
+    candidates.sort_by_key(|(idx, _, _)| *idx);
+
+    let amms = candidates
+        .iter()
+        .map(|(_, amm, _)| *amm)
+        .collect::<Vec<_>>();
+    let v2_pools = amms
+        .iter()
+        .map(|amm| match amm {
+            AMM::UniswapV2Pool(pool) if !pool.has_transfer_tax() => Some(pool),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+
+    let mut amounts = match v2_pools {
+        Some(v2_pools) => equalize_v2(&v2_pools, token_in, amount_in),
+        None => water_fill(&amms, token_in, amount_in)?,
+    };
+    settle_remainder(&amms, token_in, amount_in, &mut amounts)?;
+
+    let mut allocations = vec![];
+    let mut amount_out = U256::zero();
+    for ((idx, amm, _), amount) in candidates.iter().zip(amounts) {
+        if !amount.is_zero() {
+            amount_out += amm.simulate_swap(token_in, amount)?;
+            allocations.push((*idx, amount));
+        }
+    }
+
+    Ok((allocations, amount_out))
+}
+
+//Closed form allocation equalizing the marginal rate f*r_in*r_out / (r_in + f*x)^2 of every pool that takes part.
+//At a shared rate l each pool takes sqrt(r_in*r_out/f) / sqrt(l) - r_in/f, and pools are added in order of their
+//marginal rate at zero until the rate of the next pool is below l.
+fn equalize_v2(pools: &[&UniswapV2Pool], token_in: H160, amount_in: U256) -> Vec<U256> {
+    let curves = pools
+        .iter()
+        .map(|pool| {
+            let (reserve_in, reserve_out) = if pool.token_a == token_in {
+                (pool.reserve_0 as f64, pool.reserve_1 as f64)
+            } else {
+                (pool.reserve_1 as f64, pool.reserve_0 as f64)
+            };
+            let fee = (FEE_DENOMINATOR - pool.fee) as f64 / FEE_DENOMINATOR as f64;
+
+            (reserve_in, reserve_out, fee)
+        })
+        .collect::<Vec<_>>();
+
+    let mut order = (0..pools.len())
+        .filter(|idx| curves[*idx].0 > 0.0 && curves[*idx].1 > 0.0)
+        .collect::<Vec<_>>();
+    let initial_rate = |idx: usize| {
+        let (reserve_in, reserve_out, fee) = curves[idx];
+        fee * reserve_out / reserve_in
+    };
+    order.sort_by(|a, b| initial_rate(*b).total_cmp(&initial_rate(*a)).then(a.cmp(b)));
+
+    let amount_in_f64 = u256_to_f64_lossy(amount_in);
+    let (mut offset, mut weight) = (amount_in_f64, 0.0);
+    let mut scale = 0.0;
+    let mut active = 0;
+
+    for (position, idx) in order.iter().enumerate() {
+        let (reserve_in, reserve_out, fee) = curves[*idx];
+        offset += reserve_in / fee;
+        weight += (reserve_in * reserve_out / fee).sqrt();
+        scale = offset / weight;
+        active = position + 1;
+
+        //scale is 1 / sqrt(l), the next pool only takes part if its rate at zero beats l
+        let rate = 1.0 / (scale * scale);
+        if !order
+            .get(position + 1)
+            .is_some_and(|next| initial_rate(*next) > rate)
+        {
+            break;
+        }
+    }
+
+    let mut amounts = vec![U256::zero(); pools.len()];
+    for idx in order.iter().take(active) {
+        let (reserve_in, reserve_out, fee) = curves[*idx];
+        let amount = scale * (reserve_in * reserve_out / fee).sqrt() - reserve_in / fee;
+        if amount > 0.0 {
+            amounts[*idx] = U256::from_f64_lossy(amount);
+        }
+    }
+
+    amounts
+}
+
+//Bisects the marginal rate shared by the pools, allocating to each pool the amount bringing its gradient down to
+//that rate. Ends with the highest rate the allocations do not exceed `amount_in` at.
+fn water_fill(
+    pools: &[&AMM],
+    token_in: H160,
+    amount_in: U256,
+) -> Result<Vec<U256>, SwapSimulationError> {
+    let mut hi = 0.0_f64;
+    for amm in pools.iter() {
+        hi = hi.max(amm.gradient_f64(token_in, U256::zero())?);
+    }
+
+    let mut lo = 0.0;
+    let mut amounts = vec![U256::zero(); pools.len()];
+    for _ in 0..WATER_FILL_ITERATIONS {
+        let rate = (lo + hi) / 2.0;
+
+        let mut filled = Vec::with_capacity(pools.len());
+        for amm in pools.iter() {
+            filled.push(amount_at_rate(amm, token_in, rate, amount_in)?);
+        }
+
+        if filled
+            .iter()
+            .fold(U256::zero(), |sum, amount| sum.saturating_add(*amount))
+            > amount_in
+        {
+            lo = rate;
+        } else {
+            hi = rate;
+            amounts = filled;
+        }
+    }
+
+    Ok(amounts)
+}
+
+//Largest amount up to `max_amount_in` at which the gradient of `amm` is still at least `rate`
+fn amount_at_rate(
+    amm: &AMM,
+    token_in: H160,
+    rate: f64,
+    max_amount_in: U256,
+) -> Result<U256, SwapSimulationError> {
+    if amm.gradient_f64(token_in, U256::zero())? < rate {
+        return Ok(U256::zero());
+    }
+    if amm.gradient_f64(token_in, max_amount_in)? >= rate {
+        return Ok(max_amount_in);
+    }
+
+    let precision = (max_amount_in >> WATER_FILL_PRECISION_BITS).max(U256::one());
+    let (mut lo, mut hi) = (U256::zero(), max_amount_in);
+    while hi - lo > precision {
+        let mid = lo + (hi - lo) / 2;
+        if amm.gradient_f64(token_in, mid)? >= rate {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    Ok(lo)
+}
+
+//Makes the amounts sum to `amount_in`, adding what is missing to the pool with the best marginal rate at its
+//allocation and taking any excess from the largest allocation, the lowest index on ties
+fn settle_remainder(
+    pools: &[&AMM],
+    token_in: H160,
+    amount_in: U256,
+    amounts: &mut [U256],
+) -> Result<(), SwapSimulationError> {
+    let allocated = amounts
+        .iter()
+        .fold(U256::zero(), |sum, amount| sum.saturating_add(*amount));
+
+    if allocated < amount_in {
+        let mut best = (0, f64::MIN);
+        for (idx, (amm, amount)) in pools.iter().zip(amounts.iter()).enumerate() {
+            let rate = amm.gradient_f64(token_in, *amount)?;
+            if rate > best.1 {
+                best = (idx, rate);
+            }
+        }
+
+        amounts[best.0] += amount_in - allocated;
+    } else if allocated > amount_in {
+        let largest = (0..amounts.len())
+            .max_by(|a, b| amounts[*a].cmp(&amounts[*b]).then(b.cmp(a)))
+            .unwrap_or_default();
+
+        amounts[largest] -= allocated - amount_in;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AmmState, AMM},
+        routing::GasCostModel,
+    };
+
+    use super::{equalize_v2, split_order, water_fill, SplitOrder};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    fn v2_pool(
+        address: u64,
+        token_a: H160,
+        token_b: H160,
+        reserve_0: u128,
+        reserve_1: u128,
+        fee: u32,
+    ) -> UniswapV2Pool {
+        UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a,
+            token_b,
+            reserve_0,
+            reserve_1,
+            fee,
+            ..Default::default()
+        }
+    }
+
+    fn assert_split(pools: &[AMM], token_in: H160, amount_in: U256, split: &SplitOrder) {
+        let allocated = split
+            .allocations
+            .iter()
+            .fold(U256::zero(), |sum, (_, amount)| sum + *amount);
+        assert_eq!(allocated, amount_in);
+
+        let amount_out = split
+            .allocations
+            .iter()
+            .map(|(idx, amount)| pools[*idx].simulate_swap(token_in, *amount).unwrap())
+            .fold(U256::zero(), |sum, amount_out| sum + amount_out);
+        assert_eq!(amount_out, split.amount_out);
+    }
+
+    fn best_single(pools: &[AMM], token_in: H160, amount_in: U256) -> U256 {
+        pools
+            .iter()
+            .map(|amm| amm.simulate_swap(token_in, amount_in).unwrap())
+            .max()
+            .unwrap()
+    }
+
+    //The USDC/WETH 30bps fixture, the same pool at 5bps with a third of the liquidity, and a V2 pool at the same price
+    fn usdc_weth_pools() -> eyre::Result<Vec<AMM>> {
+        let pool_30_bps: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+
+        let mut pool_5_bps = pool_30_bps.clone();
+        pool_5_bps.address = H160::from_low_u64_be(5);
+        pool_5_bps.fee = 500;
+        pool_5_bps.liquidity /= 3;
+        for info in pool_5_bps.ticks.values_mut() {
+            info.liquidity_net /= 3;
+            info.liquidity_gross /= 3;
+        }
+
+        let price = pool_30_bps.gradient_f64(pool_30_bps.token_a, U256::zero())? / 0.997;
+        let reserve_0 = 40_000_000 * 10_u128.pow(6);
+        let v2_pool = v2_pool(
+            2,
+            pool_30_bps.token_a,
+            pool_30_bps.token_b,
+            reserve_0,
+            (reserve_0 as f64 * price) as u128,
+            300,
+        );
+
+        Ok(vec![
+            AMM::UniswapV3Pool(pool_30_bps),
+            AMM::UniswapV3Pool(pool_5_bps),
+            AMM::UniswapV2Pool(v2_pool),
+        ])
+    }
+
+    #[test]
+    fn test_split_beats_single_pools() -> eyre::Result<()> {
+        let pools = usdc_weth_pools()?;
+        let token_in = pools[0].tokens()[0];
+
+        for amount_in in [
+            U256::from(100_000 * 10_u128.pow(6)),
+            U256::from(2_000_000 * 10_u128.pow(6)) + 7,
+        ] {
+            let split = split_order(&pools, token_in, amount_in, 3, None)?;
+            assert_split(&pools, token_in, amount_in, &split);
+            assert_eq!(split.allocations.len(), 3);
+            assert!(split.amount_out > best_single(&pools, token_in, amount_in));
+
+            //Limited to two pools, the split still beats any single pool but not the three way split
+            let two_way = split_order(&pools, token_in, amount_in, 2, None)?;
+            assert_split(&pools, token_in, amount_in, &two_way);
+            assert_eq!(two_way.allocations.len(), 2);
+            assert!(two_way.amount_out > best_single(&pools, token_in, amount_in));
+            assert!(two_way.amount_out <= split.amount_out);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2_closed_form() -> eyre::Result<()> {
+        let (usdc, weth) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let v2_pools = [
+            v2_pool(
+                100,
+                usdc,
+                weth,
+                30_000_000 * 10_u128.pow(6),
+                16_000 * 10_u128.pow(18),
+                300,
+            ),
+            v2_pool(
+                101,
+                usdc,
+                weth,
+                8_000_000 * 10_u128.pow(6),
+                4_300 * 10_u128.pow(18),
+                300,
+            ),
+            v2_pool(
+                102,
+                usdc,
+                weth,
+                12_000_000 * 10_u128.pow(6),
+                6_500 * 10_u128.pow(18),
+                100,
+            ),
+            //Priced too far off to take part
+            v2_pool(
+                103,
+                usdc,
+                weth,
+                10_000_000 * 10_u128.pow(6),
+                4_000 * 10_u128.pow(18),
+                300,
+            ),
+        ];
+        let pools = v2_pools
+            .iter()
+            .cloned()
+            .map(AMM::UniswapV2Pool)
+            .collect::<Vec<_>>();
+        let amount_in = U256::from(500_000 * 10_u128.pow(6));
+
+        let split = split_order(&pools, usdc, amount_in, 4, None)?;
+        assert_split(&pools, usdc, amount_in, &split);
+        assert!(split.amount_out > best_single(&pools, usdc, amount_in));
+        assert!(split.allocations.iter().all(|(idx, _)| *idx != 3));
+
+        //The closed form and water filling agree
+        let closed_form = equalize_v2(&v2_pools.iter().collect::<Vec<_>>(), usdc, amount_in);
+        let water_filled = water_fill(&pools.iter().collect::<Vec<_>>(), usdc, amount_in)?;
+        for (a, b) in closed_form.iter().zip(water_filled.iter()) {
+            let difference = if a > b { *a - *b } else { *b - *a };
+            assert!(difference <= amount_in / 1_000_000, "{a} {b}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_is_deterministic() -> eyre::Result<()> {
+        let pools = usdc_weth_pools()?;
+        let token_in = pools[0].tokens()[0];
+        let amount_in = U256::from(1_234_567_891_u64);
+
+        let split = split_order(&pools, token_in, amount_in, 3, None)?;
+        assert_split(&pools, token_in, amount_in, &split);
+        assert_eq!(split, split_order(&pools, token_in, amount_in, 3, None)?);
+
+        assert!(split_order(&pools, H160::from_low_u64_be(9), amount_in, 3, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gas_limits_splits() -> eyre::Result<()> {
+        let pools = usdc_weth_pools()?;
+        let (usdc, weth) = (pools[0].tokens()[0], pools[0].tokens()[1]);
+
+        //Price gas so a second swap costs twice what splitting the order saves
+        let amount_in = U256::from(100_000 * 10_u128.pow(6));
+        let best = best_single(&pools, usdc, amount_in);
+        let savings = split_order(&pools, usdc, amount_in, 3, None)?.amount_out - best;
+        let gas_model = GasCostModel::new(savings * U256::from(2) / U256::from(60_000), weth);
+
+        let split = split_order(&pools, usdc, amount_in, 3, Some(&gas_model))?;
+        assert_split(&pools, usdc, amount_in, &split);
+        assert_eq!(split.allocations.len(), 1);
+        assert_eq!(split.amount_out, best);
+        //Gas is paid in WETH, the token out, so needs no conversion
+        assert_eq!(split.gas_cost, gas_model.native_cost(split.gas_used));
+        assert_eq!(split.net_amount_out, split.amount_out - split.gas_cost);
+
+        //Larger orders save more than the gas of splitting
+        let amount_in = U256::from(2_000_000 * 10_u128.pow(6));
+        let split = split_order(&pools, usdc, amount_in, 3, Some(&gas_model))?;
+        assert_split(&pools, usdc, amount_in, &split);
+        assert!(split.allocations.len() > 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+//! Quoting service for applications embedding the state space.
+//!
+//! A `Quoter` keeps a snapshot of the state space taken after each block and answers `QuoteRequest`s sent through
+//! a `QuoterHandle` on blocking threads, at most `parallelism` at a time. Every request is answered from a single
+//! snapshot, so the route, its output and its price impact are all quoted at the block of the response.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TryRecvError, Receiver, Sender},
+        oneshot, Semaphore,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    amm::AMM,
+    errors::{QuoteError, RouteError},
+    metrics::Metrics,
+    routing::{find_best_route, AmmLookup, Route, RouteConstraints, TokenGraph},
+    state_space::state::{StateSpace, SyncProgress},
+};
+
+/// Swap to quote, routed through at most `max_hops` AMMs within `constraints`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuoteRequest {
+    pub token_in: H160,
+    pub token_out: H160,
+    pub amount_in: U256,
+    pub max_hops: usize,
+    #[serde(default)]
+    pub constraints: RouteConstraints,
+}
+
+impl QuoteRequest {
+    pub fn new(token_in: H160, token_out: H160, amount_in: U256, max_hops: usize) -> Self {
+        QuoteRequest {
+            token_in,
+            token_out,
+            amount_in,
+            max_hops,
+            constraints: RouteConstraints::default(),
+        }
+    }
+
+    pub fn with_constraints(mut self, constraints: RouteConstraints) -> Self {
+        self.constraints = constraints;
+        self
+    }
+}
+
+/// Best route of a `QuoteRequest`, quoted on the snapshot taken at `as_of_block`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuoteResponse {
+    pub route: Route<H160>,
+    pub amount_out: U256,
+    /// Price impact of the amount in on the route, as in `Route::price_impact`
+    pub price_impact: f64,
+    pub as_of_block: u64,
+}
+
+/// AMMs and token graph of the state space at a block, shared by every request answered from it
+#[derive(Debug, Clone)]
+pub struct QuoteSnapshot {
+    pub block_number: u64,
+    //AMMs are shared between snapshots, each block only clones the AMMs it updated
+    amms: HashMap<H160, Arc<AMM>>,
+    graph: TokenGraph<H160>,
+}
+
+impl QuoteSnapshot {
+    pub fn new(state: &StateSpace, block_number: u64) -> Self {
+        QuoteSnapshot {
+            block_number,
+            amms: state
+                .iter()
+                .map(|(address, amm)| (*address, Arc::new(amm.clone())))
+                .collect(),
+            graph: TokenGraph::from_state_space(state),
+        }
+    }
+
+    pub fn graph(&self) -> &TokenGraph<H160> {
+        &self.graph
+    }
+
+    pub fn len(&self) -> usize {
+        self.amms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.amms.is_empty()
+    }
+}
+
+impl AmmLookup<H160> for QuoteSnapshot {
+    fn get_amm(&self, id: H160) -> Option<&AMM> {
+        self.amms.get(&id).map(|amm| amm.as_ref())
+    }
+}
+
+/// Sends quote requests to a served `Quoter`, cheap to clone and share between tasks
+#[derive(Debug, Clone)]
+pub struct QuoterHandle {
+    requests: Sender<(
+        QuoteRequest,
+        oneshot::Sender<Result<QuoteResponse, QuoteError>>,
+    )>,
+    metrics: Metrics,
+}
+
+impl QuoterHandle {
+    /// Quotes `request`, waiting for a free slot if `parallelism` requests are already being answered
+    pub async fn quote(&self, request: QuoteRequest) -> Result<QuoteResponse, QuoteError> {
+        let _timer = self.metrics.quote_timer();
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.requests
+            .send((request, reply_tx))
+            .await
+            .map_err(|_| QuoteError::QuoterStopped)?;
+        self.metrics
+            .channel_depth("quote_requests", self.queue_depth());
+
+        reply_rx.await.map_err(|_| QuoteError::QuoterStopped)?
+    }
+
+    /// Requests waiting to be picked up by the quoter
+    pub fn queue_depth(&self) -> usize {
+        self.requests.max_capacity() - self.requests.capacity()
+    }
+}
+
+/// Answers quote requests from the latest snapshot of the state space.
+///
+/// Snapshots are taken by `update`, usually called by `listen` with the AMMs updated in each block as sent by
+/// `StateSpaceManager::listen_for_state_changes`. Blocks without updates do not produce a snapshot, so
+/// `as_of_block` is the last block that changed the state space.
+#[derive(Debug)]
+pub struct Quoter {
+    snapshot: RwLock<Arc<QuoteSnapshot>>,
+    parallelism: usize,
+    metrics: Metrics,
+}
+
+impl Quoter {
+    /// Quoter answering from `state` at `block_number` until the first update
+    pub fn new(state: &StateSpace, block_number: u64) -> Self {
+        Quoter {
+            snapshot: RwLock::new(Arc::new(QuoteSnapshot::new(state, block_number))),
+            parallelism: std::thread::available_parallelism()
+                .map(|parallelism| parallelism.get())
+                .unwrap_or(4),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Requests answered at the same time, defaults to the number of available cores
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Snapshot new requests are answered from
+    pub fn snapshot(&self) -> Arc<QuoteSnapshot> {
+        self.snapshot.read().expect("Quoter poisoned").clone()
+    }
+
+    /// Takes a new snapshot at `block_number`, copying `amms_updated` from `state` and sharing every other AMM with
+    /// the previous snapshot. Requests being answered keep the snapshot they started with.
+    ///
+    /// AMMs missing from `state` are removed, AMMs missing from the previous snapshot are added.
+    pub fn update(&self, state: &StateSpace, amms_updated: &[H160], block_number: u64) {
+        let mut snapshot = QuoteSnapshot::clone(&self.snapshot());
+
+        for address in amms_updated {
+            match state.get(address) {
+                Some(amm) => {
+                    if !snapshot.amms.contains_key(address) {
+                        snapshot.graph.insert(*address, amm);
+                    }
+                    snapshot.amms.insert(*address, Arc::new(amm.clone()));
+                }
+                None => {
+                    snapshot.amms.remove(address);
+                    snapshot.graph.remove(*address);
+                }
+            }
+        }
+
+        snapshot.block_number = block_number;
+        *self.snapshot.write().expect("Quoter poisoned") = Arc::new(snapshot);
+    }
+
+    /// Quotes `request` on the current snapshot, on the calling thread
+    pub fn quote(&self, request: &QuoteRequest) -> Result<QuoteResponse, QuoteError> {
+        let snapshot = self.snapshot();
+
+        let quote = find_best_route(
+            &snapshot.graph,
+            snapshot.as_ref(),
+            request.token_in,
+            request.token_out,
+            request.amount_in,
+            request.max_hops,
+            1,
+            None,
+            &request.constraints,
+        )?
+        .into_iter()
+        .next()
+        .ok_or(RouteError::NoRoute)?;
+
+        let price_impact = quote
+            .route
+            .price_impact(request.amount_in, snapshot.as_ref())?;
+
+        Ok(QuoteResponse {
+            route: quote.route,
+            amount_out: quote.amount_out,
+            price_impact,
+            as_of_block: snapshot.block_number,
+        })
+    }
+
+    /// Takes a snapshot after each block from the AMMs updated in it, as sent by
+    /// `StateSpaceManager::listen_for_state_changes`, until the channel closes.
+    ///
+    /// Updates that queued up while a snapshot was taken are merged into the next one.
+    pub fn listen(
+        self: Arc<Self>,
+        state: Arc<tokio::sync::RwLock<StateSpace>>,
+        sync_progress: Arc<SyncProgress>,
+        mut amms_updated: Receiver<Vec<H160>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(mut updated) = amms_updated.recv().await {
+                let mut closed = false;
+                loop {
+                    match amms_updated.try_recv() {
+                        Ok(more) => updated.extend(more),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            closed = true;
+                            break;
+                        }
+                    }
+                }
+                updated.sort_unstable();
+                updated.dedup();
+
+                let state = state.read().await;
+                let block_number = sync_progress.last_synced_block();
+                self.update(&state, &updated, block_number);
+                tracing::debug!(
+                    block_number,
+                    amms_updated = updated.len(),
+                    "updated quote snapshot"
+                );
+
+                if closed {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Serves requests sent through the returned handle until every handle is dropped. Requests are answered on
+    /// blocking threads, at most `parallelism` at a time, and the others wait in a channel of `channel_buffer`.
+    pub fn serve(self: Arc<Self>, channel_buffer: usize) -> (QuoterHandle, JoinHandle<()>) {
+        let (requests_tx, mut requests_rx) = mpsc::channel::<(
+            QuoteRequest,
+            oneshot::Sender<Result<QuoteResponse, QuoteError>>,
+        )>(channel_buffer);
+
+        let handle = QuoterHandle {
+            requests: requests_tx,
+            metrics: self.metrics.clone(),
+        };
+
+        let permits = Arc::new(Semaphore::new(self.parallelism));
+        let serve_handle = tokio::spawn(async move {
+            while let Some((request, reply)) = requests_rx.recv().await {
+                let permit = permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Quoter semaphore closed");
+
+                let quoter = self.clone();
+                tokio::task::spawn_blocking(move || {
+                    let response = quoter.quote(&request);
+                    drop(permit);
+
+                    //The requester may have given up waiting
+                    let _ = reply.send(response);
+                });
+            }
+        });
+
+        (handle, serve_handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AMM},
+        errors::{QuoteError, RouteError},
+        state_space::state::initialize_state_space,
+    };
+
+    use super::{QuoteRequest, Quoter};
+
+    fn token(id: u64) -> H160 {
+        H160::from_low_u64_be(id)
+    }
+
+    fn pool(address: u64, token_a: u64, token_b: u64, reserve_0: u128, reserve_1: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: token(token_a),
+            token_b: token(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_serve_quotes() -> eyre::Result<()> {
+        let mut state = initialize_state_space(vec![
+            pool(100, 1, 2, 10_u128.pow(24), 10_u128.pow(24)),
+            pool(101, 2, 3, 10_u128.pow(24), 10_u128.pow(24)),
+        ]);
+        let quoter = Arc::new(Quoter::new(&state, 10).with_parallelism(2));
+        let (handle, _serve_handle) = quoter.clone().serve(16);
+
+        let request = QuoteRequest::new(token(1), token(3), U256::exp10(18), 2);
+        let responses =
+            futures::future::try_join_all((0..8).map(|_| handle.quote(request))).await?;
+
+        for response in responses.iter() {
+            assert_eq!(response.as_of_block, 10);
+            assert_eq!(response.route.hops().len(), 2);
+            assert_eq!(response, &responses[0]);
+        }
+        assert!(responses[0].price_impact > 0.0);
+
+        //Requests started after an update are quoted at the new block
+        state.insert(
+            H160::from_low_u64_be(101),
+            pool(101, 2, 3, 10_u128.pow(24), 2 * 10_u128.pow(24)),
+        );
+        quoter.update(&state, &[H160::from_low_u64_be(101)], 11);
+
+        let response = handle.quote(request).await?;
+        assert_eq!(response.as_of_block, 11);
+        assert!(response.amount_out > responses[0].amount_out);
+
+        assert!(matches!(
+            handle
+                .quote(QuoteRequest::new(token(1), token(4), U256::exp10(18), 3))
+                .await,
+            Err(QuoteError::RouteError(RouteError::NoRoute))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_adds_and_removes_amms() {
+        let mut state = initialize_state_space(vec![pool(100, 1, 2, 1000, 1000)]);
+        let quoter = Quoter::new(&state, 1);
+
+        state.insert(H160::from_low_u64_be(101), pool(101, 2, 3, 1000, 1000));
+        state.remove(&H160::from_low_u64_be(100));
+        quoter.update(
+            &state,
+            &[H160::from_low_u64_be(100), H160::from_low_u64_be(101)],
+            2,
+        );
+
+        let snapshot = quoter.snapshot();
+        assert_eq!(snapshot.block_number, 2);
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot
+            .graph()
+            .pools_for_pair(token(1), token(2))
+            .is_empty());
+        assert_eq!(
+            snapshot.graph().pools_for_pair(token(2), token(3)),
+            &[H160::from_low_u64_be(101)]
+        );
+    }
+}
@@ -0,0 +1,3 @@
+/// Requires the `revm` feature
+#[cfg(feature = "revm")]
+pub mod revm;
@@ -0,0 +1,521 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use ::revm::{
+    db::{CacheDB, EmptyDB},
+    primitives::{
+        AccountInfo, Bytecode, ExecutionResult, Output, TransactTo, B160, B256, U256 as RevmU256,
+    },
+    Database, EVM,
+};
+use ethers::{
+    abi::{self, Token},
+    providers::Middleware,
+    types::{BlockId, BlockNumber, Bytes, H160, H256, U256},
+    utils::id,
+};
+use thiserror::Error;
+
+use crate::errors::SwapSimulationError;
+
+/// Rounds of fetching missing and stale state before a quote gives up
+pub const MAX_FETCH_ROUNDS: usize = 32;
+
+//Account sending the quote calls, holding nothing
+const CALLER: H160 = H160([0x11; 20]);
+
+/// Encodes the quote call of `(token_in, amount_in)`
+pub type EncodeQuote = Arc<dyn Fn(H160, U256) -> Bytes + Send + Sync>;
+/// Decodes the amount out from the output of the quote call
+pub type DecodeQuote = Arc<dyn Fn(&[u8]) -> Option<U256> + Send + Sync>;
+
+/// Call executed to quote a pool
+#[derive(Clone)]
+pub enum QuoteCall {
+    /// `getAmountOut(uint256 amountIn, address tokenIn)` on the pool itself, as exposed by Solidly style pairs
+    GetAmountOut,
+    /// `quoteExactInputSingle` of the Uniswap V3 QuoterV2 at `quoter`, for V3 forks with custom logic
+    QuoterV2 {
+        quoter: H160,
+        token_out: H160,
+        fee: u32,
+    },
+    /// Any other view or state changing call at `to`, the state changes being discarded
+    Custom {
+        to: H160,
+        encode: EncodeQuote,
+        decode: DecodeQuote,
+    },
+}
+
+impl std::fmt::Debug for QuoteCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuoteCall::GetAmountOut => write!(f, "GetAmountOut"),
+            QuoteCall::QuoterV2 {
+                quoter,
+                token_out,
+                fee,
+            } => f
+                .debug_struct("QuoterV2")
+                .field("quoter", quoter)
+                .field("token_out", token_out)
+                .field("fee", fee)
+                .finish(),
+            QuoteCall::Custom { to, .. } => f.debug_struct("Custom").field("to", to).finish(),
+        }
+    }
+}
+
+impl QuoteCall {
+    fn target(&self, pool: H160) -> H160 {
+        match self {
+            QuoteCall::GetAmountOut => pool,
+            QuoteCall::QuoterV2 { quoter, .. } => *quoter,
+            QuoteCall::Custom { to, .. } => *to,
+        }
+    }
+
+    fn encode(&self, token_in: H160, amount_in: U256) -> Bytes {
+        match self {
+            QuoteCall::GetAmountOut => {
+                let mut calldata = id("getAmountOut(uint256,address)").to_vec();
+                calldata.extend(abi::encode(&[
+                    Token::Uint(amount_in),
+                    Token::Address(token_in),
+                ]));
+                calldata.into()
+            }
+            QuoteCall::QuoterV2 { token_out, fee, .. } => {
+                let mut calldata =
+                    id("quoteExactInputSingle((address,address,uint256,uint24,uint160))").to_vec();
+                calldata.extend(abi::encode(&[Token::Tuple(vec![
+                    Token::Address(token_in),
+                    Token::Address(*token_out),
+                    Token::Uint(amount_in),
+                    Token::Uint(U256::from(*fee)),
+                    Token::Uint(U256::zero()),
+                ])]));
+                calldata.into()
+            }
+            QuoteCall::Custom { encode, .. } => encode(token_in, amount_in),
+        }
+    }
+
+    fn decode(&self, output: &[u8]) -> Option<U256> {
+        match self {
+            //Both return the amount out as their first word
+            QuoteCall::GetAmountOut | QuoteCall::QuoterV2 { .. } => {
+                output.get(..32).map(U256::from_big_endian)
+            }
+            QuoteCall::Custom { decode, .. } => decode(output),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RevmQuoteError<M: Middleware> {
+    #[error("Middleware error")]
+    MiddlewareError(<M as Middleware>::Error),
+    #[error("EVM error: {0}")]
+    EvmError(String),
+    #[error("Quote call reverted")]
+    Reverted(Bytes),
+    #[error("Quote call halted: {0}")]
+    Halted(String),
+    #[error("Could not decode the output of the quote call")]
+    InvalidOutput(Bytes),
+    #[error("State read by the quote call is not cached")]
+    StateNotCached,
+    #[error("State read by the quote call was still changing after {0} fetch rounds")]
+    TooManyFetchRounds(usize),
+}
+
+//Account info, or a storage slot of the account
+type StateKey = (H160, Option<U256>);
+
+//Contract state fetched so far, and the block each entry was fetched at
+#[derive(Debug)]
+struct RevmCache {
+    db: CacheDB<EmptyDB>,
+    fetched_at: HashMap<StateKey, u64>,
+}
+
+/// Quotes pools the local math can not model by executing their contracts in revm.
+///
+/// The code and storage read by a quote are fetched on first use into a `CacheDB` kept across quotes, so repeated
+/// quotes only go to the middleware for state they have not read yet, or read longer than `max_staleness` blocks
+/// ago. Only the stale entries the quote reads are fetched again, at the current block.
+#[derive(Debug)]
+pub struct RevmQuoter<M> {
+    middleware: Arc<M>,
+    max_staleness: u64,
+    calls: RwLock<HashMap<H160, QuoteCall>>,
+    cache: Mutex<RevmCache>,
+}
+
+impl<M: 'static + Middleware> RevmQuoter<M> {
+    pub fn new(middleware: Arc<M>) -> Self {
+        RevmQuoter {
+            middleware,
+            max_staleness: 0,
+            calls: RwLock::new(HashMap::new()),
+            cache: Mutex::new(RevmCache {
+                db: CacheDB::new(EmptyDB::default()),
+                fetched_at: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Blocks state read by a quote can be behind the current block before it is fetched again
+    pub fn with_max_staleness(mut self, max_staleness: u64) -> Self {
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    /// Quotes `pool` with `call`, pools without a registered call are quoted with `QuoteCall::GetAmountOut`
+    pub fn register(&self, pool: H160, call: QuoteCall) {
+        self.calls
+            .write()
+            .expect("Revm quoter poisoned")
+            .insert(pool, call);
+    }
+
+    /// Marks the state of `address` as changed, so the next quote reading it fetches it again
+    pub fn invalidate(&self, address: H160) {
+        self.cache
+            .lock()
+            .expect("Revm quoter poisoned")
+            .fetched_at
+            .retain(|(account, _), _| *account != address);
+    }
+
+    /// Amount out of swapping `amount_in` of `token_in` on `amm_address`, executed against the current block
+    pub async fn quote(
+        &self,
+        amm_address: H160,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, RevmQuoteError<M>> {
+        let block_number = self
+            .middleware
+            .get_block_number()
+            .await
+            .map_err(RevmQuoteError::MiddlewareError)?
+            .as_u64();
+        let oldest_fresh_block = block_number.saturating_sub(self.max_staleness);
+
+        for _ in 0..MAX_FETCH_ROUNDS {
+            let (result, missing) = self.execute(amm_address, token_in, amount_in, |fetched_at| {
+                fetched_at.is_some_and(|block| block >= oldest_fresh_block)
+            });
+
+            if missing.is_empty() {
+                return result;
+            }
+
+            self.fetch(missing, block_number).await?;
+        }
+
+        Err(RevmQuoteError::TooManyFetchRounds(MAX_FETCH_ROUNDS))
+    }
+
+    /// Same as `quote` using only cached state, regardless of its age, without going to the middleware
+    pub fn quote_offline(
+        &self,
+        amm_address: H160,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, RevmQuoteError<M>> {
+        let (result, missing) = self.execute(amm_address, token_in, amount_in, |fetched_at| {
+            fetched_at.is_some()
+        });
+
+        if missing.is_empty() {
+            result
+        } else {
+            Err(RevmQuoteError::StateNotCached)
+        }
+    }
+
+    //Runs the quote call, returning its result and the state it read that is not `usable`, which invalidates the result
+    fn execute<F>(
+        &self,
+        amm_address: H160,
+        token_in: H160,
+        amount_in: U256,
+        usable: F,
+    ) -> (Result<U256, RevmQuoteError<M>>, HashSet<StateKey>)
+    where
+        F: Fn(Option<u64>) -> bool,
+    {
+        let call = self
+            .calls
+            .read()
+            .expect("Revm quoter poisoned")
+            .get(&amm_address)
+            .cloned()
+            .unwrap_or(QuoteCall::GetAmountOut);
+
+        let mut cache = self.cache.lock().expect("Revm quoter poisoned");
+        let RevmCache { db, fetched_at } = &mut *cache;
+
+        let mut evm = EVM::new();
+        evm.database(RecordingDb {
+            db,
+            fetched_at,
+            usable: &usable,
+            missing: HashSet::new(),
+        });
+        evm.env.tx.caller = B160(CALLER.0);
+        evm.env.tx.transact_to = TransactTo::Call(B160(call.target(amm_address).0));
+        evm.env.tx.data = call.encode(token_in, amount_in).0;
+        evm.env.tx.gas_limit = 30_000_000;
+
+        //The state changes of the call are discarded, the cache only holds fetched state
+        let result = evm.transact();
+        let missing = evm.db.take().map(|db| db.missing).unwrap_or_default();
+
+        let result = match result {
+            Ok(result_and_state) => match result_and_state.result {
+                ExecutionResult::Success {
+                    output: Output::Call(output),
+                    ..
+                } => call
+                    .decode(&output)
+                    .ok_or(RevmQuoteError::InvalidOutput(output.into())),
+                ExecutionResult::Success { output, .. } => {
+                    Err(RevmQuoteError::InvalidOutput(output.into_data().into()))
+                }
+                ExecutionResult::Revert { output, .. } => {
+                    Err(RevmQuoteError::Reverted(output.into()))
+                }
+                ExecutionResult::Halt { reason, .. } => {
+                    Err(RevmQuoteError::Halted(format!("{reason:?}")))
+                }
+            },
+            Err(error) => Err(RevmQuoteError::EvmError(format!("{error:?}"))),
+        };
+
+        (result, missing)
+    }
+
+    //Fetches `keys` at `block_number` into the cache
+    async fn fetch(
+        &self,
+        keys: HashSet<StateKey>,
+        block_number: u64,
+    ) -> Result<(), RevmQuoteError<M>> {
+        let block = Some(BlockId::Number(BlockNumber::Number(block_number.into())));
+        let mut accounts = vec![];
+        let mut slots = vec![];
+
+        for (address, slot) in keys {
+            match slot {
+                None => {
+                    let balance = self.middleware.get_balance(address, block);
+                    let nonce = self.middleware.get_transaction_count(address, block);
+                    let code = self.middleware.get_code(address, block);
+                    let (balance, nonce, code) = tokio::try_join!(balance, nonce, code)
+                        .map_err(RevmQuoteError::MiddlewareError)?;
+
+                    let code = Bytecode::new_raw(code.0);
+                    accounts.push((
+                        address,
+                        AccountInfo::new(
+                            to_revm_u256(balance),
+                            nonce.as_u64(),
+                            code.hash_slow(),
+                            code,
+                        ),
+                    ));
+                }
+                Some(slot) => {
+                    let mut key = H256::zero();
+                    slot.to_big_endian(key.as_bytes_mut());
+                    let value = self
+                        .middleware
+                        .get_storage_at(address, key, block)
+                        .await
+                        .map_err(RevmQuoteError::MiddlewareError)?;
+
+                    slots.push((address, slot, U256::from_big_endian(value.as_bytes())));
+                }
+            }
+        }
+
+        let mut cache = self.cache.lock().expect("Revm quoter poisoned");
+        //Accounts first, so their storage is inserted into the fetched account
+        for (address, info) in accounts {
+            cache.db.insert_account_info(B160(address.0), info);
+            cache.fetched_at.insert((address, None), block_number);
+        }
+        for (address, slot, value) in slots {
+            cache
+                .db
+                .insert_account_storage(B160(address.0), to_revm_u256(slot), to_revm_u256(value))
+                .expect("Empty database is infallible");
+            cache.fetched_at.insert((address, Some(slot)), block_number);
+        }
+
+        Ok(())
+    }
+}
+
+//Reads from the cache, recording the state that is not cached or too old to be used
+struct RecordingDb<'a, F> {
+    db: &'a mut CacheDB<EmptyDB>,
+    fetched_at: &'a HashMap<StateKey, u64>,
+    usable: &'a F,
+    missing: HashSet<StateKey>,
+}
+
+impl<'a, F: Fn(Option<u64>) -> bool> RecordingDb<'a, F> {
+    fn check(&mut self, key: StateKey) -> bool {
+        if (self.usable)(self.fetched_at.get(&key).copied()) {
+            true
+        } else {
+            self.missing.insert(key);
+            false
+        }
+    }
+}
+
+impl<'a, F: Fn(Option<u64>) -> bool> Database for RecordingDb<'a, F> {
+    type Error = std::convert::Infallible;
+
+    fn basic(&mut self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        if !self.check((H160(address.0), None)) {
+            //Executes on as an empty account, the result is discarded once the account is fetched
+            return Ok(Some(AccountInfo::default()));
+        }
+
+        self.db.basic(address)
+    }
+
+    fn code_by_hash(&mut self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.db.code_by_hash(code_hash)
+    }
+
+    fn storage(&mut self, address: B160, index: RevmU256) -> Result<RevmU256, Self::Error> {
+        let slot = U256(*index.as_limbs());
+        if !self.check((H160(address.0), Some(slot))) {
+            return Ok(RevmU256::ZERO);
+        }
+
+        self.db.storage(address, index)
+    }
+
+    fn block_hash(&mut self, _number: RevmU256) -> Result<B256, Self::Error> {
+        Ok(B256::zero())
+    }
+}
+
+fn to_revm_u256(value: U256) -> RevmU256 {
+    RevmU256::from_limbs(value.0)
+}
+
+/// Quoter used by `AMM::simulate_swap` for AMMs flagged by `AmmState::requires_rpc_quote`
+pub trait OfflineQuoter: Send + Sync {
+    fn quote_offline(
+        &self,
+        amm_address: H160,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError>;
+}
+
+impl<M: 'static + Middleware> OfflineQuoter for RevmQuoter<M> {
+    fn quote_offline(
+        &self,
+        amm_address: H160,
+        token_in: H160,
+        amount_in: U256,
+    ) -> Result<U256, SwapSimulationError> {
+        RevmQuoter::quote_offline(self, amm_address, token_in, amount_in).map_err(|error| {
+            tracing::debug!(?amm_address, ?error, "revm quote failed");
+            SwapSimulationError::RpcQuoteUnavailable(amm_address)
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref FALLBACK_QUOTER: RwLock<Option<Arc<dyn OfflineQuoter>>> = RwLock::new(None);
+}
+
+/// Sets the quoter simulating AMMs that require an RPC quote, quoting them from its cache.
+///
+/// Quotes need the state they read to be cached by an earlier `RevmQuoter::quote`, typically of every flagged AMM
+/// once per block, since `AMM::simulate_swap` can not go to the middleware.
+pub fn set_fallback_quoter(quoter: Option<Arc<dyn OfflineQuoter>>) {
+    *FALLBACK_QUOTER.write().expect("Fallback quoter poisoned") = quoter;
+}
+
+pub(crate) fn fallback_quote(
+    amm_address: H160,
+    token_in: H160,
+    amount_in: U256,
+) -> Result<U256, SwapSimulationError> {
+    let quoter = FALLBACK_QUOTER
+        .read()
+        .expect("Fallback quoter poisoned")
+        .clone();
+
+    match quoter {
+        Some(quoter) => quoter.quote_offline(amm_address, token_in, amount_in),
+        None => Err(SwapSimulationError::RpcQuoteUnavailable(amm_address)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use ethers::{
+        providers::{Http, Provider},
+        types::{H160, U256},
+    };
+
+    use crate::amm::{uniswap_v3::UniswapV3Pool, AmmState};
+
+    use super::{QuoteCall, RevmQuoter};
+
+    #[tokio::test]
+    async fn test_quote_matches_local_math() -> eyre::Result<()> {
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+
+        //USDC/WETH 0.05%
+        let pool_address = H160::from_str("0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640")?;
+        let pool = UniswapV3Pool::new_from_address(pool_address, 100, middleware.clone()).await?;
+
+        let quoter = RevmQuoter::new(middleware).with_max_staleness(10);
+        quoter.register(
+            pool_address,
+            QuoteCall::QuoterV2 {
+                quoter: H160::from_str("0x61fFE014bA17989E743c5F6cB21bF9697530B21e")?,
+                token_out: pool.token_b,
+                fee: 500,
+            },
+        );
+
+        let amount_in = U256::from(1_000_000_000_u64);
+        let quoted = quoter.quote(pool_address, pool.token_a, amount_in).await?;
+        assert_eq!(quoted, pool.simulate_swap(pool.token_a, amount_in)?);
+
+        //Repeated quotes are served from the cache
+        assert_eq!(
+            quoter.quote_offline(pool_address, pool.token_a, amount_in)?,
+            quoted
+        );
+
+        quoter.invalidate(pool_address);
+        assert!(quoter
+            .quote_offline(pool_address, pool.token_a, amount_in)
+            .is_err());
+
+        Ok(())
+    }
+}
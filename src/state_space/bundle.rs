@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+
+use ethers::types::{H160, I256, U256};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::SwapSimulationError,
+};
+
+use super::state::StateSpace;
+
+/// Swap of a bundle, as executed against the AMM state left by the previous swaps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleSwap {
+    pub pool: H160,
+    pub token_in: H160,
+    pub token_out: H160,
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+/// Outcome of a bundle simulated by `simulate_bundle`
+#[derive(Debug, Clone)]
+pub struct BundleResult {
+    pub swaps: Vec<BundleSwap>,
+    /// State of each AMM swapped through after the whole bundle
+    pub amms: HashMap<H160, AMM>,
+    /// Net amount of each token received, negative when spent, saturating at the bounds of `I256`
+    pub balance_changes: HashMap<H160, I256>,
+}
+
+impl BundleResult {
+    /// Output of the last swap of the bundle
+    pub fn amount_out(&self) -> U256 {
+        self.swaps
+            .last()
+            .map(|swap| swap.amount_out)
+            .unwrap_or_default()
+    }
+}
+
+/// Simulates `swaps` of `(pool, token_in, amount_in)` executed in order in one transaction, each seeing the
+/// effects of the previous ones on the pools, without modifying `state`.
+///
+/// The AMMs involved are copied from `state` before the first swap, so holding the lock of a shared state space
+/// for the duration of the call simulates the bundle against a consistent block. Fails with the index of the first
+/// swap whose pool is missing, does not trade its token in or fails to simulate.
+pub fn simulate_bundle(
+    state: &StateSpace,
+    swaps: &[(H160, H160, U256)],
+) -> Result<BundleResult, SwapSimulationError> {
+    let swaps = swaps
+        .iter()
+        .map(|(pool, token_in, amount_in)| (*pool, *token_in, *amount_in, U256::zero()))
+        .collect::<Vec<_>>();
+
+    simulate_bundle_checked(state, &swaps)
+}
+
+/// Same as `simulate_bundle`, with swaps of `(pool, token_in, amount_in, min_out)` failing with
+/// `SwapSimulationError::InsufficientOutput` when their output is below `min_out`, as a router reverts
+pub fn simulate_bundle_checked(
+    state: &StateSpace,
+    swaps: &[(H160, H160, U256, U256)],
+) -> Result<BundleResult, SwapSimulationError> {
+    let mut amms: HashMap<H160, AMM> = HashMap::new();
+    for (step, (pool, ..)) in swaps.iter().enumerate() {
+        if amms.contains_key(pool) {
+            continue;
+        }
+
+        match state.get(pool) {
+            Some(amm) => {
+                amms.insert(*pool, amm.clone());
+            }
+            None => {
+                return Err(SwapSimulationError::BundleStep {
+                    step,
+                    source: Box::new(SwapSimulationError::AmmNotFound(step)),
+                })
+            }
+        }
+    }
+
+    let mut result = BundleResult {
+        swaps: Vec::with_capacity(swaps.len()),
+        amms: HashMap::new(),
+        balance_changes: HashMap::new(),
+    };
+
+    for (step, (pool, token_in, amount_in, min_out)) in swaps.iter().enumerate() {
+        let amm = amms.get_mut(pool).expect("AMMs of the bundle are copied");
+
+        let swap = simulate_step(amm, *token_in, *amount_in, *min_out).map_err(|source| {
+            SwapSimulationError::BundleStep {
+                step,
+                source: Box::new(source),
+            }
+        })?;
+
+        let balance_in = result.balance_changes.entry(swap.token_in).or_default();
+        *balance_in =
+            balance_in.saturating_sub(I256::try_from(swap.amount_in).unwrap_or(I256::MAX));
+        let balance_out = result.balance_changes.entry(swap.token_out).or_default();
+        *balance_out =
+            balance_out.saturating_add(I256::try_from(swap.amount_out).unwrap_or(I256::MAX));
+
+        result.swaps.push(swap);
+    }
+
+    result.amms = amms;
+    Ok(result)
+}
+
+fn simulate_step(
+    amm: &mut AMM,
+    token_in: H160,
+    amount_in: U256,
+    min_out: U256,
+) -> Result<BundleSwap, SwapSimulationError> {
+    let token_out = amm
+        .opp_token(token_in)
+        .ok_or(SwapSimulationError::TokenNotInPool(token_in))?;
+
+    let amount_out =
+        SwapSimulationError::check_output(amm.simulate_swap_mut(token_in, amount_in)?, min_out)?;
+
+    Ok(BundleSwap {
+        pool: amm.address(),
+        token_in,
+        token_out,
+        amount_in,
+        amount_out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, I256, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, AmmState, AMM},
+        errors::SwapSimulationError,
+        state_space::state::initialize_state_space,
+    };
+
+    use super::{simulate_bundle, simulate_bundle_checked};
+
+    fn pool(address: u64, token_a: u64, token_b: u64) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: H160::from_low_u64_be(token_a),
+            token_b: H160::from_low_u64_be(token_b),
+            reserve_0: 10_u128.pow(24),
+            reserve_1: 10_u128.pow(24),
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_simulate_bundle() -> eyre::Result<()> {
+        let state = initialize_state_space(vec![pool(100, 1, 2), pool(101, 2, 3)]);
+        let (pool_a, pool_b) = (H160::from_low_u64_be(100), H160::from_low_u64_be(101));
+        let (token_1, token_2, token_3) = (
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(3),
+        );
+        let amount_in = U256::exp10(21);
+
+        let first_out = state[&pool_a].simulate_swap(token_1, amount_in)?;
+        let bundle = simulate_bundle(
+            &state,
+            &[
+                (pool_a, token_1, amount_in),
+                (pool_a, token_1, amount_in),
+                (pool_b, token_2, first_out),
+            ],
+        )?;
+
+        //The second swap sees the reserves left by the first
+        assert_eq!(bundle.swaps[0].amount_out, first_out);
+        assert!(bundle.swaps[1].amount_out < first_out);
+        assert_eq!(bundle.swaps[2].token_out, token_3);
+
+        let mut expected = state[&pool_a].clone();
+        expected.simulate_swap_mut(token_1, amount_in)?;
+        let second_out = expected.simulate_swap_mut(token_1, amount_in)?;
+        assert_eq!(bundle.swaps[1].amount_out, second_out);
+        match (&bundle.amms[&pool_a], &expected) {
+            (AMM::UniswapV2Pool(bundled), AMM::UniswapV2Pool(expected)) => {
+                assert_eq!(bundled.reserve_0, expected.reserve_0);
+                assert_eq!(bundled.reserve_1, expected.reserve_1);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            bundle.balance_changes[&token_1],
+            -I256::from_raw(amount_in * U256::from(2))
+        );
+        assert_eq!(bundle.balance_changes[&token_2], I256::from_raw(second_out));
+        assert_eq!(
+            bundle.balance_changes[&token_3],
+            I256::from_raw(bundle.amount_out())
+        );
+
+        //The state space is left untouched
+        match &state[&pool_a] {
+            AMM::UniswapV2Pool(pool) => assert_eq!(pool.reserve_0, 10_u128.pow(24)),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_bundle_failure() {
+        let state = initialize_state_space(vec![pool(100, 1, 2)]);
+        let pool_a = H160::from_low_u64_be(100);
+        let token_1 = H160::from_low_u64_be(1);
+
+        let missing_pool = simulate_bundle(
+            &state,
+            &[
+                (pool_a, token_1, U256::exp10(18)),
+                (H160::from_low_u64_be(101), token_1, U256::exp10(18)),
+            ],
+        );
+        assert!(matches!(
+            missing_pool,
+            Err(SwapSimulationError::BundleStep { step: 1, .. })
+        ));
+
+        let wrong_token = simulate_bundle(
+            &state,
+            &[
+                (pool_a, token_1, U256::exp10(18)),
+                (pool_a, token_1, U256::exp10(18)),
+                (pool_a, H160::from_low_u64_be(3), U256::exp10(18)),
+            ],
+        );
+        match wrong_token {
+            Err(SwapSimulationError::BundleStep { step, source }) => {
+                assert_eq!(step, 2);
+                assert!(matches!(*source, SwapSimulationError::TokenNotInPool(_)));
+            }
+            _ => panic!("bundle should fail on its third swap"),
+        }
+    }
+
+    #[test]
+    fn test_simulate_bundle_min_out() -> eyre::Result<()> {
+        let state = initialize_state_space(vec![pool(100, 1, 2)]);
+        let pool_a = H160::from_low_u64_be(100);
+        let (token_1, token_2) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+
+        let unchecked = simulate_bundle(
+            &state,
+            &[
+                (pool_a, token_1, U256::exp10(18)),
+                (pool_a, token_1, U256::exp10(18)),
+            ],
+        )?;
+        let (first, second) = (unchecked.swaps[0].amount_out, unchecked.swaps[1].amount_out);
+
+        //The second swap gets a worse price than the first, so a minimum met by the first one fails on it
+        let checked = simulate_bundle_checked(
+            &state,
+            &[
+                (pool_a, token_1, U256::exp10(18), first),
+                (pool_a, token_1, U256::exp10(18), first),
+            ],
+        );
+        match checked {
+            Err(error @ SwapSimulationError::BundleStep { step: 1, .. }) => {
+                assert_eq!(error.shortfall(), Some(first - second));
+            }
+            _ => panic!("bundle should fail on its second swap"),
+        }
+
+        let met = simulate_bundle_checked(
+            &state,
+            &[
+                (pool_a, token_1, U256::exp10(18), first),
+                (pool_a, token_2, U256::exp10(18), U256::zero()),
+            ],
+        )?;
+        assert_eq!(met.swaps[0].amount_out, first);
+
+        Ok(())
+    }
+}
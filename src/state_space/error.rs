@@ -8,13 +8,11 @@ use ethers::signers::WalletError;
 use ethers::types::{Block, H160, H256};
 use thiserror::Error;
 
-use super::state::MiddlewarePubsub;
-
 #[derive(Error, Debug)]
 pub enum StateSpaceError<M, P>
 where
     M: Middleware,
-    P: MiddlewarePubsub,
+    P: Middleware,
 {
     #[error("Middleware error")]
     MiddlewareError(<M as Middleware>::Error),
@@ -48,6 +46,8 @@ where
     BlockSendError(#[from] tokio::sync::mpsc::error::SendError<Block<H256>>),
     #[error("Already listening for state changes")]
     AlreadyListeningForStateChanges,
+    #[error("Timed out waiting for block {0} to be synced")]
+    WaitForBlockTimeout(u64),
 }
 
 #[derive(Error, Debug)]
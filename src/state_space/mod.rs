@@ -1,2 +1,12 @@
+pub mod bundle;
+/// Requires the `state-space` feature
+#[cfg(feature = "state-space")]
 pub mod error;
+pub mod price;
+/// Requires the `state-space` feature
+#[cfg(feature = "state-space")]
+pub mod refresh;
 pub mod state;
+
+pub use bundle::{simulate_bundle, simulate_bundle_checked, BundleResult, BundleSwap};
+pub use price::{aggregate_price, AggregatePrice, DepthWeighting};
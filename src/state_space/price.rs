@@ -0,0 +1,537 @@
+use ethers::types::{H160, U256};
+
+use crate::{
+    amm::{
+        balancer::BalancerWeightedPool,
+        bancor_v3::BancorV3Pool,
+        curve::CurveStableSwapPool,
+        curve_crypto::CurveCryptoPool,
+        dodo::DodoPool,
+        erc_4626::ERC4626Vault,
+        kyber_elastic::KyberElasticPool,
+        liquidity_book::{get_price_from_id, LiquidityBookPool},
+        maverick::MaverickPool,
+        uniswap_v2::UniswapV2Pool,
+        uniswap_v3::UniswapV3Pool,
+        velodrome::VelodromePool,
+        AmmState, AMM,
+    },
+    errors::ArithmeticError,
+    math::fixed_point::{price_x128_to_f64, u256_to_f64_lossy},
+};
+
+/// How `aggregate_price` weighs and excludes the pools of a pair
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthWeighting {
+    /// Pools priced further than this fraction from the weighted median are excluded, 0.05 is 5%
+    pub max_deviation: f64,
+    /// Ticks on each side of the current tick of a V3 pool whose active liquidity counts as depth
+    pub tick_range: i32,
+}
+
+impl Default for DepthWeighting {
+    fn default() -> Self {
+        DepthWeighting {
+            max_deviation: 0.05,
+            tick_range: 100,
+        }
+    }
+}
+
+impl DepthWeighting {
+    pub fn new(max_deviation: f64) -> Self {
+        DepthWeighting {
+            max_deviation,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_tick_range(mut self, tick_range: i32) -> Self {
+        self.tick_range = tick_range;
+        self
+    }
+}
+
+/// Price of a pair aggregated across its pools
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregatePrice {
+    /// Depth weighted mean of the prices of the contributing pools, in whole `token_b` per `token_a`
+    pub mid: f64,
+    /// Sum of the depth of the contributing pools, valued in raw units of `token_b`
+    pub total_depth: f64,
+    pub contributing_pools: Vec<H160>,
+    /// Pools priced too far from the weighted median to contribute
+    pub excluded_pools: Vec<H160>,
+}
+
+/// Depth weighted price of `token_a` in `token_b` across the AMMs of `amms` trading the pair.
+///
+/// Depth is the value of the reserves of V2, Velodrome and DODO pools, of the trading liquidity of Bancor V3 pools, of
+/// the tokens held by the active liquidity of V3, V4, KyberSwap Elastic and Algebra pools within `weighting.tick_range`
+/// ticks of the current tick, of the `token_b` reserve of vaults, of every balance of Curve StableSwap, Curve crypto
+/// and Balancer pools and of the bins of Liquidity Book and Maverick pools within as many basis points of the active
+/// bin. Pools priced further than `weighting.max_deviation` from the depth weighted median are excluded and reported,
+/// the others are averaged by depth. Custom AMMs and pools without depth or a finite price are ignored.
+///
+/// Passing the pools of the pair from `TokenGraph::pools_for_pair` rather than the whole state space keeps the
+/// call cheap enough to run every block. Returns None when no pool of the pair has depth.
+pub fn aggregate_price<'a>(
+    amms: impl IntoIterator<Item = &'a AMM>,
+    token_a: H160,
+    token_b: H160,
+    weighting: DepthWeighting,
+) -> Option<AggregatePrice> {
+    //(address, price, depth) of each pool of the pair
+    let mut quotes = amms
+        .into_iter()
+        .filter(|amm| {
+            let tokens = amm.tokens();
+            token_a != token_b && tokens.contains(&token_a) && tokens.contains(&token_b)
+        })
+        .filter_map(|amm| {
+            let price = pair_price(amm, token_a, token_b).ok()?;
+            let depth = pool_depth(amm, token_b, weighting.tick_range)?;
+
+            (price.is_finite() && price > 0.0 && depth.is_finite() && depth > 0.0)
+                .then(|| (amm.address(), price, depth))
+        })
+        .collect::<Vec<_>>();
+
+    if quotes.is_empty() {
+        return None;
+    }
+
+    quotes.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    let half_depth = quotes.iter().map(|(_, _, depth)| depth).sum::<f64>() / 2.0;
+    let mut cumulative_depth = 0.0;
+    let median = quotes
+        .iter()
+        .find(|(_, _, depth)| {
+            cumulative_depth += depth;
+            cumulative_depth >= half_depth
+        })
+        .map(|(_, price, _)| *price)
+        .unwrap_or(quotes[quotes.len() - 1].1);
+
+    let mut aggregate = AggregatePrice {
+        mid: 0.0,
+        total_depth: 0.0,
+        contributing_pools: vec![],
+        excluded_pools: vec![],
+    };
+    for (address, price, depth) in quotes {
+        if (price / median - 1.0).abs() > weighting.max_deviation {
+            aggregate.excluded_pools.push(address);
+        } else {
+            aggregate.mid += price * depth;
+            aggregate.total_depth += depth;
+            aggregate.contributing_pools.push(address);
+        }
+    }
+    aggregate.mid /= aggregate.total_depth;
+
+    Some(aggregate)
+}
+
+//Price of `token_a` in `token_b`, Curve and Balancer pools price against the first other token by default
+pub(crate) fn pair_price(amm: &AMM, token_a: H160, token_b: H160) -> Result<f64, ArithmeticError> {
+    match amm {
+        AMM::CurveStableSwapPool(pool) => pool.calculate_price_to(token_a, token_b),
+        AMM::CurveCryptoPool(pool) => pool.calculate_price_to(token_a, token_b),
+        AMM::BalancerWeightedPool(pool) => pool.calculate_price_to(token_a, token_b),
+        amm => amm.calculate_price(token_a),
+    }
+}
+
+//Depth of `amm` valued in raw units of `token_b`
+pub(crate) fn pool_depth(amm: &AMM, token_b: H160, tick_range: i32) -> Option<f64> {
+    match amm {
+        AMM::UniswapV2Pool(pool) => Some(uniswap_v2_depth(pool, token_b)),
+        AMM::UniswapV3Pool(pool) => uniswap_v3_depth(pool, token_b, tick_range),
+        AMM::ERC4626Vault(vault) => Some(erc_4626_depth(vault, token_b)),
+        AMM::CurveStableSwapPool(pool) => curve_stable_swap_depth(pool, token_b),
+        AMM::BalancerWeightedPool(pool) => balancer_weighted_depth(pool, token_b),
+        AMM::VelodromePool(pool) => velodrome_depth(pool, token_b),
+        AMM::UniswapV4Pool(pool) => uniswap_v3_depth(&pool.pool, token_b, tick_range),
+        AMM::LiquidityBookPool(pool) => liquidity_book_depth(pool, token_b, tick_range),
+        AMM::MaverickPool(pool) => maverick_depth(pool, token_b, tick_range),
+        AMM::DodoPool(pool) => dodo_depth(pool, token_b),
+        AMM::KyberElasticPool(pool) => kyber_elastic_depth(pool, token_b, tick_range),
+        AMM::AlgebraPool(pool) => uniswap_v3_depth(&pool.pool, token_b, tick_range),
+        AMM::CurveCryptoPool(pool) => curve_crypto_depth(pool, token_b),
+        AMM::BancorV3Pool(pool) => bancor_v3_depth(pool, token_b),
+        AMM::Custom(_) => None,
+    }
+}
+
+pub(crate) fn uniswap_v2_depth(pool: &UniswapV2Pool, token_b: H160) -> f64 {
+    let reserve_b = if pool.token_a == token_b {
+        pool.reserve_0
+    } else {
+        pool.reserve_1
+    };
+
+    //Both reserves are worth the same at the pool price
+    2.0 * reserve_b as f64
+}
+
+pub(crate) fn uniswap_v3_depth(
+    pool: &UniswapV3Pool,
+    token_b: H160,
+    tick_range: i32,
+) -> Option<f64> {
+    concentrated_liquidity_depth(
+        pool.sqrt_price,
+        pool.liquidity as f64,
+        pool.token_b == token_b,
+        tick_range,
+    )
+}
+
+pub(crate) fn kyber_elastic_depth(
+    pool: &KyberElasticPool,
+    token_b: H160,
+    tick_range: i32,
+) -> Option<f64> {
+    pool.opp_token(token_b)?;
+
+    //The reinvestment liquidity is in range at every price
+    concentrated_liquidity_depth(
+        pool.sqrt_price,
+        u256_to_f64_lossy(pool.liquidity()),
+        pool.token_b == token_b,
+        tick_range,
+    )
+}
+
+//Value of the tokens `liquidity` holds within `tick_range` ticks of `sqrt_price`, in token 1 or else in token 0
+fn concentrated_liquidity_depth(
+    sqrt_price: U256,
+    liquidity: f64,
+    in_token_1: bool,
+    tick_range: i32,
+) -> Option<f64> {
+    let sqrt_price = u256_to_f64_lossy(sqrt_price) / 2_f64.powi(96);
+    if sqrt_price == 0.0 {
+        return None;
+    }
+
+    //Tokens the active liquidity holds between the current price and the edges of the range
+    let half_range = 1.0001_f64.powf(tick_range.max(0) as f64 / 2.0);
+    let amount_0 = liquidity * (1.0 / sqrt_price - 1.0 / (sqrt_price * half_range));
+    let amount_1 = liquidity * (sqrt_price - sqrt_price / half_range);
+
+    //Valued in token 1, then in token 0 when it is token b
+    let value = amount_1 + amount_0 * sqrt_price * sqrt_price;
+    if in_token_1 {
+        Some(value)
+    } else {
+        Some(value / (sqrt_price * sqrt_price))
+    }
+}
+
+pub(crate) fn erc_4626_depth(vault: &ERC4626Vault, token_b: H160) -> f64 {
+    let reserve_b = if vault.asset_token == token_b {
+        vault.asset_reserve
+    } else {
+        vault.vault_reserve
+    };
+
+    u256_to_f64_lossy(reserve_b)
+}
+
+pub(crate) fn curve_stable_swap_depth(pool: &CurveStableSwapPool, token_b: H160) -> Option<f64> {
+    let token_b_decimals = pool.coin_decimals(token_b)?;
+
+    //Every balance valued at the marginal rate of its coin
+    pool.coins
+        .iter()
+        .zip(pool.balances.iter().zip(pool.decimals.iter()))
+        .map(|(coin, (balance, decimals))| {
+            let price = if *coin == token_b {
+                1.0
+            } else {
+                pool.calculate_price_to(*coin, token_b).ok()?
+            };
+
+            Some(
+                u256_to_f64_lossy(*balance) / 10_f64.powi(*decimals as i32)
+                    * price
+                    * 10_f64.powi(token_b_decimals as i32),
+            )
+        })
+        .sum()
+}
+
+pub(crate) fn curve_crypto_depth(pool: &CurveCryptoPool, token_b: H160) -> Option<f64> {
+    let token_b_decimals = pool.coin_decimals(token_b)?;
+
+    //Every balance valued at the marginal rate of its coin
+    pool.coins
+        .iter()
+        .zip(pool.balances.iter().zip(pool.decimals.iter()))
+        .map(|(coin, (balance, decimals))| {
+            let price = if *coin == token_b {
+                1.0
+            } else {
+                pool.calculate_price_to(*coin, token_b).ok()?
+            };
+
+            Some(
+                u256_to_f64_lossy(*balance) / 10_f64.powi(*decimals as i32)
+                    * price
+                    * 10_f64.powi(token_b_decimals as i32),
+            )
+        })
+        .sum()
+}
+
+pub(crate) fn balancer_weighted_depth(pool: &BalancerWeightedPool, token_b: H160) -> Option<f64> {
+    let token_b_decimals = pool.token_decimals(token_b)?;
+
+    //Every balance valued at the spot price of its token
+    pool.tokens
+        .iter()
+        .zip(pool.balances.iter().zip(pool.decimals.iter()))
+        .map(|(token, (balance, decimals))| {
+            let price = if *token == token_b {
+                1.0
+            } else {
+                pool.calculate_price_to(*token, token_b).ok()?
+            };
+
+            Some(
+                u256_to_f64_lossy(*balance) / 10_f64.powi(*decimals as i32)
+                    * price
+                    * 10_f64.powi(token_b_decimals as i32),
+            )
+        })
+        .sum()
+}
+
+pub(crate) fn velodrome_depth(pool: &VelodromePool, token_b: H160) -> Option<f64> {
+    let token_a = pool.opp_token(token_b)?;
+    let (reserve_a, reserve_b) = if pool.token_a == token_a {
+        (pool.reserve_0, pool.reserve_1)
+    } else {
+        (pool.reserve_1, pool.reserve_0)
+    };
+    let price = pool.calculate_price(token_a).ok()?;
+
+    //The reserve of token a valued at the marginal rate of the pool, which is twice the reserve b of volatile pools
+    Some(
+        u256_to_f64_lossy(reserve_b)
+            + u256_to_f64_lossy(reserve_a) / 10_f64.powi(pool.token_decimals(token_a)? as i32)
+                * price
+                * 10_f64.powi(pool.token_decimals(token_b)? as i32),
+    )
+}
+
+pub(crate) fn dodo_depth(pool: &DodoPool, token_b: H160) -> Option<f64> {
+    let token_a = pool.opp_token(token_b)?;
+    let (reserve_a, reserve_b) = if pool.token_a == token_a {
+        (pool.state.base_reserve, pool.state.quote_reserve)
+    } else {
+        (pool.state.quote_reserve, pool.state.base_reserve)
+    };
+    let price = pool.calculate_price(token_a).ok()?;
+
+    //The reserve of token a valued at the mid price of the pool
+    Some(
+        u256_to_f64_lossy(reserve_b)
+            + u256_to_f64_lossy(reserve_a) / 10_f64.powi(pool.token_decimals(token_a)? as i32)
+                * price
+                * 10_f64.powi(pool.token_decimals(token_b)? as i32),
+    )
+}
+
+pub(crate) fn bancor_v3_depth(pool: &BancorV3Pool, token_b: H160) -> Option<f64> {
+    let token_a = pool.opp_token(token_b)?;
+    let (liquidity_a, liquidity_b) = if pool.token == token_a {
+        (
+            pool.base_token_trading_liquidity,
+            pool.bnt_trading_liquidity,
+        )
+    } else {
+        (
+            pool.bnt_trading_liquidity,
+            pool.base_token_trading_liquidity,
+        )
+    };
+    let price = pool.calculate_price(token_a).ok()?;
+
+    //The trading liquidity of token a valued at the price of the pool
+    Some(
+        liquidity_b as f64
+            + liquidity_a as f64 / 10_f64.powi(pool.token_decimals(token_a)? as i32)
+                * price
+                * 10_f64.powi(pool.token_decimals(token_b)? as i32),
+    )
+}
+
+pub(crate) fn liquidity_book_depth(
+    pool: &LiquidityBookPool,
+    token_b: H160,
+    tick_range: i32,
+) -> Option<f64> {
+    pool.opp_token(token_b)?;
+
+    //Ticks are a basis point apart and bins `bin_step` basis points
+    let bins = tick_range.max(0) as u32 / pool.bin_step.max(1) as u32;
+    let ids = pool.active_id.saturating_sub(bins)..=pool.active_id.saturating_add(bins);
+
+    Some(
+        pool.bins
+            .range(ids)
+            .filter_map(|(id, (reserve_x, reserve_y))| {
+                //Price of the raw token a in raw token b
+                let price = price_x128_to_f64(get_price_from_id(*id, pool.bin_step).ok()?);
+                let value = *reserve_y as f64 + *reserve_x as f64 * price;
+
+                Some(if token_b == pool.token_b {
+                    value
+                } else {
+                    value / price
+                })
+            })
+            .sum(),
+    )
+}
+
+pub(crate) fn maverick_depth(pool: &MaverickPool, token_b: H160, tick_range: i32) -> Option<f64> {
+    pool.opp_token(token_b)?;
+
+    //Ticks of the pools are `tick_spacing` basis points apart, the bins hold both tokens scaled to 18 decimals
+    let ticks = tick_range.max(0) / pool.tick_spacing.clamp(1, i32::MAX as u32) as i32;
+    let ticks = pool.active_tick.saturating_sub(ticks)..=pool.active_tick.saturating_add(ticks);
+    let token_b_decimals = pool.token_decimals(token_b)?;
+
+    Some(
+        pool.tick_reserves()
+            .range(ticks)
+            .map(|(tick, (reserve_a, reserve_b))| {
+                //Price of a whole token a in whole token b
+                let price = 1.0001_f64.powf(*tick as f64 * pool.tick_spacing as f64);
+                let value = (u256_to_f64_lossy(*reserve_b) + u256_to_f64_lossy(*reserve_a) * price)
+                    / 1e18
+                    * 10_f64.powi(token_b_decimals as i32);
+
+                if token_b == pool.token_b {
+                    value
+                } else {
+                    value / price
+                }
+            })
+            .sum(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, U256};
+
+    use crate::amm::{
+        erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AmmState, AMM,
+    };
+
+    use super::{aggregate_price, DepthWeighting};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    //WETH/USDC pool holding `weth` WETH at `price` USDC per WETH
+    fn v2_pool(address: u64, weth: u128, price: f64, v3_pool: &UniswapV3Pool) -> AMM {
+        let mut pool = UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: v3_pool.token_b,
+            token_a_decimals: 18,
+            token_b: v3_pool.token_a,
+            token_b_decimals: 6,
+            reserve_0: weth * 10_u128.pow(18),
+            reserve_1: (weth as f64 * price * 1e6) as u128,
+            fee: 300,
+            ..Default::default()
+        };
+        pool.reset_decimal_scaling();
+
+        AMM::UniswapV2Pool(pool)
+    }
+
+    #[test]
+    fn test_aggregate_price() -> eyre::Result<()> {
+        let v3_pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let (usdc, weth) = (v3_pool.token_a, v3_pool.token_b);
+        let price = v3_pool.calculate_price(weth)?;
+
+        let amms = vec![
+            v2_pool(1, 1000, price * 1.002, &v3_pool),
+            v2_pool(2, 500, price * 0.998, &v3_pool),
+            //20% off, large but not a majority of the depth
+            v2_pool(3, 1000, price * 1.2, &v3_pool),
+            AMM::UniswapV3Pool(v3_pool.clone()),
+        ];
+
+        let aggregate =
+            aggregate_price(&amms, weth, usdc, DepthWeighting::default()).expect("pair is priced");
+        assert_eq!(aggregate.excluded_pools, vec![H160::from_low_u64_be(3)]);
+        assert_eq!(aggregate.contributing_pools.len(), 3);
+        assert!(aggregate.mid > price * 0.998 && aggregate.mid < price * 1.002);
+
+        //The V2 pools hold most of the depth, weighting the mid towards the deeper one
+        let v2_depth = 2.0 * (1000.0 * 1.002 + 500.0 * 0.998) * price * 1e6;
+        let v3_depth = aggregate.total_depth - v2_depth;
+        assert!(v3_depth > 0.0 && v3_depth < v2_depth / 10.0);
+        let expected_mid = (2.0 * 1000.0 * 1.002 * 1.002 * price * price * 1e6
+            + 2.0 * 500.0 * 0.998 * 0.998 * price * price * 1e6
+            + v3_depth * price)
+            / aggregate.total_depth;
+        assert!((aggregate.mid / expected_mid - 1.0).abs() < 1e-6);
+
+        //Prices are inverted with the pair
+        let inverse = aggregate_price(&amms, usdc, weth, DepthWeighting::default()).unwrap();
+        assert_eq!(inverse.excluded_pools, aggregate.excluded_pools);
+        assert!((inverse.mid * aggregate.mid - 1.0).abs() < 0.01);
+
+        //V3 depth grows with the range of ticks it is measured over
+        let narrow = aggregate_price(&amms[3..], weth, usdc, DepthWeighting::default()).unwrap();
+        let wide = aggregate_price(
+            &amms[3..],
+            weth,
+            usdc,
+            DepthWeighting::default().with_tick_range(200),
+        )
+        .unwrap();
+        assert!((narrow.mid / price - 1.0).abs() < 1e-12);
+        assert!((wide.total_depth / narrow.total_depth - 2.0).abs() < 0.01);
+
+        //A looser deviation keeps the outlier
+        let loose = aggregate_price(&amms, weth, usdc, DepthWeighting::new(0.5)).unwrap();
+        assert!(loose.excluded_pools.is_empty());
+        assert!(loose.mid > aggregate.mid);
+
+        assert!(
+            aggregate_price(&amms, weth, H160::from_low_u64_be(9), Default::default()).is_none()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_vault_price() {
+        let (asset, shares) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let mut vault = ERC4626Vault {
+            vault_token: shares,
+            vault_token_decimals: 18,
+            asset_token: asset,
+            asset_token_decimals: 18,
+            vault_reserve: U256::from(1000),
+            asset_reserve: U256::from(1100),
+            ..Default::default()
+        };
+        vault.reset_decimal_scaling();
+        let amms = vec![AMM::ERC4626Vault(vault)];
+
+        let aggregate = aggregate_price(&amms, shares, asset, Default::default()).unwrap();
+        assert!((aggregate.mid - 1.1).abs() < 1e-9);
+        assert_eq!(aggregate.total_depth, 1100.0);
+    }
+}
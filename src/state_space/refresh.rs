@@ -0,0 +1,438 @@
+//! Scheduled refreshes of the AMMs of the state space.
+//!
+//! Logs keep active pools fresh, but some state drifts without any: ERC4626 vaults accrue yield, pairs of rebasing
+//! tokens hold more or less than their reserves. Each AMM has a `RefreshPolicy`, and the task spawned by
+//! `StateSpaceManager::spawn_refresh` reads the AMMs due again from chain in batch requests, apart from the task
+//! applying the logs of each block.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ethers::{providers::Middleware, types::H160};
+use futures::{stream, StreamExt, TryStreamExt};
+
+use crate::{
+    amm::{multicall::BatchRequestMode, AutomatedMarketMaker, AMM},
+    errors::AMMError,
+    sync::populate_amms_with_mode,
+    tokens::TokenStore,
+};
+
+/// Blocks after which a vault is refreshed by default, about an hour on mainnet
+pub const DEFAULT_VAULT_REFRESH_BLOCKS: u64 = 300;
+/// Blocks between refreshes of a Curve pool by default, about ten minutes on mainnet
+pub const DEFAULT_CURVE_REFRESH_BLOCKS: u64 = 50;
+/// Blocks between refreshes of a Liquidity Book pool by default
+pub const DEFAULT_LIQUIDITY_BOOK_REFRESH_BLOCKS: u64 = 50;
+/// Blocks between refreshes of a Maverick pool by default, about twenty minutes on mainnet
+pub const DEFAULT_MAVERICK_REFRESH_BLOCKS: u64 = 100;
+/// Blocks between refreshes of a DODO pool by default, about ten minutes on mainnet
+pub const DEFAULT_DODO_REFRESH_BLOCKS: u64 = 50;
+/// Blocks between refreshes of a KyberSwap Elastic pool by default, about twenty minutes on mainnet
+pub const DEFAULT_KYBER_ELASTIC_REFRESH_BLOCKS: u64 = 100;
+/// Blocks between refreshes of a Curve crypto pool by default, about two minutes on mainnet
+pub const DEFAULT_CURVE_CRYPTO_REFRESH_BLOCKS: u64 = 10;
+/// Blocks between refreshes of a Bancor V3 pool by default, about ten minutes on mainnet
+pub const DEFAULT_BANCOR_V3_REFRESH_BLOCKS: u64 = 50;
+/// Interval at which the refresh task checks for AMMs due
+pub const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+//AMMs of the same variant read in each batch request, below the limit of every batch contract
+const REFRESH_BATCH_SIZE: usize = 50;
+
+/// When an AMM is read again from chain, on top of the logs it syncs from. An AMM is due once either bound passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefreshPolicy {
+    pub every_n_blocks: Option<u64>,
+    pub every_duration: Option<Duration>,
+    /// Counts from the last time the AMM was updated from a log or refreshed, rather than from its last refresh,
+    /// so AMMs with regular logs are never refreshed
+    pub on_staleness_only: bool,
+}
+
+impl RefreshPolicy {
+    pub fn never() -> Self {
+        RefreshPolicy {
+            every_n_blocks: None,
+            every_duration: None,
+            on_staleness_only: false,
+        }
+    }
+
+    pub fn every_n_blocks(every_n_blocks: u64) -> Self {
+        RefreshPolicy {
+            every_n_blocks: Some(every_n_blocks),
+            ..RefreshPolicy::never()
+        }
+    }
+
+    pub fn every_duration(every_duration: Duration) -> Self {
+        RefreshPolicy {
+            every_duration: Some(every_duration),
+            ..RefreshPolicy::never()
+        }
+    }
+
+    pub fn with_on_staleness_only(mut self, on_staleness_only: bool) -> Self {
+        self.on_staleness_only = on_staleness_only;
+        self
+    }
+
+    /// ERC4626 vaults are refreshed `DEFAULT_VAULT_REFRESH_BLOCKS` blocks after their last deposit, withdrawal or
+    /// refresh, as their yield accrues without logs. Curve pools are refreshed every `DEFAULT_CURVE_REFRESH_BLOCKS`
+    /// blocks whether they swapped or not, as liquidity changes are not synced from logs. Liquidity Book pools are
+    /// refreshed every `DEFAULT_LIQUIDITY_BOOK_REFRESH_BLOCKS` blocks, as the references of their variable fee are
+    /// not in their logs. Maverick pools are refreshed every `DEFAULT_MAVERICK_REFRESH_BLOCKS` blocks, as their swaps
+    /// are synced by simulating them again, which rounds unlike the pools. DODO pools are refreshed every
+    /// `DEFAULT_DODO_REFRESH_BLOCKS` blocks, as liquidity changes and the resets of private pools are not logged with
+    /// their amounts or parameters. KyberSwap Elastic pools are refreshed every `DEFAULT_KYBER_ELASTIC_REFRESH_BLOCKS`
+    /// blocks, as the fees their swaps compound are simulated and burns of reinvestment tokens are not synced, and
+    /// to read the ticks again around the price. Curve crypto pools are refreshed every
+    /// `DEFAULT_CURVE_CRYPTO_REFRESH_BLOCKS` blocks, as liquidity changes are not synced from logs and their price
+    /// scale moves after trades without being logged. Bancor V3 pools are refreshed every
+    /// `DEFAULT_BANCOR_V3_REFRESH_BLOCKS` blocks, as deposits, withdrawals and the network fee move their trading
+    /// liquidity without being synced. Other AMMs sync every change from logs and are never refreshed.
+    pub fn default_for(amm: &AMM) -> Self {
+        match amm {
+            AMM::ERC4626Vault(_) => RefreshPolicy::every_n_blocks(DEFAULT_VAULT_REFRESH_BLOCKS)
+                .with_on_staleness_only(true),
+            AMM::CurveStableSwapPool(_) => {
+                RefreshPolicy::every_n_blocks(DEFAULT_CURVE_REFRESH_BLOCKS)
+            }
+            AMM::LiquidityBookPool(_) => {
+                RefreshPolicy::every_n_blocks(DEFAULT_LIQUIDITY_BOOK_REFRESH_BLOCKS)
+            }
+            AMM::MaverickPool(_) => RefreshPolicy::every_n_blocks(DEFAULT_MAVERICK_REFRESH_BLOCKS),
+            AMM::DodoPool(_) => RefreshPolicy::every_n_blocks(DEFAULT_DODO_REFRESH_BLOCKS),
+            AMM::KyberElasticPool(_) => {
+                RefreshPolicy::every_n_blocks(DEFAULT_KYBER_ELASTIC_REFRESH_BLOCKS)
+            }
+            AMM::CurveCryptoPool(_) => {
+                RefreshPolicy::every_n_blocks(DEFAULT_CURVE_CRYPTO_REFRESH_BLOCKS)
+            }
+            AMM::BancorV3Pool(_) => RefreshPolicy::every_n_blocks(DEFAULT_BANCOR_V3_REFRESH_BLOCKS),
+            _ => RefreshPolicy::never(),
+        }
+    }
+
+    pub fn is_never(&self) -> bool {
+        self.every_n_blocks.is_none() && self.every_duration.is_none()
+    }
+
+    fn is_due(&self, since: Mark, block_number: u64, now: Instant) -> bool {
+        self.every_n_blocks.is_some_and(|every_n_blocks| {
+            block_number.saturating_sub(since.block) >= every_n_blocks
+        }) || self
+            .every_duration
+            .is_some_and(|every_duration| now.saturating_duration_since(since.at) >= every_duration)
+    }
+}
+
+/// Blocks and time since the state of an AMM was last confirmed, by a log or a refresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLag {
+    pub blocks: u64,
+    pub elapsed: Duration,
+}
+
+//Block an AMM was confirmed at, and when
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mark {
+    block: u64,
+    at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct ScheduleState {
+    //AMMs that are never refreshed are left out
+    policies: HashMap<H160, RefreshPolicy>,
+    updated: HashMap<H160, Mark>,
+    refreshed: HashMap<H160, Mark>,
+    //Block listeners started from, which every AMM is considered fresh at
+    started: Option<Mark>,
+}
+
+impl ScheduleState {
+    fn last_refreshed(&self, address: H160) -> Option<Mark> {
+        latest(self.refreshed.get(&address).copied(), self.started)
+    }
+
+    fn last_confirmed(&self, address: H160) -> Option<Mark> {
+        latest(
+            self.updated.get(&address).copied(),
+            self.last_refreshed(address),
+        )
+    }
+}
+
+fn latest(a: Option<Mark>, b: Option<Mark>) -> Option<Mark> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.block >= b.block { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Refresh policy of each AMM of a state space and the last block each AMM was confirmed at
+#[derive(Debug, Default)]
+pub struct RefreshSchedule {
+    state: Mutex<ScheduleState>,
+}
+
+impl RefreshSchedule {
+    pub fn new() -> Self {
+        RefreshSchedule::default()
+    }
+
+    pub fn set_policy(&self, address: H160, policy: RefreshPolicy) {
+        let mut state = self.state.lock().expect("refresh schedule poisoned");
+        if policy.is_never() {
+            state.policies.remove(&address);
+        } else {
+            state.policies.insert(address, policy);
+        }
+    }
+
+    pub fn policy(&self, address: H160) -> RefreshPolicy {
+        self.state
+            .lock()
+            .expect("refresh schedule poisoned")
+            .policies
+            .get(&address)
+            .copied()
+            .unwrap_or_else(RefreshPolicy::never)
+    }
+
+    /// Staleness of `address` at `block_number`, None before listeners started and the AMM was updated
+    pub fn staleness(&self, address: H160, block_number: u64) -> Option<BlockLag> {
+        self.staleness_at(address, block_number, Instant::now())
+    }
+
+    fn staleness_at(&self, address: H160, block_number: u64, now: Instant) -> Option<BlockLag> {
+        let state = self.state.lock().expect("refresh schedule poisoned");
+        state.last_confirmed(address).map(|mark| BlockLag {
+            blocks: block_number.saturating_sub(mark.block),
+            elapsed: now.saturating_duration_since(mark.at),
+        })
+    }
+
+    /// AMMs due for a refresh at `block_number`
+    pub fn due(&self, block_number: u64, now: Instant) -> Vec<H160> {
+        let state = self.state.lock().expect("refresh schedule poisoned");
+
+        state
+            .policies
+            .iter()
+            .filter(|(address, policy)| {
+                let since = if policy.on_staleness_only {
+                    state.last_confirmed(**address)
+                } else {
+                    state.last_refreshed(**address)
+                };
+                since.is_some_and(|since| policy.is_due(since, block_number, now))
+            })
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    //Sets the default policy of AMMs without one
+    pub(crate) fn track(&self, amms: &[AMM]) {
+        let mut state = self.state.lock().expect("refresh schedule poisoned");
+        for amm in amms {
+            let policy = RefreshPolicy::default_for(amm);
+            if !policy.is_never() {
+                state.policies.entry(amm.address()).or_insert(policy);
+            }
+        }
+    }
+
+    pub(crate) fn start(&self, block_number: u64) {
+        self.state
+            .lock()
+            .expect("refresh schedule poisoned")
+            .started = Some(Mark {
+            block: block_number,
+            at: Instant::now(),
+        });
+    }
+
+    pub(crate) fn record_updates(&self, addresses: &[H160], block_number: u64) {
+        let mark = Mark {
+            block: block_number,
+            at: Instant::now(),
+        };
+        let mut state = self.state.lock().expect("refresh schedule poisoned");
+        for address in addresses {
+            state.updated.insert(*address, mark);
+        }
+    }
+
+    pub(crate) fn record_refresh(&self, address: H160, block_number: u64) {
+        let mark = Mark {
+            block: block_number,
+            at: Instant::now(),
+        };
+        self.state
+            .lock()
+            .expect("refresh schedule poisoned")
+            .refreshed
+            .insert(address, mark);
+    }
+
+    //Whether a log updated `address` after `block_number`, which a refresh read at `block_number` is older than
+    pub(crate) fn updated_after(&self, address: H160, block_number: u64) -> bool {
+        self.state
+            .lock()
+            .expect("refresh schedule poisoned")
+            .updated
+            .get(&address)
+            .is_some_and(|mark| mark.block > block_number)
+    }
+}
+
+/// Reads `amms` again at `block_number`, in one batch request per chunk of AMMs of the same variant with at most
+/// `max_concurrent_requests` requests in flight. Custom AMMs have no batch request and are populated one at a time.
+pub async fn refresh_amms<M: Middleware>(
+    amms: Vec<AMM>,
+    block_number: u64,
+    max_concurrent_requests: usize,
+    middleware: Arc<M>,
+) -> Result<Vec<AMM>, AMMError<M>> {
+    let mut batches: HashMap<std::mem::Discriminant<AMM>, Vec<Vec<AMM>>> = HashMap::new();
+    for amm in amms {
+        let variant_batches = batches.entry(std::mem::discriminant(&amm)).or_default();
+        match variant_batches.last_mut() {
+            Some(batch) if batch.len() < REFRESH_BATCH_SIZE && !matches!(amm, AMM::Custom(_)) => {
+                batch.push(amm)
+            }
+            _ => variant_batches.push(vec![amm]),
+        }
+    }
+
+    let refreshed = stream::iter(batches.into_values().flatten())
+        .map(|mut batch| {
+            let middleware = middleware.clone();
+            async move {
+                if let AMM::Custom(_) = batch[0] {
+                    batch[0]
+                        .populate_data(Some(block_number), middleware)
+                        .await?;
+                } else {
+                    populate_amms_with_mode(
+                        &mut batch,
+                        block_number,
+                        middleware,
+                        REFRESH_BATCH_SIZE as u64,
+                        &TokenStore::new(),
+                        BatchRequestMode::Auto,
+                    )
+                    .await?;
+                }
+
+                Ok::<_, AMMError<M>>(batch)
+            }
+        })
+        .buffer_unordered(max_concurrent_requests.max(1))
+        .try_collect::<Vec<Vec<AMM>>>()
+        .await?;
+
+    Ok(refreshed.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ethers::types::H160;
+
+    use crate::amm::{erc_4626::ERC4626Vault, uniswap_v2::UniswapV2Pool, AMM};
+
+    use super::{BlockLag, RefreshPolicy, RefreshSchedule, DEFAULT_VAULT_REFRESH_BLOCKS};
+
+    #[test]
+    fn test_default_policies() {
+        let (vault, pool) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let schedule = RefreshSchedule::new();
+        schedule.track(&[
+            AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: vault,
+                ..Default::default()
+            }),
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: pool,
+                ..Default::default()
+            }),
+        ]);
+
+        assert_eq!(
+            schedule.policy(vault),
+            RefreshPolicy::every_n_blocks(DEFAULT_VAULT_REFRESH_BLOCKS)
+                .with_on_staleness_only(true)
+        );
+        assert!(schedule.policy(pool).is_never());
+
+        //Nothing is due before listeners start
+        assert!(schedule.due(u64::MAX, Instant::now()).is_empty());
+
+        schedule.start(100);
+        assert!(schedule
+            .due(100 + DEFAULT_VAULT_REFRESH_BLOCKS - 1, Instant::now())
+            .is_empty());
+        assert_eq!(
+            schedule.due(100 + DEFAULT_VAULT_REFRESH_BLOCKS, Instant::now()),
+            vec![vault]
+        );
+
+        //A deposit confirms the state of the vault
+        schedule.record_updates(&[vault], 200);
+        assert!(schedule
+            .due(100 + DEFAULT_VAULT_REFRESH_BLOCKS, Instant::now())
+            .is_empty());
+        assert_eq!(
+            schedule.due(200 + DEFAULT_VAULT_REFRESH_BLOCKS, Instant::now()),
+            vec![vault]
+        );
+    }
+
+    #[test]
+    fn test_refresh_schedule() {
+        let (every_10_blocks, every_minute) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let schedule = RefreshSchedule::new();
+        schedule.set_policy(every_10_blocks, RefreshPolicy::every_n_blocks(10));
+        schedule.set_policy(
+            every_minute,
+            RefreshPolicy::every_duration(Duration::from_secs(60)).with_on_staleness_only(true),
+        );
+        schedule.start(100);
+        let now = Instant::now();
+
+        //Without on_staleness_only, logs do not delay the refresh
+        schedule.record_updates(&[every_10_blocks, every_minute], 105);
+        assert_eq!(schedule.due(110, now), vec![every_10_blocks]);
+
+        schedule.record_refresh(every_10_blocks, 110);
+        assert!(schedule.due(119, now).is_empty());
+        let mut due = schedule.due(120, now + Duration::from_secs(61));
+        due.sort();
+        assert_eq!(due, vec![every_10_blocks, every_minute]);
+
+        //Refreshes read before a log are older than the state from the log
+        assert!(schedule.updated_after(every_minute, 104));
+        assert!(!schedule.updated_after(every_minute, 105));
+
+        assert_eq!(
+            schedule.staleness_at(every_minute, 112, now),
+            Some(BlockLag {
+                blocks: 7,
+                elapsed: Duration::ZERO
+            })
+        );
+        assert_eq!(
+            schedule
+                .staleness_at(every_10_blocks, 112, now)
+                .map(|lag| lag.blocks),
+            Some(2)
+        );
+    }
+}
@@ -1,34 +1,63 @@
+use std::collections::HashMap;
+#[cfg(feature = "state-space")]
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use crate::{
-    amm::{AutomatedMarketMaker, AMM},
+    amm::{AmmState, AMM},
     errors::EventLogError,
 };
+#[cfg(feature = "state-space")]
+use crate::{
+    errors::{CheckpointError, SwapSimulationError},
+    filters::address::BlacklistFilter,
+    metrics::Metrics,
+    sync::store::CheckpointStore,
+};
+#[cfg(feature = "state-space")]
 use arraydeque::ArrayDeque;
+use ethers::types::{Log, H160};
+#[cfg(feature = "state-space")]
 use ethers::{
     providers::{Middleware, PubsubClient, StreamExt},
-    types::{Block, Filter, Log, H160, H256},
+    types::{Block, BlockNumber, Filter, H256, U256},
 };
+#[cfg(feature = "state-space")]
 use tokio::{
     sync::{
         mpsc::{Receiver, Sender},
-        RwLock,
+        Notify, RwLock,
     },
     task::JoinHandle,
+    time::MissedTickBehavior,
 };
 
-use super::error::{StateChangeError, StateSpaceError};
+#[cfg(all(feature = "state-space", feature = "sqlite"))]
+use crate::storage::sqlite::{SqliteStore, SqliteStoreError};
+
+#[cfg(feature = "state-space")]
+use super::{
+    bundle::{simulate_bundle, simulate_bundle_checked, BundleResult},
+    error::{StateChangeError, StateSpaceError},
+    refresh::{refresh_amms, BlockLag, RefreshSchedule, REFRESH_CHECK_INTERVAL},
+};
 
 pub type StateSpace = HashMap<H160, AMM>;
+#[cfg(feature = "state-space")]
 pub type StateChangeCache = ArrayDeque<StateChange, 150>;
 
+#[cfg(feature = "state-space")]
 pub trait MiddlewarePubsub: Middleware {
     type PubsubProvider: 'static + PubsubClient;
 }
 
+#[cfg(feature = "state-space")]
 impl<T> MiddlewarePubsub for T
 where
     T: Middleware,
@@ -37,24 +66,55 @@ where
     type PubsubProvider = T::Provider;
 }
 
+#[cfg(feature = "state-space")]
 #[derive(Debug)]
 pub struct StateSpaceManager<M, P>
 where
     M: 'static + Middleware,
-    P: 'static + MiddlewarePubsub,
+    P: 'static + Middleware,
 {
     pub state: Arc<RwLock<StateSpace>>,
     pub state_change_cache: Arc<RwLock<StateChangeCache>>,
     pub middleware: Arc<M>,
     pub stream_middleware: Arc<P>,
+    pub sync_progress: Arc<SyncProgress>,
+    //AMMs matching the blacklist are rejected by `add_amms`
+    pub blacklist: Arc<RwLock<BlacklistFilter>>,
+    pub metrics: Metrics,
+    /// Whether listeners only request the logs of the AMMs in the state space, see `watch_only`
+    pub watch_only: bool,
+    /// Refresh policy of each AMM, followed by the task of `spawn_refresh`
+    pub refresh_schedule: Arc<RefreshSchedule>,
+}
+
+#[cfg(feature = "state-space")]
+impl<M> StateSpaceManager<M, M>
+where
+    M: Middleware,
+{
+    /// Manager of a small set of AMMs, synced through `middleware` alone.
+    ///
+    /// Listeners request the logs of the AMMs in the state space when they start rather than every log with an
+    /// event signature the AMMs sync on, so the requests scale with the watched AMMs instead of with the chain.
+    /// Blocks come from `listen_for_*` over a pubsub middleware or from `poll_for_*` over any middleware, with the
+    /// same notifications, gap fill and reorg handling as the full manager.
+    pub fn watch_only(amms: Vec<AMM>, middleware: Arc<M>) -> Self {
+        let mut manager = Self::new(amms, middleware.clone(), middleware);
+        manager.watch_only = true;
+        manager
+    }
 }
 
+#[cfg(feature = "state-space")]
 impl<M, P> StateSpaceManager<M, P>
 where
     M: Middleware,
-    P: MiddlewarePubsub,
+    P: Middleware,
 {
     pub fn new(amms: Vec<AMM>, middleware: Arc<M>, stream_middleware: Arc<P>) -> Self {
+        let refresh_schedule = RefreshSchedule::new();
+        refresh_schedule.track(&amms);
+
         let state: HashMap<H160, AMM> = amms
             .into_iter()
             .map(|amm| (amm.address(), amm))
@@ -65,34 +125,235 @@ where
             state_change_cache: Arc::new(RwLock::new(ArrayDeque::new())),
             middleware,
             stream_middleware,
+            sync_progress: Arc::new(SyncProgress::default()),
+            blacklist: Arc::new(RwLock::new(BlacklistFilter::default())),
+            metrics: Metrics::default(),
+            watch_only: false,
+            refresh_schedule: Arc::new(refresh_schedule),
+        }
+    }
+
+    /// Initializes the state space from the checkpoint in `store`, as synced up to the block of the checkpoint
+    pub async fn load(
+        store: Arc<dyn CheckpointStore>,
+        middleware: Arc<M>,
+        stream_middleware: Arc<P>,
+    ) -> Result<Self, CheckpointError> {
+        let checkpoint = store.read().await?;
+        let manager = Self::new(checkpoint.amms, middleware, stream_middleware);
+        manager.sync_progress.advance_to(checkpoint.block_number);
+
+        Ok(manager)
+    }
+
+    /// Writes every AMM of the state space to `store` at the last synced block, keeping the factory cursors already
+    /// stored so the checkpoint can still be resumed with `sync_amms_from_checkpoint`
+    pub async fn save(&self, store: Arc<dyn CheckpointStore>) -> Result<(), CheckpointError> {
+        let amms = self
+            .state
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<AMM>>();
+        store
+            .write_partial(&[], &amms, self.last_synced_block())
+            .await
+    }
+
+    /// Rejects AMMs matching `blacklist` when they are added to the state space.
+    /// The blacklist is shared, so entries added to it at runtime apply to subsequent additions.
+    pub fn with_blacklist(mut self, blacklist: Arc<RwLock<BlacklistFilter>>) -> Self {
+        self.blacklist = blacklist;
+        self
+    }
+
+    /// Records the block lag, the time taken to apply each block, reorgs and the depth of the block channel of listeners
+    /// started afterwards
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Returns the most recent block for which all state changes have been applied.
+    pub fn last_synced_block(&self) -> u64 {
+        self.sync_progress.last_synced_block()
+    }
+
+    /// Blocks and time since the state of `address` was last confirmed by a log or a refresh, None if the AMM is not
+    /// in the state space or no listener started yet
+    pub async fn staleness(&self, address: H160) -> Option<BlockLag> {
+        if !self.state.read().await.contains_key(&address) {
+            return None;
+        }
+
+        self.refresh_schedule
+            .staleness(address, self.last_synced_block())
+    }
+
+    /// Returns whether the manager is streaming live blocks or catching up (gap fill / reorg unwind).
+    pub fn sync_status(&self) -> SyncStatus {
+        self.sync_progress.sync_status()
+    }
+
+    /// Resolves once the manager has applied all state changes for `block_number`. Blocks unwound by a reorg are no
+    /// longer synced, so waiting for them resolves once their replacements have been applied.
+    pub async fn wait_for_block(&self, block_number: u64) {
+        self.sync_progress.wait_for_block(block_number).await
+    }
+
+    /// Same as `wait_for_block`, returning an error if the block is not synced within `timeout`.
+    pub async fn wait_for_block_with_timeout(
+        &self,
+        block_number: u64,
+        timeout: Duration,
+    ) -> Result<(), StateSpaceError<M, P>> {
+        tokio::time::timeout(timeout, self.wait_for_block(block_number))
+            .await
+            .map_err(|_| StateSpaceError::WaitForBlockTimeout(block_number))
+    }
+
+    /// Adds AMMs to the state space, i.e. pools created after the state space was initialized, returning the addresses that were added.
+    /// AMMs that are already in the state space or blacklisted are left out. Listeners only pick up the event signatures
+    /// of AMM variants that were present when they were started.
+    pub async fn add_amms(&self, amms: Vec<AMM>) -> Vec<H160> {
+        let blacklist = self.blacklist.read().await;
+        let mut state = self.state.write().await;
+        let mut added_amms = vec![];
+
+        for amm in amms {
+            if blacklist.is_blacklisted(&amm) {
+                tracing::debug!(address = ?amm.address(), "rejected blacklisted AMM");
+                continue;
+            }
+
+            let address = amm.address();
+            if let std::collections::hash_map::Entry::Vacant(entry) = state.entry(address) {
+                self.refresh_schedule.track(std::slice::from_ref(&amm));
+                entry.insert(amm);
+                added_amms.push(address);
+            }
         }
+
+        self.refresh_schedule
+            .record_updates(&added_amms, self.last_synced_block());
+        added_amms
+    }
+
+    /// Simulates `swaps` of `(pool, token_in, amount_in)` in order against the current state space with
+    /// `simulate_bundle`, holding the read lock so no block is applied during the simulation.
+    pub async fn simulate_bundle(
+        &self,
+        swaps: &[(H160, H160, U256)],
+    ) -> Result<BundleResult, SwapSimulationError> {
+        simulate_bundle(&*self.state.read().await, swaps)
+    }
+
+    /// `simulate_bundle` with a minimum output per swap, see `bundle::simulate_bundle_checked`
+    pub async fn simulate_bundle_checked(
+        &self,
+        swaps: &[(H160, H160, U256, U256)],
+    ) -> Result<BundleResult, SwapSimulationError> {
+        simulate_bundle_checked(&*self.state.read().await, swaps)
+    }
+
+    /// Persists the AMMs sent by `listen_for_state_changes` to `store` as they are updated, so each block only
+    /// rewrites the pools it touched instead of the whole state space.
+    #[cfg(feature = "sqlite")]
+    pub fn persist_state_changes(
+        &self,
+        store: Arc<SqliteStore>,
+        mut amms_updated: Receiver<Vec<H160>>,
+    ) -> JoinHandle<Result<(), SqliteStoreError>> {
+        let state = self.state.clone();
+        let sync_progress = self.sync_progress.clone();
+
+        tokio::spawn(async move {
+            while let Some(amms_updated) = amms_updated.recv().await {
+                let amms = {
+                    let state = state.read().await;
+                    amms_updated
+                        .iter()
+                        .filter_map(|address| state.get(address).cloned())
+                        .collect::<Vec<AMM>>()
+                };
+                let block_number = sync_progress.last_synced_block();
+
+                let store = store.clone();
+                let persisted = amms.len();
+                tokio::task::spawn_blocking(move || store.save_amms(&amms, block_number))
+                    .await
+                    .expect("SQLite persistence task panicked")?;
+                tracing::debug!(persisted, block_number, "persisted updated AMMs");
+            }
+
+            Ok(())
+        })
     }
 
+    /// Filter of the logs listeners request for each block, restricted to the AMMs in the state space when the
+    /// manager is `watch_only`
     pub async fn get_block_filter(&self) -> Filter {
+        let filter = Filter::new().topic0(self.event_signatures().await);
+        if !self.watch_only {
+            return filter;
+        }
+
+        //AMMs living in a singleton contract share its address
+        let mut addresses = self
+            .state
+            .read()
+            .await
+            .values()
+            .map(|amm| amm.log_address())
+            .collect::<Vec<H160>>();
+        addresses.sort();
+        addresses.dedup();
+        filter.address(addresses)
+    }
+
+    /// Event signatures the AMMs in the state space sync on, one set per AMM variant.
+    pub async fn event_signatures(&self) -> Vec<H256> {
         let mut event_signatures: Vec<H256> = vec![];
         let mut amm_variants = HashSet::new();
 
         for amm in self.state.read().await.values() {
             let variant = match amm {
-                AMM::UniswapV2Pool(_) => 0,
-                AMM::UniswapV3Pool(_) => 1,
-                AMM::ERC4626Vault(_) => 2,
+                AMM::UniswapV2Pool(_) => "uniswap_v2",
+                AMM::UniswapV3Pool(_) => "uniswap_v3",
+                AMM::ERC4626Vault(_) => "erc_4626",
+                AMM::CurveStableSwapPool(_) => "curve_stableswap",
+                AMM::BalancerWeightedPool(_) => "balancer_weighted",
+                AMM::VelodromePool(_) => "velodrome",
+                AMM::UniswapV4Pool(_) => "uniswap_v4",
+                AMM::LiquidityBookPool(_) => "liquidity_book",
+                AMM::MaverickPool(_) => "maverick",
+                AMM::DodoPool(_) => "dodo",
+                AMM::KyberElasticPool(_) => "kyber_elastic",
+                AMM::AlgebraPool(_) => "algebra",
+                AMM::CurveCryptoPool(_) => "curve_crypto",
+                AMM::BancorV3Pool(_) => "bancor_v3",
+                AMM::Custom(custom_amm) => custom_amm.protocol(),
             };
 
             if !amm_variants.contains(&variant) {
                 amm_variants.insert(variant);
-                event_signatures.extend(amm.sync_on_event_signatures());
+                for event_signature in amm.sync_on_event_signatures() {
+                    //Custom protocols may share event signatures with other variants
+                    if !event_signatures.contains(&event_signature) {
+                        event_signatures.push(event_signature);
+                    }
+                }
             }
         }
 
-        //Create a new filter
-        Filter::new().topic0(event_signatures)
+        event_signatures
     }
 
     /// Listens to new blocks and handles state changes, sending an H256 block hash when a new block is produced.
     pub async fn listen_for_new_blocks(
         &self,
-        mut last_synced_block: u64,
+        last_synced_block: u64,
         channel_buffer: usize,
     ) -> Result<
         (
@@ -110,93 +371,17 @@ where
             "listening for new blocks"
         );
 
-        let state = self.state.clone();
-        let middleware = self.middleware.clone();
-        let stream_middleware: Arc<P> = self.stream_middleware.clone();
-        let filter = self.get_block_filter().await;
-
-        let (stream_tx, mut stream_rx): (Sender<Block<H256>>, Receiver<Block<H256>>) =
-            tokio::sync::mpsc::channel(channel_buffer);
-
-        let stream_handle = tokio::spawn(async move {
-            let mut block_stream = stream_middleware
-                .subscribe_blocks()
-                .await
-                .map_err(StateSpaceError::PubsubClientError)?;
-
-            while let Some(block) = block_stream.next().await {
-                stream_tx.send(block).await?;
-            }
-
-            Ok::<(), StateSpaceError<M, P>>(())
-        });
-
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(channel_buffer);
         let (new_block_tx, new_block_rx) = tokio::sync::mpsc::channel(channel_buffer);
 
-        let state_change_cache = self.state_change_cache.clone();
-        let new_block_handle: JoinHandle<Result<(), StateSpaceError<M, P>>> =
-            tokio::spawn(async move {
-                while let Some(block) = stream_rx.recv().await {
-                    tracing::info!(?block, "received new block");
-                    if let Some(chain_head_block_number) = block.number {
-                        let chain_head_block_number = chain_head_block_number.as_u64();
-
-                        //If there is a reorg, unwind state changes from last_synced block to the chain head block number
-                        if chain_head_block_number <= last_synced_block {
-                            tracing::trace!(
-                                chain_head_block_number,
-                                last_synced_block,
-                                "reorg detected, unwinding state changes"
-                            );
-                            unwind_state_changes(
-                                state.clone(),
-                                state_change_cache.clone(),
-                                chain_head_block_number,
-                            )
-                            .await?;
-
-                            last_synced_block = chain_head_block_number - 1;
-                        }
-
-                        let from_block: u64 = last_synced_block + 1;
-                        let logs = middleware
-                            .get_logs(
-                                &filter
-                                    .clone()
-                                    .from_block(from_block)
-                                    .to_block(chain_head_block_number),
-                            )
-                            .await
-                            .map_err(StateSpaceError::MiddlewareError)?;
-
-                        if logs.is_empty() {
-                            for block_number in from_block..=chain_head_block_number {
-                                add_state_change_to_cache(
-                                    state_change_cache.clone(),
-                                    StateChange::new(None, block_number),
-                                )
-                                .await?;
-                            }
-                        } else {
-                            handle_state_changes_from_logs(
-                                state.clone(),
-                                state_change_cache.clone(),
-                                logs,
-                                middleware.clone(),
-                            )
-                            .await?;
-                        }
-
-                        last_synced_block = chain_head_block_number;
-
-                        new_block_tx.send(block).await?;
-                    } else {
-                        return Err(StateSpaceError::BlockNumberNotFound);
-                    }
-                }
-
-                Ok::<(), StateSpaceError<M, P>>(())
-            });
+        let new_block_handle = self
+            .spawn_block_handler(
+                last_synced_block,
+                stream_rx,
+                BlockNotifier::NewBlocks(new_block_tx),
+            )
+            .await;
+        let stream_handle = self.spawn_block_subscription(stream_tx);
 
         Ok((new_block_rx, vec![stream_handle, new_block_handle]))
     }
@@ -204,7 +389,7 @@ where
     /// Listens to new blocks and handles state changes, sending a Vec<H160> containing each AMM address that incurred a state change in the block.
     pub async fn listen_for_state_changes(
         &self,
-        mut last_synced_block: u64,
+        last_synced_block: u64,
         channel_buffer: usize,
     ) -> Result<
         (
@@ -222,119 +407,210 @@ where
             "listening for state changes"
         );
 
-        let state = self.state.clone();
-        let middleware = self.middleware.clone();
-        let stream_middleware: Arc<P> = self.stream_middleware.clone();
-        let filter = self.get_block_filter().await;
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(channel_buffer);
+        let (amms_updated_tx, amms_updated_rx) = tokio::sync::mpsc::channel(channel_buffer);
 
-        let (stream_tx, mut stream_rx): (Sender<Block<H256>>, Receiver<Block<H256>>) =
-            tokio::sync::mpsc::channel(channel_buffer);
+        let updated_amms_handle = self
+            .spawn_block_handler(
+                last_synced_block,
+                stream_rx,
+                BlockNotifier::StateChanges(amms_updated_tx),
+            )
+            .await;
+        let stream_handle = self.spawn_block_subscription(stream_tx);
 
-        let stream_handle = tokio::spawn(async move {
-            let mut block_stream = stream_middleware
-                .subscribe_blocks()
-                .await
-                .map_err(StateSpaceError::PubsubClientError)?;
+        Ok((amms_updated_rx, vec![stream_handle, updated_amms_handle]))
+    }
 
-            while let Some(block) = block_stream.next().await {
-                stream_tx.send(block).await?;
-            }
+    /// Listens to new blocks and handles state changes without sending notifications through a channel when AMMs are updated.
+    pub async fn listen_for_updates(
+        &self,
+        last_synced_block: u64,
+        channel_buffer: usize,
+    ) -> Result<Vec<JoinHandle<Result<(), StateSpaceError<M, P>>>>, StateSpaceError<M, P>>
+    where
+        <P as Middleware>::Provider: PubsubClient,
+    {
+        tracing::info!(last_synced_block, channel_buffer, "listening for updates");
 
-            Ok::<(), StateSpaceError<M, P>>(())
-        });
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(channel_buffer);
 
-        let (amms_updated_tx, amms_updated_rx) = tokio::sync::mpsc::channel(channel_buffer);
+        let new_block_handle = self
+            .spawn_block_handler(last_synced_block, stream_rx, BlockNotifier::None)
+            .await;
+        let stream_handle = self.spawn_block_subscription(stream_tx);
 
-        let state_change_cache = self.state_change_cache.clone();
+        Ok(vec![stream_handle, new_block_handle])
+    }
 
-        let updated_amms_handle: JoinHandle<Result<(), StateSpaceError<M, P>>> =
-            tokio::spawn(async move {
-                while let Some(block) = stream_rx.recv().await {
-                    tracing::info!(?block, "received new block");
-                    if let Some(chain_head_block_number) = block.number {
-                        let chain_head_block_number = chain_head_block_number.as_u64();
-
-                        //If there is a reorg, unwind state changes from last_synced block to the chain head block number
-                        if chain_head_block_number <= last_synced_block {
-                            tracing::trace!(
-                                chain_head_block_number,
-                                last_synced_block,
-                                "reorg detected, unwinding state changes"
-                            );
-                            unwind_state_changes(
-                                state.clone(),
-                                state_change_cache.clone(),
-                                chain_head_block_number,
-                            )
-                            .await?;
+    /// Same as `listen_for_new_blocks`, polling `middleware` for the latest block every `poll_interval` instead of
+    /// subscribing to blocks, for environments without a pubsub transport
+    pub async fn poll_for_new_blocks(
+        &self,
+        last_synced_block: u64,
+        poll_interval: Duration,
+        channel_buffer: usize,
+    ) -> Result<
+        (
+            Receiver<Block<H256>>,
+            Vec<JoinHandle<Result<(), StateSpaceError<M, P>>>>,
+        ),
+        StateSpaceError<M, P>,
+    > {
+        tracing::info!(
+            last_synced_block,
+            ?poll_interval,
+            channel_buffer,
+            "polling for new blocks"
+        );
 
-                            //set the last synced block to the head block number
-                            last_synced_block = chain_head_block_number - 1;
-                        }
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(channel_buffer);
+        let (new_block_tx, new_block_rx) = tokio::sync::mpsc::channel(channel_buffer);
 
-                        let from_block: u64 = last_synced_block + 1;
-                        let logs = middleware
-                            .get_logs(
-                                &filter
-                                    .clone()
-                                    .from_block(from_block)
-                                    .to_block(chain_head_block_number),
-                            )
-                            .await
-                            .map_err(StateSpaceError::MiddlewareError)?;
-
-                        if logs.is_empty() {
-                            for block_number in from_block..=chain_head_block_number {
-                                add_state_change_to_cache(
-                                    state_change_cache.clone(),
-                                    StateChange::new(None, block_number),
-                                )
-                                .await?;
-                            }
-                        } else {
-                            let amms_updated = handle_state_changes_from_logs(
-                                state.clone(),
-                                state_change_cache.clone(),
-                                logs,
-                                middleware.clone(),
-                            )
-                            .await?;
+        let new_block_handle = self
+            .spawn_block_handler(
+                last_synced_block,
+                stream_rx,
+                BlockNotifier::NewBlocks(new_block_tx),
+            )
+            .await;
+        let poll_handle = self.spawn_block_polling(poll_interval, stream_tx);
 
-                            amms_updated_tx.send(amms_updated).await?;
-                        }
+        Ok((new_block_rx, vec![poll_handle, new_block_handle]))
+    }
 
-                        last_synced_block = chain_head_block_number;
-                    } else {
-                        return Err(StateSpaceError::BlockNumberNotFound);
-                    }
-                }
+    /// Same as `listen_for_state_changes`, polling `middleware` for the latest block every `poll_interval`
+    pub async fn poll_for_state_changes(
+        &self,
+        last_synced_block: u64,
+        poll_interval: Duration,
+        channel_buffer: usize,
+    ) -> Result<
+        (
+            Receiver<Vec<H160>>,
+            Vec<JoinHandle<Result<(), StateSpaceError<M, P>>>>,
+        ),
+        StateSpaceError<M, P>,
+    > {
+        tracing::info!(
+            last_synced_block,
+            ?poll_interval,
+            channel_buffer,
+            "polling for state changes"
+        );
 
-                Ok::<(), StateSpaceError<M, P>>(())
-            });
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(channel_buffer);
+        let (amms_updated_tx, amms_updated_rx) = tokio::sync::mpsc::channel(channel_buffer);
 
-        Ok((amms_updated_rx, vec![stream_handle, updated_amms_handle]))
+        let updated_amms_handle = self
+            .spawn_block_handler(
+                last_synced_block,
+                stream_rx,
+                BlockNotifier::StateChanges(amms_updated_tx),
+            )
+            .await;
+        let poll_handle = self.spawn_block_polling(poll_interval, stream_tx);
+
+        Ok((amms_updated_rx, vec![poll_handle, updated_amms_handle]))
     }
 
-    /// Listens to new blocks and handles state changes without sending notifications through a channel when AMMs are updated.
-    pub async fn listen_for_updates(
+    /// Same as `listen_for_updates`, polling `middleware` for the latest block every `poll_interval`
+    pub async fn poll_for_updates(
         &self,
-        mut last_synced_block: u64,
+        last_synced_block: u64,
+        poll_interval: Duration,
         channel_buffer: usize,
-    ) -> Result<Vec<JoinHandle<Result<(), StateSpaceError<M, P>>>>, StateSpaceError<M, P>>
-    where
-        <P as Middleware>::Provider: PubsubClient,
-    {
-        tracing::info!(last_synced_block, channel_buffer, "listening for updates");
+    ) -> Result<Vec<JoinHandle<Result<(), StateSpaceError<M, P>>>>, StateSpaceError<M, P>> {
+        tracing::info!(
+            last_synced_block,
+            ?poll_interval,
+            channel_buffer,
+            "polling for updates"
+        );
+
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(channel_buffer);
 
+        let new_block_handle = self
+            .spawn_block_handler(last_synced_block, stream_rx, BlockNotifier::None)
+            .await;
+        let poll_handle = self.spawn_block_polling(poll_interval, stream_tx);
+
+        Ok(vec![poll_handle, new_block_handle])
+    }
+
+    /// Spawns the task refreshing the AMMs due under their policy in `refresh_schedule`, checking every
+    /// `REFRESH_CHECK_INTERVAL`. Refreshes read the AMMs at the last synced block with at most
+    /// `max_concurrent_requests` batch requests in flight, apart from the tasks applying blocks. An AMM updated by a
+    /// log while its refresh was in flight keeps the state from the log, and failed refreshes are retried.
+    pub fn spawn_refresh(&self, max_concurrent_requests: usize) -> JoinHandle<()> {
         let state = self.state.clone();
         let middleware = self.middleware.clone();
-        let stream_middleware: Arc<P> = self.stream_middleware.clone();
-        let filter = self.get_block_filter().await;
+        let refresh_schedule = self.refresh_schedule.clone();
+        let sync_progress = self.sync_progress.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_CHECK_INTERVAL);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-        let (stream_tx, mut stream_rx): (Sender<Block<H256>>, Receiver<Block<H256>>) =
-            tokio::sync::mpsc::channel(channel_buffer);
+            loop {
+                interval.tick().await;
 
-        let stream_handle = tokio::spawn(async move {
+                let block_number = sync_progress.last_synced_block();
+                let due = refresh_schedule.due(block_number, std::time::Instant::now());
+                if due.is_empty() {
+                    continue;
+                }
+
+                let amms = {
+                    let state = state.read().await;
+                    due.iter()
+                        .filter_map(|address| state.get(address).cloned())
+                        .collect::<Vec<AMM>>()
+                };
+
+                tracing::debug!(block_number, amms = amms.len(), "refreshing AMMs");
+                let refreshed = match refresh_amms(
+                    amms,
+                    block_number,
+                    max_concurrent_requests,
+                    middleware.clone(),
+                )
+                .await
+                {
+                    Ok(refreshed) => refreshed,
+                    Err(error) => {
+                        tracing::warn!(block_number, ?error, "failed to refresh AMMs");
+                        continue;
+                    }
+                };
+
+                let mut state = state.write().await;
+                for amm in refreshed {
+                    let address = amm.address();
+                    if refresh_schedule.updated_after(address, block_number) {
+                        continue;
+                    }
+                    if let Some(entry) = state.get_mut(&address) {
+                        *entry = amm;
+                        refresh_schedule.record_refresh(address, block_number);
+                    }
+                }
+            }
+        })
+    }
+
+    //Forwards the blocks of a block subscription of the stream middleware
+    fn spawn_block_subscription(
+        &self,
+        stream_tx: Sender<Block<H256>>,
+    ) -> JoinHandle<Result<(), StateSpaceError<M, P>>>
+    where
+        <P as Middleware>::Provider: PubsubClient,
+    {
+        let stream_middleware = self.stream_middleware.clone();
+        let stream_metrics = self.metrics.clone();
+
+        tokio::spawn(async move {
             let mut block_stream = stream_middleware
                 .subscribe_blocks()
                 .await
@@ -342,75 +618,245 @@ where
 
             while let Some(block) = block_stream.next().await {
                 stream_tx.send(block).await?;
+                stream_metrics
+                    .channel_depth("blocks", stream_tx.max_capacity() - stream_tx.capacity());
             }
 
             Ok::<(), StateSpaceError<M, P>>(())
-        });
+        })
+    }
 
+    //Forwards the latest block of the middleware each time its hash changes, until the block handler stops.
+    //A new block at the same height is a reorg, which the block handler unwinds as it would a subscribed block.
+    fn spawn_block_polling(
+        &self,
+        poll_interval: Duration,
+        stream_tx: Sender<Block<H256>>,
+    ) -> JoinHandle<Result<(), StateSpaceError<M, P>>> {
+        let middleware = self.middleware.clone();
+        let stream_metrics = self.metrics.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(poll_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut last_block_hash = None;
+
+            while !stream_tx.is_closed() {
+                interval.tick().await;
+
+                let block = middleware
+                    .get_block(BlockNumber::Latest)
+                    .await
+                    .map_err(StateSpaceError::MiddlewareError)?;
+
+                if let Some(block) = block.filter(|block| block.hash != last_block_hash) {
+                    last_block_hash = block.hash;
+                    stream_tx.send(block).await?;
+                    stream_metrics
+                        .channel_depth("blocks", stream_tx.max_capacity() - stream_tx.capacity());
+                }
+            }
+
+            Ok::<(), StateSpaceError<M, P>>(())
+        })
+    }
+
+    //Applies the logs of each block received on `stream_rx` from the block after the last synced block, unwinding
+    //the state changes of reorged blocks first, then notifies listeners through `notifier`
+    async fn spawn_block_handler(
+        &self,
+        mut last_synced_block: u64,
+        mut stream_rx: Receiver<Block<H256>>,
+        notifier: BlockNotifier,
+    ) -> JoinHandle<Result<(), StateSpaceError<M, P>>> {
+        let state = self.state.clone();
+        let middleware = self.middleware.clone();
+        let filter = self.get_block_filter().await;
+        let event_signatures = self
+            .event_signatures()
+            .await
+            .into_iter()
+            .collect::<HashSet<H256>>();
+        let sync_progress = self.sync_progress.clone();
+        sync_progress.start(last_synced_block);
+        let refresh_schedule = self.refresh_schedule.clone();
+        refresh_schedule.start(last_synced_block);
+
+        let metrics = self.metrics.clone();
         let state_change_cache = self.state_change_cache.clone();
-        let new_block_handle: JoinHandle<Result<(), StateSpaceError<M, P>>> =
-            tokio::spawn(async move {
-                while let Some(block) = stream_rx.recv().await {
-                    tracing::info!(?block, "received new block");
-                    if let Some(chain_head_block_number) = block.number {
-                        let chain_head_block_number = chain_head_block_number.as_u64();
-
-                        //If there is a reorg, unwind state changes from last_synced block to the chain head block number
-                        if chain_head_block_number <= last_synced_block {
-                            tracing::trace!(
-                                chain_head_block_number,
-                                last_synced_block,
-                                "reorg detected, unwinding state changes"
-                            );
-                            unwind_state_changes(
-                                state.clone(),
-                                state_change_cache.clone(),
-                                chain_head_block_number,
-                            )
-                            .await?;
 
-                            last_synced_block = chain_head_block_number - 1;
-                        }
+        tokio::spawn(async move {
+            while let Some(block) = stream_rx.recv().await {
+                tracing::info!(?block, "received new block");
+                if let Some(chain_head_block_number) = block.number {
+                    let chain_head_block_number = chain_head_block_number.as_u64();
+                    metrics.block_lag(chain_head_block_number.saturating_sub(last_synced_block));
+                    let _apply_timer = metrics.block_apply_timer();
+
+                    //If there is a reorg, unwind state changes from last_synced block to the chain head block number
+                    if chain_head_block_number <= last_synced_block {
+                        tracing::trace!(
+                            chain_head_block_number,
+                            last_synced_block,
+                            "reorg detected, unwinding state changes"
+                        );
+                        metrics.reorg();
+                        //Rewound before unwinding, so waiters for reorged blocks wait for their replacements
+                        sync_progress.rewind(chain_head_block_number - 1);
+                        unwind_state_changes(
+                            state.clone(),
+                            state_change_cache.clone(),
+                            chain_head_block_number,
+                        )
+                        .await?;
+
+                        //set the last synced block to the head block number
+                        last_synced_block = chain_head_block_number - 1;
+                    }
 
-                        let from_block: u64 = last_synced_block + 1;
-                        let logs = middleware
-                            .get_logs(
-                                &filter
-                                    .clone()
-                                    .from_block(from_block)
-                                    .to_block(chain_head_block_number),
-                            )
-                            .await
-                            .map_err(StateSpaceError::MiddlewareError)?;
-
-                        if logs.is_empty() {
-                            for block_number in from_block..=chain_head_block_number {
-                                add_state_change_to_cache(
-                                    state_change_cache.clone(),
-                                    StateChange::new(None, block_number),
-                                )
-                                .await?;
-                            }
-                        } else {
-                            handle_state_changes_from_logs(
-                                state.clone(),
+                    let from_block: u64 = last_synced_block + 1;
+                    if from_block < chain_head_block_number {
+                        sync_progress.set_resyncing(true);
+                    }
+
+                    let logs = middleware
+                        .get_logs(
+                            &filter
+                                .clone()
+                                .from_block(from_block)
+                                .to_block(chain_head_block_number),
+                        )
+                        .await
+                        .map_err(StateSpaceError::MiddlewareError)?;
+
+                    let amms_updated = if logs.is_empty() {
+                        for block_number in from_block..=chain_head_block_number {
+                            add_state_change_to_cache(
                                 state_change_cache.clone(),
-                                logs,
-                                middleware.clone(),
+                                StateChange::new(None, block_number),
                             )
                             .await?;
                         }
-
-                        last_synced_block = chain_head_block_number;
+                        None
                     } else {
-                        return Err(StateSpaceError::BlockNumberNotFound);
+                        let amms_updated = handle_state_changes_from_logs_with_metrics(
+                            state.clone(),
+                            state_change_cache.clone(),
+                            logs,
+                            &event_signatures,
+                            middleware.clone(),
+                            &metrics,
+                        )
+                        .await?;
+                        refresh_schedule.record_updates(&amms_updated, chain_head_block_number);
+                        Some(amms_updated)
+                    };
+
+                    //Advanced before notifying, so receivers see the block the updates were applied at
+                    last_synced_block = chain_head_block_number;
+                    sync_progress.advance(last_synced_block);
+                    metrics.block_lag(0);
+
+                    match &notifier {
+                        BlockNotifier::NewBlocks(new_block_tx) => new_block_tx.send(block).await?,
+                        BlockNotifier::StateChanges(amms_updated_tx) => {
+                            if let Some(amms_updated) = amms_updated {
+                                amms_updated_tx.send(amms_updated).await?;
+                            }
+                        }
+                        BlockNotifier::None => {}
                     }
+                } else {
+                    return Err(StateSpaceError::BlockNumberNotFound);
                 }
+            }
 
-                Ok::<(), StateSpaceError<M, P>>(())
-            });
+            Ok::<(), StateSpaceError<M, P>>(())
+        })
+    }
+}
 
-        Ok(vec![stream_handle, new_block_handle])
+//What a block handler sends once it applied a block
+#[cfg(feature = "state-space")]
+enum BlockNotifier {
+    NewBlocks(Sender<Block<H256>>),
+    //The AMMs updated in blocks with logs
+    StateChanges(Sender<Vec<H160>>),
+    None,
+}
+
+#[cfg(feature = "state-space")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// Every block up to the chain head has been applied and new blocks are applied as they arrive.
+    Live,
+    /// The manager is catching up on a range of blocks or unwinding a reorg, state may be partially applied.
+    Resyncing,
+}
+
+/// Tracks the last block applied to the state space, shared between the manager and its listener tasks.
+#[cfg(feature = "state-space")]
+#[derive(Debug, Default)]
+pub struct SyncProgress {
+    last_synced_block: AtomicU64,
+    resyncing: AtomicBool,
+    block_synced: Notify,
+}
+
+#[cfg(feature = "state-space")]
+impl SyncProgress {
+    pub fn last_synced_block(&self) -> u64 {
+        self.last_synced_block.load(Ordering::Acquire)
+    }
+
+    pub fn sync_status(&self) -> SyncStatus {
+        if self.resyncing.load(Ordering::Acquire) {
+            SyncStatus::Resyncing
+        } else {
+            SyncStatus::Live
+        }
+    }
+
+    pub async fn wait_for_block(&self, block_number: u64) {
+        loop {
+            //Register interest before checking the block so that a concurrent advance is not missed
+            let block_synced = self.block_synced.notified();
+
+            if self.last_synced_block() >= block_number {
+                return;
+            }
+
+            block_synced.await;
+        }
+    }
+
+    //Called when a listener starts, the manager is considered resyncing until the first block from the stream is applied
+    fn start(&self, last_synced_block: u64) {
+        self.resyncing.store(true, Ordering::Release);
+        self.advance_to(last_synced_block);
+    }
+
+    fn set_resyncing(&self, resyncing: bool) {
+        self.resyncing.store(resyncing, Ordering::Release);
+    }
+
+    //Marks every state change up to and including `block_number` as applied
+    fn advance(&self, block_number: u64) {
+        self.set_resyncing(false);
+        self.advance_to(block_number);
+    }
+
+    //Marks the blocks after `block_number` as no longer applied, as when a reorg unwinds them
+    fn rewind(&self, block_number: u64) {
+        self.set_resyncing(true);
+        self.last_synced_block
+            .fetch_min(block_number, Ordering::AcqRel);
+    }
+
+    fn advance_to(&self, block_number: u64) {
+        self.last_synced_block
+            .store(block_number, Ordering::Release);
+        self.block_synced.notify_waiters();
     }
 }
 
@@ -420,12 +866,14 @@ pub fn initialize_state_space(amms: Vec<AMM>) -> StateSpace {
         .collect::<HashMap<H160, AMM>>()
 }
 
+#[cfg(feature = "state-space")]
 #[derive(Debug)]
 pub struct StateChange {
     pub state_change: Option<Vec<AMM>>,
     pub block_number: u64,
 }
 
+#[cfg(feature = "state-space")]
 impl StateChange {
     pub fn new(state_change: Option<Vec<AMM>>, block_number: u64) -> Self {
         Self {
@@ -436,6 +884,8 @@ impl StateChange {
 }
 
 //Unwinds the state changes cache for every block from the most recent state change cache back to the block to unwind -1
+#[cfg(feature = "state-space")]
+#[tracing::instrument(level = "debug", skip(state, state_change_cache), err(Debug))]
 async fn unwind_state_changes(
     state: Arc<RwLock<StateSpace>>,
     state_change_cache: Arc<RwLock<StateChangeCache>>,
@@ -469,6 +919,7 @@ async fn unwind_state_changes(
     }
 }
 
+#[cfg(feature = "state-space")]
 async fn add_state_change_to_cache(
     state_change_cache: Arc<RwLock<StateChangeCache>>,
     state_change: StateChange,
@@ -488,97 +939,201 @@ async fn add_state_change_to_cache(
     Ok(())
 }
 
+#[cfg(feature = "state-space")]
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(
+        block_number = logs.last().and_then(|log| log.block_number).map(|block| block.as_u64()),
+        logs = logs.len()
+    ),
+    err(Debug)
+)]
 pub async fn handle_state_changes_from_logs<M: Middleware>(
     state: Arc<RwLock<StateSpace>>,
     state_change_cache: Arc<RwLock<StateChangeCache>>,
     logs: Vec<Log>,
+    event_signatures: &HashSet<H256>,
+    middleware: Arc<M>,
+) -> Result<Vec<H160>, StateChangeError> {
+    handle_state_changes_from_logs_with_metrics(
+        state,
+        state_change_cache,
+        logs,
+        event_signatures,
+        middleware,
+        &Metrics::default(),
+    )
+    .await
+}
+
+/// Same as `handle_state_changes_from_logs`, counting the logs AMMs fail to sync from in `metrics`
+#[cfg(feature = "state-space")]
+pub async fn handle_state_changes_from_logs_with_metrics<M: Middleware>(
+    state: Arc<RwLock<StateSpace>>,
+    state_change_cache: Arc<RwLock<StateChangeCache>>,
+    mut logs: Vec<Log>,
+    event_signatures: &HashSet<H256>,
     _middleware: Arc<M>,
+    metrics: &Metrics,
 ) -> Result<Vec<H160>, StateChangeError> {
     let mut updated_amms_set = HashSet::new();
     let mut updated_amms = vec![];
-    let mut state_changes = vec![];
 
-    let mut last_log_block_number = if let Some(log) = logs.get(0) {
-        get_block_number_from_log(log)?
-    } else {
-        return Ok(updated_amms);
-    };
+    //Drop logs that no AMM syncs on before doing any work on them
+    logs.retain(|log| {
+        log.topics
+            .first()
+            .is_some_and(|topic| event_signatures.contains(topic))
+    });
 
-    for log in logs.into_iter() {
-        let log_block_number = get_block_number_from_log(&log)?;
+    //Apply logs in the order they were emitted on chain, regardless of the order the provider returned them in
+    let logs = order_logs(logs);
 
-        // check if the log is from an amm in the state space
-        if let Some(amm) = state.write().await.get_mut(&log.address) {
-            if !updated_amms_set.contains(&log.address) {
-                updated_amms_set.insert(log.address);
-                updated_amms.push(log.address);
-            }
+    for block_logs in logs.chunk_by(|a, b| a.block_number == b.block_number) {
+        let block_number = get_block_number_from_log(&block_logs[0])?;
 
-            state_changes.push(amm.clone());
-            amm.sync_from_log(log)?;
-        }
+        let state_changes = {
+            let mut state = state.write().await;
+            apply_block_logs(&mut state, block_logs, metrics)
+        };
 
-        //Commit state changes if the block has changed since last log
-        if log_block_number != last_log_block_number {
-            if state_changes.is_empty() {
-                add_state_change_to_cache(
-                    state_change_cache.clone(),
-                    StateChange::new(None, last_log_block_number),
-                )
-                .await?;
-            } else {
-                add_state_change_to_cache(
-                    state_change_cache.clone(),
-                    StateChange::new(Some(state_changes), last_log_block_number),
-                )
-                .await?;
-                state_changes = vec![];
-            };
-
-            last_log_block_number = log_block_number;
+        for amm in &state_changes {
+            if updated_amms_set.insert(amm.address()) {
+                updated_amms.push(amm.address());
+            }
         }
-    }
 
-    if state_changes.is_empty() {
-        add_state_change_to_cache(
-            state_change_cache,
-            StateChange::new(None, last_log_block_number),
-        )
-        .await?;
-    } else {
+        let state_changes = if state_changes.is_empty() {
+            None
+        } else {
+            Some(state_changes)
+        };
+
         add_state_change_to_cache(
-            state_change_cache,
-            StateChange::new(Some(state_changes), last_log_block_number),
+            state_change_cache.clone(),
+            StateChange::new(state_changes, block_number),
         )
         .await?;
-    };
+    }
 
     Ok(updated_amms)
 }
 
+//Applies the logs of a single block, returning the state of each updated AMM before the block, in the order the AMMs were first touched.
+//AMMs only read their own logs, so logs are partitioned by the AMM they are meant for and each AMM is looked up and
+//cloned once per block. Logs an AMM fails to sync from are skipped, AMMs check a log before applying it so the AMM is
+//left as it was.
+#[cfg(feature = "state-space")]
+fn apply_block_logs(state: &mut StateSpace, logs: &[Log], metrics: &Metrics) -> Vec<AMM> {
+    //The sort is stable, so logs keep their on chain order within each AMM
+    let mut logs_by_amm = logs
+        .iter()
+        .enumerate()
+        .map(|(i, log)| (i, crate::amm::log_amm_address(log), log))
+        .collect::<Vec<_>>();
+    logs_by_amm.sort_by_key(|(_, address, _)| *address);
+
+    let mut state_changes = vec![];
+    for amm_logs in logs_by_amm.chunk_by(|(_, a, _), (_, b, _)| a == b) {
+        let (first_seen, address, _) = amm_logs[0];
+
+        if let Some(amm) = state.get_mut(&address) {
+            state_changes.push((first_seen, amm.clone()));
+
+            for (_, _, log) in amm_logs {
+                if let Err(error) = amm.sync_from_log(log) {
+                    tracing::warn!(
+                        address = ?log.address,
+                        topic0 = ?log.topics.first(),
+                        block_number = ?log.block_number,
+                        log_index = ?log.log_index,
+                        %error,
+                        "skipping log"
+                    );
+                    metrics.log_error(log_error_kind(&error));
+                }
+            }
+        }
+    }
+
+    state_changes.sort_unstable_by_key(|(first_seen, _)| *first_seen);
+
+    state_changes.into_iter().map(|(_, amm)| amm).collect()
+}
+
+#[cfg(feature = "state-space")]
+fn log_error_kind(error: &EventLogError) -> &'static str {
+    match error {
+        EventLogError::UnexpectedEvent(_) => "unexpected_event",
+        EventLogError::LogBlockNumberNotFound(_) => "missing_block_number",
+        EventLogError::EthABIError(_) | EventLogError::ABIError(_) => "abi",
+        EventLogError::MalformedLog(_) => "malformed_log",
+        EventLogError::MissingTopic { .. } => "missing_topic",
+        EventLogError::TruncatedData { .. } => "truncated_data",
+        EventLogError::LogAddressMismatch { .. } => "log_address_mismatch",
+        EventLogError::PoolIdMismatch { .. } => "pool_id_mismatch",
+    }
+}
+
+/// Sorts logs by (block_number, transaction_index, log_index) and drops duplicate deliveries of the same log.
+///
+/// Logs missing positional data keep their relative order, since the sort is stable.
+pub fn order_logs(mut logs: Vec<Log>) -> Vec<Log> {
+    logs.sort_by_key(|log| (log.block_number, log.transaction_index, log.log_index));
+
+    //Duplicates are adjacent after sorting, a log is a duplicate if it has the same position in the same transaction
+    logs.dedup_by(|log, previous| {
+        if log.log_index.is_some() && log.transaction_hash.is_some() {
+            log.block_number == previous.block_number
+                && log.transaction_hash == previous.transaction_hash
+                && log.log_index == previous.log_index
+        } else {
+            log == previous
+        }
+    });
+
+    logs
+}
+
 pub fn get_block_number_from_log(log: &Log) -> Result<u64, EventLogError> {
     if let Some(block_number) = log.block_number {
         Ok(block_number.as_u64())
     } else {
-        Err(EventLogError::LogBlockNumberNotFound)
+        Err(EventLogError::LogBlockNumberNotFound(log.into()))
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "state-space", feature = "ws"))]
 mod tests {
-    use std::{default, sync::Arc};
-
-    use crate::amm::{uniswap_v2::UniswapV2Pool, AMM};
+    use std::{collections::HashSet, default, sync::Arc};
+
+    use crate::amm::{
+        balancer::{self, BalancerWeightedPool},
+        factory::{AutomatedMarketMakerFactory, Factory},
+        uniswap_v2::{
+            factory::{UniswapV2Factory, PAIR_CREATED_EVENT_SIGNATURE},
+            UniswapV2Pool, SYNC_EVENT_SIGNATURE,
+        },
+        uniswap_v3::{
+            UniswapV3Pool, BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE, SWAP_EVENT_SIGNATURE,
+        },
+        AmmState, AMM,
+    };
+    use crate::errors::EventLogError;
     use ethers::{
+        abi::Token,
         providers::{Http, Provider, Ws},
-        types::H160,
+        types::{Log, ValueOrArray, H160, H256, U256, U64},
     };
     use tokio::sync::RwLock;
 
     use super::StateSpaceManager;
     use crate::state_space::state::{
-        add_state_change_to_cache, unwind_state_changes, StateChange, StateChangeCache,
+        add_state_change_to_cache, handle_state_changes_from_logs, order_logs,
+        unwind_state_changes, StateChange, StateChangeCache, SyncProgress, SyncStatus,
     };
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_add_state_changes() -> eyre::Result<()> {
@@ -674,4 +1229,533 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_wait_for_block() -> eyre::Result<()> {
+        let sync_progress = Arc::new(SyncProgress::default());
+        sync_progress.start(10);
+
+        assert_eq!(sync_progress.last_synced_block(), 10);
+        assert_eq!(sync_progress.sync_status(), SyncStatus::Resyncing);
+
+        //Already synced blocks resolve immediately
+        tokio::time::timeout(Duration::from_secs(1), sync_progress.wait_for_block(9)).await?;
+
+        let waiter = {
+            let sync_progress = sync_progress.clone();
+            tokio::spawn(async move { sync_progress.wait_for_block(12).await })
+        };
+
+        sync_progress.advance(11);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), sync_progress.wait_for_block(12))
+                .await
+                .is_err()
+        );
+
+        sync_progress.advance(12);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await??;
+
+        assert_eq!(sync_progress.last_synced_block(), 12);
+        assert_eq!(sync_progress.sync_status(), SyncStatus::Live);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_block_after_reorg() -> eyre::Result<()> {
+        let sync_progress = Arc::new(SyncProgress::default());
+        sync_progress.start(10);
+        sync_progress.advance(12);
+
+        //A new block 11 unwinds blocks 11 and 12
+        sync_progress.rewind(10);
+        assert_eq!(sync_progress.last_synced_block(), 10);
+        assert_eq!(sync_progress.sync_status(), SyncStatus::Resyncing);
+
+        //Reorged blocks are waited for again until their replacements are applied
+        let waiter = {
+            let sync_progress = sync_progress.clone();
+            tokio::spawn(async move { sync_progress.wait_for_block(11).await })
+        };
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), sync_progress.wait_for_block(11))
+                .await
+                .is_err()
+        );
+        //Blocks before the reorg stay synced
+        tokio::time::timeout(Duration::from_secs(1), sync_progress.wait_for_block(10)).await?;
+
+        sync_progress.advance(11);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await??;
+        assert_eq!(sync_progress.sync_status(), SyncStatus::Live);
+
+        //Rewinding never moves the watermark forward
+        sync_progress.rewind(20);
+        assert_eq!(sync_progress.last_synced_block(), 11);
+
+        Ok(())
+    }
+
+    fn sync_log(
+        address: H160,
+        reserves: (u128, u128),
+        block_number: u64,
+        transaction_index: u64,
+        log_index: u64,
+    ) -> Log {
+        Log {
+            address,
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(reserves.0)),
+                Token::Uint(U256::from(reserves.1)),
+            ])
+            .into(),
+            block_number: Some(U64::from(block_number)),
+            transaction_hash: Some(H256::from_low_u64_be(transaction_index + 1)),
+            transaction_index: Some(U64::from(transaction_index)),
+            log_index: Some(U256::from(log_index)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_order_logs() {
+        let address = H160::from_low_u64_be(1);
+        let logs = vec![
+            sync_log(address, (3, 3), 2, 0, 0),
+            sync_log(address, (2, 2), 1, 1, 3),
+            sync_log(address, (1, 1), 1, 0, 1),
+            sync_log(address, (2, 2), 1, 1, 3),
+        ];
+
+        let ordered = order_logs(logs);
+        let positions = ordered
+            .iter()
+            .map(|log| (log.block_number, log.log_index))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            positions,
+            vec![
+                (Some(U64::from(1)), Some(U256::from(1))),
+                (Some(U64::from(1)), Some(U256::from(3))),
+                (Some(U64::from(2)), Some(U256::from(0))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_intra_block_sync_ordering() -> eyre::Result<()> {
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let address = H160::from_low_u64_be(1);
+
+        let older = sync_log(address, (100, 200), 10, 0, 4);
+        let newer = sync_log(address, (150, 175), 10, 2, 9);
+
+        for logs in [
+            vec![older.clone(), newer.clone()],
+            vec![newer.clone(), older.clone()],
+            vec![newer.clone(), older.clone(), newer.clone()],
+        ] {
+            let amms = vec![AMM::UniswapV2Pool(UniswapV2Pool {
+                address,
+                ..default::Default::default()
+            })];
+
+            let state = Arc::new(RwLock::new(super::initialize_state_space(amms)));
+            let state_change_cache = Arc::new(RwLock::new(StateChangeCache::new()));
+
+            let updated_amms = handle_state_changes_from_logs(
+                state.clone(),
+                state_change_cache.clone(),
+                logs,
+                &HashSet::from([SYNC_EVENT_SIGNATURE]),
+                middleware.clone(),
+            )
+            .await?;
+
+            assert_eq!(updated_amms, vec![address]);
+
+            if let Some(AMM::UniswapV2Pool(pool)) = state.read().await.get(&address) {
+                assert_eq!((pool.reserve_0, pool.reserve_1), (150, 175));
+            } else {
+                panic!("Pool not found in state space")
+            }
+
+            //Both logs are in the same block, so only a single state change is committed
+            assert_eq!(state_change_cache.read().await.len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_log_skipped() -> eyre::Result<()> {
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let address = H160::from_low_u64_be(1);
+
+        let mut truncated = sync_log(address, (1, 1), 10, 0, 5);
+        truncated.data = truncated.data[..40].to_vec().into();
+
+        let amms = vec![AMM::UniswapV2Pool(UniswapV2Pool {
+            address,
+            ..default::Default::default()
+        })];
+        let state = Arc::new(RwLock::new(super::initialize_state_space(amms)));
+
+        //The truncated log is skipped and the logs around it are still applied
+        let updated_amms = handle_state_changes_from_logs(
+            state.clone(),
+            Arc::new(RwLock::new(StateChangeCache::new())),
+            vec![
+                sync_log(address, (100, 200), 10, 0, 4),
+                truncated,
+                sync_log(address, (150, 175), 10, 0, 6),
+            ],
+            &HashSet::from([SYNC_EVENT_SIGNATURE]),
+            middleware,
+        )
+        .await?;
+
+        assert_eq!(updated_amms, vec![address]);
+        if let Some(AMM::UniswapV2Pool(pool)) = state.read().await.get(&address) {
+            assert_eq!((pool.reserve_0, pool.reserve_1), (150, 175));
+        } else {
+            panic!("Pool not found in state space")
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crossed_logs_rejected() -> eyre::Result<()> {
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let (address_a, address_b) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+
+        let amms = [address_a, address_b]
+            .into_iter()
+            .map(|address| {
+                AMM::UniswapV2Pool(UniswapV2Pool {
+                    address,
+                    reserve_0: 1,
+                    reserve_1: 1,
+                    ..default::Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        let state = Arc::new(RwLock::new(super::initialize_state_space(amms)));
+
+        let log_a = sync_log(address_a, (100, 200), 10, 0, 0);
+        let log_b = sync_log(address_b, (300, 400), 10, 1, 1);
+
+        //Routing each log to the other pool, as an address-less filter would, leaves both pools untouched
+        for (address, log) in [(address_a, &log_b), (address_b, &log_a)] {
+            let mut state = state.write().await;
+            let amm = state.get_mut(&address).unwrap();
+
+            match amm.sync_from_log(log) {
+                Err(EventLogError::LogAddressMismatch { expected, got, .. }) => {
+                    assert_eq!((expected, got), (address, log.address));
+                }
+                result => panic!("Unexpected result {result:?}"),
+            }
+        }
+
+        for address in [address_a, address_b] {
+            if let Some(AMM::UniswapV2Pool(pool)) = state.read().await.get(&address) {
+                assert_eq!((pool.reserve_0, pool.reserve_1), (1, 1));
+            } else {
+                panic!("Pool not found in state space")
+            }
+        }
+
+        //Delivered together, each log only updates the pool that emitted it
+        handle_state_changes_from_logs(
+            state.clone(),
+            Arc::new(RwLock::new(StateChangeCache::new())),
+            vec![log_a, log_b],
+            &HashSet::from([SYNC_EVENT_SIGNATURE]),
+            middleware,
+        )
+        .await?;
+
+        for (address, reserves) in [(address_a, (100, 200)), (address_b, (300, 400))] {
+            if let Some(AMM::UniswapV2Pool(pool)) = state.read().await.get(&address) {
+                assert_eq!((pool.reserve_0, pool.reserve_1), reserves);
+            } else {
+                panic!("Pool not found in state space")
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_busy_block_routing() -> eyre::Result<()> {
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let addresses = (1..=3).map(H160::from_low_u64_be).collect::<Vec<_>>();
+
+        let amms = addresses
+            .iter()
+            .map(|address| {
+                AMM::UniswapV2Pool(UniswapV2Pool {
+                    address: *address,
+                    reserve_0: 1,
+                    reserve_1: 1,
+                    ..default::Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        //Interleaved logs from several pools, plus logs from an untracked address and with an untracked signature
+        let mut untracked_signature = sync_log(addresses[0], (0, 0), 10, 0, 1);
+        untracked_signature.topics = vec![H256::from_low_u64_be(1)];
+        let logs = vec![
+            sync_log(addresses[2], (30, 30), 10, 0, 0),
+            untracked_signature,
+            sync_log(addresses[0], (10, 10), 10, 0, 2),
+            sync_log(H160::from_low_u64_be(4), (40, 40), 10, 0, 3),
+            sync_log(addresses[2], (31, 31), 10, 1, 4),
+            sync_log(addresses[0], (11, 11), 10, 1, 5),
+            sync_log(addresses[1], (20, 20), 11, 0, 0),
+        ];
+
+        let state = Arc::new(RwLock::new(super::initialize_state_space(amms)));
+        let state_change_cache = Arc::new(RwLock::new(StateChangeCache::new()));
+
+        let updated_amms = handle_state_changes_from_logs(
+            state.clone(),
+            state_change_cache.clone(),
+            logs,
+            &HashSet::from([SYNC_EVENT_SIGNATURE]),
+            middleware,
+        )
+        .await?;
+
+        //Updated AMMs are reported in the order they were first touched
+        assert_eq!(updated_amms, vec![addresses[2], addresses[0], addresses[1]]);
+
+        let reserves = |amm: &AMM| match amm {
+            AMM::UniswapV2Pool(pool) => (pool.reserve_0, pool.reserve_1),
+            _ => panic!("Unexpected AMM variant"),
+        };
+
+        let state = state.read().await;
+        assert_eq!(reserves(&state[&addresses[0]]), (11, 11));
+        assert_eq!(reserves(&state[&addresses[1]]), (20, 20));
+        assert_eq!(reserves(&state[&addresses[2]]), (31, 31));
+
+        //Each state change holds the state of the AMM before the block, once per AMM
+        let state_change_cache = state_change_cache.read().await;
+        assert_eq!(state_change_cache.len(), 2);
+        let block_10 = state_change_cache.get(1).unwrap();
+        assert_eq!(block_10.block_number, 10);
+
+        let block_10 = block_10.state_change.as_ref().unwrap();
+        assert_eq!(
+            block_10.iter().map(|amm| amm.address()).collect::<Vec<_>>(),
+            vec![addresses[2], addresses[0]]
+        );
+        assert!(block_10.iter().all(|amm| reserves(amm) == (1, 1)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vault_log_routing() -> eyre::Result<()> {
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let tokens = vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)];
+
+        //Pools of the same Vault, named by the pool id leading with their address
+        let pools = (1..=2)
+            .map(|i| {
+                let address = H160::from_low_u64_be(i * 10);
+                let mut pool_id = H256::zero();
+                pool_id[..20].copy_from_slice(address.as_bytes());
+
+                BalancerWeightedPool::new(
+                    address,
+                    pool_id,
+                    tokens.clone(),
+                    vec![18, 18],
+                    vec![U256::exp10(21); 2],
+                    vec![U256::exp10(17) * 5; 2],
+                    U256::zero(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let swap_log = |pool: &BalancerWeightedPool, amount: u64, log_index: u64| Log {
+            address: balancer::VAULT_ADDRESS,
+            topics: vec![
+                balancer::SWAP_EVENT_SIGNATURE,
+                pool.pool_id,
+                tokens[0].into(),
+                tokens[1].into(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(amount)),
+                Token::Uint(U256::from(amount)),
+            ])
+            .into(),
+            block_number: Some(U64::from(10)),
+            transaction_hash: Some(H256::from_low_u64_be(1)),
+            transaction_index: Some(U64::zero()),
+            log_index: Some(U256::from(log_index)),
+            ..Default::default()
+        };
+
+        let logs = vec![
+            swap_log(&pools[1], 20, 0),
+            swap_log(&pools[0], 10, 1),
+            swap_log(&pools[1], 21, 2),
+        ];
+        let state = Arc::new(RwLock::new(super::initialize_state_space(
+            pools
+                .iter()
+                .cloned()
+                .map(AMM::BalancerWeightedPool)
+                .collect(),
+        )));
+
+        let updated_amms = handle_state_changes_from_logs(
+            state.clone(),
+            Arc::new(RwLock::new(StateChangeCache::new())),
+            logs,
+            &HashSet::from(balancer::VAULT_EVENT_SIGNATURES),
+            middleware,
+        )
+        .await?;
+        assert_eq!(updated_amms, vec![pools[1].address, pools[0].address]);
+
+        let state = state.read().await;
+        for (pool, amount) in [(&pools[0], 10), (&pools[1], 41)] {
+            match &state[&pool.address] {
+                AMM::BalancerWeightedPool(synced) => assert_eq!(
+                    synced.balances,
+                    vec![U256::exp10(21) + amount, U256::exp10(21) - amount]
+                ),
+                _ => panic!("Unexpected AMM variant"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_new_pool_from_creation_log() -> eyre::Result<()> {
+        let ws_endpoint = std::env::var("ETHEREUM_WS_ENDPOINT")?;
+        let rpc_endpoint = std::env::var("ETHEREUM_RPC_ENDPOINT")?;
+        let middleware = Arc::new(Provider::<Http>::try_from(rpc_endpoint)?);
+        let stream_middleware = Arc::new(Provider::<Ws>::connect(ws_endpoint).await?);
+
+        let factory =
+            Factory::UniswapV2Factory(UniswapV2Factory::new(H160::from_low_u64_be(100), 0, 300));
+        let pair = H160::from_low_u64_be(1);
+
+        //Recorded PairCreated log for a pool that is not yet in the state space
+        let creation_log = Log {
+            address: factory.address(),
+            topics: vec![
+                PAIR_CREATED_EVENT_SIGNATURE,
+                H256::from(H160::from_low_u64_be(2)),
+                H256::from(H160::from_low_u64_be(3)),
+            ],
+            data: ethers::abi::encode(&[Token::Address(pair), Token::Uint(U256::one())]).into(),
+            block_number: Some(U64::from(10)),
+            ..Default::default()
+        };
+
+        let state_space_manager =
+            StateSpaceManager::new(vec![], middleware.clone(), stream_middleware);
+
+        let amm = factory.new_empty_amm_from_log(&creation_log)?;
+        assert_eq!(state_space_manager.add_amms(vec![amm]).await, vec![pair]);
+
+        //Duplicate creation logs do not overwrite the pool
+        let duplicate = factory.new_empty_amm_from_log(&creation_log)?;
+        assert!(state_space_manager
+            .add_amms(vec![duplicate])
+            .await
+            .is_empty());
+
+        //The pool is synced from the block after its creation
+        handle_state_changes_from_logs(
+            state_space_manager.state.clone(),
+            state_space_manager.state_change_cache.clone(),
+            vec![sync_log(pair, (10, 20), 11, 0, 0)],
+            &HashSet::from([SYNC_EVENT_SIGNATURE]),
+            middleware,
+        )
+        .await?;
+
+        if let Some(AMM::UniswapV2Pool(pool)) = state_space_manager.state.read().await.get(&pair) {
+            assert_eq!((pool.reserve_0, pool.reserve_1), (10, 20));
+        } else {
+            panic!("Pool not found in state space")
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_only_filter() -> eyre::Result<()> {
+        //The filter is built without sending requests
+        let middleware = Arc::new(Provider::<Http>::try_from("http://localhost:8545")?);
+        let (v2_pool, v3_pool, added_pool) = (
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(3),
+        );
+        let amms = vec![
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: v2_pool,
+                ..Default::default()
+            }),
+            AMM::UniswapV3Pool(UniswapV3Pool {
+                address: v3_pool,
+                ..Default::default()
+            }),
+        ];
+
+        let state_space_manager = StateSpaceManager::watch_only(amms.clone(), middleware.clone());
+        let filter = state_space_manager.get_block_filter().await;
+
+        assert_eq!(
+            filter.address,
+            Some(ValueOrArray::Array(vec![v3_pool, v2_pool]))
+        );
+        let topic0 = match &filter.topics[0] {
+            Some(ValueOrArray::Array(topics)) => topics.iter().flatten().copied().collect(),
+            _ => HashSet::new(),
+        };
+        assert_eq!(
+            topic0,
+            HashSet::from([
+                SYNC_EVENT_SIGNATURE,
+                SWAP_EVENT_SIGNATURE,
+                MINT_EVENT_SIGNATURE,
+                BURN_EVENT_SIGNATURE
+            ])
+        );
+
+        //AMMs added later are watched by listeners started afterwards
+        state_space_manager
+            .add_amms(vec![AMM::UniswapV2Pool(UniswapV2Pool {
+                address: added_pool,
+                ..Default::default()
+            })])
+            .await;
+        assert_eq!(
+            state_space_manager.get_block_filter().await.address,
+            Some(ValueOrArray::Array(vec![v3_pool, v2_pool, added_pool]))
+        );
+
+        //The full manager requests the logs of every contract
+        let state_space_manager = StateSpaceManager::new(amms, middleware.clone(), middleware);
+        assert_eq!(state_space_manager.get_block_filter().await.address, None);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,3 @@
+/// Requires the `sqlite` feature
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
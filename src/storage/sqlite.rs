@@ -0,0 +1,1859 @@
+use std::{path::Path, str::FromStr, sync::Mutex};
+
+use ethers::types::{H160, H256, U256};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction};
+use thiserror::Error;
+
+use crate::{
+    amm::{
+        algebra::AlgebraPool,
+        balancer::BalancerWeightedPool,
+        bancor_v3::{self, BancorV3Pool},
+        curve::CurveStableSwapPool,
+        curve_crypto::CurveCryptoPool,
+        custom::CustomAMM,
+        dodo::{DodoPool, PMMState, RState},
+        erc_4626::ERC4626Vault,
+        kyber_elastic::{KyberElasticPool, KyberTick},
+        liquidity_book::LiquidityBookPool,
+        maverick::{BinKind, MaverickBin, MaverickPool},
+        uniswap_v2::{FeeChangeEvent, TransferTax, UniswapV2Pool},
+        uniswap_v3::{tick_serde, UniswapV3Pool},
+        uniswap_v4::UniswapV4Pool,
+        velodrome::VelodromePool,
+        AmmState, AMM,
+    },
+    filters::dedupe::Protocol,
+};
+
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS pools (
+        address TEXT PRIMARY KEY,
+        protocol TEXT NOT NULL,
+        fee INTEGER,
+        creation_block INTEGER,
+        last_synced_block INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS pool_tokens (
+        pool TEXT NOT NULL REFERENCES pools(address) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        token TEXT NOT NULL,
+        decimals INTEGER,
+        PRIMARY KEY (pool, position)
+    );
+
+    CREATE INDEX IF NOT EXISTS pool_tokens_token ON pool_tokens(token);
+
+    CREATE TABLE IF NOT EXISTS uniswap_v2_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        reserve_0 TEXT NOT NULL,
+        reserve_1 TEXT NOT NULL,
+        fee_change_signature TEXT,
+        fee_change_denominator INTEGER,
+        token_a_buy_tax INTEGER NOT NULL,
+        token_a_sell_tax INTEGER NOT NULL,
+        token_b_buy_tax INTEGER NOT NULL,
+        token_b_sell_tax INTEGER NOT NULL,
+        fee_one_for_zero INTEGER,
+        stable_swap INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS uniswap_v3_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        sqrt_price TEXT NOT NULL,
+        tick INTEGER NOT NULL,
+        liquidity TEXT NOT NULL,
+        tick_spacing INTEGER NOT NULL,
+        ticks BLOB NOT NULL,
+        tick_bitmap BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS erc4626_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        vault_reserve TEXT NOT NULL,
+        asset_reserve TEXT NOT NULL,
+        deposit_fee INTEGER NOT NULL,
+        withdraw_fee INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS curve_stableswap_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        a TEXT NOT NULL,
+        fee INTEGER NOT NULL,
+        admin_fee INTEGER NOT NULL,
+        balances TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS balancer_weighted_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        pool_id TEXT NOT NULL,
+        vault TEXT NOT NULL,
+        swap_fee TEXT NOT NULL,
+        weights TEXT NOT NULL,
+        balances TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS velodrome_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        reserve_0 TEXT NOT NULL,
+        reserve_1 TEXT NOT NULL,
+        stable INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS uniswap_v4_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        pool_manager TEXT NOT NULL,
+        pool_id TEXT NOT NULL,
+        key_fee INTEGER NOT NULL,
+        hooks TEXT NOT NULL,
+        protocol_fee INTEGER NOT NULL,
+        sqrt_price TEXT NOT NULL,
+        tick INTEGER NOT NULL,
+        liquidity TEXT NOT NULL,
+        tick_spacing INTEGER NOT NULL,
+        ticks BLOB NOT NULL,
+        tick_bitmap BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS liquidity_book_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        bin_step INTEGER NOT NULL,
+        active_id INTEGER NOT NULL,
+        bin_range_low INTEGER NOT NULL,
+        bin_range_high INTEGER NOT NULL,
+        static_fee_parameters TEXT NOT NULL,
+        variable_fee_parameters TEXT NOT NULL,
+        bins TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS maverick_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        fee TEXT NOT NULL,
+        tick_spacing INTEGER NOT NULL,
+        active_tick INTEGER NOT NULL,
+        protocol_fee_ratio INTEGER NOT NULL,
+        bins TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS dodo_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        i TEXT NOT NULL,
+        k TEXT NOT NULL,
+        base_reserve TEXT NOT NULL,
+        quote_reserve TEXT NOT NULL,
+        base_target TEXT NOT NULL,
+        quote_target TEXT NOT NULL,
+        r_state INTEGER NOT NULL,
+        lp_fee_rate TEXT NOT NULL,
+        mt_fee_rate TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS kyber_elastic_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        tick_distance INTEGER NOT NULL,
+        sqrt_price TEXT NOT NULL,
+        tick INTEGER NOT NULL,
+        base_liquidity TEXT NOT NULL,
+        reinvest_liquidity TEXT NOT NULL,
+        tick_range_low INTEGER NOT NULL,
+        tick_range_high INTEGER NOT NULL,
+        ticks TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS algebra_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        fee_one_for_zero INTEGER NOT NULL,
+        sqrt_price TEXT NOT NULL,
+        tick INTEGER NOT NULL,
+        liquidity TEXT NOT NULL,
+        tick_spacing INTEGER NOT NULL,
+        ticks BLOB NOT NULL,
+        tick_bitmap BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS curve_crypto_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        a TEXT NOT NULL,
+        gamma TEXT NOT NULL,
+        d TEXT NOT NULL,
+        fee_gamma TEXT NOT NULL,
+        mid_fee INTEGER NOT NULL,
+        out_fee INTEGER NOT NULL,
+        price_scale TEXT NOT NULL,
+        balances TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS bancor_v3_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        network TEXT NOT NULL,
+        network_info TEXT NOT NULL,
+        bnt_trading_liquidity TEXT NOT NULL,
+        base_token_trading_liquidity TEXT NOT NULL,
+        staked_balance TEXT NOT NULL,
+        trading_fee_ppm INTEGER NOT NULL,
+        trading_enabled INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS custom_state (
+        pool TEXT PRIMARY KEY REFERENCES pools(address) ON DELETE CASCADE,
+        state TEXT NOT NULL
+    );
+"#;
+
+#[derive(Error, Debug)]
+pub enum SqliteStoreError {
+    #[error("SQLite error")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serde json error")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Invalid value {0} stored in column {1}")]
+    InvalidValue(String, &'static str),
+    #[error("Pool {0} has no {1} state")]
+    MissingState(String, &'static str),
+}
+
+/// Selects the pools returned by `SqliteStore::load_amms`, every pool matches the default filter
+#[derive(Debug, Clone, Default)]
+pub struct PoolFilter {
+    /// Only pools trading this token
+    pub token: Option<H160>,
+    pub protocol: Option<Protocol>,
+    /// Only pools synced up to at least this block
+    pub min_synced_block: Option<u64>,
+}
+
+impl PoolFilter {
+    pub fn new() -> Self {
+        PoolFilter::default()
+    }
+
+    pub fn with_token(mut self, token: H160) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    pub fn with_min_synced_block(mut self, block_number: u64) -> Self {
+        self.min_synced_block = Some(block_number);
+        self
+    }
+}
+
+/// SQLite persistence of synced AMMs, with a row per pool and its state in a table per protocol.
+///
+/// Unlike a JSON checkpoint, single pools can be updated in place with `upsert_amm` and loaded by token or
+/// protocol. Pools are loaded in the order they were first saved, so `load_amms` with the default filter returns
+/// the same `Vec<AMM>` as a checkpoint written from the saved AMMs.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SqliteStoreError> {
+        SqliteStore::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, SqliteStoreError> {
+        SqliteStore::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> Result<Self, SqliteStoreError> {
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Saves `amms` synced up to `block_number` in a single transaction, replacing the stored state of pools that
+    /// were already saved
+    pub fn save_amms(&self, amms: &[AMM], block_number: u64) -> Result<(), SqliteStoreError> {
+        let mut connection = self.connection.lock().expect("SQLite store poisoned");
+        let transaction = connection.transaction()?;
+        for amm in amms {
+            write_amm(&transaction, amm, block_number)?;
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Saves the state of a single AMM synced up to `block_number`, leaving every other pool untouched
+    pub fn upsert_amm(&self, amm: &AMM, block_number: u64) -> Result<(), SqliteStoreError> {
+        self.save_amms(std::slice::from_ref(amm), block_number)
+    }
+
+    pub fn remove_amm(&self, address: H160) -> Result<bool, SqliteStoreError> {
+        let connection = self.connection.lock().expect("SQLite store poisoned");
+        let removed = connection.execute(
+            "DELETE FROM pools WHERE address = ?1",
+            params![format!("{address:?}")],
+        )?;
+
+        Ok(removed > 0)
+    }
+
+    /// Loads the pools matching `filter`, in the order they were first saved
+    pub fn load_amms(&self, filter: &PoolFilter) -> Result<Vec<AMM>, SqliteStoreError> {
+        let connection = self.connection.lock().expect("SQLite store poisoned");
+
+        let mut conditions = vec![];
+        let mut values: Vec<rusqlite::types::Value> = vec![];
+        if let Some(token) = filter.token {
+            conditions.push("address IN (SELECT pool FROM pool_tokens WHERE token = ?)");
+            values.push(format!("{token:?}").into());
+        }
+        if let Some(protocol) = filter.protocol {
+            conditions.push("protocol = ?");
+            values.push(protocol.name().to_owned().into());
+        }
+        if let Some(block_number) = filter.min_synced_block {
+            conditions.push("last_synced_block >= ?");
+            values.push((block_number as i64).into());
+        }
+
+        let mut query = "SELECT address, protocol, fee, creation_block FROM pools".to_owned();
+        if !conditions.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(&conditions.join(" AND "));
+        }
+        query.push_str(" ORDER BY rowid");
+
+        let mut statement = connection.prepare(&query)?;
+        let pools = statement
+            .query_map(params_from_iter(values), |row| {
+                Ok(PoolRow {
+                    address: row.get(0)?,
+                    protocol: row.get(1)?,
+                    fee: row.get(2)?,
+                    creation_block: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        pools
+            .into_iter()
+            .map(|pool| read_amm(&connection, pool))
+            .collect()
+    }
+
+    /// Highest block any stored pool was synced up to, None if the store is empty
+    pub fn last_synced_block(&self) -> Result<Option<u64>, SqliteStoreError> {
+        let connection = self.connection.lock().expect("SQLite store poisoned");
+        let block_number: Option<i64> =
+            connection.query_row("SELECT MAX(last_synced_block) FROM pools", [], |row| {
+                row.get(0)
+            })?;
+
+        Ok(block_number.map(|block_number| block_number as u64))
+    }
+}
+
+struct PoolRow {
+    address: String,
+    protocol: String,
+    fee: Option<u32>,
+    creation_block: Option<i64>,
+}
+
+fn write_amm(
+    transaction: &Transaction,
+    amm: &AMM,
+    block_number: u64,
+) -> Result<(), SqliteStoreError> {
+    let address = format!("{:?}", amm.address());
+    let (fee, creation_block) = match amm {
+        AMM::UniswapV2Pool(pool) => (Some(pool.fee), pool.creation_block),
+        AMM::UniswapV3Pool(pool) => (Some(pool.fee), pool.creation_block),
+        AMM::VelodromePool(pool) => (Some(pool.fee), pool.creation_block),
+        AMM::UniswapV4Pool(pool) => (Some(pool.pool.fee), pool.pool.creation_block),
+        //The fee of a Maverick pool is a WAD, too wide for the fee column, so it is kept with its state
+        AMM::MaverickPool(pool) => (None, pool.creation_block),
+        AMM::KyberElasticPool(pool) => (Some(pool.fee), pool.creation_block),
+        //The fee of swaps of token b for token a is kept with the state of an Algebra pool
+        AMM::AlgebraPool(pool) => (Some(pool.pool.fee), pool.pool.creation_block),
+        AMM::ERC4626Vault(_)
+        | AMM::CurveStableSwapPool(_)
+        | AMM::BalancerWeightedPool(_)
+        | AMM::LiquidityBookPool(_)
+        | AMM::DodoPool(_)
+        | AMM::CurveCryptoPool(_)
+        | AMM::BancorV3Pool(_)
+        | AMM::Custom(_) => (None, None),
+    };
+
+    //Updating the existing row keeps its rowid, which `load_amms` orders by
+    transaction.execute(
+        "INSERT INTO pools (address, protocol, fee, creation_block, last_synced_block)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(address) DO UPDATE SET
+            protocol = excluded.protocol,
+            fee = excluded.fee,
+            creation_block = excluded.creation_block,
+            last_synced_block = excluded.last_synced_block",
+        params![
+            address,
+            Protocol::of(amm).name(),
+            fee,
+            creation_block.map(|block| block as i64),
+            block_number as i64
+        ],
+    )?;
+
+    let decimals = match amm {
+        AMM::UniswapV2Pool(pool) => vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)],
+        AMM::UniswapV3Pool(pool) => vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)],
+        AMM::ERC4626Vault(vault) => vec![
+            Some(vault.vault_token_decimals),
+            Some(vault.asset_token_decimals),
+        ],
+        AMM::CurveStableSwapPool(pool) => pool.decimals.iter().copied().map(Some).collect(),
+        AMM::BalancerWeightedPool(pool) => pool.decimals.iter().copied().map(Some).collect(),
+        AMM::VelodromePool(pool) => vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)],
+        AMM::UniswapV4Pool(pool) => vec![
+            Some(pool.pool.token_a_decimals),
+            Some(pool.pool.token_b_decimals),
+        ],
+        AMM::LiquidityBookPool(pool) => {
+            vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)]
+        }
+        AMM::MaverickPool(pool) => vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)],
+        AMM::DodoPool(pool) => vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)],
+        AMM::KyberElasticPool(pool) => {
+            vec![Some(pool.token_a_decimals), Some(pool.token_b_decimals)]
+        }
+        AMM::AlgebraPool(pool) => vec![
+            Some(pool.pool.token_a_decimals),
+            Some(pool.pool.token_b_decimals),
+        ],
+        AMM::CurveCryptoPool(pool) => pool.decimals.iter().copied().map(Some).collect(),
+        AMM::BancorV3Pool(pool) => vec![Some(pool.token_decimals), Some(bancor_v3::BNT_DECIMALS)],
+        AMM::Custom(_) => vec![],
+    };
+    transaction.execute("DELETE FROM pool_tokens WHERE pool = ?1", params![address])?;
+    for (position, token) in amm.tokens().into_iter().enumerate() {
+        transaction.execute(
+            "INSERT INTO pool_tokens (pool, position, token, decimals) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                address,
+                position as i64,
+                format!("{token:?}"),
+                decimals.get(position).copied().flatten()
+            ],
+        )?;
+    }
+
+    match amm {
+        AMM::UniswapV2Pool(pool) => {
+            transaction.execute(
+                "INSERT OR REPLACE INTO uniswap_v2_state (pool, reserve_0, reserve_1, fee_change_signature,
+                fee_change_denominator, token_a_buy_tax, token_a_sell_tax, token_b_buy_tax, token_b_sell_tax,
+                fee_one_for_zero, stable_swap) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    address,
+                    pool.reserve_0.to_string(),
+                    pool.reserve_1.to_string(),
+                    pool.fee_change_event
+                        .map(|event| format!("{:?}", event.signature)),
+                    pool.fee_change_event.map(|event| event.denominator),
+                    pool.token_a_transfer_tax.buy_bps,
+                    pool.token_a_transfer_tax.sell_bps,
+                    pool.token_b_transfer_tax.buy_bps,
+                    pool.token_b_transfer_tax.sell_bps,
+                    pool.fee_one_for_zero,
+                    pool.stable_swap
+                ],
+            )?;
+        }
+        AMM::UniswapV3Pool(pool) => {
+            let (ticks, tick_bitmap) = serialize_ticks(pool)?;
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO uniswap_v3_state (pool, sqrt_price, tick, liquidity, tick_spacing, ticks,
+                tick_bitmap) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    address,
+                    pool.sqrt_price.to_string(),
+                    pool.tick,
+                    pool.liquidity.to_string(),
+                    pool.tick_spacing,
+                    ticks,
+                    tick_bitmap
+                ],
+            )?;
+        }
+        AMM::ERC4626Vault(vault) => {
+            transaction.execute(
+                "INSERT OR REPLACE INTO erc4626_state (pool, vault_reserve, asset_reserve, deposit_fee,
+                withdraw_fee) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    address,
+                    vault.vault_reserve.to_string(),
+                    vault.asset_reserve.to_string(),
+                    vault.deposit_fee,
+                    vault.withdraw_fee
+                ],
+            )?;
+        }
+        AMM::CurveStableSwapPool(pool) => {
+            //Balances are stored as a JSON array of decimal strings, the number of coins varies between pools
+            let balances = pool
+                .balances
+                .iter()
+                .map(U256::to_string)
+                .collect::<Vec<String>>();
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO curve_stableswap_state (pool, a, fee, admin_fee, balances)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    address,
+                    pool.a.to_string(),
+                    pool.fee as i64,
+                    pool.admin_fee as i64,
+                    serde_json::to_string(&balances)?
+                ],
+            )?;
+        }
+        AMM::BalancerWeightedPool(pool) => {
+            let to_strings =
+                |values: &[U256]| values.iter().map(U256::to_string).collect::<Vec<String>>();
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO balancer_weighted_state (pool, pool_id, vault, swap_fee, weights, balances)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    address,
+                    format!("{:?}", pool.pool_id),
+                    format!("{:?}", pool.vault),
+                    pool.swap_fee.to_string(),
+                    serde_json::to_string(&to_strings(&pool.weights))?,
+                    serde_json::to_string(&to_strings(&pool.balances))?
+                ],
+            )?;
+        }
+        AMM::VelodromePool(pool) => {
+            transaction.execute(
+                "INSERT OR REPLACE INTO velodrome_state (pool, reserve_0, reserve_1, stable) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    address,
+                    pool.reserve_0.to_string(),
+                    pool.reserve_1.to_string(),
+                    pool.stable
+                ],
+            )?;
+        }
+        AMM::UniswapV4Pool(v4_pool) => {
+            let pool = &v4_pool.pool;
+            let (ticks, tick_bitmap) = serialize_ticks(pool)?;
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO uniswap_v4_state (pool, pool_manager, pool_id, key_fee, hooks, protocol_fee,
+                sqrt_price, tick, liquidity, tick_spacing, ticks, tick_bitmap)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    address,
+                    format!("{:?}", v4_pool.pool_manager),
+                    format!("{:?}", v4_pool.pool_id),
+                    v4_pool.key_fee,
+                    format!("{:?}", v4_pool.hooks),
+                    v4_pool.protocol_fee,
+                    pool.sqrt_price.to_string(),
+                    pool.tick,
+                    pool.liquidity.to_string(),
+                    pool.tick_spacing,
+                    ticks,
+                    tick_bitmap
+                ],
+            )?;
+        }
+        AMM::LiquidityBookPool(pool) => {
+            //The reserves of each bin as strings, like the balances of Curve and Balancer pools
+            let bins = pool
+                .bins
+                .iter()
+                .map(|(id, (reserve_x, reserve_y))| {
+                    (*id, reserve_x.to_string(), reserve_y.to_string())
+                })
+                .collect::<Vec<_>>();
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO liquidity_book_state (pool, bin_step, active_id, bin_range_low, bin_range_high,
+                static_fee_parameters, variable_fee_parameters, bins)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    address,
+                    pool.bin_step,
+                    pool.active_id,
+                    pool.bin_range.0,
+                    pool.bin_range.1,
+                    serde_json::to_string(&pool.static_fee_parameters)?,
+                    serde_json::to_string(&pool.variable_fee_parameters)?,
+                    serde_json::to_string(&bins)?
+                ],
+            )?;
+        }
+        AMM::MaverickPool(pool) => {
+            let bins = pool
+                .bins
+                .iter()
+                .map(|(id, bin)| {
+                    (
+                        id.to_string(),
+                        bin.reserve_a.to_string(),
+                        bin.reserve_b.to_string(),
+                        bin.kind.as_u8(),
+                        bin.lower_tick,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO maverick_state (pool, fee, tick_spacing, active_tick, protocol_fee_ratio, bins)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    address,
+                    pool.fee.to_string(),
+                    pool.tick_spacing,
+                    pool.active_tick,
+                    pool.protocol_fee_ratio as i64,
+                    serde_json::to_string(&bins)?
+                ],
+            )?;
+        }
+        AMM::DodoPool(pool) => {
+            transaction.execute(
+                "INSERT OR REPLACE INTO dodo_state (pool, i, k, base_reserve, quote_reserve, base_target, quote_target,
+                r_state, lp_fee_rate, mt_fee_rate)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    address,
+                    pool.state.i.to_string(),
+                    pool.state.k.to_string(),
+                    pool.state.base_reserve.to_string(),
+                    pool.state.quote_reserve.to_string(),
+                    pool.state.base_target.to_string(),
+                    pool.state.quote_target.to_string(),
+                    pool.state.r.as_u8(),
+                    pool.lp_fee_rate.to_string(),
+                    pool.mt_fee_rate.to_string()
+                ],
+            )?;
+        }
+        AMM::KyberElasticPool(pool) => {
+            let ticks = pool
+                .ticks
+                .iter()
+                .map(|(tick, info)| {
+                    (
+                        *tick,
+                        info.liquidity_gross.to_string(),
+                        info.liquidity_net.to_string(),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO kyber_elastic_state (pool, tick_distance, sqrt_price, tick, base_liquidity,
+                reinvest_liquidity, tick_range_low, tick_range_high, ticks)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    address,
+                    pool.tick_distance,
+                    pool.sqrt_price.to_string(),
+                    pool.tick,
+                    pool.base_liquidity.to_string(),
+                    pool.reinvest_liquidity.to_string(),
+                    pool.tick_range.0,
+                    pool.tick_range.1,
+                    serde_json::to_string(&ticks)?
+                ],
+            )?;
+        }
+        AMM::AlgebraPool(algebra_pool) => {
+            let pool = &algebra_pool.pool;
+            let (ticks, tick_bitmap) = serialize_ticks(pool)?;
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO algebra_state (pool, fee_one_for_zero, sqrt_price, tick, liquidity, tick_spacing,
+                ticks, tick_bitmap) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    address,
+                    algebra_pool.fee_one_for_zero,
+                    pool.sqrt_price.to_string(),
+                    pool.tick,
+                    pool.liquidity.to_string(),
+                    pool.tick_spacing,
+                    ticks,
+                    tick_bitmap
+                ],
+            )?;
+        }
+        AMM::CurveCryptoPool(pool) => {
+            let to_strings =
+                |values: &[U256]| values.iter().map(U256::to_string).collect::<Vec<String>>();
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO curve_crypto_state (pool, a, gamma, d, fee_gamma, mid_fee, out_fee,
+                price_scale, balances) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    address,
+                    pool.a.to_string(),
+                    pool.gamma.to_string(),
+                    pool.d.to_string(),
+                    pool.fee_gamma.to_string(),
+                    pool.mid_fee as i64,
+                    pool.out_fee as i64,
+                    serde_json::to_string(&to_strings(&pool.price_scale))?,
+                    serde_json::to_string(&to_strings(&pool.balances))?
+                ],
+            )?;
+        }
+        AMM::BancorV3Pool(pool) => {
+            transaction.execute(
+                "INSERT OR REPLACE INTO bancor_v3_state (pool, network, network_info, bnt_trading_liquidity,
+                base_token_trading_liquidity, staked_balance, trading_fee_ppm, trading_enabled)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    address,
+                    format!("{:?}", pool.network),
+                    format!("{:?}", pool.network_info),
+                    pool.bnt_trading_liquidity.to_string(),
+                    pool.base_token_trading_liquidity.to_string(),
+                    pool.staked_balance.to_string(),
+                    pool.trading_fee_ppm,
+                    pool.trading_enabled
+                ],
+            )?;
+        }
+        AMM::Custom(amm) => {
+            transaction.execute(
+                "INSERT OR REPLACE INTO custom_state (pool, state) VALUES (?1, ?2)",
+                params![address, serde_json::to_string(amm)?],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_amm(connection: &Connection, pool: PoolRow) -> Result<AMM, SqliteStoreError> {
+    let address = parse_address(&pool.address, "address")?;
+    let creation_block = pool.creation_block.map(|block| block as u64);
+
+    let mut statement = connection.prepare_cached(
+        "SELECT token, decimals FROM pool_tokens WHERE pool = ?1 ORDER BY position",
+    )?;
+    let tokens = statement
+        .query_map(params![pool.address], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let tokens = tokens
+        .into_iter()
+        .map(|(token, decimals)| {
+            Ok((
+                parse_address(&token, "token")?,
+                decimals.unwrap_or_default(),
+            ))
+        })
+        .collect::<Result<Vec<_>, SqliteStoreError>>()?;
+    let token = |position: usize| tokens.get(position).copied().unwrap_or_default();
+
+    match pool.protocol.as_str() {
+        "uniswap_v2" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT reserve_0, reserve_1, fee_change_signature, fee_change_denominator, token_a_buy_tax,
+                token_a_sell_tax, token_b_buy_tax, token_b_sell_tax, fee_one_for_zero, stable_swap
+                FROM uniswap_v2_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, Option<u32>>(3)?,
+                        [
+                            row.get::<_, u32>(4)?,
+                            row.get::<_, u32>(5)?,
+                            row.get::<_, u32>(6)?,
+                            row.get::<_, u32>(7)?,
+                        ],
+                        (row.get::<_, Option<u32>>(8)?, row.get::<_, bool>(9)?),
+                    ))
+                })
+                .optional()?;
+            let (
+                reserve_0,
+                reserve_1,
+                signature,
+                denominator,
+                taxes,
+                (fee_one_for_zero, stable_swap),
+            ) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "uniswap_v2")),
+            };
+
+            let fee_change_event = match (signature, denominator) {
+                (Some(signature), Some(denominator)) => Some(FeeChangeEvent {
+                    signature: H256::from_str(&signature).map_err(|_| {
+                        SqliteStoreError::InvalidValue(signature.clone(), "fee_change_signature")
+                    })?,
+                    denominator,
+                }),
+                _ => None,
+            };
+
+            Ok(AMM::UniswapV2Pool(UniswapV2Pool {
+                address,
+                token_a: token(0).0,
+                token_a_decimals: token(0).1,
+                token_b: token(1).0,
+                token_b_decimals: token(1).1,
+                reserve_0: parse_u128(&reserve_0, "reserve_0")?,
+                reserve_1: parse_u128(&reserve_1, "reserve_1")?,
+                fee: pool.fee.unwrap_or_default(),
+                fee_change_event,
+                token_a_transfer_tax: TransferTax::new(taxes[0], taxes[1]),
+                token_b_transfer_tax: TransferTax::new(taxes[2], taxes[3]),
+                fee_one_for_zero,
+                stable_swap,
+                creation_block,
+                ..Default::default()
+            }))
+        }
+        "uniswap_v3" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT sqrt_price, tick, liquidity, tick_spacing, ticks, tick_bitmap FROM uniswap_v3_state
+                WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i32>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i32>(3)?,
+                        row.get::<_, Vec<u8>>(4)?,
+                        row.get::<_, Vec<u8>>(5)?,
+                    ))
+                })
+                .optional()?;
+            let (sqrt_price, tick, liquidity, tick_spacing, ticks, tick_bitmap) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "uniswap_v3")),
+            };
+
+            Ok(AMM::UniswapV3Pool(UniswapV3Pool {
+                address,
+                token_a: token(0).0,
+                token_a_decimals: token(0).1,
+                token_b: token(1).0,
+                token_b_decimals: token(1).1,
+                liquidity: parse_u128(&liquidity, "liquidity")?,
+                sqrt_price: parse_u256(&sqrt_price, "sqrt_price")?,
+                fee: pool.fee.unwrap_or_default(),
+                tick,
+                tick_spacing,
+                tick_bitmap: tick_serde::tick_bitmap::deserialize(
+                    &mut serde_json::Deserializer::from_slice(&tick_bitmap),
+                )?,
+                ticks: tick_serde::ticks::deserialize(&mut serde_json::Deserializer::from_slice(
+                    &ticks,
+                ))?,
+                creation_block,
+                ..Default::default()
+            }))
+        }
+        "erc4626" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT vault_reserve, asset_reserve, deposit_fee, withdraw_fee FROM erc4626_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, u32>(2)?,
+                        row.get::<_, u32>(3)?,
+                    ))
+                })
+                .optional()?;
+            let (vault_reserve, asset_reserve, deposit_fee, withdraw_fee) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "erc4626")),
+            };
+
+            Ok(AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: address,
+                vault_token_decimals: token(0).1,
+                asset_token: token(1).0,
+                asset_token_decimals: token(1).1,
+                vault_reserve: parse_u256(&vault_reserve, "vault_reserve")?,
+                asset_reserve: parse_u256(&asset_reserve, "asset_reserve")?,
+                deposit_fee,
+                withdraw_fee,
+                ..Default::default()
+            }))
+        }
+        "curve_stableswap" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT a, fee, admin_fee, balances FROM curve_stableswap_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })
+                .optional()?;
+            let (a, fee, admin_fee, balances) = match row {
+                Some(row) => row,
+                None => {
+                    return Err(SqliteStoreError::MissingState(
+                        pool.address,
+                        "curve_stableswap",
+                    ))
+                }
+            };
+
+            let balances = serde_json::from_str::<Vec<String>>(&balances)?
+                .iter()
+                .map(|balance| parse_u256(balance, "balances"))
+                .collect::<Result<Vec<U256>, _>>()?;
+
+            Ok(AMM::CurveStableSwapPool(CurveStableSwapPool::new(
+                address,
+                tokens.iter().map(|(token, _)| *token).collect(),
+                tokens.iter().map(|(_, decimals)| *decimals).collect(),
+                balances,
+                parse_u256(&a, "a")?,
+                fee as u64,
+                admin_fee as u64,
+            )))
+        }
+        "balancer_weighted" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT pool_id, vault, swap_fee, weights, balances FROM balancer_weighted_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                })
+                .optional()?;
+            let (pool_id, vault, swap_fee, weights, balances) = match row {
+                Some(row) => row,
+                None => {
+                    return Err(SqliteStoreError::MissingState(
+                        pool.address,
+                        "balancer_weighted",
+                    ))
+                }
+            };
+
+            let parse_u256s = |values: &str, column: &'static str| {
+                serde_json::from_str::<Vec<String>>(values)?
+                    .iter()
+                    .map(|value| parse_u256(value, column))
+                    .collect::<Result<Vec<U256>, SqliteStoreError>>()
+            };
+
+            let mut balancer_pool = BalancerWeightedPool::new(
+                address,
+                H256::from_str(&pool_id)
+                    .map_err(|_| SqliteStoreError::InvalidValue(pool_id.clone(), "pool_id"))?,
+                tokens.iter().map(|(token, _)| *token).collect(),
+                tokens.iter().map(|(_, decimals)| *decimals).collect(),
+                parse_u256s(&balances, "balances")?,
+                parse_u256s(&weights, "weights")?,
+                parse_u256(&swap_fee, "swap_fee")?,
+            );
+            balancer_pool.vault = parse_address(&vault, "vault")?;
+
+            Ok(AMM::BalancerWeightedPool(balancer_pool))
+        }
+        "velodrome" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT reserve_0, reserve_1, stable FROM velodrome_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                    ))
+                })
+                .optional()?;
+            let (reserve_0, reserve_1, stable) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "velodrome")),
+            };
+
+            Ok(AMM::VelodromePool(VelodromePool {
+                creation_block,
+                ..VelodromePool::new(
+                    address,
+                    token(0).0,
+                    token(0).1,
+                    token(1).0,
+                    token(1).1,
+                    parse_u256(&reserve_0, "reserve_0")?,
+                    parse_u256(&reserve_1, "reserve_1")?,
+                    stable,
+                    pool.fee.unwrap_or_default(),
+                )
+            }))
+        }
+        "uniswap_v4" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT pool_manager, pool_id, key_fee, hooks, protocol_fee, sqrt_price, tick, liquidity,
+                tick_spacing, ticks, tick_bitmap FROM uniswap_v4_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        [
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(3)?,
+                        ],
+                        (row.get::<_, u32>(2)?, row.get::<_, u32>(4)?),
+                        (
+                            row.get::<_, String>(5)?,
+                            row.get::<_, i32>(6)?,
+                            row.get::<_, String>(7)?,
+                            row.get::<_, i32>(8)?,
+                        ),
+                        (row.get::<_, Vec<u8>>(9)?, row.get::<_, Vec<u8>>(10)?),
+                    ))
+                })
+                .optional()?;
+            let (
+                [pool_manager, pool_id, hooks],
+                (key_fee, protocol_fee),
+                (sqrt_price, tick, liquidity, tick_spacing),
+                (ticks, tick_bitmap),
+            ) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "uniswap_v4")),
+            };
+
+            Ok(AMM::UniswapV4Pool(UniswapV4Pool {
+                pool_manager: parse_address(&pool_manager, "pool_manager")?,
+                pool_id: H256::from_str(&pool_id)
+                    .map_err(|_| SqliteStoreError::InvalidValue(pool_id.clone(), "pool_id"))?,
+                key_fee,
+                hooks: parse_address(&hooks, "hooks")?,
+                protocol_fee,
+                pool: UniswapV3Pool {
+                    address,
+                    token_a: token(0).0,
+                    token_a_decimals: token(0).1,
+                    token_b: token(1).0,
+                    token_b_decimals: token(1).1,
+                    liquidity: parse_u128(&liquidity, "liquidity")?,
+                    sqrt_price: parse_u256(&sqrt_price, "sqrt_price")?,
+                    fee: pool.fee.unwrap_or_default(),
+                    tick,
+                    tick_spacing,
+                    tick_bitmap: tick_serde::tick_bitmap::deserialize(
+                        &mut serde_json::Deserializer::from_slice(&tick_bitmap),
+                    )?,
+                    ticks: tick_serde::ticks::deserialize(
+                        &mut serde_json::Deserializer::from_slice(&ticks),
+                    )?,
+                    creation_block,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }))
+        }
+        "liquidity_book" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT bin_step, active_id, bin_range_low, bin_range_high, static_fee_parameters,
+                variable_fee_parameters, bins FROM liquidity_book_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        (row.get::<_, u16>(0)?, row.get::<_, u32>(1)?),
+                        (row.get::<_, u32>(2)?, row.get::<_, u32>(3)?),
+                        [
+                            row.get::<_, String>(4)?,
+                            row.get::<_, String>(5)?,
+                            row.get::<_, String>(6)?,
+                        ],
+                    ))
+                })
+                .optional()?;
+            let (
+                (bin_step, active_id),
+                bin_range,
+                [static_fee_parameters, variable_fee_parameters, bins],
+            ) = match row {
+                Some(row) => row,
+                None => {
+                    return Err(SqliteStoreError::MissingState(
+                        pool.address,
+                        "liquidity_book",
+                    ))
+                }
+            };
+
+            let bins = serde_json::from_str::<Vec<(u32, String, String)>>(&bins)?
+                .iter()
+                .map(|(id, reserve_x, reserve_y)| {
+                    Ok((
+                        *id,
+                        (
+                            parse_u128(reserve_x, "bins")?,
+                            parse_u128(reserve_y, "bins")?,
+                        ),
+                    ))
+                })
+                .collect::<Result<_, SqliteStoreError>>()?;
+
+            Ok(AMM::LiquidityBookPool(LiquidityBookPool {
+                bins,
+                bin_range,
+                variable_fee_parameters: serde_json::from_str(&variable_fee_parameters)?,
+                ..LiquidityBookPool::new(
+                    address,
+                    token(0).0,
+                    token(0).1,
+                    token(1).0,
+                    token(1).1,
+                    bin_step,
+                    active_id,
+                    serde_json::from_str(&static_fee_parameters)?,
+                )
+            }))
+        }
+        "maverick" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT fee, tick_spacing, active_tick, protocol_fee_ratio, bins FROM maverick_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        (row.get::<_, u32>(1)?, row.get::<_, i32>(2)?),
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                })
+                .optional()?;
+            let (fee, (tick_spacing, active_tick), protocol_fee_ratio, bins) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "maverick")),
+            };
+
+            let fee = fee
+                .parse()
+                .map_err(|_| SqliteStoreError::InvalidValue(fee.clone(), "fee"))?;
+            let bins = serde_json::from_str::<Vec<(String, String, String, u8, i32)>>(&bins)?
+                .iter()
+                .map(|(id, reserve_a, reserve_b, kind, lower_tick)| {
+                    Ok((
+                        parse_u128(id, "bins")?,
+                        MaverickBin {
+                            reserve_a: parse_u128(reserve_a, "bins")?,
+                            reserve_b: parse_u128(reserve_b, "bins")?,
+                            kind: BinKind::from_u8(*kind).ok_or_else(|| {
+                                SqliteStoreError::InvalidValue(kind.to_string(), "bins")
+                            })?,
+                            lower_tick: *lower_tick,
+                        },
+                    ))
+                })
+                .collect::<Result<_, SqliteStoreError>>()?;
+
+            Ok(AMM::MaverickPool(MaverickPool {
+                bins,
+                creation_block,
+                ..MaverickPool::new(
+                    address,
+                    token(0).0,
+                    token(0).1,
+                    token(1).0,
+                    token(1).1,
+                    fee,
+                    tick_spacing,
+                    active_tick,
+                    protocol_fee_ratio as u64,
+                )
+            }))
+        }
+        "dodo" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT i, k, base_reserve, quote_reserve, base_target, quote_target, r_state, lp_fee_rate,
+                mt_fee_rate FROM dodo_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        [
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, String>(4)?,
+                            row.get::<_, String>(5)?,
+                        ],
+                        row.get::<_, u8>(6)?,
+                        (row.get::<_, String>(7)?, row.get::<_, String>(8)?),
+                    ))
+                })
+                .optional()?;
+            let (
+                [i, k, base_reserve, quote_reserve, base_target, quote_target],
+                r_state,
+                (lp_fee_rate, mt_fee_rate),
+            ) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "dodo")),
+            };
+
+            let state = PMMState {
+                i: parse_u256(&i, "i")?,
+                k: parse_u256(&k, "k")?,
+                base_reserve: parse_u256(&base_reserve, "base_reserve")?,
+                quote_reserve: parse_u256(&quote_reserve, "quote_reserve")?,
+                base_target: parse_u256(&base_target, "base_target")?,
+                quote_target: parse_u256(&quote_target, "quote_target")?,
+                r: RState::from_u8(r_state).ok_or_else(|| {
+                    SqliteStoreError::InvalidValue(r_state.to_string(), "r_state")
+                })?,
+            };
+
+            Ok(AMM::DodoPool(DodoPool::new(
+                address,
+                token(0).0,
+                token(0).1,
+                token(1).0,
+                token(1).1,
+                state,
+                parse_u256(&lp_fee_rate, "lp_fee_rate")?,
+                parse_u256(&mt_fee_rate, "mt_fee_rate")?,
+            )))
+        }
+        "kyber_elastic" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT tick_distance, sqrt_price, tick, base_liquidity, reinvest_liquidity, tick_range_low,
+                tick_range_high, ticks FROM kyber_elastic_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        (row.get::<_, i32>(0)?, row.get::<_, i32>(2)?),
+                        (row.get::<_, i32>(5)?, row.get::<_, i32>(6)?),
+                        [
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, String>(4)?,
+                            row.get::<_, String>(7)?,
+                        ],
+                    ))
+                })
+                .optional()?;
+            let (
+                (tick_distance, tick),
+                tick_range,
+                [sqrt_price, base_liquidity, reinvest_liquidity, ticks],
+            ) = match row {
+                Some(row) => row,
+                None => {
+                    return Err(SqliteStoreError::MissingState(
+                        pool.address,
+                        "kyber_elastic",
+                    ))
+                }
+            };
+
+            let ticks = serde_json::from_str::<Vec<(i32, String, String)>>(&ticks)?
+                .iter()
+                .map(|(tick, liquidity_gross, liquidity_net)| {
+                    Ok((
+                        *tick,
+                        KyberTick {
+                            liquidity_gross: parse_u128(liquidity_gross, "ticks")?,
+                            liquidity_net: liquidity_net.parse().map_err(|_| {
+                                SqliteStoreError::InvalidValue(liquidity_net.clone(), "ticks")
+                            })?,
+                        },
+                    ))
+                })
+                .collect::<Result<_, SqliteStoreError>>()?;
+
+            Ok(AMM::KyberElasticPool(KyberElasticPool {
+                base_liquidity: parse_u128(&base_liquidity, "base_liquidity")?,
+                reinvest_liquidity: parse_u128(&reinvest_liquidity, "reinvest_liquidity")?,
+                ticks,
+                tick_range,
+                creation_block,
+                ..KyberElasticPool::new(
+                    address,
+                    token(0).0,
+                    token(0).1,
+                    token(1).0,
+                    token(1).1,
+                    pool.fee.unwrap_or_default(),
+                    tick_distance,
+                    parse_u256(&sqrt_price, "sqrt_price")?,
+                    tick,
+                )
+            }))
+        }
+        "algebra" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT fee_one_for_zero, sqrt_price, tick, liquidity, tick_spacing, ticks, tick_bitmap
+                FROM algebra_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        (
+                            row.get::<_, String>(1)?,
+                            row.get::<_, i32>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, i32>(4)?,
+                        ),
+                        (row.get::<_, Vec<u8>>(5)?, row.get::<_, Vec<u8>>(6)?),
+                    ))
+                })
+                .optional()?;
+            let (
+                fee_one_for_zero,
+                (sqrt_price, tick, liquidity, tick_spacing),
+                (ticks, tick_bitmap),
+            ) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "algebra")),
+            };
+
+            let mut algebra_pool = AlgebraPool::new(
+                address,
+                token(0).0,
+                token(0).1,
+                token(1).0,
+                token(1).1,
+                pool.fee.unwrap_or_default(),
+                tick_spacing,
+            );
+            algebra_pool.fee_one_for_zero = fee_one_for_zero;
+            algebra_pool.pool.sqrt_price = parse_u256(&sqrt_price, "sqrt_price")?;
+            algebra_pool.pool.tick = tick;
+            algebra_pool.pool.liquidity = parse_u128(&liquidity, "liquidity")?;
+            algebra_pool.pool.tick_bitmap = tick_serde::tick_bitmap::deserialize(
+                &mut serde_json::Deserializer::from_slice(&tick_bitmap),
+            )?;
+            algebra_pool.pool.ticks =
+                tick_serde::ticks::deserialize(&mut serde_json::Deserializer::from_slice(&ticks))?;
+            algebra_pool.pool.creation_block = creation_block;
+
+            Ok(AMM::AlgebraPool(algebra_pool))
+        }
+        "curve_crypto" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT a, gamma, d, fee_gamma, mid_fee, out_fee, price_scale, balances FROM curve_crypto_state
+                WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        (
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                        ),
+                        (row.get::<_, i64>(4)?, row.get::<_, i64>(5)?),
+                        (row.get::<_, String>(6)?, row.get::<_, String>(7)?),
+                    ))
+                })
+                .optional()?;
+            let ((a, gamma, d, fee_gamma), (mid_fee, out_fee), (price_scale, balances)) = match row
+            {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "curve_crypto")),
+            };
+
+            let parse_u256s = |values: &str, column: &'static str| {
+                serde_json::from_str::<Vec<String>>(values)?
+                    .iter()
+                    .map(|value| parse_u256(value, column))
+                    .collect::<Result<Vec<U256>, SqliteStoreError>>()
+            };
+
+            let mut crypto_pool = CurveCryptoPool::new(
+                address,
+                tokens.iter().map(|(token, _)| *token).collect(),
+                tokens.iter().map(|(_, decimals)| *decimals).collect(),
+                parse_u256s(&balances, "balances")?,
+                parse_u256(&a, "a")?,
+                parse_u256(&gamma, "gamma")?,
+                parse_u256s(&price_scale, "price_scale")?,
+                parse_u256(&fee_gamma, "fee_gamma")?,
+                mid_fee as u64,
+                out_fee as u64,
+            );
+            //The stored invariant is kept rather than the one computed from the balances
+            crypto_pool.d = parse_u256(&d, "d")?;
+
+            Ok(AMM::CurveCryptoPool(crypto_pool))
+        }
+        "bancor_v3" => {
+            let mut statement = connection.prepare_cached(
+                "SELECT network, network_info, bnt_trading_liquidity, base_token_trading_liquidity, staked_balance,
+                trading_fee_ppm, trading_enabled FROM bancor_v3_state WHERE pool = ?1",
+            )?;
+            let row = statement
+                .query_row(params![pool.address], |row| {
+                    Ok((
+                        (row.get::<_, String>(0)?, row.get::<_, String>(1)?),
+                        (
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, String>(4)?,
+                        ),
+                        (row.get::<_, u32>(5)?, row.get::<_, bool>(6)?),
+                    ))
+                })
+                .optional()?;
+            let (
+                (network, network_info),
+                (bnt_trading_liquidity, base_token_trading_liquidity, staked_balance),
+                (trading_fee_ppm, trading_enabled),
+            ) = match row {
+                Some(row) => row,
+                None => return Err(SqliteStoreError::MissingState(pool.address, "bancor_v3")),
+            };
+
+            let mut bancor_pool = BancorV3Pool::new(
+                parse_address(&network, "network")?,
+                parse_address(&network_info, "network_info")?,
+                token(1).0,
+                token(0).0,
+                token(0).1,
+                parse_u128(&bnt_trading_liquidity, "bnt_trading_liquidity")?,
+                parse_u128(
+                    &base_token_trading_liquidity,
+                    "base_token_trading_liquidity",
+                )?,
+                parse_u256(&staked_balance, "staked_balance")?,
+                trading_fee_ppm,
+            );
+            bancor_pool.trading_enabled = trading_enabled;
+
+            Ok(AMM::BancorV3Pool(bancor_pool))
+        }
+        "custom" => {
+            let state: Option<String> = connection
+                .prepare_cached("SELECT state FROM custom_state WHERE pool = ?1")?
+                .query_row(params![pool.address], |row| row.get(0))
+                .optional()?;
+
+            match state {
+                Some(state) => Ok(AMM::Custom(serde_json::from_str::<CustomAMM>(&state)?)),
+                None => Err(SqliteStoreError::MissingState(pool.address, "custom")),
+            }
+        }
+        _ => Err(SqliteStoreError::InvalidValue(pool.protocol, "protocol")),
+    }
+}
+
+//Ticks and tick bitmap of a pool as the JSON of `tick_serde`, stored in blobs
+fn serialize_ticks(pool: &UniswapV3Pool) -> Result<(Vec<u8>, Vec<u8>), SqliteStoreError> {
+    let mut ticks = vec![];
+    tick_serde::ticks::serialize(&pool.ticks, &mut serde_json::Serializer::new(&mut ticks))?;
+    let mut tick_bitmap = vec![];
+    tick_serde::tick_bitmap::serialize(
+        &pool.tick_bitmap,
+        &mut serde_json::Serializer::new(&mut tick_bitmap),
+    )?;
+
+    Ok((ticks, tick_bitmap))
+}
+
+fn parse_address(value: &str, column: &'static str) -> Result<H160, SqliteStoreError> {
+    H160::from_str(value).map_err(|_| SqliteStoreError::InvalidValue(value.to_owned(), column))
+}
+
+fn parse_u128(value: &str, column: &'static str) -> Result<u128, SqliteStoreError> {
+    value
+        .parse()
+        .map_err(|_| SqliteStoreError::InvalidValue(value.to_owned(), column))
+}
+
+fn parse_u256(value: &str, column: &'static str) -> Result<U256, SqliteStoreError> {
+    U256::from_dec_str(value).map_err(|_| SqliteStoreError::InvalidValue(value.to_owned(), column))
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{H160, H256, U256};
+
+    use crate::{
+        amm::{
+            algebra::AlgebraPool,
+            balancer::BalancerWeightedPool,
+            bancor_v3::BancorV3Pool,
+            curve::CurveStableSwapPool,
+            curve_crypto::CurveCryptoPool,
+            dodo::{DodoPool, PMMState, RState},
+            erc_4626::ERC4626Vault,
+            kyber_elastic::{KyberElasticPool, KyberTick, MAX_TICK},
+            liquidity_book::{LiquidityBookPool, StaticFeeParameters, VariableFeeParameters},
+            maverick::{BinKind, MaverickBin, MaverickPool},
+            uniswap_v2::{FeeChangeEvent, TransferTax, UniswapV2Pool},
+            uniswap_v3::UniswapV3Pool,
+            uniswap_v4::UniswapV4Pool,
+            velodrome::VelodromePool,
+            AmmState, AMM,
+        },
+        filters::dedupe::Protocol,
+        sync::checkpoint::{construct_checkpoint, deconstruct_checkpoint},
+    };
+
+    use super::{PoolFilter, SqliteStore};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    fn amms() -> eyre::Result<Vec<AMM>> {
+        let v3_pool: UniswapV3Pool = serde_json::from_str(UNISWAP_V3_FIXTURE)?;
+        let (usdc, weth) = (v3_pool.token_a, v3_pool.token_b);
+
+        Ok(vec![
+            AMM::UniswapV3Pool(v3_pool),
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(100),
+                token_a: usdc,
+                token_a_decimals: 6,
+                token_b: weth,
+                token_b_decimals: 18,
+                reserve_0: 10_u128.pow(12),
+                reserve_1: u128::MAX,
+                fee: 300,
+                fee_change_event: Some(FeeChangeEvent {
+                    signature: H256::repeat_byte(1),
+                    denominator: 1000,
+                }),
+                token_b_transfer_tax: TransferTax::new(100, 200),
+                fee_one_for_zero: Some(250),
+                creation_block: Some(10_000_835),
+                ..Default::default()
+            }),
+            AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: H160::from_low_u64_be(200),
+                vault_token_decimals: 18,
+                asset_token: weth,
+                asset_token_decimals: 18,
+                vault_reserve: U256::exp10(24),
+                asset_reserve: U256::MAX,
+                deposit_fee: 10,
+                withdraw_fee: 20,
+                ..Default::default()
+            }),
+            AMM::CurveStableSwapPool(CurveStableSwapPool::new(
+                H160::from_low_u64_be(300),
+                vec![H160::from_low_u64_be(301), usdc, H160::from_low_u64_be(302)],
+                vec![18, 6, 6],
+                vec![U256::exp10(26), U256::exp10(14), U256::MAX],
+                U256::from(2000),
+                1_000_000,
+                5_000_000_000,
+            )),
+            AMM::BalancerWeightedPool(BalancerWeightedPool::new(
+                H160::from_low_u64_be(400),
+                H256::repeat_byte(4),
+                vec![H160::from_low_u64_be(401), H160::from_low_u64_be(402)],
+                vec![18, 6],
+                vec![U256::exp10(24), U256::MAX],
+                vec![U256::exp10(17) * 8, U256::exp10(17) * 2],
+                U256::exp10(15) * 3,
+            )),
+            AMM::VelodromePool(VelodromePool {
+                creation_block: Some(3_200_559),
+                ..VelodromePool::new(
+                    H160::from_low_u64_be(500),
+                    usdc,
+                    6,
+                    H160::from_low_u64_be(501),
+                    18,
+                    U256::exp10(12),
+                    U256::MAX,
+                    true,
+                    5,
+                )
+            }),
+            AMM::UniswapV4Pool({
+                let mut pool = UniswapV4Pool::new(
+                    H160::from_low_u64_be(600),
+                    H160::zero(),
+                    18,
+                    usdc,
+                    6,
+                    3000,
+                    60,
+                    H160::zero(),
+                );
+                pool.protocol_fee = 100 | (200 << 12);
+                pool.pool.set_slot0(U256::one() << 96, 0)?;
+                pool.pool.set_liquidity(10_u128.pow(18))?;
+                pool.pool.creation_block = Some(21_688_329);
+                pool
+            }),
+            AMM::LiquidityBookPool({
+                let mut pool = LiquidityBookPool::new(
+                    H160::from_low_u64_be(700),
+                    H160::from_low_u64_be(701),
+                    18,
+                    usdc,
+                    6,
+                    20,
+                    8_376_279,
+                    StaticFeeParameters {
+                        base_factor: 8000,
+                        filter_period: 30,
+                        decay_period: 600,
+                        reduction_factor: 5000,
+                        variable_fee_control: 120_000,
+                        protocol_share: 1000,
+                        max_volatility_accumulator: 350_000,
+                    },
+                );
+                pool.variable_fee_parameters = VariableFeeParameters {
+                    volatility_accumulator: 20_000,
+                    volatility_reference: 10_000,
+                    id_reference: 8_376_280,
+                    time_of_last_update: 1_700_000_000,
+                };
+                pool.bin_range = (0, 8_376_379);
+                pool.bins.insert(8_376_278, (0, u128::MAX));
+                pool.bins
+                    .insert(8_376_279, (10_u128.pow(18), 10_u128.pow(6)));
+                pool
+            }),
+            AMM::MaverickPool({
+                let mut pool = MaverickPool::new(
+                    H160::from_low_u64_be(800),
+                    H160::from_low_u64_be(801),
+                    18,
+                    usdc,
+                    6,
+                    100_000_000_000_000,
+                    10,
+                    -3,
+                    250,
+                );
+                pool.bins.insert(
+                    1,
+                    MaverickBin {
+                        reserve_a: 10_u128.pow(18),
+                        reserve_b: u128::MAX,
+                        kind: BinKind::Static,
+                        lower_tick: -3,
+                    },
+                );
+                pool.bins.insert(
+                    u128::MAX,
+                    MaverickBin {
+                        reserve_a: 0,
+                        reserve_b: 10_u128.pow(18),
+                        kind: BinKind::Both,
+                        lower_tick: -4,
+                    },
+                );
+                pool.creation_block = Some(17_214_021);
+                pool
+            }),
+            AMM::DodoPool(DodoPool::new(
+                H160::from_low_u64_be(900),
+                H160::from_low_u64_be(901),
+                18,
+                usdc,
+                6,
+                PMMState {
+                    i: U256::from(2_000_000_000_u64),
+                    k: U256::exp10(17),
+                    base_reserve: U256::exp10(20),
+                    quote_reserve: U256::MAX,
+                    base_target: U256::exp10(20),
+                    quote_target: U256::zero(),
+                    r: RState::AboveOne,
+                },
+                U256::exp10(15) * 2,
+                U256::exp10(15),
+            )),
+            AMM::KyberElasticPool({
+                let mut pool = KyberElasticPool::new(
+                    H160::from_low_u64_be(1000),
+                    H160::from_low_u64_be(1001),
+                    18,
+                    usdc,
+                    6,
+                    8,
+                    1,
+                    U256::from(2).pow(U256::from(96)),
+                    0,
+                );
+                pool.base_liquidity = u128::MAX;
+                pool.reinvest_liquidity = 10_u128.pow(12);
+                pool.ticks.insert(
+                    -60,
+                    KyberTick {
+                        liquidity_gross: u128::MAX,
+                        liquidity_net: i128::MAX,
+                    },
+                );
+                pool.ticks.insert(
+                    MAX_TICK,
+                    KyberTick {
+                        liquidity_gross: 0,
+                        liquidity_net: i128::MIN,
+                    },
+                );
+                pool.tick_range = (-60, MAX_TICK);
+                pool.creation_block = Some(16_200_000);
+                pool
+            }),
+            AMM::AlgebraPool({
+                let mut pool = AlgebraPool::new(
+                    H160::from_low_u64_be(1100),
+                    usdc,
+                    6,
+                    H160::from_low_u64_be(1101),
+                    18,
+                    500,
+                    60,
+                );
+                pool.fee_one_for_zero = 2500;
+                pool.pool.set_slot0(U256::one() << 96, 0)?;
+                pool.pool.set_liquidity(10_u128.pow(18))?;
+                pool.pool.creation_block = Some(15_300_000);
+                pool
+            }),
+            AMM::CurveCryptoPool({
+                let mut pool = CurveCryptoPool::new(
+                    H160::from_low_u64_be(1200),
+                    vec![
+                        usdc,
+                        H160::from_low_u64_be(1201),
+                        H160::from_low_u64_be(1202),
+                    ],
+                    vec![6, 8, 18],
+                    vec![
+                        U256::exp10(13) * 3,
+                        U256::exp10(11),
+                        U256::exp10(22) + U256::exp10(21) * 5,
+                    ],
+                    U256::from(1_707_629),
+                    U256::from(11_809_167_828_997_u64),
+                    vec![U256::exp10(18) * 30000, U256::exp10(18) * 2000],
+                    U256::from(500_000_000_000_000_u64),
+                    3_000_000,
+                    30_000_000,
+                );
+                //The stored invariant is loaded as it was, not computed again from the balances
+                pool.d += U256::one();
+                pool
+            }),
+            AMM::BancorV3Pool({
+                let mut pool = BancorV3Pool::new(
+                    H160::from_low_u64_be(1300),
+                    H160::from_low_u64_be(1301),
+                    H160::from_low_u64_be(1302),
+                    H160::from_low_u64_be(1303),
+                    8,
+                    10_u128.pow(24) * 3,
+                    10_u128.pow(11) * 5,
+                    U256::from(6 * 10_u64.pow(11)),
+                    2000,
+                );
+                pool.trading_enabled = false;
+                pool
+            }),
+        ])
+    }
+
+    #[test]
+    fn test_load_matches_checkpoint() -> eyre::Result<()> {
+        let amms = amms()?;
+
+        let checkpoint_path = std::env::temp_dir().join("amms-sqlite-round-trip.json");
+        let checkpoint_path = checkpoint_path.to_str().expect("temp dir is valid UTF-8");
+        construct_checkpoint(vec![], &amms, 17_000_000, checkpoint_path)?;
+        let (checkpoint_amms, _) = deconstruct_checkpoint(checkpoint_path)?;
+        std::fs::remove_file(checkpoint_path)?;
+
+        let store = SqliteStore::open_in_memory()?;
+        store.save_amms(&amms, 17_000_000)?;
+        let loaded = store.load_amms(&PoolFilter::default())?;
+
+        assert_eq!(
+            serde_json::to_string(&loaded)?,
+            serde_json::to_string(&checkpoint_amms)?
+        );
+        assert_eq!(store.last_synced_block()?, Some(17_000_000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsert_and_filter() -> eyre::Result<()> {
+        let mut amms = amms()?;
+        let weth = amms[0].tokens()[1];
+
+        let store = SqliteStore::open_in_memory()?;
+        store.save_amms(&amms, 17_000_000)?;
+
+        if let AMM::UniswapV2Pool(pool) = &mut amms[1] {
+            pool.reserve_0 += 1;
+        }
+        store.upsert_amm(&amms[1], 17_000_001)?;
+
+        //The updated pool keeps its position
+        let loaded = store.load_amms(&PoolFilter::default())?;
+        assert_eq!(
+            serde_json::to_string(&loaded)?,
+            serde_json::to_string(&amms)?
+        );
+
+        let v2_pools = store.load_amms(&PoolFilter::new().with_protocol(Protocol::UniswapV2))?;
+        assert_eq!(v2_pools.len(), 1);
+        assert_eq!(v2_pools[0].address(), H160::from_low_u64_be(100));
+
+        let with_weth = store.load_amms(&PoolFilter::new().with_token(weth))?;
+        assert_eq!(with_weth.len(), 3);
+        let updated = store.load_amms(&PoolFilter::new().with_min_synced_block(17_000_001))?;
+        assert_eq!(updated.len(), 1);
+
+        assert!(store.remove_amm(H160::from_low_u64_be(200))?);
+        assert_eq!(
+            store.load_amms(&PoolFilter::new().with_token(weth))?.len(),
+            2
+        );
+
+        Ok(())
+    }
+}
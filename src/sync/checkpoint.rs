@@ -1,5 +1,4 @@
 use std::{
-    fs::read_to_string,
     panic::resume_unwind,
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
@@ -7,50 +6,268 @@ use std::{
 
 use ethers::{providers::Middleware, types::H160};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
 
 use tokio::task::JoinHandle;
 
 use crate::{
     amm::{
+        algebra::factory::AlgebraFactory,
         factory::{AutomatedMarketMakerFactory, Factory},
+        kyber_elastic::factory::KyberElasticFactory,
+        maverick::factory::MaverickFactory,
         uniswap_v2::factory::UniswapV2Factory,
         uniswap_v3::factory::UniswapV3Factory,
+        uniswap_v4::factory::UniswapV4Factory,
+        velodrome::factory::VelodromeFactory,
         AMM,
     },
     errors::{AMMError, CheckpointError},
     sync,
 };
 
-use super::amms_are_congruent;
+use super::{amms_are_congruent, store::CheckpointStore};
+
+/// Version of the checkpoint layout, written in every format. Version 2 added the token metadata of pools, version 3
+/// their `serde_version`.
+///
+/// JSON checkpoints of any older version are read, binary formats have no field names to default missing fields
+/// from, so binary checkpoints are only read at the current version. See `amm::compat` for the pools.
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+//Header byte of each binary format, JSON checkpoints have no header and start with `{`
+const BINCODE_HEADER: u8 = 1;
+const MESSAGE_PACK_HEADER: u8 = 2;
+
+/// Encoding of a checkpoint. Binary checkpoints are prefixed with a header byte, so readers detect the format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable, for inspecting checkpoints
+    Json,
+    /// Fastest to load
+    #[default]
+    Bincode,
+    MessagePack,
+}
 
-#[derive(Clone, Serialize, Deserialize)]
+impl Format {
+    /// Format of an encoded checkpoint, sniffed from its first byte
+    pub fn detect(data: &[u8]) -> Format {
+        match data.first() {
+            Some(&BINCODE_HEADER) => Format::Bincode,
+            Some(&MESSAGE_PACK_HEADER) => Format::MessagePack,
+            _ => Format::Json,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Checkpoint {
+    /// Layout the checkpoint was written with, 0 for checkpoints written before it was recorded
+    pub version: u32,
     pub timestamp: usize,
     pub block_number: u64,
-    pub factories: Vec<Factory>,
+    pub factories: Vec<FactoryCheckpoint>,
     pub amms: Vec<AMM>,
+    /// Chain the checkpoint was synced on, None for checkpoints written before the chain id was recorded
+    pub chain_id: Option<u64>,
 }
 
 impl Checkpoint {
     pub fn new(
         timestamp: usize,
         block_number: u64,
-        factories: Vec<Factory>,
+        factories: Vec<FactoryCheckpoint>,
         amms: Vec<AMM>,
     ) -> Checkpoint {
         Checkpoint {
+            version: CHECKPOINT_VERSION,
             timestamp,
             block_number,
             factories,
             amms,
+            chain_id: None,
+        }
+    }
+
+    pub fn encode(&self, format: Format) -> Result<Vec<u8>, CheckpointError> {
+        Ok(match format {
+            Format::Json => serde_json::to_vec_pretty(self)?,
+            Format::Bincode => {
+                let mut data = vec![BINCODE_HEADER];
+                bincode::serialize_into(&mut data, self)?;
+                data
+            }
+            Format::MessagePack => {
+                let mut data = vec![MESSAGE_PACK_HEADER];
+                rmp_serde::encode::write(&mut data, self)?;
+                data
+            }
+        })
+    }
+
+    /// Decodes a checkpoint in any format, detected from its header byte
+    pub fn decode(data: &[u8]) -> Result<Checkpoint, CheckpointError> {
+        let format = Format::detect(data);
+        if format != Format::Json {
+            let version = binary_version(data, format)?;
+            if version != CHECKPOINT_VERSION {
+                return Err(CheckpointError::UnsupportedVersion(version));
+            }
+        }
+
+        let checkpoint: Checkpoint = match format {
+            Format::Json => serde_json::from_slice(data)?,
+            Format::Bincode => bincode::deserialize(&data[1..])?,
+            Format::MessagePack => rmp_serde::from_slice(&data[1..])?,
+        };
+
+        if checkpoint.version > CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(checkpoint.version));
+        }
+
+        Ok(checkpoint)
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    /// Errors if the checkpoint was synced on another chain than `chain_id`.
+    /// Block numbers and addresses of a checkpoint are meaningless on any other chain, even one sharing its deployments.
+    pub fn check_chain_id(&self, chain_id: u64) -> Result<(), CheckpointError> {
+        match self.chain_id {
+            Some(checkpoint_chain_id) if checkpoint_chain_id != chain_id => {
+                Err(CheckpointError::ChainIdMismatch {
+                    checkpoint: checkpoint_chain_id,
+                    provider: chain_id,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+//Version of a binary checkpoint, read without decoding the AMMs of other layouts
+fn binary_version(data: &[u8], format: Format) -> Result<u32, CheckpointError> {
+    Ok(match format {
+        Format::Bincode => bincode::deserialize::<u32>(&data[1..])?,
+        _ => {
+            let (version, ..) = rmp_serde::from_slice::<(
+                u32,
+                IgnoredAny,
+                IgnoredAny,
+                IgnoredAny,
+                IgnoredAny,
+                IgnoredAny,
+            )>(&data[1..])?;
+            version
+        }
+    })
+}
+
+/// Discovery cursor of a single factory, new pools are searched for from `last_scanned_block + 1`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactoryCheckpoint {
+    pub factory: Factory,
+    pub last_scanned_block: u64,
+    pub pools_discovered: u64,
+}
+
+impl FactoryCheckpoint {
+    pub fn new(factory: Factory, last_scanned_block: u64, pools_discovered: u64) -> Self {
+        FactoryCheckpoint {
+            factory,
+            last_scanned_block,
+            pools_discovered,
+        }
+    }
+}
+
+//Checkpoints written before per factory cursors were added store the factories directly
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CheckpointFactory {
+    FactoryCheckpoint(FactoryCheckpoint),
+    Legacy(Factory),
+}
+
+impl<'de> Deserialize<'de> for Checkpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        //Legacy layouts were only ever written as JSON, binary formats cannot tell them apart without a schema
+        if deserializer.is_human_readable() {
+            JsonCheckpoint::deserialize(deserializer).map(Checkpoint::from)
+        } else {
+            BinaryCheckpoint::deserialize(deserializer).map(Checkpoint::from)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonCheckpoint {
+    #[serde(default)]
+    version: u32,
+    timestamp: usize,
+    block_number: u64,
+    factories: Vec<CheckpointFactory>,
+    amms: Vec<AMM>,
+    #[serde(default)]
+    chain_id: Option<u64>,
+}
+
+impl From<JsonCheckpoint> for Checkpoint {
+    fn from(checkpoint: JsonCheckpoint) -> Self {
+        let factories = checkpoint
+            .factories
+            .into_iter()
+            .map(|factory| match factory {
+                CheckpointFactory::FactoryCheckpoint(factory_checkpoint) => factory_checkpoint,
+                //Legacy factories are treated as scanned up to the checkpoint block, their pool count is unknown
+                CheckpointFactory::Legacy(factory) => {
+                    FactoryCheckpoint::new(factory, checkpoint.block_number, 0)
+                }
+            })
+            .collect();
+
+        Checkpoint {
+            version: checkpoint.version,
+            timestamp: checkpoint.timestamp,
+            block_number: checkpoint.block_number,
+            factories,
+            amms: checkpoint.amms,
+            chain_id: checkpoint.chain_id,
+        }
+    }
+}
+
+//Fields in the order they are serialized by `Checkpoint`
+#[derive(Deserialize)]
+struct BinaryCheckpoint {
+    version: u32,
+    timestamp: usize,
+    block_number: u64,
+    factories: Vec<FactoryCheckpoint>,
+    amms: Vec<AMM>,
+    chain_id: Option<u64>,
+}
+
+impl From<BinaryCheckpoint> for Checkpoint {
+    fn from(checkpoint: BinaryCheckpoint) -> Self {
+        Checkpoint {
+            version: checkpoint.version,
+            timestamp: checkpoint.timestamp,
+            block_number: checkpoint.block_number,
+            factories: checkpoint.factories,
+            amms: checkpoint.amms,
+            chain_id: checkpoint.chain_id,
         }
     }
 }
 
 //Get all pairs from last synced block and sync reserve values for each Dex in the `dexes` vec.
 pub async fn sync_amms_from_checkpoint<M: 'static + Middleware>(
-    path_to_checkpoint: &str,
+    checkpoint_store: Arc<dyn CheckpointStore>,
     step: u64,
     middleware: Arc<M>,
 ) -> Result<(Vec<Factory>, Vec<AMM>), AMMError<M>> {
@@ -59,14 +276,44 @@ pub async fn sync_amms_from_checkpoint<M: 'static + Middleware>(
         .await
         .map_err(AMMError::MiddlewareError)?
         .as_u64();
+    let chain_id = middleware
+        .get_chainid()
+        .await
+        .map_err(AMMError::MiddlewareError)?
+        .as_u64();
 
-    let checkpoint: Checkpoint =
-        serde_json::from_str(read_to_string(path_to_checkpoint)?.as_str())?;
+    let checkpoint = checkpoint_store.read().await?;
+    checkpoint.check_chain_id(chain_id)?;
+    let mut factory_checkpoints = checkpoint.factories;
 
     //Sort all of the pools from the checkpoint into uniswap_v2_pools and uniswap_v3_pools pools so we can sync them concurrently
-    let (uniswap_v2_pools, uniswap_v3_pools, erc_4626_pools) = sort_amms(checkpoint.amms);
+    let (
+        uniswap_v2_pools,
+        uniswap_v3_pools,
+        erc_4626_pools,
+        curve_pools,
+        balancer_pools,
+        velodrome_pools,
+        uniswap_v4_pools,
+        liquidity_book_pools,
+        maverick_pools,
+        dodo_pools,
+        kyber_elastic_pools,
+        algebra_pools,
+        curve_crypto_pools,
+        bancor_v3_pools,
+        custom_amms,
+    ) = sort_amms(checkpoint.amms);
+
+    //Custom AMMs can only be populated through their factory, so they are carried over as is
+    if !custom_amms.is_empty() {
+        tracing::warn!(
+            "{} custom AMMs in checkpoint will not be synced",
+            custom_amms.len()
+        );
+    }
 
-    let mut aggregated_amms = vec![];
+    let mut aggregated_amms = custom_amms;
     let mut handles = vec![];
 
     //Sync all uniswap v2 pools from checkpoint
@@ -95,6 +342,93 @@ pub async fn sync_amms_from_checkpoint<M: 'static + Middleware>(
         );
     }
 
+    //Sync all velodrome pools from checkpoint
+    if !velodrome_pools.is_empty() {
+        handles.push(
+            batch_sync_amms_from_checkpoint(
+                velodrome_pools,
+                Some(current_block),
+                middleware.clone(),
+                step,
+            )
+            .await,
+        );
+    }
+
+    //Sync all uniswap v4 pools from checkpoint
+    if !uniswap_v4_pools.is_empty() {
+        handles.push(
+            batch_sync_amms_from_checkpoint(
+                uniswap_v4_pools,
+                Some(current_block),
+                middleware.clone(),
+                step,
+            )
+            .await,
+        );
+    }
+
+    //Sync all maverick pools from checkpoint
+    if !maverick_pools.is_empty() {
+        handles.push(
+            batch_sync_amms_from_checkpoint(
+                maverick_pools,
+                Some(current_block),
+                middleware.clone(),
+                step,
+            )
+            .await,
+        );
+    }
+
+    //Sync all kyber elastic pools from checkpoint
+    if !kyber_elastic_pools.is_empty() {
+        handles.push(
+            batch_sync_amms_from_checkpoint(
+                kyber_elastic_pools,
+                Some(current_block),
+                middleware.clone(),
+                step,
+            )
+            .await,
+        );
+    }
+
+    //Sync all algebra pools from checkpoint
+    if !algebra_pools.is_empty() {
+        handles.push(
+            batch_sync_amms_from_checkpoint(
+                algebra_pools,
+                Some(current_block),
+                middleware.clone(),
+                step,
+            )
+            .await,
+        );
+    }
+
+    //Curve, Balancer, Liquidity Book, DODO, Curve crypto and Bancor V3 pools have no factory to populate them through,
+    //so they are read again by address
+    for mut pools in [
+        curve_pools,
+        balancer_pools,
+        liquidity_book_pools,
+        dodo_pools,
+        curve_crypto_pools,
+        bancor_v3_pools,
+    ] {
+        if pools.is_empty() {
+            continue;
+        }
+
+        let middleware = middleware.clone();
+        handles.push(tokio::spawn(async move {
+            sync::populate_amms(&mut pools, current_block, middleware, step).await?;
+
+            Ok::<_, AMMError<M>>(sync::remove_empty_amms(pools))
+        }));
+    }
+
     if !erc_4626_pools.is_empty() {
         // TODO: Batch sync erc4626 pools from checkpoint
         todo!(
@@ -103,17 +437,22 @@ pub async fn sync_amms_from_checkpoint<M: 'static + Middleware>(
         );
     }
 
-    // Sync all pools from the since synced block
-    handles.extend(
-        get_new_amms_from_range(
-            checkpoint.factories.clone(),
-            checkpoint.block_number,
-            current_block,
-            step,
-            middleware.clone(),
-        )
-        .await,
-    );
+    //Get all pools created since each factory was last scanned
+    let mut new_amm_handles = vec![];
+    for (idx, factory_checkpoint) in factory_checkpoints.iter().enumerate() {
+        if factory_checkpoint.last_scanned_block < current_block {
+            new_amm_handles.push((
+                idx,
+                spawn_get_new_amms_from_range(
+                    factory_checkpoint.factory.clone(),
+                    factory_checkpoint.last_scanned_block + 1,
+                    current_block,
+                    step,
+                    middleware.clone(),
+                ),
+            ));
+        }
+    }
 
     for handle in handles {
         match handle.await {
@@ -130,15 +469,44 @@ pub async fn sync_amms_from_checkpoint<M: 'static + Middleware>(
         }
     }
 
+    for (idx, handle) in new_amm_handles {
+        match handle.await {
+            Ok(sync_result) => {
+                let amms = sync_result?;
+
+                factory_checkpoints[idx].last_scanned_block = current_block;
+                factory_checkpoints[idx].pools_discovered += amms.len() as u64;
+                aggregated_amms.extend(amms);
+            }
+            Err(err) => {
+                if err.is_panic() {
+                    // Resume the panic on the main task
+                    resume_unwind(err.into_panic());
+                }
+            }
+        }
+    }
+
     //update the sync checkpoint
-    construct_checkpoint(
-        checkpoint.factories.clone(),
-        &aggregated_amms,
-        current_block,
-        path_to_checkpoint,
-    )?;
-
-    Ok((checkpoint.factories, aggregated_amms))
+    checkpoint_store
+        .write(
+            &Checkpoint::new(
+                checkpoint_timestamp()?,
+                current_block,
+                factory_checkpoints.clone(),
+                aggregated_amms.clone(),
+            )
+            .with_chain_id(chain_id),
+        )
+        .await?;
+
+    Ok((
+        factory_checkpoints
+            .into_iter()
+            .map(|factory_checkpoint| factory_checkpoint.factory)
+            .collect(),
+        aggregated_amms,
+    ))
 }
 
 pub async fn get_new_amms_from_range<M: 'static + Middleware>(
@@ -148,31 +516,36 @@ pub async fn get_new_amms_from_range<M: 'static + Middleware>(
     step: u64,
     middleware: Arc<M>,
 ) -> Vec<JoinHandle<Result<Vec<AMM>, AMMError<M>>>> {
-    //Create the filter with all the pair created events
-    //Aggregate the populated pools from each thread
-    let mut handles = vec![];
-
-    for factory in factories.into_iter() {
-        let middleware = middleware.clone();
-
-        //Spawn a new thread to get all pools and sync data for each dex
-        handles.push(tokio::spawn(async move {
-            let mut amms = factory
-                .get_all_pools_from_logs(from_block, to_block, step, middleware.clone())
-                .await?;
+    factories
+        .into_iter()
+        .map(|factory| {
+            spawn_get_new_amms_from_range(factory, from_block, to_block, step, middleware.clone())
+        })
+        .collect()
+}
 
-            factory
-                .populate_amm_data(&mut amms, Some(to_block), middleware.clone(), step)
-                .await?;
+//Spawns a new thread to get all pools created by the factory in `from_block..=to_block` and sync their data
+fn spawn_get_new_amms_from_range<M: 'static + Middleware>(
+    factory: Factory,
+    from_block: u64,
+    to_block: u64,
+    step: u64,
+    middleware: Arc<M>,
+) -> JoinHandle<Result<Vec<AMM>, AMMError<M>>> {
+    tokio::spawn(async move {
+        let mut amms = factory
+            .get_all_pools_from_logs(from_block, to_block, step, middleware.clone())
+            .await?;
 
-            //Clean empty pools
-            amms = sync::remove_empty_amms(amms);
+        factory
+            .populate_amm_data(&mut amms, Some(to_block), middleware.clone(), step)
+            .await?;
 
-            Ok::<_, AMMError<M>>(amms)
-        }));
-    }
+        //Clean empty pools
+        amms = sync::remove_empty_amms(amms);
 
-    handles
+        Ok::<_, AMMError<M>>(amms)
+    })
 }
 
 pub async fn batch_sync_amms_from_checkpoint<M: 'static + Middleware>(
@@ -193,7 +566,39 @@ pub async fn batch_sync_amms_from_checkpoint<M: 'static + Middleware>(
             0,
         ))),
 
-        AMM::ERC4626Vault(_) => None,
+        AMM::VelodromePool(_) => Some(Factory::VelodromeFactory(VelodromeFactory::new(
+            H160::zero(),
+            0,
+        ))),
+
+        AMM::UniswapV4Pool(_) => Some(Factory::UniswapV4Factory(UniswapV4Factory::new(
+            H160::zero(),
+            0,
+        ))),
+
+        AMM::MaverickPool(_) => Some(Factory::MaverickFactory(MaverickFactory::new(
+            H160::zero(),
+            0,
+        ))),
+
+        AMM::KyberElasticPool(_) => Some(Factory::KyberElasticFactory(KyberElasticFactory::new(
+            H160::zero(),
+            0,
+        ))),
+
+        AMM::AlgebraPool(_) => Some(Factory::AlgebraFactory(AlgebraFactory::new(
+            H160::zero(),
+            0,
+        ))),
+
+        AMM::ERC4626Vault(_)
+        | AMM::CurveStableSwapPool(_)
+        | AMM::BalancerWeightedPool(_)
+        | AMM::LiquidityBookPool(_)
+        | AMM::DodoPool(_)
+        | AMM::CurveCryptoPool(_)
+        | AMM::BancorV3Pool(_)
+        | AMM::Custom(_) => None,
     };
 
     //Spawn a new thread to get all pools and sync data for each dex
@@ -219,19 +624,78 @@ pub async fn batch_sync_amms_from_checkpoint<M: 'static + Middleware>(
     })
 }
 
-pub fn sort_amms(amms: Vec<AMM>) -> (Vec<AMM>, Vec<AMM>, Vec<AMM>) {
+#[allow(clippy::type_complexity)]
+pub fn sort_amms(
+    amms: Vec<AMM>,
+) -> (
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+    Vec<AMM>,
+) {
     let mut uniswap_v2_pools = vec![];
     let mut uniswap_v3_pools = vec![];
     let mut erc_4626_vaults = vec![];
+    let mut curve_pools = vec![];
+    let mut balancer_pools = vec![];
+    let mut velodrome_pools = vec![];
+    let mut uniswap_v4_pools = vec![];
+    let mut liquidity_book_pools = vec![];
+    let mut maverick_pools = vec![];
+    let mut dodo_pools = vec![];
+    let mut kyber_elastic_pools = vec![];
+    let mut algebra_pools = vec![];
+    let mut curve_crypto_pools = vec![];
+    let mut bancor_v3_pools = vec![];
+    let mut custom_amms = vec![];
     for amm in amms {
         match amm {
             AMM::UniswapV2Pool(_) => uniswap_v2_pools.push(amm),
             AMM::UniswapV3Pool(_) => uniswap_v3_pools.push(amm),
             AMM::ERC4626Vault(_) => erc_4626_vaults.push(amm),
+            AMM::CurveStableSwapPool(_) => curve_pools.push(amm),
+            AMM::BalancerWeightedPool(_) => balancer_pools.push(amm),
+            AMM::VelodromePool(_) => velodrome_pools.push(amm),
+            AMM::UniswapV4Pool(_) => uniswap_v4_pools.push(amm),
+            AMM::LiquidityBookPool(_) => liquidity_book_pools.push(amm),
+            AMM::MaverickPool(_) => maverick_pools.push(amm),
+            AMM::DodoPool(_) => dodo_pools.push(amm),
+            AMM::KyberElasticPool(_) => kyber_elastic_pools.push(amm),
+            AMM::AlgebraPool(_) => algebra_pools.push(amm),
+            AMM::CurveCryptoPool(_) => curve_crypto_pools.push(amm),
+            AMM::BancorV3Pool(_) => bancor_v3_pools.push(amm),
+            AMM::Custom(_) => custom_amms.push(amm),
         }
     }
 
-    (uniswap_v2_pools, uniswap_v3_pools, erc_4626_vaults)
+    (
+        uniswap_v2_pools,
+        uniswap_v3_pools,
+        erc_4626_vaults,
+        curve_pools,
+        balancer_pools,
+        velodrome_pools,
+        uniswap_v4_pools,
+        liquidity_book_pools,
+        maverick_pools,
+        dodo_pools,
+        kyber_elastic_pools,
+        algebra_pools,
+        curve_crypto_pools,
+        bancor_v3_pools,
+        custom_amms,
+    )
 }
 
 pub async fn get_new_pools_from_range<M: 'static + Middleware>(
@@ -269,25 +733,213 @@ pub async fn get_new_pools_from_range<M: 'static + Middleware>(
 }
 
 pub fn construct_checkpoint(
-    factories: Vec<Factory>,
+    factories: Vec<FactoryCheckpoint>,
     amms: &[AMM],
     latest_block: u64,
     checkpoint_path: &str,
 ) -> Result<(), CheckpointError> {
     let checkpoint = Checkpoint::new(
-        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64() as usize,
+        checkpoint_timestamp()?,
         latest_block,
         factories,
         amms.to_vec(),
     );
 
-    std::fs::write(checkpoint_path, serde_json::to_string_pretty(&checkpoint)?)?;
+    //Written next to the checkpoint and renamed over it, like `FileCheckpointStore`
+    let temp_path = format!("{checkpoint_path}.tmp");
+    std::fs::write(&temp_path, serde_json::to_string_pretty(&checkpoint)?)?;
+    std::fs::rename(&temp_path, checkpoint_path)?;
 
     Ok(())
 }
 
-//Deconstructs the checkpoint into a Vec<AMM>
+pub(crate) fn checkpoint_timestamp() -> Result<usize, CheckpointError> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64() as usize)
+}
+
+//Deconstructs the checkpoint into a Vec<AMM>, in any format
 pub fn deconstruct_checkpoint(checkpoint_path: &str) -> Result<(Vec<AMM>, u64), CheckpointError> {
-    let checkpoint: Checkpoint = serde_json::from_str(read_to_string(checkpoint_path)?.as_str())?;
+    let checkpoint = Checkpoint::decode(&std::fs::read(checkpoint_path)?)?;
     Ok((checkpoint.amms, checkpoint.block_number))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::types::H160;
+
+    use crate::{
+        amm::{
+            erc_4626::ERC4626Vault,
+            factory::{AutomatedMarketMakerFactory, Factory},
+            uniswap_v2::{factory::UniswapV2Factory, UniswapV2Pool},
+            uniswap_v3::{factory::UniswapV3Factory, UniswapV3Pool},
+            AMM,
+        },
+        errors::CheckpointError,
+    };
+
+    use super::{Checkpoint, FactoryCheckpoint, Format, CHECKPOINT_VERSION};
+
+    const UNISWAP_V3_FIXTURE: &str = include_str!("../../benches/fixtures/uniswap_v3_pool.json");
+
+    fn checkpoint() -> eyre::Result<Checkpoint> {
+        let amms = vec![
+            AMM::UniswapV2Pool(UniswapV2Pool {
+                address: H160::from_low_u64_be(10),
+                token_a: H160::from_low_u64_be(1),
+                token_b: H160::from_low_u64_be(2),
+                reserve_0: 1000,
+                reserve_1: 2000,
+                fee: 300,
+                ..Default::default()
+            }),
+            AMM::UniswapV3Pool(serde_json::from_str::<UniswapV3Pool>(UNISWAP_V3_FIXTURE)?),
+            AMM::ERC4626Vault(ERC4626Vault {
+                vault_token: H160::from_low_u64_be(11),
+                asset_token: H160::from_low_u64_be(1),
+                vault_reserve: 100.into(),
+                asset_reserve: 110.into(),
+                ..Default::default()
+            }),
+        ];
+
+        Ok(Checkpoint::new(
+            1690000000,
+            17700000,
+            vec![
+                FactoryCheckpoint::new(
+                    Factory::UniswapV2Factory(UniswapV2Factory::new(
+                        H160::from_low_u64_be(1),
+                        0,
+                        300,
+                    )),
+                    17699990,
+                    42,
+                ),
+                FactoryCheckpoint::new(
+                    Factory::UniswapV3Factory(UniswapV3Factory::new(H160::from_low_u64_be(2), 0)),
+                    17700000,
+                    1,
+                ),
+            ],
+            amms,
+        )
+        .with_chain_id(1))
+    }
+
+    #[test]
+    fn test_round_trip_across_formats() -> eyre::Result<()> {
+        let checkpoint = checkpoint()?;
+        let expected = serde_json::to_value(&checkpoint)?;
+
+        for format in [Format::Json, Format::Bincode, Format::MessagePack] {
+            let data = checkpoint.encode(format)?;
+            assert_eq!(Format::detect(&data), format);
+
+            let decoded = Checkpoint::decode(&data)?;
+            assert_eq!(decoded.version, CHECKPOINT_VERSION);
+            assert_eq!(decoded.chain_id, Some(1));
+            assert_eq!(serde_json::to_value(&decoded)?, expected, "{format:?}");
+        }
+
+        //Binary checkpoints are smaller than JSON ones
+        assert!(checkpoint.encode(Format::Bincode)?.len() < checkpoint.encode(Format::Json)?.len());
+
+        let mut newer = checkpoint.clone();
+        newer.version = CHECKPOINT_VERSION + 1;
+        assert!(matches!(
+            Checkpoint::decode(&newer.encode(Format::Bincode)?),
+            Err(CheckpointError::UnsupportedVersion(_))
+        ));
+
+        //Binary checkpoints of older layouts are rejected before decoding their AMMs
+        let mut older = checkpoint;
+        older.version = 1;
+        for format in [Format::Bincode, Format::MessagePack] {
+            assert!(matches!(
+                Checkpoint::decode(&older.encode(format)?),
+                Err(CheckpointError::UnsupportedVersion(1))
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_legacy_checkpoint() -> eyre::Result<()> {
+        let checkpoint: Checkpoint = serde_json::from_str(
+            r#"{
+                "timestamp": 1690000000,
+                "block_number": 17700000,
+                "factories": [
+                    {
+                        "UniswapV2Factory": {
+                            "address": "0x5c69bee701ef814a2b6a3edd4b1652cb9cc5aa6f",
+                            "creation_block": 10000835,
+                            "fee": 300
+                        }
+                    }
+                ],
+                "amms": []
+            }"#,
+        )?;
+
+        assert_eq!(checkpoint.factories.len(), 1);
+        assert_eq!(
+            checkpoint.factories[0].factory.address(),
+            H160::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")?
+        );
+        assert_eq!(checkpoint.factories[0].last_scanned_block, 17700000);
+        assert_eq!(checkpoint.factories[0].pools_discovered, 0);
+        assert_eq!(checkpoint.version, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_chain_id() -> eyre::Result<()> {
+        let checkpoint: Checkpoint = serde_json::from_str(
+            r#"{"timestamp": 1690000000, "block_number": 17700000, "factories": [], "amms": []}"#,
+        )?;
+
+        //Checkpoints written before the chain id was recorded are accepted on any chain
+        assert_eq!(checkpoint.chain_id, None);
+        assert!(checkpoint.check_chain_id(42161).is_ok());
+
+        let checkpoint: Checkpoint =
+            serde_json::from_str(&serde_json::to_string(&checkpoint.with_chain_id(42161))?)?;
+        assert_eq!(checkpoint.chain_id, Some(42161));
+        assert!(checkpoint.check_chain_id(42161).is_ok());
+        assert!(matches!(
+            checkpoint.check_chain_id(8453),
+            Err(CheckpointError::ChainIdMismatch {
+                checkpoint: 42161,
+                provider: 8453
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_factory_checkpoint_round_trip() -> eyre::Result<()> {
+        let checkpoint = Checkpoint::new(
+            1690000000,
+            17700000,
+            vec![FactoryCheckpoint::new(
+                Factory::UniswapV2Factory(UniswapV2Factory::new(H160::from_low_u64_be(1), 0, 300)),
+                17699990,
+                42,
+            )],
+            vec![],
+        );
+
+        let checkpoint: Checkpoint = serde_json::from_str(&serde_json::to_string(&checkpoint)?)?;
+        assert_eq!(checkpoint.factories[0].last_scanned_block, 17699990);
+        assert_eq!(checkpoint.factories[0].pools_discovered, 42);
+
+        Ok(())
+    }
+}
@@ -1,73 +1,349 @@
 use crate::{
     amm::{
-        factory::{AutomatedMarketMakerFactory, Factory},
-        uniswap_v2, uniswap_v3, AutomatedMarketMaker, AMM,
+        algebra, balancer, bancor_v3, curve, curve_crypto, dodo, erc_4626,
+        factory::{AmmFactory, AutomatedMarketMakerFactory, Factory},
+        kyber_elastic, liquidity_book, maverick,
+        multicall::BatchRequestMode,
+        uniswap_v2, uniswap_v3, uniswap_v4, velodrome, AmmState, PopulateOptions, AMM,
     },
     errors::AMMError,
+    filters::pipeline::{FilterPipeline, FilterReport},
+    metrics::Metrics,
+    tokens::TokenStore,
 };
 
-use ethers::providers::Middleware;
+use ethers::{providers::Middleware, types::H160};
+use tracing::{instrument, Instrument};
 
 use std::{panic::resume_unwind, sync::Arc};
 pub mod checkpoint;
+pub mod store;
+pub mod stream;
+
+use self::{
+    checkpoint::{Checkpoint, FactoryCheckpoint},
+    store::CheckpointStore,
+    stream::DEFAULT_MAX_RETAINED_POOLS,
+};
+
+pub use stream::{sync_amms_streaming, StreamingSync};
 
 pub async fn sync_amms<M: 'static + Middleware>(
     factories: Vec<Factory>,
     middleware: Arc<M>,
-    checkpoint_path: Option<&str>,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
     step: u64,
 ) -> Result<(Vec<AMM>, u64), AMMError<M>> {
+    sync_amms_with_custom_factories(factories, vec![], middleware, checkpoint_store, step).await
+}
+
+/// Syncs the built in factories alongside factories implemented outside of this crate.
+/// Custom factories are not written to the checkpoint, although the AMMs they discover are.
+pub async fn sync_amms_with_custom_factories<M: 'static + Middleware>(
+    factories: Vec<Factory>,
+    custom_factories: Vec<Box<dyn AmmFactory<M>>>,
+    middleware: Arc<M>,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    step: u64,
+) -> Result<(Vec<AMM>, u64), AMMError<M>> {
+    let (amms, current_block, _) = sync_amms_with_prefilter(
+        factories,
+        custom_factories,
+        middleware,
+        checkpoint_store,
+        step,
+        None,
+    )
+    .await?;
+
+    Ok((amms, current_block))
+}
+
+/// Same as `sync_amms_with_custom_factories`, running `prefilter` on the AMMs of every factory once they are populated,
+/// so that filtered AMMs never make it into the checkpoint. Also returns the drops of the prefilter across all factories.
+pub async fn sync_amms_with_prefilter<M: 'static + Middleware>(
+    factories: Vec<Factory>,
+    custom_factories: Vec<Box<dyn AmmFactory<M>>>,
+    middleware: Arc<M>,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    step: u64,
+    prefilter: Option<Arc<FilterPipeline<M>>>,
+) -> Result<(Vec<AMM>, u64, FilterReport), AMMError<M>> {
+    sync_amms_with_token_store(
+        factories,
+        custom_factories,
+        middleware,
+        checkpoint_store,
+        step,
+        prefilter,
+        None,
+    )
+    .await
+}
+
+/// Same as `sync_amms_with_prefilter`, recording the decimals of every synced token in `token_store`.
+/// A fresh store is used if None, pass a shared or pre-seeded store to reuse token metadata across syncs and filters.
+pub async fn sync_amms_with_token_store<M: 'static + Middleware>(
+    factories: Vec<Factory>,
+    custom_factories: Vec<Box<dyn AmmFactory<M>>>,
+    middleware: Arc<M>,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    step: u64,
+    prefilter: Option<Arc<FilterPipeline<M>>>,
+    token_store: Option<TokenStore>,
+) -> Result<(Vec<AMM>, u64, FilterReport), AMMError<M>> {
+    let mut config = SyncConfig::new(step);
+    config.checkpoint_store = checkpoint_store;
+    config.prefilter = prefilter;
+    config.token_store = token_store;
+
+    sync_amms_with_config(factories, custom_factories, middleware, config).await
+}
+
+/// Options of `sync_amms_with_config`, the other sync functions are shorthands for a subset of them
+pub struct SyncConfig<M: 'static + Middleware> {
+    /// Blocks per log request, and pools per batch request unless `batch_step` is set
+    pub step: u64,
+    /// Pools per batch request
+    pub batch_step: Option<u64>,
+    /// Chain the provider is expected to be on, the sync fails on any other chain
+    pub chain_id: Option<u64>,
+    /// Constructor calls of the batch contracts by default, falling back to Multicall3 if the provider rejects them
+    pub batch_request_mode: BatchRequestMode,
+    pub checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    /// Run on the AMMs of every factory once they are populated, so filtered AMMs never make it into the checkpoint
+    pub prefilter: Option<Arc<FilterPipeline<M>>>,
+    /// Records the decimals of every synced token, a fresh store is used if None
+    pub token_store: Option<TokenStore>,
+    /// Data fetched on top of the pool data, for the AMMs kept by the prefilter
+    pub populate_options: PopulateOptions,
+    pub metrics: Metrics,
+    /// Pools held in memory at once by `sync_amms_streaming`, see `stream::StreamingSync`
+    pub max_retained_pools: usize,
+}
+
+impl<M: 'static + Middleware> SyncConfig<M> {
+    pub fn new(step: u64) -> Self {
+        SyncConfig {
+            step,
+            batch_step: None,
+            chain_id: None,
+            batch_request_mode: BatchRequestMode::Auto,
+            checkpoint_store: None,
+            prefilter: None,
+            token_store: None,
+            populate_options: PopulateOptions::default(),
+            metrics: Metrics::default(),
+            max_retained_pools: DEFAULT_MAX_RETAINED_POOLS,
+        }
+    }
+
+    /// Steps of the preset of `chain_id`, expecting the provider to be on that chain.
+    /// Returns None if the chain has no preset, see `known_factories::chain_preset`.
+    #[cfg(feature = "known-factories")]
+    pub fn for_chain(chain_id: u64) -> Option<Self> {
+        crate::amm::known_factories::chain_preset(chain_id).map(|preset| {
+            SyncConfig::new(preset.log_step)
+                .with_batch_step(preset.batch_step)
+                .with_chain_id(chain_id)
+        })
+    }
+
+    pub fn with_batch_step(mut self, batch_step: u64) -> Self {
+        self.batch_step = Some(batch_step);
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    pub fn with_batch_request_mode(mut self, batch_request_mode: BatchRequestMode) -> Self {
+        self.batch_request_mode = batch_request_mode;
+        self
+    }
+
+    pub fn with_checkpoint_store(mut self, checkpoint_store: Arc<dyn CheckpointStore>) -> Self {
+        self.checkpoint_store = Some(checkpoint_store);
+        self
+    }
+
+    pub fn with_prefilter(mut self, prefilter: Arc<FilterPipeline<M>>) -> Self {
+        self.prefilter = Some(prefilter);
+        self
+    }
+
+    pub fn with_token_store(mut self, token_store: TokenStore) -> Self {
+        self.token_store = Some(token_store);
+        self
+    }
+
+    pub fn with_populate_options(mut self, populate_options: PopulateOptions) -> Self {
+        self.populate_options = populate_options;
+        self
+    }
+
+    /// Records the duration of each phase of the sync and the number of pools populated
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_max_retained_pools(mut self, max_retained_pools: usize) -> Self {
+        self.max_retained_pools = max_retained_pools.max(1);
+        self
+    }
+}
+
+/// Syncs the AMMs of `factories` and `custom_factories` up to the current block with the options of `config`,
+/// returning the AMMs, the block they were synced at and the drops of the prefilter across all factories
+#[instrument(skip_all, fields(factories = factories.len(), custom_factories = custom_factories.len(), step = config.step), err(Debug))]
+pub async fn sync_amms_with_config<M: 'static + Middleware>(
+    factories: Vec<Factory>,
+    custom_factories: Vec<Box<dyn AmmFactory<M>>>,
+    middleware: Arc<M>,
+    config: SyncConfig<M>,
+) -> Result<(Vec<AMM>, u64, FilterReport), AMMError<M>> {
+    let SyncConfig {
+        step,
+        batch_step,
+        chain_id,
+        batch_request_mode,
+        checkpoint_store,
+        prefilter,
+        token_store,
+        populate_options,
+        metrics,
+        max_retained_pools: _,
+    } = config;
+    let batch_step = batch_step.unwrap_or(step);
+    let token_store = token_store.unwrap_or_default();
+    let _sync_timer = metrics.sync_phase_timer("sync");
+
     tracing::info!(
         step,
-        checkpoint_path,
-        "syncing AMMs of {} factories",
-        factories.len()
+        checkpoint = checkpoint_store.is_some(),
+        "syncing AMMs of {} factories and {} custom factories",
+        factories.len(),
+        custom_factories.len()
     );
 
-    let current_block = middleware
-        .get_block_number()
-        .await
-        .map_err(AMMError::MiddlewareError)?
-        .as_u64();
-
-    tracing::trace!(current_block);
+    let (current_block, provider_chain_id) =
+        current_block_and_chain_id(&middleware, chain_id).await?;
 
     //Aggregate the populated pools from each thread
     let mut aggregated_amms: Vec<AMM> = vec![];
     let mut handles = vec![];
 
+    let context = Arc::new(PopulateContext {
+        current_block,
+        batch_step,
+        batch_request_mode,
+        prefilter: prefilter.clone(),
+        token_store: token_store.clone(),
+        populate_options,
+        metrics: metrics.clone(),
+        middleware: middleware.clone(),
+    });
+
     //For each dex supplied, get all pair created events and get reserve values
-    for factory in factories.clone() {
+    for factory in factories {
         let middleware = middleware.clone();
+        let metrics = metrics.clone();
+        let context = context.clone();
+        let span = tracing::info_span!("sync_factory", factory = ?factory.address());
 
         //Spawn a new thread to get all pools and sync data for each dex
-        handles.push(tokio::spawn(async move {
-            tracing::info!("syncing factory {}", factory.address());
-            //Get all of the amms from the factory
-            let mut amms: Vec<AMM> = factory
-                .get_all_amms(Some(current_block), middleware.clone(), step)
-                .await?;
-            populate_amms(&mut amms, current_block, middleware.clone(), step).await?;
+        handles.push((
+            Some(factory.clone()),
+            tokio::spawn(
+                async move {
+                    tracing::info!("syncing factory {}", factory.address());
+                    //Get all of the amms from the factory
+                    let discover_timer = metrics.sync_phase_timer("discover");
+                    let amms: Vec<AMM> = factory
+                        .get_all_amms(Some(current_block), middleware, step)
+                        .instrument(tracing::info_span!("discover"))
+                        .await?;
+                    discover_timer.stop();
+
+                    populate_factory_amms(&factory, amms, &context).await
+                }
+                .instrument(span),
+            ),
+        ));
+    }
 
-            //Clean empty pools
-            amms = remove_empty_amms(amms);
+    for factory in custom_factories {
+        let middleware = middleware.clone();
+        let prefilter = prefilter.clone();
+        let token_store = token_store.clone();
+        let metrics = metrics.clone();
+        let span = tracing::info_span!("sync_custom_factory", factory = ?factory.address());
+
+        handles.push((
+            None,
+            tokio::spawn(
+                async move {
+                    tracing::info!("syncing custom factory {}", factory.address());
+                    let discover_timer = metrics.sync_phase_timer("discover");
+                    let mut amms = factory
+                        .get_all_amms(current_block, middleware.clone(), step)
+                        .instrument(tracing::info_span!("discover"))
+                        .await?;
+                    discover_timer.stop();
+
+                    let populate_timer = metrics.sync_phase_timer("populate");
+                    let populate_span = tracing::info_span!("populate", amms = amms.len());
+                    factory
+                        .populate_amm_data(&mut amms, current_block, middleware.clone())
+                        .instrument(populate_span)
+                        .await?;
+                    populate_timer.stop();
+                    metrics.pools_populated("custom", amms.len());
+                    token_store.record_amm_decimals(&amms);
+
+                    let prefilter_timer = metrics.sync_phase_timer("prefilter");
+                    let (mut amms, report) = prefilter_amms(
+                        remove_empty_amms(amms),
+                        prefilter.as_deref(),
+                        middleware.clone(),
+                    )
+                    .instrument(tracing::info_span!("prefilter"))
+                    .await?;
+                    prefilter_timer.stop();
 
-            //If the factory is UniswapV2, set the fee for each pool according to the factory fee
-            if let Factory::UniswapV2Factory(factory) = factory {
-                for amm in amms.iter_mut() {
-                    if let AMM::UniswapV2Pool(ref mut pool) = amm {
-                        pool.fee = factory.fee;
+                    if populate_options.fetch_symbols {
+                        populate_token_metadata(&mut amms, &token_store, middleware).await?;
                     }
-                }
-            }
 
-            Ok::<_, AMMError<M>>(amms)
-        }));
+                    Ok((amms, report))
+                }
+                .instrument(span),
+            ),
+        ));
     }
 
-    for handle in handles {
+    //Every factory has been scanned up to the current block
+    let mut factory_checkpoints = vec![];
+    let mut prefilter_report = FilterReport::default();
+    for (factory, handle) in handles {
         match handle.await {
-            Ok(sync_result) => aggregated_amms.extend(sync_result?),
+            Ok(sync_result) => {
+                let (amms, report) = sync_result?;
+                prefilter_report.merge(report);
+
+                if let Some(factory) = factory {
+                    factory_checkpoints.push(FactoryCheckpoint::new(
+                        factory,
+                        current_block,
+                        amms.len() as u64,
+                    ));
+                }
+
+                aggregated_amms.extend(amms);
+            }
             Err(err) => {
                 {
                     if err.is_panic() {
@@ -79,21 +355,176 @@ pub async fn sync_amms<M: 'static + Middleware>(
         }
     }
 
-    //Save a checkpoint if a path is provided
-
-    if let Some(checkpoint_path) = checkpoint_path {
-        checkpoint::construct_checkpoint(
-            factories,
-            &aggregated_amms,
-            current_block,
-            checkpoint_path,
-        )?;
+    //Save a checkpoint if a store is provided
+    if let Some(checkpoint_store) = checkpoint_store {
+        let _checkpoint_timer = metrics.sync_phase_timer("checkpoint");
+        checkpoint_store
+            .write(
+                &Checkpoint::new(
+                    checkpoint::checkpoint_timestamp()?,
+                    current_block,
+                    factory_checkpoints,
+                    aggregated_amms.clone(),
+                )
+                .with_chain_id(provider_chain_id),
+            )
+            .instrument(tracing::info_span!("checkpoint"))
+            .await?;
     }
 
     tracing::info!("AMMs synced");
 
     //Return the populated aggregated amms vec
-    Ok((aggregated_amms, current_block))
+    Ok((aggregated_amms, current_block, prefilter_report))
+}
+
+//Reads the block the sync runs up to and the chain of the provider, which has to be `chain_id` if set
+async fn current_block_and_chain_id<M: Middleware>(
+    middleware: &Arc<M>,
+    chain_id: Option<u64>,
+) -> Result<(u64, u64), AMMError<M>> {
+    let current_block = middleware
+        .get_block_number()
+        .await
+        .map_err(AMMError::MiddlewareError)?
+        .as_u64();
+
+    //Checkpoints record the chain they were synced on, so the chain id is always read
+    let provider_chain_id = middleware
+        .get_chainid()
+        .await
+        .map_err(AMMError::MiddlewareError)?
+        .as_u64();
+    if let Some(chain_id) = chain_id {
+        if chain_id != provider_chain_id {
+            return Err(AMMError::ChainIdMismatch {
+                expected: chain_id,
+                provider: provider_chain_id,
+            });
+        }
+    }
+
+    tracing::trace!(current_block, chain_id = provider_chain_id);
+
+    Ok((current_block, provider_chain_id))
+}
+
+//Options of a sync shared by every factory, see `populate_factory_amms`
+struct PopulateContext<M: 'static + Middleware> {
+    current_block: u64,
+    batch_step: u64,
+    batch_request_mode: BatchRequestMode,
+    prefilter: Option<Arc<FilterPipeline<M>>>,
+    token_store: TokenStore,
+    populate_options: PopulateOptions,
+    metrics: Metrics,
+    middleware: Arc<M>,
+}
+
+//Populates the AMMs discovered from `factory` at the current block, drops empty pools, sets the fees of V2 pools and
+//runs the prefilter on them. Called with every AMM of the factory by `sync_amms_with_config`, a chunk at a time by
+//`StreamingSync`.
+async fn populate_factory_amms<M: 'static + Middleware>(
+    factory: &Factory,
+    mut amms: Vec<AMM>,
+    context: &PopulateContext<M>,
+) -> Result<(Vec<AMM>, FilterReport), AMMError<M>> {
+    let PopulateContext {
+        current_block,
+        batch_step,
+        batch_request_mode,
+        prefilter,
+        token_store,
+        populate_options,
+        metrics,
+        middleware,
+    } = context;
+
+    let populate_timer = metrics.sync_phase_timer("populate");
+    populate_amms_with_mode(
+        &mut amms,
+        *current_block,
+        middleware.clone(),
+        *batch_step,
+        token_store,
+        *batch_request_mode,
+    )
+    .await?;
+    populate_timer.stop();
+    let protocol = match factory {
+        Factory::UniswapV2Factory(_) => "uniswap_v2",
+        Factory::UniswapV3Factory(_) => "uniswap_v3",
+        Factory::VelodromeFactory(_) => "velodrome",
+        Factory::UniswapV4Factory(_) => "uniswap_v4",
+        Factory::MaverickFactory(_) => "maverick",
+        Factory::KyberElasticFactory(_) => "kyber_elastic",
+        Factory::AlgebraFactory(_) => "algebra",
+    };
+    metrics.pools_populated(protocol, amms.len());
+
+    //Clean empty pools
+    amms = remove_empty_amms(amms);
+
+    //If the factory is UniswapV2, set the fee for each pool according to the factory fee
+    if let Factory::UniswapV2Factory(factory) = factory {
+        for amm in amms.iter_mut() {
+            if let AMM::UniswapV2Pool(ref mut pool) = amm {
+                pool.fee = factory.fee;
+            }
+        }
+
+        //Forks with per pool fees override the factory fee
+        factory
+            .populate_pool_fees(&mut amms, middleware.clone())
+            .await?;
+    }
+
+    let prefilter_timer = metrics.sync_phase_timer("prefilter");
+    let (mut amms, report) = prefilter_amms(amms, prefilter.as_deref(), middleware.clone())
+        .instrument(tracing::info_span!("prefilter"))
+        .await?;
+    prefilter_timer.stop();
+
+    if populate_options.fetch_symbols {
+        populate_token_metadata(&mut amms, token_store, middleware.clone()).await?;
+    }
+
+    Ok((amms, report))
+}
+
+async fn prefilter_amms<M: 'static + Middleware>(
+    amms: Vec<AMM>,
+    prefilter: Option<&FilterPipeline<M>>,
+    middleware: Arc<M>,
+) -> Result<(Vec<AMM>, FilterReport), AMMError<M>> {
+    match prefilter {
+        Some(prefilter) => prefilter.run(amms, middleware).await,
+        None => {
+            let report = FilterReport {
+                input: amms.len(),
+                kept: amms.len(),
+                ..Default::default()
+            };
+
+            Ok((amms, report))
+        }
+    }
+}
+
+/// Syncs every AMM of the known factories for `chain_id`, see `factory::known_factories`
+#[cfg(feature = "known-factories")]
+pub async fn sync_amms_for_chain<M: 'static + Middleware>(
+    chain_id: u64,
+    middleware: Arc<M>,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    step: u64,
+) -> Result<(Vec<AMM>, u64), AMMError<M>> {
+    let factories = crate::amm::factory::known_factories(chain_id);
+    if factories.is_empty() {
+        return Err(AMMError::NoKnownFactories(chain_id));
+    }
+
+    sync_amms(factories, middleware, checkpoint_store, step).await
 }
 
 pub fn amms_are_congruent(amms: &[AMM]) -> bool {
@@ -113,6 +544,38 @@ pub async fn populate_amms<M: Middleware>(
     block_number: u64,
     middleware: Arc<M>,
     step: u64,
+) -> Result<(), AMMError<M>> {
+    populate_amms_with_token_store(amms, block_number, middleware, step, &TokenStore::new()).await
+}
+
+/// Same as `populate_amms`, recording the decimals read by the batch requests in `token_store`
+pub async fn populate_amms_with_token_store<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: u64,
+    middleware: Arc<M>,
+    step: u64,
+    token_store: &TokenStore,
+) -> Result<(), AMMError<M>> {
+    populate_amms_with_mode(
+        amms,
+        block_number,
+        middleware,
+        step,
+        token_store,
+        BatchRequestMode::Auto,
+    )
+    .await
+}
+
+/// Same as `populate_amms_with_token_store`, reading the pools through `mode`
+#[instrument(name = "populate", skip(amms, middleware, token_store), fields(amms = amms.len()), err(Debug))]
+pub async fn populate_amms_with_mode<M: Middleware>(
+    amms: &mut [AMM],
+    block_number: u64,
+    middleware: Arc<M>,
+    step: u64,
+    token_store: &TokenStore,
+    mode: BatchRequestMode,
 ) -> Result<(), AMMError<M>> {
     if amms_are_congruent(amms) {
         match amms[0] {
@@ -123,8 +586,9 @@ pub async fn populate_amms<M: Middleware>(
                     step as usize
                 };
                 for amm_chunk in amms.chunks_mut(step) {
-                    uniswap_v2::batch_request::get_amm_data_batch_request(
+                    uniswap_v2::batch_request::get_amm_data_batch_request_with_mode(
                         amm_chunk,
+                        mode,
                         middleware.clone(),
                     )
                     .await?;
@@ -138,30 +602,166 @@ pub async fn populate_amms<M: Middleware>(
                     step as usize
                 };
                 for amm_chunk in amms.chunks_mut(step) {
-                    uniswap_v3::batch_request::get_amm_data_batch_request(
+                    uniswap_v3::batch_request::get_amm_data_batch_request_with_mode(
                         amm_chunk,
                         block_number,
+                        mode,
                         middleware.clone(),
                     )
                     .await?;
                 }
             }
 
-            // TODO: Implement batch request
             AMM::ERC4626Vault(_) => {
-                for amm in amms {
-                    amm.populate_data(None, middleware.clone()).await?;
+                let step = if step > 50 {
+                    50 //Max batch size for call
+                } else {
+                    step as usize
+                };
+                for amm_chunk in amms.chunks_mut(step) {
+                    erc_4626::batch_request::get_amm_data_batch_request(
+                        amm_chunk,
+                        Some(block_number),
+                        middleware.clone(),
+                    )
+                    .await?;
                 }
             }
+
+            //Curve, Balancer, Velodrome, Uniswap V4, Liquidity Book, Maverick, DODO, KyberSwap Elastic, Algebra, Curve
+            //crypto and Bancor V3 pools are only read through Multicall3, which chunks its calls itself
+            AMM::CurveStableSwapPool(_) => {
+                curve::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::BalancerWeightedPool(_) => {
+                balancer::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::VelodromePool(_) => {
+                velodrome::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::UniswapV4Pool(_) => {
+                uniswap_v4::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::LiquidityBookPool(_) => {
+                liquidity_book::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::MaverickPool(_) => {
+                maverick::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::DodoPool(_) => {
+                dodo::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::KyberElasticPool(_) => {
+                kyber_elastic::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::AlgebraPool(_) => {
+                algebra::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::CurveCryptoPool(_) => {
+                curve_crypto::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::BancorV3Pool(_) => {
+                bancor_v3::batch_request::get_amm_data_batch_request(
+                    amms,
+                    Some(block_number),
+                    middleware.clone(),
+                )
+                .await?;
+            }
+
+            AMM::Custom(_) => return Err(AMMError::CustomAMMOperation),
         }
     } else {
         return Err(AMMError::IncongruentAMMs);
     }
 
+    //Empty pools hold zeroed tokens, which the store skips
+    token_store.record_amm_decimals(amms);
+
     //For each pair in the pairs vec, get the pool data
     Ok(())
 }
 
+/// Fetches the symbols and names of the tokens of `amms` missing from `token_store`, and copies them onto the AMMs
+#[instrument(skip(amms, token_store, middleware), fields(amms = amms.len()), err(Debug))]
+pub async fn populate_token_metadata<M: Middleware>(
+    amms: &mut [AMM],
+    token_store: &TokenStore,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let tokens = amms
+        .iter()
+        .flat_map(|amm| amm.tokens())
+        .collect::<Vec<H160>>();
+    token_store.populate_symbols(&tokens, middleware).await?;
+
+    for amm in amms.iter_mut() {
+        amm.set_token_metadata(token_store);
+    }
+
+    Ok(())
+}
+
 pub fn remove_empty_amms(amms: Vec<AMM>) -> Vec<AMM> {
     let mut cleaned_amms = vec![];
 
@@ -182,6 +782,68 @@ pub fn remove_empty_amms(amms: Vec<AMM>) -> Vec<AMM> {
                     cleaned_amms.push(amm)
                 }
             }
+            AMM::CurveStableSwapPool(ref curve_pool) => {
+                if curve_pool.data_is_populated() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::BalancerWeightedPool(ref balancer_pool) => {
+                if balancer_pool.data_is_populated() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::VelodromePool(ref velodrome_pool) => {
+                if !velodrome_pool.token_a.is_zero() && !velodrome_pool.token_b.is_zero() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            //The native currency is token a at the zero address
+            AMM::UniswapV4Pool(ref uniswap_v4_pool) => {
+                if uniswap_v4_pool.data_is_populated() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::LiquidityBookPool(ref liquidity_book_pool) => {
+                if !liquidity_book_pool.token_a.is_zero() && !liquidity_book_pool.token_b.is_zero()
+                {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::MaverickPool(ref maverick_pool) => {
+                if !maverick_pool.token_a.is_zero() && !maverick_pool.token_b.is_zero() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::DodoPool(ref dodo_pool) => {
+                if !dodo_pool.token_a.is_zero() && !dodo_pool.token_b.is_zero() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::KyberElasticPool(ref kyber_elastic_pool) => {
+                if !kyber_elastic_pool.token_a.is_zero() && !kyber_elastic_pool.token_b.is_zero() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::AlgebraPool(ref algebra_pool) => {
+                if !algebra_pool.pool.token_a.is_zero() && !algebra_pool.pool.token_b.is_zero() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::CurveCryptoPool(ref crypto_pool) => {
+                if crypto_pool.data_is_populated() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::BancorV3Pool(ref bancor_pool) => {
+                if bancor_pool.data_is_populated() {
+                    cleaned_amms.push(amm)
+                }
+            }
+            AMM::Custom(ref custom_amm) => {
+                if custom_amm.data_is_populated() {
+                    cleaned_amms.push(amm)
+                }
+            }
         }
     }
 
@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use async_trait::async_trait;
+use ethers::types::H160;
+
+use crate::{
+    amm::{factory::AutomatedMarketMakerFactory, AmmState, AMM},
+    errors::CheckpointError,
+};
+
+use super::checkpoint::{
+    checkpoint_timestamp, Checkpoint, FactoryCheckpoint, Format, CHECKPOINT_VERSION,
+};
+
+/// Backend checkpoints are read from and written to, i.e. a file, an object store or a database.
+///
+/// `FileCheckpointStore` keeps the behavior of checkpoint paths, `MemoryCheckpointStore` holds the checkpoint in memory.
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    /// Reads the stored checkpoint, `CheckpointError::NotFound` if none has been written
+    async fn read(&self) -> Result<Checkpoint, CheckpointError>;
+
+    async fn write(&self, checkpoint: &Checkpoint) -> Result<(), CheckpointError>;
+
+    /// Updates the stored checkpoint to `block_number` with `amms` and the cursors of `factories`, replacing the
+    /// AMMs and factories already stored at the same address and keeping every other one.
+    ///
+    /// The default implementation reads and rewrites the whole checkpoint, stores that can update entries in place
+    /// should override it.
+    async fn write_partial(
+        &self,
+        factories: &[FactoryCheckpoint],
+        amms: &[AMM],
+        block_number: u64,
+    ) -> Result<(), CheckpointError> {
+        let mut checkpoint = match self.read().await {
+            Ok(checkpoint) => checkpoint,
+            Err(CheckpointError::NotFound) => Checkpoint::new(0, block_number, vec![], vec![]),
+            Err(err) => return Err(err),
+        };
+
+        merge_checkpoint(&mut checkpoint, factories, amms, block_number)?;
+        self.write(&checkpoint).await
+    }
+}
+
+//Applies a partial write to `checkpoint`, keeping the position of AMMs and factories that were already stored
+fn merge_checkpoint(
+    checkpoint: &mut Checkpoint,
+    factories: &[FactoryCheckpoint],
+    amms: &[AMM],
+    block_number: u64,
+) -> Result<(), CheckpointError> {
+    let positions = checkpoint
+        .amms
+        .iter()
+        .enumerate()
+        .map(|(i, amm)| (amm.address(), i))
+        .collect::<HashMap<H160, usize>>();
+    for amm in amms {
+        match positions.get(&amm.address()) {
+            Some(i) => checkpoint.amms[*i] = amm.clone(),
+            None => checkpoint.amms.push(amm.clone()),
+        }
+    }
+
+    for factory_checkpoint in factories {
+        let address = factory_checkpoint.factory.address();
+        match checkpoint
+            .factories
+            .iter_mut()
+            .find(|stored| stored.factory.address() == address)
+        {
+            Some(stored) => *stored = factory_checkpoint.clone(),
+            None => checkpoint.factories.push(factory_checkpoint.clone()),
+        }
+    }
+
+    //The whole checkpoint is rewritten in the current layout
+    checkpoint.version = CHECKPOINT_VERSION;
+    checkpoint.timestamp = checkpoint_timestamp()?;
+    checkpoint.block_number = block_number;
+
+    Ok(())
+}
+
+/// Checkpoint stored at a path, written to a temporary file first and renamed over the checkpoint so a crash during
+/// the write never leaves a truncated checkpoint behind.
+///
+/// Checkpoints are written as bincode unless another format is set, and read in whichever format they were written.
+#[derive(Debug, Clone)]
+pub struct FileCheckpointStore {
+    pub path: PathBuf,
+    pub format: Format,
+}
+
+impl FileCheckpointStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileCheckpointStore {
+            path: path.as_ref().to_path_buf(),
+            format: Format::default(),
+        }
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for FileCheckpointStore {
+    async fn read(&self) -> Result<Checkpoint, CheckpointError> {
+        let checkpoint = match tokio::fs::read(&self.path).await {
+            Ok(checkpoint) => checkpoint,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(CheckpointError::NotFound)
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Checkpoint::decode(&checkpoint)
+    }
+
+    async fn write(&self, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        tokio::fs::write(&temp_path, checkpoint.encode(self.format)?).await?;
+        tokio::fs::rename(&temp_path, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+/// Checkpoint held in memory, for tests and for callers persisting it themselves
+#[derive(Debug, Default)]
+pub struct MemoryCheckpointStore {
+    checkpoint: RwLock<Option<Checkpoint>>,
+}
+
+impl MemoryCheckpointStore {
+    pub fn new() -> Self {
+        MemoryCheckpointStore::default()
+    }
+
+    pub fn with_checkpoint(checkpoint: Checkpoint) -> Self {
+        MemoryCheckpointStore {
+            checkpoint: RwLock::new(Some(checkpoint)),
+        }
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for MemoryCheckpointStore {
+    async fn read(&self) -> Result<Checkpoint, CheckpointError> {
+        self.checkpoint
+            .read()
+            .expect("Checkpoint store poisoned")
+            .clone()
+            .ok_or(CheckpointError::NotFound)
+    }
+
+    async fn write(&self, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+        *self.checkpoint.write().expect("Checkpoint store poisoned") = Some(checkpoint.clone());
+
+        Ok(())
+    }
+
+    async fn write_partial(
+        &self,
+        factories: &[FactoryCheckpoint],
+        amms: &[AMM],
+        block_number: u64,
+    ) -> Result<(), CheckpointError> {
+        let mut checkpoint = self.checkpoint.write().expect("Checkpoint store poisoned");
+        let checkpoint =
+            checkpoint.get_or_insert_with(|| Checkpoint::new(0, block_number, vec![], vec![]));
+
+        merge_checkpoint(checkpoint, factories, amms, block_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::H160;
+
+    use crate::{
+        amm::{
+            factory::Factory,
+            uniswap_v2::{factory::UniswapV2Factory, UniswapV2Pool},
+            AMM,
+        },
+        errors::CheckpointError,
+        sync::checkpoint::{Checkpoint, FactoryCheckpoint, Format},
+    };
+
+    use super::{CheckpointStore, FileCheckpointStore, MemoryCheckpointStore};
+
+    fn pool(address: u64, reserve_0: u128) -> AMM {
+        AMM::UniswapV2Pool(UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            reserve_0,
+            fee: 300,
+            ..Default::default()
+        })
+    }
+
+    fn factory_checkpoint(last_scanned_block: u64) -> FactoryCheckpoint {
+        FactoryCheckpoint::new(
+            Factory::UniswapV2Factory(UniswapV2Factory::new(H160::from_low_u64_be(1), 0, 300)),
+            last_scanned_block,
+            2,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_partial_write() -> eyre::Result<()> {
+        let store = MemoryCheckpointStore::new();
+        assert!(matches!(store.read().await, Err(CheckpointError::NotFound)));
+
+        store
+            .write(&Checkpoint::new(
+                1690000000,
+                100,
+                vec![factory_checkpoint(100)],
+                vec![pool(10, 1), pool(11, 1)],
+            ))
+            .await?;
+        store
+            .write_partial(&[factory_checkpoint(101)], &[pool(11, 2), pool(12, 1)], 101)
+            .await?;
+
+        let checkpoint = store.read().await?;
+        assert_eq!(checkpoint.block_number, 101);
+        assert_eq!(checkpoint.factories.len(), 1);
+        assert_eq!(checkpoint.factories[0].last_scanned_block, 101);
+
+        let reserves = checkpoint
+            .amms
+            .iter()
+            .map(|amm| match amm {
+                AMM::UniswapV2Pool(pool) => (pool.address.to_low_u64_be(), pool.reserve_0),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reserves, vec![(10, 1), (11, 2), (12, 1)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_round_trip() -> eyre::Result<()> {
+        for format in [Format::Json, Format::Bincode, Format::MessagePack] {
+            let path =
+                std::env::temp_dir().join(format!("amms-file-checkpoint-store-{format:?}.json"));
+            let store = FileCheckpointStore::new(&path).with_format(format);
+            let _ = std::fs::remove_file(&path);
+            assert!(matches!(store.read().await, Err(CheckpointError::NotFound)));
+
+            store
+                .write_partial(&[factory_checkpoint(100)], &[pool(10, 1)], 100)
+                .await?;
+            store.write_partial(&[], &[pool(10, 5)], 101).await?;
+
+            let checkpoint = store.read().await?;
+            assert_eq!(checkpoint.block_number, 101);
+            assert_eq!(checkpoint.factories[0].last_scanned_block, 100);
+            assert_eq!(checkpoint.amms.len(), 1);
+            assert_eq!(Format::detect(&std::fs::read(&path)?), format);
+            assert!(!path.with_extension("json.tmp").exists());
+
+            //Checkpoints are read in whichever format they were written
+            assert_eq!(
+                FileCheckpointStore::new(&path).read().await?.block_number,
+                101
+            );
+
+            std::fs::remove_file(&path)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,595 @@
+//! Streaming sync, for chains with more pools than fit in memory.
+//!
+//! `sync_amms_with_config` holds every pool of every factory until all of them are populated, filtered and written
+//! to the checkpoint. `sync_amms_streaming` discovers the pools of each factory a chunk at a time instead, populating,
+//! filtering and writing out each chunk before the next one is discovered, so the sync never holds more than
+//! `SyncConfig::max_retained_pools` pools. Factories are synced one after the other.
+//!
+//! The ticks of V3 and Algebra pools are read from the mints and burns of the pools of each chunk, rather than from
+//! the positions of every pool while scanning for creation logs, so the block range of the factory is scanned once
+//! per chunk. The ticks of V4 pools are read the same way from the `ModifyLiquidity` logs of the PoolManager for the
+//! pools of each chunk.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    sync::Arc,
+};
+
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, Filter, ValueOrArray, H160, H256, U256, U64},
+};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use tracing::{instrument, Instrument};
+
+use crate::{
+    amm::{
+        decode,
+        factory::{AutomatedMarketMakerFactory, Factory, TASK_LIMIT},
+        uniswap_v2::{self, factory::GET_PAIRS_STEP, UniswapV2Pool},
+        uniswap_v3::{UniswapV3Pool, BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE},
+        uniswap_v4::{UniswapV4Pool, MODIFY_LIQUIDITY_EVENT_SIGNATURE},
+        velodrome::VelodromePool,
+        AMM,
+    },
+    errors::AMMError,
+    filters::pipeline::FilterReport,
+};
+
+use super::{
+    checkpoint::{checkpoint_timestamp, Checkpoint, FactoryCheckpoint},
+    current_block_and_chain_id, populate_factory_amms,
+    store::CheckpointStore,
+    PopulateContext, SyncConfig,
+};
+
+/// Pools held in memory at once by `sync_amms_streaming`, unless set with `SyncConfig::with_max_retained_pools`
+pub const DEFAULT_MAX_RETAINED_POOLS: usize = 10_000;
+//Pools per position log request, as providers cap the addresses of a filter
+const POSITION_FILTER_ADDRESSES: usize = 500;
+
+/// Starts a streaming sync of the AMMs of `factories` up to the current block with the options of `config`.
+///
+/// The checkpoint of the store of `config` is replaced by an empty one when the sync starts, then every chunk is
+/// appended to it once populated and prefiltered. Stores overriding `CheckpointStore::write_partial` append in place,
+/// `FileCheckpointStore` reads and rewrites the whole checkpoint for each chunk.
+#[instrument(skip_all, fields(factories = factories.len(), step = config.step, max_retained_pools = config.max_retained_pools), err(Debug))]
+pub async fn sync_amms_streaming<M: 'static + Middleware>(
+    factories: Vec<Factory>,
+    middleware: Arc<M>,
+    config: SyncConfig<M>,
+) -> Result<StreamingSync<M>, AMMError<M>> {
+    let SyncConfig {
+        step,
+        batch_step,
+        chain_id,
+        batch_request_mode,
+        checkpoint_store,
+        prefilter,
+        token_store,
+        populate_options,
+        metrics,
+        max_retained_pools,
+    } = config;
+
+    let (current_block, provider_chain_id) =
+        current_block_and_chain_id(&middleware, chain_id).await?;
+
+    if let Some(checkpoint_store) = &checkpoint_store {
+        checkpoint_store
+            .write(
+                &Checkpoint::new(checkpoint_timestamp()?, current_block, vec![], vec![])
+                    .with_chain_id(provider_chain_id),
+            )
+            .await?;
+    }
+
+    tracing::info!(
+        current_block,
+        "streaming AMMs of {} factories",
+        factories.len()
+    );
+
+    Ok(StreamingSync {
+        factories: factories.into(),
+        discovery: None,
+        pending: vec![],
+        step,
+        max_retained_pools: max_retained_pools.max(1),
+        checkpoint_store,
+        context: PopulateContext {
+            current_block,
+            batch_step: batch_step.unwrap_or(step),
+            batch_request_mode,
+            prefilter,
+            token_store: token_store.unwrap_or_default(),
+            populate_options,
+            metrics,
+            middleware,
+        },
+        prefilter_report: FilterReport::default(),
+        peak_retained_pools: 0,
+    })
+}
+
+/// Cursor over the chunks of a streaming sync, see `sync_amms_streaming`.
+///
+/// Chunks hold at most `max_retained_pools` AMMs, apart from V3 pools created in a single block past the limit, which
+/// are discovered together. Across all chunks, the AMMs yielded are the AMMs `sync_amms_with_config` returns.
+pub struct StreamingSync<M: 'static + Middleware> {
+    factories: VecDeque<Factory>,
+    discovery: Option<FactoryDiscovery>,
+    //Discovered AMMs waiting to be populated
+    pending: Vec<AMM>,
+    step: u64,
+    max_retained_pools: usize,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    context: PopulateContext<M>,
+    prefilter_report: FilterReport,
+    peak_retained_pools: usize,
+}
+
+impl<M: 'static + Middleware> StreamingSync<M> {
+    /// Block the AMMs are synced at
+    pub fn block_number(&self) -> u64 {
+        self.context.current_block
+    }
+
+    /// Drops of the prefilter across the chunks yielded so far
+    pub fn prefilter_report(&self) -> &FilterReport {
+        &self.prefilter_report
+    }
+
+    /// Most AMMs held by the sync at once so far, not counting the chunks handed to the caller
+    pub fn peak_retained_pools(&self) -> usize {
+        self.peak_retained_pools
+    }
+
+    /// Discovers, populates and prefilters the next chunk of AMMs and appends it to the checkpoint store, returning
+    /// `None` once every factory has been synced. Chunks left empty by the prefilter are skipped.
+    /// The sync can not be resumed after an error.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<AMM>>, AMMError<M>> {
+        loop {
+            if self.discovery.is_none() {
+                match self.factories.pop_front() {
+                    Some(factory) => {
+                        tracing::info!("streaming factory {}", factory.address());
+                        self.discovery = Some(
+                            FactoryDiscovery::start(
+                                factory,
+                                self.context.current_block,
+                                self.context.middleware.clone(),
+                            )
+                            .await?,
+                        );
+                    }
+                    None => return Ok(None),
+                }
+            }
+            let discovery = self
+                .discovery
+                .as_mut()
+                .expect("a factory is being discovered");
+
+            //Discover until the chunk is full or every pool of the factory has been discovered
+            let discover_timer = self.context.metrics.sync_phase_timer("discover");
+            while self.pending.len() < self.max_retained_pools && !discovery.is_exhausted() {
+                let capacity = self.max_retained_pools - self.pending.len();
+                let amms = discovery
+                    .discover(capacity, self.step, self.context.middleware.clone())
+                    .await?;
+                self.pending.extend(amms);
+                self.peak_retained_pools = self.peak_retained_pools.max(self.pending.len());
+            }
+            discover_timer.stop();
+
+            let mut amms = mem::take(&mut self.pending);
+            match &discovery.factory {
+                Factory::UniswapV3Factory(_) | Factory::AlgebraFactory(_) => {
+                    populate_positions(
+                        &mut amms,
+                        self.context.current_block,
+                        self.step,
+                        self.context.middleware.clone(),
+                    )
+                    .await?;
+                }
+                Factory::UniswapV4Factory(factory) => {
+                    populate_v4_positions(
+                        &mut amms,
+                        factory.address,
+                        self.context.current_block,
+                        self.step,
+                        self.context.middleware.clone(),
+                    )
+                    .await?;
+                }
+                _ => {}
+            }
+
+            let (amms, report) = if amms.is_empty() {
+                (amms, FilterReport::default())
+            } else {
+                populate_factory_amms(&discovery.factory, amms, &self.context).await?
+            };
+            self.prefilter_report.merge(report);
+            discovery.pools_kept += amms.len() as u64;
+
+            //The factory is checkpointed along with its last chunk
+            let exhausted = discovery.is_exhausted();
+            let factory_checkpoints = if exhausted {
+                vec![FactoryCheckpoint::new(
+                    discovery.factory.clone(),
+                    self.context.current_block,
+                    discovery.pools_kept,
+                )]
+            } else {
+                vec![]
+            };
+            if exhausted {
+                self.discovery = None;
+            }
+
+            if let Some(checkpoint_store) = &self.checkpoint_store {
+                checkpoint_store
+                    .write_partial(&factory_checkpoints, &amms, self.context.current_block)
+                    .instrument(tracing::info_span!("checkpoint", amms = amms.len()))
+                    .await?;
+            }
+
+            if !amms.is_empty() {
+                return Ok(Some(amms));
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<AMM>, AMMError<M>>> {
+        stream::try_unfold(self, |mut sync| async move {
+            Ok(sync.next_chunk().await?.map(|amms| (amms, sync)))
+        })
+    }
+}
+
+//Discovery progress of the factory being synced
+struct FactoryDiscovery {
+    factory: Factory,
+    //Next pair index for V2 and Velodrome factories, next block for V3, V4, Maverick, KyberSwap Elastic and Algebra
+    //factories
+    cursor: u64,
+    //Number of pairs for V2 and Velodrome factories, the block after the current block for V3, V4, Maverick,
+    //KyberSwap Elastic and Algebra factories
+    end: u64,
+    pools_kept: u64,
+}
+
+impl FactoryDiscovery {
+    async fn start<M: 'static + Middleware>(
+        factory: Factory,
+        current_block: u64,
+        middleware: Arc<M>,
+    ) -> Result<Self, AMMError<M>> {
+        let (cursor, end) = match &factory {
+            Factory::UniswapV2Factory(uniswap_v2_factory) => {
+                (0, uniswap_v2_factory.all_pairs_length(middleware).await?)
+            }
+            Factory::UniswapV3Factory(uniswap_v3_factory) => {
+                (uniswap_v3_factory.creation_block, current_block + 1)
+            }
+            Factory::VelodromeFactory(velodrome_factory) => {
+                (0, velodrome_factory.all_pools_length(middleware).await?)
+            }
+            Factory::UniswapV4Factory(uniswap_v4_factory) => {
+                (uniswap_v4_factory.creation_block, current_block + 1)
+            }
+            Factory::MaverickFactory(maverick_factory) => {
+                (maverick_factory.creation_block, current_block + 1)
+            }
+            Factory::KyberElasticFactory(kyber_elastic_factory) => {
+                (kyber_elastic_factory.creation_block, current_block + 1)
+            }
+            Factory::AlgebraFactory(algebra_factory) => {
+                (algebra_factory.creation_block, current_block + 1)
+            }
+        };
+
+        Ok(FactoryDiscovery {
+            factory,
+            cursor,
+            end,
+            pools_kept: 0,
+        })
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.cursor >= self.end
+    }
+
+    //Discovers up to `capacity` unpopulated AMMs, in the order they were created
+    async fn discover<M: 'static + Middleware>(
+        &mut self,
+        capacity: usize,
+        step: u64,
+        middleware: Arc<M>,
+    ) -> Result<Vec<AMM>, AMMError<M>> {
+        match &self.factory {
+            Factory::UniswapV2Factory(factory) => {
+                let to_idx = (self.cursor + GET_PAIRS_STEP.min(capacity as u64)).min(self.end);
+                let pairs = uniswap_v2::batch_request::get_pairs_batch_request(
+                    factory.address,
+                    U256::from(self.cursor),
+                    U256::from(to_idx),
+                    middleware,
+                )
+                .await?;
+                self.cursor = to_idx;
+
+                Ok(pairs
+                    .into_iter()
+                    .map(|address| {
+                        AMM::UniswapV2Pool(UniswapV2Pool {
+                            address,
+                            ..Default::default()
+                        })
+                    })
+                    .collect())
+            }
+
+            //V4 pools are created by the `Initialize` logs of the PoolManager, Maverick and KyberSwap Elastic pools
+            //read their bins and ticks when populated and need no positions applied
+            Factory::UniswapV3Factory(_)
+            | Factory::UniswapV4Factory(_)
+            | Factory::MaverickFactory(_)
+            | Factory::KyberElasticFactory(_)
+            | Factory::AlgebraFactory(_) => {
+                let to_block = (self.cursor + step).min(self.end) - 1;
+                let mut logs = middleware
+                    .get_logs(
+                        &Filter::new()
+                            .topic0(ValueOrArray::Value(
+                                self.factory.amm_created_event_signature(),
+                            ))
+                            .address(self.factory.address())
+                            .from_block(BlockNumber::Number(U64([self.cursor])))
+                            .to_block(BlockNumber::Number(U64([to_block]))),
+                    )
+                    .await
+                    .map_err(AMMError::MiddlewareError)?;
+
+                //Stops before the block of the first pool past capacity, which is scanned again by the next call
+                let mut next_block = to_block + 1;
+                if logs.len() > capacity {
+                    if let Some(cut_block) = logs[capacity].block_number {
+                        let kept = logs
+                            .iter()
+                            .take_while(|log| log.block_number < Some(cut_block))
+                            .count();
+                        if kept > 0 {
+                            logs.truncate(kept);
+                            next_block = cut_block.as_u64();
+                        }
+                    }
+                }
+                self.cursor = next_block;
+
+                Ok(self.factory.amms_from_logs(&logs)?)
+            }
+
+            Factory::VelodromeFactory(factory) => {
+                let to_idx = (self.cursor + GET_PAIRS_STEP.min(capacity as u64)).min(self.end);
+                let pools = factory
+                    .get_pool_addresses(self.cursor, to_idx, middleware)
+                    .await?;
+                self.cursor = to_idx;
+
+                Ok(pools
+                    .into_iter()
+                    .map(|address| {
+                        AMM::VelodromePool(VelodromePool {
+                            address,
+                            ..Default::default()
+                        })
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+//Applies the mints and burns of the V3 and Algebra pools of `amms` from the block the first of them was created at
+//up to `to_block`, which the full sync applies while scanning for creation logs
+async fn populate_positions<M: 'static + Middleware>(
+    amms: &mut [AMM],
+    to_block: u64,
+    step: u64,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let mut pools = amms
+        .iter_mut()
+        .filter_map(|amm| match amm {
+            AMM::UniswapV3Pool(pool) => Some((pool.address, pool)),
+            AMM::AlgebraPool(algebra_pool) => {
+                Some((algebra_pool.pool.address, &mut algebra_pool.pool))
+            }
+            _ => None,
+        })
+        .collect::<HashMap<H160, &mut UniswapV3Pool>>();
+    let from_block = match pools.values().filter_map(|pool| pool.creation_block).min() {
+        Some(from_block) => from_block,
+        None => return Ok(()),
+    };
+    let addresses = pools.keys().copied().collect::<Vec<H160>>();
+
+    for addresses in addresses.chunks(POSITION_FILTER_ADDRESSES) {
+        let mut range_logs = stream::iter((from_block..=to_block).step_by(step as usize))
+            .map(|from_block| {
+                let filter = Filter::new()
+                    .topic0(vec![BURN_EVENT_SIGNATURE, MINT_EVENT_SIGNATURE])
+                    .address(addresses.to_vec())
+                    .from_block(BlockNumber::Number(U64([from_block])))
+                    .to_block(BlockNumber::Number(U64([
+                        (from_block + step - 1).min(to_block)
+                    ])));
+                let middleware = middleware.clone();
+
+                async move {
+                    middleware
+                        .get_logs(&filter)
+                        .await
+                        .map_err(AMMError::MiddlewareError)
+                }
+            })
+            .buffered(TASK_LIMIT);
+
+        //Ranges resolve in the order they were requested, so positions are applied in chronological order
+        while let Some(logs) = range_logs.try_next().await? {
+            for log in logs {
+                if let Some(pool) = pools.get_mut(&log.address) {
+                    let event_signature = decode::event_signature(&log)?;
+                    if event_signature == BURN_EVENT_SIGNATURE {
+                        pool.sync_from_burn_log(&log)?;
+                    } else if event_signature == MINT_EVENT_SIGNATURE {
+                        pool.sync_from_mint_log(&log)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Applies the `ModifyLiquidity` logs of the V4 pools of `amms`, filtered by pool id as every pool logs through the
+//PoolManager, from the block the first of them was initialized at up to `to_block`
+async fn populate_v4_positions<M: 'static + Middleware>(
+    amms: &mut [AMM],
+    pool_manager: H160,
+    to_block: u64,
+    step: u64,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>> {
+    let mut pools = amms
+        .iter_mut()
+        .filter_map(|amm| match amm {
+            AMM::UniswapV4Pool(pool) => Some((pool.pool_id, pool)),
+            _ => None,
+        })
+        .collect::<HashMap<H256, &mut UniswapV4Pool>>();
+    let from_block = match pools
+        .values()
+        .filter_map(|pool| pool.pool.creation_block)
+        .min()
+    {
+        Some(from_block) => from_block,
+        None => return Ok(()),
+    };
+    let pool_ids = pools.keys().copied().collect::<Vec<H256>>();
+
+    for pool_ids in pool_ids.chunks(POSITION_FILTER_ADDRESSES) {
+        let mut range_logs = stream::iter((from_block..=to_block).step_by(step as usize))
+            .map(|from_block| {
+                let filter = Filter::new()
+                    .topic0(MODIFY_LIQUIDITY_EVENT_SIGNATURE)
+                    .topic1(pool_ids.to_vec())
+                    .address(pool_manager)
+                    .from_block(BlockNumber::Number(U64([from_block])))
+                    .to_block(BlockNumber::Number(U64([
+                        (from_block + step - 1).min(to_block)
+                    ])));
+                let middleware = middleware.clone();
+
+                async move {
+                    middleware
+                        .get_logs(&filter)
+                        .await
+                        .map_err(AMMError::MiddlewareError)
+                }
+            })
+            .buffered(TASK_LIMIT);
+
+        //Ranges resolve in the order they were requested, so positions are applied in chronological order
+        while let Some(logs) = range_logs.try_next().await? {
+            for log in logs {
+                if let Some(pool) = log.topics.get(1).and_then(|pool_id| pools.get_mut(pool_id)) {
+                    pool.sync_from_modify_liquidity_log(&log)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+    use ethers::types::{H160, H256};
+
+    use crate::{
+        amm::{factory::Factory, uniswap_v3::factory::UniswapV3Factory, AmmState, AMM},
+        sync::{
+            store::{CheckpointStore, MemoryCheckpointStore},
+            sync_amms_with_config, SyncConfig,
+        },
+        test_utils::ForkHarness,
+    };
+
+    use super::sync_amms_streaming;
+
+    fn fingerprints(amms: &[AMM]) -> HashMap<H160, H256> {
+        amms.iter()
+            .map(|amm| (amm.address(), amm.state_fingerprint()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_streaming_sync_matches_full_sync() -> eyre::Result<()> {
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+
+        //Only the pools created in the blocks before the fork, so both syncs stay small
+        let factory = Factory::UniswapV3Factory(UniswapV3Factory::new(
+            H160::from_str("0x1F98431c8aD98523631AE4a59f267346ea31F984")?,
+            fork.block_number - 20_000,
+        ));
+
+        let (amms, block_number, _) = sync_amms_with_config(
+            vec![factory.clone()],
+            vec![],
+            fork.provider.clone(),
+            SyncConfig::new(2000),
+        )
+        .await?;
+        assert!(amms.len() > 5);
+
+        let store = Arc::new(MemoryCheckpointStore::new());
+        let mut sync = sync_amms_streaming(
+            vec![factory],
+            fork.provider.clone(),
+            SyncConfig::new(2000)
+                .with_checkpoint_store(store.clone())
+                .with_max_retained_pools(5),
+        )
+        .await?;
+        assert_eq!(sync.block_number(), block_number);
+
+        let mut streamed = vec![];
+        while let Some(chunk) = sync.next_chunk().await? {
+            assert!(chunk.len() <= 5);
+            streamed.extend(chunk);
+        }
+        assert!(sync.peak_retained_pools() <= 5);
+
+        assert_eq!(fingerprints(&streamed), fingerprints(&amms));
+
+        let checkpoint = store.read().await?;
+        assert_eq!(fingerprints(&checkpoint.amms), fingerprints(&amms));
+        assert_eq!(checkpoint.factories.len(), 1);
+        assert_eq!(checkpoint.factories[0].pools_discovered, amms.len() as u64);
+
+        Ok(())
+    }
+}
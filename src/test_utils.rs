@@ -0,0 +1,259 @@
+use std::{process::Command, str::FromStr, sync::Arc, time::Duration};
+
+use ethers::{
+    middleware::SignerMiddleware,
+    prelude::abigen,
+    providers::{Http, Middleware, Provider},
+    signers::{LocalWallet, Signer},
+    types::{Bytes, TransactionReceipt, TransactionRequest, H160, U256},
+    utils::{parse_ether, Anvil, AnvilInstance},
+};
+
+use crate::{
+    amm::{log_amm_address, AmmState, AutomatedMarketMaker, AMM},
+    routing::{encode_route, Route, RouterTarget},
+};
+
+/// Variable holding the RPC URL forked by `ForkHarness`, fork tests are skipped when it is not set
+pub const FORK_URL_ENV: &str = "ETHEREUM_RPC_ENDPOINT";
+/// Variable overriding the block forked by `ForkHarness`
+pub const FORK_BLOCK_ENV: &str = "FORK_BLOCK_NUMBER";
+/// Mainnet block forked by default, so fork tests see the same state on every run
+pub const DEFAULT_FORK_BLOCK: u64 = 17_000_000;
+
+pub const WETH: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+pub const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+pub const LINK: &str = "0x514910771AF9Ca656af840dff83E8264EcF986CA";
+
+abigen!(
+    IForkERC20,
+    r#"[
+        function approve(address spender, uint256 amount) external returns (bool)
+        function balanceOf(address account) external view returns (uint256)
+    ]"#;
+
+    IForkWETH,
+    r#"[
+        function deposit() external payable
+    ]"#;
+
+    IForkUniswapV2Router,
+    r#"[
+        function swapETHForExactTokens(uint256 amountOut, address[] calldata path, address to, uint256 deadline) external payable returns (uint256[] memory amounts)
+    ]"#;
+);
+
+pub type ForkClient = SignerMiddleware<Provider<Http>, LocalWallet>;
+
+/// Anvil instance forking mainnet at a pinned block, with a funded account to execute swaps from.
+///
+/// Fork tests start with `ForkHarness::spawn` and return early when it is None, so the suite passes without a fork
+/// URL or an anvil binary. Batch request contracts are executed with `eth_call` by the sync code, which the fork
+/// serves like any other node, and `deploy` covers contracts tests need deployed.
+#[derive(Debug)]
+pub struct ForkHarness {
+    pub anvil: AnvilInstance,
+    pub provider: Arc<Provider<Http>>,
+    pub client: Arc<ForkClient>,
+    pub block_number: u64,
+}
+
+impl ForkHarness {
+    /// Spawns anvil forking `FORK_URL_ENV` at `FORK_BLOCK_ENV` or `DEFAULT_FORK_BLOCK`, None if either the URL or
+    /// the anvil binary is missing
+    pub fn spawn() -> Option<Self> {
+        let fork_url = match std::env::var(FORK_URL_ENV) {
+            Ok(fork_url) => fork_url,
+            Err(_) => {
+                eprintln!("{FORK_URL_ENV} is not set, skipping fork test");
+                return None;
+            }
+        };
+
+        if Command::new("anvil").arg("--version").output().is_err() {
+            eprintln!("anvil is not installed, skipping fork test");
+            return None;
+        }
+
+        let block_number = std::env::var(FORK_BLOCK_ENV)
+            .ok()
+            .and_then(|block_number| block_number.parse().ok())
+            .unwrap_or(DEFAULT_FORK_BLOCK);
+
+        let anvil = Anvil::new()
+            .fork(fork_url)
+            .fork_block_number(block_number)
+            .spawn();
+
+        let provider = Arc::new(
+            Provider::<Http>::try_from(anvil.endpoint())
+                .expect("Anvil endpoint is a valid URL")
+                .interval(Duration::from_millis(10)),
+        );
+        let wallet = LocalWallet::from(anvil.keys()[0].clone()).with_chain_id(anvil.chain_id());
+        let client = Arc::new(SignerMiddleware::new(provider.as_ref().clone(), wallet));
+
+        Some(ForkHarness {
+            anvil,
+            provider,
+            client,
+            block_number,
+        })
+    }
+
+    /// Account the swaps of the harness are sent from
+    pub fn account(&self) -> H160 {
+        self.client.address()
+    }
+
+    pub async fn set_balance(&self, account: H160, amount: U256) -> eyre::Result<()> {
+        self.provider
+            .request::<_, ()>("anvil_setBalance", (account, amount))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deploys `bytecode`, constructor arguments included, returning the address of the contract
+    pub async fn deploy(&self, bytecode: Bytes) -> eyre::Result<H160> {
+        let receipt = self
+            .client
+            .send_transaction(TransactionRequest::new().data(bytecode), None)
+            .await?
+            .await?
+            .ok_or_else(|| eyre::eyre!("Deployment was dropped"))?;
+
+        receipt
+            .contract_address
+            .ok_or_else(|| eyre::eyre!("Deployment did not create a contract"))
+    }
+
+    pub async fn balance_of(&self, token: H160, account: H160) -> eyre::Result<U256> {
+        Ok(IForkERC20::new(token, self.provider.clone())
+            .balance_of(account)
+            .call()
+            .await?)
+    }
+
+    /// Gives the account of the harness at least `amount` of `token`, wrapping ETH or buying the token with it on
+    /// the Uniswap V2 router
+    pub async fn fund(&self, token: H160, amount: U256) -> eyre::Result<()> {
+        let account = self.account();
+        self.set_balance(account, parse_ether(1_000_000)?).await?;
+
+        let weth = H160::from_str(WETH)?;
+        if token == weth {
+            IForkWETH::new(weth, self.client.clone())
+                .deposit()
+                .value(amount)
+                .send()
+                .await?
+                .await?;
+        } else {
+            let router = RouterTarget::uniswap_v2_router(1)
+                .ok_or_else(|| eyre::eyre!("No Uniswap V2 router on mainnet"))?;
+            IForkUniswapV2Router::new(router.address(), self.client.clone())
+                .swap_eth_for_exact_tokens(amount, vec![weth, token], account, U256::MAX)
+                .value(parse_ether(100_000)?)
+                .send()
+                .await?
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Swaps `amount_in` of `token_in` held by the account through `amm` with the Uniswap router of its protocol,
+    /// returning the amount received and the receipt of the swap
+    pub async fn swap(
+        &self,
+        amm: &AMM,
+        token_in: H160,
+        amount_in: U256,
+    ) -> eyre::Result<(U256, TransactionReceipt)> {
+        let target = match amm {
+            AMM::UniswapV2Pool(_) => RouterTarget::uniswap_v2_router(1),
+            AMM::UniswapV3Pool(_) => RouterTarget::uniswap_v3_router(1),
+            _ => None,
+        }
+        .ok_or_else(|| eyre::eyre!("No router swaps through {:?}", amm.address()))?;
+
+        let amms = vec![amm.clone()];
+        let route = Route::new(vec![(0, token_in)], amm.get_token_out(token_in), &amms)?;
+        let account = self.account();
+        let (to, calldata, value) = encode_route(
+            &route,
+            &amms,
+            amount_in,
+            U256::zero(),
+            account,
+            U256::MAX,
+            target,
+        )?;
+
+        IForkERC20::new(token_in, self.client.clone())
+            .approve(to, amount_in)
+            .send()
+            .await?
+            .await?;
+
+        let token_out = route.token_out();
+        let balance_before = self.balance_of(token_out, account).await?;
+        let receipt = self
+            .client
+            .send_transaction(
+                TransactionRequest::new().to(to).data(calldata).value(value),
+                None,
+            )
+            .await?
+            .await?
+            .ok_or_else(|| eyre::eyre!("Swap was dropped"))?;
+        let balance_after = self.balance_of(token_out, account).await?;
+
+        Ok((balance_after - balance_before, receipt))
+    }
+
+    /// Swaps `amount_in` of `token_in` on chain through `amm` and checks the output against `simulate_swap`, then
+    /// syncs `amm` from the logs of the swap and checks its state against the pool synced from the fork.
+    /// The ticks of V3 pools must be populated for the simulation to cross them.
+    pub async fn assert_swap_matches(
+        &self,
+        amm: &mut AMM,
+        token_in: H160,
+        amount_in: U256,
+    ) -> eyre::Result<()> {
+        //Funding may swap through `amm`, which is synced afterwards
+        self.fund(token_in, amount_in).await?;
+        amm.sync(self.provider.clone()).await?;
+
+        let simulated = amm.simulate_swap(token_in, amount_in)?;
+        let (amount_out, receipt) = self.swap(amm, token_in, amount_in).await?;
+        assert_eq!(
+            simulated,
+            amount_out,
+            "Simulated output differs from the swap through {:?}",
+            amm.address()
+        );
+
+        for log in receipt.logs.iter() {
+            let synced_event = log
+                .topics
+                .first()
+                .is_some_and(|topic| amm.sync_on_event_signatures().contains(topic));
+            if log_amm_address(log) == amm.address() && synced_event {
+                amm.sync_from_log(log)?;
+            }
+        }
+
+        let mut synced = amm.clone();
+        synced.sync(self.provider.clone()).await?;
+        assert_eq!(
+            amm.state_fingerprint(),
+            synced.state_fingerprint(),
+            "State synced from logs differs from the state of {:?}",
+            amm.address()
+        );
+
+        Ok(())
+    }
+}
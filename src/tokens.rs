@@ -0,0 +1,521 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use ethers::types::H160;
+#[cfg(feature = "rpc")]
+use ethers::{
+    contract::MULTICALL_ADDRESS,
+    prelude::abigen,
+    providers::Middleware,
+    types::{Bytes, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::amm::{bancor_v3, AMM};
+#[cfg(feature = "rpc")]
+use crate::errors::AMMError;
+
+#[cfg(feature = "rpc")]
+abigen!(
+    IMulticall3,
+    r#"[
+        struct Call3 { address target; bool allowFailure; bytes callData; }
+        struct Result3 { bool success; bytes returnData; }
+        function aggregate3(Call3[] calls) external payable returns (Result3[] returnData)
+    ]"#;
+);
+
+//decimals()
+#[cfg(feature = "rpc")]
+pub(crate) const DECIMALS_SELECTOR: [u8; 4] = [49, 60, 229, 103];
+//symbol()
+#[cfg(feature = "rpc")]
+const SYMBOL_SELECTOR: [u8; 4] = [149, 216, 155, 65];
+//name()
+#[cfg(feature = "rpc")]
+const NAME_SELECTOR: [u8; 4] = [6, 253, 222, 3];
+
+//Tokens queried per multicall, each token makes three calls
+#[cfg(feature = "rpc")]
+const TOKEN_STEP: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub decimals: u8,
+    /// None if the token has no readable symbol, or if only its decimals were recorded from an AMM
+    pub symbol: Option<String>,
+    pub name: Option<String>,
+}
+
+impl TokenInfo {
+    pub fn new(decimals: u8, symbol: Option<String>, name: Option<String>) -> Self {
+        TokenInfo {
+            decimals,
+            symbol,
+            name,
+        }
+    }
+}
+
+/// Token metadata shared across pools, factories and filters, so that every token is only queried once.
+///
+/// Clones share the same underlying map, and the store serializes to a plain map of tokens,
+/// which can be written out at the end of a run and used to pre-seed the next one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "HashMap<H160, TokenInfo>", into = "HashMap<H160, TokenInfo>")]
+pub struct TokenStore {
+    tokens: Arc<RwLock<HashMap<H160, TokenInfo>>>,
+    //Tokens whose symbol and name were already queried, so tokens without readable ones are queried once per run
+    queried: Arc<RwLock<HashSet<H160>>>,
+}
+
+impl TokenStore {
+    pub fn new() -> Self {
+        TokenStore::default()
+    }
+
+    pub fn get(&self, token: H160) -> Option<TokenInfo> {
+        self.read().get(&token).cloned()
+    }
+
+    pub fn decimals(&self, token: H160) -> Option<u8> {
+        self.read().get(&token).map(|info| info.decimals)
+    }
+
+    pub fn contains(&self, token: H160) -> bool {
+        self.read().contains_key(&token)
+    }
+
+    pub fn insert(&self, token: H160, info: TokenInfo) {
+        self.write().insert(token, info);
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Copy of every token in the store
+    pub fn snapshot(&self) -> HashMap<H160, TokenInfo> {
+        self.read().clone()
+    }
+
+    /// Tokens missing from the store, deduplicated and in the order they are first seen
+    pub fn unknown_tokens(&self, tokens: impl IntoIterator<Item = H160>) -> Vec<H160> {
+        let known = self.read();
+        let mut seen = HashSet::new();
+
+        tokens
+            .into_iter()
+            .filter(|token| !known.contains_key(token) && seen.insert(*token))
+            .collect()
+    }
+
+    /// Records the decimals reported by populated AMMs for tokens that are not in the store yet.
+    /// The AMM batch requests read decimals alongside reserves, so this costs no extra calls.
+    pub fn record_amm_decimals(&self, amms: &[AMM]) {
+        let mut tokens = self.write();
+
+        for (token, decimals) in amms.iter().flat_map(recorded_decimals) {
+            if !token.is_zero() {
+                tokens
+                    .entry(token)
+                    .or_insert_with(|| TokenInfo::new(decimals, None, None));
+            }
+        }
+    }
+
+    /// Fetches the decimals, symbol and name of every token not in the store yet through Multicall3.
+    /// Tokens whose `decimals()` call fails are left out of the store. Returns the number of tokens added.
+    #[cfg(feature = "rpc")]
+    pub async fn populate<M: Middleware>(
+        &self,
+        tokens: &[H160],
+        middleware: Arc<M>,
+    ) -> Result<usize, AMMError<M>> {
+        let unknown_tokens = self.unknown_tokens(tokens.iter().copied());
+        self.fetch(unknown_tokens, middleware).await
+    }
+
+    /// Same as `populate`, also fetching the tokens only known by the decimals recorded from AMMs,
+    /// so that every token gets its symbol and name. Each token is queried at most once per store.
+    #[cfg(feature = "rpc")]
+    pub async fn populate_symbols<M: Middleware>(
+        &self,
+        tokens: &[H160],
+        middleware: Arc<M>,
+    ) -> Result<usize, AMMError<M>> {
+        let tokens_without_symbols = {
+            let known = self.read();
+            let queried = self.queried.read().expect("token store lock poisoned");
+            let mut seen = HashSet::new();
+
+            tokens
+                .iter()
+                .copied()
+                .filter(|token| {
+                    let has_metadata = known
+                        .get(token)
+                        .is_some_and(|info| info.symbol.is_some() || info.name.is_some());
+
+                    !has_metadata && !queried.contains(token) && seen.insert(*token)
+                })
+                .collect::<Vec<H160>>()
+        };
+
+        self.fetch(tokens_without_symbols, middleware).await
+    }
+
+    #[cfg(feature = "rpc")]
+    async fn fetch<M: Middleware>(
+        &self,
+        tokens: Vec<H160>,
+        middleware: Arc<M>,
+    ) -> Result<usize, AMMError<M>> {
+        if tokens.is_empty() {
+            return Ok(0);
+        }
+
+        tracing::info!("fetching metadata for {} tokens", tokens.len());
+
+        let multicall = IMulticall3::new(MULTICALL_ADDRESS, middleware);
+        let mut added = 0;
+
+        for chunk in tokens.chunks(TOKEN_STEP) {
+            let calls = chunk
+                .iter()
+                .flat_map(|token| {
+                    [DECIMALS_SELECTOR, SYMBOL_SELECTOR, NAME_SELECTOR].map(|selector| Call3 {
+                        target: *token,
+                        allow_failure: true,
+                        call_data: Bytes::from(selector.to_vec()),
+                    })
+                })
+                .collect::<Vec<Call3>>();
+
+            let results = multicall.aggregate_3(calls).call().await?;
+
+            let mut store = self.write();
+            for (token, results) in chunk.iter().zip(results.chunks(3)) {
+                let [decimals, symbol, name] = results else {
+                    continue;
+                };
+
+                if let Some(decimals) = decode_decimals(decimals) {
+                    store.insert(
+                        *token,
+                        TokenInfo::new(decimals, decode_string(symbol), decode_string(name)),
+                    );
+                    added += 1;
+                }
+            }
+
+            self.queried
+                .write()
+                .expect("token store lock poisoned")
+                .extend(chunk.iter().copied());
+        }
+
+        Ok(added)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<H160, TokenInfo>> {
+        self.tokens.read().expect("token store lock poisoned")
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<H160, TokenInfo>> {
+        self.tokens.write().expect("token store lock poisoned")
+    }
+}
+
+impl From<HashMap<H160, TokenInfo>> for TokenStore {
+    fn from(tokens: HashMap<H160, TokenInfo>) -> Self {
+        TokenStore {
+            tokens: Arc::new(RwLock::new(tokens)),
+            queried: Arc::default(),
+        }
+    }
+}
+
+impl From<TokenStore> for HashMap<H160, TokenInfo> {
+    fn from(store: TokenStore) -> Self {
+        store.snapshot()
+    }
+}
+
+//Decimals of each token as recorded on the AMM
+pub(crate) fn recorded_decimals(amm: &AMM) -> Vec<(H160, u8)> {
+    match amm {
+        AMM::UniswapV2Pool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        AMM::UniswapV3Pool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        AMM::ERC4626Vault(vault) => vec![
+            (vault.vault_token, vault.vault_token_decimals),
+            (vault.asset_token, vault.asset_token_decimals),
+        ],
+        AMM::CurveStableSwapPool(pool) => pool
+            .coins
+            .iter()
+            .copied()
+            .zip(pool.decimals.iter().copied())
+            .collect(),
+        AMM::BalancerWeightedPool(pool) => pool
+            .tokens
+            .iter()
+            .copied()
+            .zip(pool.decimals.iter().copied())
+            .collect(),
+        AMM::VelodromePool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        //The native currency of Uniswap V4 pools is not a token
+        AMM::UniswapV4Pool(pool) => [
+            (pool.pool.token_a, pool.pool.token_a_decimals),
+            (pool.pool.token_b, pool.pool.token_b_decimals),
+        ]
+        .into_iter()
+        .filter(|(token, _)| !token.is_zero())
+        .collect(),
+        AMM::LiquidityBookPool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        AMM::MaverickPool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        AMM::DodoPool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        AMM::KyberElasticPool(pool) => vec![
+            (pool.token_a, pool.token_a_decimals),
+            (pool.token_b, pool.token_b_decimals),
+        ],
+        AMM::AlgebraPool(pool) => vec![
+            (pool.pool.token_a, pool.pool.token_a_decimals),
+            (pool.pool.token_b, pool.pool.token_b_decimals),
+        ],
+        AMM::CurveCryptoPool(pool) => pool
+            .coins
+            .iter()
+            .copied()
+            .zip(pool.decimals.iter().copied())
+            .collect(),
+        AMM::BancorV3Pool(pool) => vec![
+            (pool.token, pool.token_decimals),
+            (pool.bnt, bancor_v3::BNT_DECIMALS),
+        ],
+        AMM::Custom(_) => vec![],
+    }
+}
+
+#[cfg(feature = "rpc")]
+fn decode_decimals(result: &Result3) -> Option<u8> {
+    if !result.success || result.return_data.len() < 32 {
+        return None;
+    }
+
+    let decimals = U256::from_big_endian(&result.return_data[..32]);
+    (decimals <= U256::from(u8::MAX)).then(|| decimals.as_u32() as u8)
+}
+
+#[cfg(feature = "rpc")]
+fn decode_string(result: &Result3) -> Option<String> {
+    if !result.success {
+        return None;
+    }
+
+    String::from_utf8(decode_symbol(&result.return_data))
+        .ok()
+        .filter(|string| !string.is_empty())
+}
+
+//Symbols are ABI encoded strings, or a right padded bytes32 for older tokens like MKR
+#[cfg(feature = "rpc")]
+pub(crate) fn decode_symbol(data: &[u8]) -> Vec<u8> {
+    if data.len() >= 64 {
+        let offset = U256::from_big_endian(&data[..32]);
+        if offset == U256::from(32) {
+            let len = U256::from_big_endian(&data[32..64]);
+            if len <= U256::from(data.len() - 64) {
+                return data[64..64 + len.as_usize()].to_vec();
+            }
+        }
+    }
+
+    let bytes32 = &data[..data.len().min(32)];
+    let len = bytes32
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |idx| idx + 1);
+
+    bytes32[..len].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use ethers::{
+        abi::Token,
+        providers::Provider,
+        types::{Bytes, H160, U256},
+    };
+
+    use crate::amm::{uniswap_v2::UniswapV2Pool, AmmState, AMM};
+
+    use super::{TokenInfo, TokenStore};
+
+    fn result(success: bool, data: Vec<u8>) -> Token {
+        Token::Tuple(vec![Token::Bool(success), Token::Bytes(data)])
+    }
+
+    #[test]
+    fn test_token_store() -> eyre::Result<()> {
+        let weth = H160::from_low_u64_be(1);
+        let usdc = H160::from_low_u64_be(2);
+
+        let store = TokenStore::new();
+        let shared = store.clone();
+        shared.insert(weth, TokenInfo::new(18, Some("WETH".into()), None));
+        assert_eq!(store.decimals(weth), Some(18));
+
+        assert_eq!(store.unknown_tokens([usdc, weth, usdc]), vec![usdc]);
+
+        //Decimals from AMMs never override metadata already in the store
+        store.record_amm_decimals(&[AMM::UniswapV2Pool(UniswapV2Pool {
+            token_a: weth,
+            token_a_decimals: 0,
+            token_b: usdc,
+            token_b_decimals: 6,
+            ..Default::default()
+        })]);
+        assert_eq!(store.get(weth).unwrap().symbol.as_deref(), Some("WETH"));
+        assert_eq!(store.get(usdc), Some(TokenInfo::new(6, None, None)));
+
+        let persisted = serde_json::to_string(&store)?;
+        let restored: TokenStore = serde_json::from_str(&persisted)?;
+        assert_eq!(restored.snapshot(), store.snapshot());
+        assert_eq!(
+            TokenStore::from(HashMap::from([(weth, TokenInfo::new(18, None, None))])).len(),
+            1
+        );
+
+        Ok(())
+    }
+
+    //Known tokens are never queried, so populating twice makes a single call
+    #[tokio::test]
+    async fn test_populate_only_fetches_unknown_tokens() -> eyre::Result<()> {
+        let weth = H160::from_low_u64_be(1);
+        let mkr = H160::from_low_u64_be(2);
+        let not_a_token = H160::from_low_u64_be(3);
+
+        let store = TokenStore::new();
+        store.insert(weth, TokenInfo::new(18, Some("WETH".into()), None));
+
+        let mut mkr_symbol = b"MKR".to_vec();
+        mkr_symbol.resize(32, 0);
+        let results = ethers::abi::encode(&[Token::Array(vec![
+            result(true, ethers::abi::encode(&[Token::Uint(U256::from(18))])),
+            result(true, mkr_symbol),
+            result(
+                true,
+                ethers::abi::encode(&[Token::String("Maker".to_string())]),
+            ),
+            result(false, vec![]),
+            result(false, vec![]),
+            result(false, vec![]),
+        ])]);
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(results.into())?;
+        let middleware = Arc::new(provider);
+
+        let tokens = [weth, mkr, not_a_token];
+        assert_eq!(store.populate(&tokens, middleware.clone()).await?, 1);
+        assert_eq!(
+            store.get(mkr),
+            Some(TokenInfo::new(
+                18,
+                Some("MKR".to_string()),
+                Some("Maker".to_string())
+            ))
+        );
+        assert!(!store.contains(not_a_token));
+
+        //Everything left unknown is queried again, known tokens are not
+        mock.push::<Bytes, _>(
+            ethers::abi::encode(&[Token::Array(vec![
+                result(false, vec![]),
+                result(false, vec![]),
+                result(false, vec![]),
+            ])])
+            .into(),
+        )?;
+        assert_eq!(store.populate(&tokens, middleware.clone()).await?, 0);
+        assert!(store.populate(&[weth, mkr], middleware).await.is_ok());
+
+        Ok(())
+    }
+
+    //Tokens only known by their decimals are queried for their symbol once, and the symbols are copied to the AMMs
+    #[tokio::test]
+    async fn test_populate_symbols() -> eyre::Result<()> {
+        let weth = H160::from_low_u64_be(1);
+        let usdc = H160::from_low_u64_be(2);
+
+        let mut amm = AMM::UniswapV2Pool(UniswapV2Pool {
+            token_a: weth,
+            token_a_decimals: 18,
+            token_b: usdc,
+            token_b_decimals: 6,
+            ..Default::default()
+        });
+
+        let store = TokenStore::new();
+        store.insert(weth, TokenInfo::new(18, Some("WETH".into()), None));
+        store.record_amm_decimals(std::slice::from_ref(&amm));
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(
+            ethers::abi::encode(&[Token::Array(vec![
+                result(true, ethers::abi::encode(&[Token::Uint(U256::from(6))])),
+                result(false, vec![]),
+                result(false, vec![]),
+            ])])
+            .into(),
+        )?;
+        let middleware = Arc::new(provider);
+
+        //Neither token is unknown, but usdc has no symbol yet
+        assert_eq!(store.populate(&amm.tokens(), middleware.clone()).await?, 0);
+        assert_eq!(
+            store
+                .populate_symbols(&amm.tokens(), middleware.clone())
+                .await?,
+            1
+        );
+
+        //usdc has no readable symbol and is not queried again, the mock has no response left
+        assert_eq!(store.populate_symbols(&amm.tokens(), middleware).await?, 0);
+
+        amm.set_token_metadata(&store);
+        assert_eq!(amm.token_symbol(weth), Some("WETH"));
+        assert_eq!(amm.token_symbol(usdc), None);
+
+        Ok(())
+    }
+}
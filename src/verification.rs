@@ -0,0 +1,587 @@
+//! Differential testing of the local swap math against the chain.
+//!
+//! `verify_amms` quotes a grid of amounts on every AMM both locally with `simulate_swap` and with the on chain
+//! reference of its protocol at the same block: `getAmountsOut` of a Uniswap V2 router, `quoteExactInputSingle` of
+//! the Uniswap V3 QuoterV2 and `previewDeposit` / `previewRedeem` of ERC4626 vaults. The `DivergenceReport` keeps
+//! the largest relative error of each pool and every sample over the tolerance of its protocol, with the exact
+//! inputs to reproduce it.
+//!
+//! The router and the quoter only reach the pools of their factory, pools of another factory are reported as
+//! skipped rather than compared against a different pool.
+
+use std::sync::Arc;
+
+use ethers::{
+    prelude::abigen,
+    providers::Middleware,
+    types::{BlockId, H160, U256},
+};
+
+use crate::{
+    amm::{AmmState, AMM},
+    errors::AMMError,
+    filters::dedupe::Protocol,
+    math::fixed_point::u256_to_f64_lossy,
+};
+
+abigen!(
+    IVerificationRouter,
+    r#"[
+        function factory() external view returns (address)
+        function getAmountsOut(uint256 amountIn, address[] memory path) external view returns (uint256[] memory amounts)
+    ]"#;
+
+    IVerificationV2Factory,
+    r#"[
+        function getPair(address tokenA, address tokenB) external view returns (address pair)
+    ]"#;
+
+    IVerificationQuoterV2,
+    r#"[
+        function factory() external view returns (address)
+        function quoteExactInputSingle((address,address,uint256,uint24,uint160) params) external returns (uint256 amountOut, uint160 sqrtPriceX96After, uint32 initializedTicksCrossed, uint256 gasEstimate)
+    ]"#;
+
+    IVerificationV3Pool,
+    r#"[
+        function factory() external view returns (address)
+    ]"#;
+
+    IVerificationVault,
+    r#"[
+        function previewDeposit(uint256 assets) external view returns (uint256 shares)
+        function previewRedeem(uint256 shares) external view returns (uint256 assets)
+    ]"#;
+);
+
+/// Uniswap V2 router on mainnet
+pub const UNISWAP_V2_ROUTER: H160 = H160([
+    0x7a, 0x25, 0x0d, 0x56, 0x30, 0xb4, 0xcf, 0x53, 0x97, 0x39, 0xdf, 0x2c, 0x5d, 0xac, 0xb4, 0xc6,
+    0x59, 0xf2, 0x48, 0x8d,
+]);
+/// Uniswap V3 QuoterV2 on mainnet
+pub const UNISWAP_V3_QUOTER_V2: H160 = H160([
+    0x61, 0xff, 0xe0, 0x14, 0xba, 0x17, 0x98, 0x9e, 0x74, 0x3c, 0x5f, 0x6c, 0xb2, 0x1b, 0xf9, 0x69,
+    0x75, 0x30, 0xb2, 0x1e,
+]);
+
+/// Largest divergence accepted for a sample, which passes when it is within either bound
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// Absolute difference in wei of the token out
+    pub wei: U256,
+    /// Difference relative to the on chain output
+    pub relative: f64,
+}
+
+impl Tolerance {
+    pub fn exact() -> Self {
+        Tolerance {
+            wei: U256::zero(),
+            relative: 0.0,
+        }
+    }
+
+    pub fn allows(&self, sample: &Sample) -> bool {
+        match (sample.local, sample.reference) {
+            (Some(_), Some(_)) => {
+                sample.abs_error().unwrap_or_default() <= self.wei
+                    || sample.relative_error() <= self.relative
+            }
+            //Both sides rejecting the amount agree
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Tolerance of each protocol.
+///
+/// - Uniswap V2 is exact, the constant product is computed in integers exactly like the pair.
+/// - Uniswap V3 accepts a relative error of 1e-9. The math is ported from the pool and expected to match the
+///   quoter to the wei, the margin keeps rounding differences of a few wei on large amounts from failing the
+///   check while a missing tick or a math error still diverges by orders of magnitude more.
+/// - ERC4626 accepts 1 wei, previews round against the caller and may include yield accrued since the last sync
+///   of the totals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerances {
+    pub uniswap_v2: Tolerance,
+    pub uniswap_v3: Tolerance,
+    pub erc_4626: Tolerance,
+}
+
+impl Default for Tolerances {
+    fn default() -> Self {
+        Tolerances {
+            uniswap_v2: Tolerance::exact(),
+            uniswap_v3: Tolerance {
+                wei: U256::zero(),
+                relative: 1e-9,
+            },
+            erc_4626: Tolerance {
+                wei: U256::one(),
+                relative: 0.0,
+            },
+        }
+    }
+}
+
+impl Tolerances {
+    fn of(&self, amm: &AMM) -> Tolerance {
+        match amm {
+            AMM::UniswapV2Pool(_) => self.uniswap_v2,
+            AMM::UniswapV3Pool(_) => self.uniswap_v3,
+            _ => self.erc_4626,
+        }
+    }
+}
+
+/// Amounts quoted on each pool and the contracts quoting them on chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationConfig {
+    /// Amounts swapped in each direction of each pool, in whole units of the token in
+    pub amounts: Vec<f64>,
+    pub uniswap_v2_router: H160,
+    pub uniswap_v3_quoter: H160,
+    pub tolerances: Tolerances,
+    /// Block the AMMs were synced at, the latest block if None
+    pub block_number: Option<u64>,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        VerificationConfig {
+            amounts: vec![0.001, 0.1, 1.0, 10.0, 1000.0],
+            uniswap_v2_router: UNISWAP_V2_ROUTER,
+            uniswap_v3_quoter: UNISWAP_V3_QUOTER_V2,
+            tolerances: Tolerances::default(),
+            block_number: None,
+        }
+    }
+}
+
+impl VerificationConfig {
+    pub fn new() -> Self {
+        VerificationConfig::default()
+    }
+
+    pub fn with_amounts(mut self, amounts: Vec<f64>) -> Self {
+        self.amounts = amounts;
+        self
+    }
+
+    pub fn with_uniswap_v2_router(mut self, router: H160) -> Self {
+        self.uniswap_v2_router = router;
+        self
+    }
+
+    pub fn with_uniswap_v3_quoter(mut self, quoter: H160) -> Self {
+        self.uniswap_v3_quoter = quoter;
+        self
+    }
+
+    pub fn with_tolerances(mut self, tolerances: Tolerances) -> Self {
+        self.tolerances = tolerances;
+        self
+    }
+
+    pub fn with_block_number(mut self, block_number: u64) -> Self {
+        self.block_number = Some(block_number);
+        self
+    }
+}
+
+/// One amount of the grid, quoted locally and on chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub token_in: H160,
+    pub amount_in: U256,
+    /// Output of `simulate_swap`, None if the simulation failed
+    pub local: Option<U256>,
+    /// Output of the on chain reference, None if the call reverted
+    pub reference: Option<U256>,
+}
+
+impl Sample {
+    /// Difference between the local and the on chain output, None unless both were quoted
+    pub fn abs_error(&self) -> Option<U256> {
+        match (self.local, self.reference) {
+            (Some(local), Some(reference)) => Some(if local > reference {
+                local - reference
+            } else {
+                reference - local
+            }),
+            _ => None,
+        }
+    }
+
+    /// Difference relative to the on chain output, infinite when only one side quoted the amount
+    pub fn relative_error(&self) -> f64 {
+        match (self.abs_error(), self.reference) {
+            (Some(error), _) if error.is_zero() => 0.0,
+            (Some(error), Some(reference)) if !reference.is_zero() => {
+                u256_to_f64_lossy(error) / u256_to_f64_lossy(reference)
+            }
+            (None, None) if self.local.is_none() => 0.0,
+            _ => f64::INFINITY,
+        }
+    }
+}
+
+/// Divergence of the samples of one pool
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolDivergence {
+    pub pool: H160,
+    /// Protocol name, as in `Protocol::name`
+    pub protocol: &'static str,
+    pub samples: usize,
+    pub max_relative_error: f64,
+    /// Samples over the tolerance of the protocol
+    pub failures: Vec<Sample>,
+}
+
+/// Pool left out of the verification, with the reason
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedPool {
+    pub pool: H160,
+    pub reason: String,
+}
+
+/// Outcome of `verify_amms`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DivergenceReport {
+    /// Block the references were quoted at, None for the latest block
+    pub block_number: Option<u64>,
+    pub pools: Vec<PoolDivergence>,
+    pub skipped: Vec<SkippedPool>,
+}
+
+impl DivergenceReport {
+    /// Whether every sample is within the tolerance of its protocol
+    pub fn is_within_tolerance(&self) -> bool {
+        self.pools.iter().all(|pool| pool.failures.is_empty())
+    }
+
+    /// Largest relative error of any sample
+    pub fn max_relative_error(&self) -> f64 {
+        self.pools
+            .iter()
+            .map(|pool| pool.max_relative_error)
+            .fold(0.0, f64::max)
+    }
+
+    /// Samples over the tolerance, with their pool
+    pub fn failures(&self) -> impl Iterator<Item = (H160, &Sample)> + '_ {
+        self.pools
+            .iter()
+            .flat_map(|pool| pool.failures.iter().map(move |sample| (pool.pool, sample)))
+    }
+}
+
+//On chain contract quoting a pool
+enum Reference<M: Middleware> {
+    UniswapV2(IVerificationRouter<M>),
+    UniswapV3(IVerificationQuoterV2<M>),
+    ERC4626(IVerificationVault<M>),
+}
+
+/// Quotes `config.amounts` in both directions of every AMM locally and on chain, at `config.block_number`.
+///
+/// Custom AMMs and pools the references do not reach are skipped. Middleware errors fail the whole verification,
+/// reverts of a reference only fail their sample.
+pub async fn verify_amms<M: 'static + Middleware>(
+    amms: &[AMM],
+    config: &VerificationConfig,
+    middleware: Arc<M>,
+) -> Result<DivergenceReport, AMMError<M>> {
+    let block: Option<BlockId> = config.block_number.map(|block_number| block_number.into());
+    let router = IVerificationRouter::new(config.uniswap_v2_router, middleware.clone());
+    let quoter = IVerificationQuoterV2::new(config.uniswap_v3_quoter, middleware.clone());
+
+    let mut v2_factory = None;
+    let mut v3_factory = None;
+    let mut report = DivergenceReport {
+        block_number: config.block_number,
+        ..Default::default()
+    };
+
+    for amm in amms {
+        let pool = amm.address();
+        let tokens = amm.tokens();
+
+        let reference = match amm {
+            AMM::UniswapV2Pool(uniswap_v2_pool) => {
+                let factory = match v2_factory {
+                    Some(factory) => factory,
+                    None => *v2_factory.insert(call_with(router.factory(), block).await?),
+                };
+                let pair = IVerificationV2Factory::new(factory, middleware.clone())
+                    .get_pair(uniswap_v2_pool.token_a, uniswap_v2_pool.token_b);
+
+                if call_with(pair, block).await? != pool {
+                    report.skipped.push(SkippedPool {
+                        pool,
+                        reason: "not a pair of the router factory".to_string(),
+                    });
+                    continue;
+                }
+                Reference::UniswapV2(router.clone())
+            }
+            AMM::UniswapV3Pool(_) => {
+                let factory = match v3_factory {
+                    Some(factory) => factory,
+                    None => *v3_factory.insert(call_with(quoter.factory(), block).await?),
+                };
+                let pool_factory = IVerificationV3Pool::new(pool, middleware.clone()).factory();
+
+                if call_with(pool_factory, block).await? != factory {
+                    report.skipped.push(SkippedPool {
+                        pool,
+                        reason: "not a pool of the quoter factory".to_string(),
+                    });
+                    continue;
+                }
+                Reference::UniswapV3(quoter.clone())
+            }
+            AMM::ERC4626Vault(_) => {
+                Reference::ERC4626(IVerificationVault::new(pool, middleware.clone()))
+            }
+            _ => {
+                report.skipped.push(SkippedPool {
+                    pool,
+                    reason: "no on chain reference for the protocol".to_string(),
+                });
+                continue;
+            }
+        };
+
+        let tolerance = config.tolerances.of(amm);
+        let mut divergence = PoolDivergence {
+            pool,
+            protocol: Protocol::of(amm).name(),
+            samples: 0,
+            max_relative_error: 0.0,
+            failures: vec![],
+        };
+
+        for token_in in tokens.iter().copied() {
+            let token_out = match amm.opp_token(token_in) {
+                Some(token_out) => token_out,
+                None => continue,
+            };
+            let decimals = token_decimals(amm, token_in);
+
+            for amount in config.amounts.iter() {
+                let amount_in = U256::from((amount * 10_f64.powi(decimals as i32)) as u128);
+                let sample = Sample {
+                    token_in,
+                    amount_in,
+                    local: amm.simulate_swap(token_in, amount_in).ok(),
+                    reference: quote_reference(
+                        &reference, amm, token_in, token_out, amount_in, block,
+                    )
+                    .await?,
+                };
+
+                divergence.samples += 1;
+                divergence.max_relative_error =
+                    divergence.max_relative_error.max(sample.relative_error());
+                if !tolerance.allows(&sample) {
+                    tracing::warn!(?pool, ?sample, "local quote diverges from the chain");
+                    divergence.failures.push(sample);
+                }
+            }
+        }
+
+        report.pools.push(divergence);
+    }
+
+    Ok(report)
+}
+
+//Output of the reference for a swap, None if it reverted
+async fn quote_reference<M: 'static + Middleware>(
+    reference: &Reference<M>,
+    amm: &AMM,
+    token_in: H160,
+    token_out: H160,
+    amount_in: U256,
+    block: Option<BlockId>,
+) -> Result<Option<U256>, AMMError<M>> {
+    let result = match (reference, amm) {
+        (Reference::UniswapV2(router), _) => router
+            .get_amounts_out(amount_in, vec![token_in, token_out])
+            .block_opt(block)
+            .call()
+            .await
+            .map(|amounts| amounts.last().copied().unwrap_or_default()),
+        (Reference::UniswapV3(quoter), AMM::UniswapV3Pool(pool)) => quoter
+            .quote_exact_input_single((token_in, token_out, amount_in, pool.fee, U256::zero()))
+            .block_opt(block)
+            .call()
+            .await
+            .map(|(amount_out, ..)| amount_out),
+        (Reference::ERC4626(vault), AMM::ERC4626Vault(erc_4626_vault)) => {
+            if token_in == erc_4626_vault.asset_token {
+                vault
+                    .preview_deposit(amount_in)
+                    .block_opt(block)
+                    .call()
+                    .await
+            } else {
+                vault
+                    .preview_redeem(amount_in)
+                    .block_opt(block)
+                    .call()
+                    .await
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    match result {
+        Ok(amount_out) => Ok(Some(amount_out)),
+        //Reverts are an answer of the reference, transport failures are not
+        Err(error) if error.is_revert() => Ok(None),
+        Err(error) => Err(AMMError::ContractError(error)),
+    }
+}
+
+async fn call_with<M: Middleware, D: ethers::abi::Detokenize>(
+    call: ethers::contract::ContractCall<M, D>,
+    block: Option<BlockId>,
+) -> Result<D, AMMError<M>> {
+    Ok(call.block_opt(block).call().await?)
+}
+
+trait BlockOpt {
+    fn block_opt(self, block: Option<BlockId>) -> Self;
+}
+
+impl<M: Middleware, D: ethers::abi::Detokenize> BlockOpt for ethers::contract::ContractCall<M, D> {
+    fn block_opt(self, block: Option<BlockId>) -> Self {
+        match block {
+            Some(block) => self.block(block),
+            None => self,
+        }
+    }
+}
+
+fn token_decimals(amm: &AMM, token: H160) -> u8 {
+    match amm {
+        AMM::UniswapV2Pool(pool) if token == pool.token_a => pool.token_a_decimals,
+        AMM::UniswapV2Pool(pool) => pool.token_b_decimals,
+        AMM::UniswapV3Pool(pool) if token == pool.token_a => pool.token_a_decimals,
+        AMM::UniswapV3Pool(pool) => pool.token_b_decimals,
+        AMM::ERC4626Vault(vault) if token == vault.vault_token => vault.vault_token_decimals,
+        AMM::ERC4626Vault(vault) => vault.asset_token_decimals,
+        AMM::CurveStableSwapPool(pool) => pool.coin_decimals(token).unwrap_or(18),
+        AMM::BalancerWeightedPool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::VelodromePool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::UniswapV4Pool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::LiquidityBookPool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::MaverickPool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::DodoPool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::KyberElasticPool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::AlgebraPool(pool) => pool.token_decimals(token).unwrap_or(18),
+        AMM::CurveCryptoPool(pool) => pool.coin_decimals(token).unwrap_or(18),
+        AMM::BancorV3Pool(pool) => pool.token_decimals(token).unwrap_or(18),
+        _ => 18,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::types::{H160, U256};
+
+    use crate::{
+        amm::{uniswap_v2::UniswapV2Pool, uniswap_v3::UniswapV3Pool, AMM},
+        test_utils::ForkHarness,
+    };
+
+    use super::{verify_amms, Sample, Tolerance, Tolerances, VerificationConfig};
+
+    fn sample(local: Option<u64>, reference: Option<u64>) -> Sample {
+        Sample {
+            token_in: H160::zero(),
+            amount_in: U256::exp10(18),
+            local: local.map(U256::from),
+            reference: reference.map(U256::from),
+        }
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let tolerances = Tolerances::default();
+
+        assert!(tolerances
+            .uniswap_v2
+            .allows(&sample(Some(1000), Some(1000))));
+        assert!(!tolerances
+            .uniswap_v2
+            .allows(&sample(Some(1001), Some(1000))));
+        assert!(tolerances.erc_4626.allows(&sample(Some(999), Some(1000))));
+        assert!(!tolerances.erc_4626.allows(&sample(Some(998), Some(1000))));
+
+        let relative = Tolerance {
+            wei: U256::zero(),
+            relative: 1e-3,
+        };
+        assert!(relative.allows(&sample(Some(1_000_999), Some(1_000_000))));
+        assert!(!relative.allows(&sample(Some(1_001_001), Some(1_000_000))));
+
+        //Only one side quoting the amount is a divergence, both rejecting it is not
+        assert!(!relative.allows(&sample(Some(1000), None)));
+        assert!(!relative.allows(&sample(None, Some(1000))));
+        assert!(relative.allows(&sample(None, None)));
+        assert_eq!(sample(None, Some(1000)).relative_error(), f64::INFINITY);
+        assert_eq!(sample(None, None).relative_error(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_verify_amms_on_fork() -> eyre::Result<()> {
+        let fork = match ForkHarness::spawn() {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+        let middleware = fork.provider.clone();
+
+        let amms = vec![
+            AMM::UniswapV2Pool(
+                UniswapV2Pool::new_from_address_at_block(
+                    H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")?,
+                    300,
+                    Some(fork.block_number),
+                    middleware.clone(),
+                )
+                .await?,
+            ),
+            AMM::UniswapV3Pool(
+                UniswapV3Pool::new_from_address_at_block(
+                    H160::from_str("0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640")?,
+                    100,
+                    Some(fork.block_number),
+                    middleware.clone(),
+                )
+                .await?,
+            ),
+        ];
+
+        let config = VerificationConfig::new()
+            .with_amounts(vec![0.01, 1.0, 100.0])
+            .with_block_number(fork.block_number);
+        let report = verify_amms(&amms, &config, middleware).await?;
+
+        assert_eq!(report.pools.len(), 2);
+        assert!(report.skipped.is_empty());
+        assert!(
+            report.is_within_tolerance(),
+            "diverging samples: {:?}",
+            report.failures().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+}